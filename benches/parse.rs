@@ -0,0 +1,66 @@
+//! Benchmarks for parsing CONNECT request heads, including the repeated re-parsing of a
+//! growing buffer that happens when a client sends its head in small pieces.
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use giphyproxy::http::{parse_head, ParseOptions};
+
+/// A head as sent by curl
+const TYPICAL: &[u8] = b"CONNECT api.giphy.com:443 HTTP/1.1\r\n\
+    Host: api.giphy.com:443\r\n\
+    User-Agent: curl/8.5.0\r\n\
+    Proxy-Connection: Keep-Alive\r\n\
+    \r\n";
+
+/// A head with many headers, close to the default `limits.max_head_size`
+fn many_headers() -> Vec<u8> {
+    let mut head = b"CONNECT [2606:2800:220:1:248:1893:25c8:1946]:443 HTTP/1.1\r\n".to_vec();
+    for i in 0..200 {
+        head.extend_from_slice(format!("X-Header-{}: {}\r\n", i, "v".repeat(20)).as_bytes());
+    }
+    head.extend_from_slice(b"\r\n");
+    head
+}
+
+fn bench_parse(c: &mut Criterion) {
+    let many = many_headers();
+    let options = ParseOptions::default();
+    // `many` has more headers than the default limit, which would end its parse early
+    let unlimited = ParseOptions {
+        max_headers: usize::MAX,
+        ..ParseOptions::default()
+    };
+    let mut group = c.benchmark_group("parse_head");
+    group.bench_function("typical", |b| {
+        b.iter(|| parse_head(black_box(TYPICAL), &options))
+    });
+    group.bench_function("many_headers", |b| {
+        b.iter(|| parse_head(black_box(&many), &unlimited))
+    });
+    group.bench_function("invalid", |b| {
+        b.iter(|| {
+            parse_head(
+                black_box(b"CONNECT api.giphy.com:443 HTTP/9.9\r\n\r\n"),
+                &options,
+            )
+        })
+    });
+    group.finish();
+
+    // re-parse every prefix, as `read_head` does when each read returns `chunk` bytes
+    let mut group = c.benchmark_group("parse_head_chunked");
+    for chunk in [1, 16, 256] {
+        group.bench_with_input(BenchmarkId::from_parameter(chunk), &chunk, |b, &chunk| {
+            b.iter(|| {
+                let mut size = 0;
+                while size < many.len() {
+                    size = (size + chunk).min(many.len());
+                    black_box(parse_head(&many[..size], &unlimited));
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_parse);
+criterion_main!(benches);
@@ -0,0 +1,70 @@
+//! Benchmarks for the throughput of a tunnel through `connection`, between in-memory
+//! sockets, so that the copy loop is measured without the kernel's TCP stack.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use giphyproxy::config::Config;
+use giphyproxy::connection::{connection, ConnectionInfo};
+use giphyproxy::testing::EchoBackend;
+use std::sync::Arc;
+use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+use tokio::runtime::Runtime;
+
+/// Bytes sent through the tunnel (and echoed back) in each iteration
+const PAYLOAD: usize = 1 << 20;
+
+/// Open a tunnel to an echo backend, send `PAYLOAD` bytes through it, and read them back
+async fn round_trip(config: Arc<Config>, payload: &[u8]) {
+    let (client, server) = duplex(config.limits.tunnel_buffer_size);
+    tokio::spawn(connection(
+        server,
+        EchoBackend,
+        config,
+        None,
+        None,
+        ConnectionInfo::default(),
+    ));
+    let (mut read, mut write) = tokio::io::split(client);
+
+    write
+        .write_all(b"CONNECT api.giphy.com:443 HTTP/1.1\r\n\r\n")
+        .await
+        .unwrap();
+    let mut head = vec![];
+    while !head.ends_with(b"\r\n\r\n") {
+        head.push(read.read_u8().await.unwrap());
+    }
+    assert!(head.starts_with(b"HTTP/1.1 200 "));
+
+    let send = async {
+        write.write_all(payload).await.unwrap();
+        write.shutdown().await.unwrap();
+    };
+    let receive = async {
+        let mut buf = vec![0u8; payload.len()];
+        read.read_exact(&mut buf).await.unwrap();
+    };
+    tokio::join!(send, receive);
+}
+
+fn bench_tunnel(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let payload = vec![0x5a; PAYLOAD];
+    let mut group = c.benchmark_group("tunnel");
+    group.throughput(Throughput::Bytes(PAYLOAD as u64));
+    for buffer_size in [1024, 8192, 65536] {
+        let mut config = Config::default();
+        config.limits.tunnel_buffer_size = buffer_size;
+        let config = Arc::new(config);
+        group.bench_with_input(
+            BenchmarkId::from_parameter(buffer_size),
+            &config,
+            |b, config| {
+                b.iter(|| runtime.block_on(round_trip(config.clone(), &payload)));
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_tunnel);
+criterion_main!(benches);
@@ -0,0 +1,24 @@
+//! Parse arbitrary bytes as a request head, checking that anything accepted is well-formed.
+
+#![no_main]
+use giphyproxy::http::{parse_head, ParseHeadResult, ParseOptions};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    match parse_head(data, &ParseOptions::default()) {
+        ParseHeadResult::Connect { host, len, .. } => {
+            assert!(len <= data.len());
+            assert!(data.starts_with(b"CONNECT "));
+            assert!(data[..len].ends_with(b"\r\n\r\n"));
+            assert!(!host.is_empty());
+            assert!(!host.contains(|c: char| c.is_whitespace() || c == '/'));
+        }
+        ParseHeadResult::OtherMethod { method, len } => {
+            assert!(len <= data.len());
+            assert!(data.starts_with(method.as_bytes()));
+            assert_ne!(method, "CONNECT");
+            assert!(data[..len].ends_with(b"\r\n\r\n"));
+        }
+        ParseHeadResult::TooLarge(_) | ParseHeadResult::Err(_) | ParseHeadResult::Incomplete => {}
+    }
+});
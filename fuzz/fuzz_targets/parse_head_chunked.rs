@@ -0,0 +1,36 @@
+//! Feed a request head to `parse_head` in randomly-sized chunks, as `read_head` does with
+//! the reads from a client socket, checking that the result does not depend on where the
+//! chunk boundaries fall.
+
+#![no_main]
+use giphyproxy::http::{parse_head, ParseHeadResult, ParseOptions};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|input: (Vec<u8>, Vec<u8>)| {
+    let (chunks, data) = input;
+    let options = ParseOptions::default();
+    let mut chunks = chunks.into_iter().map(|c| usize::from(c).max(1));
+
+    // re-parse the whole buffer after each "read", stopping at the first complete result
+    let mut size = 0;
+    let result = loop {
+        if size == data.len() {
+            return;
+        }
+        size = (size + chunks.next().unwrap_or(data.len())).min(data.len());
+        match parse_head(&data[..size], &options) {
+            ParseHeadResult::Incomplete => continue,
+            result => break result,
+        }
+    };
+
+    // a head is complete, or rejected, regardless of what follows it; a head exceeding a
+    // limit may be rejected as too large before a later parse error is reached
+    match parse_head(&data, &options) {
+        ParseHeadResult::Err(_) | ParseHeadResult::TooLarge(_) => assert!(matches!(
+            result,
+            ParseHeadResult::Err(_) | ParseHeadResult::TooLarge(_)
+        )),
+        full => assert_eq!(result, full),
+    }
+});
@@ -0,0 +1,528 @@
+use crate::config::{AccessLogConfig, AccessLogFormat};
+use crate::connection::Protocol;
+use crate::metrics::METRICS;
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Instant;
+use time::OffsetDateTime;
+use tracing::{field, Span};
+
+/// The `log` target used for access log records
+pub const ACCESS_LOG_TARGET: &str = "giphyproxy::access";
+
+/// Source of connection IDs; these are unique within a process
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// The access log file, if one has been installed with `AccessLog::install`
+static FILE: RwLock<Option<Arc<AccessLog>>> = RwLock::new(None);
+
+/// Why a connection ended
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Reason {
+    /// The tunnel was established, and later the client closed its side first
+    ClientClosed,
+    /// The tunnel was established, and later the backend closed its side first
+    BackendClosed,
+    /// The tunnel was closed because it was idle
+    Idle,
+    /// The tunnel was closed by an administrator
+    Terminated,
+    /// The client hung up or failed before sending a full request
+    ClientError,
+    /// The request could not be parsed
+    BadRequest,
+    /// The client failed to authenticate
+    AuthFailed,
+    /// The client took too long to send the request
+    HeadTimeout,
+    /// The requested destination is not allowed
+    Disallowed,
+    /// A request to the reverse proxy, or in an intercepted tunnel, was refused by a hook
+    Refused,
+    /// A request to the reverse proxy, or in an intercepted tunnel, was answered from the
+    /// response cache
+    Cached,
+    /// The tunnel did not begin with a TLS ClientHello for the requested host
+    SniMismatch,
+    /// The tunnel, through a listen address requiring TLS, did not begin with a TLS
+    /// handshake
+    NotTls,
+    /// The client had used its daily quota of bytes
+    QuotaExceeded,
+    /// The backend connection could not be established
+    BackendError,
+    /// Some other error occurred
+    Error,
+}
+
+impl Reason {
+    /// The HTTP status that best describes this outcome, for the Common Log Format
+    fn status(self) -> u16 {
+        use Reason::*;
+        match self {
+            ClientClosed | BackendClosed | Idle | Terminated | Cached => 200,
+            BadRequest => 400,
+            Disallowed | Refused | SniMismatch | NotTls => 403,
+            AuthFailed => 407,
+            HeadTimeout => 408,
+            QuotaExceeded => 429,
+            // as nginx logs clients that hang up before it has responded
+            ClientError => 499,
+            Error => 500,
+            BackendError => 502,
+        }
+    }
+}
+
+/// A record of a single connection, logged as one JSON line when the connection ends.
+#[derive(Debug, Serialize)]
+pub struct AccessRecord {
+    /// Identifier for this connection
+    pub id: u64,
+
+    /// The client's address, if known
+    pub client: Option<SocketAddr>,
+
+    /// The authenticated username, if the client authenticated
+    pub user: Option<String>,
+
+    /// The identity from the client's TLS certificate, if it presented one
+    pub client_cert: Option<String>,
+
+    /// The protocol the client used, once known
+    pub protocol: Option<Protocol>,
+
+    /// The requested `host:port`, if the request was parsed
+    pub target: Option<String>,
+
+    /// The client's `User-Agent` header, if it sent one
+    pub user_agent: Option<String>,
+
+    /// The `host:port` of the backend endpoint that served the connection, if the backend
+    /// has several (see `backend.failover`)
+    pub served_by: Option<String>,
+
+    /// The JA3 fingerprint of the tunnel's TLS ClientHello, if `log.tls_fingerprints` is set
+    pub ja3: Option<String>,
+
+    /// The JA4 fingerprint of the tunnel's TLS ClientHello, if `log.tls_fingerprints` is set
+    pub ja4: Option<String>,
+
+    /// Time from accepting the connection until it ended
+    pub duration_ms: u64,
+
+    /// Bytes sent from the client to the backend
+    pub bytes_up: u64,
+
+    /// Bytes sent from the backend to the client
+    pub bytes_down: u64,
+
+    /// Why the connection ended
+    pub reason: Reason,
+
+    #[serde(skip)]
+    start: Instant,
+}
+
+impl AccessRecord {
+    /// Begin a record for a new connection, assigning it an ID
+    pub fn new(client: Option<SocketAddr>) -> Self {
+        Self {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            client,
+            user: None,
+            client_cert: None,
+            protocol: None,
+            target: None,
+            user_agent: None,
+            served_by: None,
+            ja3: None,
+            ja4: None,
+            duration_ms: 0,
+            bytes_up: 0,
+            bytes_down: 0,
+            reason: Reason::Error,
+            start: Instant::now(),
+        }
+    }
+
+    /// Finish the record, calculating the connection's duration and counting it in the
+    /// per-destination and per-fingerprint metrics
+    pub fn finish(&mut self) {
+        self.duration_ms = self.start.elapsed().as_millis() as u64;
+        if let Some(target) = &self.target {
+            METRICS.destinations.record(
+                target,
+                self.reason == Reason::Disallowed,
+                self.bytes_up,
+                self.bytes_down,
+            );
+        }
+        if let Some(ja4) = &self.ja4 {
+            METRICS.fingerprints.record(ja4);
+        }
+    }
+
+    /// Serialize this record as a single line of JSON
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("access records are always serializable")
+    }
+
+    /// Format this record as a line of the Common Log Format, as of `time`.  Request lines
+    /// are not recorded, so the request field gives the protocol and target instead, and
+    /// the status is the one implied by the reason the connection ended.
+    pub fn to_common(&self, time: OffsetDateTime) -> String {
+        let or_dash = |value: Option<String>| value.unwrap_or_else(|| "-".into());
+        let month = time.month().to_string();
+        format!(
+            "{} - {} [{:02}/{}/{}:{:02}:{:02}:{:02} +0000] \"{} {}\" {} {}",
+            or_dash(self.client.map(|c| c.ip().to_string())),
+            or_dash(self.user.clone()),
+            time.day(),
+            &month[..3],
+            time.year(),
+            time.hour(),
+            time.minute(),
+            time.second(),
+            or_dash(self.protocol.and_then(serialized_name)),
+            or_dash(self.target.clone()),
+            self.reason.status(),
+            self.bytes_down,
+        )
+    }
+
+    /// Write this record to the access log file, if one is installed, or otherwise to the
+    /// diagnostic log, and to the audit trail if one is installed
+    pub fn log(&self) {
+        let file = FILE.read().unwrap().clone();
+        match file {
+            Some(file) => {
+                if let Err(e) = file.write(self) {
+                    log::error!("writing to access log {}: {}", file.path.display(), e);
+                }
+            }
+            None => log::info!(target: ACCESS_LOG_TARGET, "{}", self.to_json()),
+        }
+        crate::audit::record(self);
+    }
+
+    /// Begin a tracing span covering this connection.  Its fields describing the outcome
+    /// are filled in by `record_span`.
+    pub fn span(&self) -> Span {
+        let span = tracing::info_span!(
+            "connection",
+            id = self.id,
+            client = field::Empty,
+            protocol = field::Empty,
+            target = field::Empty,
+            user = field::Empty,
+            bytes_up = field::Empty,
+            bytes_down = field::Empty,
+            reason = field::Empty,
+        );
+        if let Some(client) = self.client {
+            span.record("client", field::display(client));
+        }
+        span
+    }
+
+    /// Fill in the fields of a span begun with `span`
+    pub fn record_span(&self, span: &Span) {
+        if let Some(protocol) = self.protocol.and_then(serialized_name) {
+            span.record("protocol", protocol.as_str());
+        }
+        if let Some(target) = &self.target {
+            span.record("target", target.as_str());
+        }
+        if let Some(user) = &self.user {
+            span.record("user", user.as_str());
+        }
+        span.record("bytes_up", self.bytes_up);
+        span.record("bytes_down", self.bytes_down);
+        if let Some(reason) = serialized_name(self.reason) {
+            span.record("reason", reason.as_str());
+        }
+    }
+}
+
+/// The serialized name of a unit enum variant, as used in the access log
+pub(crate) fn serialized_name<T: Serialize>(value: T) -> Option<String> {
+    serde_json::json!(value).as_str().map(str::to_owned)
+}
+
+/// An access log file.  Once installed, records are written to it instead of the
+/// diagnostic log.  The file is rotated when it would grow beyond its maximum size, and
+/// can be reopened after an external tool such as `logrotate` has moved it aside.
+pub struct AccessLog {
+    path: PathBuf,
+    format: AccessLogFormat,
+    max_size: u64,
+    keep: usize,
+    /// The open file, and its size
+    file: Mutex<(File, u64)>,
+}
+
+impl AccessLog {
+    /// Open the file configured in `config`, which must have a path
+    pub fn open(config: &AccessLogConfig) -> Result<Self> {
+        let path = config
+            .path
+            .clone()
+            .context("no access log path configured")?;
+        let file = Self::open_file(&path)
+            .with_context(|| format!("opening access log {}", path.display()))?;
+        Ok(Self {
+            path,
+            format: config.format,
+            max_size: config.max_size,
+            keep: config.keep,
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Open a file for appending, returning it and its current size
+    fn open_file(path: &Path) -> io::Result<(File, u64)> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok((file, size))
+    }
+
+    /// Append a record to the file, first rotating it if necessary
+    pub fn write(&self, record: &AccessRecord) -> io::Result<()> {
+        let mut line = match self.format {
+            AccessLogFormat::Json => record.to_json(),
+            AccessLogFormat::Common => record.to_common(OffsetDateTime::now_utc()),
+        };
+        line.push('\n');
+        let len = line.len() as u64;
+
+        let mut file = self.file.lock().unwrap();
+        if self.max_size > 0 && file.1 > 0 && file.1 + len > self.max_size {
+            self.rotate()?;
+            *file = Self::open_file(&self.path)?;
+        }
+        file.0.write_all(line.as_bytes())?;
+        file.1 += len;
+        Ok(())
+    }
+
+    /// Move `path` to `path.1`, after moving each existing `path.N` to `path.N+1` and
+    /// dropping the oldest beyond `keep`
+    fn rotate(&self) -> io::Result<()> {
+        for n in (1..self.keep).rev() {
+            let from = self.rotated(n);
+            if from.exists() {
+                fs::rename(from, self.rotated(n + 1))?;
+            }
+        }
+        fs::rename(&self.path, self.rotated(1))
+    }
+
+    /// The path of the `n`th rotated file
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", n));
+        path.into()
+    }
+
+    /// Reopen the file at its path, creating it if it has been moved away
+    pub fn reopen(&self) -> io::Result<()> {
+        let mut file = self.file.lock().unwrap();
+        *file = Self::open_file(&self.path)?;
+        Ok(())
+    }
+
+    /// Write all access records to this file from now on
+    pub fn install(self: &Arc<Self>) {
+        *FILE.write().unwrap() = Some(self.clone());
+    }
+
+    /// Reopen the file whenever the process receives SIGUSR1
+    #[cfg(unix)]
+    pub fn reopen_on_sigusr1(self: &Arc<Self>) -> Result<()> {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut usr1 = signal(SignalKind::user_defined1()).context("installing SIGUSR1 handler")?;
+        let this = self.clone();
+        tokio::spawn(async move {
+            while usr1.recv().await.is_some() {
+                match this.reopen() {
+                    Ok(()) => log::info!("reopened access log {}", this.path.display()),
+                    Err(e) => {
+                        log::error!("reopening access log {} failed: {}", this.path.display(), e)
+                    }
+                }
+            }
+        });
+        Ok(())
+    }
+
+    /// Reopening on SIGUSR1 is not supported on this platform
+    #[cfg(not(unix))]
+    pub fn reopen_on_sigusr1(self: &Arc<Self>) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::temp_path;
+
+    #[test]
+    fn test_ids_unique() {
+        let a = AccessRecord::new(None);
+        let b = AccessRecord::new(None);
+        assert_ne!(a.id, b.id);
+    }
+
+    #[test]
+    fn test_to_json() {
+        let mut record = AccessRecord::new(Some("10.0.0.1:5555".parse().unwrap()));
+        record.protocol = Some(Protocol::Socks5);
+        record.client_cert = Some("alice".into());
+        record.target = Some("api.giphy.com:443".into());
+        record.served_by = Some("api-backup.giphy.com:443".into());
+        record.ja4 = Some("t13d1516h2_8daaf6152771_e5627efa2ab1".into());
+        record.bytes_up = 10;
+        record.bytes_down = 20;
+        record.reason = Reason::ClientClosed;
+        let value: serde_json::Value = serde_json::from_str(&record.to_json()).unwrap();
+        assert_eq!(value["id"], record.id);
+        assert_eq!(value["client"], "10.0.0.1:5555");
+        assert_eq!(value["client_cert"], "alice");
+        assert_eq!(value["protocol"], "socks5");
+        assert_eq!(value["target"], "api.giphy.com:443");
+        assert_eq!(value["served_by"], "api-backup.giphy.com:443");
+        assert_eq!(value["ja3"], serde_json::Value::Null);
+        assert_eq!(value["ja4"], "t13d1516h2_8daaf6152771_e5627efa2ab1");
+        assert_eq!(value["bytes_up"], 10);
+        assert_eq!(value["bytes_down"], 20);
+        assert_eq!(value["reason"], "client_closed");
+    }
+
+    #[test]
+    fn test_to_common() {
+        let mut record = AccessRecord::new(Some("10.0.0.1:5555".parse().unwrap()));
+        record.protocol = Some(Protocol::Http);
+        record.user = Some("alice".into());
+        record.target = Some("api.giphy.com:443".into());
+        record.bytes_down = 2326;
+        record.reason = Reason::BackendClosed;
+        let time = OffsetDateTime::from_unix_timestamp(971186136).unwrap();
+        assert_eq!(
+            record.to_common(time),
+            "10.0.0.1 - alice [10/Oct/2000:13:55:36 +0000] \"http api.giphy.com:443\" 200 2326"
+        );
+
+        let record = AccessRecord::new(None);
+        assert_eq!(
+            record.to_common(time),
+            "- - - [10/Oct/2000:13:55:36 +0000] \"- -\" 500 0"
+        );
+    }
+
+    #[test]
+    fn test_access_log_rotate() {
+        let path = temp_path("access");
+        let log = AccessLog::open(&AccessLogConfig {
+            path: Some(path.clone()),
+            format: AccessLogFormat::Json,
+            max_size: 500,
+            keep: 2,
+        })
+        .unwrap();
+        let record = AccessRecord::new(None);
+        let line_len = record.to_json().len() + 1;
+        for _ in 0..(500 / line_len) * 3 + 1 {
+            log.write(&record).unwrap();
+        }
+
+        // three files' worth were written, and the oldest was dropped
+        let len = |path: &Path| fs::read_to_string(path).unwrap().len();
+        assert_eq!(len(&path), line_len);
+        assert_eq!(len(&log.rotated(1)), (500 / line_len) * line_len);
+        assert_eq!(len(&log.rotated(2)), (500 / line_len) * line_len);
+        assert!(!log.rotated(3).exists());
+
+        // after the file is moved aside, reopening creates it again
+        fs::rename(&path, log.rotated(1)).unwrap();
+        log.reopen().unwrap();
+        log.write(&record).unwrap();
+        assert_eq!(len(&path), line_len);
+
+        for path in [path.clone(), log.rotated(1), log.rotated(2)] {
+            fs::remove_file(path).unwrap();
+        }
+    }
+
+    /// A layer that collects the fields recorded on spans, as strings
+    #[derive(Clone, Default)]
+    struct Fields(std::sync::Arc<std::sync::Mutex<Vec<(String, String)>>>);
+
+    impl field::Visit for Fields {
+        fn record_debug(&mut self, field: &field::Field, value: &dyn std::fmt::Debug) {
+            let value = format!("{:?}", value).trim_matches('"').to_owned();
+            self.0.lock().unwrap().push((field.name().into(), value));
+        }
+    }
+
+    impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for Fields {
+        fn on_new_span(
+            &self,
+            attrs: &tracing::span::Attributes<'_>,
+            _id: &tracing::span::Id,
+            _ctx: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            attrs.record(&mut self.clone());
+        }
+
+        fn on_record(
+            &self,
+            _id: &tracing::span::Id,
+            values: &tracing::span::Record<'_>,
+            _ctx: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            values.record(&mut self.clone());
+        }
+    }
+
+    #[test]
+    fn test_span() {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let fields = Fields::default();
+        let subscriber = tracing_subscriber::registry().with(fields.clone());
+        let mut record = AccessRecord::new(Some("10.0.0.1:5555".parse().unwrap()));
+        tracing::subscriber::with_default(subscriber, || {
+            let span = record.span();
+            record.protocol = Some(Protocol::Http);
+            record.target = Some("api.giphy.com:443".into());
+            record.bytes_up = 10;
+            record.reason = Reason::Idle;
+            record.record_span(&span);
+        });
+
+        let fields = fields.0.lock().unwrap();
+        let get = |name: &str| {
+            fields
+                .iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, v)| v.as_str())
+        };
+        assert_eq!(get("id"), Some(record.id.to_string().as_str()));
+        assert_eq!(get("client"), Some("10.0.0.1:5555"));
+        assert_eq!(get("protocol"), Some("http"));
+        assert_eq!(get("target"), Some("api.giphy.com:443"));
+        assert_eq!(get("user"), None);
+        assert_eq!(get("bytes_up"), Some("10"));
+        assert_eq!(get("reason"), Some("idle"));
+    }
+}
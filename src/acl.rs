@@ -0,0 +1,195 @@
+//! Access control for client addresses, applied as soon as a connection is accepted.
+//!
+//! Rules come from the `allow` and `deny` lists in the configuration, and optionally from
+//! a rules file, which can be reloaded without a restart.  A client in a denied network is
+//! refused even if it is also in an allowed network; a client in neither is allowed unless
+//! `deny_by_default` is set.
+
+use crate::config::AclConfig;
+use crate::reload;
+use anyhow::{bail, Context, Result};
+use ipnet::IpNet;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+/// The networks from which clients are allowed or denied
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Rules {
+    allow: Vec<IpNet>,
+    deny: Vec<IpNet>,
+}
+
+/// A client access control list whose rules file can be reloaded from disk.
+pub struct Acl {
+    config: AclConfig,
+    rules: RwLock<Rules>,
+}
+
+impl Acl {
+    /// Create an ACL from its configuration, reading the rules file if one is named.
+    pub fn new(config: &AclConfig) -> Result<Self> {
+        let rules = load_rules(config)?;
+        Ok(Self {
+            config: config.clone(),
+            rules: RwLock::new(rules),
+        })
+    }
+
+    /// Re-read the rules file.  On error, the ACL continues to use the previous rules.
+    pub fn reload(&self) -> Result<()> {
+        let rules = load_rules(&self.config)?;
+        *self.rules.write().unwrap() = rules;
+        Ok(())
+    }
+
+    /// May a client with the given address connect?  IPv4-mapped IPv6 addresses are
+    /// checked as IPv4 addresses.
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        let rules = self.rules.read().unwrap();
+        if rules.deny.iter().any(|net| net.contains(&ip)) {
+            return false;
+        }
+        if rules.allow.iter().any(|net| net.contains(&ip)) {
+            return true;
+        }
+        !self.config.deny_by_default
+    }
+
+    /// Reload the rules file whenever the process receives SIGHUP, in a background task.
+    pub fn reload_on_sighup(self: &Arc<Self>) -> Result<()> {
+        if self.config.file.is_none() {
+            return Ok(());
+        }
+        let this = self.clone();
+        reload::on_sighup("client ACL", move || this.reload())
+    }
+}
+
+/// Combine the rules in the configuration with those in the rules file, if any
+fn load_rules(config: &AclConfig) -> Result<Rules> {
+    let mut rules = Rules {
+        allow: config.allow.clone(),
+        deny: config.deny.clone(),
+    };
+    if let Some(path) = &config.file {
+        read_rules_file(path, &mut rules).with_context(|| format!("reading {}", path.display()))?;
+    }
+    Ok(rules)
+}
+
+/// Read a rules file, with one rule per line in the form `allow 10.0.0.0/8` or
+/// `deny 192.0.2.7/32`.  Blank lines and lines beginning with `#` are ignored.
+fn read_rules_file(path: &Path, rules: &mut Rules) -> Result<()> {
+    let content = std::fs::read_to_string(path)?;
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (action, net) = match line.split_once(char::is_whitespace) {
+            Some((action, net)) => (action, net.trim()),
+            None => bail!(
+                "line {}: expected `allow <network>` or `deny <network>`",
+                i + 1
+            ),
+        };
+        let net: IpNet = net
+            .parse()
+            .with_context(|| format!("line {}: invalid network {:?}", i + 1, net))?;
+        match action {
+            "allow" => rules.allow.push(net),
+            "deny" => rules.deny.push(net),
+            _ => bail!("line {}: unknown action {:?}", i + 1, action),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::temp_file;
+    use std::path::PathBuf;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_permits() {
+        let acl = Acl::new(&AclConfig {
+            allow: vec!["10.0.0.0/8".parse().unwrap()],
+            deny: vec!["10.0.0.0/24".parse().unwrap()],
+            ..AclConfig::default()
+        })
+        .unwrap();
+        assert!(acl.permits(ip("10.1.2.3")));
+        assert!(!acl.permits(ip("10.0.0.7")));
+        assert!(!acl.permits(ip("::ffff:10.0.0.7")));
+        assert!(acl.permits(ip("192.0.2.1")));
+
+        let acl = Acl::new(&AclConfig {
+            allow: vec!["10.0.0.0/8".parse().unwrap()],
+            deny_by_default: true,
+            ..AclConfig::default()
+        })
+        .unwrap();
+        assert!(acl.permits(ip("10.1.2.3")));
+        assert!(acl.permits(ip("::ffff:10.1.2.3")));
+        assert!(!acl.permits(ip("192.0.2.1")));
+        assert!(!acl.permits(ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn test_rules_file_and_reload() {
+        let path = temp_file(
+            "acl-rules",
+            "# office\nallow 192.0.2.0/24\n\ndeny  192.0.2.7/32\n",
+        );
+        let acl = Acl::new(&AclConfig {
+            deny_by_default: true,
+            file: Some(path.clone()),
+            ..AclConfig::default()
+        })
+        .unwrap();
+        assert!(acl.permits(ip("192.0.2.1")));
+        assert!(!acl.permits(ip("192.0.2.7")));
+        assert!(!acl.permits(ip("198.51.100.1")));
+
+        std::fs::write(&path, "allow 198.51.100.0/24\n").unwrap();
+        acl.reload().unwrap();
+        assert!(!acl.permits(ip("192.0.2.1")));
+        assert!(acl.permits(ip("198.51.100.1")));
+
+        // invalid rules leave the previous rules in place
+        std::fs::write(&path, "allow everyone\n").unwrap();
+        assert!(acl.reload().is_err());
+        assert!(acl.permits(ip("198.51.100.1")));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_rules_file_errors() {
+        for (name, content) in [
+            ("acl-no-network", "allow\n"),
+            ("acl-bad-action", "permit 10.0.0.0/8\n"),
+            ("acl-bad-network", "deny 10.0.0.0/33\n"),
+        ] {
+            let path = temp_file(name, content);
+            let config = AclConfig {
+                file: Some(path.clone()),
+                ..AclConfig::default()
+            };
+            assert!(Acl::new(&config).is_err(), "{:?} should fail", content);
+            std::fs::remove_file(&path).unwrap();
+        }
+        let config = AclConfig {
+            file: Some(PathBuf::from("/nonexistent/acl")),
+            ..AclConfig::default()
+        };
+        assert!(Acl::new(&config).is_err());
+    }
+}
@@ -0,0 +1,632 @@
+use crate::backend::Backend;
+use crate::capture;
+use crate::healthcheck::HealthCheck;
+use crate::http::Response;
+use crate::listen::{bind_default, spawn_acceptor};
+use crate::metrics::{self, METRICS};
+use crate::registry::REGISTRY;
+use crate::tap;
+use crate::telemetry::spawn;
+use crate::upgrade;
+use anyhow::{bail, Context, Result};
+use serde::Serialize;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Maximum size of a request head to the admin server
+const MAX_ADMIN_HEAD_SIZE: usize = 4096;
+
+/// Prefix of the path for a single tunnel, followed by its ID
+const TUNNELS_PREFIX: &str = "/tunnels/";
+
+/// Paths at which requests other than `GET` change the proxy's state, and so are refused
+/// if a browser sent them
+const STATE_CHANGING: &[&str] = &["/drain", "/tap", "/capture", "/upgrade"];
+
+/// The state reported by the `/healthz` and `/readyz` endpoints
+pub struct Health {
+    /// Set once the proxy's listeners are bound
+    listening: AtomicBool,
+    /// A check that must succeed within the given time for the proxy to be ready
+    probe: Option<(Box<dyn Probe>, Duration)>,
+    /// Background health checks of the backend, which must be passing for the proxy to be
+    /// ready
+    health_check: Option<Arc<HealthCheck>>,
+}
+
+impl Health {
+    /// Create a new health state, not yet ready
+    pub fn new() -> Self {
+        Self {
+            listening: AtomicBool::new(false),
+            probe: None,
+            health_check: None,
+        }
+    }
+
+    /// Also require a connection to the given host and port through the backend to
+    /// succeed within `timeout` for the proxy to be ready
+    pub fn with_backend_probe<B: Backend + 'static>(
+        mut self,
+        backend: Arc<B>,
+        host: String,
+        port: u16,
+        timeout: Duration,
+    ) -> Self {
+        self.probe = Some((
+            Box::new(BackendProbe {
+                backend,
+                host,
+                port,
+            }),
+            timeout,
+        ));
+        self
+    }
+
+    /// Also require the backend's health checks to be passing for the proxy to be ready
+    pub(crate) fn with_health_check(mut self, health_check: Arc<HealthCheck>) -> Self {
+        self.health_check = Some(health_check);
+        self
+    }
+
+    /// Record that the proxy's listeners are bound
+    pub fn set_listening(&self) {
+        self.listening.store(true, Ordering::Relaxed);
+    }
+
+    /// Check whether the proxy is ready for traffic, returning the reason if not
+    async fn ready(&self) -> Result<(), String> {
+        if !self.listening.load(Ordering::Relaxed) {
+            return Err("not listening".into());
+        }
+        if REGISTRY.draining() {
+            return Err("draining".into());
+        }
+        if let Some((probe, timeout)) = &self.probe {
+            match tokio::time::timeout(*timeout, probe.probe()).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => return Err(format!("backend probe failed: {}", e)),
+                Err(_) => return Err("backend probe timed out".into()),
+            }
+        }
+        if let Some(check) = &self.health_check {
+            if !check.healthy() {
+                return Err(format!(
+                    "backend unhealthy: {} consecutive failed health checks",
+                    check.failures()
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A readiness check
+#[async_trait::async_trait]
+trait Probe: Send + Sync {
+    async fn probe(&self) -> Result<()>;
+}
+
+/// A readiness check that connects to a host and port through a backend, then closes
+/// the connection
+struct BackendProbe<B: Backend> {
+    backend: Arc<B>,
+    host: String,
+    port: u16,
+}
+
+#[async_trait::async_trait]
+impl<B: Backend> Probe for BackendProbe<B> {
+    async fn probe(&self) -> Result<()> {
+        self.backend.connect(&self.host, self.port).await?;
+        Ok(())
+    }
+}
+
+/// Start the admin HTTP server on the given address, in a separate task.  Each client must
+/// send its request head within `head_timeout`.  Returns the bound address.
+///
+/// The admin server is deliberately tiny: it handles one request per connection and
+/// ignores request headers and bodies.  It serves metrics and health checks, lists and
+/// terminates tunnels, controls drain mode, traffic taps, and packet capture, and starts
+/// upgrades, so it should only be exposed to trusted networks.
+pub async fn start_admin(
+    addr: SocketAddr,
+    head_timeout: Duration,
+    health: Arc<Health>,
+) -> Result<SocketAddr> {
+    let listeners = upgrade::listeners(addr, || bind_default(addr))?;
+    let local_addr = listeners[0].local_addr()?;
+    log::info!("Admin server listening on {}", local_addr);
+
+    for listener in listeners {
+        let health = health.clone();
+        spawn_acceptor(
+            "admin-acceptor",
+            listener,
+            local_addr,
+            false,
+            false,
+            move |socket, _| {
+                let health = health.clone();
+                spawn("admin-request", async move {
+                    if let Err(e) = handle_admin(socket, head_timeout, &health).await {
+                        log::debug!("admin request failed: {:?}", e);
+                    }
+                });
+            },
+        );
+    }
+
+    Ok(local_addr)
+}
+
+/// Read a single request, respond to it, and close the connection.
+async fn handle_admin<S: AsyncRead + AsyncWrite + Unpin>(
+    mut socket: S,
+    head_timeout: Duration,
+    health: &Health,
+) -> Result<()> {
+    let head = tokio::time::timeout(head_timeout, read_admin_head(&mut socket))
+        .await
+        .map_err(|_| anyhow::anyhow!("timed out reading admin request"))??;
+    let request_line = head.lines().next().unwrap_or("");
+    let mut parts = request_line.split(' ');
+    let response = match (parts.next(), parts.next()) {
+        (Some(method), Some(path)) if from_browser(method, path, &head) => {
+            Response::error(403, "Forbidden", "cross-site requests are not allowed")
+        }
+        (Some(method), Some(path)) => route(method, path, health).await,
+        _ => Response::error(400, "Bad Request", "invalid request"),
+    };
+    socket.write_all(&response.to_bytes()).await?;
+    socket.shutdown().await?;
+    Ok(())
+}
+
+/// Read up to the end of the request head, returning it as a string.
+async fn read_admin_head<S: AsyncRead + Unpin>(socket: &mut S) -> Result<String> {
+    let mut buf = vec![0u8; MAX_ADMIN_HEAD_SIZE];
+    let mut buf_size = 0;
+    loop {
+        if buf_size == buf.len() {
+            bail!("admin request head too large");
+        }
+        let n = socket
+            .read(&mut buf[buf_size..])
+            .await
+            .context("reading admin request")?;
+        if n == 0 {
+            bail!("client hung up while writing admin request");
+        }
+        buf_size += n;
+        if buf[..buf_size].windows(4).any(|w| w == b"\r\n\r\n") {
+            return Ok(String::from_utf8_lossy(&buf[..buf_size]).into_owned());
+        }
+    }
+}
+
+/// Whether a request would change the proxy's state and was sent by a web page.  Browsers
+/// send a cross-site form submission as a POST without asking first, so any page could
+/// otherwise make one; they mark it with an `Origin` header, which tools such as `curl`
+/// do not send.
+fn from_browser(method: &str, path: &str, head: &str) -> bool {
+    let path = path.split('?').next().unwrap_or(path);
+    method != "GET"
+        && STATE_CHANGING.contains(&path)
+        && head.lines().skip(1).any(|line| {
+            line.split_once(':')
+                .is_some_and(|(name, _)| name.trim().eq_ignore_ascii_case("origin"))
+        })
+}
+
+/// Generate the response for the given request.
+async fn route(method: &str, path: &str, health: &Health) -> Response {
+    let (path, query) = match path.split_once('?') {
+        Some((path, query)) => (path, query),
+        None => (path, ""),
+    };
+    match (method, path) {
+        ("GET", "/metrics") => Response::new(200, "OK")
+            .body("text/plain; version=0.0.4", metrics::render())
+            .header("Connection", "close"),
+        ("GET", "/healthz") => text("ok\n"),
+        ("GET", "/readyz") => match health.ready().await {
+            Ok(()) => text("ready\n"),
+            Err(reason) => Response::error(503, "Service Unavailable", reason),
+        },
+        ("GET", "/tunnels") => json(&REGISTRY.list()),
+        ("GET", "/destinations") => destinations(query),
+        ("GET", "/drain") => drain_status(),
+        ("POST", "/drain") => {
+            REGISTRY.set_draining(true);
+            drain_status()
+        }
+        ("DELETE", "/drain") => {
+            REGISTRY.set_draining(false);
+            drain_status()
+        }
+        ("GET", "/tap") => tap_status(),
+        ("POST", "/tap") => {
+            tap::set_enabled(true);
+            tap_status()
+        }
+        ("DELETE", "/tap") => {
+            tap::set_enabled(false);
+            tap_status()
+        }
+        ("GET", "/capture") => capture_status(),
+        ("POST", "/capture") => {
+            capture::set_running(true);
+            capture_status()
+        }
+        ("DELETE", "/capture") => {
+            capture::set_running(false);
+            capture_status()
+        }
+        ("POST", "/upgrade") => match upgrade::upgrade().await {
+            Ok(pid) => json(&serde_json::json!({ "pid": pid })),
+            Err(e) => Response::error(500, "Internal Server Error", format!("{:#}", e)),
+        },
+        ("DELETE", path) if path.starts_with(TUNNELS_PREFIX) => {
+            terminate(&path[TUNNELS_PREFIX.len()..])
+        }
+        (_, path) if path.starts_with(TUNNELS_PREFIX) => method_not_allowed("DELETE"),
+        ("GET", _) => Response::error(404, "Not Found", "not found"),
+        (_, "/drain") => method_not_allowed("GET, POST, DELETE"),
+        (_, "/tap") => method_not_allowed("GET, POST, DELETE"),
+        (_, "/capture") => method_not_allowed("GET, POST, DELETE"),
+        (_, "/upgrade") => method_not_allowed("POST"),
+        _ => method_not_allowed("GET"),
+    }
+}
+
+/// Terminate the tunnel with the given ID
+fn terminate(id: &str) -> Response {
+    match id.parse() {
+        Ok(id) if REGISTRY.terminate(id) => json(&serde_json::json!({ "terminated": id })),
+        _ => Response::error(404, "Not Found", "no such tunnel"),
+    }
+}
+
+/// The per-destination counts, limited to the destinations with the most traffic if the
+/// query has `top=N`
+fn destinations(query: &str) -> Response {
+    let top = query
+        .split('&')
+        .find_map(|param| param.strip_prefix("top="))
+        .map(str::parse::<usize>);
+    match top {
+        None => json(&METRICS.destinations.top(usize::MAX)),
+        Some(Ok(n)) => json(&METRICS.destinations.top(n)),
+        Some(Err(_)) => Response::error(400, "Bad Request", "invalid top"),
+    }
+}
+
+/// A 200 response with a plain-text body
+fn text(body: &str) -> Response {
+    Response::new(200, "OK")
+        .body("text/plain", body)
+        .header("Connection", "close")
+}
+
+/// A 200 response with a JSON body
+fn json<T: Serialize>(value: &T) -> Response {
+    let body = serde_json::to_string(value).expect("admin responses are always serializable");
+    Response::new(200, "OK")
+        .body("application/json", body)
+        .header("Connection", "close")
+}
+
+/// The current drain mode, as JSON
+fn drain_status() -> Response {
+    json(&serde_json::json!({ "draining": REGISTRY.draining() }))
+}
+
+/// Whether traffic taps are enabled, as JSON
+fn tap_status() -> Response {
+    match tap::enabled() {
+        Some(enabled) => json(&serde_json::json!({ "enabled": enabled })),
+        None => Response::error(404, "Not Found", "taps are not configured"),
+    }
+}
+
+/// Whether packet capture is running, and the file it is writing, as JSON
+fn capture_status() -> Response {
+    match capture::status() {
+        Some(file) => json(&serde_json::json!({ "running": file.is_some(), "file": file })),
+        None => Response::error(404, "Not Found", "capture is not configured"),
+    }
+}
+
+fn method_not_allowed(allow: &str) -> Response {
+    Response::error(405, "Method Not Allowed", "method not allowed").header("Allow", allow)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::backend::SingleHostBackend;
+    use crate::config::{BackendConfig, HealthCheckConfig};
+    use tokio::io::{duplex, DuplexStream};
+    use tokio::net::TcpListener;
+
+    async fn request(req: &'static [u8]) -> String {
+        request_health(req, Arc::new(Health::new())).await
+    }
+
+    async fn request_health(req: &'static [u8], health: Arc<Health>) -> String {
+        let (mut client, server) = duplex(65536);
+        let server_task =
+            tokio::spawn(
+                async move { handle_admin(server, Duration::from_secs(10), &health).await },
+            );
+        client.write_all(req).await.unwrap();
+        let mut buf = vec![];
+        client.read_to_end(&mut buf).await.unwrap();
+        server_task.await.unwrap().unwrap();
+        String::from_utf8(buf).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_metrics() {
+        let response = request(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("giphyproxy_connections_accepted_total "));
+    }
+
+    #[tokio::test]
+    async fn test_not_found() {
+        let response = request(b"GET /nope HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_bad_method() {
+        let response = request(b"POST /metrics HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+        assert!(response.contains("\r\nAllow: GET\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_tunnels() {
+        let mut record = crate::access::AccessRecord::new(None);
+        record.target = Some("api.giphy.com:443".into());
+        let registration = REGISTRY.register(&record);
+
+        let response = request(b"GET /tunnels HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Content-Type: application/json\r\n"));
+        let body = response.split("\r\n\r\n").nth(1).unwrap();
+        let tunnels: serde_json::Value = serde_json::from_str(body).unwrap();
+        let tunnel = tunnels
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|t| t["id"] == record.id)
+            .unwrap();
+        assert_eq!(tunnel["target"], "api.giphy.com:443");
+        assert_eq!(tunnel["bytes_up"], 0);
+
+        let delete = format!("DELETE /tunnels/{} HTTP/1.1\r\n\r\n", record.id);
+        let delete: &'static [u8] = Box::leak(delete.into_bytes().into_boxed_slice());
+        let response = request(delete).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        registration.terminate.notified().await;
+
+        drop(registration);
+        let response = request(delete).await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        let response = request(b"DELETE /tunnels/nope HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        let response = request(b"GET /tunnels/1 HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_upgrade_method() {
+        // a real upgrade would start another process, so this only checks the method
+        let response = request(b"PUT /upgrade HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+        assert!(response.contains("Allow: POST\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_upgrade_from_browser() {
+        let response =
+            request(b"POST /upgrade HTTP/1.1\r\nOrigin: https://evil.example\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 403 Forbidden\r\n"));
+        let response = request(b"POST /upgrade?x HTTP/1.1\r\norigin: null\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 403 Forbidden\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_destinations() {
+        METRICS
+            .destinations
+            .record("admin-test.example.com:443", false, 7, 9);
+
+        let response = request(b"GET /destinations HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        let body = response.split("\r\n\r\n").nth(1).unwrap();
+        let destinations: serde_json::Value = serde_json::from_str(body).unwrap();
+        let destination = destinations
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|d| d["destination"] == "admin-test.example.com:443")
+            .unwrap();
+        assert_eq!(destination["connections"], 1);
+        assert_eq!(destination["bytes_down"], 9);
+
+        let response = request(b"GET /destinations?top=0 HTTP/1.1\r\n\r\n").await;
+        assert!(response.ends_with("\r\n\r\n[]"));
+        let response = request(b"GET /destinations?top=x HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_drain_status() {
+        // toggling drain mode would affect other tests' listeners, so this only checks
+        // the status and method handling
+        let response = request(b"GET /drain HTTP/1.1\r\n\r\n").await;
+        assert!(response.ends_with("{\"draining\":false}"));
+        let response = request(b"PUT /drain HTTP/1.1\r\n\r\n").await;
+        assert!(response.contains("\r\nAllow: GET, POST, DELETE\r\n"));
+        let response =
+            request(b"POST /drain HTTP/1.1\r\nOrigin: https://evil.example\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 403 Forbidden\r\n"));
+        assert!(!REGISTRY.draining());
+    }
+
+    #[tokio::test]
+    async fn test_head_timeout() {
+        let (mut client, server) = duplex(65536);
+        let health = Health::new();
+        client
+            .write_all(b"GET /metrics HTTP/1.1\r\n")
+            .await
+            .unwrap();
+        let result = handle_admin(server, Duration::from_millis(50), &health).await;
+        assert!(result.unwrap_err().to_string().contains("timed out"));
+    }
+
+    #[tokio::test]
+    async fn test_tap_status() {
+        // installing taps or capture would affect other tests' tunnels, so no tests install
+        // them
+        let response = request(b"GET /tap HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        let response = request(b"POST /tap HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        let response = request(b"POST /tap HTTP/1.1\r\nOrigin: https://evil.example\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 403 Forbidden\r\n"));
+        let response = request(b"PUT /tap HTTP/1.1\r\n\r\n").await;
+        assert!(response.contains("\r\nAllow: GET, POST, DELETE\r\n"));
+        let response = request(b"GET /capture HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        let response =
+            request(b"POST /capture HTTP/1.1\r\nOrigin: https://evil.example\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 403 Forbidden\r\n"));
+    }
+
+    /// A backend whose connections never complete
+    struct StalledBackend;
+
+    #[async_trait::async_trait]
+    impl Backend for StalledBackend {
+        type Socket = DuplexStream;
+
+        async fn connect(&self, _host: &str, _port: u16) -> crate::error::Result<Self::Socket> {
+            std::future::pending().await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_healthz() {
+        let response = request(b"GET /healthz HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\nok\n"));
+    }
+
+    #[tokio::test]
+    async fn test_readyz() {
+        const READYZ: &[u8] = b"GET /readyz HTTP/1.1\r\n\r\n";
+        let health = Arc::new(Health::new());
+        let response = request_health(READYZ, health.clone()).await;
+        assert!(response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        assert!(response.ends_with("not listening\n"));
+
+        health.set_listening();
+        let response = request_health(READYZ, health).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("ready\n"));
+    }
+
+    #[tokio::test]
+    async fn test_readyz_health_check() {
+        const READYZ: &[u8] = b"GET /readyz HTTP/1.1\r\n\r\n";
+        let check = Arc::new(HealthCheck::new(HealthCheckConfig {
+            unhealthy_threshold: 1,
+            ..HealthCheckConfig::default()
+        }));
+        let health = Arc::new(Health::new().with_health_check(check.clone()));
+        health.set_listening();
+        let response = request_health(READYZ, health.clone()).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+
+        // once checks of a closed port fail, the proxy is not ready
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+        let config = BackendConfig {
+            host: "127.0.0.1".into(),
+            port,
+            ..BackendConfig::default()
+        };
+        check
+            .start(SingleHostBackend::new("127.0.0.1", port), &config)
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let response = request_health(READYZ, health).await;
+        assert!(response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        assert!(response.ends_with("backend unhealthy: 1 consecutive failed health checks\n"));
+    }
+
+    #[tokio::test]
+    async fn test_readyz_probe() {
+        const READYZ: &[u8] = b"GET /readyz HTTP/1.1\r\n\r\n";
+        let timeout = Duration::from_millis(100);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let backend = Arc::new(SingleHostBackend::new("127.0.0.1", port));
+        let health = Health::new().with_backend_probe(backend, "127.0.0.1".into(), port, timeout);
+        health.set_listening();
+        let response = request_health(READYZ, Arc::new(health)).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+
+        // once the listener is closed, the probe connection is refused
+        drop(listener);
+        let backend = Arc::new(SingleHostBackend::new("127.0.0.1", port));
+        let health = Health::new().with_backend_probe(backend, "127.0.0.1".into(), port, timeout);
+        health.set_listening();
+        let response = request_health(READYZ, Arc::new(health)).await;
+        assert!(response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        assert!(response.contains("backend probe failed"));
+
+        let backend = Arc::new(StalledBackend);
+        let health = Health::new().with_backend_probe(backend, "127.0.0.1".into(), port, timeout);
+        health.set_listening();
+        let response = request_health(READYZ, Arc::new(health)).await;
+        assert!(response.ends_with("backend probe timed out\n"));
+    }
+
+    #[tokio::test]
+    async fn test_start_admin() {
+        let addr = start_admin(
+            "127.0.0.1:0".parse().unwrap(),
+            Duration::from_secs(10),
+            Arc::new(Health::new()),
+        )
+        .await
+        .unwrap();
+        let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(b"GET /metrics HTTP/1.0\r\n\r\n")
+            .await
+            .unwrap();
+        let mut buf = vec![];
+        client.read_to_end(&mut buf).await.unwrap();
+        assert!(buf.starts_with(b"HTTP/1.1 200 OK\r\n"));
+    }
+}
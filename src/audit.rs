@@ -0,0 +1,318 @@
+//! An audit trail of connections, kept in a SQLite database.  When a connection ends, its
+//! access record is added to the database, and records older than the retention period
+//! are pruned hourly.  The database is written from a dedicated thread, so connections
+//! never wait for it.
+
+use crate::access::{serialized_name, AccessRecord};
+use crate::config::AuditConfig;
+use anyhow::{Context, Result};
+use rusqlite::{params, Connection};
+use serde::Serialize;
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// How often records older than the retention period are pruned
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// The audit trail, if one has been installed with `Audit::install`
+static AUDIT: RwLock<Option<Arc<Audit>>> = RwLock::new(None);
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS connections (
+        timestamp INTEGER NOT NULL,
+        id INTEGER NOT NULL,
+        client TEXT,
+        user TEXT,
+        destination TEXT,
+        protocol TEXT,
+        bytes_up INTEGER NOT NULL,
+        bytes_down INTEGER NOT NULL,
+        duration_ms INTEGER NOT NULL,
+        outcome TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS connections_timestamp ON connections (timestamp);
+";
+
+/// A record of one connection in the audit trail
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AuditEntry {
+    /// When the connection ended, in seconds since the Unix epoch
+    pub timestamp: i64,
+    /// The connection's ID, the same as the `id` in its access log record
+    pub id: u64,
+    pub client: Option<String>,
+    pub user: Option<String>,
+    /// The requested `host:port`
+    pub destination: Option<String>,
+    pub protocol: Option<String>,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+    pub duration_ms: u64,
+    /// Why the connection ended, as the `reason` in its access log record
+    pub outcome: String,
+}
+
+impl AuditEntry {
+    /// An entry for a finished connection, ending at `timestamp`
+    fn new(record: &AccessRecord, timestamp: i64) -> Self {
+        Self {
+            timestamp,
+            id: record.id,
+            client: record.client.map(|c| c.to_string()),
+            user: record.user.clone(),
+            destination: record.target.clone(),
+            protocol: record.protocol.and_then(serialized_name),
+            bytes_up: record.bytes_up,
+            bytes_down: record.bytes_down,
+            duration_ms: record.duration_ms,
+            outcome: serialized_name(record.reason).unwrap_or_default(),
+        }
+    }
+}
+
+/// Which entries to return from `AuditDb::query`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AuditQuery {
+    /// Only entries at or after this time, in seconds since the Unix epoch
+    pub since: Option<i64>,
+    /// Only entries for this destination
+    pub destination: Option<String>,
+    /// The maximum number of entries, the most recent ones being returned
+    pub limit: Option<usize>,
+}
+
+/// The audit database
+pub struct AuditDb {
+    conn: Connection,
+}
+
+impl AuditDb {
+    /// Open the database, creating it if necessary
+    pub fn open(path: &Path) -> Result<Self> {
+        let conn = Connection::open(path)
+            .with_context(|| format!("opening audit database {}", path.display()))?;
+        conn.execute_batch(SCHEMA)
+            .context("creating audit database schema")?;
+        Ok(Self { conn })
+    }
+
+    /// Add entries to the database, in a single transaction
+    pub fn insert(&mut self, entries: &[AuditEntry]) -> Result<()> {
+        let tx = self.conn.transaction()?;
+        {
+            let mut insert = tx.prepare_cached(
+                "INSERT INTO connections (timestamp, id, client, user, destination, protocol,
+                    bytes_up, bytes_down, duration_ms, outcome)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+            )?;
+            for e in entries {
+                insert.execute(params![
+                    e.timestamp,
+                    e.id as i64,
+                    e.client,
+                    e.user,
+                    e.destination,
+                    e.protocol,
+                    e.bytes_up as i64,
+                    e.bytes_down as i64,
+                    e.duration_ms as i64,
+                    e.outcome,
+                ])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Remove entries from before `before`, in seconds since the Unix epoch, returning the
+    /// number removed
+    pub fn prune(&self, before: i64) -> Result<usize> {
+        Ok(self
+            .conn
+            .execute("DELETE FROM connections WHERE timestamp < ?1", [before])?)
+    }
+
+    /// Find the entries matching the query, oldest first
+    pub fn query(&self, query: &AuditQuery) -> Result<Vec<AuditEntry>> {
+        let mut select = self.conn.prepare(
+            "SELECT * FROM (
+                SELECT timestamp, id, client, user, destination, protocol, bytes_up,
+                    bytes_down, duration_ms, outcome
+                FROM connections
+                WHERE (?1 IS NULL OR timestamp >= ?1) AND (?2 IS NULL OR destination = ?2)
+                ORDER BY timestamp DESC, rowid DESC
+                LIMIT ?3
+             ) ORDER BY timestamp, id",
+        )?;
+        let limit = query.limit.map(|l| l as i64).unwrap_or(-1);
+        let rows = select.query_map(params![query.since, query.destination, limit], |row| {
+            Ok(AuditEntry {
+                timestamp: row.get(0)?,
+                id: row.get::<_, i64>(1)? as u64,
+                client: row.get(2)?,
+                user: row.get(3)?,
+                destination: row.get(4)?,
+                protocol: row.get(5)?,
+                bytes_up: row.get::<_, i64>(6)? as u64,
+                bytes_down: row.get::<_, i64>(7)? as u64,
+                duration_ms: row.get::<_, i64>(8)? as u64,
+                outcome: row.get(9)?,
+            })
+        })?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+}
+
+/// The current time, in seconds since the Unix epoch
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+/// The audit trail, recording each finished connection once installed
+pub struct Audit {
+    sender: Mutex<Sender<AuditEntry>>,
+}
+
+impl Audit {
+    /// Open the configured database, which must have a path, and start the thread that
+    /// writes to it
+    pub fn start(config: &AuditConfig) -> Result<Self> {
+        let path = config
+            .database
+            .as_ref()
+            .context("no audit database configured")?;
+        let db = AuditDb::open(path)?;
+        let (sender, receiver) = mpsc::channel();
+        let retention = config.retention;
+        std::thread::Builder::new()
+            .name("audit".into())
+            .spawn(move || write_entries(db, receiver, retention))
+            .context("starting audit thread")?;
+        Ok(Self {
+            sender: Mutex::new(sender),
+        })
+    }
+
+    /// Record a finished connection
+    pub fn record(&self, record: &AccessRecord) {
+        let entry = AuditEntry::new(record, now());
+        // the thread only exits if the process is exiting
+        let _ = self.sender.lock().unwrap().send(entry);
+    }
+
+    /// Record all finished connections in this audit trail from now on
+    pub fn install(self: &Arc<Self>) {
+        *AUDIT.write().unwrap() = Some(self.clone());
+    }
+}
+
+/// Record a finished connection in the installed audit trail, if any
+pub(crate) fn record(record: &AccessRecord) {
+    if let Some(audit) = AUDIT.read().unwrap().as_ref() {
+        audit.record(record);
+    }
+}
+
+/// Write entries to the database as they arrive, in batches, pruning old entries every
+/// `PRUNE_INTERVAL`
+fn write_entries(mut db: AuditDb, receiver: Receiver<AuditEntry>, retention: Duration) {
+    let mut next_prune = Instant::now();
+    loop {
+        if Instant::now() >= next_prune {
+            match db.prune(now() - retention.as_secs() as i64) {
+                Ok(0) => {}
+                Ok(n) => log::info!("pruned {} audit records", n),
+                Err(e) => log::error!("pruning audit records: {:#}", e),
+            }
+            next_prune = Instant::now() + PRUNE_INTERVAL;
+        }
+        match receiver.recv_timeout(next_prune.saturating_duration_since(Instant::now())) {
+            Ok(entry) => {
+                let mut entries = vec![entry];
+                entries.extend(receiver.try_iter());
+                if let Err(e) = db.insert(&entries) {
+                    log::error!("writing {} audit records: {:#}", entries.len(), e);
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::access::Reason;
+    use crate::testing::temp_path;
+
+    fn entry(timestamp: i64, destination: &str) -> AuditEntry {
+        let mut record = AccessRecord::new(Some("10.0.0.1:5555".parse().unwrap()));
+        record.target = Some(destination.into());
+        record.bytes_up = 10;
+        record.reason = Reason::ClientClosed;
+        AuditEntry::new(&record, timestamp)
+    }
+
+    #[test]
+    fn test_query_and_prune() {
+        let mut db = AuditDb::open(Path::new(":memory:")).unwrap();
+        let entries = vec![
+            entry(100, "a.example.com:443"),
+            entry(200, "b.example.com:443"),
+            entry(300, "a.example.com:443"),
+        ];
+        db.insert(&entries).unwrap();
+
+        assert_eq!(db.query(&AuditQuery::default()).unwrap(), entries);
+        assert_eq!(entries[0].client.as_deref(), Some("10.0.0.1:5555"));
+        assert_eq!(entries[0].outcome, "client_closed");
+        let query = AuditQuery {
+            since: Some(150),
+            ..AuditQuery::default()
+        };
+        assert_eq!(db.query(&query).unwrap(), entries[1..]);
+        let query = AuditQuery {
+            destination: Some("a.example.com:443".into()),
+            limit: Some(1),
+            ..AuditQuery::default()
+        };
+        assert_eq!(db.query(&query).unwrap(), entries[2..]);
+
+        assert_eq!(db.prune(200).unwrap(), 1);
+        assert_eq!(db.query(&AuditQuery::default()).unwrap(), entries[1..]);
+    }
+
+    #[test]
+    fn test_audit_thread() {
+        let path = temp_path("audit");
+        let audit = Audit::start(&AuditConfig {
+            database: Some(path.clone()),
+            ..AuditConfig::default()
+        })
+        .unwrap();
+        let mut record = AccessRecord::new(None);
+        record.target = Some("api.giphy.com:443".into());
+        audit.record(&record);
+
+        // the entry is written by the thread
+        let db = AuditDb::open(&path).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let entries = loop {
+            let entries = db.query(&AuditQuery::default()).unwrap();
+            if !entries.is_empty() || Instant::now() > deadline {
+                break entries;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        };
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].id, record.id);
+        drop(audit);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,223 @@
+//! Client authentication against an htpasswd-style file.
+//!
+//! bcrypt is deliberately slow, so bcrypt hashes are checked on a blocking thread rather
+//! than on the async runtime, and credentials found valid are remembered so that each
+//! connection from an authenticated client does not pay for the check again.
+
+use anyhow::{bail, Context, Result};
+use base64::Engine;
+use ring::digest::{digest, SHA256};
+use sha1::{Digest, Sha1};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::Mutex;
+
+/// The most valid credentials an `Htpasswd` remembers; once full, it forgets them all
+const MAX_VERIFIED: usize = 1024;
+
+/// A stored password, in one of the formats produced by Apache's `htpasswd`
+#[derive(Debug, Clone, PartialEq)]
+enum PasswordHash {
+    /// `htpasswd -B`
+    Bcrypt(String),
+    /// `htpasswd -s`; the base64-encoded SHA-1 digest
+    Sha1(Vec<u8>),
+    /// `htpasswd -p`
+    Plain(String),
+}
+
+impl PasswordHash {
+    fn parse(s: &str) -> Result<Self> {
+        if s.starts_with("$2a$") || s.starts_with("$2b$") || s.starts_with("$2y$") {
+            Ok(Self::Bcrypt(s.to_owned()))
+        } else if let Some(digest) = s.strip_prefix("{SHA}") {
+            Ok(Self::Sha1(
+                base64::engine::general_purpose::STANDARD.decode(digest)?,
+            ))
+        } else if s.starts_with("$apr1$") || s.starts_with("$1$") || s.starts_with("$5$") {
+            bail!("unsupported password hash format; use bcrypt (htpasswd -B)")
+        } else {
+            Ok(Self::Plain(s.to_owned()))
+        }
+    }
+
+    /// Check a password, on a blocking thread for bcrypt
+    async fn verify(&self, password: &str) -> bool {
+        match self {
+            Self::Bcrypt(hash) => {
+                let (password, hash) = (password.to_owned(), hash.clone());
+                tokio::task::spawn_blocking(move || bcrypt::verify(password, &hash))
+                    .await
+                    .is_ok_and(|result| result.unwrap_or(false))
+            }
+            Self::Sha1(digest) => constant_time_eq(&Sha1::digest(password.as_bytes()), digest),
+            Self::Plain(stored) => constant_time_eq(password.as_bytes(), stored.as_bytes()),
+        }
+    }
+}
+
+/// Compare two byte strings without exiting early at the first difference
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// A set of users and their passwords, read from a file of `user:hash` lines as written
+/// by Apache's `htpasswd`.  Blank lines and lines beginning with `#` are ignored.
+#[derive(Debug)]
+pub struct Htpasswd {
+    users: HashMap<String, PasswordHash>,
+    /// SHA-256 digests of credentials already found valid, from `credentials_digest`
+    verified: Mutex<HashSet<Vec<u8>>>,
+}
+
+impl Htpasswd {
+    /// Read an htpasswd file
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("reading htpasswd file {}", path.display()))?;
+        Self::parse(&content).with_context(|| format!("parsing htpasswd file {}", path.display()))
+    }
+
+    /// Parse the contents of an htpasswd file
+    pub fn parse(content: &str) -> Result<Self> {
+        let mut users = HashMap::new();
+        for (i, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (user, hash) = line
+                .split_once(':')
+                .with_context(|| format!("line {}: expected user:hash", i + 1))?;
+            let hash = PasswordHash::parse(hash).with_context(|| format!("line {}", i + 1))?;
+            users.insert(user.to_owned(), hash);
+        }
+        Ok(Self {
+            users,
+            verified: Mutex::new(HashSet::new()),
+        })
+    }
+
+    /// Check a username and password
+    pub async fn verify(&self, user: &str, password: &str) -> bool {
+        let hash = match self.users.get(user) {
+            Some(hash) => hash,
+            None => return false,
+        };
+        let key = credentials_digest(user, password);
+        if self.verified.lock().unwrap().contains(&key) {
+            return true;
+        }
+        if !hash.verify(password).await {
+            return false;
+        }
+        let mut verified = self.verified.lock().unwrap();
+        if verified.len() >= MAX_VERIFIED {
+            verified.clear();
+        }
+        verified.insert(key);
+        true
+    }
+
+    /// Check the value of a `Proxy-Authorization` header, returning the authenticated
+    /// username if the credentials are valid.
+    pub async fn verify_header(&self, value: &str) -> Option<String> {
+        let (user, password) = parse_basic(value)?;
+        if self.verify(&user, &password).await {
+            Some(user)
+        } else {
+            None
+        }
+    }
+}
+
+/// A digest identifying a username and password, so that valid credentials can be
+/// remembered without keeping the password itself
+fn credentials_digest(user: &str, password: &str) -> Vec<u8> {
+    let credentials = format!("{}\0{}", user, password);
+    digest(&SHA256, credentials.as_bytes()).as_ref().to_vec()
+}
+
+/// Parse the value of a Basic `Authorization` or `Proxy-Authorization` header into a
+/// username and password
+fn parse_basic(value: &str) -> Option<(String, String)> {
+    let (scheme, credentials) = value.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = base64::engine::general_purpose::STANDARD
+        .decode(credentials.trim())
+        .ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let (user, password) = decoded.split_once(':')?;
+    Some((user.to_owned(), password.to_owned()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// An htpasswd file giving each of bob, sam, and pat the password "secret", using
+    /// bcrypt, SHA-1, and plaintext respectively
+    fn htpasswd() -> Htpasswd {
+        let bcrypt = bcrypt::hash("secret", 4).unwrap();
+        Htpasswd::parse(&format!(
+            "# comment\n\nbob:{}\nsam:{{SHA}}5en6G6MezRroT3XKqkdPOmY/BfQ=\npat:secret\n",
+            bcrypt
+        ))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_verify() {
+        let htpasswd = htpasswd();
+        for user in &["bob", "sam", "pat"] {
+            assert!(htpasswd.verify(user, "secret").await, "{}", user);
+            assert!(!htpasswd.verify(user, "wrong").await, "{}", user);
+        }
+        assert!(!htpasswd.verify("nobody", "secret").await);
+    }
+
+    #[tokio::test]
+    async fn test_verify_remembers_valid_credentials() {
+        let htpasswd = htpasswd();
+        assert!(htpasswd.verified.lock().unwrap().is_empty());
+        assert!(!htpasswd.verify("bob", "wrong").await);
+        assert!(htpasswd.verified.lock().unwrap().is_empty());
+        assert!(htpasswd.verify("bob", "secret").await);
+        assert!(htpasswd
+            .verified
+            .lock()
+            .unwrap()
+            .contains(&credentials_digest("bob", "secret")));
+        assert!(htpasswd.verify("bob", "secret").await);
+        assert!(!htpasswd.verify("bob", "wrong").await);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(Htpasswd::parse("no-colon\n").is_err());
+        assert!(Htpasswd::parse("al:$apr1$abc$def\n").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_verify_header() {
+        let htpasswd = htpasswd();
+        // pat:secret
+        assert_eq!(
+            htpasswd.verify_header("Basic cGF0OnNlY3JldA==").await,
+            Some("pat".into())
+        );
+        assert_eq!(
+            htpasswd.verify_header("basic cGF0OnNlY3JldA==").await,
+            Some("pat".into())
+        );
+        // pat:wrong
+        assert_eq!(htpasswd.verify_header("Basic cGF0Ondyb25n").await, None);
+        assert_eq!(
+            htpasswd.verify_header("Bearer cGF0OnNlY3JldA==").await,
+            None
+        );
+        assert_eq!(htpasswd.verify_header("Basic !!!").await, None);
+    }
+}
@@ -1,30 +1,482 @@
-use anyhow::{bail, Result};
-use tokio::io::{AsyncRead, AsyncWrite};
+use crate::balance::LoadBalancedBackend;
+use crate::chaos::ChaosBackend;
+use crate::client;
+use crate::config::{
+    BackendConfig, BackendKind, RecordingMode, RotationConfig, SocketConfig, UpstreamConfig,
+};
+use crate::connection::ConnectionInfo;
+use crate::dns::Resolver;
+use crate::error::{ProxyError, Result};
+use crate::failover::FailoverBackend;
+use crate::http::authority;
+use crate::layer::Ports;
+use crate::metrics::METRICS;
+use crate::recording::{RecordingBackend, ReplayBackend};
+use crate::rotation::Rotation;
+use crate::sockopt;
+use anyhow::{bail, Context};
+use base64::Engine;
+use ipnet::IpNet;
+use std::convert::TryFrom;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{duplex, split, AsyncRead, AsyncWrite};
 use tokio::net::TcpStream;
+use tokio::task::JoinSet;
+use tokio::time::{sleep, sleep_until, timeout};
 
 /// A backend represents a service to which this app can proxy.
+///
+/// A single backend is shared by all connections, so implementations must be `Send`
+/// and `Sync`.
 #[async_trait::async_trait]
-pub trait Backend {
+pub trait Backend: Send + Sync {
     type Socket: AsyncRead + AsyncWrite + Unpin + Send + 'static;
 
     /// Connect to the backend using the given host and port, and return a connected
     /// socket.
     async fn connect(&self, host: &str, port: u16) -> Result<Self::Socket>;
+
+    /// Connect on behalf of the client described by `info`.  Most backends do not need to
+    /// know about the client, and use the default implementation, which calls `connect`;
+    /// backends that wrap another backend should pass `info` through.
+    async fn connect_for(
+        &self,
+        _info: &ConnectionInfo,
+        host: &str,
+        port: u16,
+    ) -> Result<Self::Socket> {
+        self.connect(host, port).await
+    }
+}
+
+#[async_trait::async_trait]
+impl<B: Backend> Backend for Arc<B> {
+    type Socket = B::Socket;
+
+    async fn connect(&self, host: &str, port: u16) -> Result<Self::Socket> {
+        self.as_ref().connect(host, port).await
+    }
+
+    async fn connect_for(
+        &self,
+        info: &ConnectionInfo,
+        host: &str,
+        port: u16,
+    ) -> Result<Self::Socket> {
+        self.as_ref().connect_for(info, host, port).await
+    }
+}
+
+/// The error returned from `Backend::connect`, as `ProxyError::Disallowed`, when the
+/// requested host and port are not permitted.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Disallowed {
+    pub host: String,
+    pub port: u16,
+}
+
+impl fmt::Display for Disallowed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Connection to disallowed host/port {}",
+            authority(&self.host, self.port)
+        )
+    }
+}
+
+impl std::error::Error for Disallowed {}
+
+/// Check that host and port are allowed, returning a `Disallowed` error if not
+pub(crate) fn check_allowed(allowed: bool, host: &str, port: u16) -> Result<()> {
+    if !allowed {
+        return Err(Disallowed {
+            host: host.into(),
+            port,
+        }
+        .into());
+    }
+    Ok(())
+}
+
+/// The IPv4 address embedded in an IPv4-mapped (`::ffff:0:0/96`), NAT64 (`64:ff9b::/96`)
+/// or 6to4 (`2002::/16`) IPv6 address
+fn embedded_ipv4(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    let [a, b, c, d, e, f, g, h, i, j, k, l, m, n, o, p] = ip.octets();
+    match (a, b, c, d, e, f, g, h, i, j, k, l) {
+        (0x00, 0x64, 0xff, 0x9b, 0, 0, 0, 0, 0, 0, 0, 0) => Some(Ipv4Addr::new(m, n, o, p)),
+        (0x20, 0x02, ..) => Some(Ipv4Addr::new(c, d, e, f)),
+        _ => ip.to_ipv4_mapped(),
+    }
+}
+
+/// Is the given address within any of the blocked networks?  IPv6 addresses embedding an
+/// IPv4 address are also checked by that address, so that a blocked IPv4 network cannot
+/// be reached through an IPv4-mapped address or a NAT64 or 6to4 gateway.
+fn is_blocked(ip: IpAddr, blocked: &[IpNet]) -> bool {
+    let embedded = match ip {
+        IpAddr::V6(v6) => embedded_ipv4(v6).map(IpAddr::V4),
+        IpAddr::V4(_) => None,
+    };
+    std::iter::once(ip)
+        .chain(embedded)
+        .any(|ip| blocked.iter().any(|net| net.contains(&ip)))
+}
+
+/// Resolve the given host, and return the addresses that are not blocked.  If the host
+/// only resolves to blocked addresses, this returns a `Disallowed` error.
+pub(crate) async fn resolve(
+    resolver: &Resolver,
+    host: &str,
+    port: u16,
+    blocked: &[IpNet],
+) -> Result<Vec<SocketAddr>> {
+    let (refused, vetted): (Vec<_>, Vec<_>) = resolver
+        .resolve(host)
+        .await
+        .map_err(|e| ProxyError::upstream(authority(host, port), format!("{:#}", e)))?
+        .into_iter()
+        .map(|ip| SocketAddr::new(ip, port))
+        .partition(|a| is_blocked(a.ip(), blocked));
+    if !refused.is_empty() {
+        log::debug!("{} resolved to blocked addresses {:?}", host, refused);
+    }
+    check_allowed(!vetted.is_empty(), host, port)?;
+    Ok(vetted)
+}
+
+/// Where the addresses for a backend connection come from
+#[derive(Clone, Copy)]
+enum Addresses<'a> {
+    /// Resolve the host for each connection
+    Resolve(&'a Resolver),
+    /// Take them from a rotation, which re-resolves the host with the given resolver, and
+    /// is told which addresses fail to connect
+    Rotation(&'a Arc<Rotation>, &'a Arc<Resolver>),
+}
+
+/// Resolve the given host, and connect to one of its addresses that is not blocked.  Only
+/// the vetted addresses are used, so a DNS response that changes between the check and
+/// the connection cannot redirect it.
+///
+/// The addresses are rotated left by `rotation` before connecting, so that retries can
+/// begin with different addresses.  The connected socket has the options in `socket`.
+async fn resolve_and_connect(
+    addresses: Addresses<'_>,
+    host: &str,
+    port: u16,
+    blocked: &[IpNet],
+    rotation: usize,
+    socket: &SocketConfig,
+) -> Result<TcpStream> {
+    let (mut addrs, health) = match addresses {
+        Addresses::Resolve(resolver) => (resolve(resolver, host, port, blocked).await?, None),
+        Addresses::Rotation(addresses, resolver) => (
+            addresses.addresses(resolver, host, port, blocked).await?,
+            Some(addresses.as_ref()),
+        ),
+    };
+    let len = addrs.len();
+    addrs.rotate_left(rotation % len);
+    happy_eyeballs(addrs, socket, health)
+        .await
+        .map_err(|error| ProxyError::UpstreamConnect {
+            target: authority(host, port),
+            error,
+        })
+}
+
+/// How backend connections are made over TCP: hostnames are resolved with a resolver,
+/// addresses in blocked networks are refused, and each socket is given the configured
+/// options.  Every backend connecting over TCP does so through a dialer.
+#[derive(Clone)]
+pub struct Dialer {
+    resolver: Arc<Resolver>,
+    blocked: Vec<IpNet>,
+    socket: SocketConfig,
+}
+
+impl Dialer {
+    /// Create a dialer using the system resolver, with no blocked networks and default
+    /// socket options
+    pub fn new() -> Self {
+        Self {
+            resolver: Arc::new(Resolver::system()),
+            blocked: vec![],
+            socket: SocketConfig::default(),
+        }
+    }
+
+    /// Create a dialer from a backend configuration
+    pub fn from_config(config: &BackendConfig) -> Self {
+        Self::new()
+            .with_blocked_networks(config.blocked_networks.clone())
+            .with_resolver(Arc::new(Resolver::from_config(&config.dns)))
+            .with_socket_options(config.socket.clone())
+    }
+
+    /// Refuse connections to addresses in any of the given networks.
+    pub fn with_blocked_networks(mut self, blocked: Vec<IpNet>) -> Self {
+        self.blocked = blocked;
+        self
+    }
+
+    /// Resolve hostnames with the given resolver, which may be shared with other
+    /// backends.
+    pub fn with_resolver(mut self, resolver: Arc<Resolver>) -> Self {
+        self.resolver = resolver;
+        self
+    }
+
+    /// Set the given TCP options on backend connections.
+    pub fn with_socket_options(mut self, socket: SocketConfig) -> Self {
+        self.socket = socket;
+        self
+    }
+
+    /// Connect to the given host and port, as for `resolve_and_connect`, taking the
+    /// addresses from `rotation` if given.
+    pub(crate) async fn connect(
+        &self,
+        rotation: Option<&Arc<Rotation>>,
+        host: &str,
+        port: u16,
+        offset: usize,
+    ) -> Result<TcpStream> {
+        let addresses = match rotation {
+            Some(rotation) => Addresses::Rotation(rotation, &self.resolver),
+            None => Addresses::Resolve(&self.resolver),
+        };
+        resolve_and_connect(addresses, host, port, &self.blocked, offset, &self.socket).await
+    }
+}
+
+impl Default for Dialer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Time to wait for a connection attempt before starting the next one, from RFC 8305
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Order addresses for connection attempts, alternating between address families,
+/// beginning with the family of the first address (RFC 8305, section 4)
+fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let first_v6 = addrs.first().is_some_and(|a| a.is_ipv6());
+    let (mut first, mut second): (Vec<_>, Vec<_>) =
+        addrs.into_iter().partition(|a| a.is_ipv6() == first_v6);
+    let mut result = Vec::with_capacity(first.len() + second.len());
+    first.reverse();
+    second.reverse();
+    loop {
+        match (first.pop(), second.pop()) {
+            (None, None) => return result,
+            (a, b) => result.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+/// Connect to one of the given addresses, racing attempts as described in RFC 8305
+/// ("Happy Eyeballs"): attempts start in `interleave` order, each one starting as soon as
+/// any attempt fails, or `CONNECTION_ATTEMPT_DELAY` after the previous one started.  The
+/// first successful connection is returned, and the remaining attempts are abandoned.
+/// The outcome of each completed attempt is reported to `health`, if given.
+async fn happy_eyeballs(
+    addrs: Vec<SocketAddr>,
+    socket: &SocketConfig,
+    health: Option<&Rotation>,
+) -> io::Result<TcpStream> {
+    let mut remaining = interleave(addrs).into_iter();
+    let mut attempts = JoinSet::new();
+    let attempt = |addr: SocketAddr| {
+        let connect = sockopt::connect(addr, socket.clone());
+        async move { (addr, connect.await) }
+    };
+    let mut last_err = None;
+    // when the next attempt starts, unless an attempt fails before then
+    let mut next_attempt = Instant::now();
+    loop {
+        if attempts.is_empty() && remaining.len() == 0 {
+            return Err(last_err.unwrap_or_else(|| io::Error::other("no addresses")));
+        }
+        tokio::select! {
+            result = attempts.join_next(), if !attempts.is_empty() => {
+                match result.expect("attempts is not empty") {
+                    Ok((addr, Ok(socket))) => {
+                        if let Some(health) = health {
+                            health.succeeded(addr);
+                        }
+                        return Ok(socket);
+                    }
+                    Ok((addr, Err(e))) => {
+                        if let Some(health) = health {
+                            health.failed(addr);
+                        }
+                        last_err = Some(e);
+                    }
+                    Err(e) => last_err = Some(io::Error::other(e)),
+                }
+                next_attempt = Instant::now();
+            }
+            _ = sleep_until(next_attempt.into()), if remaining.len() > 0 => {
+                let addr = remaining.next().expect("remaining is not empty");
+                attempts.spawn(attempt(addr));
+                next_attempt = Instant::now() + CONNECTION_ATTEMPT_DELAY;
+            }
+        }
+    }
+}
+
+/// How failed backend connections are retried
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    /// Number of retries after the first attempt
+    pub retries: u32,
+    /// Delay before the first retry, doubling for each retry after that
+    pub backoff: Duration,
+    /// Begin each retry with a different one of the resolved addresses
+    pub rotate_addresses: bool,
+}
+
+impl RetryPolicy {
+    /// A policy that never retries
+    pub fn none() -> Self {
+        Self {
+            retries: 0,
+            backoff: Duration::ZERO,
+            rotate_addresses: false,
+        }
+    }
+
+    /// The policy given in a backend configuration
+    pub fn from_config(config: &BackendConfig) -> Self {
+        Self {
+            retries: config.connect_retries,
+            backoff: config.retry_backoff,
+            rotate_addresses: config.retry_rotate_addresses,
+        }
+    }
+
+    /// The delay before the given retry, counting from 0
+    pub(crate) fn delay(&self, retry: u32) -> Duration {
+        self.backoff.saturating_mul(1 << retry.min(16))
+    }
+}
+
+/// Connect to the given host and port over TCP with `dialer`, recording metrics for the
+/// attempt.  Each attempt is limited to `connect_timeout`, if given, and failed attempts
+/// are retried according to `retry`; disallowed destinations are never retried.
+async fn connect_tcp(
+    dialer: &Dialer,
+    rotation: Option<&Arc<Rotation>>,
+    host: &str,
+    port: u16,
+    connect_timeout: Option<Duration>,
+    retry: &RetryPolicy,
+) -> Result<TcpStream> {
+    let start = Instant::now();
+    let mut attempt = 0;
+    let result = loop {
+        let offset = if retry.rotate_addresses { attempt } else { 0 };
+        let connect = dialer.connect(rotation, host, port, offset as usize);
+        let result = match connect_timeout {
+            Some(t) => timeout(t, connect)
+                .await
+                .map_err(|elapsed| ProxyError::UpstreamConnect {
+                    target: authority(host, port),
+                    error: elapsed.into(),
+                })
+                .and_then(|r| r),
+            None => connect.await,
+        };
+        match result {
+            Err(e) if attempt < retry.retries && !matches!(e, ProxyError::Disallowed(_)) => {
+                let delay = retry.delay(attempt);
+                log::debug!(
+                    "connecting to {} failed, retrying in {:?}: {:#}",
+                    authority(host, port),
+                    delay,
+                    e
+                );
+                METRICS.backend_connect_retries.inc();
+                sleep(delay).await;
+                attempt += 1;
+            }
+            result => break result,
+        }
+    };
+    match &result {
+        Ok(_) => METRICS.backend_connect_latency.observe(start.elapsed()),
+        Err(e) if !matches!(e, ProxyError::Disallowed(_)) => METRICS.backend_connect_failures.inc(),
+        Err(_) => {}
+    }
+    result
 }
 
 /// A backend which only allows connections to a single host/port
 pub struct SingleHostBackend {
     host: String,
     port: u16,
+    connect_timeout: Option<Duration>,
+    dialer: Dialer,
+    retry: RetryPolicy,
+    rotation: Option<Arc<Rotation>>,
 }
 
 impl SingleHostBackend {
+    /// Create a backend allowing connections only to the given host and port
     pub fn new<H: Into<String>>(host: H, port: u16) -> Self {
         Self {
             host: host.into(),
             port,
+            connect_timeout: None,
+            dialer: Dialer::new(),
+            retry: RetryPolicy::none(),
+            rotation: None,
+        }
+    }
+
+    /// Create a backend from its configuration
+    pub fn from_config(config: &BackendConfig) -> Self {
+        let backend = Self::new(config.host.clone(), config.port)
+            .with_connect_timeout(config.connect_timeout)
+            .with_dialer(Dialer::from_config(config))
+            .with_retry(RetryPolicy::from_config(config));
+        match &config.rotation {
+            Some(rotation) => backend.with_rotation(rotation.clone()),
+            None => backend,
         }
     }
+
+    /// Fail connections that do not complete within the given duration.
+    pub fn with_connect_timeout(mut self, connect_timeout: Duration) -> Self {
+        self.connect_timeout = Some(connect_timeout);
+        self
+    }
+
+    /// Make connections with the given dialer.
+    pub fn with_dialer(mut self, dialer: Dialer) -> Self {
+        self.dialer = dialer;
+        self
+    }
+
+    /// Retry failed connections according to the given policy.
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Re-resolve the host periodically, and rotate connections across its addresses.
+    pub fn with_rotation(mut self, config: RotationConfig) -> Self {
+        self.rotation = Some(Arc::new(Rotation::new(config)));
+        self
+    }
 }
 
 #[async_trait::async_trait]
@@ -32,13 +484,534 @@ impl Backend for SingleHostBackend {
     type Socket = TcpStream;
 
     async fn connect(&self, host: &str, port: u16) -> Result<Self::Socket> {
-        if host != self.host || port != self.port {
-            // TODO: test this
-            bail!("Connection to disallowed host/port");
-        }
+        let allowed = host == self.host && port == self.port;
+        check_allowed(allowed, host, port)?;
 
         // connect to giphy and return the resulting stream
-        Ok(TcpStream::connect(format!("{}:{}", host, port)).await?)
+        connect_tcp(
+            &self.dialer,
+            self.rotation.as_ref(),
+            host,
+            port,
+            self.connect_timeout,
+            &self.retry,
+        )
+        .await
+    }
+}
+
+/// A pattern matching hostnames: either an exact name, or `*.` followed by a domain,
+/// which matches any name within that domain (but not the domain itself).  Matching is
+/// case-insensitive.
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(try_from = "String")]
+pub enum HostPattern {
+    Exact(String),
+    Subdomain(String),
+}
+
+impl HostPattern {
+    /// Does this pattern match the given host?
+    pub fn matches(&self, host: &str) -> bool {
+        let host = host.to_ascii_lowercase();
+        match self {
+            HostPattern::Exact(h) => &host == h,
+            HostPattern::Subdomain(domain) => {
+                host.len() > domain.len() + 1
+                    && host.ends_with(domain.as_str())
+                    && host.as_bytes()[host.len() - domain.len() - 1] == b'.'
+            }
+        }
+    }
+}
+
+impl FromStr for HostPattern {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let s = s.to_ascii_lowercase();
+        let pattern = match s.strip_prefix("*.") {
+            Some(domain) => HostPattern::Subdomain(domain.into()),
+            None => HostPattern::Exact(s.clone()),
+        };
+        let (HostPattern::Exact(name) | HostPattern::Subdomain(name)) = &pattern;
+        if name.is_empty() || name.contains('*') {
+            bail!("invalid host pattern {:?}", s);
+        }
+        Ok(pattern)
+    }
+}
+
+impl TryFrom<String> for HostPattern {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> anyhow::Result<Self> {
+        s.parse()
+    }
+}
+
+/// A set of ports: `*` for any port, or a comma-separated list of ports and inclusive
+/// ranges such as `80,443,8000-8100`.
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(try_from = "String")]
+pub enum PortSet {
+    Any,
+    Ranges(Vec<(u16, u16)>),
+}
+
+impl PortSet {
+    /// Does this set contain the given port?
+    pub fn contains(&self, port: u16) -> bool {
+        match self {
+            PortSet::Any => true,
+            PortSet::Ranges(ranges) => ranges.iter().any(|(lo, hi)| *lo <= port && port <= *hi),
+        }
+    }
+}
+
+impl FromStr for PortSet {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        if s == "*" {
+            return Ok(PortSet::Any);
+        }
+        let mut ranges = vec![];
+        for part in s.split(',') {
+            let range = match part.split_once('-') {
+                Some((lo, hi)) => (lo.parse()?, hi.parse()?),
+                None => {
+                    let p = part.parse()?;
+                    (p, p)
+                }
+            };
+            if range.0 > range.1 {
+                bail!("invalid port range {:?}", part);
+            }
+            ranges.push(range);
+        }
+        Ok(PortSet::Ranges(ranges))
+    }
+}
+
+impl TryFrom<String> for PortSet {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> anyhow::Result<Self> {
+        s.parse()
+    }
+}
+
+/// An entry in an allowlist, written `host:ports`, where `host` is a `HostPattern` and
+/// `ports` is a `PortSet`; for example, `*.giphy.com:443`.
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(try_from = "String")]
+pub struct AllowEntry {
+    pub host: HostPattern,
+    pub ports: PortSet,
+}
+
+impl AllowEntry {
+    /// Does this entry allow the given host and port?
+    pub fn allows(&self, host: &str, port: u16) -> bool {
+        self.ports.contains(port) && self.host.matches(host)
+    }
+}
+
+impl FromStr for AllowEntry {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (host, ports) = s
+            .rsplit_once(':')
+            .with_context(|| format!("allowlist entry {:?} has no port", s))?;
+        // IPv6 addresses are bracketed, and must be normalized to match the form produced by
+        // the request parsers
+        let host = match host.strip_prefix('[').and_then(|h| h.strip_suffix(']')) {
+            Some(addr) => addr
+                .parse::<Ipv6Addr>()
+                .with_context(|| format!("invalid IPv6 address in allowlist entry {:?}", s))?
+                .to_string(),
+            None => host.to_owned(),
+        };
+        let host = host
+            .parse()
+            .with_context(|| format!("invalid host in allowlist entry {:?}", s))?;
+        let ports = ports
+            .parse()
+            .with_context(|| format!("invalid ports in allowlist entry {:?}", s))?;
+        Ok(AllowEntry { host, ports })
+    }
+}
+
+impl TryFrom<String> for AllowEntry {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> anyhow::Result<Self> {
+        s.parse()
+    }
+}
+
+/// A backend endpoint, written `host:port`; for example, `api-backup.giphy.com:443`.  IPv6
+/// addresses are bracketed.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, serde::Deserialize)]
+#[serde(try_from = "String")]
+pub struct Endpoint {
+    pub host: String,
+    pub port: u16,
+}
+
+impl FromStr for Endpoint {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (host, port) = s
+            .rsplit_once(':')
+            .with_context(|| format!("backend endpoint {:?} has no port", s))?;
+        let host = match host.strip_prefix('[').and_then(|h| h.strip_suffix(']')) {
+            Some(addr) => addr
+                .parse::<Ipv6Addr>()
+                .with_context(|| format!("invalid IPv6 address in backend endpoint {:?}", s))?
+                .to_string(),
+            None => host.to_owned(),
+        };
+        if host.is_empty() {
+            anyhow::bail!("backend endpoint {:?} has no host", s);
+        }
+        let port = port
+            .parse()
+            .with_context(|| format!("invalid port in backend endpoint {:?}", s))?;
+        Ok(Endpoint { host, port })
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", authority(&self.host, self.port))
+    }
+}
+
+impl TryFrom<String> for Endpoint {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> anyhow::Result<Self> {
+        s.parse()
+    }
+}
+
+/// The allowed destinations given in a backend configuration.  If the `allow` list is
+/// empty, only the configured `host` and `port` are allowed.
+pub(crate) fn allow_entries(config: &BackendConfig) -> Vec<AllowEntry> {
+    if config.allow.is_empty() {
+        vec![AllowEntry {
+            host: HostPattern::Exact(config.host.to_ascii_lowercase()),
+            ports: PortSet::Ranges(vec![(config.port, config.port)]),
+        }]
+    } else {
+        config.allow.clone()
+    }
+}
+
+/// A backend which allows connections to any host/port matching one of a list of
+/// `AllowEntry`s.
+pub struct AllowListBackend {
+    entries: Vec<AllowEntry>,
+    connect_timeout: Option<Duration>,
+    dialer: Dialer,
+    retry: RetryPolicy,
+}
+
+impl AllowListBackend {
+    /// Create a backend allowing connections matching any of the given entries
+    pub fn new(entries: Vec<AllowEntry>) -> Self {
+        Self {
+            entries,
+            connect_timeout: None,
+            dialer: Dialer::new(),
+            retry: RetryPolicy::none(),
+        }
+    }
+
+    /// Create a backend from its configuration.  If the `allow` list is empty, only the
+    /// configured `host` and `port` are allowed.
+    pub fn from_config(config: &BackendConfig) -> Self {
+        Self::new(allow_entries(config))
+            .with_connect_timeout(config.connect_timeout)
+            .with_dialer(Dialer::from_config(config))
+            .with_retry(RetryPolicy::from_config(config))
+    }
+
+    /// Fail connections that do not complete within the given duration.
+    pub fn with_connect_timeout(mut self, connect_timeout: Duration) -> Self {
+        self.connect_timeout = Some(connect_timeout);
+        self
+    }
+
+    /// Make connections with the given dialer.  Its blocked networks are refused even if
+    /// the destination matches an entry.
+    pub fn with_dialer(mut self, dialer: Dialer) -> Self {
+        self.dialer = dialer;
+        self
+    }
+
+    /// Retry failed connections according to the given policy.
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+}
+
+#[async_trait::async_trait]
+impl Backend for AllowListBackend {
+    type Socket = TcpStream;
+
+    async fn connect(&self, host: &str, port: u16) -> Result<Self::Socket> {
+        let allowed = self.entries.iter().any(|e| e.allows(host, port));
+        check_allowed(allowed, host, port)?;
+        connect_tcp(
+            &self.dialer,
+            None,
+            host,
+            port,
+            self.connect_timeout,
+            &self.retry,
+        )
+        .await
+    }
+}
+
+/// A backend which connects to destinations through a parent HTTP proxy, by sending it
+/// a CONNECT request.  Destinations are checked against a list of `AllowEntry`s, as for
+/// `AllowListBackend`, before contacting the parent.
+pub struct ChainedBackend {
+    /// The parent proxy's `host:port`
+    proxy: String,
+    /// The value of the `Proxy-Authorization` header, if any
+    authorization: Option<String>,
+    entries: Vec<AllowEntry>,
+    connect_timeout: Option<Duration>,
+    dialer: Dialer,
+}
+
+impl ChainedBackend {
+    /// Create a backend connecting through the proxy at `proxy` (`host:port`), allowing
+    /// connections matching any of the given entries
+    pub fn new<P: Into<String>>(proxy: P, entries: Vec<AllowEntry>) -> Self {
+        Self {
+            proxy: proxy.into(),
+            authorization: None,
+            entries,
+            connect_timeout: None,
+            dialer: Dialer::new(),
+        }
+    }
+
+    /// Create a backend from its configuration, using the allowed destinations from
+    /// `config` and the parent proxy from `upstream`.
+    pub fn from_config(config: &BackendConfig, upstream: &UpstreamConfig) -> Self {
+        let mut backend = Self::new(upstream.address.clone(), allow_entries(config))
+            .with_connect_timeout(config.connect_timeout)
+            .with_dialer(Dialer::new().with_socket_options(config.socket.clone()));
+        if let Some(username) = &upstream.username {
+            backend = backend.with_basic_auth(username, upstream.password.as_deref().unwrap_or(""));
+        }
+        backend
+    }
+
+    /// Authenticate to the parent proxy with the given username and password.
+    pub fn with_basic_auth(mut self, username: &str, password: &str) -> Self {
+        let credentials =
+            base64::engine::general_purpose::STANDARD.encode(format!("{}:{}", username, password));
+        self.authorization = Some(format!("Basic {}", credentials));
+        self
+    }
+
+    /// Fail connections that do not complete within the given duration.  This includes
+    /// the time taken by the parent proxy to connect to the destination.
+    pub fn with_connect_timeout(mut self, connect_timeout: Duration) -> Self {
+        self.connect_timeout = Some(connect_timeout);
+        self
+    }
+
+    /// Connect to the parent proxy with the given dialer.
+    pub fn with_dialer(mut self, dialer: Dialer) -> Self {
+        self.dialer = dialer;
+        self
+    }
+
+    /// Connect to the parent proxy and ask it to connect to host and port
+    async fn connect_via_proxy(&self, host: &str, port: u16) -> io::Result<TcpStream> {
+        let parent: Endpoint = self.proxy.parse().map_err(io::Error::other)?;
+        let mut socket = match self
+            .dialer
+            .connect(None, &parent.host, parent.port, 0)
+            .await
+        {
+            Ok(socket) => socket,
+            Err(ProxyError::UpstreamConnect { error, .. }) => return Err(error),
+            Err(e) => return Err(io::Error::other(e)),
+        };
+
+        let status =
+            client::connect(&mut socket, host, port, self.authorization.as_deref()).await?;
+        match status {
+            Some(200..=299) => Ok(socket),
+            Some(407) => Err(io::Error::other("parent proxy requires authentication")),
+            Some(status) => Err(io::Error::other(format!(
+                "parent proxy responded with status {}",
+                status
+            ))),
+            None => Err(io::Error::other("invalid response from parent proxy")),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Backend for ChainedBackend {
+    type Socket = TcpStream;
+
+    async fn connect(&self, host: &str, port: u16) -> Result<Self::Socket> {
+        let allowed = self.entries.iter().any(|e| e.allows(host, port));
+        check_allowed(allowed, host, port)?;
+
+        let start = Instant::now();
+        let result = match self.connect_timeout {
+            Some(t) => timeout(t, self.connect_via_proxy(host, port))
+                .await
+                .map_err(io::Error::from)
+                .and_then(|r| r),
+            None => self.connect_via_proxy(host, port).await,
+        }
+        .map_err(|error| ProxyError::UpstreamConnect {
+            target: format!("{} via parent proxy {}", authority(host, port), self.proxy),
+            error,
+        });
+        match result {
+            Ok(_) => METRICS.backend_connect_latency.observe(start.elapsed()),
+            Err(_) => METRICS.backend_connect_failures.inc(),
+        }
+        result
+    }
+}
+
+/// A socket returned by a `BoxBackend`: any socket a backend may return
+pub trait BackendSocket: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> BackendSocket for T {}
+
+/// A backend whose type is chosen at runtime, such as from the configuration.  Any backend
+/// can be boxed, with its sockets boxed in turn.
+pub struct BoxBackend(Box<dyn Backend<Socket = Box<dyn BackendSocket>>>);
+
+impl BoxBackend {
+    /// Box the given backend
+    pub fn new<B: Backend + 'static>(backend: B) -> Self {
+        Self(Box::new(Boxed(backend)))
+    }
+
+    /// Create the backend selected by `config.kind`, recording its connections or
+    /// replacing it with their recordings if `config.recording` is set, and injecting
+    /// faults into its connections if `config.chaos` is set
+    pub fn from_config(config: &BackendConfig) -> Self {
+        let backend = match &config.recording {
+            Some(recording) if recording.mode == RecordingMode::Replay => {
+                Self::new(ReplayBackend::new(&recording.dir))
+            }
+            Some(recording) => {
+                Self::new(RecordingBackend::new(Self::select(config), &recording.dir))
+            }
+            None => Self::select(config),
+        };
+        match &config.chaos {
+            Some(chaos) => Self::new(ChaosBackend::new(backend, chaos)),
+            None => backend,
+        }
+    }
+
+    /// Create the backend selected by `config.kind`, allowing only `config.allowed_ports`
+    fn select(config: &BackendConfig) -> Self {
+        let backend = match (config.kind, config.load_balance.as_ref(), &config.upstream) {
+            (Some(BackendKind::SingleHost), Some(balance), _) => {
+                Self::new(LoadBalancedBackend::from_config(config, balance))
+            }
+            (Some(BackendKind::SingleHost), None, _) if !config.failover.is_empty() => {
+                Self::new(FailoverBackend::from_config(config))
+            }
+            (Some(BackendKind::SingleHost), None, _) => {
+                Self::new(SingleHostBackend::from_config(config))
+            }
+            (Some(BackendKind::Mock), _, _) => Self::new(MockBackend),
+            (Some(BackendKind::Chained) | None, _, Some(upstream)) => {
+                Self::new(ChainedBackend::from_config(config, upstream))
+            }
+            // a chained backend without an upstream is refused by `Config::validate`
+            _ => Self::new(AllowListBackend::from_config(config)),
+        };
+        match &config.allowed_ports {
+            PortSet::Any => backend,
+            ports => Self::new(Ports::new(backend, ports.clone())),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Backend for BoxBackend {
+    type Socket = Box<dyn BackendSocket>;
+
+    async fn connect(&self, host: &str, port: u16) -> Result<Self::Socket> {
+        self.0.connect(host, port).await
+    }
+
+    async fn connect_for(
+        &self,
+        info: &ConnectionInfo,
+        host: &str,
+        port: u16,
+    ) -> Result<Self::Socket> {
+        self.0.connect_for(info, host, port).await
+    }
+}
+
+/// A backend wrapper boxing the sockets of the backend it wraps
+struct Boxed<B: Backend>(B);
+
+#[async_trait::async_trait]
+impl<B: Backend> Backend for Boxed<B> {
+    type Socket = Box<dyn BackendSocket>;
+
+    async fn connect(&self, host: &str, port: u16) -> Result<Self::Socket> {
+        Ok(Box::new(self.0.connect(host, port).await?))
+    }
+
+    async fn connect_for(
+        &self,
+        info: &ConnectionInfo,
+        host: &str,
+        port: u16,
+    ) -> Result<Self::Socket> {
+        Ok(Box::new(self.0.connect_for(info, host, port).await?))
+    }
+}
+
+/// Size of the in-memory buffer for each `MockBackend` connection
+const MOCK_BUFFER: usize = 16384;
+
+/// A backend which allows connections to any host and port, each to an in-memory server
+/// that echoes back whatever it is sent.  This is useful for testing clients and
+/// configuration without network access.
+pub struct MockBackend;
+
+#[async_trait::async_trait]
+impl Backend for MockBackend {
+    type Socket = tokio::io::DuplexStream;
+
+    async fn connect(&self, _host: &str, _port: u16) -> Result<Self::Socket> {
+        let (socket, server) = duplex(MOCK_BUFFER);
+        tokio::spawn(async move {
+            let (mut read, mut write) = split(server);
+            let _ = tokio::io::copy(&mut read, &mut write).await;
+        });
+        Ok(socket)
     }
 }
 
@@ -51,10 +1024,350 @@ mod test {
     #[tokio::test]
     async fn test_connect_check() {
         let backend = SingleHostBackend::new("good-host", 443);
-        assert!(backend.connect("other-host", 443).await.is_err());
+        let err = backend.connect("other-host", 443).await.unwrap_err();
+        assert!(matches!(
+            err,
+            ProxyError::Disallowed(Disallowed { ref host, port: 443 }) if host == "other-host"
+        ));
         assert!(backend.connect("good-host", 80).await.is_err());
     }
 
+    #[test]
+    fn test_allow_entry_parse() {
+        assert_eq!(
+            "*.Giphy.com:443".parse::<AllowEntry>().unwrap(),
+            AllowEntry {
+                host: HostPattern::Subdomain("giphy.com".into()),
+                ports: PortSet::Ranges(vec![(443, 443)]),
+            }
+        );
+        assert_eq!(
+            "example.com:80,8000-8100".parse::<AllowEntry>().unwrap(),
+            AllowEntry {
+                host: HostPattern::Exact("example.com".into()),
+                ports: PortSet::Ranges(vec![(80, 80), (8000, 8100)]),
+            }
+        );
+        assert_eq!(
+            "example.com:*".parse::<AllowEntry>().unwrap().ports,
+            PortSet::Any
+        );
+        assert!("example.com".parse::<AllowEntry>().is_err());
+        assert!("example.com:".parse::<AllowEntry>().is_err());
+        assert!("example.com:99999".parse::<AllowEntry>().is_err());
+        assert!("example.com:443-80".parse::<AllowEntry>().is_err());
+        assert!("*:443".parse::<AllowEntry>().is_err());
+        assert_eq!(
+            "[2606:2800:0::1]:443".parse::<AllowEntry>().unwrap().host,
+            HostPattern::Exact("2606:2800::1".into())
+        );
+        assert!("[2606:::1]:443".parse::<AllowEntry>().is_err());
+        assert!("foo.*.com:443".parse::<AllowEntry>().is_err());
+    }
+
+    #[test]
+    fn test_allow_entry_matching() {
+        let entry: AllowEntry = "*.giphy.com:80,443".parse().unwrap();
+        assert!(entry.allows("api.giphy.com", 443));
+        assert!(entry.allows("media0.API.giphy.com", 80));
+        assert!(!entry.allows("giphy.com", 443));
+        assert!(!entry.allows("notgiphy.com", 443));
+        assert!(!entry.allows("api.giphy.com", 8080));
+
+        let entry: AllowEntry = "api.giphy.com:443".parse().unwrap();
+        assert!(entry.allows("API.giphy.com", 443));
+        assert!(!entry.allows("x.api.giphy.com", 443));
+    }
+
+    #[test]
+    fn test_parse_endpoint() {
+        let endpoint: Endpoint = "api-backup.giphy.com:443".parse().unwrap();
+        assert_eq!(endpoint.host, "api-backup.giphy.com");
+        assert_eq!(endpoint.port, 443);
+        let endpoint: Endpoint = "[2a04:4e42:0::514]:8443".parse().unwrap();
+        assert_eq!(endpoint.host, "2a04:4e42::514");
+        assert_eq!(endpoint.port, 8443);
+        assert_eq!(endpoint.to_string(), "[2a04:4e42::514]:8443");
+        assert!("api-backup.giphy.com".parse::<Endpoint>().is_err());
+        assert!(":443".parse::<Endpoint>().is_err());
+        assert!("api-backup.giphy.com:https".parse::<Endpoint>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_allow_list_check() {
+        let backend = AllowListBackend::new(vec![
+            "*.giphy.com:443".parse().unwrap(),
+            "example.com:8000-8100".parse().unwrap(),
+        ]);
+        let err = backend.connect("example.com", 443).await.unwrap_err();
+        assert!(matches!(err, ProxyError::Disallowed(_)));
+        let err = backend.connect("giphy.com", 443).await.unwrap_err();
+        assert!(matches!(err, ProxyError::Disallowed(_)));
+    }
+
+    #[tokio::test]
+    async fn test_port_policy() {
+        let config = BackendConfig {
+            allow: vec!["*.giphy.com:*".parse().unwrap()],
+            allowed_ports: "443,8443-8444".parse().unwrap(),
+            ..BackendConfig::default()
+        };
+        let backend = BoxBackend::from_config(&config);
+        for port in &[80, 8000, 8445] {
+            let err = backend.connect("api.giphy.com", *port).await.err().unwrap();
+            assert!(matches!(err, ProxyError::Disallowed(_)), "{}", port);
+        }
+
+        let config = BackendConfig {
+            kind: Some(BackendKind::SingleHost),
+            host: "good-host".into(),
+            port: 80,
+            allowed_ports: "443".parse().unwrap(),
+            ..BackendConfig::default()
+        };
+        let backend = BoxBackend::from_config(&config);
+        let err = backend.connect("good-host", 80).await.err().unwrap();
+        assert!(matches!(err, ProxyError::Disallowed(_)));
+
+        let config = BackendConfig {
+            kind: Some(BackendKind::Chained),
+            upstream: Some(UpstreamConfig {
+                address: "127.0.0.1:1".into(),
+                username: None,
+                password: None,
+            }),
+            ..config
+        };
+        let backend = BoxBackend::from_config(&config);
+        let err = backend.connect("good-host", 80).await.err().unwrap();
+        assert!(matches!(err, ProxyError::Disallowed(_)));
+    }
+
+    #[test]
+    fn test_is_blocked() {
+        let blocked = BackendConfig::default().blocked_networks;
+        for ip in &[
+            "127.0.0.1",
+            "10.1.2.3",
+            "169.254.169.254",
+            "192.168.0.1",
+            "::1",
+            "fe80::1",
+            "fd00:ec2::254",
+            "::ffff:10.0.0.1",
+            "198.18.0.1",
+            "224.0.0.251",
+            "255.255.255.255",
+            "::7f00:1",
+            "64:ff9b::9765:101",
+            "2002:9765:101::1",
+        ] {
+            assert!(is_blocked(ip.parse().unwrap(), &blocked), "{}", ip);
+        }
+        for ip in &["151.101.1.1", "2606:2800::1", "::ffff:151.101.1.1"] {
+            assert!(!is_blocked(ip.parse().unwrap(), &blocked), "{}", ip);
+        }
+
+        // NAT64 and 6to4 addresses are checked by their embedded IPv4 address
+        let blocked = vec!["10.0.0.0/8".parse().unwrap()];
+        for ip in &["64:ff9b::a00:1", "2002:a00:1::1", "::ffff:10.0.0.1"] {
+            assert!(is_blocked(ip.parse().unwrap(), &blocked), "{}", ip);
+        }
+        for ip in &["64:ff9b::9765:101", "2002:9765:101::1", "2606:2800::a00:1"] {
+            assert!(!is_blocked(ip.parse().unwrap(), &blocked), "{}", ip);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_blocked_networks() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let backend = AllowListBackend::new(vec![
+            "127.0.0.1:*".parse().unwrap(),
+            "localhost:*".parse().unwrap(),
+        ])
+        .with_dialer(
+            Dialer::new().with_blocked_networks(BackendConfig::default().blocked_networks),
+        );
+        for host in &["127.0.0.1", "localhost"] {
+            let err = backend.connect(host, port).await.unwrap_err();
+            assert!(matches!(err, ProxyError::Disallowed(_)), "{}", host);
+        }
+
+        let backend = SingleHostBackend::new("127.0.0.1", port)
+            .with_dialer(Dialer::new().with_blocked_networks(vec!["10.0.0.0/8".parse().unwrap()]));
+        backend.connect("127.0.0.1", port).await.unwrap();
+    }
+
+    #[test]
+    fn test_allow_list_from_config() {
+        let backend = AllowListBackend::from_config(&BackendConfig::default());
+        assert_eq!(
+            backend.entries,
+            vec!["api.giphy.com:443".parse::<AllowEntry>().unwrap()]
+        );
+    }
+
+    /// Run a fake parent proxy that reads a CONNECT head, checks it against `expected`,
+    /// and responds with `response` followed by `WORLD`
+    async fn fake_parent(expected: &'static str, response: &'static str) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut head = vec![0u8; expected.len()];
+            socket.read_exact(&mut head).await.unwrap();
+            assert_eq!(std::str::from_utf8(&head).unwrap(), expected);
+            socket.write_all(response.as_bytes()).await.unwrap();
+            socket.write_all(b"WORLD").await.unwrap();
+        });
+        port
+    }
+
+    #[tokio::test]
+    async fn test_chained_connect() {
+        let port = fake_parent(
+            "CONNECT api.giphy.com:443 HTTP/1.1\r\nHost: api.giphy.com:443\r\n\
+             Proxy-Authorization: Basic dXNlcjpwYXNz\r\n\r\n",
+            "HTTP/1.1 200 Connection established\r\n\r\n",
+        )
+        .await;
+        let backend = ChainedBackend::new(
+            format!("127.0.0.1:{}", port),
+            vec!["api.giphy.com:443".parse().unwrap()],
+        )
+        .with_basic_auth("user", "pass");
+
+        let mut stream = backend.connect("api.giphy.com", 443).await.unwrap();
+        let mut response = vec![];
+        stream.read_to_end(&mut response).await.unwrap();
+        assert_eq!(&response, b"WORLD");
+    }
+
+    #[tokio::test]
+    async fn test_chained_connect_refused_by_parent() {
+        let port = fake_parent(
+            "CONNECT api.giphy.com:443 HTTP/1.1\r\nHost: api.giphy.com:443\r\n\r\n",
+            "HTTP/1.1 407 Proxy Authentication Required\r\n\r\n",
+        )
+        .await;
+        let backend = ChainedBackend::new(
+            format!("127.0.0.1:{}", port),
+            vec!["api.giphy.com:443".parse().unwrap()],
+        );
+        let err = backend.connect("api.giphy.com", 443).await.unwrap_err();
+        assert!(matches!(err, ProxyError::UpstreamConnect { .. }));
+        assert!(err.to_string().contains("requires authentication"));
+    }
+
+    #[tokio::test]
+    async fn test_chained_check() {
+        // the parent is never contacted for disallowed destinations
+        let backend = ChainedBackend::new("127.0.0.1:1", vec!["*.giphy.com:443".parse().unwrap()]);
+        let err = backend.connect("example.com", 443).await.unwrap_err();
+        assert!(matches!(err, ProxyError::Disallowed(_)));
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        let retry = RetryPolicy {
+            retries: 3,
+            backoff: Duration::from_millis(100),
+            rotate_addresses: true,
+        };
+        assert_eq!(retry.delay(0), Duration::from_millis(100));
+        assert_eq!(retry.delay(2), Duration::from_millis(400));
+        assert!(retry.delay(1000) > Duration::from_secs(3600));
+    }
+
+    #[tokio::test]
+    async fn test_connect_retries() {
+        // a port that was just released, so connections to it are refused
+        let port = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let retry = RetryPolicy {
+            retries: 2,
+            backoff: Duration::from_millis(20),
+            rotate_addresses: true,
+        };
+
+        let backend = SingleHostBackend::new("127.0.0.1", port).with_retry(retry.clone());
+        let start = Instant::now();
+        assert!(backend.connect("127.0.0.1", port).await.is_err());
+        // 20ms + 40ms of backoff
+        assert!(start.elapsed() >= Duration::from_millis(60));
+
+        // disallowed destinations fail immediately
+        let start = Instant::now();
+        let err = backend.connect("other-host", port).await.unwrap_err();
+        assert!(matches!(err, ProxyError::Disallowed(_)));
+        assert!(start.elapsed() < Duration::from_millis(20));
+    }
+
+    #[test]
+    fn test_interleave() {
+        let addrs: Vec<SocketAddr> = ["[::1]:1", "[::2]:1", "[::3]:1", "10.0.0.1:1", "10.0.0.2:1"]
+            .iter()
+            .map(|a| a.parse().unwrap())
+            .collect();
+        let order: Vec<String> = interleave(addrs).iter().map(|a| a.to_string()).collect();
+        assert_eq!(
+            order,
+            vec!["[::1]:1", "10.0.0.1:1", "[::2]:1", "10.0.0.2:1", "[::3]:1"]
+        );
+
+        let addrs: Vec<SocketAddr> = ["10.0.0.1:1", "[::1]:1"]
+            .iter()
+            .map(|a| a.parse().unwrap())
+            .collect();
+        assert_eq!(interleave(addrs.clone()), addrs);
+    }
+
+    #[tokio::test]
+    async fn test_happy_eyeballs() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let good = listener.local_addr().unwrap();
+        // a port that was just released, so connections to it are refused
+        let refused = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        // the next attempt starts as soon as one fails
+        let options = SocketConfig::default();
+        let start = Instant::now();
+        let socket = happy_eyeballs(vec![refused, good], &options, None)
+            .await
+            .unwrap();
+        assert_eq!(socket.peer_addr().unwrap(), good);
+        assert!(start.elapsed() < CONNECTION_ATTEMPT_DELAY);
+
+        assert!(happy_eyeballs(vec![refused], &options, None).await.is_err());
+        assert!(happy_eyeballs(vec![], &options, None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_connect_ipv6() {
+        let listener = match TcpListener::bind("[::1]:0").await {
+            Ok(listener) => listener,
+            // IPv6 may not be available in the test environment
+            Err(_) => return,
+        };
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let _ = listener.accept().await.unwrap();
+        });
+
+        let backend = AllowListBackend::new(vec![format!("[::1]:{}", port).parse().unwrap()]);
+        backend.connect("::1", port).await.unwrap();
+    }
+
     #[tokio::test]
     async fn test_connect_good() {
         let _ = env_logger::builder().is_test(true).try_init();
@@ -83,4 +1396,24 @@ mod test {
         stream.read_to_end(&mut response).await.unwrap();
         assert_eq!(&response, b"WORLD");
     }
+
+    #[tokio::test]
+    async fn test_box_backend() {
+        let mut config = BackendConfig {
+            kind: Some(BackendKind::Mock),
+            ..BackendConfig::default()
+        };
+        let backend = BoxBackend::from_config(&config);
+        let mut socket = backend.connect("example.com", 1234).await.unwrap();
+        socket.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        socket.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+
+        // errors from the boxed backend are passed through
+        config.kind = Some(BackendKind::SingleHost);
+        let backend = BoxBackend::from_config(&config);
+        let err = backend.connect("example.com", 443).await.err().unwrap();
+        assert!(matches!(err, ProxyError::Disallowed(_)));
+    }
 }
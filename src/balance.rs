@@ -0,0 +1,351 @@
+//! Load balancing across the endpoints of a logical backend.  Connections to the backend's
+//! `host` and `port` are spread across a set of weighted endpoints, either in turn
+//! (smooth weighted round-robin) or to the endpoint with the fewest open connections
+//! relative to its weight.  If the chosen endpoint fails, or its circuit is open, the
+//! others are tried in the same order of preference.  The number of connections made to,
+//! failed to, and open to each endpoint is counted in the metrics, and the open
+//! connections feed the least-connections choice.
+
+use crate::backend::{check_allowed, Backend, Disallowed, Endpoint, SingleHostBackend};
+use crate::breaker::CircuitBreaker;
+use crate::config::{BackendConfig, BalanceStrategy, CircuitBreakerConfig, LoadBalanceConfig};
+use crate::connection::ConnectionInfo;
+use crate::error::Result;
+use crate::metrics::{EndpointCounters, METRICS};
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// One of the endpoints of a [`LoadBalancedBackend`]
+struct Member<B: Backend> {
+    endpoint: Endpoint,
+    weight: u32,
+    backend: CircuitBreaker<B>,
+    counters: Arc<EndpointCounters>,
+}
+
+/// A backend allowing connections only to a single host and port, which are spread across
+/// its endpoints
+pub struct LoadBalancedBackend<B: Backend> {
+    host: String,
+    port: u16,
+    strategy: BalanceStrategy,
+    members: Vec<Member<B>>,
+    circuit_breaker: Option<CircuitBreakerConfig>,
+    /// The current weight of each member, for smooth weighted round-robin
+    current: Mutex<Vec<i64>>,
+}
+
+impl<B: Backend> LoadBalancedBackend<B> {
+    /// Create a backend allowing connections only to `host` and `port`, with no endpoints
+    /// yet.  If `circuit_breaker` is given, each endpoint has a circuit breaker with that
+    /// configuration.
+    pub fn new<H: Into<String>>(
+        host: H,
+        port: u16,
+        strategy: BalanceStrategy,
+        circuit_breaker: Option<CircuitBreakerConfig>,
+    ) -> Self {
+        Self {
+            host: host.into(),
+            port,
+            strategy,
+            members: vec![],
+            circuit_breaker,
+            current: Mutex::new(vec![]),
+        }
+    }
+
+    /// Add an endpoint with the given weight, to which connections are made through
+    /// `backend`
+    pub fn with_endpoint(mut self, endpoint: Endpoint, weight: u32, backend: B) -> Self {
+        let counters = METRICS.endpoints.register(&endpoint.to_string());
+        self.members.push(Member {
+            endpoint,
+            weight,
+            backend: CircuitBreaker::new(backend, self.circuit_breaker.clone()),
+            counters,
+        });
+        self.current.get_mut().unwrap().push(0);
+        self
+    }
+
+    /// The indexes of the members in the order to try them for the next connection
+    fn order(&self) -> Vec<usize> {
+        let mut order = self.round_robin();
+        if self.strategy == BalanceStrategy::LeastConnections {
+            // compare active / weight without division; the sort is stable, so ties are
+            // broken by the round-robin order
+            let load = |i: usize| {
+                let member = &self.members[i];
+                (
+                    member.counters.active.get().max(0) as u128,
+                    member.weight as u128,
+                )
+            };
+            order.sort_by(|a, b| {
+                let ((active_a, weight_a), (active_b, weight_b)) = (load(*a), load(*b));
+                (active_a * weight_b).cmp(&(active_b * weight_a))
+            });
+        }
+        order
+    }
+
+    /// The next member by smooth weighted round-robin, followed by the others in order,
+    /// advancing the round-robin state
+    fn round_robin(&self) -> Vec<usize> {
+        if self.members.is_empty() {
+            return vec![];
+        }
+        let mut current = self.current.lock().unwrap();
+        let total: i64 = self.members.iter().map(|m| m.weight as i64).sum();
+        let mut chosen = 0;
+        for (i, member) in self.members.iter().enumerate() {
+            current[i] += member.weight as i64;
+            if current[i] > current[chosen] {
+                chosen = i;
+            }
+        }
+        current[chosen] -= total;
+        let len = self.members.len();
+        (0..len).map(|i| (chosen + i) % len).collect()
+    }
+}
+
+impl LoadBalancedBackend<SingleHostBackend> {
+    /// Create a backend for `config.host` and `config.port`, spreading connections across
+    /// the endpoints in `balance`.  Each endpoint is configured as a single-host backend.
+    pub fn from_config(config: &BackendConfig, balance: &LoadBalanceConfig) -> Self {
+        let mut backend = Self::new(
+            config.host.clone(),
+            config.port,
+            balance.strategy,
+            config.circuit_breaker.clone(),
+        );
+        for (endpoint, weight) in &balance.endpoints {
+            let config = BackendConfig {
+                host: endpoint.host.clone(),
+                port: endpoint.port,
+                ..config.clone()
+            };
+            backend = backend.with_endpoint(
+                endpoint.clone(),
+                *weight,
+                SingleHostBackend::from_config(&config),
+            );
+        }
+        backend
+    }
+}
+
+#[async_trait::async_trait]
+impl<B: Backend> Backend for LoadBalancedBackend<B> {
+    type Socket = Counted<B::Socket>;
+
+    async fn connect(&self, host: &str, port: u16) -> Result<Self::Socket> {
+        self.connect_for(&ConnectionInfo::default(), host, port)
+            .await
+    }
+
+    async fn connect_for(
+        &self,
+        info: &ConnectionInfo,
+        host: &str,
+        port: u16,
+    ) -> Result<Self::Socket> {
+        check_allowed(host == self.host && port == self.port, host, port)?;
+
+        let mut error = None;
+        for i in self.order() {
+            let member = &self.members[i];
+            let endpoint = &member.endpoint;
+            match member
+                .backend
+                .connect_for(info, &endpoint.host, endpoint.port)
+                .await
+            {
+                Ok(socket) => {
+                    member.counters.connections.inc();
+                    info.served_by.set(endpoint.to_string());
+                    return Ok(Counted::new(socket, member.counters.clone()));
+                }
+                Err(e) => {
+                    log::warn!("backend endpoint {} failed: {}", endpoint, e);
+                    member.counters.failures.inc();
+                    error = Some(e);
+                }
+            }
+        }
+        // with no endpoints, nothing is allowed
+        Err(error.unwrap_or_else(|| {
+            Disallowed {
+                host: host.into(),
+                port,
+            }
+            .into()
+        }))
+    }
+}
+
+/// A connection to an endpoint, counted among its active connections until dropped
+pub struct Counted<S> {
+    inner: S,
+    counters: Arc<EndpointCounters>,
+}
+
+impl<S> Counted<S> {
+    fn new(inner: S, counters: Arc<EndpointCounters>) -> Self {
+        counters.active.inc();
+        Self { inner, counters }
+    }
+}
+
+impl<S> Drop for Counted<S> {
+    fn drop(&mut self) {
+        self.counters.active.dec();
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Counted<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Counted<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::ProxyError;
+    use crate::http::authority;
+    use tokio::io::DuplexStream;
+
+    /// A backend that succeeds only if it is up
+    struct TestBackend {
+        up: bool,
+    }
+
+    #[async_trait::async_trait]
+    impl Backend for TestBackend {
+        type Socket = DuplexStream;
+
+        async fn connect(&self, host: &str, port: u16) -> Result<Self::Socket> {
+            if self.up {
+                Ok(tokio::io::duplex(16).0)
+            } else {
+                Err(ProxyError::upstream(
+                    authority(host, port),
+                    "connection refused",
+                ))
+            }
+        }
+    }
+
+    /// A balanced backend whose endpoints have the given names, weights, and states.  The
+    /// endpoints' counters are shared by all backends using those names, so each test uses
+    /// its own names.
+    fn new_balanced(
+        strategy: BalanceStrategy,
+        endpoints: &[(&str, u32, bool)],
+    ) -> LoadBalancedBackend<TestBackend> {
+        let mut backend = LoadBalancedBackend::new("api.giphy.com", 443, strategy, None);
+        for (endpoint, weight, up) in endpoints {
+            backend =
+                backend.with_endpoint(endpoint.parse().unwrap(), *weight, TestBackend { up: *up });
+        }
+        backend
+    }
+
+    async fn served_by(
+        backend: &LoadBalancedBackend<TestBackend>,
+    ) -> (String, Counted<DuplexStream>) {
+        let info = ConnectionInfo::default();
+        let socket = backend
+            .connect_for(&info, "api.giphy.com", 443)
+            .await
+            .unwrap();
+        (info.served_by.take().unwrap(), socket)
+    }
+
+    #[tokio::test]
+    async fn test_round_robin() {
+        let backend = new_balanced(
+            BalanceStrategy::RoundRobin,
+            &[("rr-a:443", 2, true), ("rr-b:443", 1, true)],
+        );
+        let mut served = vec![];
+        for _ in 0..6 {
+            served.push(served_by(&backend).await.0);
+        }
+        assert_eq!(
+            served,
+            vec!["rr-a:443", "rr-b:443", "rr-a:443", "rr-a:443", "rr-b:443", "rr-a:443"]
+        );
+
+        // only the logical backend may be requested
+        let result = backend.connect("rr-a", 443).await;
+        assert!(matches!(result, Err(ProxyError::Disallowed(_))));
+    }
+
+    #[tokio::test]
+    async fn test_least_connections() {
+        let backend = new_balanced(
+            BalanceStrategy::LeastConnections,
+            &[("lc-a:443", 1, true), ("lc-b:443", 2, true)],
+        );
+        let mut open = vec![];
+        let mut served = vec![];
+        for _ in 0..3 {
+            let (endpoint, socket) = served_by(&backend).await;
+            served.push(endpoint);
+            open.push(socket);
+        }
+        // b, with twice the weight, takes twice the open connections
+        served.sort();
+        assert_eq!(served, vec!["lc-a:443", "lc-b:443", "lc-b:443"]);
+        assert_eq!(METRICS.endpoints.register("lc-b:443").active.get(), 2);
+
+        // closed connections are no longer counted
+        open.clear();
+        assert_eq!(METRICS.endpoints.register("lc-b:443").active.get(), 0);
+        assert_eq!(METRICS.endpoints.register("lc-b:443").connections.get(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_failed_endpoint() {
+        let backend = new_balanced(
+            BalanceStrategy::RoundRobin,
+            &[("down-a:443", 1, false), ("down-b:443", 1, true)],
+        );
+        for _ in 0..2 {
+            assert_eq!(served_by(&backend).await.0, "down-b:443");
+        }
+        assert_eq!(METRICS.endpoints.register("down-a:443").failures.get(), 1);
+
+        let backend = new_balanced(BalanceStrategy::RoundRobin, &[("down-c:443", 1, false)]);
+        assert!(backend.connect("api.giphy.com", 443).await.is_err());
+    }
+}
@@ -0,0 +1,277 @@
+//! Load generation for capacity testing.  Tunnels are opened through a running proxy with
+//! the same CONNECT logic as `ChainedBackend`, a payload is sent through each and read
+//! back from a destination that echoes it, such as the proxy's `mock` backend, and the
+//! throughput, latencies, and errors are reported.
+
+use crate::client;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::task::JoinSet;
+use tokio::time::timeout;
+
+/// What to send through the proxy
+#[derive(Debug, Clone)]
+pub struct BenchOptions {
+    /// The proxy's address
+    pub proxy: SocketAddr,
+    /// The destination's host, which must echo what it is sent
+    pub host: String,
+    /// The destination's port
+    pub port: u16,
+    /// The `Proxy-Authorization` header to send, if any
+    pub authorization: Option<String>,
+    /// Number of tunnels open at once
+    pub concurrency: usize,
+    /// Total number of tunnels to open
+    pub tunnels: usize,
+    /// Bytes to send through, and receive back from, each tunnel
+    pub payload: usize,
+    /// Maximum time for each tunnel, from connecting to the proxy to receiving the payload
+    pub timeout: Duration,
+}
+
+/// The results of a run
+#[derive(Debug, Default)]
+pub struct BenchReport {
+    /// Time from the first tunnel starting to the last finishing
+    pub elapsed: Duration,
+    /// For each successful tunnel, the time from connecting to the proxy to the CONNECT
+    /// response, sorted
+    pub connect_latencies: Vec<Duration>,
+    /// For each successful tunnel, the time from connecting to the proxy to receiving the
+    /// whole payload back, sorted
+    pub latencies: Vec<Duration>,
+    /// Bytes sent through successful tunnels, in each direction
+    pub bytes: u64,
+    /// Number of failed tunnels, by reason
+    pub errors: BTreeMap<String, usize>,
+}
+
+impl BenchReport {
+    /// Total number of tunnels attempted
+    pub fn tunnels(&self) -> usize {
+        self.latencies.len() + self.errors.values().sum::<usize>()
+    }
+
+    /// Fraction of tunnels that failed
+    pub fn error_rate(&self) -> f64 {
+        match self.tunnels() {
+            0 => 0.0,
+            n => (n - self.latencies.len()) as f64 / n as f64,
+        }
+    }
+}
+
+impl fmt::Display for BenchReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let secs = self.elapsed.as_secs_f64().max(f64::EPSILON);
+        let ms = |d: Option<Duration>| d.map_or(0.0, |d| d.as_secs_f64() * 1000.0);
+        writeln!(
+            f,
+            "tunnels      {} in {:.2} s ({:.1}/s), {} failed ({:.2}%)",
+            self.tunnels(),
+            secs,
+            self.latencies.len() as f64 / secs,
+            self.tunnels() - self.latencies.len(),
+            self.error_rate() * 100.0
+        )?;
+        writeln!(
+            f,
+            "throughput   {:.2} MB/s each way",
+            self.bytes as f64 / secs / 1_000_000.0
+        )?;
+        for (name, latencies) in [
+            ("connect", &self.connect_latencies),
+            ("tunnel", &self.latencies),
+        ] {
+            writeln!(
+                f,
+                "{:<12} p50 {:.1} ms, p90 {:.1} ms, p99 {:.1} ms, max {:.1} ms",
+                name,
+                ms(percentile(latencies, 50.0)),
+                ms(percentile(latencies, 90.0)),
+                ms(percentile(latencies, 99.0)),
+                ms(latencies.last().copied())
+            )?;
+        }
+        for (reason, count) in &self.errors {
+            writeln!(f, "error        {} x {}", count, reason)?;
+        }
+        Ok(())
+    }
+}
+
+/// The `p`th percentile of `sorted`, by the nearest-rank method
+fn percentile(sorted: &[Duration], p: f64) -> Option<Duration> {
+    if sorted.is_empty() {
+        return None;
+    }
+    let rank = (p / 100.0 * sorted.len() as f64).ceil() as usize;
+    Some(sorted[rank.clamp(1, sorted.len()) - 1])
+}
+
+/// The outcome of a single tunnel: its connect and total latencies, or why it failed
+type TunnelResult = Result<(Duration, Duration), String>;
+
+/// Open `options.tunnels` tunnels, `options.concurrency` at a time, and report the results
+pub async fn run(options: BenchOptions) -> BenchReport {
+    let options = Arc::new(options);
+    let payload: Arc<[u8]> = (0..options.payload).map(|i| i as u8).collect();
+    let started = Arc::new(AtomicUsize::new(0));
+
+    let start = Instant::now();
+    let mut workers = JoinSet::new();
+    for _ in 0..options.concurrency.max(1) {
+        let options = options.clone();
+        let payload = payload.clone();
+        let started = started.clone();
+        workers.spawn(async move {
+            let mut results = vec![];
+            while started.fetch_add(1, Ordering::Relaxed) < options.tunnels {
+                let result = timeout(options.timeout, tunnel(&options, &payload)).await;
+                results.push(result.unwrap_or_else(|_| Err("timed out".into())));
+            }
+            results
+        });
+    }
+
+    let mut report = BenchReport::default();
+    while let Some(results) = workers.join_next().await {
+        for result in results.unwrap_or_default() {
+            match result {
+                Ok((connect, total)) => {
+                    report.connect_latencies.push(connect);
+                    report.latencies.push(total);
+                    report.bytes += options.payload as u64;
+                }
+                Err(reason) => *report.errors.entry(reason).or_default() += 1,
+            }
+        }
+    }
+    report.elapsed = start.elapsed();
+    report.connect_latencies.sort();
+    report.latencies.sort();
+    report
+}
+
+/// Open a tunnel, send the payload through it, and read it back
+async fn tunnel(options: &BenchOptions, payload: &[u8]) -> TunnelResult {
+    let start = Instant::now();
+    let mut socket = TcpStream::connect(options.proxy)
+        .await
+        .map_err(|e| format!("connecting to proxy: {}", e))?;
+    let _ = socket.set_nodelay(true);
+    let status = client::connect(
+        &mut socket,
+        &options.host,
+        options.port,
+        options.authorization.as_deref(),
+    )
+    .await
+    .map_err(|e| format!("sending CONNECT: {}", e))?;
+    match status {
+        Some(200..=299) => {}
+        Some(status) => return Err(format!("CONNECT response {}", status)),
+        None => return Err("invalid CONNECT response".into()),
+    }
+    let connect = start.elapsed();
+
+    // write and read at once, so that a payload larger than the buffers along the way
+    // cannot deadlock
+    let (mut reader, mut writer) = socket.into_split();
+    let write = writer.write_all(payload);
+    let mut echoed = vec![0u8; payload.len()];
+    let read = reader.read_exact(&mut echoed);
+    let (written, read) = tokio::join!(write, read);
+    written.map_err(|e| format!("sending payload: {}", e))?;
+    read.map_err(|e| format!("receiving payload: {}", e))?;
+    if echoed != payload {
+        return Err("payload was not echoed".into());
+    }
+    Ok((connect, start.elapsed()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::backend::MockBackend;
+    use crate::Proxy;
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn test_percentile() {
+        let sorted: Vec<Duration> = (1..=100).map(ms).collect();
+        assert_eq!(percentile(&sorted, 50.0), Some(ms(50)));
+        assert_eq!(percentile(&sorted, 99.0), Some(ms(99)));
+        assert_eq!(percentile(&sorted, 100.0), Some(ms(100)));
+        assert_eq!(percentile(&sorted, 0.0), Some(ms(1)));
+        assert_eq!(percentile(&[ms(7)], 90.0), Some(ms(7)));
+        assert_eq!(percentile(&[], 50.0), None);
+    }
+
+    #[test]
+    fn test_report() {
+        let mut report = BenchReport {
+            elapsed: Duration::from_secs(1),
+            connect_latencies: vec![ms(1), ms(2), ms(3)],
+            latencies: vec![ms(2), ms(4), ms(6)],
+            bytes: 3_000_000,
+            errors: BTreeMap::new(),
+        };
+        assert_eq!(report.error_rate(), 0.0);
+        report.errors.insert("timed out".into(), 1);
+        assert_eq!(report.tunnels(), 4);
+        assert_eq!(report.error_rate(), 0.25);
+        let text = report.to_string();
+        assert!(text.contains("3.00 MB/s"), "{}", text);
+        assert!(text.contains("1 x timed out"), "{}", text);
+    }
+
+    #[tokio::test]
+    async fn test_run() {
+        let addrs = Proxy::builder()
+            .bind("127.0.0.1:0".parse().unwrap())
+            .backend(MockBackend)
+            .build()
+            .unwrap()
+            .start()
+            .await
+            .unwrap();
+        let options = BenchOptions {
+            proxy: addrs[0],
+            host: "echo.example.com".into(),
+            port: 7,
+            authorization: None,
+            concurrency: 4,
+            tunnels: 10,
+            payload: 100_000,
+            timeout: Duration::from_secs(10),
+        };
+        let report = run(options.clone()).await;
+        assert_eq!(report.errors, BTreeMap::new());
+        assert_eq!(report.latencies.len(), 10);
+        assert_eq!(report.bytes, 1_000_000);
+
+        // nothing listens on the proxy's address once the listener is dropped
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy = listener.local_addr().unwrap();
+        drop(listener);
+        let report = run(BenchOptions {
+            proxy,
+            tunnels: 3,
+            ..options
+        })
+        .await;
+        assert_eq!(report.tunnels(), 3);
+        assert_eq!(report.error_rate(), 1.0);
+    }
+}
@@ -0,0 +1,275 @@
+//! A circuit breaker for backend connections.  After a number of consecutive failed
+//! connections to a destination, further connections to it fail immediately until a
+//! cooldown has passed.  Then a single connection is attempted: if it succeeds, the
+//! circuit closes again, and if not, it stays open for another cooldown.
+
+use crate::backend::Backend;
+use crate::config::CircuitBreakerConfig;
+use crate::connection::ConnectionInfo;
+use crate::error::{ProxyError, Result};
+use crate::http::authority;
+use crate::metrics::METRICS;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// The error returned, as `ProxyError::CircuitOpen`, when a destination's circuit is
+/// open.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CircuitOpen {
+    pub host: String,
+    pub port: u16,
+    /// Time until another connection will be attempted
+    pub retry_in: Duration,
+}
+
+impl fmt::Display for CircuitOpen {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Circuit open for {}; retrying in {}s",
+            authority(&self.host, self.port),
+            self.retry_in.as_secs()
+        )
+    }
+}
+
+impl std::error::Error for CircuitOpen {}
+
+/// The state of a destination that has failed recently
+#[derive(Debug, Default)]
+struct Circuit {
+    /// Consecutive failed connections
+    failures: u32,
+    /// If the circuit is open, when the next connection may be attempted
+    open_until: Option<Instant>,
+}
+
+/// A backend which wraps another, refusing connections to destinations that have failed
+/// repeatedly.  If no configuration is given, connections are passed straight through.
+pub struct CircuitBreaker<B: Backend> {
+    inner: B,
+    config: Option<CircuitBreakerConfig>,
+    /// Circuits for destinations with failures since their last success, keyed by
+    /// lowercased host and port
+    circuits: Mutex<HashMap<(String, u16), Circuit>>,
+}
+
+impl<B: Backend> CircuitBreaker<B> {
+    /// Wrap a backend, using the given configuration
+    pub fn new(inner: B, config: Option<CircuitBreakerConfig>) -> Self {
+        Self {
+            inner,
+            config,
+            circuits: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Check whether a connection may be attempted, returning an error if the circuit is
+    /// open.  Once the cooldown has passed, this allows one connection and re-arms the
+    /// cooldown, so that other connections are refused until that one completes.
+    fn check(&self, config: &CircuitBreakerConfig, key: &(String, u16)) -> Result<()> {
+        let mut circuits = self.circuits.lock().unwrap();
+        let circuit = match circuits.get_mut(key) {
+            Some(circuit) => circuit,
+            None => return Ok(()),
+        };
+        let now = Instant::now();
+        match circuit.open_until {
+            Some(until) if now < until => {
+                METRICS.circuit_rejections.inc();
+                Err(CircuitOpen {
+                    host: key.0.clone(),
+                    port: key.1,
+                    retry_in: until - now,
+                }
+                .into())
+            }
+            Some(_) => {
+                log::info!(
+                    "circuit for {} half-open; attempting a connection",
+                    authority(&key.0, key.1)
+                );
+                circuit.open_until = Some(now + config.cooldown);
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Record the result of a connection attempt
+    fn record<T>(&self, config: &CircuitBreakerConfig, key: (String, u16), result: &Result<T>) {
+        let mut circuits = self.circuits.lock().unwrap();
+        match result {
+            Ok(_) => {
+                if let Some(Circuit {
+                    open_until: Some(_),
+                    ..
+                }) = circuits.remove(&key)
+                {
+                    log::info!("circuit for {} closed", authority(&key.0, key.1));
+                }
+            }
+            // refusing a disallowed destination says nothing about its health
+            Err(ProxyError::Disallowed(_)) => {}
+            Err(_) => {
+                let target = authority(&key.0, key.1);
+                let circuit = circuits.entry(key).or_default();
+                circuit.failures += 1;
+                if circuit.failures >= config.failure_threshold && circuit.open_until.is_none() {
+                    log::warn!(
+                        "circuit for {} opened after {} consecutive failures",
+                        target,
+                        circuit.failures
+                    );
+                    METRICS.circuits_opened.inc();
+                    circuit.open_until = Some(Instant::now() + config.cooldown);
+                }
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<B: Backend> Backend for CircuitBreaker<B> {
+    type Socket = B::Socket;
+
+    async fn connect(&self, host: &str, port: u16) -> Result<Self::Socket> {
+        self.connect_for(&ConnectionInfo::default(), host, port)
+            .await
+    }
+
+    async fn connect_for(
+        &self,
+        info: &ConnectionInfo,
+        host: &str,
+        port: u16,
+    ) -> Result<Self::Socket> {
+        let config = match &self.config {
+            Some(config) => config,
+            None => return self.inner.connect_for(info, host, port).await,
+        };
+        let key = (host.to_ascii_lowercase(), port);
+        self.check(config, &key)?;
+        let result = self.inner.connect_for(info, host, port).await;
+        self.record(config, key, &result);
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::backend::Disallowed;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use tokio::io::DuplexStream;
+
+    /// A backend that counts connection attempts, failing unless `up` is set, and
+    /// disallowing the host "forbidden"
+    #[derive(Default)]
+    struct FlakyBackend {
+        attempts: AtomicUsize,
+        up: AtomicBool,
+    }
+
+    #[async_trait::async_trait]
+    impl Backend for FlakyBackend {
+        type Socket = DuplexStream;
+
+        async fn connect(&self, host: &str, port: u16) -> Result<Self::Socket> {
+            if host == "forbidden" {
+                return Err(Disallowed {
+                    host: host.into(),
+                    port,
+                }
+                .into());
+            }
+            self.attempts.fetch_add(1, Ordering::SeqCst);
+            if self.up.load(Ordering::SeqCst) {
+                Ok(tokio::io::duplex(16).0)
+            } else {
+                Err(ProxyError::upstream(
+                    authority(host, port),
+                    "connection refused",
+                ))
+            }
+        }
+    }
+
+    fn breaker(cooldown: Duration) -> CircuitBreaker<FlakyBackend> {
+        CircuitBreaker::new(
+            FlakyBackend::default(),
+            Some(CircuitBreakerConfig {
+                failure_threshold: 2,
+                cooldown,
+            }),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_opens_after_threshold() {
+        let breaker = breaker(Duration::from_secs(60));
+        assert!(breaker.connect("example.com", 443).await.is_err());
+        assert!(breaker.connect("example.com", 443).await.is_err());
+        let err = breaker.connect("Example.com", 443).await.unwrap_err();
+        assert!(matches!(err, ProxyError::CircuitOpen(_)));
+        assert_eq!(breaker.inner.attempts.load(Ordering::SeqCst), 2);
+
+        // other destinations are unaffected
+        assert!(breaker.connect("example.com", 80).await.is_err());
+        assert_eq!(breaker.inner.attempts.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_success_resets_failures() {
+        let breaker = breaker(Duration::from_secs(60));
+        assert!(breaker.connect("example.com", 443).await.is_err());
+        breaker.inner.up.store(true, Ordering::SeqCst);
+        breaker.connect("example.com", 443).await.unwrap();
+        breaker.inner.up.store(false, Ordering::SeqCst);
+        assert!(breaker.connect("example.com", 443).await.is_err());
+        // only one consecutive failure, so the circuit is still closed
+        let err = breaker.connect("example.com", 443).await.unwrap_err();
+        assert!(!matches!(err, ProxyError::CircuitOpen(_)));
+    }
+
+    #[tokio::test]
+    async fn test_half_open() {
+        let breaker = breaker(Duration::from_millis(50));
+        for _ in 0..2 {
+            assert!(breaker.connect("example.com", 443).await.is_err());
+        }
+        tokio::time::sleep(Duration::from_millis(60)).await;
+
+        // the trial connection fails, so the circuit opens again
+        let err = breaker.connect("example.com", 443).await.unwrap_err();
+        assert!(!matches!(err, ProxyError::CircuitOpen(_)));
+        let err = breaker.connect("example.com", 443).await.unwrap_err();
+        assert!(matches!(err, ProxyError::CircuitOpen(_)));
+        tokio::time::sleep(Duration::from_millis(60)).await;
+
+        // the trial connection succeeds, so the circuit closes
+        breaker.inner.up.store(true, Ordering::SeqCst);
+        breaker.connect("example.com", 443).await.unwrap();
+        assert!(breaker.circuits.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_disallowed_not_counted() {
+        let breaker = breaker(Duration::from_secs(60));
+        for _ in 0..3 {
+            let err = breaker.connect("forbidden", 443).await.unwrap_err();
+            assert!(matches!(err, ProxyError::Disallowed(_)));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_disabled() {
+        let breaker = CircuitBreaker::new(FlakyBackend::default(), None);
+        for _ in 0..10 {
+            assert!(breaker.connect("example.com", 443).await.is_err());
+        }
+        assert_eq!(breaker.inner.attempts.load(Ordering::SeqCst), 10);
+    }
+}
@@ -0,0 +1,503 @@
+//! A cache of successful responses to `GET` requests the proxy can read in full: those to
+//! the reverse proxy, and those in intercepted TLS tunnels.  Responses are keyed by the
+//! request's host and path, with its query parameters sorted and any API key parameter
+//! removed, so that requests for the same results share an entry.  The cache is bounded in
+//! total size, evicting the least recently used responses first, and can be kept in a
+//! file across restarts.
+
+use crate::config::{Config, KeyLocation};
+use crate::forward::{split_header, Body, Forward};
+use crate::metrics::METRICS;
+use anyhow::{Context, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use lru::LruCache;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// A cached response
+struct Entry {
+    /// The response head as relayed to the client, including its final empty line
+    head: Vec<u8>,
+    body: Vec<u8>,
+    stored: SystemTime,
+    expires: SystemTime,
+}
+
+impl Entry {
+    /// The number of bytes counted against the cache's maximum size
+    fn size(&self, key: &str) -> usize {
+        key.len() + self.head.len() + self.body.len()
+    }
+}
+
+/// The cached responses, in order of use, with their total size
+struct Entries {
+    lru: LruCache<String, Entry>,
+    size: usize,
+}
+
+/// An entry as it is written to the cache file
+#[derive(Serialize, Deserialize)]
+struct Persisted {
+    key: String,
+    /// The response head, base64-encoded
+    head: String,
+    /// The response body, base64-encoded
+    body: String,
+    /// When the response was stored, in seconds since the epoch
+    stored: u64,
+    /// When the response expires, in seconds since the epoch
+    expires: u64,
+}
+
+/// The response cache
+pub(crate) struct ResponseCache {
+    max_size: usize,
+    ttl: Duration,
+    path: Option<PathBuf>,
+    persist_interval: Duration,
+    /// A query parameter left out of keys, as it carries the API key
+    key_param: Option<String>,
+    entries: Mutex<Entries>,
+}
+
+impl ResponseCache {
+    /// Create a cache as configured in the `cache` section, reading any entries from its
+    /// file
+    pub(crate) fn new(config: &Config) -> Result<Self> {
+        let cache = Self {
+            max_size: config.cache.max_size,
+            ttl: config.cache.ttl,
+            path: config.cache.path.clone(),
+            persist_interval: config.cache.persist_interval,
+            key_param: match config.reverse.key_location {
+                KeyLocation::Query => Some(config.reverse.key_name.clone()),
+                KeyLocation::Header => None,
+            },
+            entries: Mutex::new(Entries {
+                lru: LruCache::unbounded(),
+                size: 0,
+            }),
+        };
+        cache.load(SystemTime::now())?;
+        Ok(cache)
+    }
+
+    /// The key for a request, or None if responses to it cannot be cached.  Only `GET`
+    /// requests without bodies or credentials, which do not forbid caching, are cached.
+    pub(crate) fn key(&self, request: &Forward) -> Option<String> {
+        let headers = &request.headers;
+        if request.method != "GET"
+            || request.body != Body::Empty
+            || headers.get("Authorization").is_some()
+            || headers.get("Cookie").is_some()
+            || directives(headers.get("Cache-Control")).any(|(name, _)| name == "no-store")
+        {
+            return None;
+        }
+        let (path, query) = match request.path.split_once('?') {
+            Some((path, query)) => (path, query),
+            None => (request.path.as_str(), ""),
+        };
+        let mut params: Vec<&str> = query
+            .split('&')
+            .filter(|param| !param.is_empty())
+            .filter(|param| {
+                let name = param.split('=').next().unwrap_or("");
+                self.key_param.as_deref() != Some(name)
+            })
+            .collect();
+        params.sort_unstable();
+        let mut key = format!("{}{}", request.host.to_ascii_lowercase(), path);
+        if !params.is_empty() {
+            key.push('?');
+            key.push_str(&params.join("&"));
+        }
+        Some(key)
+    }
+
+    /// Get the cached response to a request, with an `Age` header, if there is an
+    /// unexpired one and the request does not ask for a fresh response
+    pub(crate) fn lookup(&self, request: &Forward, now: SystemTime) -> Option<Vec<u8>> {
+        let key = self.key(request)?;
+        if directives(request.headers.get("Cache-Control")).any(|(name, _)| name == "no-cache") {
+            return None;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        let entry = match entries.lru.get(&key) {
+            Some(entry) if entry.expires > now => entry,
+            Some(_) => {
+                entries.remove(&key);
+                METRICS.response_cache_misses.inc();
+                return None;
+            }
+            None => {
+                METRICS.response_cache_misses.inc();
+                return None;
+            }
+        };
+        METRICS.response_cache_hits.inc();
+        let age = now.duration_since(entry.stored).unwrap_or_default();
+        let headers = entry
+            .head
+            .strip_suffix(b"\r\n")
+            .or_else(|| entry.head.strip_suffix(b"\n"))
+            .unwrap_or(&entry.head);
+        let mut response = headers.to_vec();
+        response.extend_from_slice(format!("Age: {}\r\n\r\n", age.as_secs()).as_bytes());
+        response.extend_from_slice(&entry.body);
+        Some(response)
+    }
+
+    /// Store a successful response to a request, given the head lines received from the
+    /// backend, and the head and body as relayed to the client.  Responses that the backend
+    /// does not allow to be cached, or that set cookies, are not stored.
+    pub(crate) fn store(
+        &self,
+        request: &Forward,
+        lines: &[Vec<u8>],
+        head: &[u8],
+        body: &[u8],
+        now: SystemTime,
+    ) {
+        let key = match self.key(request) {
+            Some(key) => key,
+            None => return,
+        };
+        let ttl = match self.ttl(lines) {
+            Some(ttl) => ttl,
+            None => return,
+        };
+        let entry = Entry {
+            head: head.to_vec(),
+            body: body.to_vec(),
+            stored: now,
+            expires: now + ttl,
+        };
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(key, entry, self.max_size);
+    }
+
+    /// How long to cache a response with the given head lines, or None if it must not be
+    /// cached
+    fn ttl(&self, lines: &[Vec<u8>]) -> Option<Duration> {
+        let mut ttl = self.ttl;
+        for (name, value) in lines[1..].iter().map(|line| split_header(line)) {
+            if name.eq_ignore_ascii_case("Set-Cookie") {
+                return None;
+            }
+            if !name.eq_ignore_ascii_case("Cache-Control") {
+                continue;
+            }
+            for (directive, argument) in directives(Some(&value)) {
+                match (directive.as_str(), argument) {
+                    ("no-store" | "no-cache" | "private", _) => return None,
+                    ("max-age" | "s-maxage", Some(secs)) => {
+                        let secs = secs.trim_matches('"').parse().ok()?;
+                        ttl = ttl.min(Duration::from_secs(secs));
+                    }
+                    _ => (),
+                }
+            }
+        }
+        if ttl.is_zero() {
+            None
+        } else {
+            Some(ttl)
+        }
+    }
+
+    /// Write the cache's unexpired entries to its file, if it has one.  The file is
+    /// replaced atomically, so a crash while writing leaves the previous contents.
+    pub(crate) fn save(&self, now: SystemTime) -> Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let persisted: Vec<Persisted> = {
+            let entries = self.entries.lock().unwrap();
+            // least recently used first, so that loading restores the order
+            entries
+                .lru
+                .iter()
+                .rev()
+                .filter(|(_, entry)| entry.expires > now)
+                .map(|(key, entry)| Persisted {
+                    key: key.clone(),
+                    head: STANDARD.encode(&entry.head),
+                    body: STANDARD.encode(&entry.body),
+                    stored: epoch_secs(entry.stored),
+                    expires: epoch_secs(entry.expires),
+                })
+                .collect()
+        };
+        let mut tmp = path.clone().into_os_string();
+        tmp.push(".tmp");
+        std::fs::write(&tmp, serde_json::to_vec(&persisted)?)
+            .with_context(|| format!("writing cache file {:?}", tmp))?;
+        std::fs::rename(&tmp, path).with_context(|| format!("writing cache file {:?}", path))
+    }
+
+    /// Read entries from the cache's file, if it has one and the file exists, skipping
+    /// any that have expired
+    fn load(&self, now: SystemTime) -> Result<()> {
+        let path = match &self.path {
+            Some(path) if path.exists() => path,
+            _ => return Ok(()),
+        };
+        let content =
+            std::fs::read(path).with_context(|| format!("reading cache file {:?}", path))?;
+        let persisted: Vec<Persisted> = serde_json::from_slice(&content)
+            .with_context(|| format!("parsing cache file {:?}", path))?;
+        let mut entries = self.entries.lock().unwrap();
+        for p in persisted {
+            let entry = Entry {
+                head: STANDARD.decode(&p.head)?,
+                body: STANDARD.decode(&p.body)?,
+                stored: UNIX_EPOCH + Duration::from_secs(p.stored),
+                expires: UNIX_EPOCH + Duration::from_secs(p.expires),
+            };
+            if entry.expires > now {
+                entries.insert(p.key, entry, self.max_size);
+            }
+        }
+        log::info!(
+            "read {} cached responses from {:?}",
+            entries.lru.len(),
+            path
+        );
+        Ok(())
+    }
+
+    /// If the cache has a file, write to it every `persist_interval` in a background task
+    pub(crate) fn persist_periodically(self: &Arc<Self>) {
+        if self.path.is_none() {
+            return;
+        }
+        let cache = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(cache.persist_interval);
+            // the first tick completes immediately
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                let cache = cache.clone();
+                let result = tokio::task::spawn_blocking(move || cache.save(SystemTime::now()));
+                if let Ok(Err(e)) = result.await {
+                    log::warn!("saving response cache: {:?}", e);
+                }
+            }
+        });
+    }
+}
+
+impl Entries {
+    /// Add an entry, replacing any with the same key, then evict the least recently used
+    /// entries until the total size is at most `max_size`.  Entries larger than `max_size`
+    /// are not added.
+    fn insert(&mut self, key: String, entry: Entry, max_size: usize) {
+        let size = entry.size(&key);
+        if size > max_size {
+            return;
+        }
+        self.remove(&key);
+        self.size += size;
+        self.lru.put(key, entry);
+        while self.size > max_size {
+            match self.lru.pop_lru() {
+                Some((key, entry)) => self.size -= entry.size(&key),
+                None => break,
+            }
+        }
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.lru.pop(key) {
+            self.size -= entry.size(key);
+        }
+    }
+}
+
+/// Parse the directives in a `Cache-Control` header, lowercasing their names
+fn directives(value: Option<&str>) -> impl Iterator<Item = (String, Option<String>)> + '_ {
+    value
+        .unwrap_or("")
+        .split(',')
+        .map(str::trim)
+        .filter(|directive| !directive.is_empty())
+        .map(|directive| match directive.split_once('=') {
+            Some((name, arg)) => (name.trim().to_ascii_lowercase(), Some(arg.trim().into())),
+            None => (directive.to_ascii_lowercase(), None),
+        })
+}
+
+fn epoch_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::CacheConfig;
+    use crate::http::{parse_origin, ParseOptions};
+    use crate::testing::temp_path;
+
+    fn cache(max_size: usize, path: Option<PathBuf>) -> ResponseCache {
+        let config = Config {
+            cache: CacheConfig {
+                max_size,
+                path,
+                ..CacheConfig::default()
+            },
+            ..Config::default()
+        };
+        ResponseCache::new(&config).unwrap()
+    }
+
+    fn request(head: &str) -> Forward {
+        let request = parse_origin(head.as_bytes(), &ParseOptions::default()).unwrap();
+        Forward::from_origin(request, "api.giphy.com".into()).unwrap()
+    }
+
+    fn lines(head: &str) -> Vec<Vec<u8>> {
+        head.split_inclusive('\n')
+            .map(|line| line.as_bytes().to_vec())
+            .collect()
+    }
+
+    const OK: &str = "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\r\n";
+
+    #[test]
+    fn test_key() {
+        let cache = cache(1000, None);
+        let key = |head: &str| cache.key(&request(head));
+        assert_eq!(
+            key("GET /v1/gifs/search?q=cat&api_key=k&limit=5 HTTP/1.1\r\n\r\n"),
+            Some("api.giphy.com/v1/gifs/search?limit=5&q=cat".into())
+        );
+        assert_eq!(
+            key("GET /v1/gifs/search?limit=5&q=cat HTTP/1.1\r\n\r\n"),
+            key("GET /v1/gifs/search?q=cat&limit=5&api_key=other HTTP/1.1\r\n\r\n"),
+        );
+        assert_eq!(
+            key("GET /v1/gifs/trending HTTP/1.1\r\n\r\n"),
+            Some("api.giphy.com/v1/gifs/trending".into())
+        );
+        assert_eq!(key("HEAD /v1/gifs/trending HTTP/1.1\r\n\r\n"), None);
+        assert_eq!(
+            key("POST /v1/gifs HTTP/1.1\r\nContent-Length: 2\r\n\r\n"),
+            None
+        );
+        assert_eq!(
+            key("GET /v1/gifs HTTP/1.1\r\nAuthorization: Basic eDp5\r\n\r\n"),
+            None
+        );
+        assert_eq!(
+            key("GET /v1/gifs HTTP/1.1\r\nCache-Control: No-Store\r\n\r\n"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_lookup() {
+        let cache = cache(1000, None);
+        let now = SystemTime::now();
+        let req = request("GET /v1/gifs/search?q=cat HTTP/1.1\r\n\r\n");
+        assert_eq!(cache.lookup(&req, now), None);
+
+        cache.store(&req, &lines(OK), OK.as_bytes(), b"{}", now);
+        let later = now + Duration::from_secs(10);
+        assert_eq!(
+            cache.lookup(&req, later),
+            Some(
+                b"HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nAge: 10\r\n\r\n{}".to_vec()
+            )
+        );
+        let fresh =
+            request("GET /v1/gifs/search?q=cat HTTP/1.1\r\nCache-Control: no-cache\r\n\r\n");
+        assert_eq!(cache.lookup(&fresh, later), None);
+
+        // expired after the default TTL
+        assert_eq!(cache.lookup(&req, now + Duration::from_secs(300)), None);
+        assert_eq!(cache.entries.lock().unwrap().size, 0);
+    }
+
+    #[test]
+    fn test_ttl() {
+        let cache = cache(1000, None);
+        let ttl = |head: &str| cache.ttl(&lines(head));
+        assert_eq!(ttl(OK), Some(Duration::from_secs(300)));
+        assert_eq!(
+            ttl("HTTP/1.1 200 OK\r\nCache-Control: public, max-age=60\r\n\r\n"),
+            Some(Duration::from_secs(60))
+        );
+        assert_eq!(
+            ttl("HTTP/1.1 200 OK\r\nCache-Control: max-age=3600\r\n\r\n"),
+            Some(Duration::from_secs(300))
+        );
+        assert_eq!(
+            ttl("HTTP/1.1 200 OK\r\nCache-Control: max-age=0\r\n\r\n"),
+            None
+        );
+        assert_eq!(
+            ttl("HTTP/1.1 200 OK\r\nCache-Control: Private\r\n\r\n"),
+            None
+        );
+        assert_eq!(ttl("HTTP/1.1 200 OK\r\nSet-Cookie: a=b\r\n\r\n"), None);
+    }
+
+    #[test]
+    fn test_eviction() {
+        let now = SystemTime::now();
+        let mut entries = Entries {
+            lru: LruCache::unbounded(),
+            size: 0,
+        };
+        let entry = |body: &[u8]| Entry {
+            head: vec![],
+            body: body.to_vec(),
+            stored: now,
+            expires: now + Duration::from_secs(60),
+        };
+        entries.insert("a".into(), entry(b"123"), 9);
+        entries.insert("b".into(), entry(b"123"), 9);
+        assert_eq!(entries.size, 8);
+        // using "a" makes "b" the least recently used
+        entries.lru.get("a");
+        entries.insert("c".into(), entry(b"1"), 9);
+        assert_eq!(entries.size, 6);
+        assert!(entries.lru.contains("a"));
+        assert!(!entries.lru.contains("b"));
+
+        // replacing an entry replaces its size
+        entries.insert("a".into(), entry(b"12"), 9);
+        assert_eq!(entries.size, 5);
+
+        // too large to cache at all
+        entries.insert("d".into(), entry(b"12345678901"), 9);
+        assert!(!entries.lru.contains("d"));
+        assert_eq!(entries.size, 5);
+    }
+
+    #[test]
+    fn test_persist() {
+        let path = temp_path("cache-persist");
+        let now = SystemTime::now();
+        let req = request("GET /v1/gifs/search?q=cat HTTP/1.1\r\n\r\n");
+
+        let cache1 = cache(1000, Some(path.clone()));
+        cache1.store(&req, &lines(OK), OK.as_bytes(), b"{}", now);
+        cache1.save(now).unwrap();
+
+        let cache2 = cache(1000, Some(path.clone()));
+        assert!(cache2.lookup(&req, now).is_some());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
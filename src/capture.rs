@@ -0,0 +1,639 @@
+//! Packet capture of tunnels, for analysis in Wireshark and other tools that read pcapng.
+//! While capture is running, from startup or once started through the admin server, the
+//! bytes of each new tunnel are written to a capture file as a TCP connection between the
+//! client and the tunnel's destination.  Each time capture starts, it writes a new file in
+//! `dir`.
+//!
+//! The IP and TCP headers are synthesized: the segments are the proxy's reads rather than
+//! the packets on the wire, and the timestamps are those of the reads.  The destination
+//! address is the target's, if it is an IP address of the client's family, and otherwise
+//! an address in 198.18.0.0/15 or 2001:db8::/32 derived from the tunnel's ID.  The SYN of
+//! each connection carries a comment with the tunnel's ID and target.
+//!
+//! Like taps, captures contain clients' traffic, and are for debugging only.
+
+use crate::access::AccessRecord;
+use crate::config::CaptureConfig;
+use crate::connection::Direction;
+use crate::metrics::METRICS;
+use crate::telemetry::spawn;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::mpsc;
+
+/// The capture, if it has been installed with `Capture::install`
+static CAPTURE: RwLock<Option<Arc<Capture>>> = RwLock::new(None);
+
+/// Packets that may wait for the writer before more are dropped
+const QUEUE: usize = 1024;
+
+/// The largest TCP payload in a synthesized segment, so that its IP packet fits in 64k
+const MAX_SEGMENT: usize = 65535 - 60;
+
+/// The pcapng link type for raw IPv4 and IPv6 packets
+const LINKTYPE_RAW: u16 = 101;
+
+const TCP_FIN: u8 = 0x01;
+const TCP_SYN: u8 = 0x02;
+const TCP_PSH: u8 = 0x08;
+const TCP_ACK: u8 = 0x10;
+
+/// A period during which capture was running, written to a single file
+struct Session {
+    generation: u64,
+    path: PathBuf,
+}
+
+/// A message to the writer
+enum Message {
+    /// Capture has started, to a new file
+    Start { generation: u64, path: PathBuf },
+    Packet {
+        generation: u64,
+        timestamp: SystemTime,
+        data: Vec<u8>,
+        comment: Option<String>,
+    },
+    /// Capture has stopped, so its file can be closed
+    Stop,
+}
+
+/// Packet capture, and the writer of its files
+pub(crate) struct Capture {
+    config: CaptureConfig,
+    /// The running session, if any
+    session: Mutex<Option<Session>>,
+    generations: AtomicU64,
+    sender: mpsc::Sender<Message>,
+    /// The writer's end of the channel, until the writer is started
+    receiver: Mutex<Option<mpsc::Receiver<Message>>>,
+}
+
+impl Capture {
+    pub(crate) fn new(config: &CaptureConfig) -> Self {
+        let (sender, receiver) = mpsc::channel(QUEUE);
+        let capture = Self {
+            config: config.clone(),
+            session: Mutex::new(None),
+            generations: AtomicU64::new(0),
+            sender,
+            receiver: Mutex::new(Some(receiver)),
+        };
+        if config.enabled {
+            capture.start();
+        }
+        capture
+    }
+
+    /// Make this capture available to new tunnels and to the admin server, and start its
+    /// writer
+    pub(crate) fn install(self: &Arc<Self>) {
+        *CAPTURE.write().unwrap() = Some(self.clone());
+        self.start_writer();
+    }
+
+    /// Start writing packets in a background task
+    fn start_writer(self: &Arc<Self>) {
+        if let Some(receiver) = self.receiver.lock().unwrap().take() {
+            spawn("capture", write(self.config.max_file_bytes, receiver));
+        }
+    }
+
+    /// Start capturing new tunnels to a new file, if not already capturing, returning the
+    /// file's path
+    pub(crate) fn start(&self) -> PathBuf {
+        let mut session = self.session.lock().unwrap();
+        if let Some(session) = &*session {
+            return session.path.clone();
+        }
+        let generation = self.generations.fetch_add(1, Ordering::Relaxed) + 1;
+        let secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let dir = self.config.dir.as_ref().expect("validated in config");
+        let path = dir.join(format!("giphyproxy-{}-{}.pcapng", secs, generation));
+        log::warn!(
+            "capturing tunnels to {:?}; tunnel data will be copied",
+            path
+        );
+        *session = Some(Session {
+            generation,
+            path: path.clone(),
+        });
+        let start = Message::Start {
+            generation,
+            path: path.clone(),
+        };
+        if self.sender.try_send(start).is_err() {
+            log::warn!(
+                "capture writer has fallen behind; not capturing to {:?}",
+                path
+            );
+        }
+        path
+    }
+
+    /// Stop capturing; tunnels already captured are no longer written
+    pub(crate) fn stop(&self) {
+        if let Some(session) = self.session.lock().unwrap().take() {
+            log::warn!("stopped capturing tunnels to {:?}", session.path);
+            let _ = self.sender.try_send(Message::Stop);
+        }
+    }
+
+    /// The file being written, if capture is running
+    pub(crate) fn file(&self) -> Option<PathBuf> {
+        self.session
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|s| s.path.clone())
+    }
+
+    /// Begin capturing the tunnel for `record`, if capture is running
+    pub(crate) fn open(self: &Arc<Self>, record: &AccessRecord) -> Option<Flow> {
+        let generation = self.session.lock().unwrap().as_ref()?.generation;
+        METRICS.tunnels_captured.inc();
+        let (client, server) = endpoints(record);
+        let flow = Flow {
+            capture: self.clone(),
+            generation,
+            client,
+            server,
+            seq: [AtomicU32::new(0), AtomicU32::new(0)],
+        };
+        let comment = format!(
+            "tunnel {} to {}",
+            record.id,
+            record.target.as_deref().unwrap_or("-")
+        );
+        flow.send(Direction::Up, TCP_SYN, &[], Some(comment));
+        flow.send(Direction::Down, TCP_SYN | TCP_ACK, &[], None);
+        flow.send(Direction::Up, TCP_ACK, &[], None);
+        Some(flow)
+    }
+}
+
+/// Whether capture is configured, and if so the file being written, if it is running
+pub(crate) fn status() -> Option<Option<PathBuf>> {
+    CAPTURE
+        .read()
+        .unwrap()
+        .as_ref()
+        .map(|capture| capture.file())
+}
+
+/// Start or stop the installed capture, returning false if capture is not configured
+pub(crate) fn set_running(running: bool) -> bool {
+    match CAPTURE.read().unwrap().as_ref() {
+        Some(capture) => {
+            if running {
+                capture.start();
+            } else {
+                capture.stop();
+            }
+            true
+        }
+        None => false,
+    }
+}
+
+/// Begin capturing the tunnel for `record` with the installed capture, if any
+pub(crate) fn open(record: &AccessRecord) -> Option<Flow> {
+    CAPTURE.read().unwrap().as_ref()?.open(record)
+}
+
+/// The client and server addresses of the synthesized connection for a tunnel
+fn endpoints(record: &AccessRecord) -> (SocketAddr, SocketAddr) {
+    let client = record
+        .client
+        .map(|c| SocketAddr::new(c.ip().to_canonical(), c.port()))
+        .unwrap_or_else(|| (Ipv4Addr::LOCALHOST, 0).into());
+    let target = record.target.as_deref().unwrap_or("");
+    if let Ok(server) = target.parse::<SocketAddr>() {
+        let server = SocketAddr::new(server.ip().to_canonical(), server.port());
+        if server.is_ipv4() == client.is_ipv4() {
+            return (client, server);
+        }
+    }
+    let port = target
+        .rsplit_once(':')
+        .and_then(|(_, port)| port.parse().ok())
+        .unwrap_or(0);
+    let id = record.id;
+    let ip = match client {
+        SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::new(
+            198,
+            18 + ((id >> 16) & 1) as u8,
+            (id >> 8) as u8,
+            id as u8,
+        )),
+        SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::from((0x2001_0db8u128 << 96) | id as u128)),
+    };
+    (client, SocketAddr::new(ip, port))
+}
+
+/// The capture of a single tunnel, as a TCP connection.  The connection is closed when this
+/// is dropped.
+pub(crate) struct Flow {
+    capture: Arc<Capture>,
+    generation: u64,
+    client: SocketAddr,
+    server: SocketAddr,
+    /// The next sequence numbers from the client and from the server
+    seq: [AtomicU32; 2],
+}
+
+impl Flow {
+    /// Capture data sent in the given direction
+    pub(crate) fn copy(&self, direction: Direction, data: &[u8]) {
+        for segment in data.chunks(MAX_SEGMENT) {
+            self.send(direction, TCP_PSH | TCP_ACK, segment, None);
+        }
+    }
+
+    /// Synthesize a segment in the given direction, and pass it to the writer
+    fn send(&self, direction: Direction, flags: u8, payload: &[u8], comment: Option<String>) {
+        let (src, dst, seq, ack) = match direction {
+            Direction::Up => (self.client, self.server, &self.seq[0], &self.seq[1]),
+            Direction::Down => (self.server, self.client, &self.seq[1], &self.seq[0]),
+        };
+        // SYN and FIN each take a sequence number
+        let len = payload.len() as u32 + u32::from(flags & (TCP_SYN | TCP_FIN) != 0);
+        let seq = seq.fetch_add(len, Ordering::Relaxed);
+        let ack = ack.load(Ordering::Relaxed);
+        let message = Message::Packet {
+            generation: self.generation,
+            timestamp: SystemTime::now(),
+            data: packet(src, dst, seq, ack, flags, payload),
+            comment,
+        };
+        if self.capture.sender.try_send(message).is_err() {
+            METRICS.capture_packets_dropped.inc();
+        }
+    }
+}
+
+impl Drop for Flow {
+    fn drop(&mut self) {
+        self.send(Direction::Up, TCP_FIN | TCP_ACK, &[], None);
+        self.send(Direction::Down, TCP_FIN | TCP_ACK, &[], None);
+        self.send(Direction::Up, TCP_ACK, &[], None);
+    }
+}
+
+/// Build an IP packet carrying a TCP segment.  Both addresses must be of the same family.
+fn packet(
+    src: SocketAddr,
+    dst: SocketAddr,
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    payload: &[u8],
+) -> Vec<u8> {
+    let mut tcp = Vec::with_capacity(20 + payload.len());
+    tcp.extend_from_slice(&src.port().to_be_bytes());
+    tcp.extend_from_slice(&dst.port().to_be_bytes());
+    tcp.extend_from_slice(&seq.to_be_bytes());
+    tcp.extend_from_slice(&ack.to_be_bytes());
+    // a 20-byte header, the flags, a 64k window, the checksum, and no urgent pointer
+    tcp.extend_from_slice(&[0x50, flags, 0xff, 0xff, 0, 0, 0, 0]);
+    tcp.extend_from_slice(payload);
+    let tcp_len = tcp.len();
+
+    let mut packet = match (src.ip(), dst.ip()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            let mut header = vec![0x45, 0];
+            header.extend_from_slice(&((20 + tcp_len) as u16).to_be_bytes());
+            // no ID, don't fragment, a TTL of 64, TCP, and the checksum
+            header.extend_from_slice(&[0, 0, 0x40, 0, 64, 6, 0, 0]);
+            header.extend_from_slice(&src.octets());
+            header.extend_from_slice(&dst.octets());
+            let sum = checksum(&header);
+            header[10..12].copy_from_slice(&sum.to_be_bytes());
+
+            let mut pseudo = vec![];
+            pseudo.extend_from_slice(&src.octets());
+            pseudo.extend_from_slice(&dst.octets());
+            pseudo.extend_from_slice(&[0, 6]);
+            pseudo.extend_from_slice(&(tcp_len as u16).to_be_bytes());
+            pseudo.extend_from_slice(&tcp);
+            let sum = checksum(&pseudo);
+            tcp[16..18].copy_from_slice(&sum.to_be_bytes());
+            header
+        }
+        (IpAddr::V6(src), IpAddr::V6(dst)) => {
+            let mut header = vec![0x60, 0, 0, 0];
+            header.extend_from_slice(&(tcp_len as u16).to_be_bytes());
+            // TCP, and a hop limit of 64
+            header.extend_from_slice(&[6, 64]);
+            header.extend_from_slice(&src.octets());
+            header.extend_from_slice(&dst.octets());
+
+            let mut pseudo = vec![];
+            pseudo.extend_from_slice(&src.octets());
+            pseudo.extend_from_slice(&dst.octets());
+            pseudo.extend_from_slice(&(tcp_len as u32).to_be_bytes());
+            pseudo.extend_from_slice(&[0, 0, 0, 6]);
+            pseudo.extend_from_slice(&tcp);
+            let sum = checksum(&pseudo);
+            tcp[16..18].copy_from_slice(&sum.to_be_bytes());
+            header
+        }
+        _ => unreachable!("synthesized addresses are of the same family"),
+    };
+    packet.extend_from_slice(&tcp);
+    packet
+}
+
+/// The Internet checksum (RFC 1071) of `data`
+fn checksum(data: &[u8]) -> u16 {
+    let mut sum: u32 = data
+        .chunks(2)
+        .map(|pair| u32::from(pair[0]) << 8 | u32::from(*pair.get(1).unwrap_or(&0)))
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// A pcapng block of the given type, with `body` padded to a multiple of four bytes
+fn block(block_type: u32, mut body: Vec<u8>) -> Vec<u8> {
+    pad(&mut body);
+    let total = (body.len() + 12) as u32;
+    let mut block = Vec::with_capacity(total as usize);
+    block.extend_from_slice(&block_type.to_le_bytes());
+    block.extend_from_slice(&total.to_le_bytes());
+    block.extend_from_slice(&body);
+    block.extend_from_slice(&total.to_le_bytes());
+    block
+}
+
+fn pad(buf: &mut Vec<u8>) {
+    while !buf.len().is_multiple_of(4) {
+        buf.push(0);
+    }
+}
+
+/// The section header and interface description that begin each capture file
+fn file_header() -> Vec<u8> {
+    let mut section = vec![];
+    section.extend_from_slice(&0x1a2b_3c4du32.to_le_bytes());
+    // version 1.0, and an unspecified section length
+    section.extend_from_slice(&1u16.to_le_bytes());
+    section.extend_from_slice(&0u16.to_le_bytes());
+    section.extend_from_slice(&(-1i64).to_le_bytes());
+    let mut interface = vec![];
+    interface.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+    // reserved, and no snap length
+    interface.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+
+    let mut header = block(0x0a0d_0d0a, section);
+    header.extend_from_slice(&block(1, interface));
+    header
+}
+
+/// An enhanced packet block for a packet captured at `timestamp`, with an optional
+/// comment
+fn enhanced_packet(timestamp: SystemTime, data: &[u8], comment: Option<&str>) -> Vec<u8> {
+    // the default timestamp resolution is microseconds
+    let micros = timestamp
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64;
+    let mut body = vec![];
+    body.extend_from_slice(&0u32.to_le_bytes());
+    body.extend_from_slice(&((micros >> 32) as u32).to_le_bytes());
+    body.extend_from_slice(&(micros as u32).to_le_bytes());
+    body.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    body.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    body.extend_from_slice(data);
+    pad(&mut body);
+    if let Some(comment) = comment {
+        // opt_comment, then opt_endofopt
+        body.extend_from_slice(&1u16.to_le_bytes());
+        body.extend_from_slice(&(comment.len() as u16).to_le_bytes());
+        body.extend_from_slice(comment.as_bytes());
+        pad(&mut body);
+        body.extend_from_slice(&[0, 0, 0, 0]);
+    }
+    block(6, body)
+}
+
+/// An open capture file
+struct File {
+    generation: u64,
+    path: PathBuf,
+    out: BufWriter<tokio::fs::File>,
+    written: u64,
+    full: bool,
+}
+
+impl File {
+    /// Create a capture file for the given session, and write its header
+    async fn create(generation: u64, path: PathBuf) -> io::Result<Self> {
+        let mut out = BufWriter::new(tokio::fs::File::create(&path).await?);
+        let header = file_header();
+        out.write_all(&header).await?;
+        Ok(Self {
+            generation,
+            path,
+            out,
+            written: header.len() as u64,
+            full: false,
+        })
+    }
+}
+
+/// The writer's state
+struct Writer {
+    max_file_bytes: u64,
+    /// The file for the running session, if it could be created
+    file: Option<File>,
+}
+
+/// Write each session's packets to its file, until the capture is dropped
+async fn write(max_file_bytes: u64, mut messages: mpsc::Receiver<Message>) {
+    let mut writer = Writer {
+        max_file_bytes,
+        file: None,
+    };
+    while let Some(message) = messages.recv().await {
+        writer.handle(message).await;
+        while let Ok(message) = messages.try_recv() {
+            writer.handle(message).await;
+        }
+        // flush whenever the queue is empty, so the file is usable while capture runs
+        if let Some(file) = &mut writer.file {
+            if let Err(e) = file.out.flush().await {
+                log::warn!("writing capture file {:?}: {}", file.path, e);
+                writer.file = None;
+            }
+        }
+    }
+}
+
+impl Writer {
+    async fn handle(&mut self, message: Message) {
+        match message {
+            Message::Start { generation, path } => {
+                self.close().await;
+                match File::create(generation, path.clone()).await {
+                    Ok(file) => self.file = Some(file),
+                    Err(e) => log::warn!("creating capture file {:?}: {}", path, e),
+                }
+            }
+            Message::Packet {
+                generation,
+                timestamp,
+                data,
+                comment,
+            } => {
+                // packets of flows from earlier sessions are dropped
+                let file = match &mut self.file {
+                    Some(file) if file.generation == generation => file,
+                    _ => return,
+                };
+                let block = enhanced_packet(timestamp, &data, comment.as_deref());
+                if file.written + block.len() as u64 > self.max_file_bytes {
+                    if !file.full {
+                        log::warn!("capture file {:?} is full; dropping packets", file.path);
+                        file.full = true;
+                    }
+                    METRICS.capture_packets_dropped.inc();
+                    return;
+                }
+                file.written += block.len() as u64;
+                if let Err(e) = file.out.write_all(&block).await {
+                    log::warn!("writing capture file {:?}: {}", file.path, e);
+                    self.file = None;
+                }
+            }
+            Message::Stop => self.close().await,
+        }
+    }
+
+    async fn close(&mut self) {
+        if let Some(mut file) = self.file.take() {
+            if let Err(e) = file.out.shutdown().await {
+                log::warn!("closing capture file {:?}: {}", file.path, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::temp_path;
+    use std::time::Duration;
+
+    /// The types of the complete blocks in a capture file
+    fn block_types(mut content: &[u8]) -> Vec<u32> {
+        let mut types = vec![];
+        while content.len() >= 8 {
+            let word = |i: usize| {
+                u32::from_le_bytes([content[i], content[i + 1], content[i + 2], content[i + 3]])
+            };
+            let len = word(4) as usize;
+            if len > content.len() {
+                break;
+            }
+            types.push(word(0));
+            content = &content[len..];
+        }
+        types
+    }
+
+    fn record(client: &str, target: &str) -> AccessRecord {
+        let mut record = AccessRecord::new(Some(client.parse().unwrap()));
+        record.target = Some(target.into());
+        record
+    }
+
+    #[test]
+    fn test_checksums() {
+        let src = "10.0.0.1:5555".parse().unwrap();
+        let dst = "192.0.2.1:443".parse().unwrap();
+        let v4 = packet(src, dst, 1, 2, TCP_PSH | TCP_ACK, b"HELLO");
+        assert_eq!(v4.len(), 45);
+        // the checksum of data including its checksum is zero
+        assert_eq!(checksum(&v4[..20]), 0);
+        let mut pseudo = v4[12..20].to_vec();
+        pseudo.extend_from_slice(&[0, 6, 0, 25]);
+        pseudo.extend_from_slice(&v4[20..]);
+        assert_eq!(checksum(&pseudo), 0);
+
+        let src = "[2001:db8::1]:5555".parse().unwrap();
+        let dst = "[2001:db8::2]:443".parse().unwrap();
+        let v6 = packet(src, dst, 1, 2, TCP_ACK, b"odd");
+        assert_eq!(v6.len(), 63);
+        let mut pseudo = v6[8..40].to_vec();
+        pseudo.extend_from_slice(&[0, 0, 0, 23, 0, 0, 0, 6]);
+        pseudo.extend_from_slice(&v6[40..]);
+        assert_eq!(checksum(&pseudo), 0);
+    }
+
+    #[test]
+    fn test_endpoints() {
+        let (client, server) = endpoints(&record("10.0.0.1:5555", "192.0.2.1:443"));
+        assert_eq!(client, "10.0.0.1:5555".parse().unwrap());
+        assert_eq!(server, "192.0.2.1:443".parse().unwrap());
+
+        // names, and addresses of the other family, are replaced
+        let (_, server) = endpoints(&record("10.0.0.1:5555", "api.giphy.com:443"));
+        assert!(server.ip().to_string().starts_with("198.1"));
+        assert_eq!(server.port(), 443);
+        let (_, server) = endpoints(&record("[::1]:5555", "192.0.2.1:443"));
+        assert!(server.ip().to_string().starts_with("2001:db8::"));
+    }
+
+    #[tokio::test]
+    async fn test_capture_file() {
+        let dir = temp_path("capture");
+        std::fs::create_dir_all(&dir).unwrap();
+        let capture = Arc::new(Capture::new(&CaptureConfig {
+            dir: Some(dir.clone()),
+            ..CaptureConfig::default()
+        }));
+        capture.start_writer();
+
+        // nothing is captured until capture starts
+        let record = record("10.0.0.1:5555", "api.giphy.com:443");
+        assert!(capture.open(&record).is_none());
+        let path = capture.start();
+        let flow = capture.open(&record).unwrap();
+        flow.copy(Direction::Up, b"HELLO");
+        drop(flow);
+        capture.stop();
+        assert_eq!(capture.file(), None);
+
+        // the file has the header, then the handshake, the data, and the close
+        let mut content = vec![];
+        for _ in 0..100 {
+            content = std::fs::read(&path).unwrap_or_default();
+            if block_types(&content).len() == 9 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(
+            block_types(&content),
+            vec![0x0a0d0d0a, 1, 6, 6, 6, 6, 6, 6, 6]
+        );
+        assert!(content.windows(5).any(|w| w == b"HELLO"));
+        let comment = format!("tunnel {} to api.giphy.com:443", record.id);
+        assert!(content
+            .windows(comment.len())
+            .any(|w| w == comment.as_bytes()));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,432 @@
+//! Fault injection, for testing how clients cope with a misbehaving backend.  With
+//! `backend.chaos` configured, a percentage of backend connections are each given one of
+//! the configured faults: a delay before connecting, data from the backend that ends
+//! early, a reset part way through, or data from the backend delivered slowly.  The faults
+//! are injected by a `FaultSocket`, which the `testing` module also uses.  Clients
+//! see these through their tunnels as they would see the same faults in the backend.
+//!
+//! The choices are made by a generator seeded from `seed` and the number of the
+//! connection, so the Nth backend connection gets the same fault in every run with the
+//! same seed.
+
+use crate::backend::Backend;
+use crate::config::{ChaosConfig, ChaosFault};
+use crate::connection::ConnectionInfo;
+use crate::error::Result;
+use crate::http::authority;
+use crate::metrics::METRICS;
+use std::convert::TryFrom;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{sleep, Sleep};
+
+/// Operations per second on a socket whose rate is limited, each of an equal share of the
+/// rate
+const THROTTLE_STEPS_PER_SECOND: u64 = 10;
+
+/// A SplitMix64 generator, which is small, fast, and random enough to choose faults
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// A number in `0..n`, for nonzero `n`
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+
+    /// A number in `0.0..100.0`
+    fn percent(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64 * 100.0
+    }
+}
+
+/// A fault chosen for a connection, with its parameter
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Fault {
+    /// Wait this long before connecting
+    Latency(Duration),
+    /// End the data from the backend after this many bytes
+    Truncate(u64),
+    /// Fail with a reset after this many bytes from the backend
+    Reset(u64),
+    /// Deliver the data from the backend at this many bytes per second
+    SlowRead(u64),
+}
+
+impl Fault {
+    /// The faults to inject into the connection's socket
+    fn spec(self) -> FaultSpec {
+        match self {
+            Fault::Latency(_) => FaultSpec::default(),
+            Fault::Truncate(bytes) => FaultSpec {
+                truncate_after: Some(bytes),
+                ..FaultSpec::default()
+            },
+            Fault::Reset(bytes) => FaultSpec {
+                reset_after: Some(bytes),
+                ..FaultSpec::default()
+            },
+            Fault::SlowRead(rate) => FaultSpec {
+                read_bytes_per_sec: Some(rate),
+                ..FaultSpec::default()
+            },
+        }
+    }
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Fault::Latency(delay) => write!(f, "latency of {:?}", delay),
+            Fault::Truncate(bytes) => write!(f, "truncation after {} bytes", bytes),
+            Fault::Reset(bytes) => write!(f, "reset after {} bytes", bytes),
+            Fault::SlowRead(rate) => write!(f, "slow reads at {} bytes/s", rate),
+        }
+    }
+}
+
+/// A backend which wraps another, injecting faults into some of its connections
+pub struct ChaosBackend<B: Backend> {
+    inner: B,
+    config: ChaosConfig,
+    seed: u64,
+    connections: AtomicU64,
+}
+
+impl<B: Backend> ChaosBackend<B> {
+    /// Wrap `inner`, injecting faults as configured.  If the configuration gives no seed,
+    /// one is chosen from the clock.
+    pub fn new(inner: B, config: &ChaosConfig) -> Self {
+        let seed = config.seed.unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map_or(0, |d| d.as_nanos() as u64)
+                ^ u64::from(std::process::id())
+        });
+        log::warn!(
+            "chaos is enabled; faults will be injected into {}% of backend connections (seed {})",
+            config.percent,
+            seed
+        );
+        Self {
+            inner,
+            config: config.clone(),
+            seed,
+            connections: AtomicU64::new(0),
+        }
+    }
+
+    /// The fault, if any, for the connection with the given number
+    fn choose(&self, connection: u64) -> Option<Fault> {
+        let mut rng = Rng(Rng(self.seed ^ connection).next());
+        if rng.percent() >= self.config.percent {
+            return None;
+        }
+        let faults = &self.config.faults;
+        let fault = match faults[rng.below(faults.len() as u64) as usize] {
+            ChaosFault::Latency => Fault::Latency(self.config.latency),
+            ChaosFault::Truncate => Fault::Truncate(rng.below(self.config.max_bytes + 1)),
+            ChaosFault::Reset => Fault::Reset(rng.below(self.config.max_bytes + 1)),
+            ChaosFault::SlowRead => Fault::SlowRead(self.config.slow_read_rate),
+        };
+        Some(fault)
+    }
+}
+
+#[async_trait::async_trait]
+impl<B: Backend> Backend for ChaosBackend<B> {
+    type Socket = FaultSocket<B::Socket>;
+
+    async fn connect(&self, host: &str, port: u16) -> Result<Self::Socket> {
+        self.connect_for(&ConnectionInfo::default(), host, port)
+            .await
+    }
+
+    async fn connect_for(
+        &self,
+        info: &ConnectionInfo,
+        host: &str,
+        port: u16,
+    ) -> Result<Self::Socket> {
+        let connection = self.connections.fetch_add(1, Ordering::Relaxed);
+        let fault = self.choose(connection);
+        if let Some(fault) = fault {
+            log::info!(
+                "chaos: injecting {} into backend connection {} to {}",
+                fault,
+                connection,
+                authority(host, port)
+            );
+            METRICS.backend_chaos_faults.inc();
+        }
+        if let Some(Fault::Latency(delay)) = fault {
+            sleep(delay).await;
+        }
+        let socket = self.inner.connect_for(info, host, port).await?;
+        Ok(FaultSocket::new(
+            socket,
+            fault.map_or_else(FaultSpec::default, Fault::spec),
+        ))
+    }
+}
+
+/// The faults a `FaultSocket` injects into the socket it wraps
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct FaultSpec {
+    /// If set, the connection is reset once this many bytes have been read from it
+    pub reset_after: Option<u64>,
+    /// If set, reads reach end-of-file once this many bytes have been read, as if the other
+    /// end had closed the connection
+    pub truncate_after: Option<u64>,
+    /// If set, reads are limited to this many bytes per second
+    pub read_bytes_per_sec: Option<u64>,
+    /// If set, writes are limited to this many bytes per second
+    pub write_bytes_per_sec: Option<u64>,
+}
+
+/// A limit on the rate of reads or writes.  Each operation transfers at most a tenth of a
+/// second's worth of bytes, and the next is delayed until those bytes would have been sent
+/// at that rate.
+struct Throttle {
+    bytes_per_sec: Option<u64>,
+    delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl Throttle {
+    fn new(bytes_per_sec: Option<u64>) -> Self {
+        Self {
+            bytes_per_sec,
+            delay: None,
+        }
+    }
+
+    /// Wait until the next operation may proceed
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        if let Some(delay) = &mut self.delay {
+            ready!(delay.as_mut().poll(cx));
+            self.delay = None;
+        }
+        Poll::Ready(())
+    }
+
+    /// The most bytes the next operation may transfer
+    fn limit(&self) -> u64 {
+        self.bytes_per_sec
+            .map_or(u64::MAX, |rate| (rate / THROTTLE_STEPS_PER_SECOND).max(1))
+    }
+
+    /// Record that `n` bytes were transferred
+    fn transferred(&mut self, n: usize) {
+        if let Some(rate) = self.bytes_per_sec.filter(|_| n > 0) {
+            let secs = n as f64 / rate.max(1) as f64;
+            self.delay = Some(Box::pin(sleep(Duration::from_secs_f64(secs))));
+        }
+    }
+}
+
+/// A socket injecting the faults in a `FaultSpec` into the socket it wraps
+pub struct FaultSocket<S> {
+    inner: S,
+    spec: FaultSpec,
+    /// Bytes read so far
+    read: u64,
+    read_throttle: Throttle,
+    write_throttle: Throttle,
+}
+
+impl<S> FaultSocket<S> {
+    pub fn new(inner: S, spec: FaultSpec) -> Self {
+        Self {
+            inner,
+            spec,
+            read: 0,
+            read_throttle: Throttle::new(spec.read_bytes_per_sec),
+            write_throttle: Throttle::new(spec.write_bytes_per_sec),
+        }
+    }
+
+    fn reset() -> io::Error {
+        io::Error::new(io::ErrorKind::ConnectionReset, "injected reset")
+    }
+
+    /// Whether the connection has been reset
+    fn is_reset(&self) -> bool {
+        self.spec.reset_after == Some(self.read)
+    }
+}
+
+/// `limit`, as a length of at most `len`
+fn at_most(limit: u64, len: usize) -> usize {
+    usize::try_from(limit).map_or(len, |limit| limit.min(len))
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for FaultSocket<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.is_reset() {
+            return Poll::Ready(Err(Self::reset()));
+        }
+        ready!(this.read_throttle.poll_ready(cx));
+
+        // read no more than the bytes remaining before the reset or truncation
+        let mut limit = this.read_throttle.limit();
+        for end in [this.spec.reset_after, this.spec.truncate_after]
+            .iter()
+            .flatten()
+        {
+            limit = limit.min(end.saturating_sub(this.read));
+        }
+        if limit == 0 {
+            // truncated, as the reset was handled above
+            return Poll::Ready(Ok(()));
+        }
+        let mut limited = ReadBuf::new(buf.initialize_unfilled_to(at_most(limit, buf.remaining())));
+        ready!(Pin::new(&mut this.inner).poll_read(cx, &mut limited))?;
+        let n = limited.filled().len();
+        buf.advance(n);
+        this.read += n as u64;
+        this.read_throttle.transferred(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for FaultSocket<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.is_reset() {
+            return Poll::Ready(Err(Self::reset()));
+        }
+        ready!(this.write_throttle.poll_ready(cx));
+        let limit = at_most(this.write_throttle.limit(), buf.len());
+        let n = ready!(Pin::new(&mut this.inner).poll_write(cx, &buf[..limit]))?;
+        this.write_throttle.transferred(n);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::backend::MockBackend;
+    use std::time::Instant;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn chaos(faults: Vec<ChaosFault>, percent: f64) -> ChaosBackend<MockBackend> {
+        ChaosBackend::new(
+            MockBackend,
+            &ChaosConfig {
+                percent,
+                faults,
+                seed: Some(42),
+                latency: Duration::from_millis(200),
+                max_bytes: 100,
+                slow_read_rate: 1000,
+            },
+        )
+    }
+
+    #[test]
+    fn test_choose() {
+        let all = ChaosConfig::default().faults;
+        let backend = chaos(all.clone(), 50.0);
+        let faults: Vec<_> = (0..1000).map(|n| backend.choose(n)).collect();
+        // the same seed gives the same choices
+        let again = chaos(all.clone(), 50.0);
+        assert_eq!(
+            faults,
+            (0..1000).map(|n| again.choose(n)).collect::<Vec<_>>()
+        );
+
+        let injected = faults.iter().flatten().count();
+        assert!((400..600).contains(&injected), "{}", injected);
+        for fault in faults.iter().flatten() {
+            match fault {
+                Fault::Latency(delay) => assert_eq!(*delay, Duration::from_millis(200)),
+                Fault::Truncate(bytes) | Fault::Reset(bytes) => assert!(*bytes <= 100),
+                Fault::SlowRead(rate) => assert_eq!(*rate, 1000),
+            }
+        }
+
+        assert!((0..1000).all(|n| chaos(all.clone(), 0.0).choose(n).is_none()));
+        let backend = chaos(vec![ChaosFault::Reset], 100.0);
+        assert!((0..1000).all(|n| matches!(backend.choose(n), Some(Fault::Reset(_)))));
+    }
+
+    #[tokio::test]
+    async fn test_truncate() {
+        let backend = chaos(vec![ChaosFault::Truncate], 100.0);
+        let Some(Fault::Truncate(bytes)) = backend.choose(0) else {
+            panic!("expected truncation");
+        };
+        let mut socket = backend.connect("echo.example.com", 7).await.unwrap();
+        socket.write_all(&[1u8; 200]).await.unwrap();
+        socket.shutdown().await.unwrap();
+        let mut echoed = vec![];
+        socket.read_to_end(&mut echoed).await.unwrap();
+        assert_eq!(echoed.len() as u64, bytes);
+    }
+
+    #[tokio::test]
+    async fn test_reset() {
+        let backend = chaos(vec![ChaosFault::Reset], 100.0);
+        let Some(Fault::Reset(bytes)) = backend.choose(0) else {
+            panic!("expected reset");
+        };
+        let mut socket = backend.connect("echo.example.com", 7).await.unwrap();
+        socket.write_all(&[1u8; 200]).await.unwrap();
+        let mut echoed = vec![];
+        let err = socket.read_to_end(&mut echoed).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+        assert_eq!(echoed.len() as u64, bytes);
+        assert!(socket.write_all(b"more").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_slow_read() {
+        let backend = chaos(vec![ChaosFault::SlowRead], 100.0);
+        let mut socket = backend.connect("echo.example.com", 7).await.unwrap();
+        socket.write_all(&[1u8; 300]).await.unwrap();
+        let start = Instant::now();
+        let mut echoed = vec![0u8; 300];
+        socket.read_exact(&mut echoed).await.unwrap();
+        // three reads of 100 bytes, the second and third each 0.1s after the one before
+        assert!(start.elapsed() >= Duration::from_millis(200));
+    }
+
+    #[tokio::test]
+    async fn test_latency() {
+        let backend = chaos(vec![ChaosFault::Latency], 100.0);
+        let start = Instant::now();
+        backend.connect("echo.example.com", 7).await.unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(200));
+    }
+}
@@ -0,0 +1,367 @@
+//! The client side of HTTP CONNECT, used by `ChainedBackend` to tunnel through a parent
+//! proxy, and by the `giphyproxy` binary's commands that talk to a running proxy: the
+//! health check, and probes that time each step of a TLS connection through the proxy.
+
+use crate::config::Config;
+use crate::http::authority;
+use anyhow::{bail, Context};
+use rustls::pki_types::ServerName;
+use std::convert::TryFrom;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use tokio_rustls::TlsConnector;
+
+/// Maximum size of a response head
+const MAX_RESPONSE_HEAD: usize = 4096;
+
+/// Ask the proxy at the other end of `socket` to connect to `host` and `port`, with the
+/// given `Proxy-Authorization` header, if any.  Returns the response's status, or None if
+/// the response is not HTTP.  With a 2xx status, the socket then carries the tunnel.
+pub async fn connect<S>(
+    socket: &mut S,
+    host: &str,
+    port: u16,
+    authorization: Option<&str>,
+) -> io::Result<Option<u16>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut request = format!(
+        "CONNECT {target} HTTP/1.1\r\nHost: {target}\r\n",
+        target = authority(host, port)
+    );
+    if let Some(authorization) = authorization {
+        request.push_str(&format!("Proxy-Authorization: {}\r\n", authorization));
+    }
+    request.push_str("\r\n");
+    socket.write_all(request.as_bytes()).await?;
+    read_status(socket).await
+}
+
+/// Read a response head, returning its status, or None if it is not HTTP.  The head is
+/// read a byte at a time, so that nothing after it is consumed.
+pub async fn read_status<S>(socket: &mut S) -> io::Result<Option<u16>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_RESPONSE_HEAD {
+            return Err(io::Error::other("response head is too large"));
+        }
+        head.push(socket.read_u8().await?);
+    }
+    Ok(parse_status(&head))
+}
+
+/// Parse the status code from an HTTP response head
+fn parse_status(head: &[u8]) -> Option<u16> {
+    let head = std::str::from_utf8(head).ok()?;
+    let mut parts = head.split(' ');
+    match (parts.next(), parts.next()) {
+        (Some(version), Some(status)) if version.starts_with("HTTP/1.") => status.parse().ok(),
+        _ => None,
+    }
+}
+
+/// The address at which to reach a server listening on `addr`: the loopback address, if
+/// it listens on all addresses
+pub fn local_addr(addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => (Ipv4Addr::LOCALHOST, addr.port()).into(),
+        IpAddr::V6(ip) if ip.is_unspecified() => (Ipv6Addr::LOCALHOST, addr.port()).into(),
+        _ => addr,
+    }
+}
+
+/// Check that the proxy described by `config` is running on this host, returning a
+/// description of what was checked.  If the admin server is enabled, this requests
+/// `/healthz`, or `/readyz` if `ready` is set, and requires a 200 response.  Otherwise, it
+/// sends a CONNECT request for the backend's `host` and `port` to the first listen
+/// address, and requires any response other than a server error.
+pub async fn healthcheck(config: &Config, ready: bool, limit: Duration) -> anyhow::Result<String> {
+    let result = match config.admin.listen {
+        Some(addr) => timeout(limit, check_admin(local_addr(addr), ready)).await,
+        None => {
+            if config.tls.cert.is_some() {
+                bail!("the health check requires admin.listen when tls.cert is set");
+            }
+            let Some(&addr) = config.listen.first() else {
+                bail!("no listen addresses configured");
+            };
+            let backend = &config.backend;
+            timeout(
+                limit,
+                check_connect(local_addr(addr), &backend.host, backend.port),
+            )
+            .await
+        }
+    };
+    result.map_err(|_| anyhow::anyhow!("no response within {:?}", limit))?
+}
+
+/// Request `/healthz` or `/readyz` from the admin server at `addr`
+async fn check_admin(addr: SocketAddr, ready: bool) -> anyhow::Result<String> {
+    let path = if ready { "/readyz" } else { "/healthz" };
+    let mut socket = TcpStream::connect(addr)
+        .await
+        .with_context(|| format!("connecting to admin server at {}", addr))?;
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        path, addr
+    );
+    socket.write_all(request.as_bytes()).await?;
+    match read_status(&mut socket).await? {
+        Some(200) => Ok(format!("{} at {} returned 200", path, addr)),
+        Some(status) => bail!("{} at {} returned {}", path, addr, status),
+        None => bail!("invalid response from admin server at {}", addr),
+    }
+}
+
+/// Send a CONNECT request for `host:port` to the proxy at `addr`
+async fn check_connect(addr: SocketAddr, host: &str, port: u16) -> anyhow::Result<String> {
+    let mut socket = TcpStream::connect(addr)
+        .await
+        .with_context(|| format!("connecting to proxy at {}", addr))?;
+    let target = authority(host, port);
+    match connect(&mut socket, host, port, None).await? {
+        Some(status) if status < 500 => Ok(format!(
+            "proxy at {} responded to CONNECT {} with {}",
+            addr, target, status
+        )),
+        Some(status) => bail!(
+            "proxy at {} responded to CONNECT {} with {}",
+            addr,
+            target,
+            status
+        ),
+        None => bail!("invalid response from proxy at {}", addr),
+    }
+}
+
+/// The result of a probe: how long each step of a TLS connection through the proxy took
+#[derive(Debug)]
+pub struct Probe {
+    /// The proxy's address
+    pub proxy: SocketAddr,
+    /// The destination, as `host:port`
+    pub target: String,
+    /// Time to connect to the proxy
+    pub proxy_connect: Duration,
+    /// Time from sending the CONNECT request to receiving the response
+    pub connect_response: Duration,
+    /// The status of the CONNECT response
+    pub status: u16,
+    /// Time for the TLS handshake with the destination, through the tunnel
+    pub tls_handshake: Duration,
+    /// The negotiated TLS version, such as `TLSv1_3`
+    pub tls_version: String,
+    /// The negotiated cipher suite
+    pub cipher_suite: String,
+}
+
+impl Probe {
+    /// The total time taken
+    pub fn total(&self) -> Duration {
+        self.proxy_connect + self.connect_response + self.tls_handshake
+    }
+}
+
+impl fmt::Display for Probe {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+        writeln!(
+            f,
+            "proxy connect     {:>10.1} ms  {}",
+            ms(self.proxy_connect),
+            self.proxy
+        )?;
+        writeln!(
+            f,
+            "CONNECT response  {:>10.1} ms  {} for {}",
+            ms(self.connect_response),
+            self.status,
+            self.target
+        )?;
+        writeln!(
+            f,
+            "upstream TLS      {:>10.1} ms  {} {}",
+            ms(self.tls_handshake),
+            self.tls_version,
+            self.cipher_suite
+        )?;
+        write!(f, "total             {:>10.1} ms", ms(self.total()))
+    }
+}
+
+/// Connect to `host:port` through the proxy at `proxy`, with the given
+/// `Proxy-Authorization` header, if any, and complete a TLS handshake with it using
+/// `connector`, timing each step.  The connection is then closed.
+pub async fn probe(
+    proxy: SocketAddr,
+    host: &str,
+    port: u16,
+    authorization: Option<&str>,
+    connector: &TlsConnector,
+) -> anyhow::Result<Probe> {
+    let target = authority(host, port);
+    let server_name = ServerName::try_from(host.to_owned())
+        .with_context(|| format!("invalid TLS server name {:?}", host))?;
+
+    let start = Instant::now();
+    let mut socket = TcpStream::connect(proxy)
+        .await
+        .with_context(|| format!("connecting to proxy at {}", proxy))?;
+    let proxy_connect = start.elapsed();
+
+    let start = Instant::now();
+    let status = connect(&mut socket, host, port, authorization)
+        .await
+        .with_context(|| format!("sending CONNECT {} to {}", target, proxy))?;
+    let connect_response = start.elapsed();
+    let status = match status {
+        Some(status @ 200..=299) => status,
+        Some(status) => bail!("proxy responded to CONNECT {} with {}", target, status),
+        None => bail!("invalid response from proxy at {}", proxy),
+    };
+
+    let start = Instant::now();
+    let tls = connector
+        .connect(server_name, socket)
+        .await
+        .with_context(|| format!("TLS handshake with {}", target))?;
+    let tls_handshake = start.elapsed();
+    let (_, connection) = tls.get_ref();
+    let tls_version = connection
+        .protocol_version()
+        .map(|v| format!("{:?}", v))
+        .unwrap_or_default();
+    let cipher_suite = connection
+        .negotiated_cipher_suite()
+        .map(|s| format!("{:?}", s.suite()))
+        .unwrap_or_default();
+
+    Ok(Probe {
+        proxy,
+        target,
+        proxy_connect,
+        connect_response,
+        status,
+        tls_handshake,
+        tls_version,
+        cipher_suite,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::backend::MockBackend;
+    use crate::testing::{FakeGiphy, GIPHY_HOST};
+    use crate::Proxy;
+    use rustls::{ClientConfig, RootCertStore};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_parse_status() {
+        assert_eq!(parse_status(b"HTTP/1.1 200 OK\r\n\r\n").unwrap(), 200);
+        assert_eq!(
+            parse_status(b"HTTP/1.0 502 Bad Gateway\r\n\r\n").unwrap(),
+            502
+        );
+        assert!(parse_status(b"SSH-2.0-OpenSSH\r\n\r\n").is_none());
+    }
+
+    #[test]
+    fn test_local_addr() {
+        assert_eq!(
+            local_addr("0.0.0.0:8080".parse().unwrap()),
+            "127.0.0.1:8080".parse().unwrap()
+        );
+        assert_eq!(
+            local_addr("[::]:8080".parse().unwrap()),
+            "[::1]:8080".parse().unwrap()
+        );
+        assert_eq!(
+            local_addr("10.0.0.1:8080".parse().unwrap()),
+            "10.0.0.1:8080".parse().unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_healthcheck_connect() {
+        let addrs = Proxy::builder()
+            .bind("127.0.0.1:0".parse().unwrap())
+            .backend(MockBackend)
+            .build()
+            .unwrap()
+            .start()
+            .await
+            .unwrap();
+        let config = Config {
+            listen: addrs,
+            ..Config::default()
+        };
+        let description = healthcheck(&config, false, Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert!(description.contains("with 200"), "{}", description);
+    }
+
+    #[tokio::test]
+    async fn test_healthcheck_refused() {
+        // nothing listens on this port
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = Config {
+            listen: vec![listener.local_addr().unwrap()],
+            ..Config::default()
+        };
+        drop(listener);
+        assert!(healthcheck(&config, false, Duration::from_secs(5))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_probe() {
+        let giphy = FakeGiphy::start().await.unwrap();
+        let addrs = Proxy::builder()
+            .bind("127.0.0.1:0".parse().unwrap())
+            .backend(giphy.backend())
+            .build()
+            .unwrap()
+            .start()
+            .await
+            .unwrap();
+        let mut roots = RootCertStore::empty();
+        for cert in rustls_pemfile::certs(&mut giphy.cert_pem().as_bytes()) {
+            roots.add(cert.unwrap()).unwrap();
+        }
+        let config =
+            ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_root_certificates(roots)
+                .with_no_client_auth();
+        let connector = TlsConnector::from(Arc::new(config));
+
+        let result = probe(addrs[0], GIPHY_HOST, 443, None, &connector)
+            .await
+            .unwrap();
+        assert_eq!(result.status, 200);
+        assert_eq!(result.target, "api.giphy.com:443");
+        assert_eq!(result.tls_version, "TLSv1_3");
+        assert!(result.to_string().contains("upstream TLS"));
+
+        // the fake backend refuses other destinations
+        let err = probe(addrs[0], "example.com", 443, None, &connector)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("with 403"), "{}", err);
+    }
+}
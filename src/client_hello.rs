@@ -0,0 +1,361 @@
+//! A minimal parser for the TLS ClientHello that begins a tunnel, used to check the server
+//! name (SNI) the client asks for without terminating TLS.  Only as much of the handshake
+//! is parsed as is needed to find the `server_name` extension
+//! ([RFC 6066](https://tools.ietf.org/html/rfc6066#section-3)); the ClientHello may span
+//! several TLS records.
+//!
+//! For tunnels which need only carry TLS, without checking the server name, the start of
+//! the first record is enough.  The `fingerprint` module parses the rest of the
+//! ClientHello, to fingerprint the client.
+
+use anyhow::{bail, Context, Result};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::time::timeout;
+
+/// TLS record content type for handshake messages
+const CONTENT_HANDSHAKE: u8 = 22;
+
+/// Handshake message type for ClientHello
+const HANDSHAKE_CLIENT_HELLO: u8 = 1;
+
+/// Extension type for the server name
+const EXTENSION_SERVER_NAME: u16 = 0;
+
+/// Server name type for DNS hostnames, the only type defined
+const NAME_TYPE_HOST_NAME: u8 = 0;
+
+/// Maximum length of a TLS record's payload
+const MAX_RECORD: usize = 16384 + 2048;
+
+/// Maximum total size of the records carrying a ClientHello
+const MAX_HELLO: usize = 65536;
+
+/// The result of parsing the start of a TLS stream
+#[derive(Debug, Clone, PartialEq)]
+pub enum Hello {
+    /// More data is needed to parse the ClientHello
+    Incomplete,
+    /// A complete ClientHello, with the server name from its SNI extension, if any
+    Complete { server_name: Option<String> },
+}
+
+/// Parse a ClientHello from the start of a TLS stream
+pub fn parse(data: &[u8]) -> Result<Hello> {
+    match message(data)? {
+        Some(body) => {
+            let server_name = server_name(&body).context("invalid ClientHello")?;
+            Ok(Hello::Complete { server_name })
+        }
+        None => Ok(Hello::Incomplete),
+    }
+}
+
+/// Reassemble the ClientHello at the start of a TLS stream from the records carrying it,
+/// returning the body of the handshake message, or None if more data is needed
+pub(crate) fn message(data: &[u8]) -> Result<Option<Vec<u8>>> {
+    let mut handshake = vec![];
+    let mut records = data;
+    loop {
+        if records.len() < 5 {
+            return Ok(None);
+        }
+        let length = handshake_record(&records[..5])?;
+        if records.len() < 5 + length {
+            return Ok(None);
+        }
+        handshake.extend_from_slice(&records[5..5 + length]);
+        records = &records[5 + length..];
+
+        if handshake.len() < 4 {
+            continue;
+        }
+        if handshake[0] != HANDSHAKE_CLIENT_HELLO {
+            bail!("TLS handshake does not begin with a ClientHello");
+        }
+        let length = u32::from_be_bytes([0, handshake[1], handshake[2], handshake[3]]) as usize;
+        if handshake.len() >= 4 + length {
+            handshake.truncate(4 + length);
+            handshake.drain(..4);
+            return Ok(Some(handshake));
+        }
+    }
+}
+
+/// Check the header of a TLS record, which must be a handshake record with a plausible
+/// version and length, returning the length of its payload
+fn handshake_record(header: &[u8]) -> Result<usize> {
+    let (content_type, major, minor) = (header[0], header[1], header[2]);
+    if content_type != CONTENT_HANDSHAKE || major != 3 || minor > 4 {
+        bail!("not a TLS handshake record");
+    }
+    let length = u16::from_be_bytes([header[3], header[4]]) as usize;
+    if length == 0 || length > MAX_RECORD {
+        bail!("invalid TLS record length {}", length);
+    }
+    Ok(length)
+}
+
+/// Find the server name in the body of a ClientHello
+fn server_name(body: &[u8]) -> Result<Option<String>> {
+    let mut hello = Reader(body);
+    hello.bytes(2 + 32)?; // legacy_version and random
+    hello.vec8()?; // legacy_session_id
+    hello.vec16()?; // cipher_suites
+    hello.vec8()?; // legacy_compression_methods
+    if hello.0.is_empty() {
+        // no extensions
+        return Ok(None);
+    }
+    let mut extensions = Reader(hello.vec16()?);
+    while !extensions.0.is_empty() {
+        let extension_type = extensions.u16()?;
+        let data = extensions.vec16()?;
+        if extension_type != EXTENSION_SERVER_NAME {
+            continue;
+        }
+        let mut names = Reader(Reader(data).vec16()?);
+        while !names.0.is_empty() {
+            let name_type = names.u8()?;
+            let name = names.vec16()?;
+            if name_type == NAME_TYPE_HOST_NAME {
+                let name = std::str::from_utf8(name).context("server name is not UTF-8")?;
+                return Ok(Some(name.to_owned()));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// A cursor over a byte slice, reading big-endian integers and length-prefixed vectors
+pub(crate) struct Reader<'a>(pub(crate) &'a [u8]);
+
+impl<'a> Reader<'a> {
+    pub(crate) fn bytes(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.0.len() < n {
+            bail!("truncated");
+        }
+        let (bytes, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    pub(crate) fn u8(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub(crate) fn u16(&mut self) -> Result<u16> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub(crate) fn vec8(&mut self) -> Result<&'a [u8]> {
+        let length = self.u8()? as usize;
+        self.bytes(length)
+    }
+
+    pub(crate) fn vec16(&mut self) -> Result<&'a [u8]> {
+        let length = self.u16()? as usize;
+        self.bytes(length)
+    }
+}
+
+/// Read from `socket`, appending to `buf`, until `buf` holds a complete ClientHello, and
+/// return the server name it asks for.  The ClientHello must arrive within `limit`.
+pub async fn read<S: AsyncRead + Unpin>(
+    socket: &mut S,
+    buf: &mut Vec<u8>,
+    limit: Duration,
+) -> Result<Option<String>> {
+    let read = async {
+        loop {
+            if let Hello::Complete { server_name } = parse(buf)? {
+                return Ok(server_name);
+            }
+            if buf.len() >= MAX_HELLO {
+                bail!("ClientHello is too large");
+            }
+            let n = socket
+                .read_buf(buf)
+                .await
+                .context("reading ClientHello from client")?;
+            if n == 0 {
+                bail!("client hung up before sending a ClientHello");
+            }
+        }
+    };
+    match timeout(limit, read).await {
+        Ok(result) => result,
+        Err(_) => bail!("timed out reading ClientHello from client"),
+    }
+}
+
+/// Read from `socket`, appending to `buf`, until `buf` holds at least a TLS record header,
+/// and check that it is the header of a handshake record.  The header must arrive within
+/// `limit`.
+pub async fn read_handshake_start<S: AsyncRead + Unpin>(
+    socket: &mut S,
+    buf: &mut Vec<u8>,
+    limit: Duration,
+) -> Result<()> {
+    let read = async {
+        while buf.len() < 5 {
+            let n = socket.read_buf(buf).await.context("reading from client")?;
+            if n == 0 {
+                bail!("client hung up before beginning a TLS handshake");
+            }
+        }
+        handshake_record(&buf[..5]).map(|_| ())
+    };
+    match timeout(limit, read).await {
+        Ok(result) => result,
+        Err(_) => bail!("timed out waiting for a TLS handshake from client"),
+    }
+}
+
+/// Does the server name from a ClientHello match the requested host?  Names are compared
+/// without regard to case or a trailing dot.  Clients do not send a server name when
+/// connecting to an IP address, so none is expected then.
+pub fn matches(server_name: Option<&str>, host: &str) -> bool {
+    let normalize = |name: &str| name.trim_end_matches('.').to_ascii_lowercase();
+    match server_name {
+        Some(name) => normalize(name) == normalize(host),
+        None => host.parse::<std::net::IpAddr>().is_ok(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rustls::pki_types::ServerName;
+    use rustls::{ClientConfig, ClientConnection, RootCertStore};
+    use std::convert::TryFrom;
+    use std::sync::Arc;
+
+    /// The ClientHello rustls sends when connecting to `host`
+    fn client_hello(host: &str) -> Vec<u8> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let config = ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(RootCertStore::empty())
+            .with_no_client_auth();
+        let name = ServerName::try_from(host.to_owned()).unwrap();
+        let mut conn = ClientConnection::new(Arc::new(config), name).unwrap();
+        let mut hello = vec![];
+        conn.write_tls(&mut hello).unwrap();
+        hello
+    }
+
+    #[test]
+    fn test_parse() {
+        let hello = client_hello("api.giphy.com");
+        assert_eq!(
+            parse(&hello).unwrap(),
+            Hello::Complete {
+                server_name: Some("api.giphy.com".into())
+            }
+        );
+        assert_eq!(parse(&hello[..3]).unwrap(), Hello::Incomplete);
+        assert_eq!(parse(&hello[..hello.len() - 1]).unwrap(), Hello::Incomplete);
+
+        // rustls sends no server name for IP addresses
+        assert_eq!(
+            parse(&client_hello("127.0.0.1")).unwrap(),
+            Hello::Complete { server_name: None }
+        );
+    }
+
+    #[test]
+    fn test_parse_fragmented() {
+        // split the handshake message across two records
+        let hello = client_hello("api.giphy.com");
+        let handshake = &hello[5..];
+        let mut fragmented = vec![];
+        for part in [&handshake[..10], &handshake[10..]].iter() {
+            fragmented.extend_from_slice(&[CONTENT_HANDSHAKE, 3, 1]);
+            fragmented.extend_from_slice(&(part.len() as u16).to_be_bytes());
+            fragmented.extend_from_slice(part);
+        }
+        assert_eq!(
+            parse(&fragmented).unwrap(),
+            Hello::Complete {
+                server_name: Some("api.giphy.com".into())
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert!(parse(b"GET / HTTP/1.1\r\n\r\n").is_err());
+        // an alert record
+        assert!(parse(&[21, 3, 3, 0, 2, 2, 40]).is_err());
+        // a ServerHello
+        assert!(parse(&[22, 3, 3, 0, 4, 2, 0, 0, 0]).is_err());
+        // a ClientHello too short for its fields
+        assert!(parse(&[22, 3, 3, 0, 6, 1, 0, 0, 2, 3, 3]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_read() {
+        let hello = client_hello("api.giphy.com");
+        let (mut client, mut server) = tokio::io::duplex(65536);
+        let (first, rest) = hello.split_at(20);
+        let mut buf = first.to_vec();
+        tokio::io::AsyncWriteExt::write_all(&mut client, rest)
+            .await
+            .unwrap();
+        let name = read(&mut server, &mut buf, Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(name.as_deref(), Some("api.giphy.com"));
+        assert_eq!(buf, hello);
+
+        drop(client);
+        let mut buf = vec![];
+        assert!(read(&mut server, &mut buf, Duration::from_secs(5))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_read_handshake_start() {
+        let hello = client_hello("api.giphy.com");
+        let limit = Duration::from_secs(5);
+        let (mut client, mut server) = tokio::io::duplex(65536);
+        let mut buf = vec![];
+        tokio::io::AsyncWriteExt::write_all(&mut client, &hello[..5])
+            .await
+            .unwrap();
+        read_handshake_start(&mut server, &mut buf, limit)
+            .await
+            .unwrap();
+        assert_eq!(buf, &hello[..5]);
+
+        let mut buf = b"SSH-2.0-OpenSSH_9.6\r\n".to_vec();
+        assert!(read_handshake_start(&mut server, &mut buf, limit)
+            .await
+            .is_err());
+        // TLS 1.3 records claim version 1.2 or earlier, but nothing claims version 1.4
+        let mut buf = vec![22, 3, 5, 0, 1];
+        assert!(read_handshake_start(&mut server, &mut buf, limit)
+            .await
+            .is_err());
+
+        drop(client);
+        let mut buf = vec![22];
+        assert!(read_handshake_start(&mut server, &mut buf, limit)
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn test_matches() {
+        assert!(matches(Some("api.giphy.com"), "api.giphy.com"));
+        assert!(matches(Some("API.giphy.com."), "api.giphy.com"));
+        assert!(!matches(Some("evil.example.com"), "api.giphy.com"));
+        assert!(!matches(None, "api.giphy.com"));
+        assert!(matches(None, "127.0.0.1"));
+        assert!(matches(None, "::1"));
+    }
+}
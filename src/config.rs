@@ -0,0 +1,3038 @@
+use crate::backend::{AllowEntry, Endpoint, HostPattern, PortSet};
+use anyhow::{Context, Result};
+use ipnet::IpNet;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Environment variable giving the path to a TOML configuration file
+pub const CONFIG_VAR: &str = "GIPHYPROXY_CONFIG";
+
+/// Environment variable giving the IP address on which to listen
+const BIND_ADDR_VAR: &str = "GIPHYPROXY_BIND_ADDR";
+
+/// Environment variable giving the port on which to listen
+const PORT_VAR: &str = "GIPHYPROXY_PORT";
+
+/// Networks to which backend connections are refused by default: private, loopback,
+/// link-local (including cloud metadata services), and other special-purpose ranges.
+const DEFAULT_BLOCKED_NETWORKS: &[&str] = &[
+    "0.0.0.0/8",
+    "10.0.0.0/8",
+    "100.64.0.0/10",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "172.16.0.0/12",
+    "192.0.0.0/24",
+    "192.168.0.0/16",
+    "198.18.0.0/15",
+    "224.0.0.0/4",
+    "240.0.0.0/4",
+    "::/96",
+    "::/128",
+    "::1/128",
+    "64:ff9b::/96",
+    "2002::/16",
+    "fc00::/7",
+    "fe80::/10",
+];
+
+/// Runtime configuration for the proxy.
+///
+/// This is built up in layers: defaults, then an optional TOML file, then environment
+/// variables, then command-line flags.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Addresses on which to listen
+    pub listen: Vec<SocketAddr>,
+
+    /// Number of sockets to bind to each listen address with `SO_REUSEPORT`, each with its
+    /// own accept loop, so that the kernel spreads new connections among them.  With the
+    /// default of 1, each address is bound normally.
+    pub acceptors: usize,
+
+    /// Maximum number of connections waiting to be accepted on each listen socket.  This
+    /// does not apply to sockets passed by systemd, whose backlog is set in the socket
+    /// unit.
+    pub backlog: u32,
+
+    /// Restart accept loops that panic, rather than treating the panic as fatal
+    pub restart_acceptors: bool,
+
+    /// Abort the process when a connection handler panics, rather than closing just that
+    /// connection
+    pub abort_on_panic: bool,
+
+    /// How the listen addresses treat new connections while the proxy is draining
+    pub drain_mode: DrainMode,
+
+    /// TCP options for connections accepted on the listen addresses
+    pub socket: SocketConfig,
+
+    /// The backend to which clients may connect
+    pub backend: BackendConfig,
+
+    /// Limits on client behavior
+    pub limits: LimitsConfig,
+
+    /// Logging configuration
+    pub log: LogConfig,
+
+    /// Export of per-connection tracing spans
+    pub tracing: TracingConfig,
+
+    /// Admin server configuration
+    pub admin: AdminConfig,
+
+    /// SOCKS5 frontend configuration
+    pub socks: SocksConfig,
+
+    /// Client authentication configuration
+    pub auth: AuthConfig,
+
+    /// HTTP/2 frontend configuration
+    pub http2: Http2Config,
+
+    /// HTTP/3 frontend configuration
+    pub http3: Http3Config,
+
+    /// WebSocket tunnel frontend configuration
+    pub websocket: WebSocketConfig,
+
+    /// Plain HTTP forward proxying
+    pub forward: ForwardConfig,
+
+    /// A reverse proxy for the Giphy API that adds the API key to each request
+    pub reverse: ReverseConfig,
+
+    /// Tunneling of connections redirected to the proxy by the firewall
+    pub transparent: TransparentConfig,
+
+    /// Interception of TLS tunnels to selected destinations
+    pub mitm: MitmConfig,
+
+    /// Filtering of Giphy API content the proxy can see
+    pub content: ContentConfig,
+
+    /// Caching of responses to requests the proxy can read in full
+    pub cache: CacheConfig,
+
+    /// TLS configuration for the listen addresses
+    pub tls: TlsConfig,
+
+    /// Access control for client addresses
+    pub acl: AclConfig,
+
+    /// PROXY protocol headers from a load balancer in front of the proxy
+    pub proxy_protocol: ProxyProtocolConfig,
+
+    /// The async runtime used by the `giphyproxy` binary
+    pub runtime: RuntimeConfig,
+
+    /// The audit trail of connections, kept in a SQLite database
+    pub audit: AuditConfig,
+
+    /// Daily limits on the bytes each client may transfer
+    pub quota: QuotaConfig,
+
+    /// If set, tunneled bytes can be copied to files or a mirror socket for debugging
+    pub tap: Option<TapConfig>,
+
+    /// If set, tunneled bytes can be captured to pcapng files for debugging
+    pub capture: Option<CaptureConfig>,
+    /// How the proxy identifies itself in the headers it sends
+    pub identity: IdentityConfig,
+
+    /// Confinement of the `giphyproxy` process once its listen addresses are bound
+    pub sandbox: SandboxConfig,
+
+    /// Running the `giphyproxy` binary in the background
+    pub daemon: DaemonConfig,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BackendConfig {
+    /// The kind of backend to use.  If not set, an allow-list backend is used, or a
+    /// chained backend if `upstream` is set.
+    pub kind: Option<BackendKind>,
+
+    /// Host to which clients may connect
+    pub host: String,
+
+    /// Port to which clients may connect
+    pub port: u16,
+
+    /// Patterns for hosts and ports to which clients may connect, such as
+    /// `*.giphy.com:443`.  If empty, only `host:port` is allowed.
+    pub allow: Vec<AllowEntry>,
+
+    /// Ports to which clients may connect, whatever the host, such as `443` or
+    /// `443,8443-8444`.  This is checked in addition to `allow`, so that hosts can be
+    /// allowed with any port while connections are limited to these.
+    pub allowed_ports: PortSet,
+
+    /// Maximum time to wait for a connection to the backend to complete
+    #[serde(rename = "connect_timeout_secs", with = "secs")]
+    pub connect_timeout: Duration,
+
+    /// Number of times to retry a failed backend connection before giving up
+    pub connect_retries: u32,
+
+    /// Delay before the first retry, doubling for each retry after that
+    #[serde(rename = "retry_backoff_ms", with = "millis")]
+    pub retry_backoff: Duration,
+
+    /// Begin each retry with a different one of the destination's resolved addresses
+    pub retry_rotate_addresses: bool,
+
+    /// A parent HTTP proxy through which to make all backend connections.  If not set,
+    /// backend connections are made directly.
+    pub upstream: Option<UpstreamConfig>,
+
+    /// Networks to which connections are refused, even if the destination is allowed.
+    /// Destinations are resolved and checked before connecting, so a permitted hostname
+    /// cannot be used to reach these addresses.
+    pub blocked_networks: Vec<IpNet>,
+
+    /// How backend hostnames are resolved
+    pub dns: DnsConfig,
+
+    /// If set, destinations that repeatedly fail are refused for a time, rather than
+    /// attempting a connection for every request
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+
+    /// If set, a single-host backend re-resolves `host` periodically in the background,
+    /// rather than for each connection, and spreads connections across its addresses,
+    /// avoiding those that recently failed
+    pub rotation: Option<RotationConfig>,
+
+    /// If set, a connection to the backend's `host` and `port` is made periodically in the
+    /// background, and `/readyz` fails while these connections fail
+    pub health_check: Option<HealthCheckConfig>,
+
+    /// With `kind = "single_host"`, endpoints to which connections are made, in order, if
+    /// `host` and `port` cannot be reached
+    pub failover: Vec<Endpoint>,
+
+    /// If set, with `kind = "single_host"`, connections to `host` and `port` are spread
+    /// across several endpoints
+    pub load_balance: Option<LoadBalanceConfig>,
+
+    /// If set, the rate at which each client IP may open backend connections is limited
+    pub connect_rate: Option<ConnectionRateConfig>,
+
+    /// If set, each backend connection (or connection to the upstream proxy) begins with a
+    /// PROXY protocol v2 header giving the client's address
+    pub send_proxy_protocol: bool,
+
+    /// If set, each CONNECT or SOCKS5 tunnel must begin with a TLS ClientHello whose server
+    /// name (SNI) is the requested host, so that an allowed address cannot be used to
+    /// reach other names it serves.  Other tunnels are closed.
+    pub verify_sni: bool,
+
+    /// A PEM file of CA certificates to trust when the proxy makes its own TLS connections
+    /// to backends, such as for the reverse proxy and intercepted tunnels.  If not set,
+    /// the Mozilla root certificates are trusted.
+    pub tls_ca: Option<PathBuf>,
+
+    /// TCP options for backend connections, and connections to `upstream`
+    pub socket: SocketConfig,
+
+    /// If set, backend connections are recorded to files, or served from earlier
+    /// recordings without connecting, for reproducing interactions offline
+    pub recording: Option<RecordingConfig>,
+
+    /// If set, faults are injected into some backend connections, for testing how clients
+    /// cope with them
+    pub chaos: Option<ChaosConfig>,
+}
+
+/// Options for TCP sockets.  Those not set are left at the operating system's defaults.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SocketConfig {
+    /// Disable Nagle's algorithm (`TCP_NODELAY`), sending small writes immediately
+    pub nodelay: bool,
+
+    /// If set, TCP keepalive probes are sent on idle connections, so that peers which
+    /// have gone away are detected and middleboxes do not drop long-lived tunnels
+    pub keepalive: Option<KeepaliveConfig>,
+
+    /// Size of the kernel send buffer (`SO_SNDBUF`), in bytes
+    pub send_buffer_size: Option<u32>,
+
+    /// Size of the kernel receive buffer (`SO_RCVBUF`), in bytes
+    pub recv_buffer_size: Option<u32>,
+
+    /// The local address from which outgoing connections are made, so that a multi-homed
+    /// host can choose its egress address.  Destinations of the other address family are
+    /// not reachable.  Only for `backend.socket`.
+    pub bind_address: Option<IpAddr>,
+
+    /// The network interface through which outgoing connections are made
+    /// (`SO_BINDTODEVICE`), such as `eth1`.  Only for `backend.socket`, and only on Linux.
+    pub bind_device: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeepaliveConfig {
+    /// Time a connection must be idle before the first probe is sent
+    #[serde(rename = "time_secs", with = "secs")]
+    pub time: Duration,
+
+    /// Time between unanswered probes
+    #[serde(rename = "interval_secs", with = "secs")]
+    pub interval: Duration,
+
+    /// Number of unanswered probes after which the connection is closed
+    pub retries: u32,
+}
+
+/// The kinds of backend that can be selected in the configuration
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BackendKind {
+    /// Connections only to `host` and `port`
+    SingleHost,
+    /// Connections to destinations matching `allow`
+    AllowList,
+    /// Connections to destinations matching `allow`, through the parent proxy `upstream`
+    Chained,
+    /// Connections to an in-memory echo server, for testing clients without network access
+    Mock,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RotationConfig {
+    /// Time between resolutions of the backend host
+    #[serde(rename = "refresh_secs", with = "secs")]
+    pub refresh: Duration,
+
+    /// Time for which an address that failed to connect is tried only after the others
+    #[serde(rename = "unhealthy_secs", with = "secs")]
+    pub unhealthy: Duration,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoadBalanceConfig {
+    /// How the endpoint for each connection is chosen
+    pub strategy: BalanceStrategy,
+
+    /// The endpoints, each with its weight: a relative share of connections, for
+    /// `round_robin`, or of open connections, for `least_connections`
+    pub endpoints: BTreeMap<Endpoint, u32>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RecordingConfig {
+    /// Whether to record connections or replay them
+    pub mode: RecordingMode,
+
+    /// The directory holding the recordings
+    pub dir: PathBuf,
+}
+
+/// What to do with recordings of backend connections
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RecordingMode {
+    /// Connect with the configured backend, and record each connection
+    Record,
+    /// Serve each connection from its recording, making no connections
+    Replay,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChaosConfig {
+    /// Percentage of backend connections into which a fault is injected
+    pub percent: f64,
+
+    /// The faults from which one is chosen at random for each affected connection
+    pub faults: Vec<ChaosFault>,
+
+    /// Seed for the random choices, so that a run can be repeated.  If not set, a seed is
+    /// chosen at startup, and logged.
+    pub seed: Option<u64>,
+
+    /// Delay before connecting, for `latency` faults
+    #[serde(rename = "latency_ms", with = "millis")]
+    pub latency: Duration,
+
+    /// `truncate` and `reset` faults happen after a random number of bytes, up to this many
+    pub max_bytes: u64,
+
+    /// Bytes per second delivered from the backend, for `slow_read` faults
+    pub slow_read_rate: u64,
+}
+
+/// The faults that can be injected into a backend connection
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChaosFault {
+    /// A delay before connecting
+    Latency,
+    /// The data from the backend ends early, as if the backend had closed the connection
+    Truncate,
+    /// The connection fails with a reset part way through
+    Reset,
+    /// The data from the backend is delivered slowly
+    SlowRead,
+}
+
+/// The ways a load-balanced backend can choose an endpoint
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BalanceStrategy {
+    /// Each endpoint in turn, in proportion to its weight
+    RoundRobin,
+    /// The endpoint with the fewest open connections relative to its weight
+    LeastConnections,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthCheckConfig {
+    /// Time between checks
+    #[serde(rename = "interval_secs", with = "secs")]
+    pub interval: Duration,
+
+    /// Maximum time for each check, including the TLS handshake
+    #[serde(rename = "timeout_secs", with = "secs")]
+    pub timeout: Duration,
+
+    /// If set, each check also performs a TLS handshake, verifying the backend's
+    /// certificate against `backend.tls_ca`
+    pub tls: bool,
+
+    /// Number of consecutive failed checks after which the backend is considered
+    /// unhealthy
+    pub unhealthy_threshold: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CircuitBreakerConfig {
+    /// Number of consecutive failed connections to a destination after which it is
+    /// refused
+    pub failure_threshold: u32,
+
+    /// Time for which a destination is refused before another connection is attempted
+    #[serde(rename = "cooldown_secs", with = "secs")]
+    pub cooldown: Duration,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DnsConfig {
+    /// Nameservers to query for backend hostnames.  If empty, the system resolver is
+    /// used.  For DNS-over-TLS and DNS-over-HTTPS, these are the servers' addresses, so
+    /// that no other resolver is needed to find them.
+    pub nameservers: Vec<SocketAddr>,
+
+    /// The protocol used to query `nameservers`
+    pub protocol: DnsProtocol,
+
+    /// The name in the nameservers' TLS certificates, such as `dns.google`; required for
+    /// DNS-over-TLS and DNS-over-HTTPS
+    pub tls_name: Option<String>,
+
+    /// If lookups with `nameservers` fail, try the system resolver.  This is off by
+    /// default, as it defeats the privacy of encrypted DNS.
+    pub fallback_to_system: bool,
+
+    /// Maximum number of names to cache; 0 disables the cache
+    pub cache_size: usize,
+
+    /// Maximum time to cache a name.  Results from the system resolver, which does not
+    /// report TTLs, are cached for this long.
+    #[serde(rename = "max_ttl_secs", with = "secs")]
+    pub max_ttl: Duration,
+
+    /// Addresses for hostnames, such as `"api.giphy.com" = ["151.101.2.2"]`, used instead
+    /// of looking the names up.  The addresses are still checked against
+    /// `backend.blocked_networks`.
+    pub hosts: BTreeMap<String, Vec<IpAddr>>,
+
+    /// Look up names that are not in `hosts`.  If false, only the names in `hosts` can be
+    /// resolved, as in an air-gapped deployment.
+    pub fallback_to_dns: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UpstreamConfig {
+    /// The parent proxy's address, as `host:port`
+    pub address: String,
+
+    /// Username for Basic authentication to the parent proxy, if it requires it
+    pub username: Option<String>,
+
+    /// Password for Basic authentication to the parent proxy
+    pub password: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// Maximum size of a request head.  CONNECT requests should be tiny, so this can
+    /// be quite small.
+    pub max_head_size: usize,
+
+    /// Maximum number of headers in a request head
+    pub max_headers: usize,
+
+    /// Maximum length of the request line or of any header line in a request head
+    pub max_header_line: usize,
+
+    /// Accept request heads whose lines end in a bare `\n` rather than `\r\n`
+    pub allow_bare_lf: bool,
+
+    /// Accept only RFC 1123 hostnames in requests, rejecting underscores and hyphens at the
+    /// ends of labels
+    pub strict_hostnames: bool,
+
+    /// Port for CONNECT requests that name only a host
+    pub default_connect_port: u16,
+
+    /// Maximum time a client may take to send its request head
+    #[serde(rename = "head_timeout_secs", with = "secs")]
+    pub head_timeout: Duration,
+
+    /// Maximum time a tunnel may go without traffic in either direction before it is
+    /// closed
+    #[serde(rename = "idle_timeout_secs", with = "secs")]
+    pub idle_timeout: Duration,
+
+    /// Maximum number of concurrent connections
+    pub max_connections: usize,
+
+    /// Maximum number of concurrent connections from a single client IP
+    pub max_connections_per_client: usize,
+
+    /// Size of the buffer used to copy each direction of a tunnel
+    pub tunnel_buffer_size: usize,
+
+    /// If set, the rate at which each client IP may open new connections is limited
+    pub connection_rate: Option<ConnectionRateConfig>,
+
+    /// Listen addresses whose CONNECT and SOCKS5 tunnels must begin with a TLS handshake.
+    /// Tunnels in which the client first sends anything else are closed.  Tunnels through
+    /// other listen addresses may carry any protocol.
+    pub require_tls: Vec<SocketAddr>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConnectionRateConfig {
+    /// Sustained rate of new connections allowed from each client IP, per second
+    pub per_second: f64,
+
+    /// Number of connections a client IP may open at once, before it is held to the
+    /// sustained rate
+    pub burst: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// Log filter (in `RUST_LOG` syntax), overriding `RUST_LOG` if set
+    pub level: Option<String>,
+
+    /// Where log records are written
+    pub output: LogOutput,
+
+    /// The syslog daemon to which log records are sent, if `output` is `syslog`
+    pub syslog: SyslogConfig,
+
+    /// A file for access records, separate from the diagnostic log
+    pub access: AccessLogConfig,
+
+    /// If set, the TLS ClientHello at the start of each CONNECT or SOCKS5 tunnel is read
+    /// (without terminating TLS), and its JA3 and JA4 fingerprints are added to the access
+    /// record and counted in the metrics
+    pub tls_fingerprints: bool,
+}
+
+/// Destinations for log records
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogOutput {
+    /// Formatted as text on stderr
+    #[default]
+    Stderr,
+    /// Sent to a syslog daemon, formatted as in RFC 5424
+    Syslog,
+    /// Sent to systemd-journald, with each field of the record as a journal field
+    Journald,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SyslogConfig {
+    /// The daemon's address: the path of a unix datagram socket, or `udp://host:port`
+    pub address: String,
+
+    /// The facility for all records
+    pub facility: SyslogFacility,
+
+    /// The APP-NAME of each message
+    pub app_name: String,
+}
+
+/// Syslog facilities suitable for the proxy
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SyslogFacility {
+    User,
+    Daemon,
+    Local0,
+    Local1,
+    Local2,
+    Local3,
+    Local4,
+    Local5,
+    Local6,
+    Local7,
+}
+
+impl SyslogFacility {
+    /// The facility's numeric code, from RFC 5424
+    pub fn code(self) -> u8 {
+        use SyslogFacility::*;
+        match self {
+            User => 1,
+            Daemon => 3,
+            Local0 => 16,
+            Local1 => 17,
+            Local2 => 18,
+            Local3 => 19,
+            Local4 => 20,
+            Local5 => 21,
+            Local6 => 22,
+            Local7 => 23,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccessLogConfig {
+    /// File to which access records are appended.  If not set, they are logged with the
+    /// diagnostic log, with target `giphyproxy::access`.
+    pub path: Option<PathBuf>,
+
+    /// Format of each record
+    pub format: AccessLogFormat,
+
+    /// Size in bytes beyond which the file is rotated, or 0 to never rotate it
+    pub max_size: u64,
+
+    /// Number of rotated files to keep, named `path.1` (the newest) to `path.N`
+    pub keep: usize,
+}
+
+/// Formats for the access log file
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessLogFormat {
+    /// One JSON object per line, as logged to the diagnostic log
+    Json,
+    /// Common Log Format, as written by web servers
+    Common,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TracingConfig {
+    /// OTLP/HTTP endpoint to which spans are exported, such as
+    /// `http://localhost:4318/v1/traces`.  Spans are not exported if this is not set.
+    pub otlp_endpoint: Option<String>,
+
+    /// Fraction of connections to trace, from 0.0 to 1.0
+    pub sample_ratio: f64,
+
+    /// The `service.name` reported with each span
+    pub service_name: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    /// Address on which to serve the admin HTTP server (including `/metrics`).  The admin
+    /// server is disabled if this is not set.
+    pub listen: Option<SocketAddr>,
+
+    /// If set, `/readyz` also requires that a connection to the backend's `host` and
+    /// `port` succeeds
+    pub probe_backend: bool,
+
+    /// Maximum time to wait for the readiness probe connection
+    #[serde(rename = "probe_timeout_secs", with = "secs")]
+    pub probe_timeout: Duration,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SocksConfig {
+    /// Accept SOCKS5 clients on the listen addresses, alongside HTTP CONNECT.  The
+    /// protocol is detected from the first byte the client sends.
+    pub enabled: bool,
+
+    /// If set, SOCKS5 clients must authenticate with this username and `password`
+    pub username: Option<String>,
+
+    /// The password for `username`
+    pub password: Option<String>,
+
+    /// If set, SOCKS5 clients may also use UDP ASSOCIATE to relay datagrams, such as QUIC,
+    /// to allowed destinations
+    pub udp: Option<UdpRelayConfig>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UdpRelayConfig {
+    /// Time after which an association with no datagrams in either direction is closed,
+    /// and after which each destination's mapping is forgotten if unused
+    #[serde(rename = "idle_timeout_secs", with = "secs")]
+    pub idle_timeout: Duration,
+
+    /// Maximum number of destinations to which each association may send at once
+    pub max_flows: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// An htpasswd-style file of users allowed to use the proxy.  If set, clients must
+    /// authenticate; otherwise, no authentication is required.
+    pub htpasswd: Option<PathBuf>,
+
+    /// The realm given in `Proxy-Authenticate` challenges
+    pub realm: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Http2Config {
+    /// Accept cleartext HTTP/2 clients (with prior knowledge) on the listen addresses,
+    /// alongside HTTP/1.1 CONNECT.  The protocol is detected from the first byte the
+    /// client sends.
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Http3Config {
+    /// UDP addresses on which to accept HTTP/3 clients, which send CONNECT requests on the
+    /// streams of a QUIC connection.  Requires building with the `http3` feature.  Disabled
+    /// if empty.
+    pub listen: Vec<SocketAddr>,
+
+    /// A PEM file containing the certificate chain to present to HTTP/3 clients.  QUIC
+    /// always uses TLS, so this is required, independent of `tls.cert`.
+    pub cert: Option<PathBuf>,
+
+    /// A PEM file containing the private key for `cert`
+    pub key: Option<PathBuf>,
+
+    /// Maximum number of concurrent streams, and so tunnels, on each connection
+    pub max_concurrent_streams: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebSocketConfig {
+    /// Accept WebSocket clients on the listen addresses, alongside HTTP/1.1 CONNECT.  A
+    /// client requests a tunnel with `GET {path}?host=api.giphy.com&port=443` and a
+    /// WebSocket upgrade, and then exchanges the tunnel's data in binary frames.
+    pub enabled: bool,
+
+    /// The path at which WebSocket tunnels are requested
+    pub path: String,
+
+    /// Origins, such as `https://app.example.com`, of the web pages allowed to open
+    /// tunnels.  Browsers send the page's origin with each request, and requests from
+    /// other origins are refused, so that any page a user visits cannot use the proxy;
+    /// requests without an origin, from clients other than browsers, are allowed.
+    pub allowed_origins: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ForwardConfig {
+    /// Forward plain HTTP requests in absolute form, such as `GET http://host/ HTTP/1.1`,
+    /// for clients that cannot use CONNECT.  Destinations are restricted exactly as for
+    /// CONNECT.  Otherwise, such requests are refused with 405.
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReverseConfig {
+    /// Addresses on which to accept plain HTTP requests for the Giphy API, such as
+    /// `GET /v1/gifs/search?q=cat`.  Each is sent over TLS to `backend.host` and
+    /// `backend.port` with the API key added.  If empty, the reverse proxy is disabled.
+    pub listen: Vec<SocketAddr>,
+
+    /// The API key to add to each request
+    pub api_key: Option<String>,
+
+    /// Where to add the API key
+    pub key_location: KeyLocation,
+
+    /// The name of the query parameter or header carrying the API key.  Any value the
+    /// client sent for it is replaced.
+    pub key_name: String,
+
+    /// Keep-alive connections to the backend, reused for later requests
+    pub pool: PoolConfig,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PoolConfig {
+    /// Maximum number of idle connections kept open, across all destinations.  If zero,
+    /// each request uses a new connection, which is closed after the response.
+    pub max_idle: usize,
+
+    /// Maximum number of idle connections kept open to any one destination
+    pub max_idle_per_host: usize,
+
+    /// Time after which an idle connection is closed rather than reused.  This should be
+    /// shorter than the time after which the backend closes idle connections.
+    #[serde(rename = "idle_timeout_secs", with = "secs")]
+    pub idle_timeout: Duration,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            max_idle: 16,
+            max_idle_per_host: 8,
+            idle_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Where the reverse proxy adds the API key to a request
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyLocation {
+    /// A query parameter
+    Query,
+    /// A request header
+    Header,
+}
+
+impl Default for TransparentConfig {
+    fn default() -> Self {
+        Self {
+            listen: vec![],
+            mode: TransparentMode::Redirect,
+        }
+    }
+}
+
+impl Default for ReverseConfig {
+    fn default() -> Self {
+        Self {
+            listen: vec![],
+            api_key: None,
+            key_location: KeyLocation::Query,
+            key_name: "api_key".into(),
+            pool: PoolConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TransparentConfig {
+    /// Addresses on which to accept connections redirected by iptables or nftables, which
+    /// arrive without a request and are tunneled to their original destination, subject
+    /// to the backend's policy as if the client had requested it.  If empty, transparent
+    /// proxying is disabled.  Linux only.
+    pub listen: Vec<SocketAddr>,
+
+    /// How the connections are redirected, which determines how their original
+    /// destination is found
+    pub mode: TransparentMode,
+}
+
+/// The ways new connections are turned away while the proxy is draining
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DrainMode {
+    /// Accept each connection and answer it with a 503
+    #[default]
+    Reject,
+    /// Stop accepting connections, leaving them in the listen sockets' backlog until the
+    /// proxy stops draining
+    StopAccepting,
+}
+
+/// The ways connections can be redirected to a transparent listener
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransparentMode {
+    /// By a `REDIRECT` or `DNAT` rule; the destination is recovered with `SO_ORIGINAL_DST`
+    Redirect,
+    /// By a `TPROXY` rule; the destination is the connection's local address
+    Tproxy,
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MitmConfig {
+    /// Destinations whose tunnels are intercepted, in the same form as `backend.allow`.
+    /// The proxy terminates the client's TLS session with a certificate for the
+    /// destination signed by `ca_cert`, and makes its own TLS connection to the
+    /// destination, so that it can see and modify the requests.  If empty, nothing is
+    /// intercepted.
+    pub hosts: Vec<AllowEntry>,
+
+    /// A PEM file containing the CA certificate that signs the certificates presented to
+    /// clients, which must trust it
+    pub ca_cert: Option<PathBuf>,
+
+    /// A PEM file containing the CA's private key
+    pub ca_key: Option<PathBuf>,
+
+    /// Log each intercepted request
+    pub log_requests: bool,
+
+    /// Refuse intercepted requests whose paths begin with any of these prefixes
+    pub deny_paths: Vec<String>,
+
+    /// Add `reverse.api_key` to each intercepted request, as the reverse proxy does
+    pub inject_api_key: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ContentConfig {
+    /// The highest content rating allowed in Giphy API requests to the reverse proxy and in
+    /// intercepted tunnels.  Requests are rewritten to ask for no higher rating, and
+    /// results with a higher rating are removed from responses.  If unset, all ratings are
+    /// allowed.
+    pub max_rating: Option<Rating>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    /// Maximum total size, in bytes, of cached responses to `GET` requests to the reverse
+    /// proxy and in intercepted tunnels; 0 disables the cache
+    pub max_size: usize,
+
+    /// Maximum time to cache a response.  A shorter `max-age` in the response's
+    /// `Cache-Control` header takes precedence.
+    #[serde(rename = "ttl_secs", with = "secs")]
+    pub ttl: Duration,
+
+    /// A file in which to keep the cache across restarts.  It is read at startup and
+    /// written every `persist_interval_secs`.
+    pub path: Option<PathBuf>,
+
+    /// How often to write the cache to `path`
+    #[serde(rename = "persist_interval_secs", with = "secs")]
+    pub persist_interval: Duration,
+}
+
+/// Giphy content ratings, from the most to the least restrictive
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Rating {
+    Y,
+    G,
+    Pg,
+    #[serde(rename = "pg-13")]
+    Pg13,
+    R,
+}
+
+impl Rating {
+    /// The rating as it appears in the Giphy API
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Rating::Y => "y",
+            Rating::G => "g",
+            Rating::Pg => "pg",
+            Rating::Pg13 => "pg-13",
+            Rating::R => "r",
+        }
+    }
+
+    /// Parse a rating as it appears in the Giphy API, ignoring case
+    pub fn parse(s: &str) -> Option<Self> {
+        [Rating::Y, Rating::G, Rating::Pg, Rating::Pg13, Rating::R]
+            .iter()
+            .copied()
+            .find(|rating| rating.as_str().eq_ignore_ascii_case(s))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    /// A PEM file containing the certificate chain to present to clients.  If set, the
+    /// listen addresses accept only TLS connections, and the proxy protocols are spoken
+    /// inside TLS.
+    pub cert: Option<PathBuf>,
+
+    /// A PEM file containing the private key for `cert`
+    pub key: Option<PathBuf>,
+
+    /// A PEM bundle of CA certificates.  If set, clients must present a certificate
+    /// signed by one of these CAs.
+    pub client_ca: Option<PathBuf>,
+
+    /// Patterns such as `*.clients.example.com`, matched against the subject common name
+    /// and DNS names of client certificates.  If not empty, clients must present a
+    /// certificate with a matching name.
+    pub client_names: Vec<HostPattern>,
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AclConfig {
+    /// Networks from which clients may connect
+    pub allow: Vec<IpNet>,
+
+    /// Networks from which clients may not connect, even if they are also in `allow`
+    pub deny: Vec<IpNet>,
+
+    /// If set, clients in neither `allow` nor `deny` are refused
+    pub deny_by_default: bool,
+
+    /// A file of further rules, one per line, such as `allow 10.0.0.0/8` or
+    /// `deny 10.1.2.3/32`.  The file is re-read on SIGHUP.
+    pub file: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProxyProtocolConfig {
+    /// If set, a PROXY protocol (v1 or v2) header at the start of a connection gives the
+    /// client's address.  Any client can send one, so this should only be enabled when
+    /// the listen addresses are reachable only through a trusted load balancer.
+    pub enabled: bool,
+
+    /// If set, connections without a PROXY protocol header are refused
+    pub required: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RuntimeConfig {
+    /// Number of worker threads; if not set, one per CPU core
+    pub worker_threads: Option<usize>,
+
+    /// If set, run everything on a single thread, which suits tiny deployments
+    pub current_thread: bool,
+
+    /// Maximum number of threads for blocking operations such as system DNS lookups; if
+    /// not set, the runtime's default (512)
+    pub max_blocking_threads: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuditConfig {
+    /// SQLite database to which a record of each connection is added; disabled if not set
+    pub database: Option<PathBuf>,
+
+    /// How long records are kept before they are pruned
+    #[serde(rename = "retention_secs", with = "secs")]
+    pub retention: Duration,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QuotaConfig {
+    /// Bytes, in both directions together, that each client may transfer per day (UTC);
+    /// 0 for no limit.  Clients are identified by their user name if they authenticated,
+    /// and otherwise by their IP address.
+    pub daily_bytes: u64,
+
+    /// Daily limits for particular users, overriding `daily_bytes`
+    pub users: HashMap<String, u64>,
+
+    /// A file in which to keep the day's usage across restarts.  It is read at startup,
+    /// and written every `persist_interval_secs` and on draining, upgrading, and shutting
+    /// down.
+    pub path: Option<PathBuf>,
+
+    /// How often to write usage to `path`
+    #[serde(rename = "persist_interval_secs", with = "secs")]
+    pub persist_interval: Duration,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TapConfig {
+    /// Tap tunnels from startup.  Otherwise, taps are enabled and disabled through the
+    /// admin server.
+    pub enabled: bool,
+
+    /// A directory in which to write each tapped tunnel's bytes, in a file named by its ID
+    pub dir: Option<PathBuf>,
+
+    /// An address to which to open a TCP connection for each tapped tunnel, carrying its
+    /// bytes in the same format as the files
+    pub mirror: Option<SocketAddr>,
+
+    /// Bytes of each direction of a tunnel to copy; the rest are not tapped
+    pub max_bytes: u64,
+
+    /// Maximum number of tunnels to tap each minute; later tunnels are not tapped
+    pub max_per_minute: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CaptureConfig {
+    /// Capture tunnels from startup.  Otherwise, capture is started and stopped through the
+    /// admin server.
+    pub enabled: bool,
+
+    /// The directory in which to write capture files, one for each time capture is started
+    pub dir: Option<PathBuf>,
+
+    /// Maximum size of a capture file; once it is reached, further packets are dropped
+    pub max_file_bytes: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SandboxConfig {
+    /// The user, by name or numeric ID, to switch to once the listen addresses are bound,
+    /// so that privileged ports can be bound as root
+    pub user: Option<String>,
+
+    /// The group, by name or numeric ID, to switch to; if not set, the user's primary group
+    pub group: Option<String>,
+
+    /// A directory, ideally empty and not writable by `user`, to make the root directory
+    /// once the listen addresses are bound (requires root)
+    pub chroot: Option<PathBuf>,
+
+    /// Set `no_new_privs` and install a seccomp filter refusing system calls the proxy
+    /// never needs, such as `execve` and `ptrace` (Linux on x86_64 and aarch64 only)
+    pub seccomp: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DaemonConfig {
+    /// Detach from the terminal and run in the background (Unix only)
+    pub enabled: bool,
+
+    /// A file to which the proxy's PID is written, locked while it runs; used by the
+    /// `stop` and `status` commands
+    pub pid_file: Option<PathBuf>,
+
+    /// A file to which stdout and stderr are appended once detached; if not set, they are
+    /// discarded
+    pub log_file: Option<PathBuf>,
+}
+
+impl QuotaConfig {
+    /// Whether any limit is configured
+    pub fn enabled(&self) -> bool {
+        self.daily_bytes > 0 || !self.users.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IdentityConfig {
+    /// The name of this proxy in `Via` headers
+    pub name: String,
+
+    /// Value of a `Proxy-Agent` header in the 200 response to CONNECT; not sent if unset
+    pub proxy_agent: Option<String>,
+
+    /// Send a `Via` header in the 200 response to CONNECT, and add one to the requests and
+    /// responses relayed by the forward and reverse proxies
+    pub via: bool,
+
+    /// Further headers for the 200 response to CONNECT, such as `Connection`
+    pub connect_headers: BTreeMap<String, String>,
+
+    /// Send no headers identifying the proxy, whatever the settings above, and remove any
+    /// `Via`, `Forwarded`, and `X-Forwarded-For` headers from forwarded requests
+    pub anonymous: bool,
+}
+
+impl IdentityConfig {
+    /// The `Via` entry for this proxy in a message with the given HTTP version, such as
+    /// `HTTP/1.1`, if one should be sent
+    pub fn via(&self, version: &str) -> Option<String> {
+        if !self.via || self.anonymous {
+            return None;
+        }
+        Some(format!(
+            "{} {}",
+            version.trim_start_matches("HTTP/"),
+            self.name
+        ))
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            listen: vec!["127.0.0.1:8080".parse().unwrap()],
+            acceptors: 1,
+            backlog: 1024,
+            restart_acceptors: false,
+            abort_on_panic: false,
+            drain_mode: DrainMode::default(),
+            socket: SocketConfig::default(),
+            backend: BackendConfig::default(),
+            limits: LimitsConfig::default(),
+            log: LogConfig::default(),
+            tracing: TracingConfig::default(),
+            admin: AdminConfig::default(),
+            socks: SocksConfig::default(),
+            auth: AuthConfig::default(),
+            http2: Http2Config::default(),
+            http3: Http3Config::default(),
+            websocket: WebSocketConfig::default(),
+            forward: ForwardConfig::default(),
+            reverse: ReverseConfig::default(),
+            transparent: TransparentConfig::default(),
+            mitm: MitmConfig::default(),
+            content: ContentConfig::default(),
+            cache: CacheConfig::default(),
+            tls: TlsConfig::default(),
+            acl: AclConfig::default(),
+            proxy_protocol: ProxyProtocolConfig::default(),
+            runtime: RuntimeConfig::default(),
+            audit: AuditConfig::default(),
+            quota: QuotaConfig::default(),
+            tap: None,
+            capture: None,
+            identity: IdentityConfig::default(),
+            sandbox: SandboxConfig::default(),
+            daemon: DaemonConfig::default(),
+        }
+    }
+}
+
+impl Default for IdentityConfig {
+    fn default() -> Self {
+        Self {
+            name: "giphyproxy".into(),
+            proxy_agent: None,
+            via: false,
+            connect_headers: BTreeMap::new(),
+            anonymous: false,
+        }
+    }
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        Self {
+            database: None,
+            retention: Duration::from_secs(90 * 24 * 60 * 60),
+        }
+    }
+}
+
+impl Default for QuotaConfig {
+    fn default() -> Self {
+        Self {
+            daily_bytes: 0,
+            users: HashMap::new(),
+            path: None,
+            persist_interval: Duration::from_secs(60),
+        }
+    }
+}
+
+impl Default for TapConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            dir: None,
+            mirror: None,
+            max_bytes: 65536,
+            max_per_minute: 10,
+        }
+    }
+}
+
+impl Default for ChaosConfig {
+    fn default() -> Self {
+        Self {
+            percent: 10.0,
+            faults: vec![
+                ChaosFault::Latency,
+                ChaosFault::Truncate,
+                ChaosFault::Reset,
+                ChaosFault::SlowRead,
+            ],
+            seed: None,
+            latency: Duration::from_secs(2),
+            max_bytes: 16384,
+            slow_read_rate: 1024,
+        }
+    }
+}
+
+impl Default for CaptureConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            dir: None,
+            max_file_bytes: 100 * 1024 * 1024,
+        }
+    }
+}
+
+impl Default for BackendConfig {
+    fn default() -> Self {
+        Self {
+            kind: None,
+            host: "api.giphy.com".into(),
+            port: 443,
+            allow: vec![],
+            allowed_ports: PortSet::Any,
+            connect_timeout: Duration::from_secs(10),
+            connect_retries: 2,
+            retry_backoff: Duration::from_millis(100),
+            retry_rotate_addresses: true,
+            upstream: None,
+            blocked_networks: DEFAULT_BLOCKED_NETWORKS
+                .iter()
+                .map(|n| n.parse().unwrap())
+                .collect(),
+            dns: DnsConfig::default(),
+            circuit_breaker: None,
+            rotation: None,
+            health_check: None,
+            failover: vec![],
+            load_balance: None,
+            connect_rate: None,
+            send_proxy_protocol: false,
+            verify_sni: false,
+            tls_ca: None,
+            socket: SocketConfig::default(),
+            recording: None,
+            chaos: None,
+        }
+    }
+}
+
+impl Default for KeepaliveConfig {
+    fn default() -> Self {
+        Self {
+            time: Duration::from_secs(60),
+            interval: Duration::from_secs(10),
+            retries: 5,
+        }
+    }
+}
+
+impl Default for RotationConfig {
+    fn default() -> Self {
+        Self {
+            refresh: Duration::from_secs(30),
+            unhealthy: Duration::from_secs(30),
+        }
+    }
+}
+
+impl Default for LoadBalanceConfig {
+    fn default() -> Self {
+        Self {
+            strategy: BalanceStrategy::RoundRobin,
+            endpoints: BTreeMap::new(),
+        }
+    }
+}
+
+impl Default for HealthCheckConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(10),
+            timeout: Duration::from_secs(2),
+            tls: false,
+            unhealthy_threshold: 3,
+        }
+    }
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 5,
+            cooldown: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Protocols for querying nameservers
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DnsProtocol {
+    /// Plain DNS, over UDP and falling back to TCP
+    Udp,
+    /// DNS-over-TLS (RFC 7858), usually on port 853
+    Tls,
+    /// DNS-over-HTTPS (RFC 8484), usually on port 443
+    Https,
+}
+
+impl Default for DnsConfig {
+    fn default() -> Self {
+        Self {
+            nameservers: vec![],
+            protocol: DnsProtocol::Udp,
+            tls_name: None,
+            fallback_to_system: false,
+            cache_size: 1024,
+            max_ttl: Duration::from_secs(60),
+            hosts: BTreeMap::new(),
+            fallback_to_dns: true,
+        }
+    }
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            max_size: 0,
+            ttl: Duration::from_secs(300),
+            path: None,
+            persist_interval: Duration::from_secs(60),
+        }
+    }
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_head_size: 1024,
+            max_headers: 32,
+            max_header_line: 1024,
+            allow_bare_lf: false,
+            strict_hostnames: false,
+            default_connect_port: 443,
+            head_timeout: Duration::from_secs(10),
+            idle_timeout: Duration::from_secs(300),
+            max_connections: 1024,
+            max_connections_per_client: 32,
+            tunnel_buffer_size: 65536,
+            connection_rate: None,
+            require_tls: vec![],
+        }
+    }
+}
+
+impl Default for ConnectionRateConfig {
+    fn default() -> Self {
+        Self {
+            per_second: 10.0,
+            burst: 20,
+        }
+    }
+}
+
+impl Default for Http2Config {
+    fn default() -> Self {
+        Self { enabled: true }
+    }
+}
+
+impl Default for Http3Config {
+    fn default() -> Self {
+        Self {
+            listen: vec![],
+            cert: None,
+            key: None,
+            max_concurrent_streams: 100,
+        }
+    }
+}
+
+impl Default for WebSocketConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: "/tunnel".into(),
+            allowed_origins: vec![],
+        }
+    }
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            htpasswd: None,
+            realm: "giphyproxy".into(),
+        }
+    }
+}
+
+impl Default for SyslogConfig {
+    fn default() -> Self {
+        Self {
+            address: "/dev/log".into(),
+            facility: SyslogFacility::Daemon,
+            app_name: "giphyproxy".into(),
+        }
+    }
+}
+
+impl Default for AccessLogConfig {
+    fn default() -> Self {
+        Self {
+            path: None,
+            format: AccessLogFormat::Json,
+            max_size: 0,
+            keep: 5,
+        }
+    }
+}
+
+impl Default for TracingConfig {
+    fn default() -> Self {
+        Self {
+            otlp_endpoint: None,
+            sample_ratio: 1.0,
+            service_name: "giphyproxy".into(),
+        }
+    }
+}
+
+impl Default for AdminConfig {
+    fn default() -> Self {
+        Self {
+            listen: None,
+            probe_backend: false,
+            probe_timeout: Duration::from_secs(2),
+        }
+    }
+}
+
+impl Default for SocksConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            username: None,
+            password: None,
+            udp: None,
+        }
+    }
+}
+
+impl Default for UdpRelayConfig {
+    fn default() -> Self {
+        Self {
+            idle_timeout: Duration::from_secs(120),
+            max_flows: 64,
+        }
+    }
+}
+
+impl Config {
+    /// Load the configuration from the given file (or, if None, from the file named by
+    /// `GIPHYPROXY_CONFIG`, if set), then apply overrides from the process environment.
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        Self::load_with_vars(path, |name| std::env::var(name).ok())
+    }
+
+    /// Load the configuration using the given function to look up variables.  This
+    /// allows testing without modifying the process environment.
+    fn load_with_vars<F: Fn(&str) -> Option<String>>(path: Option<&Path>, get: F) -> Result<Self> {
+        let path = path
+            .map(Path::to_owned)
+            .or_else(|| get(CONFIG_VAR).map(PathBuf::from));
+        let mut config = match path {
+            Some(path) => Self::from_file(&path)?,
+            None => Self::default(),
+        };
+        config.apply_vars(get)?;
+        Ok(config)
+    }
+
+    /// Read the configuration from a TOML file, using defaults for anything it omits.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("reading config file {}", path.display()))?;
+        Self::from_toml(&content).with_context(|| format!("parsing config file {}", path.display()))
+    }
+
+    /// Parse the configuration from a TOML string.
+    pub fn from_toml(content: &str) -> Result<Self> {
+        Ok(toml::from_str(content)?)
+    }
+
+    /// Apply overrides from environment variables, looked up with the given function.
+    fn apply_vars<F: Fn(&str) -> Option<String>>(&mut self, get: F) -> Result<()> {
+        let bind_addr = get(BIND_ADDR_VAR)
+            .map(|v| {
+                v.parse()
+                    .with_context(|| format!("invalid {} {:?}", BIND_ADDR_VAR, v))
+            })
+            .transpose()?;
+
+        let port = get(PORT_VAR)
+            .map(|v| {
+                v.parse()
+                    .with_context(|| format!("invalid {} {:?}", PORT_VAR, v))
+            })
+            .transpose()?;
+
+        self.override_listen(bind_addr, port);
+        Ok(())
+    }
+
+    /// Override the listen address and/or port.  If either is given, this replaces the
+    /// configured listen addresses with a single address, taking any unspecified part
+    /// from the first configured address.
+    pub fn override_listen(&mut self, bind_addr: Option<IpAddr>, port: Option<u16>) {
+        if bind_addr.is_none() && port.is_none() {
+            return;
+        }
+        let base = self
+            .listen
+            .first()
+            .copied()
+            .unwrap_or_else(|| Config::default().listen[0]);
+        let addr = SocketAddr::new(
+            bind_addr.unwrap_or_else(|| base.ip()),
+            port.unwrap_or_else(|| base.port()),
+        );
+        self.listen = vec![addr];
+    }
+
+    /// Check for values that are syntactically valid but make no sense.
+    pub fn validate(&self) -> Result<()> {
+        if self.listen.is_empty() {
+            anyhow::bail!("no listen addresses configured");
+        }
+        if self.acceptors == 0 {
+            anyhow::bail!("acceptors must be nonzero");
+        }
+        if self.limits.max_head_size == 0 {
+            anyhow::bail!("limits.max_head_size must be nonzero");
+        }
+        if self.limits.max_header_line == 0 {
+            anyhow::bail!("limits.max_header_line must be nonzero");
+        }
+        if self.limits.head_timeout.is_zero() {
+            anyhow::bail!("limits.head_timeout_secs must be nonzero");
+        }
+        if self.limits.idle_timeout.is_zero() {
+            anyhow::bail!("limits.idle_timeout_secs must be nonzero");
+        }
+        if self.limits.tunnel_buffer_size == 0 {
+            anyhow::bail!("limits.tunnel_buffer_size must be nonzero");
+        }
+        let rates = [
+            ("limits.connection_rate", &self.limits.connection_rate),
+            ("backend.connect_rate", &self.backend.connect_rate),
+        ];
+        for (name, rate) in rates.iter() {
+            if let Some(rate) = rate {
+                if !rate.per_second.is_finite() || rate.per_second <= 0.0 {
+                    anyhow::bail!("{}.per_second must be positive", name);
+                }
+                if rate.burst == 0 {
+                    anyhow::bail!("{}.burst must be nonzero", name);
+                }
+            }
+        }
+        if let Some(upstream) = &self.backend.upstream {
+            if upstream.password.is_some() && upstream.username.is_none() {
+                anyhow::bail!("backend.upstream.password requires backend.upstream.username");
+            }
+        }
+        if self.log.output == LogOutput::Syslog && self.log.syslog.address.is_empty() {
+            anyhow::bail!("log.output = \"syslog\" requires log.syslog.address");
+        }
+        if self.quota.path.is_some() && !self.quota.enabled() {
+            anyhow::bail!("quota.path requires quota.daily_bytes or quota.users");
+        }
+        if self.quota.path.is_some() && self.quota.persist_interval.is_zero() {
+            anyhow::bail!("quota.persist_interval_secs must be nonzero");
+        }
+        if self.audit.retention.is_zero() {
+            anyhow::bail!("audit.retention_secs must be nonzero");
+        }
+        let identity = &self.identity;
+        if identity.name.is_empty() || !identity.name.bytes().all(|c| c.is_ascii_graphic()) {
+            anyhow::bail!("identity.name must be a single word");
+        }
+        for (name, value) in &identity.connect_headers {
+            if name.is_empty() || !name.bytes().all(|c| c.is_ascii_alphanumeric() || c == b'-') {
+                anyhow::bail!("invalid header name {:?} in identity.connect_headers", name);
+            }
+            if value.contains(char::is_control) {
+                anyhow::bail!("invalid value for {} in identity.connect_headers", name);
+            }
+        }
+        if identity
+            .proxy_agent
+            .iter()
+            .any(|agent| agent.contains(char::is_control))
+        {
+            anyhow::bail!("invalid identity.proxy_agent");
+        }
+        if self
+            .sandbox
+            .chroot
+            .as_ref()
+            .is_some_and(|dir| !dir.is_absolute())
+        {
+            anyhow::bail!("sandbox.chroot must be an absolute path");
+        }
+        if self.daemon.log_file.is_some() && !self.daemon.enabled {
+            anyhow::bail!("daemon.log_file requires daemon.enabled");
+        }
+        if self.log.access.max_size > 0 && self.log.access.keep == 0 {
+            anyhow::bail!("log.access.max_size requires log.access.keep to be nonzero");
+        }
+        match (self.backend.kind, &self.backend.upstream) {
+            (Some(BackendKind::Chained), None) => {
+                anyhow::bail!("backend.kind = \"chained\" requires backend.upstream");
+            }
+            (Some(kind), Some(_)) if kind != BackendKind::Chained => {
+                anyhow::bail!("backend.upstream requires backend.kind = \"chained\"");
+            }
+            _ => {}
+        }
+        let dns = &self.backend.dns;
+        if dns.protocol != DnsProtocol::Udp {
+            if dns.nameservers.is_empty() {
+                anyhow::bail!("backend.dns.protocol requires backend.dns.nameservers");
+            }
+            if dns.tls_name.is_none() {
+                anyhow::bail!("backend.dns.protocol requires backend.dns.tls_name");
+            }
+        }
+        for (host, addrs) in &dns.hosts {
+            if host.is_empty() || host.parse::<IpAddr>().is_ok() {
+                anyhow::bail!("invalid hostname {:?} in backend.dns.hosts", host);
+            }
+            if addrs.is_empty() {
+                anyhow::bail!("backend.dns.hosts entry for {:?} has no addresses", host);
+            }
+        }
+        if self.backlog == 0 {
+            anyhow::bail!("backlog must be nonzero");
+        }
+        let sockets = [
+            ("socket", &self.socket),
+            ("backend.socket", &self.backend.socket),
+        ];
+        for (name, socket) in sockets.iter() {
+            if socket.send_buffer_size == Some(0) || socket.recv_buffer_size == Some(0) {
+                anyhow::bail!("{} buffer sizes must be nonzero", name);
+            }
+            if let Some(keepalive) = &socket.keepalive {
+                if keepalive.time.is_zero() || keepalive.interval.is_zero() {
+                    anyhow::bail!("{}.keepalive times must be nonzero", name);
+                }
+            }
+        }
+        if self.socket.bind_address.is_some() || self.socket.bind_device.is_some() {
+            anyhow::bail!(
+                "socket.bind_address and socket.bind_device apply only to backend.socket"
+            );
+        }
+        if !self.transparent.listen.is_empty() && !cfg!(target_os = "linux") {
+            anyhow::bail!("transparent.listen is only supported on Linux");
+        }
+        if let Some(device) = &self.backend.socket.bind_device {
+            if !cfg!(target_os = "linux") {
+                anyhow::bail!("backend.socket.bind_device is only supported on Linux");
+            }
+            if device.is_empty() {
+                anyhow::bail!("backend.socket.bind_device must not be empty");
+            }
+        }
+        if let Some(breaker) = &self.backend.circuit_breaker {
+            if breaker.failure_threshold == 0 {
+                anyhow::bail!("backend.circuit_breaker.failure_threshold must be nonzero");
+            }
+        }
+        if let Some(rotation) = &self.backend.rotation {
+            if self.backend.kind != Some(BackendKind::SingleHost) {
+                anyhow::bail!("backend.rotation requires backend.kind = \"single_host\"");
+            }
+            if rotation.refresh.is_zero() {
+                anyhow::bail!("backend.rotation.refresh_secs must be nonzero");
+            }
+        }
+        if !self.backend.failover.is_empty() && self.backend.kind != Some(BackendKind::SingleHost) {
+            anyhow::bail!("backend.failover requires backend.kind = \"single_host\"");
+        }
+        if let Some(balance) = &self.backend.load_balance {
+            if self.backend.kind != Some(BackendKind::SingleHost) {
+                anyhow::bail!("backend.load_balance requires backend.kind = \"single_host\"");
+            }
+            if !self.backend.failover.is_empty() {
+                anyhow::bail!("backend.failover cannot be used with backend.load_balance");
+            }
+            if balance.endpoints.is_empty() {
+                anyhow::bail!("backend.load_balance.endpoints must not be empty");
+            }
+            if let Some((endpoint, _)) = balance.endpoints.iter().find(|(_, w)| **w == 0) {
+                anyhow::bail!(
+                    "backend.load_balance.endpoints: weight of {} must be nonzero",
+                    endpoint
+                );
+            }
+        }
+        if let Some(check) = &self.backend.health_check {
+            if check.interval.is_zero() {
+                anyhow::bail!("backend.health_check.interval_secs must be nonzero");
+            }
+            if check.timeout.is_zero() {
+                anyhow::bail!("backend.health_check.timeout_secs must be nonzero");
+            }
+            if check.unhealthy_threshold == 0 {
+                anyhow::bail!("backend.health_check.unhealthy_threshold must be nonzero");
+            }
+        }
+        if !(0.0..=1.0).contains(&self.tracing.sample_ratio) {
+            anyhow::bail!("tracing.sample_ratio must be between 0.0 and 1.0");
+        }
+        if self.admin.probe_backend && self.admin.probe_timeout.is_zero() {
+            anyhow::bail!("admin.probe_timeout_secs must be nonzero");
+        }
+        if self.proxy_protocol.required && !self.proxy_protocol.enabled {
+            anyhow::bail!("proxy_protocol.required requires proxy_protocol.enabled");
+        }
+        if self.runtime.worker_threads == Some(0) {
+            anyhow::bail!("runtime.worker_threads must be nonzero");
+        }
+        if self.runtime.current_thread && self.runtime.worker_threads.is_some() {
+            anyhow::bail!("runtime.current_thread and runtime.worker_threads are incompatible");
+        }
+        if self.runtime.max_blocking_threads == Some(0) {
+            anyhow::bail!("runtime.max_blocking_threads must be nonzero");
+        }
+        if self.socks.username.is_some() != self.socks.password.is_some() {
+            anyhow::bail!("socks.username and socks.password must be set together");
+        }
+        if let Some(udp) = &self.socks.udp {
+            if self.backend.upstream.is_some() {
+                anyhow::bail!("socks.udp cannot be used with backend.upstream");
+            }
+            if udp.idle_timeout.is_zero() {
+                anyhow::bail!("socks.udp.idle_timeout_secs must be nonzero");
+            }
+            if udp.max_flows == 0 {
+                anyhow::bail!("socks.udp.max_flows must be nonzero");
+            }
+        }
+        if !self.http3.listen.is_empty() {
+            if !cfg!(feature = "http3") {
+                anyhow::bail!("http3.listen requires building with the http3 feature");
+            }
+            if self.http3.cert.is_none() || self.http3.key.is_none() {
+                anyhow::bail!("http3.listen requires http3.cert and http3.key");
+            }
+        }
+        if self.http3.max_concurrent_streams == 0 {
+            anyhow::bail!("http3.max_concurrent_streams must be nonzero");
+        }
+        if !self.websocket.path.starts_with('/') {
+            anyhow::bail!("websocket.path must begin with /");
+        }
+        if let Some(tap) = &self.tap {
+            if tap.dir.is_some() == tap.mirror.is_some() {
+                anyhow::bail!("exactly one of tap.dir and tap.mirror must be set");
+            }
+            if tap.max_bytes == 0 || tap.max_per_minute == 0 {
+                anyhow::bail!("tap.max_bytes and tap.max_per_minute must be nonzero");
+            }
+        }
+        if let Some(chaos) = &self.backend.chaos {
+            if !(0.0..=100.0).contains(&chaos.percent) {
+                anyhow::bail!("backend.chaos.percent must be between 0 and 100");
+            }
+            if chaos.faults.is_empty() {
+                anyhow::bail!("backend.chaos.faults must not be empty");
+            }
+            if chaos.slow_read_rate == 0 {
+                anyhow::bail!("backend.chaos.slow_read_rate must be nonzero");
+            }
+        }
+        if let Some(capture) = &self.capture {
+            if capture.dir.is_none() {
+                anyhow::bail!("capture.dir must be set");
+            }
+            if capture.max_file_bytes == 0 {
+                anyhow::bail!("capture.max_file_bytes must be nonzero");
+            }
+        }
+        if !self.reverse.listen.is_empty() && self.reverse.api_key.is_none() {
+            anyhow::bail!("reverse.listen requires reverse.api_key");
+        }
+        if self.reverse.key_name.is_empty() {
+            anyhow::bail!("reverse.key_name must not be empty");
+        }
+        if self.reverse.pool.max_idle > 0 && self.reverse.pool.idle_timeout.is_zero() {
+            anyhow::bail!("reverse.pool.idle_timeout_secs must be nonzero");
+        }
+        if !self.mitm.hosts.is_empty()
+            && (self.mitm.ca_cert.is_none() || self.mitm.ca_key.is_none())
+        {
+            anyhow::bail!("mitm.hosts requires mitm.ca_cert and mitm.ca_key");
+        }
+        if self.mitm.inject_api_key && self.reverse.api_key.is_none() {
+            anyhow::bail!("mitm.inject_api_key requires reverse.api_key");
+        }
+        if self.cache.max_size > 0 && self.cache.ttl.is_zero() {
+            anyhow::bail!("cache.ttl_secs must be nonzero");
+        }
+        if self.cache.path.is_some() && self.cache.max_size == 0 {
+            anyhow::bail!("cache.path requires cache.max_size");
+        }
+        if self.cache.path.is_some() && self.cache.persist_interval.is_zero() {
+            anyhow::bail!("cache.persist_interval_secs must be nonzero");
+        }
+        if self.tls.cert.is_some() != self.tls.key.is_some() {
+            anyhow::bail!("tls.cert and tls.key must be set together");
+        }
+        if self.tls.client_ca.is_some() && self.tls.cert.is_none() {
+            anyhow::bail!("tls.client_ca requires tls.cert and tls.key");
+        }
+        if !self.tls.client_names.is_empty() && self.tls.client_ca.is_none() {
+            anyhow::bail!("tls.client_names requires tls.client_ca");
+        }
+        Ok(())
+    }
+}
+
+/// Deserialize durations given as an integer number of seconds
+mod secs {
+    use serde::{Deserialize, Deserializer};
+    use std::time::Duration;
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Duration, D::Error> {
+        Ok(Duration::from_secs(u64::deserialize(d)?))
+    }
+}
+
+/// Deserialize durations given as an integer number of milliseconds
+mod millis {
+    use serde::{Deserialize, Deserializer};
+    use std::time::Duration;
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Duration, D::Error> {
+        Ok(Duration::from_millis(u64::deserialize(d)?))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::temp_file;
+    use std::collections::HashMap;
+
+    fn load(path: Option<&Path>, vars: &[(&str, &str)]) -> Result<Config> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        Config::load_with_vars(path, |name| vars.get(name).cloned())
+    }
+
+    fn from_map(vars: &[(&str, &str)]) -> Result<Config> {
+        load(None, vars)
+    }
+
+    #[test]
+    fn test_defaults() {
+        let config = from_map(&[]).unwrap();
+        assert_eq!(config, Config::default());
+        assert_eq!(config.listen, vec!["127.0.0.1:8080".parse().unwrap()]);
+    }
+
+    #[test]
+    fn test_bind_addr_and_port() {
+        let config = from_map(&[(BIND_ADDR_VAR, "0.0.0.0"), (PORT_VAR, "3128")]).unwrap();
+        assert_eq!(config.listen, vec!["0.0.0.0:3128".parse().unwrap()]);
+    }
+
+    #[test]
+    fn test_ipv6_bind_addr() {
+        let config = from_map(&[(BIND_ADDR_VAR, "::1")]).unwrap();
+        assert_eq!(config.listen, vec!["[::1]:8080".parse().unwrap()]);
+    }
+
+    #[test]
+    fn test_bad_bind_addr() {
+        let err = from_map(&[(BIND_ADDR_VAR, "localhost")]).unwrap_err();
+        assert!(err.to_string().contains(BIND_ADDR_VAR));
+    }
+
+    #[test]
+    fn test_bad_port() {
+        assert!(from_map(&[(PORT_VAR, "80808")]).is_err());
+        assert!(from_map(&[(PORT_VAR, "")]).is_err());
+        assert!(from_map(&[(PORT_VAR, "http")]).is_err());
+    }
+
+    #[test]
+    fn test_toml_full() {
+        let config = Config::from_toml(
+            r#"
+            listen = ["127.0.0.1:3128", "[::1]:3128"]
+            acceptors = 4
+            backlog = 4096
+            restart_acceptors = true
+            abort_on_panic = true
+            drain_mode = "stop_accepting"
+
+            [socket]
+            nodelay = true
+            recv_buffer_size = 262144
+
+            [socket.keepalive]
+            time_secs = 30
+
+            [backend]
+            kind = "chained"
+            host = "example.com"
+            port = 8443
+            allow = ["*.example.com:443,8443"]
+            allowed_ports = "443,8000-8999"
+            connect_timeout_secs = 3
+            connect_retries = 5
+            retry_backoff_ms = 250
+            retry_rotate_addresses = false
+            blocked_networks = ["10.0.0.0/8", "fd00::/8"]
+            send_proxy_protocol = true
+            verify_sni = true
+            tls_ca = "/etc/giphyproxy/backend-ca.pem"
+            failover = ["api-backup.giphy.com:443", "[2a04:4e42::514]:8443"]
+
+            [backend.socket]
+            nodelay = true
+            send_buffer_size = 1048576
+            recv_buffer_size = 1048576
+            bind_address = "192.0.2.10"
+            bind_device = "eth1"
+
+            [backend.dns]
+            nameservers = ["10.0.0.53:53", "[2001:db8::53]:53"]
+            protocol = "tls"
+            tls_name = "dns.example.com"
+            fallback_to_system = true
+            cache_size = 100
+            max_ttl_secs = 30
+            fallback_to_dns = false
+
+            [backend.dns.hosts]
+            "api.giphy.com" = ["151.101.2.2", "2a04:4e42::514"]
+
+            [backend.circuit_breaker]
+            failure_threshold = 3
+            cooldown_secs = 10
+
+            [backend.rotation]
+            refresh_secs = 60
+            unhealthy_secs = 5
+
+            [backend.load_balance]
+            strategy = "least_connections"
+
+            [backend.load_balance.endpoints]
+            "api-1.giphy.com:443" = 3
+            "api-2.giphy.com:443" = 1
+
+            [backend.health_check]
+            interval_secs = 5
+            timeout_secs = 1
+            tls = true
+            unhealthy_threshold = 2
+
+            [backend.connect_rate]
+            per_second = 2.5
+            burst = 5
+
+            [backend.upstream]
+            address = "proxy.corp:3128"
+            username = "alice"
+            password = "hunter2"
+
+            [backend.recording]
+            mode = "record"
+            dir = "/var/lib/giphyproxy/recordings"
+
+            [backend.chaos]
+            percent = 5.0
+            faults = ["reset", "slow_read"]
+            seed = 42
+            latency_ms = 500
+            max_bytes = 100
+            slow_read_rate = 10
+
+            [limits]
+            max_head_size = 2048
+            max_headers = 10
+            max_header_line = 512
+            allow_bare_lf = true
+            strict_hostnames = true
+            default_connect_port = 8443
+            head_timeout_secs = 5
+            idle_timeout_secs = 60
+            max_connections = 100
+            max_connections_per_client = 5
+            tunnel_buffer_size = 16384
+            require_tls = ["0.0.0.0:8443"]
+
+            [limits.connection_rate]
+            per_second = 2.5
+            burst = 5
+
+            [log]
+            level = "debug"
+            output = "syslog"
+            tls_fingerprints = true
+
+            [log.syslog]
+            address = "udp://logs.example.com:514"
+            facility = "local3"
+            app_name = "proxy"
+
+            [log.access]
+            path = "/var/log/giphyproxy/access.log"
+            format = "common"
+            max_size = 1048576
+            keep = 3
+
+            [tracing]
+            otlp_endpoint = "http://collector:4318/v1/traces"
+            sample_ratio = 0.25
+            service_name = "gifs"
+
+            [admin]
+            listen = "127.0.0.1:9090"
+            probe_backend = true
+            probe_timeout_secs = 5
+
+            [socks]
+            enabled = false
+            username = "user"
+            password = "pass"
+
+            [socks.udp]
+            idle_timeout_secs = 30
+            max_flows = 8
+
+            [auth]
+            htpasswd = "/etc/giphyproxy/htpasswd"
+            realm = "gifs"
+
+            [http2]
+            enabled = false
+
+            [http3]
+            listen = ["0.0.0.0:443"]
+            cert = "/etc/giphyproxy/h3.crt"
+            key = "/etc/giphyproxy/h3.key"
+            max_concurrent_streams = 10
+
+            [websocket]
+            enabled = true
+            path = "/ws"
+            allowed_origins = ["https://app.example.com"]
+
+            [forward]
+            enabled = true
+
+            [reverse]
+            listen = ["127.0.0.1:8081"]
+            api_key = "secret"
+            key_location = "header"
+            key_name = "X-Api-Key"
+
+            [reverse.pool]
+            max_idle = 4
+            max_idle_per_host = 2
+            idle_timeout_secs = 15
+
+            [transparent]
+            listen = ["0.0.0.0:3129"]
+            mode = "tproxy"
+
+            [mitm]
+            hosts = ["api.giphy.com:443"]
+            ca_cert = "/etc/giphyproxy/mitm-ca.pem"
+            ca_key = "/etc/giphyproxy/mitm-ca-key.pem"
+            log_requests = true
+            deny_paths = ["/v1/stickers"]
+            inject_api_key = true
+
+            [content]
+            max_rating = "pg-13"
+
+            [cache]
+            max_size = 10000000
+            ttl_secs = 600
+            path = "/var/cache/giphyproxy/responses.json"
+            persist_interval_secs = 30
+
+            [tls]
+            cert = "/etc/giphyproxy/cert.pem"
+            key = "/etc/giphyproxy/key.pem"
+            client_ca = "/etc/giphyproxy/clients.pem"
+            client_names = ["*.clients.example.com"]
+
+            [acl]
+            allow = ["10.0.0.0/8", "2001:db8::/32"]
+            deny = ["10.0.0.0/24"]
+            deny_by_default = true
+            file = "/etc/giphyproxy/acl"
+
+            [proxy_protocol]
+            enabled = true
+            required = true
+
+            [runtime]
+            worker_threads = 4
+            max_blocking_threads = 16
+
+            [audit]
+            database = "/var/lib/giphyproxy/audit.db"
+            retention_secs = 86400
+
+            [quota]
+            daily_bytes = 1000000000
+            path = "/var/lib/giphyproxy/quota.json"
+            persist_interval_secs = 30
+
+            [quota.users]
+            alice = 5000000000
+
+            [tap]
+            dir = "/var/tmp/giphyproxy-tap"
+            max_bytes = 1024
+            max_per_minute = 2
+
+            [capture]
+            enabled = true
+            dir = "/var/tmp/giphyproxy-capture"
+            max_file_bytes = 1000000
+
+            [identity]
+            name = "proxy.example.com"
+            proxy_agent = "giphyproxy/0.1"
+            via = true
+            anonymous = true
+
+            [identity.connect_headers]
+            Connection = "keep-alive"
+
+            [sandbox]
+            user = "nobody"
+            group = "nogroup"
+            chroot = "/var/empty"
+            seccomp = true
+
+            [daemon]
+            enabled = true
+            pid_file = "/run/giphyproxy.pid"
+            log_file = "/var/log/giphyproxy.log"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.listen,
+            vec![
+                "127.0.0.1:3128".parse().unwrap(),
+                "[::1]:3128".parse().unwrap()
+            ]
+        );
+        assert_eq!(config.acceptors, 4);
+        assert_eq!(config.backlog, 4096);
+        assert!(config.restart_acceptors);
+        assert!(config.abort_on_panic);
+        assert_eq!(config.drain_mode, DrainMode::StopAccepting);
+        assert_eq!(
+            config.socket,
+            SocketConfig {
+                nodelay: true,
+                keepalive: Some(KeepaliveConfig {
+                    time: Duration::from_secs(30),
+                    interval: Duration::from_secs(10),
+                    retries: 5,
+                }),
+                send_buffer_size: None,
+                recv_buffer_size: Some(262144),
+                bind_address: None,
+                bind_device: None,
+            }
+        );
+        assert_eq!(
+            config.backend.socket,
+            SocketConfig {
+                nodelay: true,
+                keepalive: None,
+                send_buffer_size: Some(1048576),
+                recv_buffer_size: Some(1048576),
+                bind_address: Some("192.0.2.10".parse().unwrap()),
+                bind_device: Some("eth1".into()),
+            }
+        );
+        assert_eq!(config.backend.host, "example.com");
+        assert_eq!(config.backend.port, 8443);
+        assert_eq!(
+            config.backend.allow,
+            vec!["*.example.com:443,8443".parse().unwrap()]
+        );
+        assert_eq!(
+            config.backend.allowed_ports,
+            PortSet::Ranges(vec![(443, 443), (8000, 8999)])
+        );
+        assert_eq!(config.backend.connect_timeout, Duration::from_secs(3));
+        assert_eq!(config.backend.connect_retries, 5);
+        assert_eq!(config.backend.retry_backoff, Duration::from_millis(250));
+        assert!(!config.backend.retry_rotate_addresses);
+        assert_eq!(
+            config.backend.blocked_networks,
+            vec!["10.0.0.0/8".parse().unwrap(), "fd00::/8".parse().unwrap()]
+        );
+        assert!(config.backend.send_proxy_protocol);
+        assert!(config.backend.verify_sni);
+        assert_eq!(
+            config.backend.tls_ca,
+            Some(PathBuf::from("/etc/giphyproxy/backend-ca.pem"))
+        );
+        assert_eq!(config.backend.kind, Some(BackendKind::Chained));
+        assert_eq!(
+            config.backend.dns,
+            DnsConfig {
+                nameservers: vec![
+                    "10.0.0.53:53".parse().unwrap(),
+                    "[2001:db8::53]:53".parse().unwrap()
+                ],
+                protocol: DnsProtocol::Tls,
+                tls_name: Some("dns.example.com".into()),
+                fallback_to_system: true,
+                cache_size: 100,
+                max_ttl: Duration::from_secs(30),
+                hosts: BTreeMap::from([(
+                    "api.giphy.com".to_owned(),
+                    vec![
+                        "151.101.2.2".parse().unwrap(),
+                        "2a04:4e42::514".parse().unwrap()
+                    ]
+                )]),
+                fallback_to_dns: false,
+            }
+        );
+        assert_eq!(
+            config.backend.circuit_breaker,
+            Some(CircuitBreakerConfig {
+                failure_threshold: 3,
+                cooldown: Duration::from_secs(10),
+            })
+        );
+        assert_eq!(
+            config.backend.rotation,
+            Some(RotationConfig {
+                refresh: Duration::from_secs(60),
+                unhealthy: Duration::from_secs(5),
+            })
+        );
+        assert_eq!(
+            config.backend.failover,
+            vec![
+                Endpoint {
+                    host: "api-backup.giphy.com".into(),
+                    port: 443,
+                },
+                Endpoint {
+                    host: "2a04:4e42::514".into(),
+                    port: 8443,
+                },
+            ]
+        );
+        assert_eq!(
+            config.backend.load_balance,
+            Some(LoadBalanceConfig {
+                strategy: BalanceStrategy::LeastConnections,
+                endpoints: BTreeMap::from([
+                    ("api-1.giphy.com:443".parse().unwrap(), 3),
+                    ("api-2.giphy.com:443".parse().unwrap(), 1),
+                ]),
+            })
+        );
+        assert_eq!(
+            config.backend.health_check,
+            Some(HealthCheckConfig {
+                interval: Duration::from_secs(5),
+                timeout: Duration::from_secs(1),
+                tls: true,
+                unhealthy_threshold: 2,
+            })
+        );
+        assert_eq!(
+            config.backend.connect_rate,
+            Some(ConnectionRateConfig {
+                per_second: 2.5,
+                burst: 5,
+            })
+        );
+        assert_eq!(
+            config.backend.recording,
+            Some(RecordingConfig {
+                mode: RecordingMode::Record,
+                dir: "/var/lib/giphyproxy/recordings".into(),
+            })
+        );
+        assert_eq!(
+            config.backend.upstream,
+            Some(UpstreamConfig {
+                address: "proxy.corp:3128".into(),
+                username: Some("alice".into()),
+                password: Some("hunter2".into()),
+            })
+        );
+        assert_eq!(config.limits.max_head_size, 2048);
+        assert_eq!(config.limits.max_headers, 10);
+        assert_eq!(config.limits.max_header_line, 512);
+        assert!(config.limits.allow_bare_lf);
+        assert!(config.limits.strict_hostnames);
+        assert_eq!(config.limits.default_connect_port, 8443);
+        assert_eq!(config.limits.head_timeout, Duration::from_secs(5));
+        assert_eq!(config.limits.idle_timeout, Duration::from_secs(60));
+        assert_eq!(config.limits.max_connections, 100);
+        assert_eq!(config.limits.max_connections_per_client, 5);
+        assert_eq!(config.limits.tunnel_buffer_size, 16384);
+        assert_eq!(
+            config.limits.require_tls,
+            vec!["0.0.0.0:8443".parse().unwrap()]
+        );
+        assert_eq!(
+            config.limits.connection_rate,
+            Some(ConnectionRateConfig {
+                per_second: 2.5,
+                burst: 5,
+            })
+        );
+        assert_eq!(config.log.level, Some("debug".into()));
+        assert_eq!(config.log.output, LogOutput::Syslog);
+        assert!(config.log.tls_fingerprints);
+        assert_eq!(
+            config.log.syslog,
+            SyslogConfig {
+                address: "udp://logs.example.com:514".into(),
+                facility: SyslogFacility::Local3,
+                app_name: "proxy".into(),
+            }
+        );
+        assert_eq!(
+            config.log.access,
+            AccessLogConfig {
+                path: Some("/var/log/giphyproxy/access.log".into()),
+                format: AccessLogFormat::Common,
+                max_size: 1048576,
+                keep: 3,
+            }
+        );
+        assert_eq!(
+            config.tracing,
+            TracingConfig {
+                otlp_endpoint: Some("http://collector:4318/v1/traces".into()),
+                sample_ratio: 0.25,
+                service_name: "gifs".into(),
+            }
+        );
+        assert_eq!(config.admin.listen, Some("127.0.0.1:9090".parse().unwrap()));
+        assert!(config.admin.probe_backend);
+        assert_eq!(config.admin.probe_timeout, Duration::from_secs(5));
+        assert!(!config.socks.enabled);
+        assert_eq!(config.socks.username, Some("user".into()));
+        assert_eq!(config.socks.password, Some("pass".into()));
+        assert_eq!(
+            config.socks.udp,
+            Some(UdpRelayConfig {
+                idle_timeout: Duration::from_secs(30),
+                max_flows: 8,
+            })
+        );
+        assert_eq!(
+            config.auth.htpasswd,
+            Some(PathBuf::from("/etc/giphyproxy/htpasswd"))
+        );
+        assert_eq!(config.auth.realm, "gifs");
+        assert!(!config.http2.enabled);
+        assert_eq!(
+            config.http3,
+            Http3Config {
+                listen: vec!["0.0.0.0:443".parse().unwrap()],
+                cert: Some(PathBuf::from("/etc/giphyproxy/h3.crt")),
+                key: Some(PathBuf::from("/etc/giphyproxy/h3.key")),
+                max_concurrent_streams: 10,
+            }
+        );
+        assert_eq!(
+            config.websocket,
+            WebSocketConfig {
+                enabled: true,
+                path: "/ws".into(),
+                allowed_origins: vec!["https://app.example.com".into()],
+            }
+        );
+        assert!(config.forward.enabled);
+        assert_eq!(
+            config.reverse,
+            ReverseConfig {
+                listen: vec!["127.0.0.1:8081".parse().unwrap()],
+                api_key: Some("secret".into()),
+                key_location: KeyLocation::Header,
+                key_name: "X-Api-Key".into(),
+                pool: PoolConfig {
+                    max_idle: 4,
+                    max_idle_per_host: 2,
+                    idle_timeout: Duration::from_secs(15),
+                },
+            }
+        );
+        assert_eq!(
+            config.transparent,
+            TransparentConfig {
+                listen: vec!["0.0.0.0:3129".parse().unwrap()],
+                mode: TransparentMode::Tproxy,
+            }
+        );
+        assert_eq!(
+            config.mitm,
+            MitmConfig {
+                hosts: vec!["api.giphy.com:443".parse().unwrap()],
+                ca_cert: Some(PathBuf::from("/etc/giphyproxy/mitm-ca.pem")),
+                ca_key: Some(PathBuf::from("/etc/giphyproxy/mitm-ca-key.pem")),
+                log_requests: true,
+                deny_paths: vec!["/v1/stickers".into()],
+                inject_api_key: true,
+            }
+        );
+        assert_eq!(config.content.max_rating, Some(Rating::Pg13));
+        assert_eq!(
+            config.cache,
+            CacheConfig {
+                max_size: 10000000,
+                ttl: Duration::from_secs(600),
+                path: Some(PathBuf::from("/var/cache/giphyproxy/responses.json")),
+                persist_interval: Duration::from_secs(30),
+            }
+        );
+        assert_eq!(
+            config.tls.cert,
+            Some(PathBuf::from("/etc/giphyproxy/cert.pem"))
+        );
+        assert_eq!(
+            config.tls.key,
+            Some(PathBuf::from("/etc/giphyproxy/key.pem"))
+        );
+        assert_eq!(
+            config.acl,
+            AclConfig {
+                allow: vec![
+                    "10.0.0.0/8".parse().unwrap(),
+                    "2001:db8::/32".parse().unwrap()
+                ],
+                deny: vec!["10.0.0.0/24".parse().unwrap()],
+                deny_by_default: true,
+                file: Some(PathBuf::from("/etc/giphyproxy/acl")),
+            }
+        );
+        assert_eq!(
+            config.proxy_protocol,
+            ProxyProtocolConfig {
+                enabled: true,
+                required: true,
+            }
+        );
+        assert_eq!(
+            config.runtime,
+            RuntimeConfig {
+                worker_threads: Some(4),
+                current_thread: false,
+                max_blocking_threads: Some(16),
+            }
+        );
+        assert_eq!(
+            config.audit,
+            AuditConfig {
+                database: Some("/var/lib/giphyproxy/audit.db".into()),
+                retention: Duration::from_secs(86400),
+            }
+        );
+        assert_eq!(
+            config.quota,
+            QuotaConfig {
+                daily_bytes: 1_000_000_000,
+                users: HashMap::from([("alice".to_owned(), 5_000_000_000)]),
+                path: Some("/var/lib/giphyproxy/quota.json".into()),
+                persist_interval: Duration::from_secs(30),
+            }
+        );
+        assert!(config.quota.enabled());
+        assert!(!QuotaConfig::default().enabled());
+        assert_eq!(
+            config.tap,
+            Some(TapConfig {
+                enabled: false,
+                dir: Some("/var/tmp/giphyproxy-tap".into()),
+                mirror: None,
+                max_bytes: 1024,
+                max_per_minute: 2,
+            })
+        );
+        assert_eq!(
+            config.backend.chaos,
+            Some(ChaosConfig {
+                percent: 5.0,
+                faults: vec![ChaosFault::Reset, ChaosFault::SlowRead],
+                seed: Some(42),
+                latency: Duration::from_millis(500),
+                max_bytes: 100,
+                slow_read_rate: 10,
+            })
+        );
+        assert_eq!(
+            config.capture,
+            Some(CaptureConfig {
+                enabled: true,
+                dir: Some("/var/tmp/giphyproxy-capture".into()),
+                max_file_bytes: 1000000,
+            })
+        );
+        assert_eq!(
+            config.identity,
+            IdentityConfig {
+                name: "proxy.example.com".into(),
+                proxy_agent: Some("giphyproxy/0.1".into()),
+                via: true,
+                connect_headers: BTreeMap::from([("Connection".into(), "keep-alive".into())]),
+                anonymous: true,
+            }
+        );
+        assert_eq!(
+            config.sandbox,
+            SandboxConfig {
+                user: Some("nobody".into()),
+                group: Some("nogroup".into()),
+                chroot: Some("/var/empty".into()),
+                seccomp: true,
+            }
+        );
+        assert_eq!(
+            config.daemon,
+            DaemonConfig {
+                enabled: true,
+                pid_file: Some("/run/giphyproxy.pid".into()),
+                log_file: Some("/var/log/giphyproxy.log".into()),
+            }
+        );
+    }
+
+    #[test]
+    fn test_toml_partial() {
+        let config = Config::from_toml("[backend]\nport = 8443\n").unwrap();
+        assert_eq!(config.backend.host, "api.giphy.com");
+        assert_eq!(config.backend.port, 8443);
+        assert_eq!(config.listen, Config::default().listen);
+    }
+
+    #[test]
+    fn test_toml_circuit_breaker_defaults() {
+        let config = Config::from_toml("[backend.circuit_breaker]\n").unwrap();
+        assert_eq!(
+            config.backend.circuit_breaker,
+            Some(CircuitBreakerConfig::default())
+        );
+        assert_eq!(Config::default().backend.circuit_breaker, None);
+    }
+
+    #[test]
+    fn test_toml_rotation_defaults() {
+        let config = Config::from_toml("[backend.rotation]\n").unwrap();
+        assert_eq!(config.backend.rotation, Some(RotationConfig::default()));
+        assert_eq!(Config::default().backend.rotation, None);
+    }
+
+    #[test]
+    fn test_toml_health_check_defaults() {
+        let config = Config::from_toml("[backend.health_check]\n").unwrap();
+        assert_eq!(
+            config.backend.health_check,
+            Some(HealthCheckConfig::default())
+        );
+        assert_eq!(Config::default().backend.health_check, None);
+    }
+
+    #[test]
+    fn test_toml_bad_allow_entry() {
+        assert!(Config::from_toml("[backend]\nallow = [\"*.giphy.com\"]\n").is_err());
+        assert!(Config::from_toml("[backend]\nallowed_ports = \"443-80\"\n").is_err());
+    }
+
+    #[test]
+    fn test_rating() {
+        assert_eq!(Rating::parse("PG-13"), Some(Rating::Pg13));
+        assert_eq!(Rating::parse("nc-17"), None);
+        assert!(Rating::Y < Rating::G && Rating::Pg13 < Rating::R);
+        assert!(Config::from_toml("[content]\nmax_rating = \"nc-17\"\n").is_err());
+    }
+
+    #[test]
+    fn test_toml_unknown_field() {
+        assert!(Config::from_toml("[backend]\nhots = \"example.com\"\n").is_err());
+    }
+
+    #[test]
+    fn test_env_overrides_file() {
+        let path = temp_file(
+            "env-overrides.toml",
+            "listen = [\"10.0.0.1:3128\", \"10.0.0.2:3128\"]\n",
+        );
+
+        let config = load(None, &[(CONFIG_VAR, path.to_str().unwrap())]).unwrap();
+        assert_eq!(config.listen.len(), 2);
+
+        let config = load(Some(&path), &[(PORT_VAR, "9000")]).unwrap();
+        assert_eq!(config.listen, vec!["10.0.0.1:9000".parse().unwrap()]);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_missing_file() {
+        let err = load(Some(Path::new("/nonexistent/giphyproxy.toml")), &[]).unwrap_err();
+        assert!(err.to_string().contains("/nonexistent/giphyproxy.toml"));
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(Config::from_toml("listen = []")
+            .unwrap()
+            .validate()
+            .is_err());
+        assert!(
+            Config::from_toml("[reverse]\nlisten = [\"127.0.0.1:8081\"]")
+                .unwrap()
+                .validate()
+                .is_err()
+        );
+        assert!(Config::from_toml("[reverse]\nkey_name = \"\"")
+            .unwrap()
+            .validate()
+            .is_err());
+        assert!(Config::from_toml("[reverse.pool]\nidle_timeout_secs = 0")
+            .unwrap()
+            .validate()
+            .is_err());
+        assert!(
+            Config::from_toml("[reverse.pool]\nmax_idle = 0\nidle_timeout_secs = 0")
+                .unwrap()
+                .validate()
+                .is_ok()
+        );
+        assert!(Config::from_toml("[mitm]\nhosts = [\"api.giphy.com:443\"]")
+            .unwrap()
+            .validate()
+            .is_err());
+        assert!(Config::from_toml("[mitm]\ninject_api_key = true")
+            .unwrap()
+            .validate()
+            .is_err());
+        assert!(Config::from_toml("[backend]\nkind = \"chained\"")
+            .unwrap()
+            .validate()
+            .is_err());
+        assert!(Config::from_toml(
+            "[backend]\nkind = \"mock\"\n[backend.upstream]\naddress = \"proxy:3128\""
+        )
+        .unwrap()
+        .validate()
+        .is_err());
+        assert!(Config::from_toml("[backend]\nkind = \"single_host\"")
+            .unwrap()
+            .validate()
+            .is_ok());
+        assert!(Config::from_toml("[cache]\nmax_size = 1000\nttl_secs = 0")
+            .unwrap()
+            .validate()
+            .is_err());
+        assert!(Config::from_toml("[cache]\npath = \"/tmp/cache.json\"")
+            .unwrap()
+            .validate()
+            .is_err());
+        assert!(Config::from_toml("acceptors = 0")
+            .unwrap()
+            .validate()
+            .is_err());
+        assert!(Config::from_toml("[limits]\nmax_head_size = 0")
+            .unwrap()
+            .validate()
+            .is_err());
+        assert!(Config::from_toml("[limits]\nmax_header_line = 0")
+            .unwrap()
+            .validate()
+            .is_err());
+        assert!(Config::from_toml("[limits]\ntunnel_buffer_size = 0")
+            .unwrap()
+            .validate()
+            .is_err());
+        assert!(
+            Config::from_toml("[limits.connection_rate]\nper_second = 0.0")
+                .unwrap()
+                .validate()
+                .is_err()
+        );
+        assert!(Config::from_toml("[limits.connection_rate]\nburst = 0")
+            .unwrap()
+            .validate()
+            .is_err());
+        assert!(Config::from_toml("[backend.connect_rate]\nburst = 0")
+            .unwrap()
+            .validate()
+            .is_err());
+        assert!(
+            Config::from_toml("[admin]\nprobe_backend = true\nprobe_timeout_secs = 0")
+                .unwrap()
+                .validate()
+                .is_err()
+        );
+        assert!(Config::from_toml("[tracing]\nsample_ratio = 1.5")
+            .unwrap()
+            .validate()
+            .is_err());
+        assert!(Config::from_toml("[proxy_protocol]\nrequired = true")
+            .unwrap()
+            .validate()
+            .is_err());
+        assert!(Config::from_toml("[runtime]\nworker_threads = 0")
+            .unwrap()
+            .validate()
+            .is_err());
+        assert!(
+            Config::from_toml("[runtime]\ncurrent_thread = true\nworker_threads = 2")
+                .unwrap()
+                .validate()
+                .is_err()
+        );
+        assert!(Config::from_toml("[socks]\nusername = \"user\"")
+            .unwrap()
+            .validate()
+            .is_err());
+        assert!(Config::from_toml("[http3]\nlisten = [\"0.0.0.0:443\"]")
+            .unwrap()
+            .validate()
+            .is_err());
+        assert!(Config::from_toml("[tap]").unwrap().validate().is_err());
+        assert!(
+            Config::from_toml("[tap]\ndir = \"/tmp\"\nmirror = \"127.0.0.1:9\"")
+                .unwrap()
+                .validate()
+                .is_err()
+        );
+        assert!(Config::from_toml("[tap]\ndir = \"/tmp\"\nmax_bytes = 0")
+            .unwrap()
+            .validate()
+            .is_err());
+        assert!(Config::from_toml("[backend.chaos]")
+            .unwrap()
+            .validate()
+            .is_ok());
+        assert!(Config::from_toml("[backend.chaos]\npercent = 101.0")
+            .unwrap()
+            .validate()
+            .is_err());
+        assert!(Config::from_toml("[backend.chaos]\nfaults = []")
+            .unwrap()
+            .validate()
+            .is_err());
+        assert!(Config::from_toml("[backend.chaos]\nfaults = [\"explode\"]").is_err());
+        assert!(Config::from_toml("[capture]").unwrap().validate().is_err());
+        assert!(
+            Config::from_toml("[capture]\ndir = \"/tmp\"\nmax_file_bytes = 0")
+                .unwrap()
+                .validate()
+                .is_err()
+        );
+        assert!(Config::from_toml("[websocket]\npath = \"tunnel\"")
+            .unwrap()
+            .validate()
+            .is_err());
+        assert!(Config::from_toml("[http3]\nmax_concurrent_streams = 0")
+            .unwrap()
+            .validate()
+            .is_err());
+        assert!(Config::from_toml("[socks.udp]\nmax_flows = 0")
+            .unwrap()
+            .validate()
+            .is_err());
+        assert!(
+            Config::from_toml("[socks.udp]\n[backend.upstream]\naddress = \"parent:3128\"")
+                .unwrap()
+                .validate()
+                .is_err()
+        );
+        assert!(Config::from_toml("[tls]\ncert = \"cert.pem\"")
+            .unwrap()
+            .validate()
+            .is_err());
+        assert!(Config::from_toml(
+            "[backend.dns]\nnameservers = [\"1.1.1.1:853\"]\nprotocol = \"tls\""
+        )
+        .unwrap()
+        .validate()
+        .is_err());
+        assert!(
+            Config::from_toml("[backend.dns.hosts]\n\"api.giphy.com\" = []")
+                .unwrap()
+                .validate()
+                .is_err()
+        );
+        assert!(
+            Config::from_toml("[backend.dns.hosts]\n\"10.0.0.1\" = [\"10.0.0.2\"]")
+                .unwrap()
+                .validate()
+                .is_err()
+        );
+        assert!(Config::from_toml("[backend.rotation]\n")
+            .unwrap()
+            .validate()
+            .is_err());
+        assert!(Config::from_toml(
+            "[backend]\nkind = \"single_host\"\n[backend.rotation]\nrefresh_secs = 0"
+        )
+        .unwrap()
+        .validate()
+        .is_err());
+        assert!(
+            Config::from_toml("[backend]\nfailover = [\"api-backup.giphy.com:443\"]")
+                .unwrap()
+                .validate()
+                .is_err()
+        );
+        assert!(Config::from_toml(
+            "[backend]\nkind = \"single_host\"\n[backend.load_balance]\nstrategy = \"round_robin\""
+        )
+        .unwrap()
+        .validate()
+        .is_err());
+        assert!(Config::from_toml(
+            "[backend]\nkind = \"single_host\"\n[backend.load_balance.endpoints]\n\"a:443\" = 0"
+        )
+        .unwrap()
+        .validate()
+        .is_err());
+        assert!(Config::from_toml(
+            "[backend]\nkind = \"single_host\"\n[backend.load_balance.endpoints]\n\"a:443\" = 1"
+        )
+        .unwrap()
+        .validate()
+        .is_ok());
+        assert!(
+            Config::from_toml("[backend.health_check]\ninterval_secs = 0")
+                .unwrap()
+                .validate()
+                .is_err()
+        );
+        assert!(
+            Config::from_toml("[backend.health_check]\nunhealthy_threshold = 0")
+                .unwrap()
+                .validate()
+                .is_err()
+        );
+        assert!(Config::from_toml("[tls]\nclient_names = [\"alice\"]")
+            .unwrap()
+            .validate()
+            .is_err());
+        assert!(
+            Config::from_toml("[log]\noutput = \"syslog\"\n[log.syslog]\naddress = \"\"")
+                .unwrap()
+                .validate()
+                .is_err()
+        );
+        assert!(Config::from_toml("backlog = 0")
+            .unwrap()
+            .validate()
+            .is_err());
+        assert!(Config::from_toml("[backend.socket]\nsend_buffer_size = 0")
+            .unwrap()
+            .validate()
+            .is_err());
+        assert!(Config::from_toml("[socket]\nbind_address = \"10.0.0.1\"")
+            .unwrap()
+            .validate()
+            .is_err());
+        assert!(Config::from_toml("[backend.socket]\nbind_device = \"\"")
+            .unwrap()
+            .validate()
+            .is_err());
+        assert!(Config::from_toml("[socket.keepalive]\ninterval_secs = 0")
+            .unwrap()
+            .validate()
+            .is_err());
+        assert!(Config::from_toml("[quota]\npath = \"quota.json\"")
+            .unwrap()
+            .validate()
+            .is_err());
+        assert!(Config::from_toml(
+            "[quota]\ndaily_bytes = 1\npath = \"q\"\npersist_interval_secs = 0"
+        )
+        .unwrap()
+        .validate()
+        .is_err());
+        assert!(Config::from_toml("[audit]\nretention_secs = 0")
+            .unwrap()
+            .validate()
+            .is_err());
+        assert!(Config::from_toml("[identity]\nname = \"giphy proxy\"")
+            .unwrap()
+            .validate()
+            .is_err());
+        assert!(
+            Config::from_toml("[identity.connect_headers]\n\"X: y\" = \"z\"")
+                .unwrap()
+                .validate()
+                .is_err()
+        );
+        assert!(
+            Config::from_toml("[identity.connect_headers]\nX = \"y\\r\\nZ: z\"")
+                .unwrap()
+                .validate()
+                .is_err()
+        );
+        assert!(Config::from_toml("[log.access]\nmax_size = 1000\nkeep = 0")
+            .unwrap()
+            .validate()
+            .is_err());
+        assert!(Config::from_toml("[sandbox]\nchroot = \"empty\"")
+            .unwrap()
+            .validate()
+            .is_err());
+        assert!(Config::from_toml("[daemon]\nlog_file = \"giphyproxy.log\"")
+            .unwrap()
+            .validate()
+            .is_err());
+    }
+}
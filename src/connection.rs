@@ -1,193 +1,2238 @@
+use crate::access::{AccessRecord, Reason};
+use crate::auth::Htpasswd;
 use crate::backend::Backend;
-use crate::http::{parse_head, ParseHeadResult};
-use anyhow::{bail, Context, Result};
-use tokio::io::{split, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufStream};
+use crate::capture;
+use crate::client_hello;
+use crate::config::{Config, IdentityConfig, LimitsConfig};
+use crate::error::{IoContext, ProxyError, Result};
+use crate::fingerprint::{self, Fingerprint};
+use crate::forward::{self, Forward};
+use crate::hooks::Hooks;
+use crate::http::{
+    authority, parse_absolute, parse_head, Headers, ParseHeadResult, ParseOptions, Response,
+};
+use crate::http2;
+use crate::metrics::{ActiveTunnel, METRICS};
+use crate::mitm::{Mitm, Prefixed};
+use crate::panics;
+use crate::quota;
+use crate::registry::{Registration, REGISTRY};
+use crate::socks::{self, Command, Reply};
+use crate::tap::Tap;
+use crate::telemetry::spawn;
+use crate::udp;
+use crate::websocket;
+use serde::Serialize;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{
+    duplex, split, AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite,
+    AsyncWriteExt, BufReader,
+};
+use tokio::sync::Notify;
+use tokio::time::{sleep, timeout_at};
+use tracing::Instrument;
 
-/// Maximum size of a request head; this helps avoid abuse.  It is very low because
-/// CONNECT requests should be tiny.  This is allocated on the stack, so increases
-/// should be considered carefully.
-const MAX_HEAD_SIZE: usize = 1024;
+/// Information about a client connection that is not available from the socket itself,
+/// since `connection` is written against AsyncRead and AsyncWrite.
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionInfo {
+    /// The client's address, if known
+    pub peer: Option<SocketAddr>,
 
-/// Read the HTTP request head from S and write back a response, reading no more than
-/// necessary.  Returns the CONNECT host and port.
+    /// The address of the load balancer that passed the client's address in a PROXY
+    /// protocol header, if any
+    pub proxied_by: Option<SocketAddr>,
+
+    /// The local address on which the connection was accepted, if known
+    pub local: Option<SocketAddr>,
+
+    /// The identity from the client's TLS certificate, if it presented one
+    pub client_cert: Option<String>,
+
+    /// For a connection redirected to a transparent listener, the destination the client
+    /// originally addressed
+    pub original_dst: Option<SocketAddr>,
+
+    /// Set by backends that choose between several endpoints to the one that accepted the
+    /// connection
+    pub served_by: ServedBy,
+}
+
+/// The backend endpoint that accepted a connection, shared between clones of a
+/// [`ConnectionInfo`]
+#[derive(Debug, Clone, Default)]
+pub struct ServedBy(Arc<Mutex<Option<String>>>);
+
+impl ServedBy {
+    /// Record the endpoint, as `host:port`
+    pub fn set(&self, endpoint: String) {
+        *self.0.lock().unwrap() = Some(endpoint);
+    }
+
+    /// Take the recorded endpoint, if any, leaving none recorded
+    pub fn take(&self) -> Option<String> {
+        self.0.lock().unwrap().take()
+    }
+}
+
+impl ConnectionInfo {
+    /// Information for a TCP connection accepted on `local` from `peer`
+    pub fn tcp(peer: SocketAddr, local: SocketAddr) -> Self {
+        Self {
+            peer: Some(peer),
+            proxied_by: None,
+            local: Some(local),
+            client_cert: None,
+            original_dst: None,
+            served_by: ServedBy::default(),
+        }
+    }
+}
+
+impl std::fmt::Display for ConnectionInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.peer {
+            Some(peer) => write!(f, "{}", peer)?,
+            None => write!(f, "unknown client")?,
+        }
+        if let Some(proxied_by) = self.proxied_by {
+            write!(f, " via {}", proxied_by)?;
+        }
+        Ok(())
+    }
+}
+
+/// The protocol a client used to request a tunnel
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Protocol {
+    /// An HTTP CONNECT request
+    Http,
+    /// A SOCKS5 CONNECT request
+    Socks5,
+    /// A SOCKS5 UDP ASSOCIATE request, relaying datagrams to the destinations the client
+    /// names in each
+    SocksUdp,
+    /// CONNECT requests on the streams of a cleartext HTTP/2 connection
+    Http2,
+    /// CONNECT requests on the streams of an HTTP/3 (QUIC) connection
+    Http3,
+    /// A plain HTTP request in absolute form, forwarded by the proxy
+    Forward,
+    /// A WebSocket upgrade request for a tunnel, whose data is carried in binary frames
+    #[serde(rename = "websocket")]
+    WebSocket,
+    /// A plain HTTP request to the reverse proxy, forwarded to the backend with the API key
+    Reverse,
+    /// A connection redirected to the proxy by the firewall, tunneled to its original
+    /// destination
+    Transparent,
+}
+
+/// A client's request for a tunnel
+struct Request {
+    protocol: Protocol,
+    host: String,
+    port: u16,
+    /// HTTP version of the request, which responses match; `HTTP/1.1` for SOCKS5
+    version: String,
+    /// Request headers; always empty for SOCKS5
+    headers: Headers,
+    /// Data the client sent after its request, without waiting for a response, which must
+    /// be forwarded to the backend
+    extra: Vec<u8>,
+    /// For plain HTTP requests to be forwarded, the rest of the request
+    forward: Option<Forward>,
+}
+
+/// Write a response to the client.  Errors are ignored when writing error responses,
+/// since the connection is about to be closed anyway.
+pub(crate) async fn send_response<S: AsyncWrite + Unpin>(
+    socket: &mut S,
+    response: Response,
+) -> Result<()> {
+    if response.status != 200 {
+        log::debug!("responding {} {}", response.status, response.reason);
+    }
+    socket
+        .write_all(&response.to_bytes())
+        .await
+        .io_context("writing response to client")
+}
+
+/// The response to a successful CONNECT request in the given HTTP version, with the headers
+/// configured in `identity`.  An anonymous proxy sends no `Proxy-Agent` or `Via` header,
+/// even if one is among the configured `connect_headers`.
+fn connect_response(version: &str, identity: &IdentityConfig) -> Response {
+    let mut response = Response::ok().version(version);
+    for (name, value) in &identity.connect_headers {
+        let identifying =
+            name.eq_ignore_ascii_case("Proxy-Agent") || name.eq_ignore_ascii_case("Via");
+        if !(identity.anonymous && identifying) {
+            response = response.header(name, value);
+        }
+    }
+    if let (Some(agent), false) = (&identity.proxy_agent, identity.anonymous) {
+        response = response.header("Proxy-Agent", agent);
+    }
+    if let Some(via) = identity.via(version) {
+        response = response.header("Via", via);
+    }
+    response
+}
+
+/// Read the client's request, in either HTTP CONNECT or SOCKS5 form, reading no more than
+/// necessary.  The request must arrive before `deadline`, and an HTTP head may be at most
+/// `limits.max_head_size` bytes; this helps avoid abuse.  Returns the request, including
+/// any data buffered after it, or responds with an error if the request is invalid or too
+/// slow, recording the reason in `record`.
 async fn handle_connect<S: AsyncRead + AsyncWrite + Unpin>(
+    socket: &mut BufReader<S>,
+    protocol: Protocol,
+    deadline: tokio::time::Instant,
+    config: &Config,
+    htpasswd: Option<&Htpasswd>,
+    record: &mut AccessRecord,
+) -> Result<Request> {
+    let request = async {
+        let request = match protocol {
+            Protocol::Http => read_head(socket, config, record).await?,
+            Protocol::Socks5 => {
+                let (command, host, port) =
+                    socks::handshake(socket, &config.socks, htpasswd, record).await?;
+                Request {
+                    protocol: match command {
+                        Command::Connect => Protocol::Socks5,
+                        Command::UdpAssociate => Protocol::SocksUdp,
+                    },
+                    host,
+                    port,
+                    version: "HTTP/1.1".into(),
+                    headers: Headers::default(),
+                    extra: vec![],
+                    forward: None,
+                }
+            }
+            Protocol::Http2 => unreachable!("HTTP/2 requests are handled by the http2 module"),
+            Protocol::Http3 => unreachable!("HTTP/3 requests are handled by the http3 module"),
+            Protocol::Forward => unreachable!("forwarded requests are detected as HTTP"),
+            Protocol::WebSocket => unreachable!("WebSocket requests are detected as HTTP"),
+            Protocol::Reverse => unreachable!("reverse proxy requests are handled separately"),
+            Protocol::Transparent => unreachable!("redirected connections have no request"),
+            Protocol::SocksUdp => unreachable!("UDP associations are requested with SOCKS5"),
+        };
+        Ok::<_, ProxyError>(request)
+    };
+    let mut request = match timeout_at(deadline, request).await {
+        Ok(result) => result?,
+        Err(_) => return head_timed_out(socket, record).await,
+    };
+
+    // take anything else the client has sent from the read buffer, too
+    let buffered = socket.buffer().len();
+    request.extra.extend_from_slice(socket.buffer());
+    socket.consume(buffered);
+
+    record.protocol = Some(request.protocol);
+    log::debug!(
+        "got {:?} request for {}",
+        request.protocol,
+        authority(&request.host, request.port)
+    );
+
+    Ok(request)
+}
+
+/// The request implied by a connection redirected to the proxy, for a tunnel to `dst`
+fn transparent_request(dst: SocketAddr, record: &mut AccessRecord) -> Request {
+    record.protocol = Some(Protocol::Transparent);
+    log::debug!("got redirected connection for {}", dst);
+    Request {
+        protocol: Protocol::Transparent,
+        host: dst.ip().to_string(),
+        port: dst.port(),
+        version: "HTTP/1.1".into(),
+        headers: Headers::default(),
+        extra: vec![],
+        forward: None,
+    }
+}
+
+/// Respond to a client that did not send its request in time.  This always fails.
+async fn head_timed_out<S: AsyncWrite + Unpin, T>(
+    socket: &mut S,
+    record: &mut AccessRecord,
+) -> Result<T> {
+    record.reason = Reason::HeadTimeout;
+    // SOCKS5 has no way to report a timeout, so just close the connection
+    if record.protocol != Some(Protocol::Socks5) {
+        let response = Response::error(408, "Request Timeout", "timed out reading request");
+        let _ = send_response(socket, response).await;
+    }
+    Err(ProxyError::Timeout("reading head from client".into()))
+}
+
+/// Check the credentials in an HTTP request's `Proxy-Authorization` header, responding
+/// with 407 if they are missing or invalid.
+async fn check_proxy_authorization<S: AsyncWrite + Unpin>(
+    socket: &mut S,
+    request: &Request,
+    htpasswd: &Htpasswd,
+    realm: &str,
+    record: &mut AccessRecord,
+) -> Result<()> {
+    let user = match request.headers.get("Proxy-Authorization") {
+        Some(value) => htpasswd.verify_header(value).await,
+        None => None,
+    };
+    match user {
+        Some(user) => {
+            record.user = Some(user);
+            Ok(())
+        }
+        None => {
+            record.reason = Reason::AuthFailed;
+            let response = Response::error(
+                407,
+                "Proxy Authentication Required",
+                "proxy authentication required",
+            )
+            .header("Proxy-Authenticate", format!("Basic realm=\"{}\"", realm))
+            .version(&*request.version);
+            let _ = send_response(socket, response).await;
+            if request.headers.get("Proxy-Authorization").is_some() {
+                return Err(ProxyError::Auth("invalid proxy credentials".into()));
+            }
+            Err(ProxyError::Auth("missing proxy credentials".into()))
+        }
+    }
+}
+
+/// Determine the client's protocol by peeking at the first byte it sends, which must
+/// arrive before `deadline`.  Since plain HTTP requests such as `POST` also begin with the
+/// first byte of the HTTP/2 preface, any further bytes that have already arrived are
+/// compared with the preface, too.
+async fn detect_protocol<S: AsyncBufRead + AsyncWrite + Unpin>(
     socket: &mut S,
-) -> Result<(String, u16)> {
-    // try to read the head and get the host and port to connect to
-    let host;
-    let port;
+    deadline: tokio::time::Instant,
+    config: &Config,
+    record: &mut AccessRecord,
+) -> Result<Protocol> {
+    record.reason = Reason::ClientError;
+    let (first, preface) = match timeout_at(deadline, socket.fill_buf()).await {
+        Ok(buf) => {
+            let buf = buf.io_context("reading request from client")?;
+            let n = buf.len().min(http2::PREFACE.len());
+            (buf.first().copied(), buf[..n] == http2::PREFACE[..n])
+        }
+        Err(_) => return head_timed_out(socket, record).await,
+    };
+    let protocol = match first {
+        None => {
+            return Err(ProxyError::Closed(
+                "client hung up before sending a request".into(),
+            ))
+        }
+        Some(socks::VERSION) if config.socks.enabled => Protocol::Socks5,
+        Some(_) if preface && config.http2.enabled => Protocol::Http2,
+        Some(_) => Protocol::Http,
+    };
+    record.protocol = Some(protocol);
+    Ok(protocol)
+}
 
-    let mut buf = [0u8; MAX_HEAD_SIZE];
+/// Read and parse the request head, without any time limit, returning the request with any
+/// data read after it.  Heads larger than `limits.max_head_size`, with more than
+/// `limits.max_headers` headers, or with lines longer than `limits.max_header_line` are
+/// rejected with 431.  If `websocket.enabled` is set, WebSocket upgrade requests for its
+/// path are tunnel requests, too.  Requests with other methods are forwarded if
+/// `forward.enabled` is set, and otherwise rejected with 405.
+async fn read_head<S: AsyncRead + AsyncWrite + Unpin>(
+    socket: &mut S,
+    config: &Config,
+    record: &mut AccessRecord,
+) -> Result<Request> {
+    let limits = &config.limits;
+    let options = ParseOptions::from(limits);
+    let mut buf = vec![0u8; limits.max_head_size];
     let mut buf_size = 0;
-    loop {
+    let (protocol, host, port, version, headers, forward, len) = loop {
+        if buf_size == buf.len() {
+            record.reason = Reason::BadRequest;
+            let response = Response::error(
+                431,
+                "Request Header Fields Too Large",
+                "request head too large",
+            );
+            let _ = send_response(socket, response).await;
+            return Err(ProxyError::Parse(format!(
+                "request head exceeds {} bytes",
+                limits.max_head_size
+            )));
+        }
+
+        record.reason = Reason::ClientError;
         let n = socket
             .read(&mut buf[buf_size..])
             .await
-            .context("reading head from client")?;
+            .io_context("reading head from client")?;
         if n == 0 {
-            bail!("client hung up while writing HTTP head");
+            return Err(ProxyError::Closed(
+                "client hung up while writing HTTP head".into(),
+            ));
         }
         buf_size += n;
 
-        match parse_head(&buf[..buf_size]) {
-            ParseHeadResult::Connect { host: h, port: p } => {
-                host = h;
-                port = p;
-                break;
+        match parse_head(&buf[..buf_size], &options) {
+            ParseHeadResult::Connect {
+                host,
+                port,
+                version,
+                headers,
+                len,
+            } => break (Protocol::Http, host, port, version, headers, None, len),
+            ParseHeadResult::OtherMethod { len, .. }
+                if config.websocket.enabled
+                    && websocket::is_tunnel_request(&buf[..len], &options, &config.websocket) =>
+            {
+                match websocket::parse_upgrade(&buf[..len], &options, &config.websocket) {
+                    Ok((host, port, version, headers)) => {
+                        break (Protocol::WebSocket, host, port, version, headers, None, len)
+                    }
+                    Err(e) => {
+                        record.reason = Reason::BadRequest;
+                        let response =
+                            Response::error(400, "Bad Request", "invalid WebSocket request");
+                        let _ = send_response(socket, response).await;
+                        return Err(e);
+                    }
+                }
+            }
+            ParseHeadResult::OtherMethod { len, .. } if config.forward.enabled => {
+                let forward = parse_absolute(&buf[..len], &options).and_then(|request| {
+                    let version = request.version.clone();
+                    Forward::new(request)
+                        .map(|(host, port, forward)| (host, port, version, forward))
+                        .map_err(|e| ProxyError::Parse(format!("{:#}", e)))
+                });
+                match forward {
+                    Ok((host, port, version, forward)) => {
+                        let headers = forward.headers.clone();
+                        break (
+                            Protocol::Forward,
+                            host,
+                            port,
+                            version,
+                            headers,
+                            Some(forward),
+                            len,
+                        );
+                    }
+                    Err(e) => {
+                        METRICS.parse_failures.inc();
+                        record.reason = Reason::BadRequest;
+                        let response = Response::error(400, "Bad Request", "invalid request");
+                        let _ = send_response(socket, response).await;
+                        return Err(e);
+                    }
+                }
+            }
+            ParseHeadResult::OtherMethod { method, .. } => {
+                record.reason = Reason::BadRequest;
+                let response = Response::error(
+                    405,
+                    "Method Not Allowed",
+                    "only CONNECT requests are supported",
+                )
+                .header("Allow", "CONNECT");
+                let _ = send_response(socket, response).await;
+                return Err(ProxyError::Parse(format!("unsupported method {}", method)));
+            }
+            ParseHeadResult::TooLarge(limit) => {
+                record.reason = Reason::BadRequest;
+                let response = Response::error(431, "Request Header Fields Too Large", limit);
+                let _ = send_response(socket, response).await;
+                return Err(ProxyError::Parse(format!(
+                    "request head rejected: {}",
+                    limit
+                )));
+            }
+            ParseHeadResult::Err(e) => {
+                METRICS.parse_failures.inc();
+                record.reason = Reason::BadRequest;
+                let response = Response::error(400, "Bad Request", "invalid CONNECT request");
+                let _ = send_response(socket, response).await;
+                return Err(e);
             }
-            ParseHeadResult::Err(e) => return Err(e.context("reading head from client")),
             ParseHeadResult::Incomplete => (), // loop again..
         }
+    };
+
+    Ok(Request {
+        protocol,
+        host,
+        port,
+        version,
+        headers,
+        extra: buf[len..buf_size].to_vec(),
+        forward,
+    })
+}
+
+/// A direction of data transfer through a tunnel
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Direction {
+    /// From the client to the backend
+    Up,
+    /// From the backend to the client
+    Down,
+}
+
+/// Tracks the bytes transferred through a tunnel and the time of the most recent transfer
+/// in either direction, shared between the copies in each direction.  The bytes are
+/// charged against the client's quota as they are transferred.
+pub(crate) struct TunnelState {
+    start: Instant,
+    /// Milliseconds from `start` to the most recent activity
+    last: AtomicU64,
+    bytes_up: AtomicU64,
+    bytes_down: AtomicU64,
+    /// The tunnel's debugging tap, if it is tapped
+    tap: Option<Tap>,
+    /// The tunnel's packet capture, if it is captured
+    capture: Option<capture::Flow>,
+    /// The meter for the client's quota, if it is limited
+    quota: Option<quota::Meter>,
+    /// Notified when the client has used its quota
+    over_quota: Notify,
+}
+
+impl TunnelState {
+    pub(crate) fn new(
+        tap: Option<Tap>,
+        capture: Option<capture::Flow>,
+        quota: Option<quota::Meter>,
+    ) -> Self {
+        Self {
+            start: Instant::now(),
+            last: AtomicU64::new(0),
+            bytes_up: AtomicU64::new(0),
+            bytes_down: AtomicU64::new(0),
+            tap,
+            capture,
+            quota,
+            over_quota: Notify::new(),
+        }
+    }
+
+    /// Record activity now
+    pub(crate) fn touch(&self) {
+        let now = self.start.elapsed().as_millis() as u64;
+        self.last.fetch_max(now, Ordering::Relaxed);
+    }
+
+    /// Record that `n` bytes were transferred in the given direction
+    pub(crate) fn transferred(&self, direction: Direction, n: u64) {
+        let (local, global) = match direction {
+            Direction::Up => (&self.bytes_up, &METRICS.bytes_up),
+            Direction::Down => (&self.bytes_down, &METRICS.bytes_down),
+        };
+        local.fetch_add(n, Ordering::Relaxed);
+        global.add(n);
+        if let Some(quota) = &self.quota {
+            if !quota.charge(n) {
+                self.over_quota.notify_one();
+            }
+        }
     }
 
-    log::debug!("got CONNECT for {}:{}", host, port);
+    /// Copy data transferred in the given direction to the tunnel's tap and packet capture,
+    /// if it has them
+    pub(crate) fn tap(&self, direction: Direction, data: &[u8]) {
+        if let Some(tap) = &self.tap {
+            tap.copy(direction, data);
+        }
+        if let Some(capture) = &self.capture {
+            capture.copy(direction, data);
+        }
+    }
+
+    /// Whether the tunnel is tapped or captured, so its data must pass through userspace
+    pub(crate) fn tapped(&self) -> bool {
+        self.tap.is_some() || self.capture.is_some()
+    }
+
+    /// The bytes transferred so far, up and down
+    pub(crate) fn bytes(&self) -> (u64, u64) {
+        (
+            self.bytes_up.load(Ordering::Relaxed),
+            self.bytes_down.load(Ordering::Relaxed),
+        )
+    }
 
-    // write the response, with no headers..
-    socket.write_all(b"HTTP/1.1 200 OK\r\n\r\n").await?;
+    /// How long since the most recent activity?
+    fn idle_for(&self) -> Duration {
+        self.start.elapsed() - Duration::from_millis(self.last.load(Ordering::Relaxed))
+    }
+}
 
-    Ok((host, port))
+/// A summary of a finished connection or tunnel
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ConnectionSummary {
+    /// Bytes sent from the client to the backend
+    pub bytes_up: u64,
+    /// Bytes sent from the backend to the client
+    pub bytes_down: u64,
+    /// How long the connection or tunnel lasted
+    pub duration: Duration,
+    /// Why it ended; for a tunnel, one of `ClientClosed`, `BackendClosed`, `Idle`,
+    /// `Terminated`, or `Error`
+    pub reason: Reason,
 }
 
-/// Proxy data bidirectionally between client_socket and backend_socket.
-async fn bidirectional_proxy<CS, BS>(client_socket: CS, backend_socket: BS) -> Result<()>
-where
-    CS: AsyncRead + AsyncWrite + Unpin + Send + 'static,
-    BS: AsyncRead + AsyncWrite + Unpin + Send + 'static,
-{
-    async fn copy<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
-        mut read: R,
-        read_name: &'static str,
-        mut write: W,
-        write_name: &'static str,
-    ) -> Result<()> {
-        let mut buf = [0u8; 1024];
-        loop {
-            let n = read
-                .read(&mut buf)
-                .await
-                .with_context(|| format!("reading from {}", read_name))?;
-            if n == 0 {
-                // read socket is closed; we must shut down the write half
-                // explicitly (simply dropping it is not enough, as its split
-                // half is still running).  We ignore an error here since an
-                // error suggests the write side is already shut (e.g., if this
-                // socket is completely closed)
-                let _ = write.shutdown().await;
-                return Ok(());
-            }
-
-            // Write the data back
-            write
-                .write_all(&buf[0..n])
-                .await
-                .with_context(|| format!("writing to {}", write_name))?;
+impl ConnectionSummary {
+    /// Summarize a finished access record
+    pub fn from_record(record: &AccessRecord) -> Self {
+        Self {
+            bytes_up: record.bytes_up,
+            bytes_down: record.bytes_down,
+            duration: Duration::from_millis(record.duration_ms),
+            reason: record.reason,
+        }
+    }
+
+    /// Whether a tunnel was established and then closed normally by either side
+    pub fn tunneled(&self) -> bool {
+        matches!(self.reason, Reason::ClientClosed | Reason::BackendClosed)
+    }
+
+    /// Record the outcome of a tunnel in an access record
+    pub(crate) fn record(&self, record: &mut AccessRecord) {
+        record.bytes_up = self.bytes_up;
+        record.bytes_down = self.bytes_down;
+        record.reason = self.reason;
+    }
+}
+
+impl std::fmt::Display for ConnectionSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} bytes up, {} bytes down in {:?} ({:?})",
+            self.bytes_up, self.bytes_down, self.duration, self.reason
+        )
+    }
+}
+
+/// Copy data from `read` to `write` through a buffer of `buffer_size` bytes until `read`
+/// is closed, then shut down `write`.
+async fn copy<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
+    mut read: R,
+    read_name: &'static str,
+    mut write: W,
+    write_name: &'static str,
+    buffer_size: usize,
+    state: &TunnelState,
+    direction: Direction,
+) -> Result<()> {
+    let mut buf = vec![0u8; buffer_size];
+    loop {
+        let n = read
+            .read(&mut buf)
+            .await
+            .with_io_context(|| format!("reading from {}", read_name))?;
+        if n == 0 {
+            // read socket is closed; we must shut down the write half explicitly (simply
+            // dropping it is not enough, as its split half is still in use).  We ignore an
+            // error here since an error suggests the write side is already shut (e.g., if
+            // this socket is completely closed)
+            let _ = write.shutdown().await;
+            return Ok(());
         }
+        state.touch();
+
+        write
+            .write_all(&buf[0..n])
+            .await
+            .with_io_context(|| format!("writing to {}", write_name))?;
+        state.transferred(direction, n as u64);
+        state.tap(direction, &buf[0..n]);
     }
+}
 
-    // split each socket into read and write halfs, then spawn tasks to
-    // copy data between them
+/// Proxy data bidirectionally between client_socket and backend_socket, closing both if
+/// there is no traffic in either direction for the idle timeout, or if the tunnel is
+/// terminated through the registry.  Both directions are copied concurrently within the
+/// calling task, and each continues after the other has finished, so half-closed
+/// connections work as expected.
+pub(crate) async fn bidirectional_proxy<CS, BS>(
+    client_socket: CS,
+    backend_socket: BS,
+    limits: &LimitsConfig,
+    tunnel: &Registration,
+) -> ConnectionSummary
+where
+    CS: AsyncRead + AsyncWrite + Unpin,
+    BS: AsyncRead + AsyncWrite + Unpin,
+{
     let (client_read, client_write) = split(client_socket);
     let (backend_read, backend_write) = split(backend_socket);
+    let state = &tunnel.state;
 
-    let copy_client_to_backend = tokio::spawn(async move {
-        if let Err(e) = copy(
-            client_read,
-            "client socket",
-            backend_write,
-            "backend socket",
-        )
-        .await
-        {
+    let copy_client_to_backend = copy(
+        client_read,
+        "client socket",
+        backend_write,
+        "backend socket",
+        limits.tunnel_buffer_size,
+        state,
+        Direction::Up,
+    );
+    let copy_backend_to_client = copy(
+        backend_read,
+        "backend socket",
+        client_write,
+        "client socket",
+        limits.tunnel_buffer_size,
+        state,
+        Direction::Down,
+    );
+    run_tunnel(
+        tunnel,
+        copy_client_to_backend,
+        copy_backend_to_client,
+        limits.idle_timeout,
+    )
+    .await
+}
+
+/// Run the copies for each direction of a tunnel until both have finished, until there
+/// has been no traffic in either direction for `idle_timeout`, or until the tunnel is
+/// terminated, returning the outcome.  A tunnel whose copies both finish was closed by
+/// whichever side closed first, unless either copy failed.
+pub(crate) async fn run_tunnel<U, D>(
+    tunnel: &Registration,
+    up: U,
+    down: D,
+    idle_timeout: Duration,
+) -> ConnectionSummary
+where
+    U: Future<Output = Result<()>>,
+    D: Future<Output = Result<()>>,
+{
+    // each copy finishes with whether it succeeded and the order in which it finished
+    let finished = AtomicU64::new(0);
+    let outcome = |result: Result<()>| {
+        if let Err(e) = &result {
             log::warn!("while proxying: {}", e);
         }
-    });
+        (result.is_ok(), finished.fetch_add(1, Ordering::Relaxed))
+    };
+    let up = async { outcome(up.await) };
+    let down = async { outcome(down.await) };
+    let copies = async {
+        let ((up_ok, up_order), (down_ok, down_order)) = tokio::join!(up, down);
+        match (up_ok && down_ok, up_order < down_order) {
+            (true, true) => Reason::ClientClosed,
+            (true, false) => Reason::BackendClosed,
+            (false, _) => Reason::Error,
+        }
+    };
+    supervise(tunnel, copies, idle_timeout).await
+}
 
-    let copy_backend_to_client = tokio::spawn(async move {
-        if let Err(e) = copy(
-            backend_read,
-            "backend socket",
-            client_write,
-            "client socket",
-        )
-        .await
-        {
-            log::warn!("while proxying: {}", e);
+/// Run `transfer`, which moves data through a tunnel and returns why it ended, until it
+/// finishes, until there has been no traffic in either direction for `idle_timeout`, until
+/// the client has used its quota, or until the tunnel is terminated, returning the
+/// outcome.
+pub(crate) async fn supervise<F>(
+    tunnel: &Registration,
+    transfer: F,
+    idle_timeout: Duration,
+) -> ConnectionSummary
+where
+    F: Future<Output = Reason>,
+{
+    let state = &tunnel.state;
+
+    // wait until the tunnel has been idle for idle_timeout
+    let idle = async {
+        loop {
+            let idle_for = state.idle_for();
+            if idle_for >= idle_timeout {
+                return idle_for;
+            }
+            sleep(idle_timeout - idle_for).await;
+        }
+    };
+
+    // wait for the transfer to finish, for the tunnel to go idle or over quota, or for it
+    // to be terminated; the caller drops the sockets afterward, closing them, in any case
+    let reason = tokio::select! {
+        reason = transfer => reason,
+        idle_for = idle => {
+            log::info!("tunnel idle for {:?}; closing", idle_for);
+            Reason::Idle
         }
-    });
+        _ = state.over_quota.notified() => {
+            log::info!("client has used its daily quota; closing tunnel");
+            Reason::QuotaExceeded
+        }
+        _ = tunnel.terminate.notified() => {
+            log::info!("tunnel terminated by administrator");
+            Reason::Terminated
+        }
+    };
 
-    // wait for those tasks to finish
-    let results = tokio::join!(copy_client_to_backend, copy_backend_to_client);
-    results.0?;
-    results.1?;
+    let (bytes_up, bytes_down) = state.bytes();
+    let summary = ConnectionSummary {
+        bytes_up,
+        bytes_down,
+        duration: state.start.elapsed(),
+        reason,
+    };
+    log::debug!("tunnel closed: {}", summary);
+    summary
+}
 
-    Ok(())
+/// Proxy data between the client and backend sockets after the request has been handled.
+/// On Linux, if both are plain TCP sockets and no client data is buffered, this uses the
+/// zero-copy `splice` path, unless the tunnel is tapped or captured; otherwise data is
+/// copied through userspace buffers.
+async fn tunnel<S, BS>(
+    socket: BufReader<S>,
+    backend_socket: BS,
+    limits: &LimitsConfig,
+    tunnel: &Registration,
+) -> ConnectionSummary
+where
+    S: AsyncRead + AsyncWrite + Unpin + 'static,
+    BS: AsyncRead + AsyncWrite + Unpin + 'static,
+{
+    #[cfg(target_os = "linux")]
+    if socket.buffer().is_empty() && !tunnel.state.tapped() {
+        use std::any::Any;
+        use tokio::net::TcpStream;
+
+        let client = (socket.get_ref() as &dyn Any).downcast_ref::<TcpStream>();
+        let backend = (&backend_socket as &dyn Any).downcast_ref::<TcpStream>();
+        if let (Some(client), Some(backend)) = (client, backend) {
+            return crate::splice::proxy(client, backend, limits, tunnel).await;
+        }
+    }
+    bidirectional_proxy(socket, backend_socket, limits, tunnel).await
 }
 
 /// Handle a single client connection until it ends.  This is implemented in terms of
-/// AsyncRead and AsyncWrite, so it has no access to metadata such as the client's IP.
-pub async fn connection<S: AsyncRead + AsyncWrite + Unpin + Send + 'static, B: Backend>(
+/// AsyncRead and AsyncWrite, so details such as the client's address are supplied by the
+/// caller in `info`.
+///
+/// If `htpasswd` is given, clients must authenticate as one of its users.  If `mitm` is
+/// given, CONNECT tunnels to the destinations it selects are intercepted.  When the
+/// connection ends, a record of it is written to the access log, any error is logged, and
+/// a summary is returned.
+pub async fn connection<
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    B: Backend + 'static,
+>(
+    socket: S,
+    backend: B,
+    config: Arc<Config>,
+    htpasswd: Option<Arc<Htpasswd>>,
+    mitm: Option<Arc<Mitm>>,
+    info: ConnectionInfo,
+) -> ConnectionSummary {
+    let mut record = AccessRecord::new(info.peer);
+    record.client_cert = info.client_cert.clone();
+    log::debug!(
+        "connection {}: handling connection from {}",
+        record.id,
+        info
+    );
+
+    let span = record.span();
+    let abort_on_panic = config.abort_on_panic;
+    let result = panics::catch_unwind(handle_connection(
+        socket,
+        backend,
+        config,
+        htpasswd,
+        mitm,
+        &info,
+        &mut record,
+    ))
+    .instrument(span.clone())
+    .await
+    .unwrap_or_else(|payload| Err(panics::report(payload, &mut record, abort_on_panic)));
+
+    if let Err(e) = result {
+        log::error!("connection from {} failed: {:#}", info, e);
+    }
+
+    record.finish();
+    record.record_span(&span);
+    record.log();
+    ConnectionSummary::from_record(&record)
+}
+
+/// Implementation of `connection`, filling in the access record as it goes
+async fn handle_connection<
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    B: Backend + 'static,
+>(
     socket: S,
     backend: B,
+    config: Arc<Config>,
+    htpasswd: Option<Arc<Htpasswd>>,
+    mitm: Option<Arc<Mitm>>,
+    info: &ConnectionInfo,
+    record: &mut AccessRecord,
 ) -> Result<()> {
-    log::info!("Handling connection");
+    // wrap the socket in a buffer so we don't read a byte at a time from the input; writes
+    // pass straight through
+    let mut socket = BufReader::with_capacity(8192, socket);
+
+    let request = match info.original_dst {
+        // a redirected connection has no request; its tunnel is to the original destination
+        Some(dst) => transparent_request(dst, record),
+        None => {
+            // HTTP/2 connections carry any number of requests, each handled separately
+            let deadline = tokio::time::Instant::now() + config.limits.head_timeout;
+            let read_head = tracing::info_span!("read_head");
+            let protocol = detect_protocol(&mut socket, deadline, &config, record)
+                .instrument(read_head.clone())
+                .await?;
+            if protocol == Protocol::Http2 {
+                record.reason = Reason::ClientClosed;
+                return http2::serve(socket, Arc::new(backend), config, htpasswd, info.clone())
+                    .await
+                    .map_err(ProxyError::from);
+            }
+
+            // read the request
+            handle_connect(
+                &mut socket,
+                protocol,
+                deadline,
+                &config,
+                htpasswd.as_deref(),
+                record,
+            )
+            .instrument(read_head)
+            .await?
+        }
+    };
+    let htpasswd = htpasswd.as_deref();
+    record.target = Some(authority(&request.host, request.port));
+    record.user_agent = request.headers.get("User-Agent").map(str::to_owned);
+
+    // SOCKS5 clients authenticate during the handshake; HTTP clients must send credentials
+    // with the request
+    if let (Protocol::Http | Protocol::Forward | Protocol::WebSocket, Some(htpasswd)) =
+        (request.protocol, htpasswd)
+    {
+        check_proxy_authorization(&mut socket, &request, htpasswd, &config.auth.realm, record)
+            .await?;
+    }
+    let Request {
+        protocol,
+        host,
+        port,
+        version,
+        headers,
+        extra,
+        forward,
+    } = request;
+
+    // clients that have used their daily quota get no more tunnels today
+    if let Err(e) = quota::check(record) {
+        record.reason = Reason::QuotaExceeded;
+        let _ = match protocol {
+            Protocol::Http | Protocol::Forward | Protocol::WebSocket => {
+                let response = Response::error(429, "Too Many Requests", "daily quota exceeded")
+                    .version(version);
+                send_response(&mut socket, response).await
+            }
+            Protocol::Socks5 | Protocol::SocksUdp => {
+                socks::send_reply(&mut socket, Reply::NotAllowed)
+                    .await
+                    .map_err(ProxyError::from)
+            }
+            // a redirected client is not talking to the proxy, so it is just disconnected
+            Protocol::Transparent => Ok(()),
+            Protocol::Http2 | Protocol::Http3 | Protocol::Reverse => unreachable!(),
+        };
+        return Err(e);
+    }
+
+    // a UDP association relays datagrams to any number of destinations, rather than
+    // tunneling to one
+    if protocol == Protocol::SocksUdp {
+        record.target = None;
+        let udp = match &config.socks.udp {
+            Some(udp) => udp,
+            None => unreachable!("UDP ASSOCIATE is refused unless enabled"),
+        };
+        return udp::associate(socket, port, udp, &config.backend, info, record).await;
+    }
+
+    // connect to the backend, and tell the client how that went
+    let connect = backend
+        .connect_for(info, &host, port)
+        .instrument(tracing::info_span!("backend_connect"));
+    let backend_socket = match connect.await {
+        Ok(s) => s,
+        Err(e) => {
+            let disallowed = record_backend_error(&e, info, record);
+            let _ = match (protocol, disallowed) {
+                (Protocol::Http | Protocol::Forward | Protocol::WebSocket, true) => {
+                    let response = Response::error(403, "Forbidden", "destination not allowed")
+                        .version(version);
+                    send_response(&mut socket, response).await
+                }
+                (Protocol::Http | Protocol::Forward | Protocol::WebSocket, false) => {
+                    let response = Response::error(
+                        502,
+                        "Bad Gateway",
+                        format!("could not connect to {}", authority(&host, port)),
+                    )
+                    .version(version);
+                    send_response(&mut socket, response).await
+                }
+                (Protocol::Socks5, disallowed) => {
+                    let reply = if disallowed {
+                        Reply::NotAllowed
+                    } else {
+                        Reply::HostUnreachable
+                    };
+                    socks::send_reply(&mut socket, reply)
+                        .await
+                        .map_err(ProxyError::from)
+                }
+                (Protocol::Transparent, _) => Ok(()),
+                (Protocol::Http2 | Protocol::Http3 | Protocol::Reverse | Protocol::SocksUdp, _) => {
+                    unreachable!()
+                }
+            };
+            return Err(e);
+        }
+    };
+    record.served_by = info.served_by.take();
+    record.reason = Reason::Error;
+    let _active = ActiveTunnel::new();
+    let registration = REGISTRY.register(record);
+
+    // a forwarded request gets the backend's response rather than a response of its own
+    if let Some(mut forward) = forward {
+        forward.identify(&config.identity);
+        let summary = forward::forward(
+            socket,
+            extra,
+            backend_socket,
+            &forward,
+            &Hooks::default(),
+            &config.limits,
+            &registration,
+        )
+        .instrument(tracing::info_span!("tunnel"))
+        .await;
+        summary.record(record);
+        return Ok(());
+    }
+
+    match protocol {
+        Protocol::Http => {
+            let response = connect_response(&version, &config.identity);
+            send_response(&mut socket, response).await?
+        }
+        Protocol::Socks5 => socks::send_reply(&mut socket, Reply::Succeeded).await?,
+        Protocol::WebSocket => {
+            let response = websocket::switching_protocols(&headers);
+            send_response(&mut socket, response).await?
+        }
+        Protocol::Transparent => {}
+        Protocol::Http2
+        | Protocol::Http3
+        | Protocol::Forward
+        | Protocol::Reverse
+        | Protocol::SocksUdp => {
+            unreachable!()
+        }
+    }
+
+    let tunnel = Tunnel {
+        host: &host,
+        port,
+        config: &config,
+        info,
+        registration: &registration,
+    };
+    // a WebSocket tunnel's data arrives in frames, which are translated through an
+    // in-memory pipe, so that the tunnel is checked and copied as usual
+    if protocol == Protocol::WebSocket {
+        let (frames, pipe) = duplex(websocket::BUFFER);
+        spawn(
+            "websocket-frames",
+            websocket::relay(Prefixed::new(extra, socket), frames),
+        );
+        let pipe = BufReader::new(pipe);
+        return tunnel.run(pipe, vec![], backend_socket, mitm, record).await;
+    }
+    tunnel
+        .run(socket, extra, backend_socket, mitm, record)
+        .await
+}
+
+/// A tunnel whose backend connection has been made and acknowledged to the client
+struct Tunnel<'a> {
+    host: &'a str,
+    port: u16,
+    config: &'a Config,
+    info: &'a ConnectionInfo,
+    registration: &'a Registration,
+}
 
-    // wrap the socket in a bufer so we don't read a byte at a time from the input, but
-    // setting writer_capacity to 0 to get immediate writes
-    let mut socket = BufStream::with_capacity(8192, 0, socket);
+impl Tunnel<'_> {
+    /// Check what the client sends through the tunnel, if required, and then copy data
+    /// between the client and the backend, or intercept the tunnel, until either closes.
+    /// `extra` is anything the client sent after its request.
+    async fn run<S, BS>(
+        self,
+        mut socket: BufReader<S>,
+        mut extra: Vec<u8>,
+        mut backend_socket: BS,
+        mitm: Option<Arc<Mitm>>,
+        record: &mut AccessRecord,
+    ) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+        BS: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let Tunnel {
+            host,
+            port,
+            config,
+            info,
+            registration,
+        } = self;
+        // the tunnel must begin with a ClientHello for the requested host, or on some
+        // listen addresses at least with a TLS handshake, which is sent on to the backend
+        // once checked
+        if config.backend.verify_sni {
+            let limit = config.limits.head_timeout;
+            match client_hello::read(&mut socket, &mut extra, limit).await {
+                Ok(name) if client_hello::matches(name.as_deref(), host) => (),
+                Ok(name) => {
+                    record.reason = Reason::SniMismatch;
+                    return Err(ProxyError::Policy(format!(
+                        "tunnel to {} began with a ClientHello for {:?}",
+                        host, name
+                    )));
+                }
+                Err(e) => {
+                    record.reason = Reason::SniMismatch;
+                    return Err(ProxyError::Policy(format!("tunnel to {}: {:#}", host, e)));
+                }
+            }
+        } else if info
+            .local
+            .is_some_and(|local| config.limits.require_tls.contains(&local))
+        {
+            let limit = config.limits.head_timeout;
+            if let Err(e) = client_hello::read_handshake_start(&mut socket, &mut extra, limit).await
+            {
+                record.reason = Reason::NotTls;
+                return Err(ProxyError::Policy(format!(
+                    "tunnel to {} does not carry TLS: {:#}",
+                    host, e
+                )));
+            }
+        }
+
+        // fingerprinting is only for information, so a tunnel which does not begin with a
+        // ClientHello carries on without one
+        if config.log.tls_fingerprints {
+            let limit = config.limits.head_timeout;
+            let fingerprint = client_hello::read(&mut socket, &mut extra, limit)
+                .await
+                .and_then(|_| fingerprint::fingerprint(&extra));
+            match fingerprint {
+                Ok(Fingerprint { ja3, ja4 }) => {
+                    log::debug!("{} ClientHello has JA3 {} and JA4 {}", info, ja3, ja4);
+                    record.ja3 = Some(ja3);
+                    record.ja4 = Some(ja4);
+                }
+                Err(e) => log::debug!("{}: no TLS fingerprint: {:#}", info, e),
+            }
+        }
 
-    // read the HTTP request head and write the response
-    let (host, port) = handle_connect(&mut socket).await?;
+        // an intercepted tunnel carries a TLS session which the proxy terminates itself,
+        // beginning with anything the client sent after its request
+        if let Some(mitm) = mitm.filter(|mitm| mitm.intercepts(host, port)) {
+            let socket = Prefixed::new(extra, socket);
+            let summary = mitm
+                .intercept(socket, backend_socket, host, port, record, registration)
+                .instrument(tracing::info_span!("tunnel"))
+                .await?;
+            summary.record(record);
+            return Ok(());
+        }
 
-    // connect to the backend
-    let backend_socket = backend.connect(&host, port).await?;
+        // forward anything the client sent after its request, then copy data between the
+        // backend and frontend
+        if !extra.is_empty() {
+            backend_socket
+                .write_all(&extra)
+                .await
+                .io_context("writing to backend socket")?;
+            registration
+                .state
+                .transferred(Direction::Up, extra.len() as u64);
+            registration.state.tap(Direction::Up, &extra);
+        }
+        let tunnel = tunnel(socket, backend_socket, &config.limits, registration)
+            .instrument(tracing::info_span!("tunnel"))
+            .await;
+        tunnel.record(record);
+        Ok(())
+    }
+}
 
-    // copy data between the backend and frontend
-    Ok(bidirectional_proxy(socket, backend_socket).await?)
+/// Record why connecting to the backend failed, logging disallowed destinations.
+/// Returns true if the destination was disallowed.
+pub(crate) fn record_backend_error(
+    e: &ProxyError,
+    info: &ConnectionInfo,
+    record: &mut AccessRecord,
+) -> bool {
+    if let ProxyError::Disallowed(d) = e {
+        log::warn!(
+            "{} requested disallowed destination {}",
+            info,
+            authority(&d.host, d.port)
+        );
+        record.reason = Reason::Disallowed;
+        true
+    } else {
+        record.reason = Reason::BackendError;
+        false
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::backend::Disallowed;
+    use crate::config::QuotaConfig;
+    use crate::quota::Quotas;
+    use crate::testing::EchoBackend;
+    use rustls::pki_types::ServerName;
+    use std::collections::{BTreeMap, HashMap};
+    use std::convert::TryFrom;
     use tokio::io::{duplex, split, DuplexStream};
 
-    /// An echo backend for testing
-    pub struct EchoBackend;
+    /// A backend that fails to connect: with `Disallowed` for host `forbidden`, and with
+    /// some other error for any other host
+    pub struct FailingBackend;
 
     #[async_trait::async_trait]
-    impl Backend for EchoBackend {
+    impl Backend for FailingBackend {
         type Socket = DuplexStream;
-        async fn connect(&self, _host: &str, _port: u16) -> Result<Self::Socket> {
-            let (client, mut server) = tokio::io::duplex(1024);
+        async fn connect(&self, host: &str, port: u16) -> Result<Self::Socket> {
+            if host == "forbidden" {
+                Err(Disallowed {
+                    host: host.into(),
+                    port,
+                }
+                .into())
+            } else {
+                Err(ProxyError::upstream(
+                    authority(host, port),
+                    "connection refused",
+                ))
+            }
+        }
+    }
+
+    /// A backend whose connections answer a single HTTP request, ending in `ping`, with
+    /// the request they received
+    pub struct HttpBackend;
 
-            // spawn a task to echo data
+    #[async_trait::async_trait]
+    impl Backend for HttpBackend {
+        type Socket = DuplexStream;
+        async fn connect(&self, _host: &str, _port: u16) -> Result<Self::Socket> {
+            let (mut server, backend_socket) = duplex(1024);
             tokio::spawn(async move {
+                let mut request = vec![];
                 let mut buf = [0u8; 1024];
-                loop {
-                    log::trace!("echo reading");
-                    let n = match server.read(&mut buf).await {
-                        Ok(n) if n == 0 => {
-                            log::trace!("echo got EOF");
-                            return;
-                        }
-                        Ok(n) => n,
-                        Err(e) => {
-                            eprintln!("failed to read from socket; err = {:?}", e);
-                            return;
-                        }
-                    };
-                    log::trace!("echo read {} bytes", n);
-
-                    log::trace!("echo writing");
-                    if let Err(e) = server.write_all(&buf[0..n]).await {
-                        eprintln!("failed to write to socket; err = {:?}", e);
-                        return;
-                    }
-                    log::trace!("echo wrote {} bytes", n);
+                while !request.ends_with(b"ping") {
+                    let n = server.read(&mut buf).await.unwrap();
+                    assert_ne!(n, 0);
+                    request.extend_from_slice(&buf[..n]);
                 }
+                let head = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n",
+                    request.len()
+                );
+                server.write_all(head.as_bytes()).await.unwrap();
+                server.write_all(&request).await.unwrap();
             });
+            Ok(backend_socket)
+        }
+    }
+
+    /// Send `request` to a connection using the given backend, and return the full
+    /// response, asserting that the connection fails.
+    async fn error_response<B: Backend + 'static>(backend: B, request: &'static [u8]) -> String {
+        let (mut client, server) = duplex(1024);
+        let server_task = tokio::spawn(async move {
+            connection(
+                server,
+                backend,
+                Arc::new(Config::default()),
+                None,
+                None,
+                ConnectionInfo::default(),
+            )
+            .await
+        });
+        client.write_all(request).await.unwrap();
+        let mut buf = vec![];
+        client.read_to_end(&mut buf).await.unwrap();
+        assert!(!server_task.await.unwrap().tunneled());
+        String::from_utf8(buf).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_bad_request() {
+        let response = error_response(EchoBackend, b"CONNECT foo.com:https HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        assert!(response.ends_with("\r\n\r\ninvalid CONNECT request\n"));
+    }
+
+    #[test]
+    fn test_connection_info_display() {
+        let info = ConnectionInfo::tcp(
+            "10.0.0.1:5555".parse().unwrap(),
+            "127.0.0.1:8080".parse().unwrap(),
+        );
+        assert_eq!(info.to_string(), "10.0.0.1:5555");
+        assert_eq!(ConnectionInfo::default().to_string(), "unknown client");
+    }
+
+    #[tokio::test]
+    async fn test_access_record_disallowed() {
+        let (mut client, server) = duplex(1024);
+        let server_task = tokio::spawn(async move {
+            let mut record = AccessRecord::new(None);
+            let result = handle_connection(
+                server,
+                FailingBackend,
+                Arc::new(Config::default()),
+                None,
+                None,
+                &ConnectionInfo::default(),
+                &mut record,
+            )
+            .await;
+            (result, record)
+        });
+        client
+            .write_all(b"CONNECT forbidden:443 HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+        let mut buf = vec![];
+        client.read_to_end(&mut buf).await.unwrap();
+        let (result, record) = server_task.await.unwrap();
+        assert!(result.is_err());
+        assert_eq!(record.target.as_deref(), Some("forbidden:443"));
+        assert_eq!(record.reason, Reason::Disallowed);
+    }
+
+    /// Send `request` to a connection with the given config, asserting that it fails,
+    /// and return the full response
+    async fn limited_response(config: Config, request: &'static [u8]) -> Vec<u8> {
+        let (mut client, server) = duplex(1024);
+        let server_task = tokio::spawn(async move {
+            connection(
+                server,
+                EchoBackend,
+                Arc::new(config),
+                None,
+                None,
+                ConnectionInfo::default(),
+            )
+            .await
+        });
+        client.write_all(request).await.unwrap();
+        let mut buf = vec![];
+        client.read_to_end(&mut buf).await.unwrap();
+        assert!(!server_task.await.unwrap().tunneled());
+        buf
+    }
+
+    #[tokio::test]
+    async fn test_head_too_large() {
+        let mut config = Config::default();
+        config.limits.max_head_size = 40;
+
+        let buf = limited_response(
+            config,
+            b"CONNECT foo.com:443 HTTP/1.1\r\nUser-Agent: something-long\r\n\r\n",
+        )
+        .await;
+        assert!(buf.starts_with(b"HTTP/1.1 431 Request Header Fields Too Large\r\n"));
+        assert!(buf.ends_with(b"request head too large\n"));
+    }
+
+    #[tokio::test]
+    async fn test_other_method() {
+        let response = error_response(
+            EchoBackend,
+            b"GET http://foo.com/ HTTP/1.1\r\nHost: foo.com\r\n\r\n",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+        assert!(response.contains("\r\nAllow: CONNECT\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_forward() {
+        let mut config = Config::default();
+        config.forward.enabled = true;
+        let (mut client, server) = duplex(1024);
+        let server_task = tokio::spawn(connection(
+            server,
+            HttpBackend,
+            Arc::new(config),
+            None,
+            None,
+            ConnectionInfo::default(),
+        ));
+        client
+            .write_all(b"POST http://foo.com/gifs HTTP/1.1\r\nContent-Length: 4\r\n\r\nping")
+            .await
+            .unwrap();
+        let mut buf = vec![];
+        client.read_to_end(&mut buf).await.unwrap();
+
+        let expected = "POST /gifs HTTP/1.1\r\nHost: foo.com\r\nContent-Length: 4\r\n\
+                        Connection: close\r\n\r\nping";
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                expected.len(),
+                expected
+            )
+        );
+        let summary = server_task.await.unwrap();
+        assert_eq!(summary.reason, Reason::BackendClosed);
+        assert_eq!(summary.bytes_up, expected.len() as u64);
+    }
+
+    #[tokio::test]
+    async fn test_forward_disallowed() {
+        let mut config = Config::default();
+        config.forward.enabled = true;
+        let (mut client, server) = duplex(1024);
+        let server_task = tokio::spawn(connection(
+            server,
+            FailingBackend,
+            Arc::new(config),
+            None,
+            None,
+            ConnectionInfo::default(),
+        ));
+        client
+            .write_all(b"GET http://forbidden/ HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+        let mut buf = vec![];
+        client.read_to_end(&mut buf).await.unwrap();
+        assert!(buf.starts_with(b"HTTP/1.1 403 Forbidden\r\n"));
+        assert_eq!(server_task.await.unwrap().reason, Reason::Disallowed);
+    }
+
+    #[tokio::test]
+    async fn test_forward_host_mismatch() {
+        let mut config = Config::default();
+        config.forward.enabled = true;
+        let (mut client, server) = duplex(1024);
+        let server_task = tokio::spawn(connection(
+            server,
+            HttpBackend,
+            Arc::new(config),
+            None,
+            None,
+            ConnectionInfo::default(),
+        ));
+        client
+            .write_all(b"GET http://foo.com/ HTTP/1.1\r\nHost: internal.corp\r\n\r\n")
+            .await
+            .unwrap();
+        let mut buf = vec![];
+        client.read_to_end(&mut buf).await.unwrap();
+        assert!(buf.starts_with(b"HTTP/1.1 400 Bad Request\r\n"));
+        assert_eq!(server_task.await.unwrap().reason, Reason::BadRequest);
+    }
+
+    #[tokio::test]
+    async fn test_head_at_limit() {
+        const REQUEST: &[u8] = b"CONNECT foo.com:443 HTTP/1.1\r\n\r\n";
+        let mut config = Config::default();
+        config.limits.max_head_size = REQUEST.len();
+
+        // a head filling the buffer exactly is accepted..
+        let (mut client, server) = duplex(1024);
+        let server_task = tokio::spawn(connection(
+            server,
+            EchoBackend,
+            Arc::new(config.clone()),
+            None,
+            None,
+            ConnectionInfo::default(),
+        ));
+        client.write_all(REQUEST).await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut buf = vec![];
+        client.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"HTTP/1.1 200 OK\r\n\r\n");
+        assert!(server_task.await.unwrap().tunneled());
+
+        // ..but one more byte is too many
+        config.limits.max_head_size -= 1;
+        let buf = limited_response(config, REQUEST).await;
+        assert!(buf.starts_with(b"HTTP/1.1 431 Request Header Fields Too Large\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_too_many_headers() {
+        let mut config = Config::default();
+        config.limits.max_headers = 1;
+
+        let buf = limited_response(
+            config,
+            b"CONNECT foo.com:443 HTTP/1.1\r\nA: 1\r\nB: 2\r\n\r\n",
+        )
+        .await;
+        assert!(buf.starts_with(b"HTTP/1.1 431 Request Header Fields Too Large\r\n"));
+        assert!(buf.ends_with(b"too many headers\n"));
+    }
 
-            return Ok(client);
+    #[tokio::test]
+    async fn test_head_timeout() {
+        let mut config = Config::default();
+        config.limits.head_timeout = std::time::Duration::from_millis(50);
+
+        let (mut client, server) = duplex(1024);
+        let server_task = tokio::spawn(async move {
+            connection(
+                server,
+                EchoBackend,
+                Arc::new(config),
+                None,
+                None,
+                ConnectionInfo::default(),
+            )
+            .await
+        });
+
+        // send a partial head and then stall
+        client.write_all(b"CONNECT foo.c").await.unwrap();
+        let mut buf = vec![];
+        client.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(server_task.await.unwrap().reason, Reason::HeadTimeout);
+        assert!(buf.starts_with(b"HTTP/1.1 408 Request Timeout\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_bidirectional_proxy() {
+        let limits = LimitsConfig {
+            tunnel_buffer_size: 7,
+            ..LimitsConfig::default()
+        };
+        let (mut client, client_end) = duplex(64);
+        let (backend_end, mut backend) = duplex(64);
+        let proxy = tokio::spawn(async move {
+            let registration = REGISTRY.register(&AccessRecord::new(None));
+            bidirectional_proxy(client_end, backend_end, &limits, &registration).await
+        });
+
+        // the client sends a large request and closes its end, after which the backend
+        // can still respond
+        let request: Vec<u8> = (0..10000u32).map(|i| i as u8).collect();
+        let expected = request.clone();
+        let client_task = tokio::spawn(async move {
+            client.write_all(&request).await.unwrap();
+            client.shutdown().await.unwrap();
+            let mut buf = vec![];
+            client.read_to_end(&mut buf).await.unwrap();
+            buf
+        });
+        let mut buf = vec![];
+        backend.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, expected);
+        backend.write_all(b"response").await.unwrap();
+        drop(backend);
+
+        assert_eq!(client_task.await.unwrap(), b"response");
+        let tunnel = proxy.await.unwrap();
+        assert_eq!(tunnel.bytes_up, 10000);
+        assert_eq!(tunnel.bytes_down, 8);
+        assert_eq!(tunnel.reason, Reason::ClientClosed);
+    }
+
+    #[tokio::test]
+    async fn test_backend_closed() {
+        let (mut client, client_end) = duplex(64);
+        let (backend_end, mut backend) = duplex(64);
+        let proxy = tokio::spawn(async move {
+            let registration = REGISTRY.register(&AccessRecord::new(None));
+            let limits = LimitsConfig::default();
+            bidirectional_proxy(client_end, backend_end, &limits, &registration).await
+        });
+
+        // the backend responds and hangs up before the client closes its end
+        backend.write_all(b"bye").await.unwrap();
+        drop(backend);
+        let mut buf = vec![];
+        client.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"bye");
+        drop(client);
+
+        let tunnel = proxy.await.unwrap();
+        assert_eq!(tunnel.reason, Reason::BackendClosed);
+        assert_eq!((tunnel.bytes_up, tunnel.bytes_down), (0, 3));
+    }
+
+    #[tokio::test]
+    async fn test_terminate_tunnel() {
+        let (_client, client_end) = duplex(64);
+        let (backend_end, _backend) = duplex(64);
+        let record = AccessRecord::new(None);
+        let registration = REGISTRY.register(&record);
+        let limits = LimitsConfig::default();
+        let proxy = bidirectional_proxy(client_end, backend_end, &limits, &registration);
+        assert!(REGISTRY.terminate(record.id));
+        assert_eq!(proxy.await.reason, Reason::Terminated);
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeout() {
+        let _ = env_logger::builder().is_test(true).try_init();
+
+        let mut config = Config::default();
+        config.limits.idle_timeout = std::time::Duration::from_millis(100);
+
+        let (mut client, server) = duplex(1024);
+        let server_task = tokio::spawn(async move {
+            connection(
+                server,
+                EchoBackend,
+                Arc::new(config),
+                None,
+                None,
+                ConnectionInfo::default(),
+            )
+            .await
+        });
+
+        client
+            .write_all(b"CONNECT foo.com:1234 HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+
+        // some traffic keeps the tunnel open..
+        for _ in 0..3 {
+            sleep(Duration::from_millis(50)).await;
+            client.write_all(b"ping").await.unwrap();
         }
+
+        // ..but then the server should close the connection when it goes idle
+        let mut buf = vec![];
+        client.read_to_end(&mut buf).await.unwrap();
+        let summary = server_task.await.unwrap();
+        assert_eq!(summary.reason, Reason::Idle);
+        assert_eq!(summary.bytes_up, 12);
+        assert_eq!(&buf, b"HTTP/1.1 200 OK\r\n\r\npingpingping");
+    }
+
+    /// Begin a tunnel to `api.giphy.com:443` with a ClientHello for `server_name`,
+    /// returning what the client receives and the access record
+    async fn tls_tunnel(server_name: &str, config: Config) -> (Vec<u8>, AccessRecord) {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let client_config = rustls::ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(rustls::RootCertStore::empty())
+            .with_no_client_auth();
+        let name = ServerName::try_from(server_name.to_owned()).unwrap();
+        let mut tls = rustls::ClientConnection::new(Arc::new(client_config), name).unwrap();
+        let mut hello = vec![];
+        tls.write_tls(&mut hello).unwrap();
+
+        let (mut client, server) = duplex(4096);
+        let server_task = tokio::spawn(async move {
+            let mut record = AccessRecord::new(None);
+            let info = ConnectionInfo::default();
+            let config = Arc::new(config);
+            let _ = handle_connection(server, EchoBackend, config, None, None, &info, &mut record)
+                .await;
+            record
+        });
+        client
+            .write_all(b"CONNECT api.giphy.com:443 HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+        client.write_all(&hello).await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut buf = vec![];
+        client.read_to_end(&mut buf).await.unwrap();
+        (buf, server_task.await.unwrap())
+    }
+
+    /// Begin a tunnel as with `tls_tunnel`, with `backend.verify_sni` set
+    async fn verify_sni(server_name: &str) -> (Vec<u8>, AccessRecord) {
+        let mut config = Config::default();
+        config.backend.verify_sni = true;
+        tls_tunnel(server_name, config).await
+    }
+
+    #[tokio::test]
+    async fn test_verify_sni() {
+        let (received, record) = verify_sni("api.giphy.com").await;
+        // the echo backend returns the ClientHello
+        assert!(received.starts_with(b"HTTP/1.1 200 OK\r\n\r\n\x16\x03"));
+        assert_eq!(record.reason, Reason::ClientClosed);
+
+        let (received, record) = verify_sni("smuggled.example.com").await;
+        assert_eq!(received, b"HTTP/1.1 200 OK\r\n\r\n");
+        assert_eq!(record.reason, Reason::SniMismatch);
+    }
+
+    #[tokio::test]
+    async fn test_tls_fingerprints() {
+        let mut config = Config::default();
+        config.log.tls_fingerprints = true;
+        let (received, record) = tls_tunnel("api.giphy.com", config).await;
+        assert!(received.starts_with(b"HTTP/1.1 200 OK\r\n\r\n\x16\x03"));
+        assert_eq!(record.reason, Reason::ClientClosed);
+        assert_eq!(record.ja3.map(|ja3| ja3.len()), Some(32));
+        assert!(record.ja4.unwrap().starts_with("t13d"));
+
+        // without fingerprinting, nothing is recorded
+        let (_, record) = tls_tunnel("api.giphy.com", Config::default()).await;
+        assert_eq!(record.ja4, None);
+    }
+
+    #[tokio::test]
+    async fn test_require_tls() {
+        let local: SocketAddr = "127.0.0.1:8443".parse().unwrap();
+        let mut config = Config::default();
+        config.limits.require_tls = vec![local];
+        let config = Arc::new(config);
+
+        let tunnel = |local: SocketAddr, data: &'static [u8]| {
+            let config = config.clone();
+            async move {
+                let (mut client, server) = duplex(1024);
+                let server_task = tokio::spawn(async move {
+                    let mut record = AccessRecord::new(None);
+                    let info = ConnectionInfo::tcp("10.0.0.1:1234".parse().unwrap(), local);
+                    let _ = handle_connection(
+                        server,
+                        EchoBackend,
+                        config,
+                        None,
+                        None,
+                        &info,
+                        &mut record,
+                    )
+                    .await;
+                    record.reason
+                });
+                client
+                    .write_all(b"CONNECT foo.com:443 HTTP/1.1\r\n\r\n")
+                    .await
+                    .unwrap();
+                client.write_all(data).await.unwrap();
+                client.shutdown().await.unwrap();
+                let mut buf = vec![];
+                client.read_to_end(&mut buf).await.unwrap();
+                (buf, server_task.await.unwrap())
+            }
+        };
+
+        let tls: &[u8] = &[22, 3, 1, 0, 1, 1];
+        let (received, reason) = tunnel(local, tls).await;
+        assert_eq!(received, [b"HTTP/1.1 200 OK\r\n\r\n", tls].concat());
+        assert_eq!(reason, Reason::ClientClosed);
+
+        let (received, reason) = tunnel(local, b"GET / HTTP/1.1\r\n\r\n").await;
+        assert_eq!(received, b"HTTP/1.1 200 OK\r\n\r\n");
+        assert_eq!(reason, Reason::NotTls);
+
+        // other listen addresses allow anything
+        let other = "127.0.0.1:8080".parse().unwrap();
+        let (received, reason) = tunnel(other, b"ping").await;
+        assert_eq!(received, b"HTTP/1.1 200 OK\r\n\r\nping");
+        assert_eq!(reason, Reason::ClientClosed);
+    }
+
+    #[tokio::test]
+    async fn test_pipelined_data() {
+        let (mut client, server) = duplex(1024);
+        let server_task = tokio::spawn(async move {
+            let mut record = AccessRecord::new(None);
+            let result = handle_connection(
+                server,
+                EchoBackend,
+                Arc::new(Config::default()),
+                None,
+                None,
+                &ConnectionInfo::default(),
+                &mut record,
+            )
+            .await;
+            result.map(|_| record.bytes_up)
+        });
+
+        // the client sends data along with its request, without waiting for the response
+        client
+            .write_all(b"CONNECT foo.com:1234 HTTP/1.1\r\n\r\nping")
+            .await
+            .unwrap();
+        client.write_all(b"pong").await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut buf = vec![];
+        client.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(server_task.await.unwrap().unwrap(), 8);
+        assert_eq!(&buf, b"HTTP/1.1 200 OK\r\n\r\npingpong");
+    }
+
+    #[tokio::test]
+    async fn test_socks5() {
+        let (mut client, server) = duplex(1024);
+        let server_task = tokio::spawn(async move {
+            connection(
+                server,
+                EchoBackend,
+                Arc::new(Config::default()),
+                None,
+                None,
+                ConnectionInfo::default(),
+            )
+            .await
+        });
+
+        client
+            .write_all(b"\x05\x01\x00\x05\x01\x00\x03\x07foo.com\x04\xd2ping")
+            .await
+            .unwrap();
+        client.shutdown().await.unwrap();
+        let mut buf = vec![];
+        client.read_to_end(&mut buf).await.unwrap();
+        let summary = server_task.await.unwrap();
+        assert_eq!(summary.reason, Reason::ClientClosed);
+        assert_eq!((summary.bytes_up, summary.bytes_down), (4, 4));
+        assert_eq!(&buf, b"\x05\x00\x05\x00\x00\x01\0\0\0\0\0\0ping");
+    }
+
+    #[tokio::test]
+    async fn test_socks5_udp_associate() {
+        let mut config = Config::default();
+        config.socks.udp = Some(Default::default());
+        let (mut client, server) = duplex(1024);
+        let server_task = tokio::spawn(async move {
+            connection(
+                server,
+                EchoBackend,
+                Arc::new(config),
+                None,
+                None,
+                ConnectionInfo::default(),
+            )
+            .await
+        });
+
+        client
+            .write_all(b"\x05\x01\x00\x05\x03\x00\x01\0\0\0\0\0\0")
+            .await
+            .unwrap();
+        // the reply gives the relay's address, and the association lasts until the client
+        // closes its connection
+        let mut reply = [0u8; 12];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply[..6], b"\x05\x00\x05\x00\x00\x01");
+        drop(client);
+        assert_eq!(server_task.await.unwrap().reason, Reason::ClientClosed);
+    }
+
+    #[tokio::test]
+    async fn test_transparent() {
+        let (mut client, server) = duplex(1024);
+        let server_task = tokio::spawn(async move {
+            let info = ConnectionInfo {
+                original_dst: Some("192.0.2.1:443".parse().unwrap()),
+                ..ConnectionInfo::default()
+            };
+            connection(
+                server,
+                EchoBackend,
+                Arc::new(Config::default()),
+                None,
+                None,
+                info,
+            )
+            .await
+        });
+
+        // the client's data is tunneled directly, with no request or response
+        client.write_all(b"ping").await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut buf = vec![];
+        client.read_to_end(&mut buf).await.unwrap();
+        let summary = server_task.await.unwrap();
+        assert_eq!(summary.reason, Reason::ClientClosed);
+        assert_eq!((summary.bytes_up, summary.bytes_down), (4, 4));
+        assert_eq!(&buf, b"ping");
+    }
+
+    #[tokio::test]
+    async fn test_websocket() {
+        let mut config = Config::default();
+        config.websocket.enabled = true;
+        let (mut client, server) = duplex(1024);
+        let server_task = tokio::spawn(connection(
+            server,
+            EchoBackend,
+            Arc::new(config),
+            None,
+            None,
+            ConnectionInfo::default(),
+        ));
+        client
+            .write_all(
+                b"GET /tunnel?host=api.giphy.com&port=443 HTTP/1.1\r\n\
+                Upgrade: websocket\r\nConnection: Upgrade\r\n\
+                Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        // a binary frame, masked with zeroes
+        client.write_all(b"\x82\x84\0\0\0\0ping").await.unwrap();
+        let expected: &[u8] = b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\
+            Connection: Upgrade\r\nSec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\r\n\
+            \x82\x04ping";
+        let mut buf = vec![0u8; expected.len()];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, expected);
+
+        // closing the WebSocket closes the tunnel
+        client.write_all(b"\x88\x82\0\0\0\0\x03\xe8").await.unwrap();
+        let mut buf = vec![];
+        client.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"\x88\x02\x03\xe8");
+        let summary = server_task.await.unwrap();
+        assert_eq!(summary.reason, Reason::ClientClosed);
+        assert_eq!((summary.bytes_up, summary.bytes_down), (4, 4));
+    }
+
+    #[tokio::test]
+    async fn test_socks5_forbidden() {
+        let (mut client, server) = duplex(1024);
+        let server_task = tokio::spawn(async move {
+            connection(
+                server,
+                FailingBackend,
+                Arc::new(Config::default()),
+                None,
+                None,
+                ConnectionInfo::default(),
+            )
+            .await
+        });
+
+        client
+            .write_all(b"\x05\x01\x00\x05\x01\x00\x03\x09forbidden\x01\xbb")
+            .await
+            .unwrap();
+        let mut buf = vec![];
+        client.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(server_task.await.unwrap().reason, Reason::Disallowed);
+        assert_eq!(&buf, b"\x05\x00\x05\x02\x00\x01\0\0\0\0\0\0");
+    }
+
+    /// Send `request` to a connection requiring authentication as pat:secret, returning
+    /// the full response and the connection's summary
+    async fn auth_response(request: &'static [u8]) -> (String, ConnectionSummary) {
+        let htpasswd = Arc::new(Htpasswd::parse("pat:secret\n").unwrap());
+        let (mut client, server) = duplex(1024);
+        let server_task = tokio::spawn(async move {
+            connection(
+                server,
+                EchoBackend,
+                Arc::new(Config::default()),
+                Some(htpasswd),
+                None,
+                ConnectionInfo::default(),
+            )
+            .await
+        });
+        client.write_all(request).await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut buf = vec![];
+        client.read_to_end(&mut buf).await.unwrap();
+        (String::from_utf8(buf).unwrap(), server_task.await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_proxy_auth_missing() {
+        let (response, summary) = auth_response(b"CONNECT foo.com:443 HTTP/1.1\r\n\r\n").await;
+        assert_eq!(summary.reason, Reason::AuthFailed);
+        assert!(response.starts_with("HTTP/1.1 407 Proxy Authentication Required\r\n"));
+        assert!(response.contains("\r\nProxy-Authenticate: Basic realm=\"giphyproxy\"\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_proxy_auth_invalid() {
+        // pat:wrong
+        let (response, summary) = auth_response(
+            b"CONNECT foo.com:443 HTTP/1.1\r\nProxy-Authorization: Basic cGF0Ondyb25n\r\n\r\n",
+        )
+        .await;
+        assert_eq!(summary.reason, Reason::AuthFailed);
+        assert!(response.starts_with("HTTP/1.1 407 Proxy Authentication Required\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_proxy_auth_valid() {
+        // pat:secret
+        let (response, summary) = auth_response(
+            b"CONNECT foo.com:443 HTTP/1.1\r\nProxy-Authorization: Basic cGF0OnNlY3JldA==\r\n\r\n",
+        )
+        .await;
+        assert_eq!(summary.reason, Reason::ClientClosed);
+        assert_eq!(response, "HTTP/1.1 200 OK\r\n\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_quota_exceeded() {
+        // only these users are limited, so other tests are unaffected by the installed
+        // quotas
+        let quotas = Arc::new(
+            Quotas::new(&QuotaConfig {
+                users: HashMap::from([("over".to_owned(), 1), ("under".to_owned(), 10)]),
+                ..QuotaConfig::default()
+            })
+            .unwrap(),
+        );
+        let mut used = AccessRecord::new(None);
+        used.user = Some("over".into());
+        used.bytes_up = 1;
+        quotas.add(&used, std::time::SystemTime::now());
+        quotas.install();
+
+        let htpasswd = Arc::new(Htpasswd::parse("over:secret\n").unwrap());
+        let (mut client, server) = duplex(1024);
+        let server_task = tokio::spawn(async move {
+            connection(
+                server,
+                EchoBackend,
+                Arc::new(Config::default()),
+                Some(htpasswd),
+                None,
+                ConnectionInfo::default(),
+            )
+            .await
+        });
+        client
+            .write_all(
+                b"CONNECT foo.com:443 HTTP/1.1\r\nProxy-Authorization: Basic b3ZlcjpzZWNyZXQ=\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        let mut buf = vec![];
+        client.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(server_task.await.unwrap().reason, Reason::QuotaExceeded);
+        assert!(buf.starts_with(b"HTTP/1.1 429 Too Many Requests\r\n"));
+
+        // a tunnel is closed once its client uses its quota
+        let htpasswd = Arc::new(Htpasswd::parse("under:secret\n").unwrap());
+        let (mut client, server) = duplex(1024);
+        let server_task = tokio::spawn(async move {
+            connection(
+                server,
+                EchoBackend,
+                Arc::new(Config::default()),
+                Some(htpasswd),
+                None,
+                ConnectionInfo::default(),
+            )
+            .await
+        });
+        client
+            .write_all(
+                b"CONNECT foo.com:443 HTTP/1.1\r\nProxy-Authorization: Basic dW5kZXI6c2VjcmV0\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        client.write_all(&[0u8; 20]).await.unwrap();
+        let mut buf = vec![];
+        client.read_to_end(&mut buf).await.unwrap();
+        let summary = server_task.await.unwrap();
+        assert_eq!(summary.reason, Reason::QuotaExceeded);
+        assert!(summary.bytes_up >= 10);
+    }
+
+    /// Open an HTTP/2 connection to a connection using the given backend, returning a
+    /// client for sending requests on it
+    async fn http2_client<B: Backend + 'static>(
+        backend: B,
+    ) -> h2::client::SendRequest<bytes::Bytes> {
+        let (client, server) = duplex(65536);
+        tokio::spawn(async move {
+            connection(
+                server,
+                backend,
+                Arc::new(Config::default()),
+                None,
+                None,
+                ConnectionInfo::default(),
+            )
+            .await
+        });
+        let (send_request, h2_connection) = h2::client::handshake(client).await.unwrap();
+        tokio::spawn(h2_connection);
+        send_request
+    }
+
+    fn http2_connect(authority: &str) -> http::Request<()> {
+        http::Request::builder()
+            .method(http::Method::CONNECT)
+            .uri(authority)
+            .body(())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_http2() {
+        let mut client = http2_client(EchoBackend).await;
+
+        // two tunnels on the same connection
+        for msg in &["ping", "pong"] {
+            let (response, mut send) = client
+                .send_request(http2_connect("foo.com:1234"), false)
+                .unwrap();
+            let response = response.await.unwrap();
+            assert_eq!(response.status(), http::StatusCode::OK);
+
+            send.send_data(bytes::Bytes::from_static(msg.as_bytes()), true)
+                .unwrap();
+            let mut recv = response.into_body();
+            let mut received = vec![];
+            while let Some(data) = recv.data().await {
+                let data = data.unwrap();
+                received.extend_from_slice(&data);
+                let _ = recv.flow_control().release_capacity(data.len());
+            }
+            assert_eq!(&received, msg.as_bytes());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_http2_forbidden() {
+        let mut client = http2_client(FailingBackend).await;
+
+        let (response, _) = client
+            .send_request(http2_connect("forbidden:443"), true)
+            .unwrap();
+        assert_eq!(
+            response.await.unwrap().status(),
+            http::StatusCode::FORBIDDEN
+        );
+
+        let (response, _) = client
+            .send_request(http2_connect("foo.com:443"), true)
+            .unwrap();
+        assert_eq!(
+            response.await.unwrap().status(),
+            http::StatusCode::BAD_GATEWAY
+        );
+    }
+
+    #[tokio::test]
+    async fn test_forbidden() {
+        let response =
+            error_response(FailingBackend, b"CONNECT forbidden:443 HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 403 Forbidden\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_bad_gateway() {
+        let response =
+            error_response(FailingBackend, b"CONNECT foo.com:443 HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 502 Bad Gateway\r\n"));
+        assert!(response.ends_with("could not connect to foo.com:443\n"));
+    }
+
+    #[tokio::test]
+    async fn test_http_1_0() {
+        let (mut client, server) = duplex(1024);
+        let server_task = tokio::spawn(connection(
+            server,
+            EchoBackend,
+            Arc::new(Config::default()),
+            None,
+            None,
+            ConnectionInfo::default(),
+        ));
+        client
+            .write_all(b"CONNECT foo.com:443 HTTP/1.0 \r\n\r\n")
+            .await
+            .unwrap();
+        client.shutdown().await.unwrap();
+        let mut buf = vec![];
+        client.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"HTTP/1.0 200 OK\r\n\r\n");
+        assert!(server_task.await.unwrap().tunneled());
+
+        let response =
+            error_response(FailingBackend, b"CONNECT forbidden:443 HTTP/1.0\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.0 403 Forbidden\r\n"));
+    }
+
+    #[test]
+    fn test_connect_response() {
+        let mut identity = IdentityConfig {
+            proxy_agent: Some("giphyproxy/0.1".into()),
+            via: true,
+            connect_headers: BTreeMap::from([
+                ("Connection".to_owned(), "keep-alive".to_owned()),
+                ("Via".to_owned(), "1.1 corp".to_owned()),
+            ]),
+            ..IdentityConfig::default()
+        };
+        assert_eq!(
+            connect_response("HTTP/1.0", &identity).to_bytes(),
+            b"HTTP/1.0 200 OK\r\nConnection: keep-alive\r\nVia: 1.1 corp\r\n\
+              Proxy-Agent: giphyproxy/0.1\r\nVia: 1.0 giphyproxy\r\n\r\n"
+        );
+        identity.anonymous = true;
+        assert_eq!(
+            connect_response("HTTP/1.1", &identity).to_bytes(),
+            b"HTTP/1.1 200 OK\r\nConnection: keep-alive\r\n\r\n"
+        );
+        assert_eq!(
+            connect_response("HTTP/1.1", &IdentityConfig::default()),
+            Response::ok()
+        );
     }
 
     #[tokio::test]
@@ -196,7 +2241,15 @@ mod test {
 
         let (mut client, server) = duplex(64);
         let server_task = tokio::spawn(async move {
-            connection(server, EchoBackend).await.unwrap();
+            connection(
+                server,
+                EchoBackend,
+                Arc::new(Config::default()),
+                None,
+                None,
+                ConnectionInfo::default(),
+            )
+            .await
         });
         let client_task = tokio::spawn(async move {
             client
@@ -227,7 +2280,9 @@ mod test {
         });
 
         // join the threads to check that the server task exits when the connection closes
-        tokio::join!(server_task).0.unwrap();
+        let summary = tokio::join!(server_task).0.unwrap();
         tokio::join!(client_task).0.unwrap();
+        assert_eq!(summary.reason, Reason::ClientClosed);
+        assert_eq!((summary.bytes_up, summary.bytes_down), (15, 15));
     }
 }
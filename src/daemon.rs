@@ -0,0 +1,333 @@
+//! Running in the background without a process supervisor.  With `daemon.enabled`, the
+//! process detaches from its terminal by forking twice around `setsid`, redirects its
+//! standard streams, and the original process waits for the proxy to report that it is
+//! ready before exiting, so that startup failures still give a non-zero exit status.
+//! Unix only.
+//!
+//! The PID file is locked with `flock` for as long as the proxy runs, so a second proxy
+//! refuses to start, and `status` and `stop` can tell a running proxy from a stale file.
+//! The file is left in place when the proxy exits.
+
+use crate::config::DaemonConfig;
+use anyhow::{bail, Result};
+use std::path::Path;
+use std::time::Duration;
+
+/// Detach from the terminal, if `config.enabled`, and lock and write the PID file, if one
+/// is configured.  When detaching, this returns only in the detached process; the calling
+/// process exits once the proxy is ready, or fails to start.  `upgrading` says whether
+/// this process was started by an upgrade, to take over from a running process.
+///
+/// This must be called while the process has a single thread, before the async runtime is
+/// built.
+pub fn start(config: &DaemonConfig, upgrading: bool) -> Result<()> {
+    imp::start(config, upgrading)
+}
+
+/// Tell the original process, if this one detached from it, that the proxy is ready
+pub fn notify_ready() -> Result<()> {
+    imp::notify_ready()
+}
+
+/// The PID of the proxy running with the given PID file, if any
+pub fn status(pid_file: &Path) -> Result<Option<u32>> {
+    imp::status(pid_file)
+}
+
+/// Ask the proxy running with the given PID file to stop, with SIGTERM, and wait up to
+/// `timeout` for it to exit.  Returns its PID, or None if it was not running.
+pub fn stop(pid_file: &Path, timeout: Duration) -> Result<Option<u32>> {
+    imp::stop(pid_file, timeout)
+}
+
+/// Parse the contents of a PID file
+#[cfg_attr(not(unix), allow(dead_code))]
+fn parse_pid(contents: &str) -> Result<u32> {
+    match contents.trim().parse() {
+        Ok(pid) if pid > 0 => Ok(pid),
+        _ => bail!("invalid PID file contents {:?}", contents.trim()),
+    }
+}
+
+#[cfg(unix)]
+mod imp {
+    use super::*;
+    use anyhow::Context;
+    use std::fs::{File, OpenOptions};
+    use std::io::{self, PipeWriter, Read, Seek, Write};
+    use std::os::fd::AsRawFd;
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::sync::Mutex;
+    use std::time::Instant;
+
+    /// The pipe to the original process, until the proxy is ready
+    static READY: Mutex<Option<PipeWriter>> = Mutex::new(None);
+
+    /// The locked PID file, held open for the life of the process
+    static PID_FILE: Mutex<Option<File>> = Mutex::new(None);
+
+    /// How often `stop` checks whether the proxy has exited
+    const STOP_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+    pub(super) fn start(config: &DaemonConfig, upgrading: bool) -> Result<()> {
+        // a process started by an upgrade is already detached, if its predecessor was
+        let pid_file = match &config.pid_file {
+            Some(path) => Some((open(path)?, path)),
+            None => None,
+        };
+        match pid_file {
+            Some((file, path)) if upgrading => {
+                // the previous process holds the lock until it exits
+                let path = path.clone();
+                std::thread::spawn(move || {
+                    if let Err(e) = lock(&file, &path, true).and_then(|()| write_pid(file, &path)) {
+                        log::error!("{:#}", e);
+                    }
+                });
+            }
+            Some((file, path)) => {
+                // the lock is taken before detaching, so that a conflict is reported on the
+                // terminal, and the detached process inherits it
+                lock(&file, path, false)?;
+                if config.enabled {
+                    detach(config)?;
+                }
+                write_pid(file, path)?;
+            }
+            None if config.enabled && !upgrading => detach(config)?,
+            None => {}
+        }
+        Ok(())
+    }
+
+    /// Open the PID file, creating it if necessary
+    fn open(path: &Path) -> Result<File> {
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .mode(0o644)
+            .open(path)
+            .with_context(|| format!("opening PID file {}", path.display()))
+    }
+
+    /// Lock the PID file, failing if another process holds the lock, unless `wait`
+    fn lock(file: &File, path: &Path, wait: bool) -> Result<()> {
+        let operation = if wait {
+            libc::LOCK_EX
+        } else {
+            libc::LOCK_EX | libc::LOCK_NB
+        };
+        // SAFETY: the descriptor is open
+        if unsafe { libc::flock(file.as_raw_fd(), operation) } < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::WouldBlock {
+                let mut contents = String::new();
+                (&*file).read_to_string(&mut contents)?;
+                bail!(
+                    "giphyproxy is already running (PID {}, from {})",
+                    contents.trim(),
+                    path.display()
+                );
+            }
+            return Err(e).with_context(|| format!("locking PID file {}", path.display()));
+        }
+        Ok(())
+    }
+
+    /// Write this process's PID to the locked PID file, and keep it open
+    fn write_pid(mut file: File, path: &Path) -> Result<()> {
+        file.set_len(0)?;
+        file.rewind()?;
+        writeln!(file, "{}", std::process::id())
+            .with_context(|| format!("writing PID file {}", path.display()))?;
+        *PID_FILE.lock().unwrap() = Some(file);
+        Ok(())
+    }
+
+    /// Detach from the terminal.  The calling process waits for the detached process to
+    /// report that it is ready, and exits.
+    fn detach(config: &DaemonConfig) -> Result<()> {
+        // files are opened first, so that errors are reported on the terminal
+        let output = match &config.log_file {
+            Some(path) => OpenOptions::new()
+                .append(true)
+                .create(true)
+                .open(path)
+                .with_context(|| format!("opening log file {}", path.display()))?,
+            None => File::options().write(true).open("/dev/null")?,
+        };
+        let input = File::open("/dev/null")?;
+        let (reader, writer) = io::pipe().context("creating pipe")?;
+
+        // SAFETY: the process has a single thread, so it can continue after fork
+        match unsafe { libc::fork() } {
+            -1 => return Err(io::Error::last_os_error()).context("forking"),
+            0 => {}
+            child => wait_for_ready(child, reader, writer, config),
+        }
+        drop(reader);
+        // SAFETY: as above; the intermediate process exits without running destructors or
+        // exit handlers, which belong to the original process
+        unsafe {
+            if libc::setsid() < 0 {
+                return Err(io::Error::last_os_error()).context("starting a new session");
+            }
+            // the session leader exits, so the proxy can never acquire a terminal
+            match libc::fork() {
+                -1 => return Err(io::Error::last_os_error()).context("forking"),
+                0 => {}
+                _ => libc::_exit(0),
+            }
+            for (file, fd) in [(&input, 0), (&output, 1), (&output, 2)] {
+                if libc::dup2(file.as_raw_fd(), fd) < 0 {
+                    return Err(io::Error::last_os_error()).context("redirecting stdio");
+                }
+            }
+        }
+        *READY.lock().unwrap() = Some(writer);
+        Ok(())
+    }
+
+    /// In the original process, wait for the detached process to write its PID, and exit
+    fn wait_for_ready(
+        child: libc::pid_t,
+        mut reader: io::PipeReader,
+        writer: PipeWriter,
+        config: &DaemonConfig,
+    ) -> ! {
+        // the read sees end-of-file if the detached process exits before it is ready
+        drop(writer);
+        // SAFETY: child is the intermediate process, which exits straight away
+        unsafe { libc::waitpid(child, std::ptr::null_mut(), 0) };
+        let mut pid = String::new();
+        let _ = reader.read_to_string(&mut pid);
+        if pid.is_empty() {
+            match &config.log_file {
+                Some(path) => eprintln!(
+                    "giphyproxy exited before it was ready; see {}",
+                    path.display()
+                ),
+                None => eprintln!("giphyproxy exited before it was ready"),
+            }
+            std::process::exit(1);
+        }
+        eprintln!("giphyproxy started (PID {})", pid);
+        std::process::exit(0);
+    }
+
+    pub(super) fn notify_ready() -> Result<()> {
+        if let Some(mut writer) = READY.lock().unwrap().take() {
+            write!(writer, "{}", std::process::id()).context("notifying the original process")?;
+        }
+        Ok(())
+    }
+
+    pub(super) fn status(pid_file: &Path) -> Result<Option<u32>> {
+        let mut file = match File::open(pid_file) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(e).with_context(|| format!("opening {}", pid_file.display()));
+            }
+        };
+        // the running proxy holds an exclusive lock, so a shared lock is only granted if
+        // there is none
+        // SAFETY: the descriptor is open
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_SH | libc::LOCK_NB) } == 0 {
+            return Ok(None);
+        }
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::WouldBlock {
+            return Err(e).with_context(|| format!("locking {}", pid_file.display()));
+        }
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        Ok(Some(parse_pid(&contents)?))
+    }
+
+    pub(super) fn stop(pid_file: &Path, timeout: Duration) -> Result<Option<u32>> {
+        let Some(pid) = status(pid_file)? else {
+            return Ok(None);
+        };
+        // SAFETY: kill has no memory-safety requirements
+        if unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) } < 0 {
+            return Err(io::Error::last_os_error())
+                .with_context(|| format!("signalling process {}", pid));
+        }
+        let deadline = Instant::now() + timeout;
+        while status(pid_file)?.is_some() {
+            if Instant::now() >= deadline {
+                bail!("process {} did not stop within {:?}", pid, timeout);
+            }
+            std::thread::sleep(STOP_POLL_INTERVAL);
+        }
+        Ok(Some(pid))
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+        use crate::testing::temp_path;
+
+        #[test]
+        fn test_status() {
+            let path = temp_path("pid");
+            assert_eq!(status(&path).unwrap(), None);
+
+            // a file that is not locked is stale
+            std::fs::write(&path, "12345\n").unwrap();
+            assert_eq!(status(&path).unwrap(), None);
+
+            let file = File::open(&path).unwrap();
+            // SAFETY: the descriptor is open
+            assert_eq!(
+                unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) },
+                0
+            );
+            assert_eq!(status(&path).unwrap(), Some(12345));
+            assert!(lock(&File::open(&path).unwrap(), &path, false).is_err());
+            drop(file);
+            assert_eq!(status(&path).unwrap(), None);
+            std::fs::remove_file(&path).unwrap();
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod imp {
+    use super::*;
+
+    pub(super) fn start(config: &DaemonConfig, _upgrading: bool) -> Result<()> {
+        if config.enabled || config.pid_file.is_some() {
+            bail!("daemon options are not supported on this platform");
+        }
+        Ok(())
+    }
+
+    pub(super) fn notify_ready() -> Result<()> {
+        Ok(())
+    }
+
+    pub(super) fn status(_pid_file: &Path) -> Result<Option<u32>> {
+        bail!("PID files are not supported on this platform")
+    }
+
+    pub(super) fn stop(_pid_file: &Path, _timeout: Duration) -> Result<Option<u32>> {
+        bail!("PID files are not supported on this platform")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_pid() {
+        assert_eq!(parse_pid("123\n").unwrap(), 123);
+        assert!(parse_pid("").is_err());
+        assert!(parse_pid("0").is_err());
+        assert!(parse_pid("abc").is_err());
+    }
+}
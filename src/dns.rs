@@ -0,0 +1,328 @@
+//! Resolution of backend hostnames, with an in-process cache.
+//!
+//! By default, names are resolved with the system resolver, which does not report TTLs,
+//! so results are cached for the configured maximum TTL.  If nameservers are configured,
+//! they are queried directly, using plain DNS, DNS-over-TLS, or DNS-over-HTTPS, and the
+//! TTLs in their responses are respected, up to the same maximum.
+//!
+//! Names with configured addresses, like those in a hosts file, are not looked up at all,
+//! and lookups can be limited to those names.
+
+use crate::config::{DnsConfig, DnsProtocol};
+use crate::metrics::METRICS;
+use anyhow::{bail, Context, Result};
+use hickory_resolver::config::{
+    NameServerConfig, NameServerConfigGroup, Protocol, ResolverConfig, ResolverOpts,
+};
+use hickory_resolver::TokioAsyncResolver;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::net::lookup_host;
+
+/// Where names are looked up
+enum Lookup {
+    /// The system resolver (`getaddrinfo`)
+    System,
+    /// The given nameservers, falling back to the system resolver if the flag is set
+    Nameservers(Box<TokioAsyncResolver>, bool),
+}
+
+/// A resolver for backend hostnames.  A single resolver is shared by all connections.
+pub struct Resolver {
+    /// Configured addresses, by lowercased name without a trailing dot
+    hosts: HashMap<String, Vec<IpAddr>>,
+    /// Whether names not in `hosts` are looked up
+    fallback_to_dns: bool,
+    lookup: Lookup,
+    cache: Mutex<Cache>,
+    max_ttl: Duration,
+}
+
+impl Resolver {
+    /// A resolver using the system resolver, with the default cache settings
+    pub fn system() -> Self {
+        Self::from_config(&DnsConfig::default())
+    }
+
+    /// Create a resolver from its configuration
+    pub fn from_config(config: &DnsConfig) -> Self {
+        let lookup = if config.nameservers.is_empty() {
+            Lookup::System
+        } else {
+            let protocols: &[Protocol] = match config.protocol {
+                DnsProtocol::Udp => &[Protocol::Udp, Protocol::Tcp],
+                DnsProtocol::Tls => &[Protocol::Tls],
+                DnsProtocol::Https => &[Protocol::Https],
+            };
+            let mut group = NameServerConfigGroup::new();
+            for addr in &config.nameservers {
+                for protocol in protocols {
+                    let mut nameserver = NameServerConfig::new(*addr, *protocol);
+                    nameserver.tls_dns_name = config.tls_name.clone();
+                    group.push(nameserver);
+                }
+            }
+            let mut opts = ResolverOpts::default();
+            // results are cached here, where the cache is visible in metrics
+            opts.cache_size = 0;
+            // only the configured nameservers are consulted, not /etc/hosts
+            opts.use_hosts_file = false;
+            Lookup::Nameservers(
+                Box::new(TokioAsyncResolver::tokio(
+                    ResolverConfig::from_parts(None, vec![], group),
+                    opts,
+                )),
+                config.fallback_to_system,
+            )
+        };
+        let hosts = config
+            .hosts
+            .iter()
+            .map(|(host, addrs)| (canonical(host), addrs.clone()))
+            .collect();
+        Self {
+            hosts,
+            fallback_to_dns: config.fallback_to_dns,
+            lookup,
+            cache: Mutex::new(Cache::new(config.cache_size)),
+            max_ttl: config.max_ttl,
+        }
+    }
+
+    /// Resolve a hostname to its addresses.  IP addresses are returned as-is.
+    pub async fn resolve(&self, host: &str) -> Result<Vec<IpAddr>> {
+        if let Ok(ip) = host.parse() {
+            return Ok(vec![ip]);
+        }
+        if let Some(addrs) = self.hosts.get(&canonical(host)) {
+            return Ok(addrs.clone());
+        }
+        if !self.fallback_to_dns {
+            bail!("{} is not in backend.dns.hosts", host);
+        }
+        let host = host.to_ascii_lowercase();
+        if let Some(addrs) = self.cache.lock().unwrap().get(&host, Instant::now()) {
+            METRICS.dns_cache_hits.inc();
+            return Ok(addrs);
+        }
+        METRICS.dns_cache_misses.inc();
+
+        let start = Instant::now();
+        let (addrs, ttl) = self
+            .lookup(&host)
+            .await
+            .with_context(|| format!("resolving {}", host))?;
+        let now = Instant::now();
+        METRICS.dns_resolve_latency.observe(now - start);
+        if addrs.is_empty() {
+            bail!("{} did not resolve to any addresses", host);
+        }
+
+        let expires = now + ttl.min(self.max_ttl);
+        self.cache
+            .lock()
+            .unwrap()
+            .insert(host, addrs.clone(), expires, now);
+        Ok(addrs)
+    }
+
+    /// Look up a name, without the cache, returning its addresses and their TTL
+    async fn lookup(&self, host: &str) -> Result<(Vec<IpAddr>, Duration)> {
+        match &self.lookup {
+            Lookup::System => self.lookup_system(host).await,
+            Lookup::Nameservers(resolver, fallback) => match resolver.lookup_ip(host).await {
+                Ok(lookup) => {
+                    let ttl = lookup
+                        .valid_until()
+                        .saturating_duration_since(Instant::now());
+                    Ok((lookup.iter().collect(), ttl))
+                }
+                Err(e) if *fallback => {
+                    log::warn!(
+                        "resolving {} with the configured nameservers failed ({}); \
+                             using the system resolver",
+                        host,
+                        e
+                    );
+                    self.lookup_system(host).await
+                }
+                Err(e) => Err(e.into()),
+            },
+        }
+    }
+
+    /// Look up a name with the system resolver
+    async fn lookup_system(&self, host: &str) -> Result<(Vec<IpAddr>, Duration)> {
+        let addrs = lookup_host((host, 0)).await?.map(|a| a.ip()).collect();
+        Ok((addrs, self.max_ttl))
+    }
+}
+
+/// A bounded cache of resolved names
+struct Cache {
+    entries: HashMap<String, (Vec<IpAddr>, Instant)>,
+    capacity: usize,
+}
+
+impl Cache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            entries: HashMap::new(),
+            capacity,
+        }
+    }
+
+    /// Get the unexpired addresses for a name
+    fn get(&self, host: &str, now: Instant) -> Option<Vec<IpAddr>> {
+        match self.entries.get(host) {
+            Some((addrs, expires)) if *expires > now => Some(addrs.clone()),
+            _ => None,
+        }
+    }
+
+    /// Add addresses for a name.  If the cache is full, expired entries are removed, and
+    /// then if necessary the entry closest to expiring.
+    fn insert(&mut self, host: String, addrs: Vec<IpAddr>, expires: Instant, now: Instant) {
+        if self.capacity == 0 || expires <= now {
+            return;
+        }
+        if self.entries.len() >= self.capacity && !self.entries.contains_key(&host) {
+            self.entries.retain(|_, (_, expires)| *expires > now);
+            if self.entries.len() >= self.capacity {
+                let soonest = self
+                    .entries
+                    .iter()
+                    .min_by_key(|(_, (_, expires))| *expires)
+                    .map(|(host, _)| host.clone());
+                if let Some(soonest) = soonest {
+                    self.entries.remove(&soonest);
+                }
+            }
+        }
+        self.entries.insert(host, (addrs, expires));
+    }
+}
+
+/// A hostname as it is looked up in the configured hosts: lowercased, without a trailing
+/// dot
+fn canonical(host: &str) -> String {
+    host.trim_end_matches('.').to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    fn ip(s: &str) -> Vec<IpAddr> {
+        vec![s.parse().unwrap()]
+    }
+
+    #[test]
+    fn test_cache_expiry() {
+        let mut cache = Cache::new(10);
+        let now = Instant::now();
+        cache.insert(
+            "a".into(),
+            ip("10.0.0.1"),
+            now + Duration::from_secs(5),
+            now,
+        );
+        assert_eq!(cache.get("a", now), Some(ip("10.0.0.1")));
+        assert_eq!(cache.get("a", now + Duration::from_secs(5)), None);
+        assert_eq!(cache.get("b", now), None);
+    }
+
+    #[test]
+    fn test_cache_eviction() {
+        let mut cache = Cache::new(2);
+        let now = Instant::now();
+        let secs = |n| now + Duration::from_secs(n);
+        cache.insert("a".into(), ip("10.0.0.1"), secs(10), now);
+        cache.insert("b".into(), ip("10.0.0.2"), secs(5), now);
+        cache.insert("c".into(), ip("10.0.0.3"), secs(20), now);
+        // b was closest to expiring
+        assert!(cache.get("a", now).is_some());
+        assert!(cache.get("b", now).is_none());
+        assert!(cache.get("c", now).is_some());
+
+        // once a has expired, it is removed first
+        cache.insert("d".into(), ip("10.0.0.4"), secs(30), secs(15));
+        assert!(cache.get("a", now).is_none());
+        assert!(cache.get("c", now).is_some());
+        assert!(cache.get("d", now).is_some());
+    }
+
+    #[test]
+    fn test_cache_disabled() {
+        let mut cache = Cache::new(0);
+        let now = Instant::now();
+        cache.insert(
+            "a".into(),
+            ip("10.0.0.1"),
+            now + Duration::from_secs(5),
+            now,
+        );
+        assert_eq!(cache.get("a", now), None);
+    }
+
+    #[tokio::test]
+    async fn test_resolve() {
+        let resolver = Resolver::system();
+        assert_eq!(resolver.resolve("10.0.0.1").await.unwrap(), ip("10.0.0.1"));
+        assert_eq!(resolver.resolve("::1").await.unwrap(), ip("::1"));
+
+        let addrs = resolver.resolve("LocalHost").await.unwrap();
+        assert!(addrs.iter().all(|a| a.is_loopback()));
+        // the result is cached under the lowercased name
+        let cached = resolver
+            .cache
+            .lock()
+            .unwrap()
+            .get("localhost", Instant::now());
+        assert_eq!(cached, Some(addrs));
+    }
+
+    #[tokio::test]
+    async fn test_nameserver_failure() {
+        // nothing listens on port 1, so the configured nameserver fails, and without a
+        // fallback, so does the lookup
+        let config = DnsConfig {
+            nameservers: vec!["127.0.0.1:1".parse().unwrap()],
+            protocol: DnsProtocol::Tls,
+            tls_name: Some("localhost".into()),
+            ..DnsConfig::default()
+        };
+        let resolver = Resolver::from_config(&config);
+        assert!(resolver.resolve("api.giphy.com").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_hosts() {
+        let config = DnsConfig {
+            hosts: BTreeMap::from([("API.giphy.com".to_owned(), ip("192.0.2.1"))]),
+            ..DnsConfig::default()
+        };
+        let resolver = Resolver::from_config(&config);
+        assert_eq!(
+            resolver.resolve("api.giphy.com").await.unwrap(),
+            ip("192.0.2.1")
+        );
+        assert_eq!(
+            resolver.resolve("Api.Giphy.Com.").await.unwrap(),
+            ip("192.0.2.1")
+        );
+        assert!(resolver.resolve("localhost").await.is_ok());
+
+        let config = DnsConfig {
+            fallback_to_dns: false,
+            ..config
+        };
+        let resolver = Resolver::from_config(&config);
+        assert!(resolver.resolve("api.giphy.com").await.is_ok());
+        assert!(resolver.resolve("10.0.0.1").await.is_ok());
+        assert!(resolver.resolve("localhost").await.is_err());
+    }
+}
@@ -0,0 +1,131 @@
+//! The error type returned by the library's connection-handling API.  Each variant is a
+//! kind of failure that callers may want to handle differently, for example when choosing
+//! the response to send to the client.
+
+use crate::backend::Disallowed;
+use crate::breaker::CircuitOpen;
+use std::fmt;
+use std::io;
+
+/// A failure while handling a connection
+#[derive(Debug, thiserror::Error)]
+pub enum ProxyError {
+    /// A request or response head could not be parsed
+    #[error("{0}")]
+    Parse(String),
+
+    /// The requested destination is not permitted
+    #[error(transparent)]
+    Disallowed(#[from] Disallowed),
+
+    /// The destination has failed repeatedly, and its circuit breaker is open
+    #[error(transparent)]
+    CircuitOpen(#[from] CircuitOpen),
+
+    /// The client has exceeded the rate at which it may open backend connections
+    #[error("backend connection rate exceeded for {0}")]
+    RateLimited(std::net::IpAddr),
+
+    /// The client has used its daily quota of bytes; the string identifies the client
+    #[error("daily quota exceeded for {0}")]
+    QuotaExceeded(String),
+
+    /// Connecting to the destination, or to a parent proxy on its behalf, failed.  `error`
+    /// has kind `TimedOut` if the connection timed out.
+    #[error("connecting to {target}: {error}")]
+    UpstreamConnect { target: String, error: io::Error },
+
+    /// Reading from or writing to a socket failed
+    #[error("{context}: {error}")]
+    Io { context: String, error: io::Error },
+
+    /// The client did not send what was expected within the time allowed
+    #[error("timed out {0}")]
+    Timeout(String),
+
+    /// The client closed its connection before sending what was expected
+    #[error("{0}")]
+    Closed(String),
+
+    /// The client did not give valid proxy credentials
+    #[error("{0}")]
+    Auth(String),
+
+    /// A tunnel was refused because of what the client sent through it
+    #[error("{0}")]
+    Policy(String),
+
+    /// The handler for the connection panicked; the string is the panic's message
+    #[error("panicked: {0}")]
+    Panic(String),
+
+    /// A failure reported by one of the modules that use `anyhow`, such as the SOCKS and
+    /// HTTP/2 handlers
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+/// A `Result` whose error is a `ProxyError`
+pub type Result<T, E = ProxyError> = std::result::Result<T, E>;
+
+impl ProxyError {
+    /// An `UpstreamConnect` error for the given target and message
+    pub(crate) fn upstream<T: Into<String>, E: fmt::Display>(target: T, error: E) -> Self {
+        ProxyError::UpstreamConnect {
+            target: target.into(),
+            error: io::Error::other(error.to_string()),
+        }
+    }
+}
+
+/// Add context to an I/O error, producing `ProxyError::Io`, in the style of
+/// `anyhow::Context`
+pub(crate) trait IoContext<T> {
+    fn io_context<C: fmt::Display>(self, context: C) -> Result<T>;
+
+    fn with_io_context<C: fmt::Display, F: FnOnce() -> C>(self, f: F) -> Result<T>;
+}
+
+impl<T> IoContext<T> for io::Result<T> {
+    fn io_context<C: fmt::Display>(self, context: C) -> Result<T> {
+        self.map_err(|error| ProxyError::Io {
+            context: context.to_string(),
+            error,
+        })
+    }
+
+    fn with_io_context<C: fmt::Display, F: FnOnce() -> C>(self, f: F) -> Result<T> {
+        self.map_err(|error| ProxyError::Io {
+            context: f().to_string(),
+            error,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_display() {
+        let err = ProxyError::from(Disallowed {
+            host: "example.com".into(),
+            port: 443,
+        });
+        assert_eq!(
+            err.to_string(),
+            "Connection to disallowed host/port example.com:443"
+        );
+
+        let err: Result<()> = Err(io::Error::from(io::ErrorKind::UnexpectedEof))
+            .io_context("reading head from client");
+        assert!(matches!(err, Err(ProxyError::Io { .. })));
+        assert!(err
+            .unwrap_err()
+            .to_string()
+            .starts_with("reading head from client: "));
+
+        let err = ProxyError::from(anyhow::anyhow!("inner").context("outer"));
+        assert_eq!(format!("{:#}", err), "outer: inner");
+    }
+}
@@ -0,0 +1,228 @@
+//! Failover between the endpoints of a logical backend.  Connections to the backend's
+//! `host` and `port` are made to the first of an ordered list of endpoints that accepts
+//! them: the primary, then each failover endpoint in turn.  Each endpoint has its own
+//! circuit breaker, so that one which has failed repeatedly is skipped without waiting for
+//! it to fail again.  The endpoint that served each connection is recorded in its access
+//! log record, as `served_by`.
+
+use crate::backend::{check_allowed, Backend, SingleHostBackend};
+use crate::breaker::CircuitBreaker;
+use crate::config::{BackendConfig, CircuitBreakerConfig};
+use crate::connection::ConnectionInfo;
+use crate::error::Result;
+use crate::http::authority;
+use crate::metrics::METRICS;
+
+/// One of the endpoints of a [`FailoverBackend`]
+struct Member<B: Backend> {
+    host: String,
+    port: u16,
+    backend: CircuitBreaker<B>,
+}
+
+/// A backend allowing connections only to a single host and port, which are made to the
+/// first of its endpoints that accepts them
+pub struct FailoverBackend<B: Backend> {
+    endpoints: Vec<Member<B>>,
+    circuit_breaker: Option<CircuitBreakerConfig>,
+}
+
+impl<B: Backend> FailoverBackend<B> {
+    /// Create a backend allowing connections only to `host` and `port`, made through
+    /// `backend`.  If `circuit_breaker` is given, each endpoint has a circuit breaker with
+    /// that configuration.
+    pub fn new<H: Into<String>>(
+        host: H,
+        port: u16,
+        backend: B,
+        circuit_breaker: Option<CircuitBreakerConfig>,
+    ) -> Self {
+        let mut failover = Self {
+            endpoints: vec![],
+            circuit_breaker,
+        };
+        failover.push(host.into(), port, backend);
+        failover
+    }
+
+    /// Add an endpoint, to which connections are made through `backend` when the
+    /// endpoints before it fail
+    pub fn with_endpoint<H: Into<String>>(mut self, host: H, port: u16, backend: B) -> Self {
+        self.push(host.into(), port, backend);
+        self
+    }
+
+    fn push(&mut self, host: String, port: u16, backend: B) {
+        self.endpoints.push(Member {
+            host,
+            port,
+            backend: CircuitBreaker::new(backend, self.circuit_breaker.clone()),
+        });
+    }
+}
+
+impl FailoverBackend<SingleHostBackend> {
+    /// Create a backend for `config.host` and `config.port`, failing over to each of
+    /// `config.failover`.  Each endpoint is configured as a single-host backend.
+    pub fn from_config(config: &BackendConfig) -> Self {
+        let mut backend = Self::new(
+            config.host.clone(),
+            config.port,
+            SingleHostBackend::from_config(config),
+            config.circuit_breaker.clone(),
+        );
+        for endpoint in &config.failover {
+            let config = BackendConfig {
+                host: endpoint.host.clone(),
+                port: endpoint.port,
+                ..config.clone()
+            };
+            backend = backend.with_endpoint(
+                endpoint.host.clone(),
+                endpoint.port,
+                SingleHostBackend::from_config(&config),
+            );
+        }
+        backend
+    }
+}
+
+#[async_trait::async_trait]
+impl<B: Backend> Backend for FailoverBackend<B> {
+    type Socket = B::Socket;
+
+    async fn connect(&self, host: &str, port: u16) -> Result<Self::Socket> {
+        self.connect_for(&ConnectionInfo::default(), host, port)
+            .await
+    }
+
+    async fn connect_for(
+        &self,
+        info: &ConnectionInfo,
+        host: &str,
+        port: u16,
+    ) -> Result<Self::Socket> {
+        let primary = &self.endpoints[0];
+        check_allowed(host == primary.host && port == primary.port, host, port)?;
+
+        let mut endpoints = self.endpoints.iter().enumerate().peekable();
+        loop {
+            // there is always at least one endpoint, and the last returns its error
+            let (i, endpoint) = endpoints.next().unwrap();
+            let target = authority(&endpoint.host, endpoint.port);
+            let result = endpoint
+                .backend
+                .connect_for(info, &endpoint.host, endpoint.port)
+                .await;
+            match (result, endpoints.peek()) {
+                (Ok(socket), _) => {
+                    if i > 0 {
+                        METRICS.backend_failovers.inc();
+                    }
+                    info.served_by.set(target);
+                    return Ok(socket);
+                }
+                (Err(e), Some((_, next))) => {
+                    log::warn!(
+                        "backend endpoint {} failed ({}); trying {}",
+                        target,
+                        e,
+                        authority(&next.host, next.port)
+                    );
+                }
+                (Err(e), None) => return Err(e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::ProxyError;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::io::DuplexStream;
+
+    /// A backend that counts connection attempts, succeeding only if it is up
+    struct TestBackend {
+        up: bool,
+        attempts: AtomicUsize,
+    }
+
+    fn new_backend(up: bool) -> Arc<TestBackend> {
+        Arc::new(TestBackend {
+            up,
+            attempts: AtomicUsize::new(0),
+        })
+    }
+
+    #[async_trait::async_trait]
+    impl Backend for TestBackend {
+        type Socket = DuplexStream;
+
+        async fn connect(&self, host: &str, port: u16) -> Result<Self::Socket> {
+            self.attempts.fetch_add(1, Ordering::SeqCst);
+            if self.up {
+                Ok(tokio::io::duplex(16).0)
+            } else {
+                Err(ProxyError::upstream(
+                    authority(host, port),
+                    "connection refused",
+                ))
+            }
+        }
+    }
+
+    fn attempts(backends: &[&Arc<TestBackend>]) -> Vec<usize> {
+        backends
+            .iter()
+            .map(|backend| backend.attempts.load(Ordering::SeqCst))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_failover() {
+        let (a, b, c) = (new_backend(false), new_backend(false), new_backend(true));
+        let backend = FailoverBackend::new("primary", 443, a.clone(), None)
+            .with_endpoint("secondary", 443, b.clone())
+            .with_endpoint("tertiary", 8443, c.clone());
+        let info = ConnectionInfo::default();
+        backend.connect_for(&info, "primary", 443).await.unwrap();
+        assert_eq!(attempts(&[&a, &b, &c]), vec![1, 1, 1]);
+        assert_eq!(info.served_by.take(), Some("tertiary:8443".into()));
+
+        // only the primary may be requested
+        let err = backend.connect("secondary", 443).await.unwrap_err();
+        assert!(matches!(err, ProxyError::Disallowed(_)));
+
+        // if all endpoints fail, the last error is returned
+        let backend = FailoverBackend::new("primary", 443, new_backend(false), None).with_endpoint(
+            "secondary",
+            443,
+            new_backend(false),
+        );
+        let err = backend.connect("primary", 443).await.unwrap_err();
+        assert!(err.to_string().contains("secondary:443"));
+    }
+
+    #[tokio::test]
+    async fn test_open_circuit() {
+        let breaker = CircuitBreakerConfig {
+            failure_threshold: 1,
+            cooldown: Duration::from_secs(60),
+        };
+        let (a, b) = (new_backend(false), new_backend(true));
+        let backend = FailoverBackend::new("primary", 443, a.clone(), Some(breaker)).with_endpoint(
+            "secondary",
+            443,
+            b.clone(),
+        );
+        for _ in 0..3 {
+            backend.connect("primary", 443).await.unwrap();
+        }
+        // once its circuit opened, the primary was skipped
+        assert_eq!(attempts(&[&a, &b]), vec![1, 3]);
+    }
+}
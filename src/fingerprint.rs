@@ -0,0 +1,367 @@
+//! JA3 and JA4 fingerprints of the TLS ClientHello at the start of a tunnel, computed
+//! without terminating TLS.  Both summarize the client's TLS library and its
+//! configuration, rather than the server it asks for, so an unusual fingerprint points to
+//! an unusual client.
+//!
+//! [JA3](https://github.com/salesforce/ja3) is the MD5 hash of the ClientHello's version,
+//! cipher suites, extensions, groups, and point formats, in the order sent.
+//! [JA4](https://github.com/FoxIO-LLC/ja4) is more readable, and sorts the cipher suites
+//! and extensions, so it is stable for clients which randomize their order.  GREASE values
+//! ([RFC 8701](https://tools.ietf.org/html/rfc8701)) are ignored by both.
+
+use crate::client_hello::{self, Reader};
+use anyhow::{Context, Result};
+use ring::digest::{digest, SHA256};
+use std::fmt::Write;
+
+/// Extension types used in the fingerprints
+const EXTENSION_SERVER_NAME: u16 = 0;
+const EXTENSION_SUPPORTED_GROUPS: u16 = 10;
+const EXTENSION_EC_POINT_FORMATS: u16 = 11;
+const EXTENSION_SIGNATURE_ALGORITHMS: u16 = 13;
+const EXTENSION_ALPN: u16 = 16;
+const EXTENSION_SUPPORTED_VERSIONS: u16 = 43;
+
+/// The fingerprints of a single ClientHello
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fingerprint {
+    /// The JA3 fingerprint, as the hex MD5 hash
+    pub ja3: String,
+    /// The JA4 fingerprint, such as `t13d1516h2_8daaf6152771_e5627efa2ab1`
+    pub ja4: String,
+}
+
+/// The fields of a ClientHello which make up its fingerprints, without GREASE values
+#[derive(Debug, Default)]
+struct Fields {
+    version: u16,
+    cipher_suites: Vec<u16>,
+    extensions: Vec<u16>,
+    server_name: bool,
+    groups: Vec<u16>,
+    point_formats: Vec<u8>,
+    signature_algorithms: Vec<u16>,
+    alpn: Option<Vec<u8>>,
+    supported_versions: Vec<u16>,
+}
+
+/// Fingerprint the complete ClientHello at the start of a TLS stream
+pub fn fingerprint(data: &[u8]) -> Result<Fingerprint> {
+    let body = client_hello::message(data)?.context("incomplete ClientHello")?;
+    let fields = fields(&body).context("invalid ClientHello")?;
+    Ok(Fingerprint {
+        ja3: hex(&md5(ja3(&fields).as_bytes())),
+        ja4: ja4(&fields),
+    })
+}
+
+/// Is this a GREASE value, reserved so that clients can check servers ignore unknown ones?
+fn grease(value: u16) -> bool {
+    value & 0x0f0f == 0x0a0a && value >> 8 == value & 0xff
+}
+
+/// Read a vector of 16-bit values, dropping GREASE values
+fn u16s(data: &[u8]) -> Result<Vec<u16>> {
+    let mut reader = Reader(data);
+    let mut values = vec![];
+    while !reader.0.is_empty() {
+        let value = reader.u16()?;
+        if !grease(value) {
+            values.push(value);
+        }
+    }
+    Ok(values)
+}
+
+/// Parse the fields of a ClientHello, from the body of its handshake message
+fn fields(body: &[u8]) -> Result<Fields> {
+    let mut hello = Reader(body);
+    let mut fields = Fields {
+        version: hello.u16()?,
+        ..Fields::default()
+    };
+    hello.bytes(32)?; // random
+    hello.vec8()?; // legacy_session_id
+    fields.cipher_suites = u16s(hello.vec16()?)?;
+    hello.vec8()?; // legacy_compression_methods
+    if hello.0.is_empty() {
+        return Ok(fields);
+    }
+    let mut extensions = Reader(hello.vec16()?);
+    while !extensions.0.is_empty() {
+        let extension_type = extensions.u16()?;
+        let mut data = Reader(extensions.vec16()?);
+        if grease(extension_type) {
+            continue;
+        }
+        fields.extensions.push(extension_type);
+        match extension_type {
+            EXTENSION_SERVER_NAME => fields.server_name = true,
+            EXTENSION_SUPPORTED_GROUPS => fields.groups = u16s(data.vec16()?)?,
+            EXTENSION_EC_POINT_FORMATS => fields.point_formats = data.vec8()?.to_vec(),
+            EXTENSION_SIGNATURE_ALGORITHMS => fields.signature_algorithms = u16s(data.vec16()?)?,
+            EXTENSION_ALPN => {
+                let mut protocols = Reader(data.vec16()?);
+                if !protocols.0.is_empty() {
+                    fields.alpn = Some(protocols.vec8()?.to_vec());
+                }
+            }
+            EXTENSION_SUPPORTED_VERSIONS => fields.supported_versions = u16s(data.vec8()?)?,
+            _ => {}
+        }
+    }
+    Ok(fields)
+}
+
+/// The JA3 string, before hashing: the version, cipher suites, extensions, groups, and
+/// point formats, in decimal
+fn ja3(fields: &Fields) -> String {
+    let join = |values: &mut dyn Iterator<Item = u16>| {
+        values.map(|v| v.to_string()).collect::<Vec<_>>().join("-")
+    };
+    format!(
+        "{},{},{},{},{}",
+        fields.version,
+        join(&mut fields.cipher_suites.iter().copied()),
+        join(&mut fields.extensions.iter().copied()),
+        join(&mut fields.groups.iter().copied()),
+        join(&mut fields.point_formats.iter().map(|&p| p as u16)),
+    )
+}
+
+/// The JA4 fingerprint: a readable summary of the ClientHello, the hashed cipher suites,
+/// and the hashed extensions and signature algorithms
+fn ja4(fields: &Fields) -> String {
+    let version = fields
+        .supported_versions
+        .iter()
+        .copied()
+        .max()
+        .unwrap_or(fields.version);
+    let version = match version {
+        0x0304 => "13",
+        0x0303 => "12",
+        0x0302 => "11",
+        0x0301 => "10",
+        0x0300 => "s3",
+        _ => "00",
+    };
+    let alpn = match fields.alpn.as_deref() {
+        Some(&[first, .., last]) | Some(&[first @ last]) => {
+            if first.is_ascii_alphanumeric() && last.is_ascii_alphanumeric() {
+                format!("{}{}", first as char, last as char)
+            } else {
+                format!("{}{}", &hex(&[first])[..1], &hex(&[last])[1..])
+            }
+        }
+        _ => "00".into(),
+    };
+    let summary = format!(
+        "t{}{}{:02}{:02}{}",
+        version,
+        if fields.server_name { 'd' } else { 'i' },
+        fields.cipher_suites.len().min(99),
+        fields.extensions.len().min(99),
+        alpn,
+    );
+
+    let hex_list = |values: &[u16]| {
+        values
+            .iter()
+            .map(|v| format!("{:04x}", v))
+            .collect::<Vec<_>>()
+            .join(",")
+    };
+    let mut cipher_suites = fields.cipher_suites.clone();
+    cipher_suites.sort_unstable();
+    let mut extensions: Vec<u16> = fields
+        .extensions
+        .iter()
+        .copied()
+        .filter(|&e| e != EXTENSION_SERVER_NAME && e != EXTENSION_ALPN)
+        .collect();
+    extensions.sort_unstable();
+    let mut extensions = hex_list(&extensions);
+    if !fields.signature_algorithms.is_empty() {
+        extensions.push('_');
+        extensions.push_str(&hex_list(&fields.signature_algorithms));
+    }
+    format!(
+        "{}_{}_{}",
+        summary,
+        truncated_hash(&hex_list(&cipher_suites)),
+        truncated_hash(&extensions)
+    )
+}
+
+/// The first 12 hex digits of the SHA-256 hash of a JA4 list, or zeroes if it is empty
+fn truncated_hash(list: &str) -> String {
+    if list.is_empty() {
+        return "0".repeat(12);
+    }
+    hex(&digest(&SHA256, list.as_bytes()).as_ref()[..6])
+}
+
+/// Format bytes as lowercase hex
+fn hex(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len() * 2);
+    for b in bytes {
+        let _ = write!(out, "{:02x}", b);
+    }
+    out
+}
+
+/// The MD5 hash of `data` ([RFC 1321](https://tools.ietf.org/html/rfc1321)), which JA3
+/// requires.  MD5 is long broken, but here it only needs to summarize a string.
+fn md5(data: &[u8]) -> [u8; 16] {
+    const SHIFTS: [[u32; 4]; 4] = [
+        [7, 12, 17, 22],
+        [5, 9, 14, 20],
+        [4, 11, 16, 23],
+        [6, 10, 15, 21],
+    ];
+
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&(data.len() as u64).wrapping_mul(8).to_le_bytes());
+
+    let mut state: [u32; 4] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476];
+    for block in message.chunks(64) {
+        let words: Vec<u32> = block
+            .chunks(4)
+            .map(|w| u32::from_le_bytes([w[0], w[1], w[2], w[3]]))
+            .collect();
+        let [mut a, mut b, mut c, mut d] = state;
+        for i in 0..64 {
+            let (f, g) = match i / 16 {
+                0 => ((b & c) | (!b & d), i),
+                1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+                2 => (b ^ c ^ d, (3 * i + 5) % 16),
+                _ => (c ^ (b | !d), (7 * i) % 16),
+            };
+            let k = ((i as f64 + 1.0).sin().abs() * 4294967296.0) as u32;
+            let f = f.wrapping_add(a).wrapping_add(k).wrapping_add(words[g]);
+            a = d;
+            d = c;
+            c = b;
+            b = b.wrapping_add(f.rotate_left(SHIFTS[i / 16][i % 4]));
+        }
+        for (s, v) in state.iter_mut().zip([a, b, c, d]) {
+            *s = s.wrapping_add(v);
+        }
+    }
+
+    let mut hash = [0u8; 16];
+    for (out, s) in hash.chunks_mut(4).zip(state) {
+        out.copy_from_slice(&s.to_le_bytes());
+    }
+    hash
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A ClientHello record with the given cipher suites and extensions
+    fn client_hello(cipher_suites: &[u16], extensions: &[(u16, Vec<u8>)]) -> Vec<u8> {
+        let mut body = vec![3, 3];
+        body.extend_from_slice(&[0; 32]);
+        body.push(0);
+        body.extend_from_slice(&((cipher_suites.len() * 2) as u16).to_be_bytes());
+        for c in cipher_suites {
+            body.extend_from_slice(&c.to_be_bytes());
+        }
+        body.extend_from_slice(&[1, 0]);
+        let mut ext = vec![];
+        for (t, data) in extensions {
+            ext.extend_from_slice(&t.to_be_bytes());
+            ext.extend_from_slice(&(data.len() as u16).to_be_bytes());
+            ext.extend_from_slice(data);
+        }
+        body.extend_from_slice(&(ext.len() as u16).to_be_bytes());
+        body.extend_from_slice(&ext);
+
+        let mut handshake = vec![1];
+        handshake.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+        handshake.extend_from_slice(&body);
+        let mut record = vec![22, 3, 1];
+        record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+        record.extend_from_slice(&handshake);
+        record
+    }
+
+    #[test]
+    fn test_md5() {
+        assert_eq!(hex(&md5(b"")), "d41d8cd98f00b204e9800998ecf8427e");
+        assert_eq!(hex(&md5(b"abc")), "900150983cd24fb0d6963f7d28e17f72");
+        assert_eq!(hex(&md5(&[b'a'; 100])), "36a92cc94a9e0fa21f625f8bfb007adf");
+    }
+
+    #[test]
+    fn test_truncated_hash() {
+        // the example from the JA4 documentation
+        assert_eq!(
+            truncated_hash(
+                "002f,0035,009c,009d,1301,1302,1303,c013,c014,c02b,c02c,c02f,c030,cca8,cca9"
+            ),
+            "8daaf6152771"
+        );
+        assert_eq!(truncated_hash(""), "000000000000");
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        let hello = client_hello(
+            &[0x0a0a, 0x1301, 0x1302],
+            &[
+                (0x1a1a, vec![]),
+                (0, b"\x00\x10\x00\x00\x0dapi.giphy.com".to_vec()),
+                (10, vec![0, 4, 0, 0x1d, 0, 0x17]),
+                (11, vec![1, 0]),
+                (13, vec![0, 4, 4, 3, 8, 4]),
+                (16, b"\x00\x0c\x02h2\x08http/1.1".to_vec()),
+                (43, vec![4, 0x3a, 0x3a, 3, 4]),
+            ],
+        );
+        let fields = fields(&client_hello::message(&hello).unwrap().unwrap()).unwrap();
+        assert_eq!(ja3(&fields), "771,4865-4866,0-10-11-13-16-43,29-23,0");
+
+        let fingerprint = fingerprint(&hello).unwrap();
+        assert_eq!(fingerprint.ja3, hex(&md5(ja3(&fields).as_bytes())));
+        assert_eq!(
+            fingerprint.ja4,
+            format!(
+                "t13d0206h2_{}_{}",
+                truncated_hash("1301,1302"),
+                truncated_hash("000a,000b,000d,002b_0403,0804")
+            )
+        );
+    }
+
+    #[test]
+    fn test_fingerprint_minimal() {
+        // no SNI, ALPN, or supported versions
+        let fingerprint = fingerprint(&client_hello(&[0xc02f], &[])).unwrap();
+        assert_eq!(
+            fingerprint.ja4,
+            format!("t12i010000_{}_000000000000", truncated_hash("c02f"))
+        );
+
+        let fields = fields(
+            &client_hello::message(&client_hello(&[], &[(16, b"\x00\x02\x01\xff".to_vec())]))
+                .unwrap()
+                .unwrap(),
+        )
+        .unwrap();
+        assert!(ja4(&fields).starts_with("t12i0001ff_000000000000_"));
+    }
+
+    #[test]
+    fn test_fingerprint_invalid() {
+        assert!(fingerprint(&client_hello(&[0x1301], &[])[..20]).is_err());
+        assert!(fingerprint(b"GET / HTTP/1.1\r\n\r\n").is_err());
+    }
+}
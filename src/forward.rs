@@ -0,0 +1,1147 @@
+//! Plain HTTP forward proxying, for clients that send requests in absolute form, such as
+//! `GET http://example.com/ HTTP/1.1`, rather than using CONNECT.  Each request is
+//! rewritten to origin form and sent to the backend on a connection of its own, and the
+//! response is relayed back, after which both connections are closed.  Bodies in either
+//! direction, including chunked bodies, are passed through unchanged.
+//!
+//! The reverse proxy uses the same forwarding, but may ask the backend to keep its
+//! connection open, so that it can be reused for a later request.
+
+use crate::access::Reason;
+use crate::config::{IdentityConfig, LimitsConfig};
+use crate::connection::{supervise, ConnectionSummary, Direction, TunnelState};
+use crate::hooks::Hooks;
+use crate::http::{authority, AbsoluteRequest, Headers, OriginRequest, Response};
+use crate::registry::Registration;
+use anyhow::{bail, Context, Result};
+use tokio::io::{
+    split, AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt,
+    BufReader,
+};
+
+/// Maximum length of a chunk-size or trailer line in a chunked request body
+const MAX_LINE: u64 = 4096;
+
+/// Maximum size of a response head from the backend
+const MAX_RESPONSE_HEAD: usize = 65536;
+
+/// Maximum size of a response body that is read in full to be rewritten by hooks
+const MAX_REWRITTEN_BODY: u64 = 16 * 1024 * 1024;
+
+/// Headers that identify the client or the proxies a request has passed through, which are
+/// removed from forwarded requests by an anonymous proxy
+const IDENTIFYING: &[&str] = &["Via", "Forwarded", "X-Forwarded-For"];
+
+/// Headers that apply only to a single connection, and are not forwarded in either
+/// direction.  `Transfer-Encoding` is also hop-by-hop, but since bodies are passed through
+/// unchanged, it remains accurate.
+const HOP_BY_HOP: &[&str] = &[
+    "Connection",
+    "Keep-Alive",
+    "Proxy-Authenticate",
+    "Proxy-Authorization",
+    "Proxy-Connection",
+    "TE",
+    "Upgrade",
+];
+
+/// How the length of a request body is determined
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Body {
+    /// There is no body
+    Empty,
+    /// The body has this many bytes
+    Length(u64),
+    /// The body uses the chunked transfer coding
+    Chunked,
+}
+
+impl Body {
+    /// Determine the body framing of a request from its headers, rejecting ambiguous or
+    /// unsupported framing.  The head is forwarded as it is, so anything a backend might
+    /// frame differently, such as a second `Transfer-Encoding` header, is rejected.
+    pub(crate) fn of(headers: &Headers) -> Result<Self> {
+        let values = |name: &str| -> Vec<&str> {
+            headers
+                .iter()
+                .filter(|(n, _)| n.eq_ignore_ascii_case(name))
+                .map(|(_, value)| value)
+                .collect()
+        };
+        match (
+            values("Transfer-Encoding").as_slice(),
+            values("Content-Length").as_slice(),
+        ) {
+            ([_, ..], [_, ..]) => bail!("request has both Transfer-Encoding and Content-Length"),
+            ([coding], []) if coding.eq_ignore_ascii_case("chunked") => Ok(Body::Chunked),
+            ([coding], []) => bail!("unsupported Transfer-Encoding {:?}", coding),
+            ([_, _, ..], []) => bail!("request has multiple Transfer-Encoding headers"),
+            ([], []) => Ok(Body::Empty),
+            ([], [length]) => {
+                Ok(Body::Length(parse_length(length).with_context(|| {
+                    format!("invalid Content-Length {:?}", length)
+                })?))
+            }
+            ([], _) => bail!("request has multiple Content-Length headers"),
+        }
+    }
+}
+
+/// Parse a `Content-Length` value, which must be all ASCII digits
+fn parse_length(value: &str) -> Option<u64> {
+    if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    value.parse().ok()
+}
+
+/// A request to be forwarded, with the parts of its head not shared with CONNECT requests
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Forward {
+    pub(crate) method: String,
+    /// The request target, in origin form
+    pub(crate) path: String,
+    pub(crate) version: String,
+    /// The `Host` header to send, which replaces any the client sent
+    pub(crate) host: String,
+    /// The client's headers, of which all but `Host` and the hop-by-hop headers are sent
+    pub(crate) headers: Headers,
+    pub(crate) body: Body,
+    /// This proxy's entry in the `Via` headers added to the request and its response, if
+    /// any
+    pub(crate) via: Option<String>,
+    /// Ask the backend to keep the connection open after the response
+    pub(crate) keep_alive: bool,
+}
+
+impl Forward {
+    /// Make a `Forward` for an absolute-form request, returning it with the request's
+    /// destination.  The destination is taken from the URI's authority, which is checked
+    /// against the backend's policy, so a `Host` header naming anything else is rejected,
+    /// lest a backend that routes on `Host` be reached with a destination the policy did
+    /// not see.
+    pub(crate) fn new(request: AbsoluteRequest) -> Result<(String, u16, Self)> {
+        check_host_header(&request)?;
+        let forward = Forward {
+            method: request.method,
+            path: request.path,
+            version: request.version,
+            host: host_header(&request.host, request.port, 80),
+            body: Body::of(&request.headers)?,
+            headers: request.headers,
+            via: None,
+            keep_alive: false,
+        };
+        Ok((request.host, request.port, forward))
+    }
+
+    /// Make a `Forward` for an origin-form request, to be sent with the given `Host`
+    /// header
+    pub(crate) fn from_origin(request: OriginRequest, host: String) -> Result<Self> {
+        Ok(Forward {
+            method: request.method,
+            path: request.path,
+            version: request.version,
+            host,
+            body: Body::of(&request.headers)?,
+            headers: request.headers,
+            via: None,
+            keep_alive: false,
+        })
+    }
+
+    /// Identify the proxy in the request and its response as `identity` says, or if it is
+    /// anonymous, remove the headers identifying the client and earlier proxies
+    pub(crate) fn identify(&mut self, identity: &IdentityConfig) {
+        if identity.anonymous {
+            for name in IDENTIFYING {
+                self.headers.remove(name);
+            }
+        }
+        self.via = identity.via(&self.version);
+    }
+
+    /// The request head to send to the backend
+    fn head(&self) -> Vec<u8> {
+        let mut head = format!(
+            "{} {} {}\r\nHost: {}\r\n",
+            self.method, self.path, self.version, self.host
+        );
+        let connection = self.headers.get("Connection").unwrap_or("");
+        for (name, value) in self.headers.iter() {
+            if !name.eq_ignore_ascii_case("Host") && !is_hop_by_hop(name, connection) {
+                head.push_str(&format!("{}: {}\r\n", name, value));
+            }
+        }
+        if let Some(via) = &self.via {
+            head.push_str(&format!("Via: {}\r\n", via));
+        }
+        if self.keep_alive {
+            head.push_str("Connection: keep-alive\r\n\r\n");
+        } else {
+            head.push_str("Connection: close\r\n\r\n");
+        }
+        head.into_bytes()
+    }
+}
+
+/// The `Host` header for a destination, omitting the port if it is the default port for
+/// the scheme
+pub(crate) fn host_header(host: &str, port: u16, default_port: u16) -> String {
+    if port == default_port {
+        authority(host, port)
+            .trim_end_matches(&format!(":{}", port))
+            .to_owned()
+    } else {
+        authority(host, port)
+    }
+}
+
+/// Check that a request has at most one `Host` header, naming the destination in its URI
+fn check_host_header(request: &AbsoluteRequest) -> Result<()> {
+    let mut hosts = request
+        .headers
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("Host"));
+    match (hosts.next(), hosts.next()) {
+        (Some(_), Some(_)) => bail!("request has multiple Host headers"),
+        (Some((_, host)), None) if !host_matches(host, &request.host, request.port) => {
+            bail!(
+                "Host header {:?} does not match the request URI's {}",
+                host,
+                authority(&request.host, request.port)
+            )
+        }
+        _ => (),
+    }
+    Ok(())
+}
+
+/// Does the value of a `Host` header name the given destination, with or without the
+/// default port?
+fn host_matches(header: &str, host: &str, port: u16) -> bool {
+    let header = header.to_ascii_lowercase();
+    let host = host.to_ascii_lowercase();
+    header == host_header(&host, port, 80) || header == authority(&host, port)
+}
+
+/// Is the named header hop-by-hop, given the value of the `Connection` header in the same
+/// message?
+fn is_hop_by_hop(name: &str, connection: &str) -> bool {
+    HOP_BY_HOP.iter().any(|h| h.eq_ignore_ascii_case(name))
+        || connection
+            .split(',')
+            .any(|option| option.trim().eq_ignore_ascii_case(name))
+}
+
+/// Forward a request to the backend and relay its response to the client.  The client's
+/// request head has been read, and `extra` holds any data it sent after the head.  If any
+/// of `hooks` rewrite responses, a successful response is rewritten before it is relayed,
+/// and if it can be cached, it is stored in the hooks' cache.
+/// The tunnel is supervised like any other, so it is closed if idle or terminated.
+pub(crate) async fn forward<S, BS>(
+    socket: BufReader<S>,
+    extra: Vec<u8>,
+    mut backend_socket: BS,
+    request: &Forward,
+    hooks: &Hooks,
+    limits: &LimitsConfig,
+    tunnel: &Registration,
+) -> ConnectionSummary
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    BS: AsyncRead + AsyncWrite + Unpin,
+{
+    let exchange = exchange(
+        socket,
+        extra,
+        &mut backend_socket,
+        request,
+        hooks,
+        limits,
+        tunnel,
+    );
+    exchange.await.0
+}
+
+/// Like `forward`, but leaving `backend_socket` open, and returning whether it can be used
+/// for another request: that is, if `request.keep_alive` is set, the exchange completed,
+/// the response's length was known from its head, and the backend did not say it would
+/// close the connection.
+pub(crate) async fn exchange<S, BS>(
+    socket: BufReader<S>,
+    extra: Vec<u8>,
+    backend_socket: &mut BS,
+    request: &Forward,
+    hooks: &Hooks,
+    limits: &LimitsConfig,
+    tunnel: &Registration,
+) -> (ConnectionSummary, bool)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    BS: AsyncRead + AsyncWrite + Unpin,
+{
+    let (client_read, mut client_write) = split(socket);
+    let mut client_read = BufReader::new(extra.as_slice().chain(client_read));
+    let (backend_read, mut backend_write) = split(backend_socket);
+    let mut backend_read = BufReader::new(backend_read);
+    let state = &tunnel.state;
+
+    let up = async {
+        let head = request.head();
+        write_counted(&mut backend_write, &head, state, Direction::Up).await?;
+        copy_body(
+            &mut client_read,
+            &mut backend_write,
+            request.body,
+            state,
+            Direction::Up,
+        )
+        .await?;
+        backend_write
+            .flush()
+            .await
+            .context("writing to backend socket")
+    };
+    let down = async {
+        let framed =
+            relay_response(&mut backend_read, &mut client_write, request, hooks, state).await?;
+        // anything more the backend sent would be taken as the next response
+        Ok::<_, anyhow::Error>(framed && backend_read.buffer().is_empty())
+    };
+    let mut reusable = false;
+    let exchange = async {
+        match tokio::join!(up, down) {
+            (Ok(()), Ok(framed)) => {
+                reusable = framed;
+                Reason::BackendClosed
+            }
+            (up, down) => {
+                for e in [up.err(), down.err()].iter().flatten() {
+                    log::warn!("while forwarding: {}", e);
+                }
+                Reason::Error
+            }
+        }
+    };
+    let summary = supervise(tunnel, exchange, limits.idle_timeout).await;
+    (summary, reusable)
+}
+
+/// Copy a body framed as `body` says from `read` to `write`
+async fn copy_body<R, W>(
+    read: &mut R,
+    write: &mut W,
+    body: Body,
+    state: &TunnelState,
+    direction: Direction,
+) -> Result<()>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    match body {
+        Body::Empty => Ok(()),
+        Body::Length(length) => copy_exact(read, write, length, state, direction).await,
+        Body::Chunked => copy_chunked(read, write, state, direction).await,
+    }
+}
+
+/// Write `buf` to the backend or client, counting it as transferred
+async fn write_counted<W: AsyncWrite + Unpin>(
+    write: &mut W,
+    buf: &[u8],
+    state: &TunnelState,
+    direction: Direction,
+) -> Result<()> {
+    let name = match direction {
+        Direction::Up => "backend socket",
+        Direction::Down => "client socket",
+    };
+    write
+        .write_all(buf)
+        .await
+        .with_context(|| format!("writing to {}", name))?;
+    state.touch();
+    state.transferred(direction, buf.len() as u64);
+    state.tap(direction, buf);
+    Ok(())
+}
+
+/// Copy exactly `length` bytes of body in the given direction
+async fn copy_exact<R, W>(
+    read: &mut R,
+    write: &mut W,
+    length: u64,
+    state: &TunnelState,
+    direction: Direction,
+) -> Result<()>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let peer = match direction {
+        Direction::Up => "client",
+        Direction::Down => "backend",
+    };
+    let mut remaining = length;
+    while remaining > 0 {
+        let buf = read
+            .fill_buf()
+            .await
+            .with_context(|| format!("reading from {} socket", peer))?;
+        if buf.is_empty() {
+            bail!("{} hung up with {} bytes of body unsent", peer, remaining);
+        }
+        let n = buf.len().min(remaining as usize);
+        write_counted(write, &buf[..n], state, direction).await?;
+        read.consume(n);
+        remaining -= n as u64;
+    }
+    Ok(())
+}
+
+/// Read a line ending in CRLF from a chunked body, of at most `MAX_LINE` bytes
+async fn read_line<R: AsyncBufRead + Unpin>(read: &mut R) -> Result<Vec<u8>> {
+    let mut line = vec![];
+    read.take(MAX_LINE)
+        .read_until(b'\n', &mut line)
+        .await
+        .context("reading chunked body")?;
+    if !line.ends_with(b"\r\n") {
+        bail!("invalid or overlong line in chunked body");
+    }
+    Ok(line)
+}
+
+/// Copy a chunked body in the given direction, through its last chunk and any trailers
+async fn copy_chunked<R, W>(
+    read: &mut R,
+    write: &mut W,
+    state: &TunnelState,
+    direction: Direction,
+) -> Result<()>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    loop {
+        let line = read_line(read).await?;
+        write_counted(write, &line, state, direction).await?;
+        let size = chunk_size(&line)?;
+        if size == 0 {
+            break;
+        }
+        copy_exact(read, write, size, state, direction).await?;
+        let end = read_line(read).await?;
+        if end != b"\r\n" {
+            bail!("chunk is longer than its size");
+        }
+        write_counted(write, &end, state, direction).await?;
+    }
+    // trailers, ending with an empty line
+    loop {
+        let line = read_line(read).await?;
+        write_counted(write, &line, state, direction).await?;
+        if line == b"\r\n" {
+            return Ok(());
+        }
+    }
+}
+
+/// Parse the size from a chunk-size line, ignoring any chunk extensions.  The size must be
+/// 1 to 16 hex digits, with nothing else before the extensions.
+fn chunk_size(line: &[u8]) -> Result<u64> {
+    let line = line.strip_suffix(b"\r\n").unwrap_or(line);
+    let size = line.split(|&b| b == b';').next().unwrap_or(b"");
+    if size.is_empty() || size.len() > 16 || !size.iter().all(u8::is_ascii_hexdigit) {
+        bail!("invalid chunk size {:?}", String::from_utf8_lossy(size));
+    }
+    // the size is all hex digits, so it is ASCII
+    Ok(u64::from_str_radix(std::str::from_utf8(size)?, 16)?)
+}
+
+/// Relay the backend's response to the client, rewriting its head to remove hop-by-hop
+/// headers and to say that the connection will close.  Informational (1xx) responses are
+/// relayed as they are.  If the backend does not send a valid response head, or a response
+/// to be rewritten or cached by `hooks` cannot be read, the client gets a 502 response
+/// instead.  Returns whether the backend's connection can be reused, having been read
+/// only to the end of the response.
+async fn relay_response<R, W>(
+    read: &mut R,
+    write: &mut W,
+    request: &Forward,
+    hooks: &Hooks,
+    state: &TunnelState,
+) -> Result<bool>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut interim = false;
+    loop {
+        let head = read_response_head(read)
+            .await
+            .and_then(|lines| Ok((status_code(&lines[0])?, lines)));
+        let (status, lines) = match head {
+            Ok(head) => head,
+            Err(e) => {
+                if !interim {
+                    let response =
+                        Response::error(502, "Bad Gateway", "invalid response from backend");
+                    let _ = write.write_all(&response.to_bytes()).await;
+                }
+                return Err(e);
+            }
+        };
+        if (100..200).contains(&status) && status != 101 {
+            write_counted(write, &lines.concat(), state, Direction::Down).await?;
+            interim = true;
+            continue;
+        }
+        let framing = reusable_framing(request, status, &lines);
+        if status == 200 && request.method != "HEAD" && hooks.reads_responses(request) {
+            let mut body = match read_response_body(read, &lines).await {
+                Ok(body) => body,
+                Err(e) => {
+                    let response =
+                        Response::error(502, "Bad Gateway", "invalid response from backend");
+                    let _ = write.write_all(&response.to_bytes()).await;
+                    return Err(e);
+                }
+            };
+            hooks.rewrite_response(request, &mut body);
+            let head = rewrite_response_head(&lines, Some(body.len()), request.via.as_deref());
+            hooks.store_response(request, &lines, &head, &body);
+            write_counted(write, &head, state, Direction::Down).await?;
+            write_counted(write, &body, state, Direction::Down).await?;
+            write.shutdown().await.context("writing to client socket")?;
+            return Ok(framing.is_some());
+        }
+        write_counted(
+            write,
+            &rewrite_response_head(&lines, None, request.via.as_deref()),
+            state,
+            Direction::Down,
+        )
+        .await?;
+        if let Some(body) = framing {
+            copy_body(read, write, body, state, Direction::Down).await?;
+            write.shutdown().await.context("writing to client socket")?;
+            return Ok(true);
+        }
+        break;
+    }
+
+    let mut buf = vec![0u8; 8192];
+    loop {
+        let n = read
+            .read(&mut buf)
+            .await
+            .context("reading from backend socket")?;
+        if n == 0 {
+            break;
+        }
+        write_counted(write, &buf[..n], state, Direction::Down).await?;
+    }
+    write.shutdown().await.context("writing to client socket")?;
+    Ok(false)
+}
+
+/// If `request` asked to keep the connection open, and the backend agreed, how the length
+/// of the response body is determined from its head.  Returns `None` if the connection
+/// cannot be reused, including when the body ends only when the backend closes the
+/// connection.
+fn reusable_framing(request: &Forward, status: u16, lines: &[Vec<u8>]) -> Option<Body> {
+    if !request.keep_alive || !lines[0].starts_with(b"HTTP/1.1 ") || status == 101 {
+        return None;
+    }
+    let headers: Vec<(String, String)> = lines[1..].iter().map(|l| split_header(l)).collect();
+    let values = |name: &str| -> Vec<&str> {
+        headers
+            .iter()
+            .filter(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+            .collect()
+    };
+    if values("Connection")
+        .iter()
+        .flat_map(|value| value.split(','))
+        .any(|option| option.trim().eq_ignore_ascii_case("close"))
+    {
+        return None;
+    }
+    if request.method == "HEAD" || status == 204 || status == 304 {
+        return Some(Body::Empty);
+    }
+    // the last coding applied determines the framing
+    let last_coding = values("Transfer-Encoding")
+        .iter()
+        .flat_map(|value| value.split(','))
+        .last()
+        .map(str::trim);
+    match (last_coding, values("Content-Length").as_slice()) {
+        (Some(coding), []) if coding.eq_ignore_ascii_case("chunked") => Some(Body::Chunked),
+        (None, [length]) => parse_length(length).map(Body::Length),
+        _ => None,
+    }
+}
+
+/// Read a response head from the backend, returning its lines including their line
+/// endings, the last of which is empty
+async fn read_response_head<R: AsyncBufRead + Unpin>(read: &mut R) -> Result<Vec<Vec<u8>>> {
+    let mut lines = vec![];
+    let mut size = 0;
+    loop {
+        let mut line = vec![];
+        let limit = (MAX_RESPONSE_HEAD - size) as u64;
+        read.take(limit)
+            .read_until(b'\n', &mut line)
+            .await
+            .context("reading from backend socket")?;
+        if !line.ends_with(b"\n") {
+            bail!("backend sent an incomplete or overlong response head");
+        }
+        size += line.len();
+        let end = line == b"\r\n" || line == b"\n";
+        lines.push(line);
+        if end {
+            return Ok(lines);
+        }
+    }
+}
+
+/// Read the body of a response in full, removing any chunked transfer coding.  Bodies with
+/// a content coding, or larger than `MAX_REWRITTEN_BODY`, are rejected.
+async fn read_response_body<R: AsyncBufRead + Unpin>(
+    read: &mut R,
+    lines: &[Vec<u8>],
+) -> Result<Vec<u8>> {
+    let header = |name: &str| {
+        lines[1..]
+            .iter()
+            .map(|line| split_header(line))
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value)
+    };
+    match header("Content-Encoding") {
+        Some(coding) if !coding.eq_ignore_ascii_case("identity") => {
+            bail!("cannot rewrite response with Content-Encoding {:?}", coding)
+        }
+        _ => (),
+    }
+
+    let mut body = vec![];
+    let chunked = header("Transfer-Encoding")
+        .is_some_and(|coding| coding.to_ascii_lowercase().ends_with("chunked"));
+    if chunked {
+        loop {
+            let size = chunk_size(&read_line(read).await?)?;
+            if size == 0 {
+                break;
+            }
+            if body.len() as u64 + size > MAX_REWRITTEN_BODY {
+                bail!("response body is too large to rewrite");
+            }
+            let start = body.len();
+            body.resize(start + size as usize, 0);
+            read.read_exact(&mut body[start..])
+                .await
+                .context("reading from backend socket")?;
+            if read_line(read).await? != b"\r\n" {
+                bail!("chunk is longer than its size");
+            }
+        }
+        // trailers, which are dropped
+        while read_line(read).await? != b"\r\n" {}
+        return Ok(body);
+    }
+
+    let length = match header("Content-Length") {
+        Some(length) => Some(
+            parse_length(&length)
+                .with_context(|| format!("invalid Content-Length {:?}", length))?,
+        ),
+        None => None,
+    };
+    if length.is_some_and(|length| length > MAX_REWRITTEN_BODY) {
+        bail!("response body is too large to rewrite");
+    }
+    // without a length, the body ends when the backend closes the connection
+    let limit = length.unwrap_or(MAX_REWRITTEN_BODY + 1);
+    read.take(limit)
+        .read_to_end(&mut body)
+        .await
+        .context("reading from backend socket")?;
+    match length {
+        Some(length) if (body.len() as u64) < length => {
+            bail!(
+                "backend hung up with {} bytes of body unsent",
+                length - body.len() as u64
+            )
+        }
+        None if body.len() as u64 > MAX_REWRITTEN_BODY => {
+            bail!("response body is too large to rewrite")
+        }
+        _ => Ok(body),
+    }
+}
+
+/// Parse the status code from a response's status line
+fn status_code(line: &[u8]) -> Result<u16> {
+    let line = String::from_utf8_lossy(line);
+    let mut parts = line.split(' ');
+    match (parts.next(), parts.next().map(str::parse)) {
+        (Some(version), Some(Ok(status))) if version.starts_with("HTTP/1.") => Ok(status),
+        _ => bail!("backend sent an invalid status line {:?}", line.trim_end()),
+    }
+}
+
+/// Split a header line into its name and value
+pub(crate) fn split_header(line: &[u8]) -> (String, String) {
+    let line = String::from_utf8_lossy(line);
+    match line.split_once(':') {
+        Some((name, value)) => (name.trim().to_owned(), value.trim().to_owned()),
+        None => (line.trim().to_owned(), String::new()),
+    }
+}
+
+/// Rewrite a response head for relaying to the client.  If the body has been read and
+/// rewritten, `length` is its new length, replacing any framing headers.  If `via` is
+/// given, a `Via` header with that value is added.
+fn rewrite_response_head(lines: &[Vec<u8>], length: Option<usize>, via: Option<&str>) -> Vec<u8> {
+    let connection: Vec<String> = lines
+        .iter()
+        .map(|line| split_header(line))
+        .filter(|(name, _)| name.eq_ignore_ascii_case("Connection"))
+        .map(|(_, value)| value)
+        .collect();
+    let connection = connection.join(",");
+
+    let (status, rest) = lines.split_first().expect("heads have a status line");
+    let (end, headers) = rest.split_last().expect("heads end with an empty line");
+    let mut head = status.clone();
+    for line in headers {
+        let name = split_header(line).0;
+        let framing = name.eq_ignore_ascii_case("Content-Length")
+            || name.eq_ignore_ascii_case("Transfer-Encoding");
+        if is_hop_by_hop(&name, &connection) || (framing && length.is_some()) {
+            continue;
+        }
+        head.extend_from_slice(line);
+    }
+    if let Some(length) = length {
+        head.extend_from_slice(format!("Content-Length: {}\r\n", length).as_bytes());
+    }
+    if let Some(via) = via {
+        head.extend_from_slice(format!("Via: {}\r\n", via).as_bytes());
+    }
+    head.extend_from_slice(b"Connection: close\r\n");
+    head.extend_from_slice(end);
+    head
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::access::AccessRecord;
+    use crate::hooks::Hook;
+    use crate::http::{parse_absolute, ParseOptions};
+    use crate::registry::REGISTRY;
+    use tokio::io::duplex;
+
+    fn request(head: &[u8]) -> Forward {
+        Forward::new(parse_absolute(head, &ParseOptions::default()).unwrap())
+            .unwrap()
+            .2
+    }
+
+    #[test]
+    fn test_body() {
+        let headers = |h: &[(&str, &str)]| {
+            Headers::from(
+                h.iter()
+                    .map(|(n, v)| (n.to_string(), v.to_string()))
+                    .collect::<Vec<_>>(),
+            )
+        };
+        assert_eq!(Body::of(&headers(&[])).unwrap(), Body::Empty);
+        assert_eq!(
+            Body::of(&headers(&[("content-length", "12")])).unwrap(),
+            Body::Length(12)
+        );
+        assert_eq!(
+            Body::of(&headers(&[("Transfer-Encoding", "Chunked")])).unwrap(),
+            Body::Chunked
+        );
+        assert!(Body::of(&headers(&[("Content-Length", "x")])).is_err());
+        assert!(Body::of(&headers(&[("Transfer-Encoding", "gzip")])).is_err());
+        assert!(Body::of(&headers(&[
+            ("Transfer-Encoding", "chunked"),
+            ("Content-Length", "3")
+        ]))
+        .is_err());
+        assert!(Body::of(&headers(&[
+            ("Content-Length", "3"),
+            ("Content-Length", "4")
+        ]))
+        .is_err());
+        // a backend might frame these differently
+        assert!(Body::of(&headers(&[
+            ("Transfer-Encoding", "chunked"),
+            ("Transfer-Encoding", "gzip")
+        ]))
+        .is_err());
+        assert!(Body::of(&headers(&[
+            ("Transfer-Encoding", "chunked"),
+            ("Transfer-Encoding", "chunked")
+        ]))
+        .is_err());
+        assert!(Body::of(&headers(&[("Transfer-Encoding", "gzip, chunked")])).is_err());
+        assert!(Body::of(&headers(&[("Transfer-Encoding", "chunked, chunked")])).is_err());
+        assert!(Body::of(&headers(&[("Content-Length", "+5")])).is_err());
+        assert!(Body::of(&headers(&[("Content-Length", "-0")])).is_err());
+        assert!(Body::of(&headers(&[("Content-Length", "")])).is_err());
+        assert!(Body::of(&headers(&[("Content-Length", "5 5")])).is_err());
+    }
+
+    #[test]
+    fn test_head() {
+        let forward = request(
+            b"GET http://example.com/gifs?q=cat HTTP/1.1\r\nHost: Example.com:80\r\n\
+              Connection: X-Secret\r\nX-Secret: 1\r\nProxy-Authorization: Basic eA==\r\n\
+              Accept: */*\r\n\r\n",
+        );
+        assert_eq!(
+            String::from_utf8(forward.head()).unwrap(),
+            "GET /gifs?q=cat HTTP/1.1\r\nHost: example.com\r\nAccept: */*\r\n\
+             Connection: close\r\n\r\n"
+        );
+
+        let mut forward = request(b"GET http://[::1]:8080 HTTP/1.0\r\n\r\n");
+        assert_eq!(
+            String::from_utf8(forward.head()).unwrap(),
+            "GET / HTTP/1.0\r\nHost: [::1]:8080\r\nConnection: close\r\n\r\n"
+        );
+        forward.keep_alive = true;
+        assert_eq!(
+            String::from_utf8(forward.head()).unwrap(),
+            "GET / HTTP/1.0\r\nHost: [::1]:8080\r\nConnection: keep-alive\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn test_identify() {
+        const HEAD: &[u8] = b"GET http://example.com/ HTTP/1.0\r\nVia: 1.1 corp\r\n\
+                              X-Forwarded-For: 10.0.0.1\r\n\r\n";
+        let mut identity = IdentityConfig {
+            via: true,
+            ..IdentityConfig::default()
+        };
+        let mut forward = request(HEAD);
+        forward.identify(&identity);
+        assert_eq!(
+            String::from_utf8(forward.head()).unwrap(),
+            "GET / HTTP/1.0\r\nHost: example.com\r\nVia: 1.1 corp\r\n\
+             X-Forwarded-For: 10.0.0.1\r\nVia: 1.0 giphyproxy\r\nConnection: close\r\n\r\n"
+        );
+
+        identity.anonymous = true;
+        let mut forward = request(HEAD);
+        forward.identify(&identity);
+        assert_eq!(forward.via, None);
+        assert_eq!(
+            String::from_utf8(forward.head()).unwrap(),
+            "GET / HTTP/1.0\r\nHost: example.com\r\nConnection: close\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn test_host_mismatch() {
+        let new =
+            |head: &[u8]| Forward::new(parse_absolute(head, &ParseOptions::default()).unwrap());
+        assert!(new(b"GET http://example.com/ HTTP/1.1\r\nHost: example.com\r\n\r\n").is_ok());
+        assert!(new(b"GET http://[::1]:8080/ HTTP/1.1\r\nHost: [::1]:8080\r\n\r\n").is_ok());
+        assert!(new(b"GET http://example.com/ HTTP/1.1\r\nHost: internal\r\n\r\n").is_err());
+        assert!(
+            new(b"GET http://example.com/ HTTP/1.1\r\nHost: example.com:8080\r\n\r\n").is_err()
+        );
+        assert!(
+            new(b"GET http://example.com/ HTTP/1.1\r\nHost: example.com\r\n\
+                      Host: internal\r\n\r\n")
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_host_header() {
+        assert_eq!(host_header("example.com", 80, 80), "example.com");
+        assert_eq!(host_header("example.com", 443, 80), "example.com:443");
+        assert_eq!(host_header("::1", 443, 443), "[::1]");
+    }
+
+    #[test]
+    fn test_chunk_size() {
+        assert_eq!(chunk_size(b"1a\r\n").unwrap(), 26);
+        assert_eq!(chunk_size(b"0;ext=1\r\n").unwrap(), 0);
+        assert_eq!(chunk_size(b"FFFFFFFFFFFFFFFF\r\n").unwrap(), u64::MAX);
+        assert!(chunk_size(b"zz\r\n").is_err());
+        assert!(chunk_size(b"\r\n").is_err());
+        assert!(chunk_size(b"+a\r\n").is_err());
+        assert!(chunk_size(b" a\r\n").is_err());
+        assert!(chunk_size(b"a \r\n").is_err());
+        assert!(chunk_size(b"a ;ext\r\n").is_err());
+        assert!(chunk_size(b"0x1a\r\n").is_err());
+        assert!(chunk_size(b"10000000000000000\r\n").is_err());
+    }
+
+    #[test]
+    fn test_reusable_framing() {
+        let mut get = request(b"GET http://example.com/ HTTP/1.1\r\n\r\n");
+        let framing = |request: &Forward, status: u16, head: &[u8]| {
+            let lines: Vec<Vec<u8>> = head
+                .split_inclusive(|&b| b == b'\n')
+                .map(<[u8]>::to_vec)
+                .collect();
+            reusable_framing(request, status, &lines)
+        };
+        let length = b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n";
+        assert_eq!(framing(&get, 200, length), None);
+
+        get.keep_alive = true;
+        assert_eq!(framing(&get, 200, length), Some(Body::Length(5)));
+        assert_eq!(
+            framing(
+                &get,
+                200,
+                b"HTTP/1.1 200 OK\r\nTransfer-Encoding: gzip, chunked\r\n\r\n"
+            ),
+            Some(Body::Chunked)
+        );
+        // only an exact match on the last coding is chunked
+        assert_eq!(
+            framing(
+                &get,
+                200,
+                b"HTTP/1.1 200 OK\r\nTransfer-Encoding: notchunked\r\n\r\n"
+            ),
+            None
+        );
+        assert_eq!(
+            framing(
+                &get,
+                200,
+                b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked, gzip\r\n\r\n"
+            ),
+            None
+        );
+        assert_eq!(
+            framing(&get, 200, b"HTTP/1.1 200 OK\r\nContent-Length: +5\r\n\r\n"),
+            None
+        );
+        assert_eq!(
+            framing(&get, 304, b"HTTP/1.1 304 Not Modified\r\n\r\n"),
+            Some(Body::Empty)
+        );
+        // the body ends when the connection closes
+        assert_eq!(framing(&get, 200, b"HTTP/1.1 200 OK\r\n\r\n"), None);
+        assert_eq!(
+            framing(
+                &get,
+                200,
+                b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\nConnection: Close\r\n\r\n"
+            ),
+            None
+        );
+        assert_eq!(
+            framing(&get, 200, b"HTTP/1.0 200 OK\r\nContent-Length: 5\r\n\r\n"),
+            None
+        );
+
+        let mut head = request(b"HEAD http://example.com/ HTTP/1.1\r\n\r\n");
+        head.keep_alive = true;
+        assert_eq!(framing(&head, 200, length), Some(Body::Empty));
+    }
+
+    #[test]
+    fn test_rewrite_response_head() {
+        let lines: Vec<Vec<u8>> = [
+            "HTTP/1.1 200 OK\r\n",
+            "Connection: keep-alive, X-Hop\r\n",
+            "X-Hop: 1\r\n",
+            "Keep-Alive: timeout=5\r\n",
+            "Transfer-Encoding: chunked\r\n",
+            "\r\n",
+        ]
+        .iter()
+        .map(|l| l.as_bytes().to_vec())
+        .collect();
+        assert_eq!(
+            String::from_utf8(rewrite_response_head(&lines, None, None)).unwrap(),
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n"
+        );
+        assert_eq!(
+            String::from_utf8(rewrite_response_head(&lines, Some(10), Some("1.1 gp"))).unwrap(),
+            "HTTP/1.1 200 OK\r\nContent-Length: 10\r\nVia: 1.1 gp\r\nConnection: close\r\n\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_forward_chunked() {
+        let head = b"POST http://example.com/upload HTTP/1.1\r\n\
+                     Transfer-Encoding: chunked\r\nExpect: 100-continue\r\n\r\n";
+        let forward_request = request(head);
+        let (mut client, client_end) = duplex(1024);
+        let (backend_end, mut backend) = duplex(1024);
+        let proxy = tokio::spawn(async move {
+            let registration = REGISTRY.register(&AccessRecord::new(None));
+            forward(
+                BufReader::new(client_end),
+                b"4\r\nping\r\n".to_vec(),
+                backend_end,
+                &forward_request,
+                &Hooks::default(),
+                &LimitsConfig::default(),
+                &registration,
+            )
+            .await
+        });
+
+        // the rest of the body follows the part that arrived with the head
+        client
+            .write_all(b"0\r\nX-Trailer: 1\r\n\r\nignored")
+            .await
+            .unwrap();
+
+        let expected: &[u8] = b"POST /upload HTTP/1.1\r\nHost: example.com\r\n\
+            Transfer-Encoding: chunked\r\nExpect: 100-continue\r\nConnection: close\r\n\r\n\
+            4\r\nping\r\n0\r\nX-Trailer: 1\r\n\r\n";
+        let mut buf = vec![0u8; expected.len()];
+        backend.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, expected);
+
+        backend
+            .write_all(
+                b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\nKeep-Alive: timeout=5\r\n\
+                  Transfer-Encoding: chunked\r\n\r\n4\r\npong\r\n0\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        drop(backend);
+
+        let mut buf = vec![];
+        client.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\
+             Connection: close\r\n\r\n4\r\npong\r\n0\r\n\r\n"
+        );
+        let summary = proxy.await.unwrap();
+        assert_eq!(summary.reason, Reason::BackendClosed);
+        assert_eq!(summary.bytes_up, expected.len() as u64);
+    }
+
+    #[tokio::test]
+    async fn test_forward_bad_response() {
+        let forward_request = request(b"GET http://example.com/ HTTP/1.1\r\n\r\n");
+        let (mut client, client_end) = duplex(1024);
+        let (backend_end, mut backend) = duplex(1024);
+        let proxy = tokio::spawn(async move {
+            let registration = REGISTRY.register(&AccessRecord::new(None));
+            forward(
+                BufReader::new(client_end),
+                vec![],
+                backend_end,
+                &forward_request,
+                &Hooks::default(),
+                &LimitsConfig::default(),
+                &registration,
+            )
+            .await
+        });
+
+        backend.write_all(b"SSH-2.0-OpenSSH\r\n\r\n").await.unwrap();
+        drop(backend);
+        let mut buf = vec![];
+        client.read_to_end(&mut buf).await.unwrap();
+        assert!(buf.starts_with(b"HTTP/1.1 502 Bad Gateway\r\n"));
+        assert_eq!(proxy.await.unwrap().reason, Reason::Error);
+    }
+
+    /// A hook that replaces response bodies with their length
+    struct BodyLength;
+
+    impl Hook for BodyLength {
+        fn request(&self, _request: &mut Forward) -> Result<(), Response> {
+            Ok(())
+        }
+
+        fn rewrites_responses(&self) -> bool {
+            true
+        }
+
+        fn response(&self, _request: &Forward, body: &mut Vec<u8>) {
+            *body = body.len().to_string().into_bytes();
+        }
+    }
+
+    /// Forward a GET request to a backend that sends `response`, with the `BodyLength`
+    /// hook, returning what the client receives
+    async fn rewritten(response: &'static [u8]) -> String {
+        let forward_request = request(b"GET http://example.com/ HTTP/1.1\r\n\r\n");
+        let (mut client, client_end) = duplex(1024);
+        let (backend_end, mut backend) = duplex(1024);
+        let proxy = tokio::spawn(async move {
+            let registration = REGISTRY.register(&AccessRecord::new(None));
+            let hooks = Hooks::from(vec![Box::new(BodyLength) as Box<dyn Hook>]);
+            forward(
+                BufReader::new(client_end),
+                vec![],
+                backend_end,
+                &forward_request,
+                &hooks,
+                &LimitsConfig::default(),
+                &registration,
+            )
+            .await
+        });
+        backend.write_all(response).await.unwrap();
+        drop(backend);
+        let mut buf = vec![];
+        client.read_to_end(&mut buf).await.unwrap();
+        proxy.await.unwrap();
+        String::from_utf8(buf).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_forward_rewrite() {
+        assert_eq!(
+            rewritten(
+                b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+                  4\r\npong\r\n3\r\n!!!\r\n0\r\n\r\n"
+            )
+            .await,
+            "HTTP/1.1 200 OK\r\nContent-Length: 1\r\nConnection: close\r\n\r\n7"
+        );
+        assert_eq!(
+            rewritten(b"HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\npong").await,
+            "HTTP/1.1 200 OK\r\nContent-Length: 1\r\nConnection: close\r\n\r\n4"
+        );
+        // only successful responses are rewritten
+        assert_eq!(
+            rewritten(b"HTTP/1.1 404 Not Found\r\nContent-Length: 4\r\n\r\nnope").await,
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 4\r\nConnection: close\r\n\r\nnope"
+        );
+        assert!(rewritten(
+            b"HTTP/1.1 200 OK\r\nContent-Encoding: gzip\r\nContent-Length: 4\r\n\r\n\x1f\x8b.."
+        )
+        .await
+        .starts_with("HTTP/1.1 502 Bad Gateway\r\n"));
+        // Content-Length must be all digits, as for requests
+        assert!(
+            rewritten(b"HTTP/1.1 200 OK\r\nContent-Length: +4\r\n\r\npong")
+                .await
+                .starts_with("HTTP/1.1 502 Bad Gateway\r\n")
+        );
+    }
+}
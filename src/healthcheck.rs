@@ -0,0 +1,180 @@
+//! Active health checks of the backend.  A background task periodically connects to the
+//! backend's configured host and port, optionally completing a TLS handshake, and records
+//! the outcome and latency of each check.  After enough consecutive failures the backend is
+//! considered unhealthy, which the admin server's `/readyz` endpoint reports, until a check
+//! succeeds again.  Repeated failures are logged with increasing severity.
+
+use crate::backend::Backend;
+use crate::config::{BackendConfig, HealthCheckConfig};
+use crate::metrics::METRICS;
+use crate::telemetry::spawn;
+use crate::tls::TlsBackend;
+use anyhow::Result;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+use tokio::time::timeout;
+
+/// The state of the backend's health checks
+pub(crate) struct HealthCheck {
+    config: HealthCheckConfig,
+    /// The number of consecutive failed checks
+    failures: AtomicU32,
+}
+
+impl HealthCheck {
+    pub(crate) fn new(config: HealthCheckConfig) -> Self {
+        Self {
+            config,
+            failures: AtomicU32::new(0),
+        }
+    }
+
+    /// Whether the backend is healthy: fewer than `unhealthy_threshold` of the most recent
+    /// checks have failed.  The backend is healthy until the first check completes.
+    pub(crate) fn healthy(&self) -> bool {
+        self.failures() < self.config.unhealthy_threshold
+    }
+
+    /// The number of consecutive failed checks
+    pub(crate) fn failures(&self) -> u32 {
+        self.failures.load(Ordering::Relaxed)
+    }
+
+    /// Begin checking `config.host` and `config.port` through `backend` every `interval`,
+    /// in a background task, until this is dropped.  The first check is made immediately.
+    pub(crate) fn start<B: Backend + 'static>(
+        self: &Arc<Self>,
+        backend: B,
+        config: &BackendConfig,
+    ) -> Result<()> {
+        if self.config.tls {
+            self.spawn_checks(TlsBackend::new(backend, config)?, config);
+        } else {
+            self.spawn_checks(backend, config);
+        }
+        Ok(())
+    }
+
+    fn spawn_checks<B: Backend + 'static>(self: &Arc<Self>, backend: B, config: &BackendConfig) {
+        let weak: Weak<Self> = Arc::downgrade(self);
+        let (host, port) = (config.host.clone(), config.port);
+        let (interval, check_timeout) = (self.config.interval, self.config.timeout);
+        spawn("health-check", async move {
+            let mut interval = tokio::time::interval(interval);
+            loop {
+                interval.tick().await;
+                let start = Instant::now();
+                let result = match timeout(check_timeout, backend.connect(&host, port)).await {
+                    Ok(Ok(_)) => Ok(()),
+                    Ok(Err(e)) => Err(e.to_string()),
+                    Err(_) => Err(format!("timed out after {:?}", check_timeout)),
+                };
+                match weak.upgrade() {
+                    Some(check) => check.record(result, start.elapsed()),
+                    None => return,
+                }
+            }
+        });
+    }
+
+    /// Record the result of a check that took `latency`
+    fn record(&self, result: Result<(), String>, latency: Duration) {
+        let threshold = self.config.unhealthy_threshold;
+        match result {
+            Ok(()) => {
+                METRICS.health_checks.inc();
+                METRICS.health_check_latency.observe(latency);
+                METRICS.backend_healthy.set(1);
+                let failures = self.failures.swap(0, Ordering::Relaxed);
+                if failures >= threshold {
+                    log::info!(
+                        "backend healthy again after {} failed health checks",
+                        failures
+                    );
+                } else if failures > 0 {
+                    log::debug!("backend health check succeeded after {} failures", failures);
+                }
+            }
+            Err(e) => {
+                METRICS.health_check_failures.inc();
+                let failures = self.failures.fetch_add(1, Ordering::Relaxed) + 1;
+                if failures < threshold {
+                    log::info!(
+                        "backend health check failed ({} of {}): {}",
+                        failures,
+                        threshold,
+                        e
+                    );
+                } else if failures == threshold {
+                    METRICS.backend_healthy.set(0);
+                    log::warn!(
+                        "backend unhealthy after {} failed health checks: {}",
+                        failures,
+                        e
+                    );
+                } else {
+                    log::error!(
+                        "backend still unhealthy after {} failed health checks: {}",
+                        failures,
+                        e
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::backend::SingleHostBackend;
+    use tokio::net::TcpListener;
+
+    fn new_check(unhealthy_threshold: u32) -> Arc<HealthCheck> {
+        Arc::new(HealthCheck::new(HealthCheckConfig {
+            interval: Duration::from_millis(10),
+            timeout: Duration::from_millis(100),
+            tls: false,
+            unhealthy_threshold,
+        }))
+    }
+
+    #[test]
+    fn test_record() {
+        let check = new_check(2);
+        assert!(check.healthy());
+        check.record(Err("refused".into()), Duration::ZERO);
+        assert!(check.healthy());
+        check.record(Err("refused".into()), Duration::ZERO);
+        assert!(!check.healthy());
+        check.record(Err("refused".into()), Duration::ZERO);
+        assert_eq!(check.failures(), 3);
+        check.record(Ok(()), Duration::from_millis(5));
+        assert!(check.healthy());
+        assert_eq!(check.failures(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_start() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let config = BackendConfig {
+            host: "127.0.0.1".into(),
+            port,
+            ..BackendConfig::default()
+        };
+        let check = new_check(2);
+        check
+            .start(SingleHostBackend::new("127.0.0.1", port), &config)
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(check.healthy());
+        assert_eq!(check.failures(), 0);
+
+        // once the listener is closed, the checks fail
+        drop(listener);
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert!(!check.healthy());
+    }
+}
@@ -0,0 +1,418 @@
+//! Hooks for requests the proxy can read in full: those to the reverse proxy, and those in
+//! intercepted TLS tunnels.  Each request passes through the hooks in order before it is
+//! sent to the backend, and any hook may log it, rewrite it, or refuse it.  Hooks may also
+//! rewrite the bodies of successful responses, in which case those responses are read in
+//! full before being relayed to the client.  The hooks also hold the response cache, if
+//! any, so that cacheable responses are read in full and stored as they are relayed.
+
+use crate::cache::ResponseCache;
+use crate::config::{Config, KeyLocation, Rating, ReverseConfig};
+use crate::forward::Forward;
+use crate::http::Response;
+use serde_json::Value;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// A hook applied to each request before it is sent to the backend
+pub(crate) trait Hook: Send + Sync {
+    /// Inspect or rewrite the request, or refuse it by returning the response to send
+    /// instead
+    fn request(&self, request: &mut Forward) -> Result<(), Response>;
+
+    /// Does this hook rewrite response bodies?
+    fn rewrites_responses(&self) -> bool {
+        false
+    }
+
+    /// Rewrite the body of a successful (200) response to the given request
+    fn response(&self, _request: &Forward, _body: &mut Vec<u8>) {}
+}
+
+/// An ordered list of hooks, with the response cache
+#[derive(Default)]
+pub(crate) struct Hooks {
+    hooks: Vec<Box<dyn Hook>>,
+    cache: Option<Arc<ResponseCache>>,
+}
+
+impl Hooks {
+    /// The hooks for the reverse proxy, which adds the API key to each request
+    pub(crate) fn reverse(config: &Config) -> Self {
+        let mut hooks: Vec<Box<dyn Hook>> = vec![Box::new(ApiKey::new(&config.reverse))];
+        if let Some(max) = config.content.max_rating {
+            hooks.push(Box::new(RatingFilter(max)));
+        }
+        Self::from(hooks)
+    }
+
+    /// The hooks for intercepted requests, as configured in the `mitm` section.  Requests
+    /// are logged as the client sent them, before any are refused or rewritten.
+    pub(crate) fn mitm(config: &Config) -> Self {
+        let mitm = &config.mitm;
+        let mut hooks: Vec<Box<dyn Hook>> = vec![];
+        if mitm.log_requests {
+            hooks.push(Box::new(LogRequests));
+        }
+        if !mitm.deny_paths.is_empty() {
+            hooks.push(Box::new(DenyPaths(mitm.deny_paths.clone())));
+        }
+        if mitm.inject_api_key {
+            hooks.push(Box::new(ApiKey::new(&config.reverse)));
+        }
+        if let Some(max) = config.content.max_rating {
+            hooks.push(Box::new(RatingFilter(max)));
+        }
+        Self::from(hooks)
+    }
+
+    /// Answer cacheable requests from `cache` where possible, and store the responses to
+    /// them
+    pub(crate) fn set_cache(&mut self, cache: Arc<ResponseCache>) {
+        self.cache = Some(cache);
+    }
+
+    /// Apply each hook in turn, stopping at the first to refuse the request.  Cacheable
+    /// requests then have any `Accept-Encoding` header removed, since encoded responses
+    /// cannot be read and cached.
+    pub(crate) fn apply(&self, request: &mut Forward) -> Result<(), Response> {
+        self.hooks
+            .iter()
+            .try_for_each(|hook| hook.request(request))?;
+        if self.cache_key(request).is_some() {
+            request.headers.remove("Accept-Encoding");
+        }
+        Ok(())
+    }
+
+    /// Get the cached response to a request, if there is one
+    pub(crate) fn cached(&self, request: &Forward) -> Option<Vec<u8>> {
+        self.cache.as_ref()?.lookup(request, SystemTime::now())
+    }
+
+    /// Must a successful response to this request be read in full, to be rewritten by any
+    /// hook or stored in the cache?
+    pub(crate) fn reads_responses(&self, request: &Forward) -> bool {
+        self.hooks.iter().any(|hook| hook.rewrites_responses()) || self.cache_key(request).is_some()
+    }
+
+    /// Apply each hook to the body of a successful response, in turn
+    pub(crate) fn rewrite_response(&self, request: &Forward, body: &mut Vec<u8>) {
+        for hook in &self.hooks {
+            hook.response(request, body);
+        }
+    }
+
+    /// Store a successful response in the cache, given the head lines received from the
+    /// backend and the rewritten head and body relayed to the client
+    pub(crate) fn store_response(
+        &self,
+        request: &Forward,
+        lines: &[Vec<u8>],
+        head: &[u8],
+        body: &[u8],
+    ) {
+        if let Some(cache) = &self.cache {
+            cache.store(request, lines, head, body, SystemTime::now());
+        }
+    }
+
+    fn cache_key(&self, request: &Forward) -> Option<String> {
+        self.cache.as_ref()?.key(request)
+    }
+}
+
+impl From<Vec<Box<dyn Hook>>> for Hooks {
+    fn from(hooks: Vec<Box<dyn Hook>>) -> Self {
+        Self { hooks, cache: None }
+    }
+}
+
+/// Log each request's method and URL
+struct LogRequests;
+
+impl Hook for LogRequests {
+    fn request(&self, request: &mut Forward) -> Result<(), Response> {
+        log::info!(
+            "intercepted {} https://{}{}",
+            request.method,
+            request.host,
+            request.path
+        );
+        Ok(())
+    }
+}
+
+/// Refuse requests whose paths begin with any of these prefixes
+struct DenyPaths(Vec<String>);
+
+impl Hook for DenyPaths {
+    fn request(&self, request: &mut Forward) -> Result<(), Response> {
+        if self.0.iter().any(|prefix| request.path.starts_with(prefix)) {
+            return Err(Response::error(403, "Forbidden", "request not allowed"));
+        }
+        Ok(())
+    }
+}
+
+/// Add the API key to each request, replacing any the client sent
+struct ApiKey {
+    location: KeyLocation,
+    name: String,
+    key: String,
+}
+
+impl ApiKey {
+    fn new(reverse: &ReverseConfig) -> Self {
+        Self {
+            location: reverse.key_location,
+            name: reverse.key_name.clone(),
+            // validated to be set when the key is to be added
+            key: reverse.api_key.clone().unwrap_or_default(),
+        }
+    }
+}
+
+impl Hook for ApiKey {
+    fn request(&self, request: &mut Forward) -> Result<(), Response> {
+        match self.location {
+            KeyLocation::Query => {
+                request.path = with_query_param(&request.path, &self.name, &self.key)
+            }
+            KeyLocation::Header => request.headers.set(self.name.as_str(), self.key.as_str()),
+        }
+        Ok(())
+    }
+}
+
+/// Limit the content rating of API results: requests ask for no higher rating than the
+/// maximum, and GIFs with a higher rating, or none, are removed from responses.  Other
+/// results, such as categories, are left alone.  Compressed responses cannot be rewritten,
+/// so requests do not accept them.
+struct RatingFilter(Rating);
+
+impl Hook for RatingFilter {
+    fn request(&self, request: &mut Forward) -> Result<(), Response> {
+        let requested = query_param(&request.path, "rating").and_then(Rating::parse);
+        let rating = match requested {
+            Some(rating) if rating < self.0 => rating,
+            _ => self.0,
+        };
+        request.path = with_query_param(&request.path, "rating", rating.as_str());
+        request.headers.remove("Accept-Encoding");
+        Ok(())
+    }
+
+    fn rewrites_responses(&self) -> bool {
+        true
+    }
+
+    fn response(&self, _request: &Forward, body: &mut Vec<u8>) {
+        // responses that are not JSON, such as images, contain no results
+        let mut response: Value = match serde_json::from_slice(body) {
+            Ok(response) => response,
+            Err(_) => return,
+        };
+        let allowed = |item: &Value| {
+            let gif = item.get("images").is_some() || item.get("rating").is_some();
+            !gif || item
+                .get("rating")
+                .and_then(Value::as_str)
+                .and_then(Rating::parse)
+                .is_some_and(|rating| rating <= self.0)
+        };
+        let removed = match response.get_mut("data") {
+            Some(Value::Array(items)) => {
+                let count = items.len();
+                items.retain(allowed);
+                count - items.len()
+            }
+            // endpoints for a single GIF return it as an object
+            Some(item @ Value::Object(_)) if !allowed(item) => {
+                *item = Value::Object(Default::default());
+                1
+            }
+            _ => 0,
+        };
+        if removed == 0 {
+            return;
+        }
+        if let Some(Value::Number(count)) = response.pointer_mut("/pagination/count") {
+            if let Some(n) = count.as_u64() {
+                *count = n.saturating_sub(removed as u64).into();
+            }
+        }
+        *body = serde_json::to_vec(&response).expect("JSON values can be serialized");
+    }
+}
+
+/// Get the value of a query parameter in a path, if it is present
+fn query_param<'a>(path: &'a str, name: &str) -> Option<&'a str> {
+    let (_, query) = path.split_once('?')?;
+    query
+        .split('&')
+        .filter_map(|param| param.split_once('='))
+        .find(|(param_name, _)| *param_name == name)
+        .map(|(_, value)| value)
+}
+
+/// Set a query parameter in a path, replacing any existing values for it
+fn with_query_param(path: &str, name: &str, value: &str) -> String {
+    let (path, query) = match path.split_once('?') {
+        Some((path, query)) => (path, query),
+        None => (path, ""),
+    };
+    let encoded_name = percent_encode(name);
+    let mut params: Vec<String> = query
+        .split('&')
+        .filter(|param| {
+            let param_name = param.split('=').next().unwrap_or("");
+            !param.is_empty() && param_name != name && param_name != encoded_name
+        })
+        .map(str::to_owned)
+        .collect();
+    params.push(format!("{}={}", encoded_name, percent_encode(value)));
+    format!("{}?{}", path, params.join("&"))
+}
+
+/// Percent-encode everything but the unreserved characters of RFC 3986
+fn percent_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::http::{parse_origin, ParseOptions};
+
+    fn request(head: &[u8]) -> Forward {
+        Forward::from_origin(
+            parse_origin(head, &ParseOptions::default()).unwrap(),
+            "api.giphy.com".into(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_with_query_param() {
+        assert_eq!(
+            with_query_param("/v1/gifs", "api_key", "k"),
+            "/v1/gifs?api_key=k"
+        );
+        assert_eq!(
+            with_query_param(
+                "/v1/gifs/search?q=cat&api_key=wrong&limit=1",
+                "api_key",
+                "k"
+            ),
+            "/v1/gifs/search?q=cat&limit=1&api_key=k"
+        );
+        assert_eq!(with_query_param("/?", "key", "a b&c"), "/?key=a%20b%26c");
+    }
+
+    #[test]
+    fn test_query_param() {
+        assert_eq!(
+            query_param("/v1/gifs?q=cat&rating=pg", "rating"),
+            Some("pg")
+        );
+        assert_eq!(query_param("/v1/gifs?q=cat", "rating"), None);
+        assert_eq!(query_param("/v1/gifs", "rating"), None);
+    }
+
+    #[test]
+    fn test_api_key_header() {
+        let hooks = Hooks::from(vec![Box::new(ApiKey {
+            location: KeyLocation::Header,
+            name: "X-Api-Key".into(),
+            key: "secret".into(),
+        }) as Box<dyn Hook>]);
+        let mut request = request(b"GET /v1/gifs?q=cat HTTP/1.1\r\nx-api-key: wrong\r\n\r\n");
+        hooks.apply(&mut request).unwrap();
+        assert_eq!(request.path, "/v1/gifs?q=cat");
+        assert_eq!(request.headers.get("X-Api-Key"), Some("secret"));
+        assert_eq!(request.headers.len(), 1);
+    }
+
+    #[test]
+    fn test_mitm_hooks() {
+        let mut config = Config::default();
+        config.mitm.deny_paths = vec!["/v1/stickers".into()];
+        config.mitm.inject_api_key = true;
+        config.reverse.api_key = Some("secret".into());
+        let hooks = Hooks::mitm(&config);
+
+        let mut allowed = request(b"GET /v1/gifs/trending HTTP/1.1\r\n\r\n");
+        hooks.apply(&mut allowed).unwrap();
+        assert_eq!(allowed.path, "/v1/gifs/trending?api_key=secret");
+
+        let mut denied = request(b"GET /v1/stickers/trending HTTP/1.1\r\n\r\n");
+        assert_eq!(hooks.apply(&mut denied).unwrap_err().status, 403);
+        assert_eq!(denied.path, "/v1/stickers/trending");
+    }
+
+    #[test]
+    fn test_rating_request() {
+        let filter = RatingFilter(Rating::Pg);
+        let rewrite = |head: &[u8]| {
+            let mut request = request(head);
+            filter.request(&mut request).unwrap();
+            request
+        };
+        let request =
+            rewrite(b"GET /v1/gifs/search?q=cat HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n");
+        assert_eq!(request.path, "/v1/gifs/search?q=cat&rating=pg");
+        assert_eq!(request.headers.get("Accept-Encoding"), None);
+        // stricter ratings are kept, and others lowered
+        let request = rewrite(b"GET /v1/gifs/search?q=cat&rating=g HTTP/1.1\r\n\r\n");
+        assert_eq!(request.path, "/v1/gifs/search?q=cat&rating=g");
+        let request = rewrite(b"GET /v1/gifs/search?rating=r&q=cat HTTP/1.1\r\n\r\n");
+        assert_eq!(request.path, "/v1/gifs/search?q=cat&rating=pg");
+    }
+
+    #[test]
+    fn test_rating_response() {
+        let filter = RatingFilter(Rating::Pg);
+        let request = request(b"GET /v1/gifs/search?q=cat HTTP/1.1\r\n\r\n");
+        let rewrite = |body: Value| {
+            let mut body = serde_json::to_vec(&body).unwrap();
+            filter.response(&request, &mut body);
+            serde_json::from_slice::<Value>(&body).unwrap()
+        };
+
+        let body = rewrite(serde_json::json!({
+            "data": [
+                {"id": "a", "rating": "g"},
+                {"id": "b", "rating": "r"},
+                {"id": "c", "rating": "pg"},
+                {"id": "d", "images": {}},
+                {"name": "cats"},
+            ],
+            "pagination": {"count": 5, "offset": 0},
+        }));
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "data": [
+                    {"id": "a", "rating": "g"},
+                    {"id": "c", "rating": "pg"},
+                    {"name": "cats"},
+                ],
+                "pagination": {"count": 3, "offset": 0},
+            })
+        );
+
+        let body = rewrite(serde_json::json!({"data": {"id": "b", "rating": "pg-13"}}));
+        assert_eq!(body, serde_json::json!({"data": {}}));
+
+        let mut body = b"GIF89a".to_vec();
+        filter.response(&request, &mut body);
+        assert_eq!(body, b"GIF89a");
+    }
+}
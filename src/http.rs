@@ -1,20 +1,92 @@
-use anyhow::{anyhow, Error, Result};
+use crate::config::LimitsConfig;
+use crate::error::{ProxyError, Result};
 use nom::{
-    bytes::streaming::{tag, take_while, take_while1},
-    character::{is_alphanumeric, is_digit},
-    combinator::{map_res, value},
+    branch::alt,
+    bytes::streaming::{tag, tag_no_case, take_until, take_while, take_while1},
+    character::{is_alphanumeric, is_digit, is_hex_digit},
+    combinator::{map, map_res, peek, value, verify},
+    error::ErrorKind,
     multi::many0,
-    sequence::tuple,
+    sequence::{delimited, preceded, terminated, tuple},
 };
-use nom::{Err, IResult};
+use nom::{Err, IResult, Needed};
+use std::net::Ipv6Addr;
+use std::num::ParseIntError;
+use std::str::Utf8Error;
 
+/// Request headers, in the order they were given.  Names may repeat.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Headers(Vec<(String, String)>);
+
+impl Headers {
+    /// Get the value of the first header with the given name, ignoring case
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Iterate over (name, value) pairs
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0.iter().map(|(n, v)| (n.as_str(), v.as_str()))
+    }
+
+    /// The number of headers
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Set a header, replacing any headers with the same name, ignoring case
+    pub fn set<N: Into<String>, V: Into<String>>(&mut self, name: N, value: V) {
+        let name = name.into();
+        self.0.retain(|(n, _)| !n.eq_ignore_ascii_case(&name));
+        self.0.push((name, value.into()));
+    }
+
+    /// Remove all headers with the given name, ignoring case
+    pub fn remove(&mut self, name: &str) {
+        self.0.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+    }
+}
+
+impl From<Vec<(String, String)>> for Headers {
+    fn from(headers: Vec<(String, String)>) -> Self {
+        Self(headers)
+    }
+}
+
+/// The result of parsing a (possibly partial) request head with `parse_head`.
 #[derive(Debug)]
 pub enum ParseHeadResult {
     /// Successful parse
-    Connect { host: String, port: u16 },
+    Connect {
+        host: String,
+        port: u16,
+        /// `HTTP/1.1` or `HTTP/1.0`
+        version: String,
+        headers: Headers,
+        /// Length of the head; any input after this was sent after the request
+        len: usize,
+    },
+
+    /// A complete request head with a method other than CONNECT, which is not supported
+    OtherMethod {
+        method: String,
+        /// Length of the head
+        len: usize,
+    },
+
+    /// The head, complete or not, exceeds one of the limits in `ParseOptions`; the string
+    /// says which
+    TooLarge(&'static str),
 
     /// Unrecoverable error
-    Err(Error),
+    Err(ProxyError),
 
     /// Valid so far, but incomplete
     Incomplete,
@@ -26,131 +98,654 @@ impl PartialEq for ParseHeadResult {
     fn eq(&self, other: &Self) -> bool {
         match (self, other) {
             (Incomplete, Incomplete) => true,
-            (Connect { host: h1, port: p1 }, Connect { host: h2, port: p2 })
-                if h1 == h2 && p1 == p2 =>
-            {
-                true
-            }
-            // note that errors always compare inequal (anyhow::Error does not support PartialEq)
+            (
+                Connect {
+                    host: h1,
+                    port: p1,
+                    version: v1,
+                    headers: hd1,
+                    len: l1,
+                },
+                Connect {
+                    host: h2,
+                    port: p2,
+                    version: v2,
+                    headers: hd2,
+                    len: l2,
+                },
+            ) if h1 == h2 && p1 == p2 && v1 == v2 && hd1 == hd2 && l1 == l2 => true,
+            (
+                OtherMethod {
+                    method: m1,
+                    len: l1,
+                },
+                OtherMethod {
+                    method: m2,
+                    len: l2,
+                },
+            ) if m1 == m2 && l1 == l2 => true,
+            (TooLarge(w1), TooLarge(w2)) => w1 == w2,
+            // note that errors always compare inequal (ProxyError does not support PartialEq)
             _ => false,
         }
     }
 }
 
-/// Parse an HTTP request head.
+/// A plain HTTP request in absolute form, such as `GET http://example.com/ HTTP/1.1`, as
+/// sent to a forward proxy
+#[derive(Debug, Clone, PartialEq)]
+pub struct AbsoluteRequest {
+    pub method: String,
+    pub host: String,
+    pub port: u16,
+    /// The path and query, which form the request target in origin form
+    pub path: String,
+    /// `HTTP/1.1` or `HTTP/1.0`
+    pub version: String,
+    pub headers: Headers,
+}
+
+/// A plain HTTP request in origin form, such as `GET /v1/gifs HTTP/1.1`, as sent to a
+/// server
+#[derive(Debug, Clone, PartialEq)]
+pub struct OriginRequest {
+    pub method: String,
+    /// The path and query
+    pub path: String,
+    /// `HTTP/1.1` or `HTTP/1.0`
+    pub version: String,
+    pub headers: Headers,
+}
+
+/// An HTTP response to a CONNECT request.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Response {
+    /// `HTTP/1.1` unless set to match the request with `version`
+    pub version: String,
+    pub status: u16,
+    pub reason: &'static str,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl Response {
+    /// A response with the given status and no headers or body
+    pub fn new(status: u16, reason: &'static str) -> Self {
+        Self {
+            version: "HTTP/1.1".into(),
+            status,
+            reason,
+            headers: vec![],
+            body: String::new(),
+        }
+    }
+
+    /// The response to a successful CONNECT.  This has no headers, and after it is
+    /// written the connection becomes a tunnel.
+    pub fn ok() -> Self {
+        Self::new(200, "OK")
+    }
+
+    /// An error response with a short plain-text body.  The connection will be closed
+    /// after this response, and the headers say so.
+    pub fn error<B: Into<String>>(status: u16, reason: &'static str, body: B) -> Self {
+        let mut body = body.into();
+        body.push('\n');
+        Self::new(status, reason)
+            .body("text/plain", body)
+            .header("Connection", "close")
+    }
+
+    /// Set the HTTP version of this response, to match that of the request
+    pub fn version<V: Into<String>>(mut self, version: V) -> Self {
+        self.version = version.into();
+        self
+    }
+
+    /// Add a header to this response
+    pub fn header<N: Into<String>, V: Into<String>>(mut self, name: N, value: V) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// Set the body of this response, adding `Content-Type` and `Content-Length` headers
+    pub fn body<B: Into<String>>(self, content_type: &str, body: B) -> Self {
+        let body = body.into();
+        let mut response = self
+            .header("Content-Type", content_type)
+            .header("Content-Length", body.len().to_string());
+        response.body = body;
+        response
+    }
+
+    /// Serialize this response for transmission
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut s = format!("{} {} {}\r\n", self.version, self.status, self.reason);
+        for (name, value) in &self.headers {
+            s.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        s.push_str("\r\n");
+        s.push_str(&self.body);
+        s.into_bytes()
+    }
+}
+
+/// Format a host and port as a URI authority, `host:port`, bracketing IPv6 addresses as
+/// in `[::1]:443`
+pub fn authority(host: &str, port: u16) -> String {
+    if host.contains(':') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+/// Limits on, and leniency in, the parsing of request heads
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseOptions {
+    /// Maximum number of headers
+    pub max_headers: usize,
+
+    /// Maximum length of the request line or of any header line, excluding its line ending
+    pub max_line_length: usize,
+
+    /// Accept lines ending in a bare `\n`, as sent by some hand-written clients, as well as
+    /// `\r\n`
+    pub allow_bare_lf: bool,
+
+    /// Accept only RFC 1123 hostnames in request targets, rejecting underscores and hyphens
+    /// at the ends of labels (see `dns_name`)
+    pub strict_hostnames: bool,
+
+    /// The port for CONNECT requests that give only a host, such as `CONNECT api.giphy.com
+    /// HTTP/1.1`
+    pub default_port: u16,
+}
+
+impl Default for ParseOptions {
+    fn default() -> Self {
+        Self::from(&LimitsConfig::default())
+    }
+}
+
+impl From<&LimitsConfig> for ParseOptions {
+    fn from(limits: &LimitsConfig) -> Self {
+        Self {
+            max_headers: limits.max_headers,
+            max_line_length: limits.max_header_line,
+            allow_bare_lf: limits.allow_bare_lf,
+            strict_hostnames: limits.strict_hostnames,
+            default_port: limits.default_connect_port,
+        }
+    }
+}
+
+/// Parse an HTTP request head.  Input after the end of the head, such as the start of a
+/// TLS handshake that a client sent without waiting for the response, is not parsed.
+///
+/// This is *severely* limited to accept HTTP/1.1 and HTTP/1.0 CONNECT requests with simple
+/// headers, and nothing else.  Depending on requirements, this could easily be expanded to
+/// be more permissive.  Requests with other methods are recognized, without being parsed
+/// beyond the method, so that they can be refused.
 ///
-/// This is *severely* limited to accept HTTP/1.1 CONNECT requests, allowing but ignoring simple
-/// headers, and nothing else.  Depending on requirements, this could easily be expanded to be more
-/// permissive.
-pub fn parse_head(input: &[u8]) -> ParseHeadResult {
-    match parse_connect(input) {
-        IResult::Ok((remaining, output)) if remaining.len() == 0 => Connect {
-            host: output.0.to_owned(),
-            port: output.1,
-        },
-        IResult::Ok(_) => Err(anyhow!("extra bytes in head")),
+/// The limits in `options` are checked as the head arrives, so a head that exceeds them is
+/// rejected as `TooLarge` without waiting for the rest of it.
+pub fn parse_head(input: &[u8], options: &ParseOptions) -> ParseHeadResult {
+    if let Some(limit) = exceeded_limit(input, options) {
+        return TooLarge(limit);
+    }
+    let bare_lf = options.allow_bare_lf;
+    match other_method(input, bare_lf) {
+        IResult::Ok((rest, method)) => {
+            return OtherMethod {
+                method,
+                len: input.len() - rest.len(),
+            }
+        }
+        IResult::Err(Err::Incomplete(_)) => return Incomplete,
+        // this is a CONNECT request, or not a request at all
+        IResult::Err(_) => (),
+    }
+    match parse_connect(input, options) {
+        IResult::Ok((rest, ((host, port), version, headers))) => {
+            match dns_name(host, options.strict_hostnames) {
+                Ok(host) => Connect {
+                    host,
+                    port,
+                    version,
+                    headers,
+                    len: input.len() - rest.len(),
+                },
+                Result::Err(e) => Err(e),
+            }
+        }
         IResult::Err(Err::Incomplete(_)) => Incomplete,
-        IResult::Err(Err::Failure(e)) => Err(anyhow!(
-            "bad request: {:?} (input: {})",
-            e,
-            String::from_utf8_lossy(e.input)
-        )),
-        IResult::Err(Err::Error(e)) => Err(anyhow!(
-            "bad request: {:?} (input: {})",
-            e,
-            String::from_utf8_lossy(e.input)
-        )),
+        IResult::Err(Err::Failure(e)) | IResult::Err(Err::Error(e)) => Err(bad_request(e)),
     }
 }
 
+/// Parse a complete request head in absolute form, as recognized by `parse_head` as
+/// `OtherMethod` with the same `options`.  Only `http` URIs are supported.
+pub fn parse_absolute(head: &[u8], options: &ParseOptions) -> Result<AbsoluteRequest> {
+    match absolute_request(head, options.allow_bare_lf) {
+        IResult::Ok((_, mut request)) => {
+            request.host = dns_name(request.host, options.strict_hostnames)?;
+            Ok(request)
+        }
+        IResult::Err(Err::Incomplete(_)) => {
+            Result::Err(ProxyError::Parse("incomplete request head".into()))
+        }
+        IResult::Err(Err::Failure(e)) | IResult::Err(Err::Error(e)) => Result::Err(bad_request(e)),
+    }
+}
+
+/// Parse a complete request head in origin form, such as `GET /v1/gifs HTTP/1.1`, as
+/// recognized by `parse_head` as `OtherMethod` with the same `options`.
+pub fn parse_origin(head: &[u8], options: &ParseOptions) -> Result<OriginRequest> {
+    match origin_request(head, options.allow_bare_lf) {
+        IResult::Ok((_, request)) => Ok(request),
+        IResult::Err(Err::Incomplete(_)) => {
+            Result::Err(ProxyError::Parse("incomplete request head".into()))
+        }
+        IResult::Err(Err::Failure(e)) | IResult::Err(Err::Error(e)) => Result::Err(bad_request(e)),
+    }
+}
+
+/// Check that a host parsed from a request target is a plausible DNS name, returning it
+/// without any trailing root dot.  The name must be at most 253 characters, in labels of
+/// 1 to 63 characters.  Labels may contain underscores, as in `_service.example.com`, and
+/// hyphens anywhere, unless `strict` is set, in which case they must be RFC 1123 labels of
+/// letters, digits, and interior hyphens, and only IDNA labels (`xn--`) may have hyphens in
+/// the third and fourth positions.  IPv6 addresses are returned unchanged.
+pub(crate) fn dns_name(host: String, strict: bool) -> Result<String> {
+    if host.contains(':') {
+        return Ok(host);
+    }
+    let invalid = |why: &str| ProxyError::Parse(format!("invalid hostname {:?}: {}", host, why));
+    let name = host.strip_suffix('.').unwrap_or(&host);
+    if name.is_empty() {
+        return Result::Err(invalid("empty"));
+    }
+    if name.len() > 253 {
+        return Result::Err(invalid("longer than 253 characters"));
+    }
+    for label in name.split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Result::Err(invalid("labels must have 1 to 63 characters"));
+        }
+        if strict {
+            if label.contains('_') || label.starts_with('-') || label.ends_with('-') {
+                return Result::Err(invalid("not an RFC 1123 hostname"));
+            }
+            let reserved = label.get(2..4) == Some("--");
+            if reserved && !label.to_ascii_lowercase().starts_with("xn--") {
+                return Result::Err(invalid("hyphens in the third and fourth positions"));
+            }
+        }
+    }
+    Ok(name.to_owned())
+}
+
+/// The error for input that a parser rejected
+fn bad_request(e: nom::error::Error<&[u8]>) -> ProxyError {
+    ProxyError::Parse(format!(
+        "bad request: {:?} (input: {})",
+        e,
+        String::from_utf8_lossy(e.input)
+    ))
+}
+
+/// Check the lines of a possibly-incomplete head against the limits in `options`, returning
+/// a description of the first limit exceeded.  Lines after the blank line ending the head
+/// are not checked.
+fn exceeded_limit(input: &[u8], options: &ParseOptions) -> Option<&'static str> {
+    let mut headers = 0;
+    for (i, line) in input.split(|&c| c == b'\n').enumerate() {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.len() > options.max_line_length {
+            return Some("line too long");
+        }
+        if i > 0 {
+            if line.is_empty() {
+                break;
+            }
+            headers += 1;
+            if headers > options.max_headers {
+                return Some("too many headers");
+            }
+        }
+    }
+    None
+}
+
+/// The host, port, version, and headers of a CONNECT request
+type Head = ((String, u16), String, Headers);
+
 /// Recognize a full CONNECT request head (see notes for `parse_head`)
-fn parse_connect(input: &[u8]) -> IResult<&[u8], (&str, u16)> {
-    fn to_tuple<'h>(input: (&[u8], (&'h str, u16), &[u8], (), (), ())) -> Result<(&'h str, u16)> {
-        Ok(input.1)
+fn parse_connect<'i>(input: &'i [u8], options: &ParseOptions) -> IResult<&'i [u8], Head> {
+    type Parts<'i> = (&'i [u8], (String, u16), String, (), Headers, ());
+    fn to_tuple(input: Parts<'_>) -> Head {
+        (input.1, input.2, input.4)
     }
-    map_res(
+    let (bare_lf, default_port) = (options.allow_bare_lf, options.default_port);
+    map(
         tuple((
             tag(b"CONNECT "),
-            hostport,
-            tag(b" HTTP/1.1"),
-            rn,
-            headers,
-            rn,
+            alt((hostport, map(host, move |host| (host, default_port)))),
+            version,
+            newline(bare_lf),
+            headers(bare_lf),
+            newline(bare_lf),
         )),
         to_tuple,
     )(input)
 }
 
-/// Recognize a hostname:port pair.  This is rather conservative, since for this use the only valid
-/// value is `api.giphy.com:443`
-fn hostport(input: &[u8]) -> IResult<&[u8], (&str, u16)> {
-    fn to_tuple<'h>(input: (&'h str, &[u8], u16)) -> Result<(&'h str, u16)> {
-        Ok((input.0, input.2))
+/// Recognize a request method and the space following it
+fn method(input: &[u8]) -> IResult<&[u8], &[u8]> {
+    fn method_char(c: u8) -> bool {
+        c.is_ascii_uppercase() || c == b'-' || c == b'_'
+    }
+    terminated(take_while1(method_char), tag(b" "))(input)
+}
+
+/// Recognize a full request head with a method other than CONNECT, returning the method
+fn other_method(input: &[u8], bare_lf: bool) -> IResult<&[u8], String> {
+    let (rest, method) = method(input)?;
+    if method == b"CONNECT" {
+        return IResult::Err(Err::Error(nom::error::Error::new(input, ErrorKind::Tag)));
     }
-    map_res(tuple((hostname, tag(":"), port)), to_tuple)(input)
+    // the rest of the head is ignored
+    let (rest, _) = rest_of_head(rest, bare_lf)?;
+    // note: unwrap is safe since method_char only accepts ascii
+    Ok((rest, String::from_utf8(method.to_vec()).unwrap()))
 }
 
-/// Parse a hostname as part of a CONNECT request
+/// Recognize the remainder of a request head, through the blank line that ends it
+fn rest_of_head(input: &[u8], bare_lf: bool) -> IResult<&[u8], ()> {
+    if !bare_lf {
+        return value((), terminated(take_until("\r\n\r\n"), tag(b"\r\n\r\n")))(input);
+    }
+    // a blank line is a newline directly followed by another, either of which may be bare
+    let newlines = input.iter().enumerate().filter(|&(_, &c)| c == b'\n');
+    for (i, _) in newlines {
+        match newline(true)(&input[i + 1..]) {
+            IResult::Err(Err::Error(_)) => (),
+            result => return result,
+        }
+    }
+    IResult::Err(Err::Incomplete(Needed::Unknown))
+}
+
+/// Recognize a full request head in absolute form (see `parse_absolute`)
+fn absolute_request(input: &[u8], bare_lf: bool) -> IResult<&[u8], AbsoluteRequest> {
+    let (input, method) = method(input)?;
+    let (input, _) = tag_no_case(b"http://")(input)?;
+    let (input, (host, port)) = alt((hostport, map(host, |host| (host, 80))))(input)?;
+    let (input, (path, version, headers)) = request_rest(input, bare_lf)?;
+    let request = AbsoluteRequest {
+        method: ascii_string(method),
+        host,
+        port,
+        path,
+        version,
+        headers,
+    };
+    Ok((input, request))
+}
+
+/// Recognize a full request head in origin form (see `parse_origin`)
+fn origin_request(input: &[u8], bare_lf: bool) -> IResult<&[u8], OriginRequest> {
+    let (input, method) = method(input)?;
+    let (input, _) = peek(tag(b"/"))(input)?;
+    let (input, (path, version, headers)) = request_rest(input, bare_lf)?;
+    let request = OriginRequest {
+        method: ascii_string(method),
+        path,
+        version,
+        headers,
+    };
+    Ok((input, request))
+}
+
+/// Recognize the remainder of a request head after the method and any scheme and
+/// authority: the path and query, the HTTP/1 version, and the headers.  An empty path is
+/// returned as `/`.
+fn request_rest(input: &[u8], bare_lf: bool) -> IResult<&[u8], (String, String, Headers)> {
+    let (input, path) = verify(take_while(|c: u8| c.is_ascii_graphic()), |path: &[u8]| {
+        path.is_empty() || path[0] == b'/' || path[0] == b'?'
+    })(input)?;
+    let (input, version) = version(input)?;
+    let (input, _) = newline(bare_lf)(input)?;
+    let (input, headers) = headers(bare_lf)(input)?;
+    let (input, _) = newline(bare_lf)(input)?;
+
+    let path = match path {
+        [] => "/".to_owned(),
+        [b'?', ..] => format!("/{}", ascii_string(path)),
+        _ => ascii_string(path),
+    };
+    Ok((input, (path, version, headers)))
+}
+
+/// Recognize the space and HTTP/1 version at the end of a request line, along with any
+/// trailing spaces, which some clients send
+fn version(input: &[u8]) -> IResult<&[u8], String> {
+    map(
+        terminated(
+            preceded(tag(b" "), alt((tag(b"HTTP/1.1"), tag(b"HTTP/1.0")))),
+            take_while(|c| c == b' '),
+        ),
+        ascii_string,
+    )(input)
+}
+
+/// Convert input already known to be ascii into a string
+fn ascii_string(input: &[u8]) -> String {
+    // note: unwrap is safe since the callers' parsers accept only ascii
+    std::str::from_utf8(input).unwrap().to_owned()
+}
+
+/// Recognize a host:port pair, where the host is a hostname or a bracketed IPv6 address.
+/// This is rather conservative, since for this use the only valid value is
+/// `api.giphy.com:443`.
+fn hostport(input: &[u8]) -> IResult<&[u8], (String, u16)> {
+    fn to_tuple(input: (String, &[u8], u16)) -> (String, u16) {
+        (input.0, input.2)
+    }
+    map(tuple((host, tag(":"), port)), to_tuple)(input)
+}
+
+/// Recognize a host, either a hostname or a bracketed IPv6 address
+fn host(input: &[u8]) -> IResult<&[u8], String> {
+    fn hostname_string(input: &[u8]) -> IResult<&[u8], String> {
+        map(hostname, str::to_owned)(input)
+    }
+    alt((ipv6_literal, hostname_string))(input)
+}
+
+/// Parse a bracketed IPv6 address, such as `[2606:2800::1]`, into its canonical form
+/// without brackets
+fn ipv6_literal(input: &[u8]) -> IResult<&[u8], String> {
+    fn ipv6_char(c: u8) -> bool {
+        is_hex_digit(c) || c == b':' || c == b'.'
+    }
+    fn to_string(input: &[u8]) -> Result<String, std::net::AddrParseError> {
+        let addr: Ipv6Addr = ascii_string(input).parse()?;
+        Ok(addr.to_string())
+    }
+    map_res(
+        delimited(tag(b"["), take_while1(ipv6_char), tag(b"]")),
+        to_string,
+    )(input)
+}
+
+/// Parse a hostname as part of a CONNECT request.  This only recognizes the characters
+/// that may appear in a hostname; `dns_name` checks the result.
 fn hostname(input: &[u8]) -> IResult<&[u8], &str> {
-    fn to_str(input: &[u8]) -> Result<&str> {
-        Ok(std::str::from_utf8(input)?)
+    fn to_str(input: &[u8]) -> Result<&str, Utf8Error> {
+        std::str::from_utf8(input)
     }
     fn hostname_char(c: u8) -> bool {
-        is_alphanumeric(c) || c == b'.' || c == b'-'
+        is_alphanumeric(c) || c == b'.' || c == b'-' || c == b'_'
     }
     map_res(take_while(hostname_char), to_str)(input)
 }
 
 /// Parse a port number into a u16
 fn port(input: &[u8]) -> IResult<&[u8], u16> {
-    fn to_u16<'h>(input: &[u8]) -> Result<u16> {
-        // note: unwrap is safe since we've confirmed input is just ascii digits
-        Ok(std::str::from_utf8(input).unwrap().parse()?)
+    fn to_u16(input: &[u8]) -> Result<u16, ParseIntError> {
+        ascii_string(input).parse()
     }
     map_res(take_while(is_digit), to_u16)(input)
 }
 
-/// Parse and ignore zero or more headers
-fn headers(input: &[u8]) -> IResult<&[u8], ()> {
-    value((), many0(header))(input)
+/// Parse zero or more headers
+fn headers(bare_lf: bool) -> impl Fn(&[u8]) -> IResult<&[u8], Headers> {
+    move |input| map(many0(header(bare_lf)), Headers::from)(input)
 }
 
-/// Parse and ignore a header.  This does not parse the full generality of headers!
-fn header(input: &[u8]) -> IResult<&[u8], ()> {
+/// Parse a header into its name and value, with surrounding whitespace removed from the
+/// value.  This does not parse the full generality of headers!
+fn header(bare_lf: bool) -> impl Fn(&[u8]) -> IResult<&[u8], (String, String)> {
+    fn name_char(c: u8) -> bool {
+        is_alphanumeric(c) || c == b'-' || c == b'_'
+    }
     fn not_newline(c: u8) -> bool {
         c != b'\r' && c != b'\n'
     }
-    value((), tuple((take_while1(not_newline), rn)))(input)
+    fn to_pair(input: (&[u8], &[u8], &[u8])) -> Result<(String, String), Utf8Error> {
+        let name = std::str::from_utf8(input.0)?;
+        let value = std::str::from_utf8(input.2)?;
+        Ok((name.to_owned(), value.trim().to_owned()))
+    }
+    move |input| {
+        map_res(
+            terminated(
+                tuple((take_while1(name_char), tag(b":"), take_while(not_newline))),
+                newline(bare_lf),
+            ),
+            to_pair,
+        )(input)
+    }
 }
 
-/// Recognize a \r\n sequence
-fn rn(input: &[u8]) -> IResult<&[u8], ()> {
-    value((), tag(b"\r\n"))(input)
+/// Recognize a line ending: a \r\n sequence, or if `bare_lf` is set, also a lone \n
+fn newline(bare_lf: bool) -> impl Fn(&[u8]) -> IResult<&[u8], ()> {
+    move |input| {
+        if bare_lf {
+            value((), alt((tag(b"\r\n"), tag(b"\n"))))(input)
+        } else {
+            value((), tag(b"\r\n"))(input)
+        }
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
 
+    /// Parse a head with the default options
+    fn parse(input: &[u8]) -> ParseHeadResult {
+        parse_head(input, &ParseOptions::default())
+    }
+
+    fn absolute(head: &[u8]) -> Result<AbsoluteRequest> {
+        parse_absolute(head, &ParseOptions::default())
+    }
+
+    fn origin(head: &[u8]) -> Result<OriginRequest> {
+        parse_origin(head, &ParseOptions::default())
+    }
+
     #[test]
     fn test_empty() {
-        assert_eq!(parse_head(b""), Incomplete);
+        assert_eq!(parse(b""), Incomplete);
     }
 
     #[test]
     fn test_prefix() {
-        assert_eq!(parse_head(b"CONNECT foo."), Incomplete);
+        assert_eq!(parse(b"CONNECT foo."), Incomplete);
     }
 
     #[test]
     fn test_bad_prefix() {
-        assert!(matches!(parse_head(b"GET"), Err(_)));
+        assert!(matches!(parse(b"get / HTTP/1.1\r\n"), Err(_)));
+        assert!(matches!(parse(b" CONNECT"), Err(_)));
+    }
+
+    #[test]
+    fn test_other_method() {
+        assert_eq!(parse(b"GET"), Incomplete);
+        assert_eq!(parse(b"GET http://example.com/ HTTP/1.1\r\n"), Incomplete);
+        let head = b"GET http://example.com/ HTTP/1.1\r\nHost: example.com\r\n\r\n";
+        assert_eq!(
+            parse(head),
+            OtherMethod {
+                method: "GET".into(),
+                len: head.len(),
+            }
+        );
+        assert!(matches!(
+            parse(b"CONNECT foo.com:443 HTTP/1.1\r\n\r\n"),
+            Connect { .. }
+        ));
+    }
+
+    #[test]
+    fn test_absolute() {
+        let request = absolute(
+            b"POST HTTP://api.giphy.com:8080/v1/gifs?q=cat HTTP/1.1\r\nContent-Length: 0\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(
+            request,
+            AbsoluteRequest {
+                method: "POST".into(),
+                host: "api.giphy.com".into(),
+                port: 8080,
+                path: "/v1/gifs?q=cat".into(),
+                version: "HTTP/1.1".into(),
+                headers: Headers::from(vec![("Content-Length".into(), "0".into())]),
+            }
+        );
+
+        let request = absolute(b"GET http://[::1]?x HTTP/1.0\r\n\r\n").unwrap();
+        assert_eq!((request.host.as_str(), request.port), ("::1", 80));
+        assert_eq!(request.path, "/?x");
+        assert_eq!(request.version, "HTTP/1.0");
+
+        let request = absolute(b"GET http://example.com HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(request.path, "/");
+
+        // only plain http is forwarded; https goes through CONNECT
+        assert!(absolute(b"GET https://example.com/ HTTP/1.1\r\n\r\n").is_err());
+        assert!(absolute(b"GET /index.html HTTP/1.1\r\n\r\n").is_err());
+        assert!(absolute(b"GET http://example.com/ HTTP/2\r\n\r\n").is_err());
+        assert!(absolute(b"GET http://example.com/a b HTTP/1.1\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn test_origin() {
+        let request =
+            origin(b"GET /v1/gifs/search?q=cat HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        assert_eq!(
+            request,
+            OriginRequest {
+                method: "GET".into(),
+                path: "/v1/gifs/search?q=cat".into(),
+                version: "HTTP/1.1".into(),
+                headers: Headers::from(vec![("Host".into(), "localhost".into())]),
+            }
+        );
+        assert!(origin(b"GET http://example.com/ HTTP/1.1\r\n\r\n").is_err());
+        assert!(origin(b"GET * HTTP/1.1\r\n\r\n").is_err());
     }
 
     #[test]
     fn test_bad_port_too_large() {
         assert!(matches!(
-            parse_head(b"CONNECT foo.com:9999999 HTTP/1.1\r\n\r\n"),
+            parse(b"CONNECT foo.com:9999999 HTTP/1.1\r\n\r\n"),
             Err(_)
         ));
     }
@@ -158,7 +753,7 @@ mod test {
     #[test]
     fn test_bad_invalid_hostname() {
         assert!(matches!(
-            parse_head(b"CONNECT fo/o.c/om:10 HTTP/1.1\r\n\r\n"),
+            parse(b"CONNECT fo/o.c/om:10 HTTP/1.1\r\n\r\n"),
             Err(_)
         ));
     }
@@ -166,30 +761,418 @@ mod test {
     #[test]
     fn test_good_no_headers() {
         assert_eq!(
-            parse_head(b"CONNECT foo.com:1234 HTTP/1.1\r\n\r\n"),
+            parse(b"CONNECT foo.com:1234 HTTP/1.1\r\n\r\n"),
+            Connect {
+                host: "foo.com".to_owned(),
+                port: 1234u16,
+                version: "HTTP/1.1".into(),
+                headers: Headers::default(),
+                len: 33,
+            }
+        );
+    }
+
+    #[test]
+    fn test_good_ipv6() {
+        assert_eq!(
+            parse(b"CONNECT [2606:2800:0::1]:443 HTTP/1.1\r\n\r\n"),
+            Connect {
+                host: "2606:2800::1".to_owned(),
+                port: 443u16,
+                version: "HTTP/1.1".into(),
+                headers: Headers::default(),
+                len: 41,
+            }
+        );
+        assert_eq!(
+            parse(b"CONNECT [::ffff:10.0.0.1]:80 HTTP/1.1\r\n\r\n"),
+            Connect {
+                host: "::ffff:10.0.0.1".to_owned(),
+                port: 80u16,
+                version: "HTTP/1.1".into(),
+                headers: Headers::default(),
+                len: 41,
+            }
+        );
+    }
+
+    #[test]
+    fn test_good_http_1_0() {
+        assert_eq!(
+            parse(b"CONNECT foo.com:443 HTTP/1.0\r\n\r\n"),
+            Connect {
+                host: "foo.com".to_owned(),
+                port: 443u16,
+                version: "HTTP/1.0".into(),
+                headers: Headers::default(),
+                len: 32,
+            }
+        );
+        assert_eq!(
+            parse(b"CONNECT foo.com:443 HTTP/1.0  \r\nHost: foo.com:443\r\n\r\n"),
+            Connect {
+                host: "foo.com".to_owned(),
+                port: 443u16,
+                version: "HTTP/1.0".into(),
+                headers: vec![("Host".into(), "foo.com:443".into())].into(),
+                len: 53,
+            }
+        );
+        assert_eq!(parse(b"CONNECT foo.com:443 HTTP/1.0 "), Incomplete);
+        assert!(matches!(
+            parse(b"CONNECT foo.com:443 HTTP/2.0\r\n\r\n"),
+            Err(_)
+        ));
+    }
+
+    #[test]
+    fn test_bare_lf() {
+        const HEAD: &[u8] = b"CONNECT foo.com:443 HTTP/1.1\nHost: foo.com\r\nA: b\n\n";
+        assert!(matches!(parse(HEAD), Err(_)));
+
+        let options = ParseOptions {
+            allow_bare_lf: true,
+            ..ParseOptions::default()
+        };
+        assert_eq!(
+            parse_head(HEAD, &options),
             Connect {
                 host: "foo.com".to_owned(),
-                port: 1234u16
+                port: 443u16,
+                version: "HTTP/1.1".into(),
+                headers: vec![("Host".into(), "foo.com".into()), ("A".into(), "b".into()),].into(),
+                len: HEAD.len(),
+            }
+        );
+        assert_eq!(parse_head(&HEAD[..HEAD.len() - 1], &options), Incomplete);
+
+        const GET: &[u8] = b"GET http://foo.com/ HTTP/1.0\nHost: foo.com\n\r\nextra";
+        assert_eq!(parse(GET), Incomplete);
+        assert_eq!(
+            parse_head(GET, &options),
+            OtherMethod {
+                method: "GET".into(),
+                len: GET.len() - 5,
+            }
+        );
+        let request = parse_absolute(&GET[..GET.len() - 5], &options).unwrap();
+        assert_eq!(request.headers.get("Host"), Some("foo.com"));
+        assert_eq!(parse_head(b"GET / HTTP/1.1\n\r", &options), Incomplete);
+    }
+
+    #[test]
+    fn test_limits() {
+        let options = ParseOptions {
+            max_headers: 2,
+            max_line_length: 40,
+            ..ParseOptions::default()
+        };
+        let head = b"CONNECT foo.com:443 HTTP/1.1\r\nA: 1\r\nB: 2\r\n\r\nC: 3\r\n";
+        assert!(matches!(parse_head(head, &options), Connect { .. }));
+
+        // limits are enforced before the head is complete
+        let head = b"CONNECT foo.com:443 HTTP/1.1\r\nA: 1\r\nB: 2\r\nC";
+        assert_eq!(parse_head(head, &options), TooLarge("too many headers"));
+        let head = b"CONNECT foo.com:443 HTTP/1.1\r\nUser-Agent: 0123456789012345678901234567890";
+        assert_eq!(parse_head(head, &options), TooLarge("line too long"));
+        let head = b"GET http://foo.com/0123456789012345678901234567890 HTTP/1.1\r\n";
+        assert_eq!(parse_head(head, &options), TooLarge("line too long"));
+    }
+
+    #[test]
+    fn test_hostnames() {
+        fn host(input: &str, strict: bool) -> Option<String> {
+            let options = ParseOptions {
+                strict_hostnames: strict,
+                ..ParseOptions::default()
+            };
+            let head = format!("CONNECT {}:443 HTTP/1.1\r\n\r\n", input);
+            match parse_head(head.as_bytes(), &options) {
+                Connect { host, .. } => Some(host),
+                _ => None,
             }
+        }
+
+        for name in &[
+            "api.giphy.com",
+            "xn--bcher-kva.example",
+            "XN--BCHER-KVA.example",
+        ] {
+            assert_eq!(host(name, true).as_deref(), Some(*name));
+        }
+        assert_eq!(
+            host("api.giphy.com.", true).as_deref(),
+            Some("api.giphy.com")
+        );
+        for name in &["_srv.example.com", "my_host.local", "-edge-.example.com"] {
+            assert_eq!(host(name, false).as_deref(), Some(*name));
+            assert_eq!(host(name, true), None);
+        }
+        assert_eq!(
+            host("ab--c.example", false).as_deref(),
+            Some("ab--c.example")
+        );
+        assert_eq!(host("ab--c.example", true), None);
+
+        let long_label = "a".repeat(64);
+        let long_name = vec!["a".repeat(63); 4].join(".");
+        for name in &["", ".", "a..b", ".a", &long_label, &long_name] {
+            assert_eq!(host(name, false), None, "{:?}", name);
+        }
+        assert!(absolute(b"GET http://a..b/ HTTP/1.1\r\n\r\n").is_err());
+        assert_eq!(
+            absolute(b"GET http://a.b./ HTTP/1.1\r\n\r\n").unwrap().host,
+            "a.b"
         );
     }
 
+    #[test]
+    fn test_default_port() {
+        assert_eq!(
+            parse(b"CONNECT api.giphy.com HTTP/1.1\r\n\r\n"),
+            Connect {
+                host: "api.giphy.com".to_owned(),
+                port: 443u16,
+                version: "HTTP/1.1".into(),
+                headers: Headers::default(),
+                len: 34,
+            }
+        );
+        let options = ParseOptions {
+            default_port: 8443,
+            ..ParseOptions::default()
+        };
+        assert!(matches!(
+            parse_head(b"CONNECT [::1] HTTP/1.1\r\n\r\n", &options),
+            Connect { port: 8443, .. }
+        ));
+        assert_eq!(parse(b"CONNECT api.giphy.com"), Incomplete);
+        assert!(matches!(
+            parse(b"CONNECT api.giphy.com: HTTP/1.1\r\n\r\n"),
+            Err(_)
+        ));
+    }
+
+    #[test]
+    fn test_prefix_ipv6() {
+        assert_eq!(parse(b"CONNECT [2606:28"), Incomplete);
+    }
+
+    #[test]
+    fn test_bad_ipv6() {
+        assert!(matches!(
+            parse(b"CONNECT [2606:::1]:443 HTTP/1.1\r\n\r\n"),
+            Err(_)
+        ));
+        assert!(matches!(
+            parse(b"CONNECT 2606:2800::1:443 HTTP/1.1\r\n\r\n"),
+            Err(_)
+        ));
+    }
+
+    #[test]
+    fn test_authority() {
+        assert_eq!(authority("api.giphy.com", 443), "api.giphy.com:443");
+        assert_eq!(authority("10.0.0.1", 80), "10.0.0.1:80");
+        assert_eq!(authority("2606:2800::1", 443), "[2606:2800::1]:443");
+    }
+
     #[test]
     fn test_good_headers() {
         assert_eq!(
-            parse_head(b"CONNECT foo.com:1234 HTTP/1.1\r\nProxy-Connection: Keep-Alive\r\n\r\n"),
+            parse(
+                b"CONNECT foo.com:1234 HTTP/1.1\r\n\
+                  Proxy-Connection: Keep-Alive\r\n\
+                  Proxy-Authorization:Basic Zm9vOmJhcg== \r\n\r\n"
+            ),
             Connect {
                 host: "foo.com".to_owned(),
-                port: 1234u16
+                port: 1234u16,
+                version: "HTTP/1.1".into(),
+                headers: vec![
+                    ("Proxy-Connection".into(), "Keep-Alive".into()),
+                    ("Proxy-Authorization".into(), "Basic Zm9vOmJhcg==".into()),
+                ]
+                .into(),
+                len: 104,
             }
         );
     }
 
     #[test]
-    fn test_extra_chars() {
+    fn test_headers_get() {
+        let headers: Headers = vec![
+            ("User-Agent".into(), "curl/7.68.0".into()),
+            ("Via".into(), "1.1 a".into()),
+            ("via".into(), "1.1 b".into()),
+        ]
+        .into();
+        assert_eq!(headers.get("user-agent"), Some("curl/7.68.0"));
+        assert_eq!(headers.get("VIA"), Some("1.1 a"));
+        assert_eq!(headers.get("Host"), None);
+        assert_eq!(headers.len(), 3);
+        assert_eq!(headers.iter().nth(2), Some(("via", "1.1 b")));
+    }
+
+    #[test]
+    fn test_headers_set_remove() {
+        let mut headers: Headers = vec![
+            ("Via".into(), "1.1 a".into()),
+            ("Accept".into(), "*/*".into()),
+            ("via".into(), "1.1 b".into()),
+        ]
+        .into();
+        headers.set("VIA", "1.1 c");
+        assert_eq!(
+            headers.iter().collect::<Vec<_>>(),
+            vec![("Accept", "*/*"), ("VIA", "1.1 c")]
+        );
+        headers.remove("accept");
+        assert_eq!(headers.iter().collect::<Vec<_>>(), vec![("VIA", "1.1 c")]);
+    }
+
+    #[test]
+    fn test_bad_header() {
         assert!(matches!(
-            parse_head(b"CONNECT foo.com:1234 HTTP/1.1\r\n\r\nUHOH"),
+            parse(b"CONNECT foo.com:1234 HTTP/1.1\r\nno colon here\r\n\r\n"),
             Err(_)
         ));
     }
+
+    #[test]
+    fn test_response_ok() {
+        assert_eq!(Response::ok().to_bytes(), b"HTTP/1.1 200 OK\r\n\r\n");
+    }
+
+    #[test]
+    fn test_response_version() {
+        assert_eq!(
+            Response::ok().version("HTTP/1.0").to_bytes(),
+            b"HTTP/1.0 200 OK\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn test_response_error() {
+        assert_eq!(
+            String::from_utf8(Response::error(403, "Forbidden", "nope").to_bytes()).unwrap(),
+            "HTTP/1.1 403 Forbidden\r\n\
+             Content-Type: text/plain\r\n\
+             Content-Length: 5\r\n\
+             Connection: close\r\n\
+             \r\n\
+             nope\n"
+        );
+    }
+
+    #[test]
+    fn test_extra_chars() {
+        assert_eq!(
+            parse(b"CONNECT foo.com:1234 HTTP/1.1\r\n\r\n\x16\x03\x01"),
+            Connect {
+                host: "foo.com".to_owned(),
+                port: 1234u16,
+                version: "HTTP/1.1".into(),
+                headers: Headers::default(),
+                len: 33,
+            }
+        );
+    }
+
+    /// Properties of `parse_head` on complete and partial CONNECT heads, which
+    /// `read_head` relies on when re-parsing its buffer after each read from the client
+    mod streaming {
+        use super::*;
+        use proptest::prelude::*;
+
+        /// A host as sent in a CONNECT request, and as parsed
+        fn host() -> impl Strategy<Value = (String, String)> {
+            prop_oneof![
+                "[a-z0-9_]([a-z0-9_-]{0,8}[a-z0-9])?(\\.[a-z0-9_]([a-z0-9_-]{0,8}[a-z0-9])?){0,3}"
+                    .prop_map(|h| (h.clone(), h)),
+                any::<Ipv6Addr>().prop_map(|a| (format!("[{}]", a), a.to_string())),
+            ]
+        }
+
+        /// Header names and values, as sent
+        fn headers() -> impl Strategy<Value = Vec<(String, String)>> {
+            prop::collection::vec(("[A-Za-z0-9_-]{1,16}", "[ -~]{0,40}"), 0..5)
+        }
+
+        /// A CONNECT head, without the blank line that ends it
+        fn request_and_headers(host: &str, port: u16, headers: &[(String, String)]) -> Vec<u8> {
+            let mut head = format!("CONNECT {}:{} HTTP/1.1\r\n", host, port);
+            for (name, value) in headers {
+                head.push_str(&format!("{}:{}\r\n", name, value));
+            }
+            head.into_bytes()
+        }
+
+        /// The result of successfully parsing a CONNECT head
+        fn connect(
+            host: String,
+            port: u16,
+            headers: &[(String, String)],
+            len: usize,
+        ) -> ParseHeadResult {
+            let headers: Vec<_> = headers
+                .iter()
+                .map(|(n, v)| (n.clone(), v.trim().to_owned()))
+                .collect();
+            Connect {
+                host,
+                port,
+                version: "HTTP/1.1".into(),
+                headers: headers.into(),
+                len,
+            }
+        }
+
+        proptest! {
+            #[test]
+            fn prefixes_incomplete(
+                (sent, parsed) in host(),
+                port in any::<u16>(),
+                headers in headers(),
+            ) {
+                let mut head = request_and_headers(&sent, port, &headers);
+                head.extend_from_slice(b"\r\n");
+                for split in 0..head.len() {
+                    prop_assert_eq!(parse(&head[..split]), Incomplete, "split at {}", split);
+                }
+                prop_assert_eq!(parse(&head), connect(parsed, port, &headers, head.len()));
+            }
+
+            #[test]
+            fn trailing_data_ignored(
+                (sent, parsed) in host(),
+                port in any::<u16>(),
+                headers in headers(),
+                trailing in prop::collection::vec(any::<u8>(), 1..64),
+            ) {
+                let mut head = request_and_headers(&sent, port, &headers);
+                head.extend_from_slice(b"\r\n");
+                let len = head.len();
+                head.extend_from_slice(&trailing);
+                prop_assert_eq!(parse(&head), connect(parsed, port, &headers, len));
+            }
+
+            #[test]
+            fn junk_is_error(
+                (sent, _) in host(),
+                port in any::<u16>(),
+                headers in headers(),
+                junk in any::<u8>().prop_filter("could continue the head", |&c| {
+                    !(c.is_ascii_alphanumeric() || c == b'-' || c == b'_' || c == b'\r')
+                }),
+                trailing in prop::collection::vec(any::<u8>(), 0..64),
+            ) {
+                // junk in place of the blank line ending the head
+                let mut head = request_and_headers(&sent, port, &headers);
+                head.push(junk);
+                head.extend_from_slice(&trailing);
+                prop_assert!(matches!(parse(&head), Err(_)));
+            }
+        }
+    }
 }
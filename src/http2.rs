@@ -0,0 +1,325 @@
+//! An HTTP/2 frontend.  Clients open a cleartext HTTP/2 connection with prior knowledge,
+//! and send CONNECT requests (RFC 7540, section 8.3) on its streams.  Each stream is a
+//! separate tunnel, with its own access log record.
+
+use crate::access::{AccessRecord, Reason};
+use crate::auth::Htpasswd;
+use crate::backend::Backend;
+use crate::config::Config;
+use crate::connection::{
+    bidirectional_proxy, record_backend_error, ConnectionInfo, Protocol, ServedBy,
+};
+use crate::http::authority;
+use crate::metrics::ActiveTunnel;
+use crate::panics;
+use crate::quota;
+use crate::registry::REGISTRY;
+use crate::telemetry::spawn;
+use anyhow::{anyhow, bail, Context, Result};
+use bytes::Bytes;
+use h2::server::SendResponse;
+use h2::{RecvStream, SendStream};
+use http::{HeaderValue, Method, Request, Response, StatusCode};
+use std::net::Ipv6Addr;
+use std::sync::Arc;
+use tokio::io::{
+    duplex, split, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadHalf,
+    WriteHalf,
+};
+use tokio::time::timeout;
+use tracing::Instrument;
+
+/// The connection preface every HTTP/2 client sends first
+pub const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// Size of the buffer between each HTTP/2 stream and its tunnel
+const STREAM_BUFFER: usize = 16384;
+
+/// Serve an HTTP/2 connection until the client closes it, handling each stream in its own
+/// task.
+pub(crate) async fn serve<S, B>(
+    socket: S,
+    backend: Arc<B>,
+    config: Arc<Config>,
+    htpasswd: Option<Arc<Htpasswd>>,
+    info: ConnectionInfo,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    B: Backend + 'static,
+{
+    let mut connection = timeout(config.limits.head_timeout, h2::server::handshake(socket))
+        .await
+        .context("timed out in HTTP/2 handshake")?
+        .context("HTTP/2 handshake")?;
+
+    while let Some(result) = connection.accept().await {
+        let (request, respond) = result.context("accepting HTTP/2 stream")?;
+        let backend = backend.clone();
+        let config = config.clone();
+        let htpasswd = htpasswd.clone();
+        // streams are concurrent, so each records its own backend endpoint
+        let info = ConnectionInfo {
+            served_by: ServedBy::default(),
+            ..info.clone()
+        };
+        spawn("http2-stream", async move {
+            let mut record = AccessRecord::new(info.peer);
+            record.client_cert = info.client_cert.clone();
+            record.protocol = Some(Protocol::Http2);
+            // each stream has its own access record, and so its own span
+            let span = record.span();
+            let result = panics::catch_unwind(handle_stream(
+                request,
+                respond,
+                backend,
+                &config,
+                htpasswd.as_deref(),
+                &info,
+                &mut record,
+            ))
+            .instrument(span.clone())
+            .await
+            .unwrap_or_else(|payload| {
+                Err(panics::report(payload, &mut record, config.abort_on_panic).into())
+            });
+            if let Err(e) = result {
+                log::debug!("HTTP/2 stream from {} failed: {:?}", info, e);
+            }
+            record.finish();
+            record.record_span(&span);
+            record.log();
+        });
+    }
+
+    Ok(())
+}
+
+/// A response refusing a CONNECT request, with the reason it was refused
+pub(crate) struct Refusal {
+    pub(crate) response: Response<()>,
+    pub(crate) error: anyhow::Error,
+}
+
+impl Refusal {
+    fn new<E: Into<anyhow::Error>>(status: StatusCode, error: E) -> Self {
+        let mut response = Response::new(());
+        *response.status_mut() = status;
+        Self {
+            response,
+            error: error.into(),
+        }
+    }
+}
+
+/// Check a CONNECT request received on a stream of an HTTP/2 or HTTP/3 connection, and
+/// connect to the backend for it.  If the request is refused, the result contains the
+/// response to send on the stream.
+pub(crate) async fn connect_stream<B: Backend, T>(
+    request: &Request<T>,
+    backend: &B,
+    config: &Config,
+    htpasswd: Option<&Htpasswd>,
+    info: &ConnectionInfo,
+    record: &mut AccessRecord,
+) -> std::result::Result<B::Socket, Refusal> {
+    if request.method() != Method::CONNECT {
+        record.reason = Reason::BadRequest;
+        return Err(Refusal::new(
+            StatusCode::METHOD_NOT_ALLOWED,
+            anyhow!("unsupported method {}", request.method()),
+        ));
+    }
+    let (host, port) = match request.uri().authority().and_then(|a| {
+        let host = normalize_host(a.host())?;
+        Some((host, a.port_u16()?))
+    }) {
+        Some(hostport) => hostport,
+        None => {
+            record.reason = Reason::BadRequest;
+            return Err(Refusal::new(
+                StatusCode::BAD_REQUEST,
+                anyhow!("invalid CONNECT authority {:?}", request.uri()),
+            ));
+        }
+    };
+    record.target = Some(authority(&host, port));
+    record.user_agent = request
+        .headers()
+        .get(http::header::USER_AGENT)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_owned);
+
+    if let Some(htpasswd) = htpasswd {
+        let value = request
+            .headers()
+            .get(http::header::PROXY_AUTHORIZATION)
+            .and_then(|v| v.to_str().ok());
+        let user = match value {
+            Some(value) => htpasswd.verify_header(value).await,
+            None => None,
+        };
+        match user {
+            Some(user) => record.user = Some(user),
+            None => {
+                record.reason = Reason::AuthFailed;
+                let mut refusal = Refusal::new(
+                    StatusCode::PROXY_AUTHENTICATION_REQUIRED,
+                    anyhow!("missing or invalid proxy credentials"),
+                );
+                let challenge = format!("Basic realm=\"{}\"", config.auth.realm);
+                if let Ok(value) = HeaderValue::from_str(&challenge) {
+                    refusal
+                        .response
+                        .headers_mut()
+                        .insert(http::header::PROXY_AUTHENTICATE, value);
+                }
+                return Err(refusal);
+            }
+        }
+    }
+
+    if let Err(e) = quota::check(record) {
+        record.reason = Reason::QuotaExceeded;
+        return Err(Refusal::new(StatusCode::TOO_MANY_REQUESTS, e));
+    }
+
+    let connect = backend
+        .connect_for(info, &host, port)
+        .instrument(tracing::info_span!("backend_connect"));
+    match connect.await {
+        Ok(socket) => Ok(socket),
+        Err(e) => {
+            let status = if record_backend_error(&e, info, record) {
+                StatusCode::FORBIDDEN
+            } else {
+                StatusCode::BAD_GATEWAY
+            };
+            Err(Refusal::new(status, e))
+        }
+    }
+}
+
+/// Handle a single stream, which should carry a CONNECT request
+async fn handle_stream<B: Backend>(
+    request: Request<RecvStream>,
+    mut respond: SendResponse<Bytes>,
+    backend: Arc<B>,
+    config: &Config,
+    htpasswd: Option<&Htpasswd>,
+    info: &ConnectionInfo,
+    record: &mut AccessRecord,
+) -> Result<()> {
+    let backend_socket =
+        match connect_stream(&request, &*backend, config, htpasswd, info, record).await {
+            Ok(socket) => socket,
+            Err(refusal) => {
+                log::debug!("responding {} on HTTP/2 stream", refusal.response.status());
+                let _ = respond.send_response(refusal.response, true);
+                return Err(refusal.error);
+            }
+        };
+
+    record.served_by = info.served_by.take();
+    record.reason = Reason::Error;
+    let send = respond.send_response(Response::new(()), false)?;
+    let recv = request.into_body();
+
+    // bridge the stream to one end of an in-memory pipe, and proxy the other end
+    let (stream_end, tunnel_end) = duplex(STREAM_BUFFER);
+    let (read, write) = split(stream_end);
+    spawn("copy-up", pump_from_stream(recv, write));
+    spawn("copy-down", pump_to_stream(read, send));
+
+    let _active = ActiveTunnel::new();
+    let registration = REGISTRY.register(record);
+    let tunnel = bidirectional_proxy(tunnel_end, backend_socket, &config.limits, &registration)
+        .instrument(tracing::info_span!("tunnel"))
+        .await;
+    tunnel.record(record);
+    Ok(())
+}
+
+/// Normalize the host from a CONNECT authority, removing the brackets around IPv6
+/// addresses
+fn normalize_host(host: &str) -> Option<String> {
+    match host.strip_prefix('[').and_then(|h| h.strip_suffix(']')) {
+        Some(addr) => Some(addr.parse::<Ipv6Addr>().ok()?.to_string()),
+        None if !host.is_empty() => Some(host.to_owned()),
+        None => None,
+    }
+}
+
+/// Copy data received on the stream into the pipe, releasing flow-control capacity as it
+/// is consumed, and shut down the pipe when the client ends the stream.
+async fn pump_from_stream(mut recv: RecvStream, mut write: WriteHalf<DuplexStream>) {
+    while let Some(data) = recv.data().await {
+        let data = match data {
+            Ok(data) => data,
+            Err(e) => {
+                log::debug!("reading from HTTP/2 stream: {}", e);
+                break;
+            }
+        };
+        if write.write_all(&data).await.is_err() {
+            break;
+        }
+        let _ = recv.flow_control().release_capacity(data.len());
+    }
+    let _ = write.shutdown().await;
+}
+
+/// Copy data from the pipe to the stream, waiting for flow-control capacity, and end the
+/// stream when the pipe is closed.
+async fn pump_to_stream(mut read: ReadHalf<DuplexStream>, mut send: SendStream<Bytes>) {
+    if let Err(e) = pump_to_stream_inner(&mut read, &mut send).await {
+        log::debug!("writing to HTTP/2 stream: {}", e);
+        send.send_reset(h2::Reason::CANCEL);
+    }
+}
+
+async fn pump_to_stream_inner(
+    read: &mut ReadHalf<DuplexStream>,
+    send: &mut SendStream<Bytes>,
+) -> Result<()> {
+    let mut buf = vec![0u8; STREAM_BUFFER];
+    loop {
+        let n = read.read(&mut buf).await?;
+        if n == 0 {
+            send.send_data(Bytes::new(), true)?;
+            return Ok(());
+        }
+
+        let mut data = Bytes::copy_from_slice(&buf[..n]);
+        while !data.is_empty() {
+            send.reserve_capacity(data.len());
+            let capacity = match std::future::poll_fn(|cx| send.poll_capacity(cx)).await {
+                Some(capacity) => capacity?,
+                None => bail!("stream closed by client"),
+            };
+            if capacity > 0 {
+                let chunk = data.split_to(capacity.min(data.len()));
+                send.send_data(chunk, false)?;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_normalize_host() {
+        assert_eq!(
+            normalize_host("api.giphy.com"),
+            Some("api.giphy.com".into())
+        );
+        assert_eq!(
+            normalize_host("[2606:2800:0::1]"),
+            Some("2606:2800::1".into())
+        );
+        assert_eq!(normalize_host("[nope]"), None);
+        assert_eq!(normalize_host(""), None);
+    }
+}
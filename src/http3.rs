@@ -0,0 +1,368 @@
+//! An experimental HTTP/3 frontend.  Clients open a QUIC connection, and send CONNECT
+//! requests (RFC 9114, section 4.4) on its streams.  As with HTTP/2, each stream is a
+//! separate tunnel, with its own access log record, and is subject to the same
+//! authentication, quotas, and backend policy as the TCP listeners.  The client ACL and
+//! draining are checked for each connection, before its handshake.
+
+use crate::access::{AccessRecord, Reason};
+use crate::acl::Acl;
+use crate::auth::Htpasswd;
+use crate::backend::Backend;
+use crate::config::{Config, Http3Config, TlsConfig};
+use crate::connection::{bidirectional_proxy, ConnectionInfo, Protocol};
+use crate::http2::connect_stream;
+use crate::metrics::{ActiveTunnel, METRICS};
+use crate::panics;
+use crate::registry::REGISTRY;
+use crate::telemetry::spawn;
+use crate::tls;
+use anyhow::{Context, Result};
+use bytes::{Buf, Bytes};
+use h3::server::{RequestResolver, RequestStream};
+use http::Response;
+use quinn::crypto::rustls::QuicServerConfig;
+use quinn::{Endpoint, Incoming, ServerConfig, TransportConfig, VarInt};
+use std::convert::TryFrom;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{duplex, split, AsyncReadExt, AsyncWriteExt, DuplexStream, ReadHalf, WriteHalf};
+use tokio::time::timeout;
+use tracing::Instrument;
+
+/// Size of the buffer between each HTTP/3 stream and its tunnel
+const STREAM_BUFFER: usize = 16384;
+
+type SendStream = RequestStream<h3_quinn::SendStream<Bytes>, Bytes>;
+type RecvStream = RequestStream<h3_quinn::RecvStream, Bytes>;
+
+/// State shared by all HTTP/3 connections
+struct Shared<B: Backend> {
+    config: Arc<Config>,
+    backend: Arc<B>,
+    htpasswd: Option<Arc<Htpasswd>>,
+    acl: Option<Arc<Acl>>,
+}
+
+/// Bind each of the HTTP/3 listen addresses, and accept connections on them in background
+/// tasks.  Returns the bound addresses, in the same order as the configuration.
+pub(crate) async fn start<B: Backend + 'static>(
+    config: Arc<Config>,
+    backend: Arc<B>,
+    htpasswd: Option<Arc<Htpasswd>>,
+    acl: Option<Arc<Acl>>,
+) -> Result<Vec<SocketAddr>> {
+    let server_config = server_config(&config.http3)?;
+    let shared = Arc::new(Shared {
+        config: config.clone(),
+        backend,
+        htpasswd,
+        acl,
+    });
+
+    let mut bound = Vec::with_capacity(config.http3.listen.len());
+    for addr in &config.http3.listen {
+        let endpoint = Endpoint::server(server_config.clone(), *addr)
+            .with_context(|| format!("binding {}", addr))?;
+        let local_addr = endpoint.local_addr()?;
+        log::info!("Listening for HTTP/3 on {}", local_addr);
+        bound.push(local_addr);
+        spawn(
+            "http3-acceptor",
+            shared.clone().accept(endpoint, local_addr),
+        );
+    }
+    Ok(bound)
+}
+
+/// Build the QUIC server configuration from the certificate and key in the HTTP/3
+/// configuration, offering only `h3` via ALPN
+fn server_config(config: &Http3Config) -> Result<ServerConfig> {
+    let tls_config = TlsConfig {
+        cert: config.cert.clone(),
+        key: config.key.clone(),
+        ..TlsConfig::default()
+    };
+    let mut crypto = tls::load_server_config(&tls_config).context("http3")?;
+    crypto.alpn_protocols = vec![b"h3".to_vec()];
+    let crypto = QuicServerConfig::try_from(Arc::new(crypto))?;
+
+    let mut transport = TransportConfig::default();
+    transport.max_concurrent_bidi_streams(VarInt::from_u32(config.max_concurrent_streams));
+    let mut server_config = ServerConfig::with_crypto(Arc::new(crypto));
+    server_config.transport_config(Arc::new(transport));
+    Ok(server_config)
+}
+
+impl<B: Backend + 'static> Shared<B> {
+    /// Accept connections on `endpoint` until it is closed
+    async fn accept(self: Arc<Self>, endpoint: Endpoint, local: SocketAddr) {
+        while let Some(incoming) = endpoint.accept().await {
+            METRICS.connections_accepted.inc();
+            let info = ConnectionInfo {
+                peer: Some(incoming.remote_address()),
+                local: Some(local),
+                ..ConnectionInfo::default()
+            };
+
+            if let Some(acl) = &self.acl {
+                if !acl.permits(incoming.remote_address().ip()) {
+                    log::warn!("refusing HTTP/3 connection from {}: denied by ACL", info);
+                    METRICS.connections_denied.inc();
+                    incoming.refuse();
+                    continue;
+                }
+            }
+            if REGISTRY.draining() {
+                log::debug!("refusing HTTP/3 connection from {}: draining", info);
+                METRICS.connections_rejected.inc();
+                incoming.refuse();
+                continue;
+            }
+
+            let shared = self.clone();
+            spawn("http3-connection", async move {
+                if let Err(e) = shared.serve(incoming, &info).await {
+                    log::debug!("HTTP/3 connection from {} failed: {:#}", info, e);
+                }
+            });
+        }
+    }
+
+    /// Serve a connection until the client closes it, handling each stream in its own
+    /// task
+    async fn serve(self: Arc<Self>, incoming: Incoming, info: &ConnectionInfo) -> Result<()> {
+        let handshake = async {
+            let connection = incoming.await.context("QUIC handshake")?;
+            h3::server::builder()
+                .build::<_, Bytes>(h3_quinn::Connection::new(connection))
+                .await
+                .context("HTTP/3 handshake")
+        };
+        let mut connection = timeout(self.config.limits.head_timeout, handshake)
+            .await
+            .context("timed out in HTTP/3 handshake")??;
+
+        loop {
+            let resolver = match connection.accept().await {
+                Ok(Some(resolver)) => resolver,
+                Ok(None) => return Ok(()),
+                Err(e) if e.is_h3_no_error() => return Ok(()),
+                Err(e) => return Err(e).context("accepting HTTP/3 stream"),
+            };
+            let shared = self.clone();
+            let info = info.clone();
+            spawn("http3-stream", async move {
+                let mut record = AccessRecord::new(info.peer);
+                record.protocol = Some(Protocol::Http3);
+                // each stream has its own access record, and so its own span
+                let span = record.span();
+                let abort_on_panic = shared.config.abort_on_panic;
+                let result =
+                    panics::catch_unwind(shared.handle_stream(resolver, &info, &mut record))
+                        .instrument(span.clone())
+                        .await
+                        .unwrap_or_else(|payload| {
+                            Err(panics::report(payload, &mut record, abort_on_panic).into())
+                        });
+                if let Err(e) = result {
+                    log::debug!("HTTP/3 stream from {} failed: {:?}", info, e);
+                }
+                record.finish();
+                record.record_span(&span);
+                record.log();
+            });
+        }
+    }
+
+    /// Handle a single stream, which should carry a CONNECT request
+    async fn handle_stream(
+        &self,
+        resolver: RequestResolver<h3_quinn::Connection, Bytes>,
+        info: &ConnectionInfo,
+        record: &mut AccessRecord,
+    ) -> Result<()> {
+        let (request, mut stream) = match resolver.resolve_request().await {
+            Ok(resolved) => resolved,
+            Err(e) => {
+                record.reason = Reason::BadRequest;
+                return Err(e).context("reading HTTP/3 request");
+            }
+        };
+        // streams are concurrent, so each records its own backend endpoint
+        let info = ConnectionInfo {
+            served_by: Default::default(),
+            ..info.clone()
+        };
+        let config = &self.config;
+        let connect = connect_stream(
+            &request,
+            &*self.backend,
+            config,
+            self.htpasswd.as_deref(),
+            &info,
+            record,
+        );
+        let backend_socket = match connect.await {
+            Ok(socket) => socket,
+            Err(refusal) => {
+                log::debug!("responding {} on HTTP/3 stream", refusal.response.status());
+                if stream.send_response(refusal.response).await.is_ok() {
+                    let _ = stream.finish().await;
+                }
+                return Err(refusal.error);
+            }
+        };
+
+        record.served_by = info.served_by.take();
+        record.reason = Reason::Error;
+        stream.send_response(Response::new(())).await?;
+        let (send, recv) = stream.split();
+
+        // bridge the stream to one end of an in-memory pipe, and proxy the other end
+        let (stream_end, tunnel_end) = duplex(STREAM_BUFFER);
+        let (read, write) = split(stream_end);
+        spawn("copy-up", pump_from_stream(recv, write));
+        spawn("copy-down", pump_to_stream(read, send));
+
+        let _active = ActiveTunnel::new();
+        let registration = REGISTRY.register(record);
+        let tunnel = bidirectional_proxy(tunnel_end, backend_socket, &config.limits, &registration)
+            .instrument(tracing::info_span!("tunnel"))
+            .await;
+        tunnel.record(record);
+        Ok(())
+    }
+}
+
+/// Copy data received on the stream into the pipe, and shut down the pipe when the client
+/// ends the stream.
+async fn pump_from_stream(mut recv: RecvStream, mut write: WriteHalf<DuplexStream>) {
+    loop {
+        let mut data = match recv.recv_data().await {
+            Ok(Some(data)) => data,
+            Ok(None) => break,
+            Err(e) => {
+                log::debug!("reading from HTTP/3 stream: {}", e);
+                break;
+            }
+        };
+        let chunk = data.copy_to_bytes(data.remaining());
+        if write.write_all(&chunk).await.is_err() {
+            break;
+        }
+    }
+    let _ = write.shutdown().await;
+}
+
+/// Copy data from the pipe to the stream, and end the stream when the pipe is closed.
+async fn pump_to_stream(mut read: ReadHalf<DuplexStream>, mut send: SendStream) {
+    if let Err(e) = pump_to_stream_inner(&mut read, &mut send).await {
+        log::debug!("writing to HTTP/3 stream: {}", e);
+    }
+}
+
+async fn pump_to_stream_inner(
+    read: &mut ReadHalf<DuplexStream>,
+    send: &mut SendStream,
+) -> Result<()> {
+    let mut buf = vec![0u8; STREAM_BUFFER];
+    loop {
+        let n = read.read(&mut buf).await?;
+        if n == 0 {
+            send.finish().await?;
+            return Ok(());
+        }
+        send.send_data(Bytes::copy_from_slice(&buf[..n])).await?;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::backend::SingleHostBackend;
+    use crate::testing::{echo_once, temp_file};
+    use http::{Method, Request, StatusCode};
+    use quinn::crypto::rustls::QuicClientConfig;
+    use rustls::RootCertStore;
+
+    /// A QUIC client endpoint trusting only `roots`, offering `h3`
+    fn client(roots: RootCertStore) -> Endpoint {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let mut crypto = rustls::ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        crypto.alpn_protocols = vec![b"h3".to_vec()];
+        let crypto = QuicClientConfig::try_from(Arc::new(crypto)).unwrap();
+        let mut endpoint = Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+        endpoint.set_default_client_config(quinn::ClientConfig::new(Arc::new(crypto)));
+        endpoint
+    }
+
+    #[tokio::test]
+    async fn test_connect() {
+        let port = echo_once().await;
+
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let config = Config {
+            http3: Http3Config {
+                listen: vec!["127.0.0.1:0".parse().unwrap()],
+                cert: Some(temp_file("h3-crt", &certified.cert.pem())),
+                key: Some(temp_file("h3-key", &certified.key_pair.serialize_pem())),
+                ..Http3Config::default()
+            },
+            ..Config::default()
+        };
+        let backend = Arc::new(SingleHostBackend::new("127.0.0.1", port));
+        let addrs = start(Arc::new(config.clone()), backend, None, None)
+            .await
+            .unwrap();
+
+        let mut roots = RootCertStore::empty();
+        roots.add(certified.cert.der().clone()).unwrap();
+        let connection = client(roots)
+            .connect(addrs[0], "localhost")
+            .unwrap()
+            .await
+            .unwrap();
+        let (mut driver, mut send_request) = h3::client::new(h3_quinn::Connection::new(connection))
+            .await
+            .unwrap();
+        spawn("h3-client-driver", async move { driver.wait_idle().await });
+
+        // only CONNECT is supported
+        let request = Request::get("https://localhost/").body(()).unwrap();
+        let mut stream = send_request.send_request(request).await.unwrap();
+        stream.finish().await.unwrap();
+        let response = stream.recv_response().await.unwrap();
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+
+        let request = Request::builder()
+            .method(Method::CONNECT)
+            .uri(format!("127.0.0.1:{}", port))
+            .body(())
+            .unwrap();
+        let mut stream = send_request.send_request(request).await.unwrap();
+        let response = stream.recv_response().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        stream
+            .send_data(Bytes::from_static(b"HELLO"))
+            .await
+            .unwrap();
+        let mut received = vec![];
+        while let Some(mut data) = stream.recv_data().await.unwrap() {
+            received.extend_from_slice(&data.copy_to_bytes(data.remaining()));
+        }
+        assert_eq!(received, b"HELLO");
+
+        for path in [&config.http3.cert, &config.http3.key]
+            .iter()
+            .copied()
+            .flatten()
+        {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
@@ -0,0 +1,518 @@
+//! Composable backend wrappers, each adding one policy to the backend it wraps, in the
+//! manner of tower layers.  A stack is assembled from the innermost backend outward with
+//! [`Stack`]:
+//!
+//! ```
+//! use giphyproxy::backend::PortSet;
+//! use giphyproxy::layer::{DirectBackend, Stack};
+//! use std::time::Duration;
+//!
+//! let backend = Stack::new(DirectBackend::new())
+//!     .timeout(Duration::from_secs(5))
+//!     .metrics()
+//!     .allow_list(vec!["*.giphy.com:443".parse().unwrap()])
+//!     .ports("443,8443".parse::<PortSet>().unwrap())
+//!     .build();
+//! ```
+//!
+//! The wrappers pass the client's `ConnectionInfo` through to the backend they wrap.
+
+use crate::backend::{
+    allow_entries, check_allowed, AllowEntry, Backend, Dialer, PortSet, RetryPolicy,
+};
+use crate::config::{BackendConfig, ConnectionRateConfig};
+use crate::connection::ConnectionInfo;
+use crate::error::{ProxyError, Result};
+use crate::http::authority;
+use crate::listen::RateLimiter;
+use crate::metrics::METRICS;
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use tokio::time::{sleep, timeout};
+
+/// A backend which connects directly to any host and port, other than addresses in its
+/// dialer's blocked networks.  It applies no other policy, and is meant to be wrapped.
+pub struct DirectBackend {
+    dialer: Dialer,
+}
+
+impl DirectBackend {
+    /// Create a backend using the system resolver, with no blocked networks
+    pub fn new() -> Self {
+        Self {
+            dialer: Dialer::new(),
+        }
+    }
+
+    /// Make connections with the given dialer.
+    pub fn with_dialer(mut self, dialer: Dialer) -> Self {
+        self.dialer = dialer;
+        self
+    }
+}
+
+impl Default for DirectBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait::async_trait]
+impl Backend for DirectBackend {
+    type Socket = TcpStream;
+
+    async fn connect(&self, host: &str, port: u16) -> Result<Self::Socket> {
+        self.dialer.connect(None, host, port, 0).await
+    }
+}
+
+/// A backend which allows connections only to destinations matching one of a list of
+/// `AllowEntry`s, refusing others with `ProxyError::Disallowed`.
+pub struct AllowList<B: Backend> {
+    inner: B,
+    entries: Vec<AllowEntry>,
+}
+
+impl<B: Backend> AllowList<B> {
+    pub fn new(inner: B, entries: Vec<AllowEntry>) -> Self {
+        Self { inner, entries }
+    }
+}
+
+#[async_trait::async_trait]
+impl<B: Backend> Backend for AllowList<B> {
+    type Socket = B::Socket;
+
+    async fn connect(&self, host: &str, port: u16) -> Result<Self::Socket> {
+        self.connect_for(&ConnectionInfo::default(), host, port)
+            .await
+    }
+
+    async fn connect_for(
+        &self,
+        info: &ConnectionInfo,
+        host: &str,
+        port: u16,
+    ) -> Result<Self::Socket> {
+        let allowed = self.entries.iter().any(|e| e.allows(host, port));
+        check_allowed(allowed, host, port)?;
+        self.inner.connect_for(info, host, port).await
+    }
+}
+
+/// A backend which allows connections only to ports in a `PortSet`, whatever the host,
+/// refusing others with `ProxyError::Disallowed`.  This is the single place the
+/// `allowed_ports` policy is applied, whichever backend it wraps.
+pub struct Ports<B: Backend> {
+    inner: B,
+    ports: PortSet,
+}
+
+impl<B: Backend> Ports<B> {
+    pub fn new(inner: B, ports: PortSet) -> Self {
+        Self { inner, ports }
+    }
+}
+
+#[async_trait::async_trait]
+impl<B: Backend> Backend for Ports<B> {
+    type Socket = B::Socket;
+
+    async fn connect(&self, host: &str, port: u16) -> Result<Self::Socket> {
+        self.connect_for(&ConnectionInfo::default(), host, port)
+            .await
+    }
+
+    async fn connect_for(
+        &self,
+        info: &ConnectionInfo,
+        host: &str,
+        port: u16,
+    ) -> Result<Self::Socket> {
+        check_allowed(self.ports.contains(port), host, port)?;
+        self.inner.connect_for(info, host, port).await
+    }
+}
+
+/// A backend which fails connections that do not complete within a time limit, with a
+/// `ProxyError::UpstreamConnect` of kind `TimedOut`.
+pub struct Timeout<B: Backend> {
+    inner: B,
+    limit: Duration,
+}
+
+impl<B: Backend> Timeout<B> {
+    pub fn new(inner: B, limit: Duration) -> Self {
+        Self { inner, limit }
+    }
+}
+
+#[async_trait::async_trait]
+impl<B: Backend> Backend for Timeout<B> {
+    type Socket = B::Socket;
+
+    async fn connect(&self, host: &str, port: u16) -> Result<Self::Socket> {
+        self.connect_for(&ConnectionInfo::default(), host, port)
+            .await
+    }
+
+    async fn connect_for(
+        &self,
+        info: &ConnectionInfo,
+        host: &str,
+        port: u16,
+    ) -> Result<Self::Socket> {
+        match timeout(self.limit, self.inner.connect_for(info, host, port)).await {
+            Ok(result) => result,
+            Err(elapsed) => Err(ProxyError::UpstreamConnect {
+                target: authority(host, port),
+                error: elapsed.into(),
+            }),
+        }
+    }
+}
+
+/// A backend which retries failed connections according to a `RetryPolicy`.  Disallowed
+/// destinations are never retried.  The policy's `rotate_addresses` is not used, since
+/// the wrapped backend chooses the addresses.
+pub struct Retry<B: Backend> {
+    inner: B,
+    policy: RetryPolicy,
+}
+
+impl<B: Backend> Retry<B> {
+    pub fn new(inner: B, policy: RetryPolicy) -> Self {
+        Self { inner, policy }
+    }
+}
+
+#[async_trait::async_trait]
+impl<B: Backend> Backend for Retry<B> {
+    type Socket = B::Socket;
+
+    async fn connect(&self, host: &str, port: u16) -> Result<Self::Socket> {
+        self.connect_for(&ConnectionInfo::default(), host, port)
+            .await
+    }
+
+    async fn connect_for(
+        &self,
+        info: &ConnectionInfo,
+        host: &str,
+        port: u16,
+    ) -> Result<Self::Socket> {
+        let mut attempt = 0;
+        loop {
+            match self.inner.connect_for(info, host, port).await {
+                Err(e)
+                    if attempt < self.policy.retries && !matches!(e, ProxyError::Disallowed(_)) =>
+                {
+                    let delay = self.policy.delay(attempt);
+                    log::debug!(
+                        "connecting to {} failed, retrying in {:?}: {:#}",
+                        authority(host, port),
+                        delay,
+                        e
+                    );
+                    METRICS.backend_connect_retries.inc();
+                    sleep(delay).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// A backend which records the latency of successful connections, and counts failed
+/// connections other than those to disallowed destinations.
+pub struct Metrics<B: Backend> {
+    inner: B,
+}
+
+impl<B: Backend> Metrics<B> {
+    pub fn new(inner: B) -> Self {
+        Self { inner }
+    }
+}
+
+#[async_trait::async_trait]
+impl<B: Backend> Backend for Metrics<B> {
+    type Socket = B::Socket;
+
+    async fn connect(&self, host: &str, port: u16) -> Result<Self::Socket> {
+        self.connect_for(&ConnectionInfo::default(), host, port)
+            .await
+    }
+
+    async fn connect_for(
+        &self,
+        info: &ConnectionInfo,
+        host: &str,
+        port: u16,
+    ) -> Result<Self::Socket> {
+        let start = Instant::now();
+        let result = self.inner.connect_for(info, host, port).await;
+        match &result {
+            Ok(_) => METRICS.backend_connect_latency.observe(start.elapsed()),
+            Err(ProxyError::Disallowed(_)) => {}
+            Err(_) => METRICS.backend_connect_failures.inc(),
+        }
+        result
+    }
+}
+
+/// A backend which limits the rate at which each client IP may open connections, refusing
+/// others with `ProxyError::RateLimited`.  Connections for clients whose address is not
+/// known are not limited, and if no configuration is given, connections are passed
+/// straight through.
+pub struct RateLimit<B: Backend> {
+    inner: B,
+    limiter: Option<RateLimiter>,
+}
+
+impl<B: Backend> RateLimit<B> {
+    pub fn new(inner: B, config: Option<&ConnectionRateConfig>) -> Self {
+        Self {
+            inner,
+            limiter: config.map(RateLimiter::new),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<B: Backend> Backend for RateLimit<B> {
+    type Socket = B::Socket;
+
+    async fn connect(&self, host: &str, port: u16) -> Result<Self::Socket> {
+        self.connect_for(&ConnectionInfo::default(), host, port)
+            .await
+    }
+
+    async fn connect_for(
+        &self,
+        info: &ConnectionInfo,
+        host: &str,
+        port: u16,
+    ) -> Result<Self::Socket> {
+        if let (Some(limiter), Some(peer)) = (&self.limiter, info.peer) {
+            let ip = peer.ip().to_canonical();
+            if !limiter.try_connect(ip, std::time::Instant::now()) {
+                METRICS.backend_connects_rate_limited.inc();
+                return Err(ProxyError::RateLimited(ip));
+            }
+        }
+        self.inner.connect_for(info, host, port).await
+    }
+}
+
+/// A builder for a stack of backend wrappers, beginning with the innermost backend.  Each
+/// method wraps the stack built so far.
+pub struct Stack<B: Backend>(B);
+
+/// The stack built by `Stack::from_config`
+pub type ConfiguredStack = Ports<AllowList<RateLimit<Metrics<Retry<Timeout<DirectBackend>>>>>>;
+
+impl Stack<DirectBackend> {
+    /// Build the stack described by a backend configuration: connections are checked
+    /// against the allowed destinations and the client's connection rate, then made
+    /// directly, with each attempt limited to `connect_timeout` and retried as configured.
+    pub fn from_config(config: &BackendConfig) -> ConfiguredStack {
+        let direct = DirectBackend::new().with_dialer(Dialer::from_config(config));
+        Stack::new(direct)
+            .timeout(config.connect_timeout)
+            .retry(RetryPolicy::from_config(config))
+            .metrics()
+            .rate_limit(config.connect_rate.as_ref())
+            .allow_list(allow_entries(config))
+            .ports(config.allowed_ports.clone())
+            .build()
+    }
+}
+
+impl<B: Backend> Stack<B> {
+    /// Begin a stack with the given backend
+    pub fn new(backend: B) -> Self {
+        Stack(backend)
+    }
+
+    /// Allow only destinations matching `entries`
+    pub fn allow_list(self, entries: Vec<AllowEntry>) -> Stack<AllowList<B>> {
+        Stack(AllowList::new(self.0, entries))
+    }
+
+    /// Allow only ports in `ports`
+    pub fn ports(self, ports: PortSet) -> Stack<Ports<B>> {
+        Stack(Ports::new(self.0, ports))
+    }
+
+    /// Fail connections that take longer than `limit`
+    pub fn timeout(self, limit: Duration) -> Stack<Timeout<B>> {
+        Stack(Timeout::new(self.0, limit))
+    }
+
+    /// Retry failed connections according to `policy`
+    pub fn retry(self, policy: RetryPolicy) -> Stack<Retry<B>> {
+        Stack(Retry::new(self.0, policy))
+    }
+
+    /// Record connection metrics
+    pub fn metrics(self) -> Stack<Metrics<B>> {
+        Stack(Metrics::new(self.0))
+    }
+
+    /// Limit each client's connection rate, if `config` is given
+    pub fn rate_limit(self, config: Option<&ConnectionRateConfig>) -> Stack<RateLimit<B>> {
+        Stack(RateLimit::new(self.0, config))
+    }
+
+    /// Finish the stack, returning the outermost backend
+    pub fn build(self) -> B {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::io::DuplexStream;
+    use tokio::net::TcpListener;
+
+    /// A backend which fails its first `failures` connections, counting attempts, and
+    /// never completes connections to host `stalled`
+    #[derive(Default)]
+    struct TestBackend {
+        failures: usize,
+        attempts: AtomicUsize,
+    }
+
+    #[async_trait::async_trait]
+    impl Backend for TestBackend {
+        type Socket = DuplexStream;
+
+        async fn connect(&self, host: &str, port: u16) -> Result<Self::Socket> {
+            if host == "stalled" {
+                std::future::pending::<()>().await;
+            }
+            if self.attempts.fetch_add(1, Ordering::SeqCst) < self.failures {
+                return Err(ProxyError::upstream(
+                    authority(host, port),
+                    "connection refused",
+                ));
+            }
+            Ok(tokio::io::duplex(16).0)
+        }
+    }
+
+    fn client(addr: &str) -> ConnectionInfo {
+        ConnectionInfo {
+            peer: Some(addr.parse().unwrap()),
+            ..ConnectionInfo::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_allow_list() {
+        let backend = Stack::new(TestBackend::default())
+            .allow_list(vec!["*.giphy.com:443,8443".parse().unwrap()])
+            .ports("443".parse().unwrap())
+            .build();
+        assert!(backend.connect("api.giphy.com", 443).await.is_ok());
+        for (host, port) in [("api.giphy.com", 8443), ("example.com", 443)].iter() {
+            let err = backend.connect(host, *port).await.err().unwrap();
+            assert!(
+                matches!(err, ProxyError::Disallowed(_)),
+                "{}:{}",
+                host,
+                port
+            );
+        }
+        assert_eq!(backend.inner.inner.attempts.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_timeout() {
+        let backend = Stack::new(TestBackend::default())
+            .timeout(Duration::from_millis(10))
+            .build();
+        match backend.connect("stalled", 443).await {
+            Err(ProxyError::UpstreamConnect { error, .. }) => {
+                assert_eq!(error.kind(), std::io::ErrorKind::TimedOut)
+            }
+            _ => panic!("expected a timeout"),
+        }
+        assert!(backend.connect("example.com", 443).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_retry() {
+        let policy = RetryPolicy {
+            retries: 2,
+            backoff: Duration::from_millis(1),
+            rotate_addresses: false,
+        };
+        let backend = TestBackend {
+            failures: 2,
+            ..TestBackend::default()
+        };
+        let backend = Stack::new(backend).retry(policy.clone()).build();
+        assert!(backend.connect("example.com", 443).await.is_ok());
+        assert_eq!(backend.inner.attempts.load(Ordering::SeqCst), 3);
+
+        let backend = TestBackend {
+            failures: 3,
+            ..TestBackend::default()
+        };
+        let backend = Stack::new(backend).retry(policy).build();
+        assert!(backend.connect("example.com", 443).await.is_err());
+        assert_eq!(backend.inner.attempts.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit() {
+        let config = ConnectionRateConfig {
+            per_second: 0.001,
+            burst: 2,
+        };
+        let backend = Stack::new(TestBackend::default())
+            .rate_limit(Some(&config))
+            .build();
+        let alice = client("10.0.0.1:5555");
+        assert!(backend
+            .connect_for(&alice, "example.com", 443)
+            .await
+            .is_ok());
+        assert!(backend
+            .connect_for(&alice, "example.com", 443)
+            .await
+            .is_ok());
+        let err = backend
+            .connect_for(&alice, "example.com", 443)
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(err, ProxyError::RateLimited(_)));
+
+        // other clients, and connections for no client, are not affected
+        let bob = client("10.0.0.2:5555");
+        assert!(backend.connect_for(&bob, "example.com", 443).await.is_ok());
+        assert!(backend.connect("example.com", 443).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_from_config() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let config = BackendConfig {
+            host: "127.0.0.1".into(),
+            port,
+            blocked_networks: vec![],
+            ..BackendConfig::default()
+        };
+        let backend = Stack::from_config(&config);
+        backend.connect("127.0.0.1", port).await.unwrap();
+        let err = backend.connect("127.0.0.2", port).await.unwrap_err();
+        assert!(matches!(err, ProxyError::Disallowed(_)));
+    }
+}
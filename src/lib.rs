@@ -0,0 +1,80 @@
+//! An HTTP CONNECT proxy, designed to allow clients to reach the Giphy API without
+//! revealing their IP address to Giphy.
+//!
+//! The simplest way to embed the proxy is with [`Proxy::builder`]:
+//!
+//! ```no_run
+//! # async fn run() -> anyhow::Result<()> {
+//! use giphyproxy::{backend::SingleHostBackend, Proxy};
+//!
+//! let proxy = Proxy::builder()
+//!     .bind("127.0.0.1:8080".parse()?)
+//!     .backend(SingleHostBackend::new("api.giphy.com", 443))
+//!     .build()?;
+//! let addrs = proxy.start().await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The lower-level pieces are available in the public modules.
+
+pub mod access;
+pub mod acl;
+pub mod admin;
+pub mod audit;
+pub mod auth;
+pub mod backend;
+pub mod balance;
+pub mod bench;
+pub mod breaker;
+mod cache;
+mod capture;
+pub mod chaos;
+pub mod client;
+pub mod client_hello;
+pub mod config;
+pub mod connection;
+pub mod daemon;
+pub mod dns;
+pub mod error;
+pub mod failover;
+mod fingerprint;
+mod forward;
+mod healthcheck;
+mod hooks;
+pub mod http;
+pub mod http2;
+#[cfg(feature = "http3")]
+mod http3;
+pub mod layer;
+pub mod listen;
+pub mod metrics;
+pub mod mitm;
+mod panics;
+mod pool;
+mod proxy;
+pub mod proxy_protocol;
+pub mod quota;
+pub mod recording;
+pub mod registry;
+mod reload;
+mod reverse;
+mod rotation;
+pub mod sandbox;
+mod sockopt;
+pub mod socks;
+#[cfg(target_os = "linux")]
+mod splice;
+mod syslog;
+pub mod systemd;
+mod tap;
+pub mod telemetry;
+#[cfg(any(test, feature = "test-util"))]
+pub mod testing;
+pub mod tls;
+mod transparent;
+mod udp;
+pub mod upgrade;
+mod websocket;
+
+pub use proxy::{Proxy, ProxyBuilder};
@@ -1,27 +1,959 @@
-use crate::backend::SingleHostBackend;
-use crate::connection::connection;
-use anyhow::Result;
-use tokio::net::TcpListener;
+use crate::acl::Acl;
+use crate::auth::Htpasswd;
+use crate::backend::Backend;
+use crate::config::{Config, ConnectionRateConfig, DrainMode, LimitsConfig, TransparentMode};
+use crate::connection::{connection, ConnectionInfo};
+use crate::http::Response;
+use crate::metrics::METRICS;
+use crate::mitm::Mitm;
+use crate::proxy_protocol;
+use crate::registry::REGISTRY;
+use crate::sockopt;
+use crate::systemd;
+use crate::telemetry::spawn;
+use crate::tls::Acceptor;
+use crate::transparent;
+use crate::upgrade;
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore, SetOnce};
 
-/// Listen for connections on the given IP and port, handling each one with `connection`.
+/// Admission control for new connections, limiting both the total number of concurrent
+/// connections and the number from any single client IP.
+pub struct Admission {
+    global: Arc<Semaphore>,
+    per_client: Mutex<HashMap<IpAddr, usize>>,
+    max_per_client: usize,
+}
+
+/// One of the global permits, taken for a connection whose client is not yet known, such
+/// as one whose PROXY protocol header has not been read.  Released when dropped.
+pub struct Reservation {
+    global: OwnedSemaphorePermit,
+    admission: Arc<Admission>,
+}
+
+/// Permission for a connection to proceed, released when dropped.
+pub struct Permit {
+    _global: OwnedSemaphorePermit,
+    admission: Arc<Admission>,
+    ip: IpAddr,
+}
+
+impl Admission {
+    pub fn new(limits: &LimitsConfig) -> Arc<Self> {
+        Arc::new(Self {
+            global: Arc::new(Semaphore::new(limits.max_connections)),
+            per_client: Mutex::new(HashMap::new()),
+            max_per_client: limits.max_connections_per_client,
+        })
+    }
+
+    /// Try to admit a new connection from the given IP, returning None if either limit
+    /// has been reached.
+    pub fn try_admit(self: &Arc<Self>, ip: IpAddr) -> Option<Permit> {
+        self.try_reserve()?.admit(ip)
+    }
+
+    /// Try to take one of the global permits for a new connection, returning None if the
+    /// global limit has been reached.  The per-client limit is applied by
+    /// `Reservation::admit`, once the client is known.
+    pub fn try_reserve(self: &Arc<Self>) -> Option<Reservation> {
+        Some(Reservation {
+            global: self.global.clone().try_acquire_owned().ok()?,
+            admission: self.clone(),
+        })
+    }
+}
+
+impl Reservation {
+    /// Admit the connection as one from the given IP, returning None if the per-client
+    /// limit has been reached; the global permit is then released.
+    pub fn admit(self, ip: IpAddr) -> Option<Permit> {
+        let mut per_client = self.admission.per_client.lock().unwrap();
+        let count = per_client.entry(ip).or_insert(0);
+        if *count >= self.admission.max_per_client {
+            return None;
+        }
+        *count += 1;
+        drop(per_client);
+
+        Some(Permit {
+            _global: self.global,
+            admission: self.admission,
+            ip,
+        })
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let mut per_client = self.admission.per_client.lock().unwrap();
+        if let Some(count) = per_client.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                per_client.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// The number of clients tracked by a `RateLimiter` before it first looks for clients whose
+/// buckets have drained, and can be forgotten
+const RATE_LIMITER_PRUNE_AT: usize = 1024;
+
+/// A leaky-bucket limit on the rate at which each client IP may open new connections.
+/// Each connection adds one to its client's bucket, which drains at a steady rate, and a
+/// connection that would overflow the bucket is refused.
+pub struct RateLimiter {
+    per_second: f64,
+    burst: f64,
+    buckets: Mutex<Buckets>,
+}
+
+struct Buckets {
+    /// The level of each client's bucket, as of the given time
+    levels: HashMap<IpAddr, (f64, Instant)>,
+    /// Size of `levels` at which to next remove drained buckets
+    prune_at: usize,
+}
+
+impl RateLimiter {
+    pub fn new(config: &ConnectionRateConfig) -> Self {
+        Self {
+            per_second: config.per_second,
+            burst: config.burst as f64,
+            buckets: Mutex::new(Buckets {
+                levels: HashMap::new(),
+                prune_at: RATE_LIMITER_PRUNE_AT,
+            }),
+        }
+    }
+
+    /// The level of a bucket at `now`, after draining
+    fn level_at(&self, (level, updated): (f64, Instant), now: Instant) -> f64 {
+        let drained = now.saturating_duration_since(updated).as_secs_f64() * self.per_second;
+        (level - drained).max(0.0)
+    }
+
+    /// Record a new connection from the given IP at `now`, returning false if the client
+    /// has exceeded its rate and the connection should be refused.
+    pub fn try_connect(&self, ip: IpAddr, now: Instant) -> bool {
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.levels.len() >= buckets.prune_at {
+            buckets
+                .levels
+                .retain(|_, bucket| self.level_at(*bucket, now) > 0.0);
+            buckets.prune_at = RATE_LIMITER_PRUNE_AT.max(buckets.levels.len() * 2);
+        }
+
+        let bucket = buckets.levels.entry(ip).or_insert((0.0, now));
+        let level = self.level_at(*bucket, now);
+        if level + 1.0 > self.burst {
+            *bucket = (level, now);
+            return false;
+        }
+        *bucket = (level + 1.0, now);
+        true
+    }
+}
+
+/// Send a client an error response, and close its connection.  A redirected client is not
+/// talking to the proxy, so it gets no response.
+async fn reject(mut socket: TcpStream, info: &ConnectionInfo, response: Response) {
+    if info.original_dst.is_none() {
+        let _ = socket.write_all(&response.to_bytes()).await;
+    }
+    let _ = socket.shutdown().await;
+}
+
+/// The state shared by all listeners
+struct Shared<B: Backend> {
+    config: Arc<Config>,
+    backend: Arc<B>,
+    htpasswd: Option<Arc<Htpasswd>>,
+    mitm: Option<Arc<Mitm>>,
+    tls: Option<Arc<Acceptor>>,
+    acl: Option<Arc<Acl>>,
+    /// Connection limits, shared by all listeners
+    admission: Arc<Admission>,
+    rate_limiter: Option<RateLimiter>,
+}
+
+/// Listen for connections on the configured addresses, handling each one with `connection`
+/// and the given backend, requiring authentication if `htpasswd` is given and intercepting
+/// tunnels selected by `mitm` if it is given.  If `tls` is
+/// given, each connection begins with a TLS handshake, which must complete within the
+/// head timeout, and clients that fail to present an acceptable certificate are dropped
+/// before their request is read.  If `acl` is given, clients it does not permit are
+/// disconnected as soon as they are accepted.
+///
+/// If the PROXY protocol is enabled, the client address in each connection's header
+/// (which must arrive within the head timeout) is used in place of the load balancer's
+/// address for access control, rate limits, and logging.
 ///
-/// This function returns when the port is bound, with the listener running in a separate task.
-pub async fn start_listening(ip_and_port: &str) -> Result<()> {
-    log::info!("Listening on {}", ip_and_port);
-    let listener = TcpListener::bind(ip_and_port).await?;
+/// Connections to the transparent listeners were redirected there by the firewall, and are
+/// tunneled to their original destinations, without the PROXY protocol, TLS, or
+/// authentication.
+///
+/// If `acceptors` is more than 1, each address is bound that many times with
+/// `SO_REUSEPORT`, with an accept loop for each socket.  If the process was started by
+/// systemd socket activation, the sockets systemd passes are used instead of the
+/// configured addresses, and if it was started by an upgrade, the sockets the previous
+/// process handed over are used for their addresses.
+///
+/// This function returns when all ports are bound, with the listeners running in separate
+/// tasks.  The result contains the bound addresses, in the same order as the configuration
+/// (or the systemd socket unit).  The transparent listeners are not included.
+pub async fn start_listening<B: Backend + 'static>(
+    config: Arc<Config>,
+    backend: Arc<B>,
+    htpasswd: Option<Arc<Htpasswd>>,
+    mitm: Option<Arc<Mitm>>,
+    tls: Option<Arc<Acceptor>>,
+    acl: Option<Arc<Acl>>,
+) -> Result<Vec<SocketAddr>> {
+    let shared = Arc::new(Shared {
+        admission: Admission::new(&config.limits),
+        rate_limiter: config.limits.connection_rate.as_ref().map(RateLimiter::new),
+        config: config.clone(),
+        backend,
+        htpasswd,
+        mitm,
+        tls,
+        acl,
+    });
 
-    tokio::spawn(async move {
-        loop {
-            let (socket, _) = listener.accept().await.expect("socket.accept failed");
-            let backend = SingleHostBackend::new("api.giphy.com", 443);
+    // the sockets for each listen address
+    let mut listeners: Vec<Vec<TcpListener>> = vec![];
+    let inherited = systemd::take_listeners()?;
+    if inherited.is_empty() {
+        for addr in &config.listen {
+            let addr = *addr;
+            listeners.push(upgrade::listeners(addr, || {
+                bind(addr, config.acceptors, &config)
+            })?);
+        }
+    } else {
+        log::info!("Using {} sockets from systemd", inherited.len());
+        for listener in inherited {
+            listener.set_nonblocking(true)?;
+            listeners.push(vec![TcpListener::from_std(listener)?]);
+        }
+    }
+
+    let mut bound = Vec::with_capacity(listeners.len());
+    for acceptors in listeners {
+        let local_addr = acceptors[0].local_addr()?;
+        if acceptors.len() > 1 {
+            log::info!(
+                "Listening on {} with {} acceptors",
+                local_addr,
+                acceptors.len()
+            );
+        } else {
+            log::info!("Listening on {}", local_addr);
+        }
+        bound.push(local_addr);
+
+        for listener in acceptors {
+            let shared = shared.clone();
+            spawn_acceptor(
+                "acceptor",
+                listener,
+                local_addr,
+                config.restart_acceptors,
+                config.drain_mode == DrainMode::StopAccepting,
+                move |socket, peer| {
+                    METRICS.connections_accepted.inc();
+                    spawn(
+                        "connection",
+                        shared.clone().accepted(socket, peer, local_addr, None),
+                    );
+                },
+            );
+        }
+    }
+
+    let mode = config.transparent.mode;
+    for addr in &config.transparent.listen {
+        let addr = *addr;
+        let listeners =
+            upgrade::listeners(addr, || Ok(vec![transparent::bind(addr, mode, &config)?]))?;
+        for listener in listeners {
+            let local_addr = listener.local_addr()?;
+            log::info!("Listening for redirected connections on {}", local_addr);
+            let shared = shared.clone();
+            spawn_acceptor(
+                "transparent-acceptor",
+                listener,
+                local_addr,
+                config.restart_acceptors,
+                config.drain_mode == DrainMode::StopAccepting,
+                move |socket, peer| {
+                    METRICS.connections_accepted.inc();
+                    spawn(
+                        "connection",
+                        shared
+                            .clone()
+                            .accepted(socket, peer, local_addr, Some(mode)),
+                    );
+                },
+            );
+        }
+    }
+
+    Ok(bound)
+}
 
-            tokio::spawn(async move {
-                if let Err(e) = connection(socket, backend).await {
-                    log::error!("connection handler failed: {:?}", e);
+/// What an error from `accept(2)` means for the accept loop
+#[derive(Debug, PartialEq)]
+enum AcceptErrorKind {
+    /// The error concerns only the connection being accepted, so the next can be accepted
+    /// immediately
+    Connection,
+    /// The process or system is out of some resource, such as file descriptors, so
+    /// accepting should pause to let connections finish and release them
+    Exhausted,
+    /// The listener cannot accept connections any more
+    Fatal,
+}
+
+impl AcceptErrorKind {
+    fn of(error: &io::Error) -> Self {
+        use io::ErrorKind::*;
+        #[cfg(unix)]
+        if let Some(libc::EMFILE | libc::ENFILE | libc::ENOBUFS | libc::ENOMEM) =
+            error.raw_os_error()
+        {
+            return AcceptErrorKind::Exhausted;
+        }
+        match error.kind() {
+            ConnectionAborted | ConnectionReset | Interrupted | WouldBlock | TimedOut => {
+                AcceptErrorKind::Connection
+            }
+            // Linux reports protocol errors and firewall rejections on the new connection
+            // from accept(2)
+            PermissionDenied => AcceptErrorKind::Connection,
+            #[cfg(unix)]
+            _ if error.raw_os_error() == Some(libc::EPROTO) => AcceptErrorKind::Connection,
+            OutOfMemory => AcceptErrorKind::Exhausted,
+            _ => AcceptErrorKind::Fatal,
+        }
+    }
+}
+
+/// The shortest and longest pauses after accept errors caused by resource exhaustion.  The
+/// pause doubles with each consecutive error.
+const ACCEPT_BACKOFF: (Duration, Duration) = (Duration::from_millis(10), Duration::from_secs(1));
+
+/// The first fatal error from an accept loop
+static ACCEPT_FAILURE: SetOnce<String> = SetOnce::const_new();
+
+/// The pause before restarting an accept loop that panicked
+const ACCEPTOR_RESTART_DELAY: Duration = Duration::from_millis(100);
+
+/// Accept a connection from the listener.  Errors affecting only the connection being
+/// accepted are skipped, and errors caused by resource exhaustion, such as running out of
+/// file descriptors, are retried after a pause.  Other errors are returned.
+async fn accept(listener: &TcpListener) -> io::Result<(TcpStream, SocketAddr)> {
+    let mut backoff = ACCEPT_BACKOFF.0;
+    loop {
+        let error = match listener.accept().await {
+            Ok(accepted) => return Ok(accepted),
+            Err(e) => e,
+        };
+        METRICS.accept_errors.inc();
+        match AcceptErrorKind::of(&error) {
+            AcceptErrorKind::Connection => log::debug!("accepting connection: {}", error),
+            AcceptErrorKind::Exhausted => {
+                log::error!("accepting connection: {}; pausing for {:?}", error, backoff);
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(ACCEPT_BACKOFF.1);
+            }
+            AcceptErrorKind::Fatal => return Err(error),
+        }
+    }
+}
+
+/// Run an accept loop for `listener`, bound to `addr`, in a task named `name`, passing
+/// each connection to `handle`.  The loop is supervised by another task: if it ends with
+/// a fatal error, or panics and `restart` is not set, the failure is recorded for
+/// `accept_failure`.  If it panics and `restart` is set, it is restarted after a pause.
+/// If `pause_while_draining` is set, no connections are accepted while the proxy is
+/// draining, and none are accepted at all once the listening sockets have been handed
+/// over to a new process by an upgrade.
+pub(crate) fn spawn_acceptor<F>(
+    name: &'static str,
+    listener: TcpListener,
+    addr: SocketAddr,
+    restart: bool,
+    pause_while_draining: bool,
+    handle: F,
+) where
+    F: Fn(TcpStream, SocketAddr) + Send + Sync + 'static,
+{
+    let listener = Arc::new(listener);
+    let handle = Arc::new(handle);
+    spawn("acceptor-supervisor", async move {
+        loop {
+            let (listener, handle) = (listener.clone(), handle.clone());
+            let task = spawn(name, async move {
+                loop {
+                    if pause_while_draining {
+                        REGISTRY.not_draining().await;
+                    }
+                    let accepted = tokio::select! {
+                        accepted = accept(&listener) => accepted,
+                        // once the sockets are handed over, the new process accepts
+                        // connections instead
+                        () = upgrade::handed_over() => std::future::pending().await,
+                    };
+                    match accepted {
+                        Ok((socket, peer)) => handle(socket, peer),
+                        Err(e) => break e,
+                    }
                 }
             });
+            match task.await {
+                Ok(e) => return acceptor_failed(addr, e),
+                Err(e) if e.is_panic() && restart => {
+                    log::error!("accept loop for {} panicked; restarting it", addr);
+                    tokio::time::sleep(ACCEPTOR_RESTART_DELAY).await;
+                }
+                Err(e) => return acceptor_failed(addr, e),
+            }
         }
     });
+}
+
+/// Record that the accept loop for `addr` has failed, so that `accept_failure` returns
+fn acceptor_failed<E: std::fmt::Display>(addr: SocketAddr, error: E) {
+    let message = format!("accepting connections on {}: {}", addr, error);
+    log::error!("{}", message);
+    let _ = ACCEPT_FAILURE.set(message);
+}
+
+/// Wait until an accept loop fails, returning its error.  Connections are still accepted
+/// on other addresses, but as the failure is permanent, the process should usually exit
+/// so that it can be restarted.
+pub async fn accept_failure() -> anyhow::Error {
+    anyhow::anyhow!("{}", ACCEPT_FAILURE.wait().await)
+}
+
+/// Bind `count` sockets to the same address, with `SO_REUSEPORT` if there is more than
+/// one, each with the configured backlog and buffer sizes.  If the address has port 0,
+/// the first socket's port is used for the rest.
+fn bind(mut addr: SocketAddr, count: usize, config: &Config) -> Result<Vec<TcpListener>> {
+    let mut listeners = Vec::with_capacity(count);
+    for _ in 0..count {
+        let socket = sockopt::socket(addr, &config.socket)?;
+        // as for TcpListener::bind
+        #[cfg(unix)]
+        socket.set_reuseaddr(true)?;
+        if count > 1 {
+            set_reuseport(&socket)?;
+        }
+        socket
+            .bind(addr)
+            .with_context(|| format!("binding {}", addr))?;
+        let listener = socket.listen(config.backlog)?;
+        addr = listener.local_addr()?;
+        listeners.push(listener);
+    }
+    Ok(listeners)
+}
+
+/// Bind a single socket to `addr`, with the operating system's default options
+pub(crate) fn bind_default(addr: SocketAddr) -> Result<Vec<TcpListener>> {
+    let listener = std::net::TcpListener::bind(addr)?;
+    listener.set_nonblocking(true)?;
+    Ok(vec![TcpListener::from_std(listener)?])
+}
+
+#[cfg(unix)]
+fn set_reuseport(socket: &TcpSocket) -> Result<()> {
+    Ok(socket.set_reuseport(true)?)
+}
+
+/// `SO_REUSEPORT` is not supported on this platform
+#[cfg(not(unix))]
+fn set_reuseport(_socket: &TcpSocket) -> Result<()> {
+    anyhow::bail!("acceptors > 1 is not supported on this platform")
+}
+
+impl<B: Backend + 'static> Shared<B> {
+    /// Handle a newly accepted connection, rejecting it if necessary.  If `transparent` is
+    /// given, the connection was accepted on a transparent listener in that mode.
+    async fn accepted(
+        self: Arc<Self>,
+        mut socket: TcpStream,
+        peer: SocketAddr,
+        local: SocketAddr,
+        transparent: Option<TransparentMode>,
+    ) {
+        if let Err(e) = sockopt::configure(&socket, &self.config.socket) {
+            log::warn!("setting socket options for {}: {}", peer, e);
+        }
+        let mut info = ConnectionInfo::tcp(peer, local);
+        if let Some(mode) = transparent {
+            match transparent::original_dst(&socket, mode, local) {
+                Ok(dst) => info.original_dst = Some(dst),
+                Err(e) => {
+                    log::warn!("refusing connection from {}: {}", peer, e);
+                    return;
+                }
+            }
+        }
+
+        // the draining check and the global limit apply before anything is read from the
+        // connection, so that connections which send nothing are limited, too
+        if REGISTRY.draining() {
+            log::debug!("rejecting connection from {}: draining", info);
+            METRICS.connections_rejected.inc();
+            let response = Response::error(503, "Service Unavailable", "server is draining");
+            return reject(socket, &info, response).await;
+        }
+        let Some(reservation) = self.admission.try_reserve() else {
+            return self.too_many_connections(socket, &info).await;
+        };
+
+        let proxy_protocol = &self.config.proxy_protocol;
+        if proxy_protocol.enabled && transparent.is_none() {
+            let header = tokio::time::timeout(
+                self.config.limits.head_timeout,
+                proxy_protocol::read_header(&mut socket, proxy_protocol.required),
+            )
+            .await;
+            match header {
+                Ok(Ok(Some(client))) => {
+                    info.peer = Some(client);
+                    info.proxied_by = Some(peer);
+                }
+                Ok(Ok(None)) => {}
+                Ok(Err(e)) => {
+                    log::warn!("refusing connection from {}: {:#}", peer, e);
+                    return;
+                }
+                Err(_) => {
+                    log::warn!(
+                        "refusing connection from {}: timed out reading PROXY protocol header",
+                        peer
+                    );
+                    return;
+                }
+            }
+        }
+        // the address of the client itself, after any PROXY protocol header
+        let client = info.peer.unwrap_or(peer);
+
+        if let Some(acl) = &self.acl {
+            if !acl.permits(client.ip()) {
+                log::warn!("refusing connection from {}: denied by ACL", info);
+                METRICS.connections_denied.inc();
+                // dropping the socket closes it
+                return;
+            }
+        }
+
+        if let Some(rate_limiter) = &self.rate_limiter {
+            if !rate_limiter.try_connect(client.ip(), Instant::now()) {
+                // a misbehaving client may do this many times a second, so this is only
+                // logged at debug level; the metric shows how often it happens
+                log::debug!(
+                    "rejecting connection from {}: connection rate exceeded",
+                    info
+                );
+                METRICS.connections_rate_limited.inc();
+                let response =
+                    Response::error(429, "Too Many Requests", "connection rate exceeded");
+                return reject(socket, &info, response).await;
+            }
+        }
+
+        let Some(permit) = reservation.admit(client.ip()) else {
+            return self.too_many_connections(socket, &info).await;
+        };
+
+        let config = self.config.clone();
+        let backend = self.backend.clone();
+        let htpasswd = self.htpasswd.clone();
+        let mitm = self.mitm.clone();
+        let tls = self.tls.as_ref().filter(|_| transparent.is_none());
+        let summary = match tls {
+            Some(tls) => match tls.accept(socket, config.limits.head_timeout).await {
+                Ok((socket, client_cert)) => {
+                    let info = ConnectionInfo {
+                        client_cert,
+                        ..info.clone()
+                    };
+                    connection(socket, backend, config, htpasswd, mitm, info).await
+                }
+                Err(e) => {
+                    log::error!("connection from {} failed: {:?}", info, e);
+                    return;
+                }
+            },
+            None => connection(socket, backend, config, htpasswd, mitm, info.clone()).await,
+        };
+        log::debug!("connection from {} closed: {}", info, summary);
+        drop(permit);
+    }
+
+    /// Reject a connection because of the connection limits
+    async fn too_many_connections(&self, socket: TcpStream, info: &ConnectionInfo) {
+        log::warn!("rejecting connection from {}: too many connections", info);
+        METRICS.connections_rejected.inc();
+        let response = Response::error(503, "Service Unavailable", "too many connections");
+        reject(socket, info, response).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn admission(max_connections: usize, max_connections_per_client: usize) -> Arc<Admission> {
+        Admission::new(&LimitsConfig {
+            max_connections,
+            max_connections_per_client,
+            ..LimitsConfig::default()
+        })
+    }
+
+    #[test]
+    fn test_accept_error_kind() {
+        let kind = |kind: io::ErrorKind| AcceptErrorKind::of(&io::Error::from(kind));
+        assert_eq!(
+            kind(io::ErrorKind::ConnectionAborted),
+            AcceptErrorKind::Connection
+        );
+        assert_eq!(kind(io::ErrorKind::OutOfMemory), AcceptErrorKind::Exhausted);
+        assert_eq!(kind(io::ErrorKind::InvalidInput), AcceptErrorKind::Fatal);
+        #[cfg(unix)]
+        {
+            let os = |code: i32| AcceptErrorKind::of(&io::Error::from_raw_os_error(code));
+            assert_eq!(os(libc::EMFILE), AcceptErrorKind::Exhausted);
+            assert_eq!(os(libc::ENFILE), AcceptErrorKind::Exhausted);
+            assert_eq!(os(libc::EPROTO), AcceptErrorKind::Connection);
+            assert_eq!(os(libc::EBADF), AcceptErrorKind::Fatal);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_acceptor_restart() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        let panicked = std::sync::atomic::AtomicBool::new(false);
+        spawn_acceptor(
+            "test-acceptor",
+            listener,
+            addr,
+            true,
+            false,
+            move |socket, _| {
+                // the first connection makes the accept loop panic
+                if !panicked.swap(true, std::sync::atomic::Ordering::SeqCst) {
+                    panic!("test panic");
+                }
+                sender.send(socket).unwrap();
+            },
+        );
+
+        let _first = TcpStream::connect(addr).await.unwrap();
+        let second = TcpStream::connect(addr).await.unwrap();
+        let accepted = receiver.recv().await.unwrap();
+        assert_eq!(accepted.peer_addr().unwrap(), second.local_addr().unwrap());
+    }
+
+    #[test]
+    fn test_admission_per_client() {
+        let admission = admission(10, 2);
+        let a: IpAddr = "10.0.0.1".parse().unwrap();
+        let b: IpAddr = "10.0.0.2".parse().unwrap();
+
+        let p1 = admission.try_admit(a).unwrap();
+        let _p2 = admission.try_admit(a).unwrap();
+        assert!(admission.try_admit(a).is_none());
+        let _p3 = admission.try_admit(b).unwrap();
+
+        drop(p1);
+        assert!(admission.try_admit(a).is_some());
+    }
+
+    #[test]
+    fn test_admission_global() {
+        let admission = admission(2, 10);
+        let a: IpAddr = "10.0.0.1".parse().unwrap();
+        let b: IpAddr = "10.0.0.2".parse().unwrap();
+
+        let p1 = admission.try_admit(a).unwrap();
+        let _p2 = admission.try_admit(b).unwrap();
+        assert!(admission.try_admit(b).is_none());
+
+        drop(p1);
+        assert!(admission.try_admit(b).is_some());
+    }
+
+    #[test]
+    fn test_admission_reserve() {
+        let admission = admission(2, 1);
+        let a: IpAddr = "10.0.0.1".parse().unwrap();
+
+        // a reservation holds a global permit before its client is known
+        let r1 = admission.try_reserve().unwrap();
+        let _p1 = admission.try_admit(a).unwrap();
+        assert!(admission.try_reserve().is_none());
+        // the per-client limit applies once it is
+        assert!(r1.admit(a).is_none());
+        assert!(admission.try_reserve().is_some());
+    }
+
+    #[test]
+    fn test_admission_releases_client_entries() {
+        let admission = admission(10, 10);
+        let a: IpAddr = "10.0.0.1".parse().unwrap();
+        drop(admission.try_admit(a).unwrap());
+        assert!(admission.per_client.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let limiter = RateLimiter::new(&ConnectionRateConfig {
+            per_second: 2.0,
+            burst: 3,
+        });
+        let a: IpAddr = "10.0.0.1".parse().unwrap();
+        let b: IpAddr = "10.0.0.2".parse().unwrap();
+        let start = Instant::now();
+        let at = |millis| start + std::time::Duration::from_millis(millis);
+
+        // a burst is allowed, but then connections are refused..
+        for _ in 0..3 {
+            assert!(limiter.try_connect(a, at(0)));
+        }
+        assert!(!limiter.try_connect(a, at(0)));
+        assert!(!limiter.try_connect(a, at(100)));
+        // ..without affecting other clients
+        assert!(limiter.try_connect(b, at(100)));
+
+        // the bucket drains at two connections per second
+        assert!(limiter.try_connect(a, at(500)));
+        assert!(!limiter.try_connect(a, at(600)));
+        assert!(limiter.try_connect(a, at(1000)));
+        assert!(limiter.try_connect(a, at(5000)));
+    }
+
+    #[test]
+    fn test_rate_limiter_prunes_drained_buckets() {
+        let limiter = RateLimiter::new(&ConnectionRateConfig {
+            per_second: 1.0,
+            burst: 1,
+        });
+        let start = Instant::now();
+        for i in 0..RATE_LIMITER_PRUNE_AT as u32 {
+            assert!(limiter.try_connect(IpAddr::from(i.to_be_bytes()), start));
+        }
+        // after a second, all of those buckets have drained
+        let later = start + std::time::Duration::from_secs(1);
+        assert!(limiter.try_connect("10.0.0.1".parse().unwrap(), later));
+        assert_eq!(limiter.buckets.lock().unwrap().levels.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limited_with_429() {
+        use tokio::io::AsyncReadExt;
+
+        let mut config = Config {
+            listen: vec!["127.0.0.1:0".parse().unwrap()],
+            ..Config::default()
+        };
+        config.limits.connection_rate = Some(ConnectionRateConfig {
+            per_second: 0.001,
+            burst: 1,
+        });
+        let backend = Arc::new(crate::backend::SingleHostBackend::new("127.0.0.1", 1));
+        let addrs = start_listening(Arc::new(config), backend, None, None, None, None)
+            .await
+            .unwrap();
+
+        // the first connection is allowed, and the second is refused
+        let _first = TcpStream::connect(addrs[0]).await.unwrap();
+        let mut client = TcpStream::connect(addrs[0]).await.unwrap();
+        let mut buf = vec![];
+        client.read_to_end(&mut buf).await.unwrap();
+        assert!(buf.starts_with(b"HTTP/1.1 429 Too Many Requests\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_acl_denied() {
+        use crate::config::AclConfig;
+        use tokio::io::AsyncReadExt;
+
+        let config = Config {
+            listen: vec!["127.0.0.1:0".parse().unwrap()],
+            ..Config::default()
+        };
+        let acl = Acl::new(&AclConfig {
+            deny: vec!["127.0.0.0/8".parse().unwrap()],
+            ..AclConfig::default()
+        })
+        .unwrap();
+        let backend = Arc::new(crate::backend::SingleHostBackend::new("127.0.0.1", 1));
+        let addrs = start_listening(
+            Arc::new(config),
+            backend,
+            None,
+            None,
+            None,
+            Some(Arc::new(acl)),
+        )
+        .await
+        .unwrap();
+
+        // the connection is closed without any response
+        let mut client = TcpStream::connect(addrs[0]).await.unwrap();
+        let mut buf = vec![];
+        let _ = client.read_to_end(&mut buf).await;
+        assert!(buf.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_proxy_protocol() {
+        use crate::config::{AclConfig, ProxyProtocolConfig};
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let config = Config {
+            listen: vec!["127.0.0.1:0".parse().unwrap()],
+            proxy_protocol: ProxyProtocolConfig {
+                enabled: true,
+                required: true,
+            },
+            ..Config::default()
+        };
+        let acl = Acl::new(&AclConfig {
+            deny: vec!["192.0.2.0/24".parse().unwrap()],
+            ..AclConfig::default()
+        })
+        .unwrap();
+        let backend = Arc::new(crate::backend::SingleHostBackend::new("127.0.0.1", 1));
+        let addrs = start_listening(
+            Arc::new(config),
+            backend,
+            None,
+            None,
+            None,
+            Some(Arc::new(acl)),
+        )
+        .await
+        .unwrap();
+
+        let addr = addrs[0];
+        let request = |head: &'static [u8]| async move {
+            let mut client = TcpStream::connect(addr).await.unwrap();
+            client.write_all(head).await.unwrap();
+            let mut buf = vec![];
+            let _ = client.read_to_end(&mut buf).await;
+            buf
+        };
+
+        // the ACL applies to the address in the header
+        let response = request(
+            b"PROXY TCP4 198.51.100.1 127.0.0.1 5555 8080\r\nCONNECT 127.0.0.1:1 HTTP/1.1\r\n\r\n",
+        )
+        .await;
+        assert!(response.starts_with(b"HTTP/1.1 502 Bad Gateway\r\n"));
+        let response = request(
+            b"PROXY TCP4 192.0.2.1 127.0.0.1 5555 8080\r\nCONNECT 127.0.0.1:1 HTTP/1.1\r\n\r\n",
+        )
+        .await;
+        assert!(response.is_empty());
+
+        // connections without a header are closed without any response
+        let response = request(b"CONNECT 127.0.0.1:1 HTTP/1.1\r\n\r\n").await;
+        assert!(response.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_proxy_protocol_idle_connection_is_limited() {
+        use crate::config::ProxyProtocolConfig;
+        use tokio::io::AsyncReadExt;
+
+        let mut config = Config {
+            listen: vec!["127.0.0.1:0".parse().unwrap()],
+            proxy_protocol: ProxyProtocolConfig {
+                enabled: true,
+                required: true,
+            },
+            ..Config::default()
+        };
+        config.limits.max_connections = 1;
+        let backend = Arc::new(crate::backend::SingleHostBackend::new("127.0.0.1", 1));
+        let addrs = start_listening(Arc::new(config), backend, None, None, None, None)
+            .await
+            .unwrap();
+
+        // a connection that never sends its header holds the only permit..
+        let _idle = TcpStream::connect(addrs[0]).await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        // ..so the next is refused without waiting for its header
+        let mut client = TcpStream::connect(addrs[0]).await.unwrap();
+        let mut buf = vec![];
+        client.read_to_end(&mut buf).await.unwrap();
+        assert!(buf.starts_with(b"HTTP/1.1 503 Service Unavailable\r\n"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_acceptors() {
+        use tokio::io::AsyncReadExt;
+
+        let mut config = Config {
+            listen: vec!["127.0.0.1:0".parse().unwrap()],
+            acceptors: 4,
+            ..Config::default()
+        };
+        config.limits.max_connections_per_client = 0;
+        let backend = Arc::new(crate::backend::SingleHostBackend::new("127.0.0.1", 1));
+        let addrs = start_listening(Arc::new(config), backend, None, None, None, None)
+            .await
+            .unwrap();
+        assert_eq!(addrs.len(), 1);
+        assert_ne!(addrs[0].port(), 0);
+
+        // whichever acceptor gets each connection, it is handled the same way
+        for _ in 0..8 {
+            let mut client = TcpStream::connect(addrs[0]).await.unwrap();
+            let mut buf = vec![];
+            client.read_to_end(&mut buf).await.unwrap();
+            assert!(buf.starts_with(b"HTTP/1.1 503 Service Unavailable\r\n"));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reject_with_503() {
+        use tokio::io::AsyncReadExt;
+
+        let mut config = Config {
+            listen: vec!["127.0.0.1:0".parse().unwrap()],
+            ..Config::default()
+        };
+        config.limits.max_connections_per_client = 0;
+        let backend = Arc::new(crate::backend::SingleHostBackend::new("127.0.0.1", 1));
+        let addrs = start_listening(Arc::new(config), backend, None, None, None, None)
+            .await
+            .unwrap();
 
-    Ok(())
+        let mut client = TcpStream::connect(addrs[0]).await.unwrap();
+        let mut buf = vec![];
+        client.read_to_end(&mut buf).await.unwrap();
+        assert!(buf.starts_with(b"HTTP/1.1 503 Service Unavailable\r\n"));
+    }
 }
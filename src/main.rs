@@ -1,44 +1,672 @@
-mod backend;
-mod connection;
-mod http;
-mod listen;
-
 use anyhow::Result;
-use listen::start_listening;
-use std::time::Duration;
-use tokio::time;
+use base64::Engine;
+use clap::{Args, Parser, Subcommand};
+use giphyproxy::audit::{AuditDb, AuditQuery};
+use giphyproxy::backend::Endpoint;
+use giphyproxy::bench::{self, BenchOptions};
+use giphyproxy::config::{AuditConfig, Config, DaemonConfig, RuntimeConfig};
+use giphyproxy::registry::REGISTRY;
+use giphyproxy::Proxy;
+use giphyproxy::{client, daemon, listen, quota, sandbox, systemd, telemetry, tls, upgrade};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::runtime::{self, Runtime};
+
+/// An HTTP CONNECT proxy for the Giphy API
+#[derive(Parser, Debug)]
+#[command(name = "giphyproxy", version)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    overrides: ConfigArgs,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Run the proxy (the default)
+    Serve,
+
+    /// Validate the configuration, print it, and exit
+    CheckConfig,
+
+    /// Print the version and exit
+    Version,
+
+    /// Print records from the audit database, one JSON object per line, oldest first
+    Audit(AuditArgs),
+
+    /// Stop the proxy running with the configured PID file (`daemon.pid_file`)
+    Stop(StopArgs),
+
+    /// Report whether the proxy is running with the configured PID file, exiting with
+    /// status 3 if it is not
+    Status,
+
+    /// Check that the proxy is serving on this host, for container health checks, exiting
+    /// with status 1 if it is not
+    Healthcheck(HealthcheckArgs),
+
+    /// Connect to a TLS server through the proxy, and report how long each step took
+    Probe(ProbeArgs),
+
+    /// Send payloads through many tunnels to an echoing destination, and report the
+    /// throughput, latencies, and errors
+    Bench(BenchArgs),
+}
+
+#[derive(Args, Debug)]
+struct AuditArgs {
+    /// Only records for connections that ended within this many seconds
+    #[arg(long, value_name = "SECS")]
+    since: Option<u64>,
+
+    /// Only records for this destination (`host:port`)
+    #[arg(long, value_name = "HOST:PORT")]
+    destination: Option<String>,
+
+    /// The maximum number of records, the most recent being printed
+    #[arg(long, default_value_t = 100)]
+    limit: usize,
+}
+
+impl AuditArgs {
+    /// Query the configured audit database and print the results
+    fn run(&self, config: &AuditConfig) -> Result<()> {
+        let Some(path) = &config.database else {
+            anyhow::bail!("no audit database configured (audit.database)");
+        };
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let query = AuditQuery {
+            since: self.since.map(|s| now.saturating_sub(s) as i64),
+            destination: self.destination.clone(),
+            limit: Some(self.limit),
+        };
+        for entry in AuditDb::open(path)?.query(&query)? {
+            println!("{}", serde_json::to_string(&entry)?);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Args, Debug)]
+struct StopArgs {
+    /// Seconds to wait for the proxy to exit
+    #[arg(long, value_name = "SECS", default_value_t = 30)]
+    timeout: u64,
+}
+
+impl StopArgs {
+    /// Stop the proxy and wait for it to exit
+    fn run(&self, config: &DaemonConfig) -> Result<()> {
+        let pid_file = pid_file(config)?;
+        match daemon::stop(pid_file, Duration::from_secs(self.timeout))? {
+            Some(pid) => println!("stopped giphyproxy (PID {})", pid),
+            None => println!("giphyproxy is not running"),
+        }
+        Ok(())
+    }
+}
+
+#[derive(Args, Debug)]
+struct HealthcheckArgs {
+    /// Request the admin server's `/readyz`, rather than `/healthz`
+    #[arg(long)]
+    ready: bool,
+
+    /// Seconds to wait for a response
+    #[arg(long, value_name = "SECS", default_value_t = 5)]
+    timeout: u64,
+}
+
+impl HealthcheckArgs {
+    /// Check the proxy described by the configuration
+    fn run(&self, config: &Config) -> Result<()> {
+        let runtime = runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let limit = Duration::from_secs(self.timeout);
+        let description = runtime.block_on(client::healthcheck(config, self.ready, limit))?;
+        println!("healthy: {}", description);
+        Ok(())
+    }
+}
+
+/// How to reach a running proxy, for the commands that act as its clients
+#[derive(Args, Debug)]
+struct ProxyArgs {
+    /// The proxy's address (default: the first listen address)
+    #[arg(long, value_name = "ADDR")]
+    proxy: Option<SocketAddr>,
+
+    /// Credentials for the proxy, if it requires authentication
+    #[arg(long, value_name = "USER:PASSWORD")]
+    proxy_user: Option<String>,
+}
+
+impl ProxyArgs {
+    /// The proxy's address
+    fn addr(&self, config: &Config) -> Result<SocketAddr> {
+        match (self.proxy, config.listen.first()) {
+            (Some(proxy), _) => Ok(proxy),
+            (None, _) if config.tls.cert.is_some() => {
+                anyhow::bail!("cannot use TLS to the proxy; give --proxy explicitly")
+            }
+            (None, Some(&addr)) => Ok(client::local_addr(addr)),
+            (None, None) => anyhow::bail!("no listen addresses configured"),
+        }
+    }
+
+    /// The `Proxy-Authorization` header for the credentials, if any
+    fn authorization(&self) -> Option<String> {
+        self.proxy_user.as_ref().map(|credentials| {
+            format!(
+                "Basic {}",
+                base64::engine::general_purpose::STANDARD.encode(credentials)
+            )
+        })
+    }
+}
+
+#[derive(Args, Debug)]
+struct ProbeArgs {
+    /// The TLS server to connect to
+    #[arg(value_name = "HOST:PORT")]
+    target: Endpoint,
+
+    #[command(flatten)]
+    proxy: ProxyArgs,
+
+    /// Seconds to wait for the whole probe
+    #[arg(long, value_name = "SECS", default_value_t = 10)]
+    timeout: u64,
+}
+
+impl ProbeArgs {
+    /// Probe the target and print the timings
+    fn run(&self, config: &Config) -> Result<()> {
+        let proxy = self.proxy.addr(config)?;
+        let authorization = self.proxy.authorization();
+        let connector = tls::backend_connector(&config.backend)?;
+        let probe = client::probe(
+            proxy,
+            &self.target.host,
+            self.target.port,
+            authorization.as_deref(),
+            &connector,
+        );
+        let runtime = runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let limit = Duration::from_secs(self.timeout);
+        let probe = runtime
+            .block_on(async { tokio::time::timeout(limit, probe).await })
+            .map_err(|_| anyhow::anyhow!("no result within {:?}", limit))??;
+        println!("{}", probe);
+        Ok(())
+    }
+}
+
+#[derive(Args, Debug)]
+struct BenchArgs {
+    /// The destination, which must echo what it is sent (default: `backend.host` and
+    /// `backend.port`)
+    #[arg(long, value_name = "HOST:PORT")]
+    target: Option<Endpoint>,
+
+    #[command(flatten)]
+    proxy: ProxyArgs,
+
+    /// Number of tunnels open at once
+    #[arg(long, value_name = "N", default_value_t = 10)]
+    concurrency: usize,
+
+    /// Total number of tunnels to open
+    #[arg(long, value_name = "N", default_value_t = 100)]
+    tunnels: usize,
+
+    /// Bytes to send through each tunnel
+    #[arg(long, value_name = "BYTES", default_value_t = 16384)]
+    payload: usize,
+
+    /// Seconds each tunnel may take before it counts as failed
+    #[arg(long, value_name = "SECS", default_value_t = 30)]
+    timeout: u64,
+}
+
+impl BenchArgs {
+    /// Run the benchmark and print the report, failing if any tunnel failed
+    fn run(&self, config: &Config) -> Result<()> {
+        let (host, port) = match &self.target {
+            Some(target) => (target.host.clone(), target.port),
+            None => (config.backend.host.clone(), config.backend.port),
+        };
+        let options = BenchOptions {
+            proxy: self.proxy.addr(config)?,
+            host,
+            port,
+            authorization: self.proxy.authorization(),
+            concurrency: self.concurrency,
+            tunnels: self.tunnels,
+            payload: self.payload,
+            timeout: Duration::from_secs(self.timeout),
+        };
+        let report = build_runtime(&config.runtime)?.block_on(bench::run(options));
+        print!("{}", report);
+        if !report.errors.is_empty() {
+            anyhow::bail!(
+                "{} of {} tunnels failed",
+                report.errors.values().sum::<usize>(),
+                report.tunnels()
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Report whether the proxy is running, exiting with status 3 (as for LSB init scripts)
+/// if it is not
+fn status(config: &DaemonConfig) -> Result<()> {
+    match daemon::status(pid_file(config)?)? {
+        Some(pid) => {
+            println!("giphyproxy is running (PID {})", pid);
+            Ok(())
+        }
+        None => {
+            println!("giphyproxy is not running");
+            std::process::exit(3);
+        }
+    }
+}
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    env_logger::init();
+/// The configured PID file
+fn pid_file(config: &DaemonConfig) -> Result<&Path> {
+    match &config.pid_file {
+        Some(path) => Ok(path),
+        None => anyhow::bail!("no PID file configured (daemon.pid_file)"),
+    }
+}
+
+/// Command-line overrides for values in `Config`.  These take precedence over the
+/// environment and the configuration file.
+#[derive(Args, Debug)]
+struct ConfigArgs {
+    /// TOML configuration file (default: `$GIPHYPROXY_CONFIG`, if set)
+    #[arg(long, global = true, value_name = "FILE")]
+    config: Option<PathBuf>,
+
+    /// IP address on which to listen
+    #[arg(long, global = true, value_name = "IP")]
+    bind_addr: Option<IpAddr>,
+
+    /// Port on which to listen
+    #[arg(long, global = true)]
+    port: Option<u16>,
+
+    /// Host to which clients may connect
+    #[arg(long, global = true, value_name = "HOST")]
+    backend_host: Option<String>,
+
+    /// Port to which clients may connect
+    #[arg(long, global = true, value_name = "PORT")]
+    backend_port: Option<u16>,
+
+    /// Log filter, in `RUST_LOG` syntax (e.g., `debug`); overrides `RUST_LOG`
+    #[arg(long, global = true, value_name = "FILTER")]
+    log_level: Option<String>,
+
+    /// Seconds to wait for a backend connection before giving up
+    #[arg(long, global = true, value_name = "SECS")]
+    connect_timeout: Option<u64>,
+
+    /// Seconds a client may take to send its CONNECT request
+    #[arg(long, global = true, value_name = "SECS")]
+    head_timeout: Option<u64>,
+
+    /// Seconds a tunnel may go without traffic before it is closed
+    #[arg(long, global = true, value_name = "SECS")]
+    idle_timeout: Option<u64>,
+
+    /// Number of runtime worker threads (default: one per CPU core)
+    #[arg(long, global = true, value_name = "N")]
+    worker_threads: Option<usize>,
+
+    /// Run on a single thread
+    #[arg(long, global = true)]
+    current_thread: bool,
+
+    /// Detach from the terminal and run in the background
+    #[arg(long, global = true)]
+    daemonize: bool,
+
+    /// File to which the proxy's PID is written, and from which `stop` and `status` read it
+    #[arg(long, global = true, value_name = "FILE")]
+    pid_file: Option<PathBuf>,
+}
+
+impl ConfigArgs {
+    /// Apply any values given on the command line to the config
+    fn apply(self, config: &mut Config) {
+        config.override_listen(self.bind_addr, self.port);
+        if let Some(backend_host) = self.backend_host {
+            config.backend.host = backend_host;
+        }
+        if let Some(backend_port) = self.backend_port {
+            config.backend.port = backend_port;
+        }
+        if let Some(log_level) = self.log_level {
+            config.log.level = Some(log_level);
+        }
+        if let Some(connect_timeout) = self.connect_timeout {
+            config.backend.connect_timeout = Duration::from_secs(connect_timeout);
+        }
+        if let Some(head_timeout) = self.head_timeout {
+            config.limits.head_timeout = Duration::from_secs(head_timeout);
+        }
+        if let Some(idle_timeout) = self.idle_timeout {
+            config.limits.idle_timeout = Duration::from_secs(idle_timeout);
+        }
+        if let Some(worker_threads) = self.worker_threads {
+            config.runtime.worker_threads = Some(worker_threads);
+            config.runtime.current_thread = false;
+        }
+        if self.current_thread {
+            config.runtime.current_thread = true;
+            config.runtime.worker_threads = None;
+        }
+        if self.daemonize {
+            config.daemon.enabled = true;
+        }
+        if let Some(pid_file) = self.pid_file {
+            config.daemon.pid_file = Some(pid_file);
+        }
+    }
+}
+
+/// Run the proxy until the process receives SIGTERM or SIGINT, notifying systemd (or the
+/// previous process, after an upgrade) when it is ready and when it begins to stop, and
+/// then saving quota usage.  Once the listening sockets are handed over to a new process,
+/// it runs until its tunnels have closed.  `inherited` is what the previous process passed
+/// to this one, if any.
+async fn serve(config: Config, inherited: upgrade::Inherited) -> Result<()> {
+    upgrade::inherit(inherited);
+    let sandbox = config.sandbox.clone();
+    Proxy::builder().config(config).build()?.start().await?;
+    sandbox::apply(&sandbox)?;
+
+    // the listeners run in other tasks
+    systemd::notify("READY=1")?;
+    upgrade::notify_ready()?;
+    daemon::notify_ready()?;
+    systemd::spawn_watchdog();
+    tokio::select! {
+        r = shutdown_signal() => r?,
+        // exit with an error, so that a supervisor restarts the proxy
+        e = listen::accept_failure() => return Err(e),
+        () = upgrade::handed_over() => {
+            log::info!("waiting for open tunnels to close before exiting");
+            tokio::select! {
+                r = shutdown_signal() => r?,
+                () = REGISTRY.idle() => {}
+            }
+        }
+    }
+    log::info!("shutting down");
+    systemd::notify("STOPPING=1")?;
+    quota::save();
+    Ok(())
+}
+
+/// Wait for a signal asking the process to stop
+#[cfg(unix)]
+async fn shutdown_signal() -> Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        _ = terminate.recv() => {}
+        r = tokio::signal::ctrl_c() => r?,
+    }
+    Ok(())
+}
+
+/// Wait for a signal asking the process to stop
+#[cfg(not(unix))]
+async fn shutdown_signal() -> Result<()> {
+    tokio::signal::ctrl_c().await?;
+    Ok(())
+}
+
+/// Build the async runtime described by the configuration
+fn build_runtime(config: &RuntimeConfig) -> Result<Runtime> {
+    let mut builder = if config.current_thread {
+        runtime::Builder::new_current_thread()
+    } else {
+        let mut builder = runtime::Builder::new_multi_thread();
+        if let Some(worker_threads) = config.worker_threads {
+            builder.worker_threads(worker_threads);
+        }
+        builder
+    };
+    if let Some(max_blocking_threads) = config.max_blocking_threads {
+        builder.max_blocking_threads(max_blocking_threads);
+    }
+    Ok(builder.enable_all().build()?)
+}
+
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+
+    let mut config = Config::load(cli.overrides.config.as_deref())?;
+    cli.overrides.apply(&mut config);
+    config.validate()?;
 
-    // TODO: bound IP and port should be configurable via env vars (11-factor style)
-    start_listening("127.0.0.1:8080").await?;
+    let command = cli.command.unwrap_or(Command::Serve);
+    let mut inherited = upgrade::Inherited::default();
+    if let Command::Serve = command {
+        // before any threads are started
+        inherited = upgrade::Inherited::from_env()?;
+        daemon::start(&config.daemon, inherited.started_by_upgrade())?;
+    }
+
+    let _telemetry = telemetry::init(&config.log, &config.tracing)?;
 
-    // sleep forever, as the listener runs in another task
-    loop {
-        time::sleep(Duration::from_secs(3600)).await;
+    match command {
+        Command::Serve => build_runtime(&config.runtime)?.block_on(serve(config, inherited)),
+        Command::CheckConfig => {
+            println!("{:#?}", config);
+            Ok(())
+        }
+        Command::Version => {
+            println!("giphyproxy {}", env!("CARGO_PKG_VERSION"));
+            Ok(())
+        }
+        Command::Audit(args) => args.run(&config.audit),
+        Command::Stop(args) => args.run(&config.daemon),
+        Command::Status => status(&config.daemon),
+        Command::Healthcheck(args) => args.run(&config),
+        Command::Probe(args) => args.run(&config),
+        Command::Bench(args) => args.run(&config),
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use giphyproxy::testing::FakeGiphy;
+
+    #[test]
+    fn test_cli_overrides() {
+        let cli = Cli::try_parse_from([
+            "giphyproxy",
+            "check-config",
+            "--port",
+            "3128",
+            "--backend-host",
+            "example.com",
+            "--connect-timeout",
+            "3",
+        ])
+        .unwrap();
+        assert!(matches!(cli.command, Some(Command::CheckConfig)));
+
+        let mut config = Config::default();
+        cli.overrides.apply(&mut config);
+        assert_eq!(config.listen, vec!["127.0.0.1:3128".parse().unwrap()]);
+        assert_eq!(config.backend.host, "example.com");
+        assert_eq!(config.backend.port, 443);
+        assert_eq!(config.backend.connect_timeout, Duration::from_secs(3));
+    }
+
+    #[test]
+    fn test_cli_runtime() {
+        let mut config = Config::default();
+        config.runtime.current_thread = true;
+        let cli = Cli::try_parse_from(["giphyproxy", "--worker-threads", "2"]).unwrap();
+        cli.overrides.apply(&mut config);
+        assert_eq!(config.runtime.worker_threads, Some(2));
+        assert!(!config.runtime.current_thread);
+        build_runtime(&config.runtime).unwrap();
+
+        let cli = Cli::try_parse_from(["giphyproxy", "--current-thread"]).unwrap();
+        cli.overrides.apply(&mut config);
+        assert_eq!(config.runtime.worker_threads, None);
+        assert!(config.runtime.current_thread);
+        build_runtime(&config.runtime).unwrap();
+    }
+
+    #[test]
+    fn test_cli_audit() {
+        let cli = Cli::try_parse_from(["giphyproxy", "audit", "--since", "3600"]).unwrap();
+        let Some(Command::Audit(args)) = cli.command else {
+            panic!("expected audit command");
+        };
+        assert_eq!(args.since, Some(3600));
+        assert_eq!(args.limit, 100);
+        assert!(args.run(&AuditConfig::default()).is_err());
+    }
+
+    #[test]
+    fn test_cli_daemon() {
+        let cli = Cli::try_parse_from([
+            "giphyproxy",
+            "stop",
+            "--pid-file",
+            "giphyproxy.pid",
+            "--timeout",
+            "5",
+        ])
+        .unwrap();
+        let Some(Command::Stop(args)) = cli.command else {
+            panic!("expected stop command");
+        };
+        assert_eq!(args.timeout, 5);
+        assert!(args.run(&DaemonConfig::default()).is_err());
 
-    /// Test the whole process, for a single request.  This
-    /// test requires that
-    ///  * giphy be up and available
-    ///  * port 8080 be unused locally
+        let mut config = Config::default();
+        cli.overrides.apply(&mut config);
+        assert_eq!(config.daemon.pid_file, Some("giphyproxy.pid".into()));
+        assert!(!config.daemon.enabled);
+
+        let cli = Cli::try_parse_from(["giphyproxy", "--daemonize"]).unwrap();
+        cli.overrides.apply(&mut config);
+        assert!(config.daemon.enabled);
+    }
+
+    #[test]
+    fn test_cli_healthcheck() {
+        let cli = Cli::try_parse_from(["giphyproxy", "healthcheck", "--ready"]).unwrap();
+        let Some(Command::Healthcheck(args)) = cli.command else {
+            panic!("expected healthcheck command");
+        };
+        assert!(args.ready);
+        assert_eq!(args.timeout, 5);
+    }
+
+    #[test]
+    fn test_cli_probe() {
+        let cli = Cli::try_parse_from([
+            "giphyproxy",
+            "probe",
+            "api.giphy.com:443",
+            "--proxy",
+            "127.0.0.1:3128",
+        ])
+        .unwrap();
+        let Some(Command::Probe(args)) = cli.command else {
+            panic!("expected probe command");
+        };
+        assert_eq!(args.target.host, "api.giphy.com");
+        assert_eq!(args.target.port, 443);
+        assert_eq!(
+            args.proxy.addr(&Config::default()).unwrap(),
+            "127.0.0.1:3128".parse().unwrap()
+        );
+        assert_eq!(args.proxy.authorization(), None);
+        assert_eq!(args.timeout, 10);
+
+        assert!(Cli::try_parse_from(["giphyproxy", "probe", "api.giphy.com"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_bench() {
+        let cli = Cli::try_parse_from([
+            "giphyproxy",
+            "bench",
+            "--concurrency",
+            "50",
+            "--payload",
+            "1000000",
+            "--proxy-user",
+            "user:pass",
+        ])
+        .unwrap();
+        let Some(Command::Bench(args)) = cli.command else {
+            panic!("expected bench command");
+        };
+        assert_eq!(args.concurrency, 50);
+        assert_eq!(args.tunnels, 100);
+        assert_eq!(args.payload, 1000000);
+        assert_eq!(args.target, None);
+        assert_eq!(
+            args.proxy.addr(&Config::default()).unwrap(),
+            "127.0.0.1:8080".parse().unwrap()
+        );
+        assert_eq!(
+            args.proxy.authorization().as_deref(),
+            Some("Basic dXNlcjpwYXNz")
+        );
+    }
+
+    #[test]
+    fn test_cli_bad_port() {
+        assert!(Cli::try_parse_from(["giphyproxy", "--port", "99999"]).is_err());
+    }
+
+    /// Test the whole process, for a single request, against a fake Giphy server
     #[tokio::test]
     async fn giphy_test() {
         let _ = env_logger::builder().is_test(true).try_init();
 
-        // start the server
-        start_listening("127.0.0.1:8080").await.unwrap();
+        // start the fake upstream and the proxy, on ephemeral ports
+        let giphy = FakeGiphy::start().await.unwrap();
+        let addrs = Proxy::builder()
+            .bind("127.0.0.1:0".parse().unwrap())
+            .backend(giphy.backend())
+            .build()
+            .unwrap()
+            .start()
+            .await
+            .unwrap();
 
         // connect with a "real" HTTP client
         let client = reqwest::Client::builder()
-            .proxy(reqwest::Proxy::https("http://127.0.0.1:8080").unwrap())
+            .proxy(reqwest::Proxy::https(format!("http://{}", addrs[0])).unwrap())
+            .add_root_certificate(
+                reqwest::Certificate::from_pem(giphy.cert_pem().as_bytes()).unwrap(),
+            )
             .build()
             .unwrap();
 
@@ -0,0 +1,814 @@
+//! Process-wide metrics, rendered in the Prometheus text exposition format.
+//!
+//! Metrics are kept in a global so that any module can be instrumented without
+//! threading a registry through every function.
+
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// A monotonically increasing counter
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    const fn new() -> Self {
+        Self(AtomicU64::new(0))
+    }
+
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// A value that can go up and down
+pub struct Gauge(AtomicI64);
+
+impl Gauge {
+    const fn new() -> Self {
+        Self(AtomicI64::new(0))
+    }
+
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dec(&self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub fn set(&self, value: i64) {
+        self.0.store(value, Ordering::Relaxed);
+    }
+}
+
+/// Upper bounds, in seconds, of the buckets used for latency histograms
+const LATENCY_BUCKETS: [f64; 10] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+/// A histogram of durations, using `LATENCY_BUCKETS`
+pub struct Histogram {
+    /// Non-cumulative counts for each bucket in `LATENCY_BUCKETS`
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    /// Total number of observations, including those above the largest bucket
+    count: AtomicU64,
+    /// Sum of all observations, in microseconds
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    const fn new() -> Self {
+        Self {
+            buckets: [const { AtomicU64::new(0) }; LATENCY_BUCKETS.len()],
+            count: AtomicU64::new(0),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, d: Duration) {
+        let secs = d.as_secs_f64();
+        if let Some(i) = LATENCY_BUCKETS.iter().position(|b| secs <= *b) {
+            self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(d.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+}
+
+/// Maximum number of destinations counted individually.  Connections to any others are
+/// counted under `OTHER_DESTINATION`, bounding the number of time series.
+pub const MAX_DESTINATIONS: usize = 256;
+
+/// The destination under which connections beyond `MAX_DESTINATIONS` are counted
+pub const OTHER_DESTINATION: &str = "other";
+
+/// Counts for the connections requesting a single destination
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct DestinationStats {
+    /// The requested `host:port`
+    pub destination: String,
+    /// Connections requesting this destination, including those refused
+    pub connections: u64,
+    /// Connections refused because the destination is not allowed
+    pub disallowed: u64,
+    /// Bytes sent from clients to the destination
+    pub bytes_up: u64,
+    /// Bytes sent from the destination to clients
+    pub bytes_down: u64,
+}
+
+/// Per-destination counts, updated as each connection ends
+pub struct Destinations(Mutex<BTreeMap<String, DestinationStats>>);
+
+impl Destinations {
+    const fn new() -> Self {
+        Self(Mutex::new(BTreeMap::new()))
+    }
+
+    /// Count a finished connection to `destination`
+    pub fn record(&self, destination: &str, disallowed: bool, bytes_up: u64, bytes_down: u64) {
+        let mut destinations = self.0.lock().unwrap();
+        let key = if destinations.len() < MAX_DESTINATIONS || destinations.contains_key(destination)
+        {
+            destination
+        } else {
+            OTHER_DESTINATION
+        };
+        let stats = destinations
+            .entry(key.to_owned())
+            .or_insert_with(|| DestinationStats {
+                destination: key.to_owned(),
+                ..DestinationStats::default()
+            });
+        stats.connections += 1;
+        if disallowed {
+            stats.disallowed += 1;
+        }
+        stats.bytes_up += bytes_up;
+        stats.bytes_down += bytes_down;
+    }
+
+    /// The counts for all destinations, in order of destination
+    pub fn all(&self) -> Vec<DestinationStats> {
+        self.0.lock().unwrap().values().cloned().collect()
+    }
+
+    /// The `n` destinations with the most bytes transferred, most first, with ties broken
+    /// by the number of connections
+    pub fn top(&self, n: usize) -> Vec<DestinationStats> {
+        let mut all = self.all();
+        all.sort_by_key(|d| std::cmp::Reverse((d.bytes_up + d.bytes_down, d.connections)));
+        all.truncate(n);
+        all
+    }
+}
+
+/// Maximum number of JA4 fingerprints counted individually.  Tunnels with any others are
+/// counted under `OTHER_FINGERPRINT`, so that clients cannot create time series at will.
+pub const MAX_FINGERPRINTS: usize = 64;
+
+/// The fingerprint under which tunnels beyond `MAX_FINGERPRINTS` are counted
+pub const OTHER_FINGERPRINT: &str = "other";
+
+/// Per-fingerprint counts of tunnels, by the JA4 fingerprint of their TLS ClientHello
+pub struct Fingerprints(Mutex<BTreeMap<String, u64>>);
+
+impl Fingerprints {
+    const fn new() -> Self {
+        Self(Mutex::new(BTreeMap::new()))
+    }
+
+    /// Count a finished tunnel with fingerprint `ja4`
+    pub fn record(&self, ja4: &str) {
+        let mut fingerprints = self.0.lock().unwrap();
+        let key = if fingerprints.len() < MAX_FINGERPRINTS || fingerprints.contains_key(ja4) {
+            ja4
+        } else {
+            OTHER_FINGERPRINT
+        };
+        *fingerprints.entry(key.to_owned()).or_default() += 1;
+    }
+
+    /// The counts for all fingerprints, in order of fingerprint
+    pub fn all(&self) -> Vec<(String, u64)> {
+        let fingerprints = self.0.lock().unwrap();
+        fingerprints.iter().map(|(k, v)| (k.clone(), *v)).collect()
+    }
+}
+
+/// Counts for the connections made to a single backend endpoint by a load-balanced backend
+pub struct EndpointCounters {
+    /// Connections made
+    pub connections: Counter,
+    /// Connection attempts that failed
+    pub failures: Counter,
+    /// Connections currently open
+    pub active: Gauge,
+}
+
+/// Per-endpoint counts, for each endpoint of a load-balanced backend
+pub struct Endpoints(Mutex<BTreeMap<String, Arc<EndpointCounters>>>);
+
+impl Endpoints {
+    const fn new() -> Self {
+        Self(Mutex::new(BTreeMap::new()))
+    }
+
+    /// The counters for `endpoint`, created if this is the first use of it
+    pub fn register(&self, endpoint: &str) -> Arc<EndpointCounters> {
+        self.0
+            .lock()
+            .unwrap()
+            .entry(endpoint.to_owned())
+            .or_insert_with(|| {
+                Arc::new(EndpointCounters {
+                    connections: Counter::new(),
+                    failures: Counter::new(),
+                    active: Gauge::new(),
+                })
+            })
+            .clone()
+    }
+
+    /// The counters for all endpoints, in order of endpoint
+    fn all(&self) -> Vec<(String, Arc<EndpointCounters>)> {
+        let endpoints = self.0.lock().unwrap();
+        endpoints
+            .iter()
+            .map(|(endpoint, counters)| (endpoint.clone(), counters.clone()))
+            .collect()
+    }
+}
+
+/// All metrics for the process
+pub struct Metrics {
+    pub connections_accepted: Counter,
+    pub accept_errors: Counter,
+    pub connections_rejected: Counter,
+    pub connections_rate_limited: Counter,
+    pub connections_denied: Counter,
+    pub active_tunnels: Gauge,
+    pub bytes_up: Counter,
+    pub bytes_down: Counter,
+    pub parse_failures: Counter,
+    pub backend_connect_failures: Counter,
+    pub backend_connect_retries: Counter,
+    pub backend_connect_latency: Histogram,
+    pub backend_connects_rate_limited: Counter,
+    pub backend_failovers: Counter,
+    pub backend_chaos_faults: Counter,
+    pub connections_over_quota: Counter,
+    pub tunnels_tapped: Counter,
+    pub tap_chunks_dropped: Counter,
+    pub tunnels_captured: Counter,
+    pub capture_packets_dropped: Counter,
+    pub connection_panics: Counter,
+    pub circuits_opened: Counter,
+    pub circuit_rejections: Counter,
+    pub dns_cache_hits: Counter,
+    pub dns_cache_misses: Counter,
+    pub dns_resolve_latency: Histogram,
+    pub response_cache_hits: Counter,
+    pub response_cache_misses: Counter,
+    pub pool_hits: Counter,
+    pub pool_misses: Counter,
+    pub pool_idle: Gauge,
+    pub health_checks: Counter,
+    pub health_check_failures: Counter,
+    pub health_check_latency: Histogram,
+    pub backend_healthy: Gauge,
+    pub udp_associations: Counter,
+    pub udp_datagrams_dropped: Counter,
+    pub destinations: Destinations,
+    pub endpoints: Endpoints,
+    pub fingerprints: Fingerprints,
+}
+
+/// The global metrics
+pub static METRICS: Metrics = Metrics {
+    connections_accepted: Counter::new(),
+    accept_errors: Counter::new(),
+    connections_rejected: Counter::new(),
+    connections_rate_limited: Counter::new(),
+    connections_denied: Counter::new(),
+    active_tunnels: Gauge::new(),
+    bytes_up: Counter::new(),
+    bytes_down: Counter::new(),
+    parse_failures: Counter::new(),
+    backend_connect_failures: Counter::new(),
+    backend_connect_retries: Counter::new(),
+    backend_connect_latency: Histogram::new(),
+    backend_connects_rate_limited: Counter::new(),
+    backend_failovers: Counter::new(),
+    backend_chaos_faults: Counter::new(),
+    connections_over_quota: Counter::new(),
+    tunnels_tapped: Counter::new(),
+    tap_chunks_dropped: Counter::new(),
+    tunnels_captured: Counter::new(),
+    capture_packets_dropped: Counter::new(),
+    connection_panics: Counter::new(),
+    circuits_opened: Counter::new(),
+    circuit_rejections: Counter::new(),
+    dns_cache_hits: Counter::new(),
+    dns_cache_misses: Counter::new(),
+    dns_resolve_latency: Histogram::new(),
+    response_cache_hits: Counter::new(),
+    response_cache_misses: Counter::new(),
+    pool_hits: Counter::new(),
+    pool_misses: Counter::new(),
+    pool_idle: Gauge::new(),
+    health_checks: Counter::new(),
+    health_check_failures: Counter::new(),
+    health_check_latency: Histogram::new(),
+    backend_healthy: Gauge::new(),
+    udp_associations: Counter::new(),
+    udp_datagrams_dropped: Counter::new(),
+    destinations: Destinations::new(),
+    endpoints: Endpoints::new(),
+    fingerprints: Fingerprints::new(),
+};
+
+/// Increments `active_tunnels` while it exists
+pub struct ActiveTunnel(());
+
+impl ActiveTunnel {
+    pub fn new() -> Self {
+        METRICS.active_tunnels.inc();
+        Self(())
+    }
+}
+
+impl Default for ActiveTunnel {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for ActiveTunnel {
+    fn drop(&mut self) {
+        METRICS.active_tunnels.dec();
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: u64) {
+    header(out, name, "counter", help);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+fn histogram(out: &mut String, name: &str, help: &str, h: &Histogram) {
+    header(out, name, "histogram", help);
+    let mut cumulative = 0;
+    for (bound, bucket) in LATENCY_BUCKETS.iter().zip(h.buckets.iter()) {
+        cumulative += bucket.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+    }
+    let count = h.count();
+    let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+    let sum = h.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+    let _ = writeln!(out, "{}_sum {}", name, sum);
+    let _ = writeln!(out, "{}_count {}", name, count);
+}
+
+/// Render all metrics in the Prometheus text exposition format
+pub fn render() -> String {
+    let m = &METRICS;
+    let mut out = String::new();
+    counter(
+        &mut out,
+        "giphyproxy_connections_accepted_total",
+        "Client connections accepted",
+        m.connections_accepted.get(),
+    );
+    counter(
+        &mut out,
+        "giphyproxy_accept_errors_total",
+        "Errors accepting client connections, including those that were retried",
+        m.accept_errors.get(),
+    );
+    counter(
+        &mut out,
+        "giphyproxy_connections_rejected_total",
+        "Client connections rejected due to connection limits",
+        m.connections_rejected.get(),
+    );
+    counter(
+        &mut out,
+        "giphyproxy_connections_rate_limited_total",
+        "Client connections rejected because the client opened connections too quickly",
+        m.connections_rate_limited.get(),
+    );
+    counter(
+        &mut out,
+        "giphyproxy_connections_denied_total",
+        "Client connections refused by the client ACL",
+        m.connections_denied.get(),
+    );
+
+    header(
+        &mut out,
+        "giphyproxy_active_tunnels",
+        "gauge",
+        "Tunnels currently proxying data",
+    );
+    let _ = writeln!(out, "giphyproxy_active_tunnels {}", m.active_tunnels.get());
+
+    header(
+        &mut out,
+        "giphyproxy_bytes_proxied_total",
+        "counter",
+        "Bytes proxied, by direction (up is client to backend)",
+    );
+    let _ = writeln!(
+        out,
+        "giphyproxy_bytes_proxied_total{{direction=\"up\"}} {}",
+        m.bytes_up.get()
+    );
+    let _ = writeln!(
+        out,
+        "giphyproxy_bytes_proxied_total{{direction=\"down\"}} {}",
+        m.bytes_down.get()
+    );
+
+    counter(
+        &mut out,
+        "giphyproxy_parse_failures_total",
+        "CONNECT requests that could not be parsed",
+        m.parse_failures.get(),
+    );
+    counter(
+        &mut out,
+        "giphyproxy_backend_connect_failures_total",
+        "Failed connections to the backend",
+        m.backend_connect_failures.get(),
+    );
+    counter(
+        &mut out,
+        "giphyproxy_backend_connect_retries_total",
+        "Retried connections to the backend",
+        m.backend_connect_retries.get(),
+    );
+    histogram(
+        &mut out,
+        "giphyproxy_backend_connect_seconds",
+        "Time taken to connect to the backend",
+        &m.backend_connect_latency,
+    );
+    counter(
+        &mut out,
+        "giphyproxy_backend_connects_rate_limited_total",
+        "Backend connections refused because the client exceeded its connection rate",
+        m.backend_connects_rate_limited.get(),
+    );
+    counter(
+        &mut out,
+        "giphyproxy_backend_failovers_total",
+        "Backend connections made to a failover endpoint because the preceding endpoints failed",
+        m.backend_failovers.get(),
+    );
+    counter(
+        &mut out,
+        "giphyproxy_backend_chaos_faults_total",
+        "Backend connections into which a chaos fault was injected",
+        m.backend_chaos_faults.get(),
+    );
+    counter(
+        &mut out,
+        "giphyproxy_connections_over_quota_total",
+        "Connections refused because the client had used its daily quota of bytes",
+        m.connections_over_quota.get(),
+    );
+    counter(
+        &mut out,
+        "giphyproxy_tunnels_tapped_total",
+        "Tunnels whose bytes were copied to a debugging tap",
+        m.tunnels_tapped.get(),
+    );
+    counter(
+        &mut out,
+        "giphyproxy_tap_chunks_dropped_total",
+        "Chunks of tunnel data not copied to a tap because its writer had fallen behind",
+        m.tap_chunks_dropped.get(),
+    );
+    counter(
+        &mut out,
+        "giphyproxy_tunnels_captured_total",
+        "Tunnels written to a packet capture file",
+        m.tunnels_captured.get(),
+    );
+    counter(
+        &mut out,
+        "giphyproxy_capture_packets_dropped_total",
+        "Synthesized packets not written to the capture file because the writer had fallen behind or the file was full",
+        m.capture_packets_dropped.get(),
+    );
+    counter(
+        &mut out,
+        "giphyproxy_connection_panics_total",
+        "Connections whose handler panicked",
+        m.connection_panics.get(),
+    );
+    counter(
+        &mut out,
+        "giphyproxy_circuits_opened_total",
+        "Times a failing destination's circuit breaker opened",
+        m.circuits_opened.get(),
+    );
+    counter(
+        &mut out,
+        "giphyproxy_circuit_rejections_total",
+        "Connections refused because the destination's circuit breaker was open",
+        m.circuit_rejections.get(),
+    );
+    counter(
+        &mut out,
+        "giphyproxy_dns_cache_hits_total",
+        "Backend hostnames resolved from the cache",
+        m.dns_cache_hits.get(),
+    );
+    counter(
+        &mut out,
+        "giphyproxy_dns_cache_misses_total",
+        "Backend hostnames not found in the cache",
+        m.dns_cache_misses.get(),
+    );
+    histogram(
+        &mut out,
+        "giphyproxy_dns_resolve_seconds",
+        "Time taken to resolve backend hostnames not found in the cache",
+        &m.dns_resolve_latency,
+    );
+    counter(
+        &mut out,
+        "giphyproxy_response_cache_hits_total",
+        "Requests answered from the response cache",
+        m.response_cache_hits.get(),
+    );
+    counter(
+        &mut out,
+        "giphyproxy_response_cache_misses_total",
+        "Cacheable requests not found in the response cache",
+        m.response_cache_misses.get(),
+    );
+    counter(
+        &mut out,
+        "giphyproxy_pool_hits_total",
+        "Requests sent on an idle keep-alive connection to the backend",
+        m.pool_hits.get(),
+    );
+    counter(
+        &mut out,
+        "giphyproxy_pool_misses_total",
+        "Requests for which no idle keep-alive connection to the backend was available",
+        m.pool_misses.get(),
+    );
+    header(
+        &mut out,
+        "giphyproxy_pool_idle_connections",
+        "gauge",
+        "Idle keep-alive connections to backends",
+    );
+    let _ = writeln!(
+        out,
+        "giphyproxy_pool_idle_connections {}",
+        m.pool_idle.get()
+    );
+    counter(
+        &mut out,
+        "giphyproxy_health_checks_total",
+        "Successful backend health checks",
+        m.health_checks.get(),
+    );
+    counter(
+        &mut out,
+        "giphyproxy_health_check_failures_total",
+        "Failed backend health checks",
+        m.health_check_failures.get(),
+    );
+    histogram(
+        &mut out,
+        "giphyproxy_health_check_seconds",
+        "Time taken by successful backend health checks",
+        &m.health_check_latency,
+    );
+    header(
+        &mut out,
+        "giphyproxy_backend_healthy",
+        "gauge",
+        "1 if the backend passed its most recent health checks, otherwise 0",
+    );
+    let _ = writeln!(
+        out,
+        "giphyproxy_backend_healthy {}",
+        m.backend_healthy.get()
+    );
+    counter(
+        &mut out,
+        "giphyproxy_udp_associations_total",
+        "SOCKS5 UDP associations established",
+        m.udp_associations.get(),
+    );
+    counter(
+        &mut out,
+        "giphyproxy_udp_datagrams_dropped_total",
+        "UDP datagrams not relayed, because they were invalid, not from the client, or to a disallowed destination",
+        m.udp_datagrams_dropped.get(),
+    );
+
+    let endpoints = m.endpoints.all();
+    header(
+        &mut out,
+        "giphyproxy_endpoint_connections_total",
+        "counter",
+        "Connections made to each endpoint of a load-balanced backend",
+    );
+    for (endpoint, counters) in &endpoints {
+        let _ = writeln!(
+            out,
+            "giphyproxy_endpoint_connections_total{{endpoint=\"{}\"}} {}",
+            endpoint,
+            counters.connections.get()
+        );
+    }
+    header(
+        &mut out,
+        "giphyproxy_endpoint_failures_total",
+        "counter",
+        "Failed connection attempts to each endpoint of a load-balanced backend",
+    );
+    for (endpoint, counters) in &endpoints {
+        let _ = writeln!(
+            out,
+            "giphyproxy_endpoint_failures_total{{endpoint=\"{}\"}} {}",
+            endpoint,
+            counters.failures.get()
+        );
+    }
+    header(
+        &mut out,
+        "giphyproxy_endpoint_active_connections",
+        "gauge",
+        "Open connections to each endpoint of a load-balanced backend",
+    );
+    for (endpoint, counters) in &endpoints {
+        let _ = writeln!(
+            out,
+            "giphyproxy_endpoint_active_connections{{endpoint=\"{}\"}} {}",
+            endpoint,
+            counters.active.get()
+        );
+    }
+
+    let destinations = m.destinations.all();
+    header(
+        &mut out,
+        "giphyproxy_destination_connections_total",
+        "counter",
+        "Connections requesting each destination, including refused connections",
+    );
+    for d in &destinations {
+        let _ = writeln!(
+            out,
+            "giphyproxy_destination_connections_total{{destination=\"{}\"}} {}",
+            d.destination, d.connections
+        );
+    }
+    header(
+        &mut out,
+        "giphyproxy_destination_disallowed_total",
+        "counter",
+        "Connections refused because the requested destination is not allowed",
+    );
+    for d in &destinations {
+        let _ = writeln!(
+            out,
+            "giphyproxy_destination_disallowed_total{{destination=\"{}\"}} {}",
+            d.destination, d.disallowed
+        );
+    }
+    header(
+        &mut out,
+        "giphyproxy_destination_bytes_total",
+        "counter",
+        "Bytes proxied to and from each destination, by direction",
+    );
+    for d in &destinations {
+        for (direction, bytes) in [("up", d.bytes_up), ("down", d.bytes_down)] {
+            let _ = writeln!(
+                out,
+                "giphyproxy_destination_bytes_total{{destination=\"{}\",direction=\"{}\"}} {}",
+                d.destination, direction, bytes
+            );
+        }
+    }
+
+    header(
+        &mut out,
+        "giphyproxy_tls_fingerprint_tunnels_total",
+        "counter",
+        "Finished tunnels by the JA4 fingerprint of their TLS ClientHello, when log.tls_fingerprints is set",
+    );
+    for (ja4, count) in m.fingerprints.all() {
+        let _ = writeln!(
+            out,
+            "giphyproxy_tls_fingerprint_tunnels_total{{ja4=\"{}\"}} {}",
+            ja4, count
+        );
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_destinations() {
+        let destinations = Destinations::new();
+        destinations.record("a.example.com:443", false, 10, 100);
+        destinations.record("b.example.com:443", true, 0, 0);
+        destinations.record("a.example.com:443", false, 5, 5);
+        for i in 0..MAX_DESTINATIONS {
+            destinations.record(&format!("host{}:443", i), false, 1, 0);
+        }
+
+        let all = destinations.all();
+        assert_eq!(all.len(), MAX_DESTINATIONS + 1);
+        let other = all.iter().find(|d| d.destination == OTHER_DESTINATION);
+        assert_eq!(other.map(|d| d.connections), Some(2));
+
+        let top = destinations.top(2);
+        assert_eq!(
+            top[0],
+            DestinationStats {
+                destination: "a.example.com:443".into(),
+                connections: 2,
+                disallowed: 0,
+                bytes_up: 15,
+                bytes_down: 105,
+            }
+        );
+        assert_eq!(top[1].destination, OTHER_DESTINATION);
+        assert_eq!(
+            destinations
+                .all()
+                .iter()
+                .find(|d| d.destination == "b.example.com:443")
+                .map(|d| d.disallowed),
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn test_fingerprints() {
+        let fingerprints = Fingerprints::new();
+        fingerprints.record("t13d1516h2_8daaf6152771_e5627efa2ab1");
+        for i in 0..MAX_FINGERPRINTS {
+            fingerprints.record(&format!("t12i0100_{:012}_000000000000", i));
+        }
+        fingerprints.record("t13d1516h2_8daaf6152771_e5627efa2ab1");
+
+        let all = fingerprints.all();
+        assert_eq!(all.len(), MAX_FINGERPRINTS + 1);
+        assert!(all.contains(&("t13d1516h2_8daaf6152771_e5627efa2ab1".into(), 2)));
+        assert!(all.contains(&(OTHER_FINGERPRINT.into(), 1)));
+    }
+
+    #[test]
+    fn test_histogram() {
+        let h = Histogram::new();
+        h.observe(Duration::from_millis(3));
+        h.observe(Duration::from_millis(30));
+        h.observe(Duration::from_secs(30));
+
+        let mut out = String::new();
+        histogram(&mut out, "h", "help", &h);
+        assert!(out.contains("h_bucket{le=\"0.005\"} 1\n"));
+        assert!(out.contains("h_bucket{le=\"0.025\"} 1\n"));
+        assert!(out.contains("h_bucket{le=\"0.05\"} 2\n"));
+        assert!(out.contains("h_bucket{le=\"5\"} 2\n"));
+        assert!(out.contains("h_bucket{le=\"+Inf\"} 3\n"));
+        assert!(out.contains("h_sum 30.033\n"));
+        assert!(out.contains("h_count 3\n"));
+    }
+
+    #[test]
+    fn test_active_tunnel_guard() {
+        // other tests may be running concurrently, so this only checks that the gauge
+        // is incremented at least once while the guard exists
+        let guard = ActiveTunnel::new();
+        assert!(METRICS.active_tunnels.get() >= 1);
+        drop(guard);
+    }
+
+    #[test]
+    fn test_render() {
+        METRICS.parse_failures.inc();
+        let out = render();
+        assert!(out.contains("# TYPE giphyproxy_parse_failures_total counter\n"));
+        assert!(out.contains("giphyproxy_bytes_proxied_total{direction=\"up\"} "));
+        assert!(out.contains("giphyproxy_backend_connect_seconds_bucket{le=\"+Inf\"} "));
+    }
+}
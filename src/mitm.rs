@@ -0,0 +1,467 @@
+//! Interception of TLS tunnels to selected destinations, for deployments that need to see
+//! or modify the requests clients make.  For an intercepted tunnel, the proxy completes
+//! the client's TLS handshake itself, with a certificate for the destination signed by a
+//! configured CA, and makes its own TLS connection to the destination.  The request in the
+//! tunnel then passes through the configured hooks before it is sent on, and the response
+//! is relayed back, after which the tunnel is closed.
+//!
+//! Clients must trust the CA, so this is only useful where the proxy's operator also
+//! controls the clients.
+
+use crate::access::{AccessRecord, Reason};
+use crate::backend::AllowEntry;
+use crate::cache::ResponseCache;
+use crate::config::Config;
+use crate::connection::{send_response, ConnectionSummary};
+use crate::forward::{self, host_header};
+use crate::hooks::Hooks;
+use crate::http::Response;
+use crate::registry::Registration;
+use crate::reverse::{read_forward, respond_from_cache};
+use crate::tls;
+use anyhow::{bail, Context, Result};
+use rcgen::{Certificate, CertificateParams, DnType, KeyPair};
+use rustls::pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer, ServerName};
+use rustls::ServerConfig;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context as TaskContext, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, BufReader, ReadBuf};
+use tokio::time::timeout;
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+/// How long the certificates presented to clients are valid
+const CERT_VALIDITY: time::Duration = time::Duration::days(7);
+
+/// How long a certificate is used before a fresh one is generated, well before it expires
+const CERT_REFRESH: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The most certificates to keep; when there are more, all are discarded
+const MAX_CERTS: usize = 1024;
+
+/// Intercepts TLS tunnels to the destinations in `mitm.hosts`
+pub struct Mitm {
+    config: Arc<Config>,
+    ca_cert: Certificate,
+    ca_key: KeyPair,
+    /// Server configurations presenting a certificate for each destination host, with
+    /// the time each was generated
+    certs: Mutex<HashMap<String, (Instant, Arc<ServerConfig>)>>,
+    connector: TlsConnector,
+    hooks: Hooks,
+}
+
+impl Mitm {
+    /// Create an interceptor, reading the CA certificate and its PKCS#8 private key from
+    /// the files named in the `mitm` section.
+    pub fn new(config: Arc<Config>) -> Result<Self> {
+        let (cert_path, key_path) = match (&config.mitm.ca_cert, &config.mitm.ca_key) {
+            (Some(cert), Some(key)) => (cert, key),
+            _ => bail!("mitm.ca_cert and mitm.ca_key are required"),
+        };
+        let cert = tls::read_certs(cert_path)
+            .with_context(|| format!("reading {}", cert_path.display()))?;
+        let key =
+            tls::read_key(key_path).with_context(|| format!("reading {}", key_path.display()))?;
+        let ca_key = KeyPair::try_from(&key).context("invalid mitm.ca_key")?;
+        // certificates are signed by a copy of the CA certificate with the same name and key
+        let ca_cert = CertificateParams::from_ca_cert_der(&cert[0])
+            .and_then(|params| params.self_signed(&ca_key))
+            .context("invalid mitm.ca_cert")?;
+        let connector = tls::backend_connector(&config.backend)?;
+        Ok(Self {
+            hooks: Hooks::mitm(&config),
+            config,
+            ca_cert,
+            ca_key,
+            certs: Mutex::new(HashMap::new()),
+            connector,
+        })
+    }
+
+    /// Answer intercepted requests from `cache` where possible, and store the responses
+    /// to them
+    pub(crate) fn with_cache(mut self, cache: Arc<ResponseCache>) -> Self {
+        self.hooks.set_cache(cache);
+        self
+    }
+
+    /// Should tunnels to this destination be intercepted?
+    pub fn intercepts(&self, host: &str, port: u16) -> bool {
+        self.config
+            .mitm
+            .hosts
+            .iter()
+            .any(|entry: &AllowEntry| entry.allows(host, port))
+    }
+
+    /// Intercept a tunnel to `host`:`port` which the client has been told is established,
+    /// with `backend_socket` connected to the destination.  The client's TLS handshake
+    /// and request must arrive within the head timeout.  Failures before the request is
+    /// forwarded are recorded in `record`.
+    pub(crate) async fn intercept<S, BS>(
+        &self,
+        socket: S,
+        backend_socket: BS,
+        host: &str,
+        port: u16,
+        record: &mut AccessRecord,
+        tunnel: &Registration,
+    ) -> Result<ConnectionSummary>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+        BS: AsyncRead + AsyncWrite + Unpin,
+    {
+        let config = &self.config;
+        let acceptor = TlsAcceptor::from(self.server_config(host)?);
+        let client = match timeout(config.limits.head_timeout, acceptor.accept(socket)).await {
+            Ok(Ok(client)) => client,
+            Ok(Err(e)) => {
+                record.reason = Reason::ClientError;
+                return Err(e).context("TLS handshake with client");
+            }
+            Err(_) => {
+                record.reason = Reason::HeadTimeout;
+                bail!("timed out in TLS handshake with client");
+            }
+        };
+        let mut client = BufReader::with_capacity(8192, client);
+        let hooks = &self.hooks;
+        let (request, extra) = read_forward(
+            &mut client,
+            config,
+            record,
+            hooks,
+            host_header(host, port, 443),
+        )
+        .await?;
+        if respond_from_cache(&mut client, hooks, &request, record).await? {
+            return Ok(ConnectionSummary::from_record(record));
+        }
+
+        let name = ServerName::try_from(host.to_owned())
+            .with_context(|| format!("invalid destination host {:?}", host))?;
+        let handshake = self.connector.connect(name, backend_socket);
+        let backend = match timeout(config.backend.connect_timeout, handshake).await {
+            Ok(Ok(backend)) => backend,
+            result => {
+                record.reason = Reason::BackendError;
+                let response = Response::error(502, "Bad Gateway", "TLS handshake failed");
+                let _ = send_response(&mut client, response).await;
+                return match result {
+                    Ok(Err(e)) => Err(e).with_context(|| format!("TLS handshake with {}", host)),
+                    _ => bail!("timed out in TLS handshake with {}", host),
+                };
+            }
+        };
+
+        record.reason = Reason::Error;
+        let limits = &config.limits;
+        let hooks = &self.hooks;
+        Ok(forward::forward(client, extra, backend, &request, hooks, limits, tunnel).await)
+    }
+
+    /// Get a server configuration presenting a certificate for `host`, generating the
+    /// certificate if necessary
+    fn server_config(&self, host: &str) -> Result<Arc<ServerConfig>> {
+        let mut certs = self.certs.lock().unwrap();
+        if let Some((generated, server_config)) = certs.get(host) {
+            if generated.elapsed() < CERT_REFRESH {
+                return Ok(server_config.clone());
+            }
+        }
+        if certs.len() >= MAX_CERTS {
+            certs.clear();
+        }
+        let server_config = Arc::new(self.generate(host)?);
+        certs.insert(host.to_owned(), (Instant::now(), server_config.clone()));
+        Ok(server_config)
+    }
+
+    /// Generate a certificate for `host`, signed by the CA, and a server configuration
+    /// presenting it.  Only HTTP/1.1 is offered via ALPN.
+    fn generate(&self, host: &str) -> Result<ServerConfig> {
+        let mut params = CertificateParams::new(vec![host.to_owned()])
+            .with_context(|| format!("invalid host {:?} for certificate", host))?;
+        params.distinguished_name.push(DnType::CommonName, host);
+        let now = time::OffsetDateTime::now_utc();
+        // allow for clients whose clocks are a little behind
+        params.not_before = now - time::Duration::hours(1);
+        params.not_after = now + CERT_VALIDITY;
+        let key = KeyPair::generate()?;
+        let cert = params.signed_by(&key, &self.ca_cert, &self.ca_key)?;
+
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let mut server_config = ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![cert.der().clone()],
+                PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key.serialize_der())),
+            )?;
+        server_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        Ok(server_config)
+    }
+}
+
+#[cfg(test)]
+impl Mitm {
+    /// Use the given TLS connector, rather than one trusting the usual root certificates
+    fn with_connector(mut self, connector: TlsConnector) -> Self {
+        self.connector = connector;
+        self
+    }
+}
+
+/// A stream whose reads begin with data already read from it, such as the start of a TLS
+/// handshake that the client sent along with its CONNECT request
+pub(crate) struct Prefixed<S> {
+    prefix: Vec<u8>,
+    pos: usize,
+    inner: S,
+}
+
+impl<S> Prefixed<S> {
+    pub(crate) fn new(prefix: Vec<u8>, inner: S) -> Self {
+        Self {
+            prefix,
+            pos: 0,
+            inner,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Prefixed<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.pos < self.prefix.len() {
+            let n = buf.remaining().min(self.prefix.len() - self.pos);
+            buf.put_slice(&self.prefix[self.pos..self.pos + n]);
+            self.pos += n;
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Prefixed<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::registry::REGISTRY;
+    use crate::testing::temp_file;
+    use rcgen::{BasicConstraints, IsCa};
+    use rustls::{ClientConfig, RootCertStore};
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+    /// A TLS client configuration trusting only the given certificate
+    fn client_config(trusted: &rustls::pki_types::CertificateDer<'static>) -> ClientConfig {
+        let mut roots = RootCertStore::empty();
+        roots.add(trusted.clone()).unwrap();
+        ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth()
+    }
+
+    /// An interceptor for `localhost:443` with a newly generated CA and the given hooks
+    /// configuration, returning it with a client configuration trusting the CA.  The
+    /// interceptor trusts the destination certificate `upstream`.
+    fn mitm(
+        name: &str,
+        mut config: Config,
+        upstream: &rustls::pki_types::CertificateDer<'static>,
+    ) -> (Mitm, ClientConfig) {
+        let ca_key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(vec![]).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params
+            .distinguished_name
+            .push(DnType::CommonName, "giphyproxy test CA");
+        let ca_cert = params.self_signed(&ca_key).unwrap();
+
+        config.mitm.hosts = vec!["localhost:443".parse().unwrap()];
+        config.mitm.ca_cert = Some(temp_file(&format!("{}-ca.crt", name), &ca_cert.pem()));
+        config.mitm.ca_key = Some(temp_file(
+            &format!("{}-ca.key", name),
+            &ca_key.serialize_pem(),
+        ));
+        let mitm = Mitm::new(Arc::new(config.clone())).unwrap();
+        for path in [&config.mitm.ca_cert, &config.mitm.ca_key]
+            .iter()
+            .copied()
+            .flatten()
+        {
+            std::fs::remove_file(path).unwrap();
+        }
+        let connector = TlsConnector::from(Arc::new(client_config(upstream)));
+        (mitm.with_connector(connector), client_config(ca_cert.der()))
+    }
+
+    /// Run a TLS server for `localhost` on `socket`, answering a single request with the
+    /// request head it received, returning its certificate
+    fn upstream(socket: DuplexStream) -> rustls::pki_types::CertificateDer<'static> {
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let server_config =
+            ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_no_client_auth()
+                .with_single_cert(
+                    vec![certified.cert.der().clone()],
+                    PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der()).into(),
+                )
+                .unwrap();
+        tokio::spawn(async move {
+            let acceptor = TlsAcceptor::from(Arc::new(server_config));
+            let mut server = match acceptor.accept(socket).await {
+                Ok(server) => server,
+                // the request was refused before connecting
+                Err(_) => return,
+            };
+            let mut request = vec![];
+            let mut buf = [0u8; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let n = server.read(&mut buf).await.unwrap();
+                assert_ne!(n, 0);
+                request.extend_from_slice(&buf[..n]);
+            }
+            let head = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n",
+                request.len()
+            );
+            server.write_all(head.as_bytes()).await.unwrap();
+            server.write_all(&request).await.unwrap();
+            server.shutdown().await.unwrap();
+        });
+        certified.cert.der().clone()
+    }
+
+    /// Send `request` through an intercepted tunnel to `localhost:443`, returning the
+    /// response and the reason recorded for the tunnel
+    async fn exchange(name: &str, config: Config, request: &'static [u8]) -> (String, Reason) {
+        let (backend_socket, upstream_socket) = duplex(16384);
+        let upstream_cert = upstream(upstream_socket);
+        let (mitm, client_config) = mitm(name, config, &upstream_cert);
+
+        let (client_socket, server_socket) = duplex(16384);
+        let client_task = tokio::spawn(async move {
+            let connector = TlsConnector::from(Arc::new(client_config));
+            let name = ServerName::try_from("localhost").unwrap();
+            let mut client = connector.connect(name, client_socket).await.unwrap();
+            client.write_all(request).await.unwrap();
+            let mut buf = vec![];
+            // the proxy closes the connection without close_notify
+            let _ = client.read_to_end(&mut buf).await;
+            String::from_utf8(buf).unwrap()
+        });
+
+        let mut record = AccessRecord::new(None);
+        let registration = REGISTRY.register(&record);
+        let socket = Prefixed::new(vec![], server_socket);
+        let result = mitm
+            .intercept(
+                socket,
+                backend_socket,
+                "localhost",
+                443,
+                &mut record,
+                &registration,
+            )
+            .await;
+        if let Ok(summary) = result {
+            summary.record(&mut record);
+        }
+        (client_task.await.unwrap(), record.reason)
+    }
+
+    #[test]
+    fn test_intercepts() {
+        let upstream = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let (mitm, _) = mitm("intercepts", Config::default(), upstream.cert.der());
+        assert!(mitm.intercepts("localhost", 443));
+        assert!(!mitm.intercepts("localhost", 8443));
+        assert!(!mitm.intercepts("api.giphy.com", 443));
+
+        // certificates are reused
+        let first = mitm.server_config("localhost").unwrap();
+        assert!(Arc::ptr_eq(
+            &first,
+            &mitm.server_config("localhost").unwrap()
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_intercept() {
+        let mut config = Config::default();
+        config.mitm.inject_api_key = true;
+        config.reverse.api_key = Some("secret".into());
+        let (response, reason) = exchange(
+            "intercept",
+            config,
+            b"GET /v1/gifs/trending HTTP/1.1\r\nHost: localhost\r\n\r\n",
+        )
+        .await;
+        let expected = "GET /v1/gifs/trending?api_key=secret HTTP/1.1\r\n\
+                        Host: localhost\r\nConnection: close\r\n\r\n";
+        assert_eq!(
+            response,
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                expected.len(),
+                expected
+            )
+        );
+        assert_eq!(reason, Reason::BackendClosed);
+    }
+
+    #[tokio::test]
+    async fn test_intercept_refused() {
+        let mut config = Config::default();
+        config.mitm.deny_paths = vec!["/v1/stickers".into()];
+        let (response, reason) = exchange(
+            "intercept-refused",
+            config,
+            b"GET /v1/stickers/trending HTTP/1.1\r\nHost: localhost\r\n\r\n",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 403 Forbidden\r\n"));
+        assert_eq!(reason, Reason::Refused);
+    }
+
+    #[tokio::test]
+    async fn test_prefixed() {
+        let (mut client, server) = duplex(64);
+        let mut prefixed = Prefixed::new(b"hello, ".to_vec(), server);
+        client.write_all(b"world").await.unwrap();
+        drop(client);
+        let mut buf = String::new();
+        prefixed.read_to_string(&mut buf).await.unwrap();
+        assert_eq!(buf, "hello, world");
+    }
+}
@@ -0,0 +1,101 @@
+//! Isolation of panics in connection handlers.  A panic while handling one connection is
+//! caught, logged with the connection's ID and destination, and counted, and the
+//! connection is then closed and recorded like any other failure, rather than its task
+//! ending silently.  With `abort_on_panic`, the process aborts instead, for deployments
+//! that would rather restart than keep running after a bug.
+
+use crate::access::{AccessRecord, Reason};
+use crate::error::ProxyError;
+use crate::metrics::METRICS;
+use std::any::Any;
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// A future which catches panics in the future it wraps, returning the panic's payload as
+/// an error
+pub(crate) struct CatchUnwind<F>(Pin<Box<F>>);
+
+/// Catch panics while polling `future`
+pub(crate) fn catch_unwind<F: Future>(future: F) -> CatchUnwind<F> {
+    CatchUnwind(Box::pin(future))
+}
+
+impl<F: Future> Future for CatchUnwind<F> {
+    type Output = Result<F::Output, Box<dyn Any + Send>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // a future that panicked is never polled again, so its state cannot be observed
+        match panic::catch_unwind(AssertUnwindSafe(|| self.0.as_mut().poll(cx))) {
+            Ok(Poll::Ready(output)) => Poll::Ready(Ok(output)),
+            Ok(Poll::Pending) => Poll::Pending,
+            Err(payload) => Poll::Ready(Err(payload)),
+        }
+    }
+}
+
+/// The message given to `panic!`, if it was a string
+fn message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s
+    } else {
+        "(non-string payload)"
+    }
+}
+
+/// Report a panic while handling the connection described by `record`, aborting the
+/// process if `abort` is set, and otherwise marking the record as failed and returning an
+/// error describing the panic
+pub(crate) fn report(
+    payload: Box<dyn Any + Send>,
+    record: &mut AccessRecord,
+    abort: bool,
+) -> ProxyError {
+    let message = message(&*payload);
+    METRICS.connection_panics.inc();
+    log::error!(
+        "connection {} to {} panicked: {}",
+        record.id,
+        record.target.as_deref().unwrap_or("(unknown destination)"),
+        message
+    );
+    if abort {
+        log::error!("aborting, as abort_on_panic is set");
+        std::process::abort();
+    }
+    record.reason = Reason::Error;
+    ProxyError::Panic(message.to_owned())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_catch_unwind() {
+        assert_eq!(catch_unwind(async { 5 }).await.unwrap(), 5);
+
+        let mut record = AccessRecord::new(None);
+        record.reason = Reason::ClientClosed;
+        record.target = Some("api.giphy.com:443".into());
+        let target = record.target.clone();
+        let payload = catch_unwind(async move {
+            tokio::task::yield_now().await;
+            panic!("lost {:?}", target);
+        })
+        .await
+        .unwrap_err();
+
+        let before = METRICS.connection_panics.get();
+        let err = report(payload, &mut record, false);
+        assert_eq!(
+            err.to_string(),
+            "panicked: lost Some(\"api.giphy.com:443\")"
+        );
+        assert_eq!(record.reason, Reason::Error);
+        assert!(METRICS.connection_panics.get() > before);
+    }
+}
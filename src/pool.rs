@@ -0,0 +1,231 @@
+//! A pool of idle keep-alive connections to backends, for the modes in which the proxy
+//! makes HTTP requests itself.  Connections are keyed by destination, and are closed once
+//! they have been idle for the configured time, or to make room when the pool is full.
+//! Before a connection is reused, it is checked for having been closed by the backend.
+
+use crate::config::PoolConfig;
+use crate::metrics::METRICS;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::time::timeout;
+
+/// Idle connections, with the time each was returned to the pool, oldest first
+type Idle<S> = HashMap<String, VecDeque<(Instant, S)>>;
+
+/// A pool of idle connections of type `S`
+pub(crate) struct Pool<S> {
+    config: PoolConfig,
+    idle: Mutex<Idle<S>>,
+}
+
+impl<S: AsyncRead + Unpin> Pool<S> {
+    pub(crate) fn new(config: PoolConfig) -> Self {
+        Self {
+            config,
+            idle: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether connections are pooled at all
+    pub(crate) fn enabled(&self) -> bool {
+        self.config.max_idle > 0
+    }
+
+    /// Take the most recently used idle connection to `key` that is still open, if any
+    pub(crate) async fn take(&self, key: &str) -> Option<S> {
+        loop {
+            let socket = {
+                let mut idle = self.idle.lock().unwrap();
+                self.reap_locked(&mut idle);
+                idle.get_mut(key).and_then(VecDeque::pop_back)
+            };
+            match socket {
+                Some((_, mut socket)) => {
+                    METRICS.pool_idle.dec();
+                    if is_open(&mut socket).await {
+                        METRICS.pool_hits.inc();
+                        return Some(socket);
+                    }
+                }
+                None => {
+                    METRICS.pool_misses.inc();
+                    return None;
+                }
+            }
+        }
+    }
+
+    /// Return a connection to `key` to the pool, closing the oldest idle connections if it
+    /// is full
+    pub(crate) fn put(&self, key: &str, socket: S) {
+        if !self.enabled() {
+            return;
+        }
+        let mut idle = self.idle.lock().unwrap();
+        self.reap_locked(&mut idle);
+        let sockets = idle.entry(key.to_owned()).or_default();
+        if sockets.len() >= self.config.max_idle_per_host {
+            if sockets.pop_front().is_none() {
+                // max_idle_per_host is zero
+                return;
+            }
+            METRICS.pool_idle.dec();
+        }
+        sockets.push_back((Instant::now(), socket));
+        METRICS.pool_idle.inc();
+
+        while count(&idle) > self.config.max_idle {
+            let oldest = idle
+                .iter()
+                .filter_map(|(key, sockets)| Some((sockets.front()?.0, key.clone())))
+                .min();
+            if let Some((_, key)) = oldest {
+                remove_oldest(&mut idle, &key);
+            }
+        }
+    }
+
+    /// Close connections that have been idle for longer than the idle timeout
+    pub(crate) fn reap(&self) {
+        self.reap_locked(&mut self.idle.lock().unwrap());
+    }
+
+    /// Close expired connections, with the lock held
+    fn reap_locked(&self, idle: &mut Idle<S>) {
+        let now = Instant::now();
+        for sockets in idle.values_mut() {
+            while let Some((returned, _)) = sockets.front() {
+                if now.duration_since(*returned) < self.config.idle_timeout {
+                    break;
+                }
+                sockets.pop_front();
+                METRICS.pool_idle.dec();
+            }
+        }
+        idle.retain(|_, sockets| !sockets.is_empty());
+    }
+}
+
+impl<S> Drop for Pool<S> {
+    fn drop(&mut self) {
+        if let Ok(idle) = self.idle.get_mut() {
+            for _ in 0..count(idle) {
+                METRICS.pool_idle.dec();
+            }
+        }
+    }
+}
+
+/// The number of idle connections in the pool
+fn count<S>(idle: &Idle<S>) -> usize {
+    idle.values().map(VecDeque::len).sum()
+}
+
+/// Close the oldest idle connection to `key`
+fn remove_oldest<S>(idle: &mut Idle<S>, key: &str) {
+    if let Some(sockets) = idle.get_mut(key) {
+        if sockets.pop_front().is_some() {
+            METRICS.pool_idle.dec();
+        }
+        if sockets.is_empty() {
+            idle.remove(key);
+        }
+    }
+}
+
+/// Whether an idle connection is still usable: the backend has neither closed it nor sent
+/// anything unsolicited on it
+async fn is_open<S: AsyncRead + Unpin>(socket: &mut S) -> bool {
+    let mut buf = [0u8; 1];
+    // the read is polled once before the timeout is checked
+    timeout(Duration::ZERO, socket.read(&mut buf))
+        .await
+        .is_err()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::{duplex, DuplexStream};
+
+    fn new_pool(
+        max_idle: usize,
+        max_idle_per_host: usize,
+        idle_timeout: Duration,
+    ) -> Pool<DuplexStream> {
+        Pool::new(PoolConfig {
+            max_idle,
+            max_idle_per_host,
+            idle_timeout,
+        })
+    }
+
+    /// A connection, with its other end, which must be kept for it to remain open
+    fn connection() -> (DuplexStream, DuplexStream) {
+        duplex(64)
+    }
+
+    #[tokio::test]
+    async fn test_take_and_put() {
+        let pool = new_pool(4, 2, Duration::from_secs(60));
+        assert!(pool.take("a:443").await.is_none());
+
+        let (a, _a) = connection();
+        pool.put("a:443", a);
+        assert!(pool.take("b:443").await.is_none());
+        assert!(pool.take("a:443").await.is_some());
+        assert!(pool.take("a:443").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_closed() {
+        let pool = new_pool(4, 2, Duration::from_secs(60));
+        let (a, peer) = connection();
+        pool.put("a:443", a);
+        drop(peer);
+        assert!(pool.take("a:443").await.is_none());
+
+        let (a, mut peer) = connection();
+        pool.put("a:443", a);
+        tokio::io::AsyncWriteExt::write_all(&mut peer, b"HTTP/1.1 408 Request Timeout\r\n")
+            .await
+            .unwrap();
+        assert!(pool.take("a:443").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_bounds() {
+        let pool = new_pool(3, 2, Duration::from_secs(60));
+        let mut peers = vec![];
+        for key in ["a:443", "a:443", "a:443", "b:443", "c:443"].iter() {
+            let (socket, peer) = connection();
+            pool.put(key, socket);
+            peers.push(peer);
+        }
+        // one connection to a was closed for the per-host limit, and another to make room
+        // for c
+        let idle = pool.idle.lock().unwrap();
+        assert_eq!(count(&idle), 3);
+        assert_eq!(idle["a:443"].len(), 1);
+        assert_eq!(idle["b:443"].len(), 1);
+        assert_eq!(idle["c:443"].len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_reap() {
+        let pool = new_pool(4, 2, Duration::from_millis(10));
+        let (a, _a) = connection();
+        pool.put("a:443", a);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        pool.reap();
+        assert!(pool.idle.lock().unwrap().is_empty());
+
+        let pool = new_pool(0, 2, Duration::from_secs(60));
+        assert!(!pool.enabled());
+        let (a, _a) = connection();
+        pool.put("a:443", a);
+        assert!(pool.take("a:443").await.is_none());
+    }
+}
@@ -0,0 +1,343 @@
+use crate::access::AccessLog;
+use crate::acl::Acl;
+use crate::admin::{start_admin, Health};
+use crate::audit::Audit;
+use crate::auth::Htpasswd;
+use crate::backend::{Backend, BoxBackend};
+use crate::breaker::CircuitBreaker;
+use crate::cache::ResponseCache;
+use crate::capture::Capture;
+use crate::config::{AclConfig, Config};
+use crate::healthcheck::HealthCheck;
+use crate::layer::RateLimit;
+use crate::listen::start_listening;
+use crate::mitm::Mitm;
+use crate::proxy_protocol::ProxyProtocolBackend;
+use crate::quota::Quotas;
+use crate::registry::REGISTRY;
+use crate::reverse::Reverse;
+use crate::tap::Taps;
+use crate::tls::Acceptor;
+use anyhow::Result;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+/// A configured proxy, ready to start.
+pub struct Proxy<B: Backend> {
+    config: Arc<Config>,
+    backend: Arc<RateLimit<CircuitBreaker<ProxyProtocolBackend<B>>>>,
+    htpasswd: Option<Arc<Htpasswd>>,
+    mitm: Option<Arc<Mitm>>,
+    cache: Option<Arc<ResponseCache>>,
+    tls: Option<Arc<Acceptor>>,
+    acl: Option<Arc<Acl>>,
+    access_log: Option<Arc<AccessLog>>,
+    audit: Option<Arc<Audit>>,
+    quotas: Option<Arc<Quotas>>,
+    taps: Option<Arc<Taps>>,
+    capture: Option<Arc<Capture>>,
+    health: Arc<Health>,
+    health_check: Option<Arc<HealthCheck>>,
+}
+
+/// A builder for [`Proxy`].  Anything not set explicitly is taken from the default
+/// [`Config`].
+pub struct ProxyBuilder<B: Backend> {
+    config: Config,
+    bind: Vec<SocketAddr>,
+    /// Creates the backend from the final configuration
+    make_backend: Box<dyn FnOnce(&Config) -> B>,
+}
+
+impl Proxy<BoxBackend> {
+    /// Begin building a new proxy.  Unless `backend` is called, the proxy will use the
+    /// backend selected by the `backend` section of the config (see
+    /// [`BoxBackend::from_config`]).
+    pub fn builder() -> ProxyBuilder<BoxBackend> {
+        ProxyBuilder {
+            config: Config::default(),
+            bind: vec![],
+            make_backend: Box::new(|config| BoxBackend::from_config(&config.backend)),
+        }
+    }
+}
+
+impl<B: Backend + 'static> Proxy<B> {
+    /// Bind all configured listen addresses and begin accepting connections in
+    /// background tasks, also starting the admin server, HTTP/3 listeners, and reverse
+    /// proxy if configured, reloading the TLS certificate and client ACL rules file on
+    /// SIGHUP, and periodically saving the response cache to its file.  If an access log
+    /// file is configured, access records are written to it from now on, and it is
+    /// reopened on SIGUSR1.  A snapshot of the open tunnels is logged on SIGUSR2, and
+    /// drain mode is toggled on SIGQUIT.  Returns the bound listen addresses, which is
+    /// useful when binding to port 0.
+    ///
+    /// The admin server's `/readyz` endpoint reports the proxy as ready once this has
+    /// bound the listen addresses, and, if backend health checks are configured, while
+    /// they pass.  The checks begin here.
+    pub async fn start(&self) -> Result<Vec<SocketAddr>> {
+        if let Some(addr) = self.config.admin.listen {
+            start_admin(addr, self.config.limits.head_timeout, self.health.clone()).await?;
+        }
+        if let Some(tls) = &self.tls {
+            tls.reload_on_sighup()?;
+        }
+        if let Some(acl) = &self.acl {
+            acl.reload_on_sighup()?;
+        }
+        if let Some(cache) = &self.cache {
+            cache.persist_periodically();
+        }
+        REGISTRY.dump_on_sigusr2()?;
+        REGISTRY.toggle_drain_on_sigquit()?;
+        if let Some(access_log) = &self.access_log {
+            access_log.install();
+            access_log.reopen_on_sigusr1()?;
+        }
+        if let Some(audit) = &self.audit {
+            audit.install();
+        }
+        if let Some(quotas) = &self.quotas {
+            quotas.install();
+            quotas.persist_periodically();
+        }
+        if let Some(taps) = &self.taps {
+            taps.install();
+        }
+        if let Some(capture) = &self.capture {
+            capture.install();
+        }
+        if let Some(check) = &self.health_check {
+            check.start(self.backend.clone(), &self.config.backend)?;
+        }
+        let addrs = start_listening(
+            self.config.clone(),
+            self.backend.clone(),
+            self.htpasswd.clone(),
+            self.mitm.clone(),
+            self.tls.clone(),
+            self.acl.clone(),
+        )
+        .await?;
+        #[cfg(feature = "http3")]
+        if !self.config.http3.listen.is_empty() {
+            crate::http3::start(
+                self.config.clone(),
+                self.backend.clone(),
+                self.htpasswd.clone(),
+                self.acl.clone(),
+            )
+            .await?;
+        }
+        if !self.config.reverse.listen.is_empty() {
+            let mut reverse = Reverse::new(self.config.clone(), self.backend.clone())?;
+            if let Some(cache) = &self.cache {
+                reverse = reverse.with_cache(cache.clone());
+            }
+            Arc::new(reverse).start().await?;
+        }
+        self.health.set_listening();
+        Ok(addrs)
+    }
+
+    /// The configuration this proxy will use
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+}
+
+impl<B: Backend + 'static> ProxyBuilder<B> {
+    /// Use the given configuration as the basis for the proxy.  Other builder methods
+    /// override values in this configuration.
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Listen on the given address.  This can be called multiple times to listen on
+    /// several addresses, and replaces any listen addresses in the configuration.
+    pub fn bind(mut self, addr: SocketAddr) -> Self {
+        self.bind.push(addr);
+        self
+    }
+
+    /// Use the given backend for all connections.
+    pub fn backend<B2: Backend + 'static>(self, backend: B2) -> ProxyBuilder<B2> {
+        ProxyBuilder {
+            config: self.config,
+            bind: self.bind,
+            make_backend: Box::new(move |_| backend),
+        }
+    }
+
+    /// Build the proxy, validating its configuration, reading the htpasswd file, TLS
+    /// certificate, interception CA, client ACL rules file, and response cache file, and
+    /// opening the access log file, if any.  The backend is wrapped in a
+    /// [`CircuitBreaker`] if one is configured, in a [`ProxyProtocolBackend`] if
+    /// `backend.send_proxy_protocol` is set, and in a [`RateLimit`] if
+    /// `backend.connect_rate` is set.
+    pub fn build(mut self) -> Result<Proxy<B>> {
+        if !self.bind.is_empty() {
+            self.config.listen = self.bind;
+        }
+        self.config.validate()?;
+        let htpasswd = match &self.config.auth.htpasswd {
+            Some(path) => Some(Arc::new(Htpasswd::from_file(path)?)),
+            None => None,
+        };
+        let tls = match &self.config.tls.cert {
+            Some(_) => Some(Arc::new(Acceptor::new(&self.config.tls)?)),
+            None => None,
+        };
+        let acl = if self.config.acl == AclConfig::default() {
+            None
+        } else {
+            Some(Arc::new(Acl::new(&self.config.acl)?))
+        };
+        let access_log = match &self.config.log.access.path {
+            Some(_) => Some(Arc::new(AccessLog::open(&self.config.log.access)?)),
+            None => None,
+        };
+        let audit = match &self.config.audit.database {
+            Some(_) => Some(Arc::new(Audit::start(&self.config.audit)?)),
+            None => None,
+        };
+        let quotas = if self.config.quota.enabled() {
+            Some(Arc::new(Quotas::new(&self.config.quota)?))
+        } else {
+            None
+        };
+        let taps = self
+            .config
+            .tap
+            .as_ref()
+            .map(|config| Arc::new(Taps::new(config)));
+        let capture = self
+            .config
+            .capture
+            .as_ref()
+            .map(|config| Arc::new(Capture::new(config)));
+        let backend = ProxyProtocolBackend::new(
+            (self.make_backend)(&self.config),
+            self.config.backend.send_proxy_protocol,
+        );
+        let backend = CircuitBreaker::new(backend, self.config.backend.circuit_breaker.clone());
+        let backend = RateLimit::new(backend, self.config.backend.connect_rate.as_ref());
+        let backend = Arc::new(backend);
+        let mut health = Health::new();
+        if self.config.admin.probe_backend {
+            health = health.with_backend_probe(
+                backend.clone(),
+                self.config.backend.host.clone(),
+                self.config.backend.port,
+                self.config.admin.probe_timeout,
+            );
+        }
+        let health_check = self
+            .config
+            .backend
+            .health_check
+            .clone()
+            .map(|config| Arc::new(HealthCheck::new(config)));
+        if let Some(check) = &health_check {
+            health = health.with_health_check(check.clone());
+        }
+        let config = Arc::new(self.config);
+        let cache = if config.cache.max_size == 0 {
+            None
+        } else {
+            Some(Arc::new(ResponseCache::new(&config)?))
+        };
+        let mitm = if config.mitm.hosts.is_empty() {
+            None
+        } else {
+            let mut mitm = Mitm::new(config.clone())?;
+            if let Some(cache) = &cache {
+                mitm = mitm.with_cache(cache.clone());
+            }
+            Some(Arc::new(mitm))
+        };
+        Ok(Proxy {
+            config,
+            backend,
+            htpasswd,
+            mitm,
+            cache,
+            tls,
+            acl,
+            access_log,
+            audit,
+            quotas,
+            taps,
+            capture,
+            health: Arc::new(health),
+            health_check,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::backend::SingleHostBackend;
+    use crate::testing::echo_once;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    #[test]
+    fn test_build_bind_overrides_config() {
+        let proxy = Proxy::builder()
+            .bind("127.0.0.1:1234".parse().unwrap())
+            .bind("[::1]:1234".parse().unwrap())
+            .build()
+            .unwrap();
+        assert_eq!(
+            proxy.config().listen,
+            vec![
+                "127.0.0.1:1234".parse().unwrap(),
+                "[::1]:1234".parse().unwrap()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_build_invalid_config() {
+        let config = Config {
+            listen: vec![],
+            ..Config::default()
+        };
+        assert!(Proxy::builder().config(config).build().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_start() {
+        let _ = env_logger::builder().is_test(true).try_init();
+
+        let port = echo_once().await;
+
+        let proxy = Proxy::builder()
+            .bind("127.0.0.1:0".parse().unwrap())
+            .backend(SingleHostBackend::new("127.0.0.1", port))
+            .build()
+            .unwrap();
+        let addrs = proxy.start().await.unwrap();
+        assert_eq!(addrs.len(), 1);
+        assert_ne!(addrs[0].port(), 0);
+
+        let mut client = TcpStream::connect(addrs[0]).await.unwrap();
+        client
+            .write_all(format!("CONNECT 127.0.0.1:{} HTTP/1.1\r\n\r\n", port).as_bytes())
+            .await
+            .unwrap();
+
+        const EXPECTED_RESPONSE: &[u8] = b"HTTP/1.1 200 OK\r\n\r\n";
+        let mut buf = [0u8; EXPECTED_RESPONSE.len()];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, EXPECTED_RESPONSE);
+
+        client.write_all(b"HELLO").await.unwrap();
+        let mut buf = [0u8; 5];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"HELLO");
+    }
+}
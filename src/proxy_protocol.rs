@@ -0,0 +1,490 @@
+//! Support for the PROXY protocol (versions 1 and 2), with which a layer-4 load balancer
+//! passes the original client address at the start of each connection.  See
+//! <https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt>.
+//!
+//! The proxy can read these headers from its clients, with [`read_header`], and send them
+//! to its backends, with [`ProxyProtocolBackend`].
+
+use crate::backend::Backend;
+use crate::connection::ConnectionInfo;
+use crate::error::{self, IoContext};
+use anyhow::{bail, Context, Result};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// The signature beginning a version 1 header
+const V1_SIGNATURE: &[u8] = b"PROXY ";
+
+/// The signature beginning a version 2 header
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Maximum length of a version 1 header, including the CRLF
+const V1_MAX_LEN: usize = 107;
+
+/// Time to wait between checks for more data while deciding whether a header is present
+const PEEK_INTERVAL: Duration = Duration::from_millis(10);
+
+/// What the start of a connection looks like
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Detected {
+    V1,
+    V2,
+    /// The connection does not begin with a PROXY protocol header
+    Absent,
+    /// Not enough data has arrived to tell
+    Incomplete,
+}
+
+/// Read a PROXY protocol header from the start of the socket, returning the original
+/// client address it carries.  This returns `None` if the header does not carry an
+/// address (for example, a load balancer's health check), or if there is no header and
+/// `required` is false.  Only the header itself is consumed from the socket.
+///
+/// This does not limit how long the client may take; callers should apply a timeout.
+pub async fn read_header(socket: &mut TcpStream, required: bool) -> Result<Option<SocketAddr>> {
+    let mut buf = [0u8; 16];
+    let detected = loop {
+        let n = socket
+            .peek(&mut buf)
+            .await
+            .context("reading PROXY protocol header")?;
+        if n == 0 {
+            bail!("client hung up before sending a PROXY protocol header");
+        }
+        match detect(&buf[..n]) {
+            // peek returns immediately while any data is waiting, so poll for the rest
+            Detected::Incomplete => tokio::time::sleep(PEEK_INTERVAL).await,
+            detected => break detected,
+        }
+    };
+
+    match detected {
+        Detected::V1 => {
+            let mut line = Vec::with_capacity(V1_MAX_LEN);
+            // read a byte at a time, so as to consume nothing after the header
+            while !line.ends_with(b"\r\n") {
+                if line.len() == V1_MAX_LEN {
+                    bail!("PROXY protocol v1 header too long");
+                }
+                line.push(
+                    socket
+                        .read_u8()
+                        .await
+                        .context("reading PROXY protocol header")?,
+                );
+            }
+            parse_v1(&line)
+        }
+        Detected::V2 => {
+            socket
+                .read_exact(&mut buf)
+                .await
+                .context("reading PROXY protocol header")?;
+            let len = u16::from_be_bytes([buf[14], buf[15]]) as usize;
+            let mut addresses = vec![0u8; len];
+            socket
+                .read_exact(&mut addresses)
+                .await
+                .context("reading PROXY protocol header")?;
+            parse_v2(&buf, &addresses)
+        }
+        Detected::Absent if required => bail!("missing PROXY protocol header"),
+        Detected::Absent => Ok(None),
+        Detected::Incomplete => unreachable!("incomplete headers are waited for above"),
+    }
+}
+
+/// Determine whether data begins with a PROXY protocol header
+fn detect(data: &[u8]) -> Detected {
+    for (signature, detected) in [(V1_SIGNATURE, Detected::V1), (V2_SIGNATURE, Detected::V2)] {
+        let n = data.len().min(signature.len());
+        if data[..n] == signature[..n] {
+            return if n == signature.len() {
+                detected
+            } else {
+                Detected::Incomplete
+            };
+        }
+    }
+    Detected::Absent
+}
+
+/// Parse a version 1 header, such as `PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n`
+fn parse_v1(line: &[u8]) -> Result<Option<SocketAddr>> {
+    let line = std::str::from_utf8(line)
+        .ok()
+        .and_then(|line| line.strip_suffix("\r\n"))
+        .context("invalid PROXY protocol v1 header")?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields[..] {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", family @ ("TCP4" | "TCP6"), src, dst, src_port, dst_port] => {
+            let src = parse_v1_addr(family, src)?;
+            parse_v1_addr(family, dst)?;
+            let src_port = parse_v1_port(src_port)?;
+            parse_v1_port(dst_port)?;
+            Ok(Some(SocketAddr::new(src, src_port)))
+        }
+        _ => bail!("invalid PROXY protocol v1 header {:?}", line),
+    }
+}
+
+/// Parse an address in a version 1 header, which must belong to the given family
+fn parse_v1_addr(family: &str, addr: &str) -> Result<IpAddr> {
+    let parsed = match family {
+        "TCP4" => addr.parse::<Ipv4Addr>().map(IpAddr::V4),
+        _ => addr.parse::<Ipv6Addr>().map(IpAddr::V6),
+    };
+    parsed.with_context(|| {
+        format!(
+            "invalid {} address {:?} in PROXY protocol header",
+            family, addr
+        )
+    })
+}
+
+/// Parse a port in a version 1 header, which must be plain decimal digits without
+/// leading zeroes
+fn parse_v1_port(port: &str) -> Result<u16> {
+    let valid = !port.is_empty()
+        && port.bytes().all(|b| b.is_ascii_digit())
+        && (port == "0" || !port.starts_with('0'));
+    match port.parse() {
+        Ok(port) if valid => Ok(port),
+        _ => bail!("invalid port {:?} in PROXY protocol header", port),
+    }
+}
+
+/// Parse a version 2 header, given its fixed 16-byte part and the variable part that
+/// follows it
+fn parse_v2(header: &[u8; 16], rest: &[u8]) -> Result<Option<SocketAddr>> {
+    let version = header[12] >> 4;
+    let command = header[12] & 0x0f;
+    if version != 2 {
+        bail!("unsupported PROXY protocol version {}", version);
+    }
+    match command {
+        // LOCAL: the connection was made by the load balancer itself
+        0 => return Ok(None),
+        // PROXY
+        1 => {}
+        _ => bail!("invalid PROXY protocol v2 command {}", command),
+    }
+
+    // any TLVs after the addresses are ignored
+    match header[13] {
+        // TCP over IPv4
+        0x11 => {
+            if rest.len() < 12 {
+                bail!("PROXY protocol v2 header too short for IPv4 addresses");
+            }
+            let ip = Ipv4Addr::new(rest[0], rest[1], rest[2], rest[3]);
+            let port = u16::from_be_bytes([rest[8], rest[9]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        // TCP over IPv6
+        0x21 => {
+            if rest.len() < 36 {
+                bail!("PROXY protocol v2 header too short for IPv6 addresses");
+            }
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&rest[..16]);
+            let port = u16::from_be_bytes([rest[32], rest[33]]);
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(octets).into(), port)))
+        }
+        // unspecified, UDP, or UNIX sockets, for which the connection's own address is used
+        0x00 | 0x12 | 0x22 | 0x31 | 0x32 => Ok(None),
+        family => bail!("invalid PROXY protocol v2 address family {:#04x}", family),
+    }
+}
+
+/// Encode a version 2 header for a connection from `src` to `dst`.  If either address is
+/// unknown, the header uses the LOCAL command, telling the receiver to use the
+/// connection's own addresses.
+pub fn encode_v2(src: Option<SocketAddr>, dst: Option<SocketAddr>) -> Vec<u8> {
+    let mut header = V2_SIGNATURE.to_vec();
+    let (src, dst) = match (src, dst) {
+        (Some(src), Some(dst)) => (src, dst),
+        _ => {
+            // LOCAL, with unspecified addresses
+            header.extend_from_slice(&[0x20, 0x00, 0, 0]);
+            return header;
+        }
+    };
+    let (src_ip, dst_ip) = (src.ip().to_canonical(), dst.ip().to_canonical());
+    let mut addresses = vec![];
+    let family = match (src_ip, dst_ip) {
+        (IpAddr::V4(src_ip), IpAddr::V4(dst_ip)) => {
+            addresses.extend_from_slice(&src_ip.octets());
+            addresses.extend_from_slice(&dst_ip.octets());
+            0x11
+        }
+        // if the families differ, both are given as IPv6 addresses
+        _ => {
+            for ip in [src_ip, dst_ip] {
+                let ip = match ip {
+                    IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                    IpAddr::V6(ip) => ip,
+                };
+                addresses.extend_from_slice(&ip.octets());
+            }
+            0x21
+        }
+    };
+    addresses.extend_from_slice(&src.port().to_be_bytes());
+    addresses.extend_from_slice(&dst.port().to_be_bytes());
+
+    // PROXY
+    header.push(0x21);
+    header.push(family);
+    header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+    header.extend_from_slice(&addresses);
+    header
+}
+
+/// A backend which wraps another, beginning each connection with a PROXY protocol v2
+/// header carrying the client's address, and the local address on which the client
+/// connected.  If `enabled` is false, connections are passed straight through.
+pub struct ProxyProtocolBackend<B: Backend> {
+    inner: B,
+    enabled: bool,
+}
+
+impl<B: Backend> ProxyProtocolBackend<B> {
+    /// Wrap a backend, sending headers only if `enabled` is set
+    pub fn new(inner: B, enabled: bool) -> Self {
+        Self { inner, enabled }
+    }
+}
+
+#[async_trait::async_trait]
+impl<B: Backend> Backend for ProxyProtocolBackend<B> {
+    type Socket = B::Socket;
+
+    async fn connect(&self, host: &str, port: u16) -> error::Result<Self::Socket> {
+        self.connect_for(&ConnectionInfo::default(), host, port)
+            .await
+    }
+
+    async fn connect_for(
+        &self,
+        info: &ConnectionInfo,
+        host: &str,
+        port: u16,
+    ) -> error::Result<Self::Socket> {
+        let mut socket = self.inner.connect_for(info, host, port).await?;
+        if self.enabled {
+            socket
+                .write_all(&encode_v2(info.peer, info.local))
+                .await
+                .io_context("writing PROXY protocol header to backend")?;
+        }
+        Ok(socket)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+
+    fn addr(s: &str) -> Option<SocketAddr> {
+        Some(s.parse().unwrap())
+    }
+
+    /// A version 2 header with the given command and family bytes, and address data
+    fn v2(command: u8, family: u8, rest: &[u8]) -> ([u8; 16], Vec<u8>) {
+        let mut header = [0u8; 16];
+        header[..12].copy_from_slice(V2_SIGNATURE);
+        header[12] = command;
+        header[13] = family;
+        header[14..].copy_from_slice(&(rest.len() as u16).to_be_bytes());
+        (header, rest.to_vec())
+    }
+
+    #[test]
+    fn test_detect() {
+        assert_eq!(detect(b"PROXY TCP4"), Detected::V1);
+        assert_eq!(detect(b"PROX"), Detected::Incomplete);
+        assert_eq!(detect(V2_SIGNATURE), Detected::V2);
+        assert_eq!(detect(&V2_SIGNATURE[..5]), Detected::Incomplete);
+        assert_eq!(detect(b"CONNECT"), Detected::Absent);
+        assert_eq!(detect(b"\x05\x01\x00"), Detected::Absent);
+        assert_eq!(detect(b"\r\nhello"), Detected::Absent);
+    }
+
+    #[test]
+    fn test_parse_v1() {
+        assert_eq!(
+            parse_v1(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n").unwrap(),
+            addr("192.0.2.1:56324")
+        );
+        assert_eq!(
+            parse_v1(b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n").unwrap(),
+            addr("[2001:db8::1]:56324")
+        );
+        assert_eq!(parse_v1(b"PROXY UNKNOWN\r\n").unwrap(), None);
+        assert_eq!(
+            parse_v1(b"PROXY UNKNOWN ffff::1 ffff::2 1 2\r\n").unwrap(),
+            None
+        );
+        for bad in [
+            &b"PROXY TCP4 192.0.2.1 198.51.100.1 56324\r\n"[..],
+            b"PROXY TCP4 2001:db8::1 198.51.100.1 56324 443\r\n",
+            b"PROXY TCP6 192.0.2.1 198.51.100.1 56324 443\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 056324 443\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 +5632 443\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 65536 443\r\n",
+            b"PROXY TCP4  192.0.2.1 198.51.100.1 56324 443\r\n",
+            b"PROXY UDP4 192.0.2.1 198.51.100.1 56324 443\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\n",
+        ] {
+            assert!(parse_v1(bad).is_err(), "{:?}", String::from_utf8_lossy(bad));
+        }
+    }
+
+    #[test]
+    fn test_parse_v2() {
+        let (header, rest) = v2(
+            0x21,
+            0x11,
+            &[192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb],
+        );
+        assert_eq!(parse_v2(&header, &rest).unwrap(), addr("192.0.2.1:56324"));
+
+        let mut rest = vec![0u8; 36];
+        rest[..16].copy_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        rest[32..34].copy_from_slice(&56324u16.to_be_bytes());
+        // followed by a TLV, which is ignored
+        rest.extend_from_slice(&[0x04, 0x00, 0x01, 0x00]);
+        let (header, rest) = v2(0x21, 0x21, &rest);
+        assert_eq!(
+            parse_v2(&header, &rest).unwrap(),
+            addr("[2001:db8::1]:56324")
+        );
+
+        let (header, rest) = v2(0x20, 0x00, &[]);
+        assert_eq!(parse_v2(&header, &rest).unwrap(), None);
+
+        for (command, family, rest) in [
+            (0x11, 0x11, vec![0u8; 12]),
+            (0x22, 0x11, vec![0u8; 12]),
+            (0x21, 0x11, vec![0u8; 11]),
+            (0x21, 0x21, vec![0u8; 12]),
+            (0x21, 0x41, vec![0u8; 12]),
+        ] {
+            let (header, rest) = v2(command, family, &rest);
+            assert!(parse_v2(&header, &rest).is_err());
+        }
+    }
+
+    /// Send the given bytes over a TCP connection, and read a header from the other end,
+    /// returning its result and the remaining data
+    async fn read_from(
+        data: &'static [u8],
+        required: bool,
+    ) -> (Result<Option<SocketAddr>>, Vec<u8>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = tokio::spawn(async move {
+            let mut client = TcpStream::connect(addr).await.unwrap();
+            // send the data in two parts, to exercise waiting for more
+            client.write_all(&data[..3]).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            client.write_all(&data[3..]).await.unwrap();
+        });
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let result = read_header(&mut socket, required).await;
+        client.await.unwrap();
+        let mut remaining = vec![];
+        socket.read_to_end(&mut remaining).await.unwrap();
+        (result, remaining)
+    }
+
+    #[test]
+    fn test_encode_v2() {
+        let parse = |encoded: Vec<u8>| {
+            let mut header = [0u8; 16];
+            header.copy_from_slice(&encoded[..16]);
+            let len = u16::from_be_bytes([header[14], header[15]]) as usize;
+            assert_eq!(encoded.len(), 16 + len);
+            (header[13], parse_v2(&header, &encoded[16..]).unwrap())
+        };
+
+        let encoded = encode_v2(addr("192.0.2.1:56324"), addr("198.51.100.1:8080"));
+        assert_eq!(
+            encoded[16..],
+            [192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x1f, 0x90]
+        );
+        assert_eq!(parse(encoded), (0x11, addr("192.0.2.1:56324")));
+
+        // an IPv4-mapped client on an IPv4 listener is sent as IPv4
+        let encoded = encode_v2(addr("[::ffff:192.0.2.1]:5555"), addr("198.51.100.1:8080"));
+        assert_eq!(parse(encoded), (0x11, addr("192.0.2.1:5555")));
+
+        let encoded = encode_v2(addr("192.0.2.1:5555"), addr("[2001:db8::2]:8080"));
+        assert_eq!(parse(encoded), (0x21, addr("[::ffff:192.0.2.1]:5555")));
+
+        let encoded = encode_v2(None, addr("198.51.100.1:8080"));
+        assert_eq!(encoded.len(), 16);
+        assert_eq!(parse(encoded), (0x00, None));
+    }
+
+    #[tokio::test]
+    async fn test_backend() {
+        use crate::backend::SingleHostBackend;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let backend = ProxyProtocolBackend::new(SingleHostBackend::new("127.0.0.1", port), true);
+        let info = ConnectionInfo::tcp(
+            "192.0.2.1:5555".parse().unwrap(),
+            "127.0.0.1:8080".parse().unwrap(),
+        );
+        let (client, accepted) = tokio::join!(
+            backend.connect_for(&info, "127.0.0.1", port),
+            listener.accept()
+        );
+        let mut client = client.unwrap();
+        client.write_all(b"hello").await.unwrap();
+        drop(client);
+
+        let (mut socket, _) = accepted.unwrap();
+        assert_eq!(
+            read_header(&mut socket, true).await.unwrap(),
+            addr("192.0.2.1:5555")
+        );
+        let mut remaining = vec![];
+        socket.read_to_end(&mut remaining).await.unwrap();
+        assert_eq!(remaining, b"hello");
+    }
+
+    #[tokio::test]
+    async fn test_read_header() {
+        let (result, remaining) = read_from(
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nCONNECT",
+            true,
+        )
+        .await;
+        assert_eq!(result.unwrap(), addr("192.0.2.1:56324"));
+        assert_eq!(remaining, b"CONNECT");
+
+        let (result, remaining) = read_from(
+            b"\r\n\r\n\0\r\nQUIT\n\x21\x11\x00\x0c\xc0\x00\x02\x01\xc6\x33\x64\x01\xdc\x04\x01\xbbCONNECT",
+            true,
+        )
+        .await;
+        assert_eq!(result.unwrap(), addr("192.0.2.1:56324"));
+        assert_eq!(remaining, b"CONNECT");
+
+        let (result, remaining) = read_from(b"CONNECT", false).await;
+        assert_eq!(result.unwrap(), None);
+        assert_eq!(remaining, b"CONNECT");
+
+        let (result, _) = read_from(b"CONNECT", true).await;
+        assert!(result.is_err());
+    }
+}
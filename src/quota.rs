@@ -0,0 +1,321 @@
+//! Daily quotas on the bytes each client may transfer.  Clients are identified by their
+//! user name if they authenticated, and otherwise by their IP address.  A connection is
+//! refused if its client has already used its quota for the day (UTC).  The bytes a
+//! tunnel transfers are counted as they flow, and a tunnel is closed once its client has
+//! used its quota.  The day's usage can be kept in a file across restarts.
+
+use crate::access::AccessRecord;
+use crate::config::QuotaConfig;
+use crate::error::ProxyError;
+use crate::metrics::METRICS;
+use crate::telemetry::spawn;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The quotas, if they have been installed with `Quotas::install`
+static QUOTAS: RwLock<Option<Arc<Quotas>>> = RwLock::new(None);
+
+/// Bytes used by each client on one day, as kept in memory and in the quota file
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+struct Usage {
+    /// The day, in days since the epoch
+    day: u64,
+    /// Bytes used by each client, keyed by user name or IP address
+    used: HashMap<String, u64>,
+}
+
+/// Daily byte quotas for all clients
+pub struct Quotas {
+    daily_bytes: u64,
+    users: HashMap<String, u64>,
+    path: Option<PathBuf>,
+    persist_interval: Duration,
+    usage: Mutex<Usage>,
+}
+
+/// The client of a connection, for quota purposes: its user name if it authenticated, and
+/// otherwise its IP address.  Connections whose client is not known are not limited.
+fn client(record: &AccessRecord) -> Option<(String, Option<&str>)> {
+    match (&record.user, record.client) {
+        (Some(user), _) => Some((user.clone(), Some(user))),
+        (None, Some(addr)) => Some((addr.ip().to_canonical().to_string(), None)),
+        (None, None) => None,
+    }
+}
+
+/// The day containing `now`, in days since the epoch
+fn day(now: SystemTime) -> u64 {
+    now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() / (24 * 60 * 60)
+}
+
+impl Quotas {
+    /// Create quotas as configured, reading the day's usage from the quota file if there
+    /// is one
+    pub fn new(config: &QuotaConfig) -> Result<Self> {
+        let quotas = Self {
+            daily_bytes: config.daily_bytes,
+            users: config.users.clone(),
+            path: config.path.clone(),
+            persist_interval: config.persist_interval,
+            usage: Mutex::new(Usage::default()),
+        };
+        quotas.load(SystemTime::now())?;
+        Ok(quotas)
+    }
+
+    /// The daily limit for a client, or None if it is not limited
+    fn limit(&self, user: Option<&str>) -> Option<u64> {
+        let limit = user
+            .and_then(|user| self.users.get(user))
+            .copied()
+            .unwrap_or(self.daily_bytes);
+        (limit > 0).then_some(limit)
+    }
+
+    /// The usage for the day containing `now`, starting afresh if the day has changed
+    fn usage(&self, now: SystemTime) -> std::sync::MutexGuard<'_, Usage> {
+        let mut usage = self.usage.lock().unwrap();
+        let today = day(now);
+        if usage.day != today {
+            *usage = Usage {
+                day: today,
+                used: HashMap::new(),
+            };
+        }
+        usage
+    }
+
+    /// Check that the client of a connection beginning at `now` has not used its quota
+    pub fn check(&self, record: &AccessRecord, now: SystemTime) -> Result<(), ProxyError> {
+        let Some((key, user)) = client(record) else {
+            return Ok(());
+        };
+        let Some(limit) = self.limit(user) else {
+            return Ok(());
+        };
+        let used = self.usage(now).used.get(&key).copied().unwrap_or(0);
+        if used >= limit {
+            METRICS.connections_over_quota.inc();
+            return Err(ProxyError::QuotaExceeded(key));
+        }
+        Ok(())
+    }
+
+    /// Count the bytes transferred by a connection at `now` against its client's quota
+    pub fn add(&self, record: &AccessRecord, now: SystemTime) {
+        let Some((key, user)) = client(record) else {
+            return;
+        };
+        let bytes = record.bytes_up + record.bytes_down;
+        if self.limit(user).is_some() && bytes > 0 {
+            self.charge(&key, bytes, now);
+        }
+    }
+
+    /// Count `bytes` transferred at `now` against a client's quota, returning the client's
+    /// usage for the day
+    fn charge(&self, key: &str, bytes: u64, now: SystemTime) -> u64 {
+        let mut usage = self.usage(now);
+        let used = usage.used.entry(key.to_owned()).or_insert(0);
+        *used += bytes;
+        *used
+    }
+
+    /// A meter charging the bytes a tunnel transfers against its client's quota, or None
+    /// if the client is not limited
+    pub(crate) fn meter(self: &Arc<Self>, record: &AccessRecord) -> Option<Meter> {
+        let (key, user) = client(record)?;
+        let limit = self.limit(user)?;
+        Some(Meter {
+            quotas: self.clone(),
+            key,
+            limit,
+        })
+    }
+
+    /// Write the day's usage to the quota file, if there is one.  The file is replaced
+    /// atomically, so a crash while writing leaves the previous contents.
+    pub fn save(&self) -> Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let content = serde_json::to_vec(&*self.usage.lock().unwrap())?;
+        let mut tmp = path.clone().into_os_string();
+        tmp.push(".tmp");
+        std::fs::write(&tmp, content).with_context(|| format!("writing quota file {:?}", tmp))?;
+        std::fs::rename(&tmp, path).with_context(|| format!("writing quota file {:?}", path))
+    }
+
+    /// Read usage from the quota file, if there is one and it exists, ignoring it if it is
+    /// for a day other than the one containing `now`
+    fn load(&self, now: SystemTime) -> Result<()> {
+        let path = match &self.path {
+            Some(path) if path.exists() => path,
+            _ => return Ok(()),
+        };
+        let content =
+            std::fs::read(path).with_context(|| format!("reading quota file {:?}", path))?;
+        let usage: Usage = serde_json::from_slice(&content)
+            .with_context(|| format!("parsing quota file {:?}", path))?;
+        if usage.day == day(now) {
+            log::info!(
+                "read usage for {} clients from {:?}",
+                usage.used.len(),
+                path
+            );
+            *self.usage.lock().unwrap() = usage;
+        }
+        Ok(())
+    }
+
+    /// Apply these quotas to all connections from now on
+    pub fn install(self: &Arc<Self>) {
+        *QUOTAS.write().unwrap() = Some(self.clone());
+    }
+
+    /// If there is a quota file, write to it every `persist_interval` in a background task
+    pub fn persist_periodically(self: &Arc<Self>) {
+        if self.path.is_none() {
+            return;
+        }
+        let quotas = self.clone();
+        spawn("quota-persist", async move {
+            let mut interval = tokio::time::interval(quotas.persist_interval);
+            // the first tick completes immediately
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                let quotas = quotas.clone();
+                let result = tokio::task::spawn_blocking(move || quotas.save());
+                if let Ok(Err(e)) = result.await {
+                    log::warn!("saving quota usage: {:?}", e);
+                }
+            }
+        });
+    }
+}
+
+/// Charges the bytes a tunnel transfers against its client's quota as they flow
+pub(crate) struct Meter {
+    quotas: Arc<Quotas>,
+    key: String,
+    limit: u64,
+}
+
+impl Meter {
+    /// Count `bytes` against the client's quota, returning false once it has been used
+    pub(crate) fn charge(&self, bytes: u64) -> bool {
+        self.quotas.charge(&self.key, bytes, SystemTime::now()) < self.limit
+    }
+}
+
+/// Check the connection's client against the installed quotas, if any
+pub(crate) fn check(record: &AccessRecord) -> Result<(), ProxyError> {
+    match QUOTAS.read().unwrap().as_ref() {
+        Some(quotas) => quotas.check(record, SystemTime::now()),
+        None => Ok(()),
+    }
+}
+
+/// Count the connection's bytes against the installed quotas, if any.  This is for bytes
+/// which do not pass through a tunnel, as those are charged by its meter.
+pub(crate) fn add(record: &AccessRecord) {
+    if let Some(quotas) = QUOTAS.read().unwrap().as_ref() {
+        quotas.add(record, SystemTime::now());
+    }
+}
+
+/// Write the installed quotas' usage to the quota file, if there is one, logging any
+/// failure.  This is done when the proxy drains, hands its listeners to a new process, or
+/// shuts down, so that usage since the last periodic save is not lost.
+pub fn save() {
+    if let Some(quotas) = QUOTAS.read().unwrap().as_ref() {
+        if let Err(e) = quotas.save() {
+            log::warn!("saving quota usage: {:?}", e);
+        }
+    }
+}
+
+/// A meter for the tunnel of the connection, under the installed quotas, if any
+pub(crate) fn meter(record: &AccessRecord) -> Option<Meter> {
+    QUOTAS.read().unwrap().as_ref()?.meter(record)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::temp_path;
+
+    fn quotas(path: Option<PathBuf>) -> Quotas {
+        Quotas::new(&QuotaConfig {
+            daily_bytes: 1000,
+            users: HashMap::from([("vip".to_owned(), 0), ("small".to_owned(), 10)]),
+            path,
+            ..QuotaConfig::default()
+        })
+        .unwrap()
+    }
+
+    fn record(user: Option<&str>, bytes: u64) -> AccessRecord {
+        let mut record = AccessRecord::new(Some("10.0.0.1:5555".parse().unwrap()));
+        record.user = user.map(str::to_owned);
+        record.bytes_down = bytes;
+        record
+    }
+
+    #[test]
+    fn test_quota() {
+        let quotas = quotas(None);
+        let now = SystemTime::now();
+
+        quotas.add(&record(None, 999), now);
+        assert!(quotas.check(&record(None, 0), now).is_ok());
+        quotas.add(&record(None, 1), now);
+        let err = quotas.check(&record(None, 0), now).unwrap_err();
+        assert_eq!(err.to_string(), "daily quota exceeded for 10.0.0.1");
+
+        // users are counted separately from their IP, with their own limits
+        assert!(quotas.check(&record(Some("small"), 0), now).is_ok());
+        quotas.add(&record(Some("small"), 10), now);
+        assert!(quotas.check(&record(Some("small"), 0), now).is_err());
+        quotas.add(&record(Some("vip"), 1_000_000), now);
+        assert!(quotas.check(&record(Some("vip"), 0), now).is_ok());
+
+        // usage starts afresh each day
+        let tomorrow = now + Duration::from_secs(24 * 60 * 60);
+        assert!(quotas.check(&record(None, 0), tomorrow).is_ok());
+        assert!(quotas.check(&record(Some("small"), 0), tomorrow).is_ok());
+    }
+
+    #[test]
+    fn test_persist() {
+        let path = temp_path("quota-persist");
+        let quotas1 = quotas(Some(path.clone()));
+        quotas1.add(&record(None, 1000), SystemTime::now());
+        quotas1.save().unwrap();
+
+        let quotas2 = quotas(Some(path.clone()));
+        assert!(quotas2.check(&record(None, 0), SystemTime::now()).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_meter() {
+        let quotas = Arc::new(quotas(None));
+        let meter = quotas.meter(&record(Some("small"), 0)).unwrap();
+        assert!(meter.charge(9));
+        assert!(!meter.charge(1));
+        assert!(quotas
+            .check(&record(Some("small"), 0), SystemTime::now())
+            .is_err());
+
+        // unlimited clients have no meter
+        assert!(quotas.meter(&record(Some("vip"), 0)).is_none());
+        assert!(quotas.meter(&AccessRecord::new(None)).is_none());
+    }
+}
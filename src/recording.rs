@@ -0,0 +1,361 @@
+//! Recording and replay of backend connections, for reproducing interactions offline and
+//! for regression tests of the tunnel path.  With `backend.recording.mode = "record"`, the
+//! bytes sent and received on each connection made by the configured backend are written
+//! to a file in `dir`; with `"replay"`, no connections are made, and each is instead served
+//! from the recording of the same connection.
+//!
+//! Connections are matched by destination and order: the Nth connection to `host:port`
+//! is recorded to, and replayed from, `host_port_N.rec`.  A recording is in the format of
+//! a traffic tap (see `tap`): a line of JSON naming the destination, followed by a record
+//! for each chunk of data.  Replay sends the recorded downstream data in order, first
+//! waiting for as many bytes from the client as were recorded before it, so a client that
+//! sends the same requests gets the same responses.  The bytes are replayed exactly, so
+//! TLS sessions, whose handshakes differ every time, cannot be replayed.
+
+use crate::backend::Backend;
+use crate::connection::{ConnectionInfo, Direction};
+use crate::error::{IoContext, ProxyError, Result};
+use crate::http::authority;
+use crate::tap;
+use crate::telemetry::spawn;
+use anyhow::{bail, Context};
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{Context as TaskContext, Poll};
+use tokio::io::{
+    duplex, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadBuf,
+};
+use tokio::sync::mpsc;
+
+/// Size of the in-memory buffer for each replayed connection
+const REPLAY_BUFFER: usize = 16384;
+
+/// A chunk of connection data, and the direction in which it was sent
+type Chunk = (Direction, Vec<u8>);
+
+/// The number of connections made to each destination, to name their recordings
+#[derive(Default)]
+struct Sequence(Mutex<HashMap<(String, u16), u64>>);
+
+impl Sequence {
+    /// The path of the recording for the next connection to `host:port` in `dir`
+    fn next(&self, dir: &Path, host: &str, port: u16) -> PathBuf {
+        let host = host.to_ascii_lowercase();
+        let mut counts = self.0.lock().unwrap();
+        let count = counts.entry((host.clone(), port)).or_default();
+        let n = *count;
+        *count += 1;
+        // IPv6 addresses contain colons, which are not allowed in file names everywhere
+        let host: String = host
+            .chars()
+            .map(|c| match c {
+                'a'..='z' | '0'..='9' | '.' | '-' => c,
+                _ => '_',
+            })
+            .collect();
+        dir.join(format!("{}_{}_{}.rec", host, port, n))
+    }
+}
+
+/// A backend which wraps another, recording each connection it makes to a file
+pub struct RecordingBackend<B: Backend> {
+    inner: B,
+    dir: PathBuf,
+    sequence: Sequence,
+}
+
+impl<B: Backend> RecordingBackend<B> {
+    /// Wrap `inner`, recording its connections to files in `dir`
+    pub fn new<P: Into<PathBuf>>(inner: B, dir: P) -> Self {
+        Self {
+            inner,
+            dir: dir.into(),
+            sequence: Sequence::default(),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<B: Backend> Backend for RecordingBackend<B> {
+    type Socket = RecordingSocket<B::Socket>;
+
+    async fn connect(&self, host: &str, port: u16) -> Result<Self::Socket> {
+        self.connect_for(&ConnectionInfo::default(), host, port)
+            .await
+    }
+
+    async fn connect_for(
+        &self,
+        info: &ConnectionInfo,
+        host: &str,
+        port: u16,
+    ) -> Result<Self::Socket> {
+        let socket = self.inner.connect_for(info, host, port).await?;
+        let path = self.sequence.next(&self.dir, host, port);
+        let target = authority(host, port);
+        let (sender, receiver) = mpsc::unbounded_channel();
+        spawn("recording", async move {
+            if let Err(e) = write(&path, &target, receiver).await {
+                log::warn!("recording of {} failed: {:#}", target, e);
+            }
+        });
+        Ok(RecordingSocket {
+            inner: socket,
+            sender,
+        })
+    }
+}
+
+/// Create a recording, and write its header and chunks to it
+async fn write(
+    path: &Path,
+    target: &str,
+    mut chunks: mpsc::UnboundedReceiver<Chunk>,
+) -> anyhow::Result<()> {
+    if let Some(dir) = path.parent() {
+        tokio::fs::create_dir_all(dir)
+            .await
+            .with_context(|| format!("creating {:?}", dir))?;
+    }
+    let mut file = tokio::fs::File::create(path)
+        .await
+        .with_context(|| format!("creating {:?}", path))?;
+    let mut line = serde_json::to_vec(&serde_json::json!({ "target": target }))?;
+    line.push(b'\n');
+    file.write_all(&line).await?;
+    while let Some((direction, data)) = chunks.recv().await {
+        file.write_all(&tap::encode(direction, &data)).await?;
+    }
+    file.flush().await?;
+    Ok(())
+}
+
+/// A socket returned by `RecordingBackend`, copying the data read from and written to the
+/// socket it wraps to the recording.  The recording is complete once this is dropped.
+pub struct RecordingSocket<S> {
+    inner: S,
+    sender: mpsc::UnboundedSender<Chunk>,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for RecordingSocket<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let data = &buf.filled()[before..];
+        if !data.is_empty() {
+            // the writer only stops early if the recording failed, which has been logged
+            let _ = self.sender.send((Direction::Down, data.to_vec()));
+        }
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for RecordingSocket<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = result {
+            if n > 0 {
+                let _ = self.sender.send((Direction::Up, buf[..n].to_vec()));
+            }
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// A backend which makes no connections, serving each from its recording instead.
+/// Destinations without a recording for the connection are refused.
+pub struct ReplayBackend {
+    dir: PathBuf,
+    sequence: Sequence,
+}
+
+impl ReplayBackend {
+    /// Replay the recordings in `dir`
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        Self {
+            dir: dir.into(),
+            sequence: Sequence::default(),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Backend for ReplayBackend {
+    type Socket = DuplexStream;
+
+    async fn connect(&self, host: &str, port: u16) -> Result<Self::Socket> {
+        let path = self.sequence.next(&self.dir, host, port);
+        let target = authority(host, port);
+        let data = match tokio::fs::read(&path).await {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(ProxyError::upstream(
+                    target,
+                    format!("no recording at {}", path.display()),
+                ));
+            }
+            Err(e) => return Err(e).with_io_context(|| format!("reading {}", path.display())),
+        };
+        let chunks = parse(&data).with_context(|| format!("reading {}", path.display()))?;
+        let (socket, server) = duplex(REPLAY_BUFFER);
+        spawn("replay", async move {
+            if let Err(e) = replay(server, chunks).await {
+                log::debug!("replay of {} ended early: {}", target, e);
+            }
+        });
+        Ok(socket)
+    }
+}
+
+/// Parse a recording into its chunks
+fn parse(data: &[u8]) -> anyhow::Result<Vec<Chunk>> {
+    let Some(header_end) = data.iter().position(|&b| b == b'\n') else {
+        bail!("recording has no header");
+    };
+    let mut rest = &data[header_end + 1..];
+    let mut chunks = vec![];
+    while !rest.is_empty() {
+        if rest.len() < 5 {
+            bail!("recording is truncated");
+        }
+        let direction = match rest[0] {
+            b'u' => Direction::Up,
+            b'd' => Direction::Down,
+            b => bail!("invalid chunk direction {:?} in recording", b as char),
+        };
+        let len = u32::from_be_bytes(rest[1..5].try_into().unwrap()) as usize;
+        let Some(chunk) = rest.get(5..5 + len) else {
+            bail!("recording is truncated");
+        };
+        chunks.push((direction, chunk.to_vec()));
+        rest = &rest[5 + len..];
+    }
+    Ok(chunks)
+}
+
+/// Play the server's side of a recorded connection on `socket`
+async fn replay(mut socket: DuplexStream, chunks: Vec<Chunk>) -> io::Result<()> {
+    let mut mismatched = false;
+    for (direction, data) in chunks {
+        match direction {
+            Direction::Up => {
+                let mut received = vec![0u8; data.len()];
+                socket.read_exact(&mut received).await?;
+                if received != data && !mismatched {
+                    log::warn!("replayed connection received data that differs from the recording");
+                    mismatched = true;
+                }
+            }
+            Direction::Down => socket.write_all(&data).await?,
+        }
+    }
+    socket.shutdown().await?;
+    // read whatever else the client sends, so that its writes do not fail
+    tokio::io::copy(&mut socket, &mut tokio::io::sink()).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::backend::MockBackend;
+    use crate::testing::temp_path;
+
+    #[test]
+    fn test_sequence() {
+        let sequence = Sequence::default();
+        let dir = Path::new("/r");
+        assert_eq!(
+            sequence.next(dir, "API.giphy.com", 443),
+            Path::new("/r/api.giphy.com_443_0.rec")
+        );
+        assert_eq!(
+            sequence.next(dir, "api.giphy.com", 443),
+            Path::new("/r/api.giphy.com_443_1.rec")
+        );
+        assert_eq!(
+            sequence.next(dir, "api.giphy.com", 80),
+            Path::new("/r/api.giphy.com_80_0.rec")
+        );
+        assert_eq!(sequence.next(dir, "::1", 80), Path::new("/r/__1_80_0.rec"));
+    }
+
+    #[test]
+    fn test_parse() {
+        let mut data = b"{\"target\":\"a:1\"}\n".to_vec();
+        data.extend(tap::encode(Direction::Up, b"ping"));
+        data.extend(tap::encode(Direction::Down, b"pong"));
+        assert_eq!(
+            parse(&data).unwrap(),
+            vec![
+                (Direction::Up, b"ping".to_vec()),
+                (Direction::Down, b"pong".to_vec())
+            ]
+        );
+        assert!(parse(&data[..data.len() - 1]).is_err());
+        assert!(parse(b"no header").is_err());
+        assert!(parse(b"{}\nx\0\0\0\0").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_record_and_replay() {
+        let dir = temp_path("recording-round-trip");
+
+        // record two connections through the echo backend
+        let recorder = RecordingBackend::new(MockBackend, &dir);
+        for message in [&b"first"[..], b"second"] {
+            let mut socket = recorder.connect("echo.example.com", 7).await.unwrap();
+            socket.write_all(message).await.unwrap();
+            let mut echoed = vec![0u8; message.len()];
+            socket.read_exact(&mut echoed).await.unwrap();
+            assert_eq!(echoed, message);
+        }
+        drop(recorder);
+        // the recordings are complete once their writers have finished
+        let path = dir.join("echo.example.com_7_1.rec");
+        for _ in 0..100 {
+            if tokio::fs::read(&path)
+                .await
+                .is_ok_and(|data| parse(&data).is_ok_and(|chunks| chunks.len() == 2))
+            {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+
+        // replay them in the same order, without the echo backend
+        let replayer = ReplayBackend::new(&dir);
+        for message in [&b"first"[..], b"second"] {
+            let mut socket = replayer.connect("echo.example.com", 7).await.unwrap();
+            socket.write_all(message).await.unwrap();
+            let mut replayed = vec![];
+            socket.read_to_end(&mut replayed).await.unwrap();
+            assert_eq!(replayed, message);
+        }
+        // there is no third recording, nor any for other destinations
+        assert!(replayer.connect("echo.example.com", 7).await.is_err());
+        assert!(replayer.connect("other.example.com", 7).await.is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,325 @@
+//! A registry of the tunnels currently open, so that the admin server can list and
+//! terminate them, along with the proxy's drain mode.  A snapshot of the registry is
+//! logged when the process receives SIGUSR2, and drain mode is toggled when it receives
+//! SIGQUIT.
+
+use crate::access::AccessRecord;
+use crate::capture;
+use crate::connection::TunnelState;
+use crate::metrics::METRICS;
+use crate::quota;
+use crate::tap;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+/// How often `Registry::idle` checks for open tunnels
+const IDLE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// An open tunnel
+struct Entry {
+    client: Option<SocketAddr>,
+    target: Option<String>,
+    started: Instant,
+    state: Arc<TunnelState>,
+    terminate: Arc<Notify>,
+}
+
+/// A summary of an open tunnel, as reported by the admin server
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TunnelInfo {
+    /// The tunnel's ID, the same as the `id` in its access log record
+    pub id: u64,
+    pub client: Option<SocketAddr>,
+    pub target: Option<String>,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+    /// Seconds since the tunnel was established
+    pub age_secs: u64,
+}
+
+/// The open tunnels, and whether the proxy is draining
+pub struct Registry {
+    tunnels: Mutex<BTreeMap<u64, Entry>>,
+    draining: AtomicBool,
+    /// Notified whenever the proxy leaves drain mode
+    resumed: Notify,
+}
+
+/// The global registry
+pub static REGISTRY: Registry = Registry {
+    tunnels: Mutex::new(BTreeMap::new()),
+    draining: AtomicBool::new(false),
+    resumed: Notify::const_new(),
+};
+
+impl Registry {
+    /// Register a newly established tunnel, described by its access record.  The tunnel
+    /// remains registered until the returned value is dropped.
+    pub(crate) fn register(&'static self, record: &AccessRecord) -> Registration {
+        let state = Arc::new(TunnelState::new(
+            tap::open(record),
+            capture::open(record),
+            quota::meter(record),
+        ));
+        let terminate = Arc::new(Notify::new());
+        self.tunnels.lock().unwrap().insert(
+            record.id,
+            Entry {
+                client: record.client,
+                target: record.target.clone(),
+                started: Instant::now(),
+                state: state.clone(),
+                terminate: terminate.clone(),
+            },
+        );
+        Registration {
+            registry: self,
+            id: record.id,
+            state,
+            terminate,
+        }
+    }
+
+    /// Summarize the open tunnels, in order of ID
+    pub fn list(&self) -> Vec<TunnelInfo> {
+        self.tunnels
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, entry)| {
+                let (bytes_up, bytes_down) = entry.state.bytes();
+                TunnelInfo {
+                    id: *id,
+                    client: entry.client,
+                    target: entry.target.clone(),
+                    bytes_up,
+                    bytes_down,
+                    age_secs: entry.started.elapsed().as_secs(),
+                }
+            })
+            .collect()
+    }
+
+    /// A human-readable snapshot of the open tunnels, one per line after a summary line
+    /// with their totals and the proxy's connection counters
+    pub fn snapshot(&self) -> String {
+        let tunnels = self.list();
+        let bytes_up: u64 = tunnels.iter().map(|t| t.bytes_up).sum();
+        let bytes_down: u64 = tunnels.iter().map(|t| t.bytes_down).sum();
+        let mut out = format!(
+            "{} open tunnels ({} bytes up, {} bytes down); {} connections accepted, {} \
+             rejected, {} backend connect failures{}",
+            tunnels.len(),
+            bytes_up,
+            bytes_down,
+            METRICS.connections_accepted.get(),
+            METRICS.connections_rejected.get(),
+            METRICS.backend_connect_failures.get(),
+            if self.draining() { "; draining" } else { "" },
+        );
+        for t in tunnels {
+            out.push_str(&format!(
+                "\n  tunnel {}: {} -> {}, {} bytes up, {} bytes down, open {}s",
+                t.id,
+                t.client
+                    .map(|c| c.to_string())
+                    .unwrap_or_else(|| "unknown client".into()),
+                t.target.as_deref().unwrap_or("unknown target"),
+                t.bytes_up,
+                t.bytes_down,
+                t.age_secs,
+            ));
+        }
+        out
+    }
+
+    /// Log a snapshot whenever the process receives SIGUSR2
+    #[cfg(unix)]
+    pub fn dump_on_sigusr2(&'static self) -> anyhow::Result<()> {
+        use anyhow::Context;
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut usr2 = signal(SignalKind::user_defined2()).context("installing SIGUSR2 handler")?;
+        crate::telemetry::spawn("dump-on-sigusr2", async move {
+            while usr2.recv().await.is_some() {
+                log::info!("{}", self.snapshot());
+            }
+        });
+        Ok(())
+    }
+
+    /// Dumping on SIGUSR2 is not supported on this platform
+    #[cfg(not(unix))]
+    pub fn dump_on_sigusr2(&'static self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Close the tunnel with the given ID, returning false if there is no such tunnel
+    pub fn terminate(&self, id: u64) -> bool {
+        match self.tunnels.lock().unwrap().get(&id) {
+            Some(entry) => {
+                entry.terminate.notify_one();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Is the proxy draining?  While draining, new connections are refused, and open
+    /// tunnels continue until they close.
+    pub fn draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    /// Enter or leave drain mode.  Quota usage is saved on entering drain mode, as the
+    /// process is likely to be stopped soon.
+    pub fn set_draining(&self, draining: bool) {
+        log::info!(
+            "{} drain mode",
+            if draining { "entering" } else { "leaving" }
+        );
+        self.draining.store(draining, Ordering::Relaxed);
+        if draining {
+            quota::save();
+        } else {
+            self.resumed.notify_waiters();
+        }
+    }
+
+    /// Wait until no tunnels are open
+    pub async fn idle(&self) {
+        while !self.tunnels.lock().unwrap().is_empty() {
+            tokio::time::sleep(IDLE_POLL_INTERVAL).await;
+        }
+    }
+
+    /// Wait until the proxy is not draining
+    pub async fn not_draining(&self) {
+        loop {
+            // created before checking, so that it sees a notification sent after the check
+            let resumed = self.resumed.notified();
+            if !self.draining() {
+                return;
+            }
+            resumed.await;
+        }
+    }
+
+    /// Toggle drain mode whenever the process receives SIGQUIT
+    #[cfg(unix)]
+    pub fn toggle_drain_on_sigquit(&'static self) -> anyhow::Result<()> {
+        use anyhow::Context;
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut quit = signal(SignalKind::quit()).context("installing SIGQUIT handler")?;
+        crate::telemetry::spawn("drain-on-sigquit", async move {
+            while quit.recv().await.is_some() {
+                self.set_draining(!self.draining());
+            }
+        });
+        Ok(())
+    }
+
+    /// Toggling drain mode on SIGQUIT is not supported on this platform
+    #[cfg(not(unix))]
+    pub fn toggle_drain_on_sigquit(&'static self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// A registered tunnel, removed from the registry when dropped
+pub(crate) struct Registration {
+    registry: &'static Registry,
+    id: u64,
+    /// Bytes transferred and activity, updated by the tunnel
+    pub(crate) state: Arc<TunnelState>,
+    /// Notified when the tunnel should be terminated
+    pub(crate) terminate: Arc<Notify>,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.registry.tunnels.lock().unwrap().remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// The entry for the given ID.  Other tests may register tunnels concurrently, so
+    /// tests only look at their own.
+    fn info(id: u64) -> Option<TunnelInfo> {
+        REGISTRY.list().into_iter().find(|t| t.id == id)
+    }
+
+    #[tokio::test]
+    async fn test_register() {
+        let mut record = AccessRecord::new(Some("10.0.0.1:5555".parse().unwrap()));
+        record.target = Some("api.giphy.com:443".into());
+        let registration = REGISTRY.register(&record);
+        registration
+            .state
+            .transferred(crate::connection::Direction::Up, 10);
+
+        assert_eq!(
+            info(record.id),
+            Some(TunnelInfo {
+                id: record.id,
+                client: record.client,
+                target: record.target.clone(),
+                bytes_up: 10,
+                bytes_down: 0,
+                age_secs: 0,
+            })
+        );
+
+        assert!(REGISTRY.terminate(record.id));
+        // the notification is stored until the tunnel waits for it
+        registration.terminate.notified().await;
+
+        drop(registration);
+        assert_eq!(info(record.id), None);
+        assert!(!REGISTRY.terminate(record.id));
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let mut record = AccessRecord::new(Some("10.0.0.2:5555".parse().unwrap()));
+        record.target = Some("api.giphy.com:443".into());
+        let registration = REGISTRY.register(&record);
+        registration
+            .state
+            .transferred(crate::connection::Direction::Down, 25);
+
+        let snapshot = REGISTRY.snapshot();
+        assert!(snapshot.lines().next().unwrap().contains(" open tunnels ("));
+        assert!(snapshot.contains(&format!(
+            "\n  tunnel {}: 10.0.0.2:5555 -> api.giphy.com:443, 0 bytes up, 25 bytes down, open 0s",
+            record.id
+        )));
+    }
+
+    #[tokio::test]
+    async fn test_not_draining() {
+        // a registry of its own, since draining the global one would affect other tests
+        static DRAINING: Registry = Registry {
+            tunnels: Mutex::new(BTreeMap::new()),
+            draining: AtomicBool::new(false),
+            resumed: Notify::const_new(),
+        };
+        DRAINING.not_draining().await;
+
+        DRAINING.set_draining(true);
+        let waiter = tokio::spawn(DRAINING.not_draining());
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert!(!waiter.is_finished());
+        DRAINING.set_draining(false);
+        waiter.await.unwrap();
+    }
+}
@@ -0,0 +1,35 @@
+//! Reloading of files, such as the TLS certificates and the client ACL rules file, when
+//! the process receives SIGHUP.
+
+use anyhow::Result;
+
+/// Call `reload` whenever the process receives SIGHUP, in a background task, logging
+/// whether it succeeded.  `what` names what is reloaded, for the log.
+#[cfg(unix)]
+pub(crate) fn on_sighup<F>(what: &'static str, reload: F) -> Result<()>
+where
+    F: Fn() -> Result<()> + Send + 'static,
+{
+    use anyhow::Context;
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = signal(SignalKind::hangup()).context("installing SIGHUP handler")?;
+    crate::telemetry::spawn("reload-on-sighup", async move {
+        while hangup.recv().await.is_some() {
+            match reload() {
+                Ok(()) => log::info!("reloaded {}", what),
+                Err(e) => log::error!("reloading {} failed: {:?}", what, e),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Reloading on SIGHUP is not supported on this platform
+#[cfg(not(unix))]
+pub(crate) fn on_sighup<F>(_what: &'static str, _reload: F) -> Result<()>
+where
+    F: Fn() -> Result<()> + Send + 'static,
+{
+    Ok(())
+}
@@ -0,0 +1,572 @@
+//! A reverse proxy for the Giphy API, which keeps the API key out of client configuration.
+//! Clients send plain HTTP API requests, such as `GET /v1/gifs/search?q=cat`, to the
+//! reverse proxy's listen addresses.  Each request is sent over TLS to the backend with the
+//! configured API key added, and the response is streamed back, after which the client
+//! connection is closed.  The backend connection is kept open for reuse by later requests
+//! where possible, unless the connection pool is disabled.  If the response cache is
+//! enabled, cached responses are sent without contacting the backend.
+
+use crate::access::{AccessRecord, Reason};
+use crate::backend::Backend;
+use crate::cache::ResponseCache;
+use crate::config::Config;
+use crate::connection::{
+    record_backend_error, send_response, ConnectionInfo, ConnectionSummary, Protocol,
+};
+use crate::error::ProxyError;
+use crate::forward::{self, host_header, Forward};
+use crate::hooks::Hooks;
+use crate::http::{
+    authority, parse_head, parse_origin, OriginRequest, ParseHeadResult, ParseOptions, Response,
+};
+use crate::listen::{bind_default, spawn_acceptor};
+use crate::metrics::{ActiveTunnel, METRICS};
+use crate::panics;
+use crate::pool::Pool;
+use crate::quota;
+use crate::registry::REGISTRY;
+use crate::telemetry::spawn;
+use crate::tls::TlsBackend;
+use crate::upgrade;
+use anyhow::{bail, Context, Result};
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::time::timeout;
+use tracing::Instrument;
+
+/// The reverse proxy, sending requests to a backend
+pub struct Reverse<B: Backend> {
+    config: Arc<Config>,
+    backend: TlsBackend<B>,
+    pool: Pool<<TlsBackend<B> as Backend>::Socket>,
+    hooks: Hooks,
+}
+
+impl<B: Backend + 'static> Reverse<B> {
+    /// Create a reverse proxy that connects over TLS through `backend` to `backend.host`
+    /// and `backend.port`, verifying the backend's certificate against `backend.tls_ca` or
+    /// the Mozilla root certificates
+    pub fn new(config: Arc<Config>, backend: B) -> Result<Self> {
+        Ok(Self {
+            hooks: Hooks::reverse(&config),
+            backend: TlsBackend::new(backend, &config.backend)?,
+            pool: Pool::new(config.reverse.pool.clone()),
+            config,
+        })
+    }
+
+    /// Bind the reverse proxy's listen addresses and begin accepting connections in
+    /// background tasks
+    pub async fn start(self: Arc<Self>) -> Result<()> {
+        self.reap_periodically();
+        for addr in &self.config.reverse.listen {
+            let listeners = upgrade::listeners(*addr, || bind_default(*addr))
+                .with_context(|| format!("binding reverse proxy address {}", addr))?;
+            for listener in listeners {
+                let local = listener.local_addr()?;
+                log::info!("Reverse proxy listening on {}", local);
+
+                let reverse = self.clone();
+                let restart = self.config.restart_acceptors;
+                spawn_acceptor(
+                    "reverse-acceptor",
+                    listener,
+                    local,
+                    restart,
+                    false,
+                    move |socket, peer| {
+                        METRICS.connections_accepted.inc();
+                        let reverse = reverse.clone();
+                        spawn("reverse-connection", async move {
+                            reverse
+                                .connection(socket, ConnectionInfo::tcp(peer, local))
+                                .await
+                        });
+                    },
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Close idle backend connections as they expire, in a background task
+    fn reap_periodically(self: &Arc<Self>) {
+        if !self.pool.enabled() {
+            return;
+        }
+        let reverse = self.clone();
+        spawn("reverse-pool-reaper", async move {
+            let mut interval = tokio::time::interval(reverse.config.reverse.pool.idle_timeout);
+            loop {
+                interval.tick().await;
+                reverse.pool.reap();
+            }
+        });
+    }
+
+    /// Handle a single client connection until it ends, writing a record of it to the
+    /// access log
+    pub async fn connection<S>(&self, socket: S, info: ConnectionInfo) -> ConnectionSummary
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut record = AccessRecord::new(info.peer);
+        record.protocol = Some(Protocol::Reverse);
+        let span = record.span();
+        let result = panics::catch_unwind(self.handle(socket, &info, &mut record))
+            .instrument(span.clone())
+            .await
+            .unwrap_or_else(|payload| {
+                Err(panics::report(payload, &mut record, self.config.abort_on_panic).into())
+            });
+        if let Err(e) = result {
+            log::error!("reverse proxy connection from {} failed: {:?}", info, e);
+        }
+
+        record.finish();
+        record.record_span(&span);
+        record.log();
+        ConnectionSummary::from_record(&record)
+    }
+
+    /// Implementation of `connection`, filling in the access record as it goes
+    async fn handle<S>(
+        &self,
+        socket: S,
+        info: &ConnectionInfo,
+        record: &mut AccessRecord,
+    ) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let config = &self.config;
+        let mut socket = BufReader::with_capacity(8192, socket);
+        let (host, port) = (&config.backend.host, config.backend.port);
+        let (mut forward, extra) = read_forward(
+            &mut socket,
+            config,
+            record,
+            &self.hooks,
+            host_header(host, port, 443),
+        )
+        .await?;
+        forward.identify(&config.identity);
+        forward.keep_alive = self.pool.enabled();
+        let target = authority(host, port);
+        record.target = Some(target.clone());
+        if respond_from_cache(&mut socket, &self.hooks, &forward, record).await? {
+            return Ok(());
+        }
+        if let Err(e) = quota::check(record) {
+            record.reason = Reason::QuotaExceeded;
+            let response = Response::error(429, "Too Many Requests", "daily quota exceeded");
+            let _ = send_response(&mut socket, response).await;
+            return Err(e.into());
+        }
+
+        let pooled = self.pool.take(&target).await;
+        let connect = async {
+            match pooled {
+                Some(socket) => Ok(socket),
+                None => {
+                    let socket = self
+                        .backend
+                        .connect_for(info, host, port)
+                        .instrument(tracing::info_span!("backend_connect"))
+                        .await;
+                    record.served_by = info.served_by.take();
+                    socket
+                }
+            }
+        };
+        let mut backend_socket = match connect.await {
+            Ok(s) => s,
+            Err(e) => {
+                if !record_backend_error(&e, info, record) {
+                    // TLS errors are not recorded by record_backend_error
+                    record.reason = Reason::BackendError;
+                }
+                let response = Response::error(502, "Bad Gateway", "could not connect to backend");
+                let _ = send_response(&mut socket, response).await;
+                return Err(e.into());
+            }
+        };
+
+        record.reason = Reason::Error;
+        let _active = ActiveTunnel::new();
+        let registration = REGISTRY.register(record);
+        let (summary, reusable) = forward::exchange(
+            socket,
+            extra,
+            &mut backend_socket,
+            &forward,
+            &self.hooks,
+            &config.limits,
+            &registration,
+        )
+        .instrument(tracing::info_span!("tunnel"))
+        .await;
+        summary.record(record);
+        if reusable {
+            self.pool.put(&target, backend_socket);
+        }
+        Ok(())
+    }
+}
+
+impl<B: Backend> Reverse<B> {
+    /// Answer requests from `cache` where possible, and store the responses to them
+    pub(crate) fn with_cache(mut self, cache: Arc<ResponseCache>) -> Self {
+        self.hooks.set_cache(cache);
+        self
+    }
+}
+
+#[cfg(test)]
+impl<B: Backend> Reverse<B> {
+    /// Use the given TLS connector, rather than one trusting the usual root certificates
+    fn with_connector(mut self, connector: tokio_rustls::TlsConnector) -> Self {
+        self.backend = self.backend.with_connector(connector);
+        self
+    }
+}
+
+/// Read an origin-form request from the client within `limits.head_timeout`, and prepare
+/// it to be sent with the given `Host` header, applying `hooks`.  Returns the request with
+/// any data read after it, or responds with an error if the request is invalid, too slow,
+/// or refused, recording the reason in `record`.
+pub(crate) async fn read_forward<S: AsyncRead + AsyncWrite + Unpin>(
+    socket: &mut S,
+    config: &Config,
+    record: &mut AccessRecord,
+    hooks: &Hooks,
+    host: String,
+) -> Result<(Forward, Vec<u8>)> {
+    let (request, extra) = match timeout(
+        config.limits.head_timeout,
+        read_request(socket, config, record),
+    )
+    .await
+    {
+        Ok(result) => result?,
+        Err(_) => {
+            record.reason = Reason::HeadTimeout;
+            let response = Response::error(408, "Request Timeout", "timed out reading request");
+            let _ = send_response(socket, response).await;
+            bail!("timed out reading head from client");
+        }
+    };
+    record.user_agent = request.headers.get("User-Agent").map(str::to_owned);
+
+    let mut forward = match Forward::from_origin(request, host) {
+        Ok(forward) => forward,
+        Err(e) => {
+            record.reason = Reason::BadRequest;
+            let response = Response::error(400, "Bad Request", "invalid request");
+            let _ = send_response(socket, response).await;
+            return Err(e);
+        }
+    };
+    if let Err(response) = hooks.apply(&mut forward) {
+        record.reason = Reason::Refused;
+        let status = response.status;
+        let _ = send_response(socket, response).await;
+        bail!("request refused with {}", status);
+    }
+    Ok((forward, extra))
+}
+
+/// Answer a request with its cached response, if `hooks` has one, recording this in
+/// `record` and charging it against the client's quota.  Returns whether the request was
+/// answered.
+pub(crate) async fn respond_from_cache<S: AsyncWrite + Unpin>(
+    socket: &mut S,
+    hooks: &Hooks,
+    request: &Forward,
+    record: &mut AccessRecord,
+) -> Result<bool> {
+    let response = match hooks.cached(request) {
+        Some(response) => response,
+        None => return Ok(false),
+    };
+    record.reason = Reason::Cached;
+    socket
+        .write_all(&response)
+        .await
+        .context("writing to client socket")?;
+    socket
+        .shutdown()
+        .await
+        .context("writing to client socket")?;
+    record.bytes_down = response.len() as u64;
+    quota::add(record);
+    Ok(true)
+}
+
+/// Read and parse the request head, without any time limit, returning the request with any
+/// data read after it.  As for the main proxy, heads larger than `limits.max_head_size`,
+/// with more than `limits.max_headers` headers, or with lines longer than
+/// `limits.max_header_line` are rejected with 431.  Only origin-form
+/// requests such as `GET /v1/gifs HTTP/1.1` are accepted.
+async fn read_request<S: AsyncRead + AsyncWrite + Unpin>(
+    socket: &mut S,
+    config: &Config,
+    record: &mut AccessRecord,
+) -> Result<(OriginRequest, Vec<u8>)> {
+    let limits = &config.limits;
+    let options = ParseOptions::from(limits);
+    let mut buf = vec![0u8; limits.max_head_size];
+    let mut buf_size = 0;
+    let (request, len) = loop {
+        if buf_size == buf.len() {
+            record.reason = Reason::BadRequest;
+            let response = Response::error(
+                431,
+                "Request Header Fields Too Large",
+                "request head too large",
+            );
+            let _ = send_response(socket, response).await;
+            bail!("request head exceeds {} bytes", limits.max_head_size);
+        }
+
+        record.reason = Reason::ClientError;
+        let n = socket
+            .read(&mut buf[buf_size..])
+            .await
+            .context("reading head from client")?;
+        if n == 0 {
+            bail!("client hung up while writing HTTP head");
+        }
+        buf_size += n;
+
+        let result = match parse_head(&buf[..buf_size], &options) {
+            ParseHeadResult::OtherMethod { len, .. } => {
+                parse_origin(&buf[..len], &options).map(|request| (request, len))
+            }
+            ParseHeadResult::TooLarge(limit) => {
+                record.reason = Reason::BadRequest;
+                let response = Response::error(431, "Request Header Fields Too Large", limit);
+                let _ = send_response(socket, response).await;
+                bail!("request head rejected: {}", limit);
+            }
+            ParseHeadResult::Connect { .. } => {
+                Err(ProxyError::Parse("CONNECT is not supported".into()))
+            }
+            ParseHeadResult::Err(e) => Err(e),
+            ParseHeadResult::Incomplete => continue,
+        };
+        match result {
+            Ok(result) => break result,
+            Err(e) => {
+                METRICS.parse_failures.inc();
+                record.reason = Reason::BadRequest;
+                let response = Response::error(400, "Bad Request", "invalid request");
+                let _ = send_response(socket, response).await;
+                return Err(anyhow::Error::from(e).context("reading head from client"));
+            }
+        }
+    };
+
+    Ok((request, buf[len..buf_size].to_vec()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::backend::Disallowed;
+    use crate::error;
+    use rustls::{ClientConfig, RootCertStore, ServerConfig};
+    use tokio::io::{duplex, AsyncWriteExt, DuplexStream};
+    use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+    /// A backend whose connections lead to a TLS server for `localhost`, which answers
+    /// each request with the request head it received, until a request asks it to close
+    /// the connection
+    struct TlsEchoBackend {
+        acceptor: TlsAcceptor,
+    }
+
+    #[async_trait::async_trait]
+    impl Backend for TlsEchoBackend {
+        type Socket = DuplexStream;
+        async fn connect(&self, host: &str, port: u16) -> error::Result<Self::Socket> {
+            if host != "localhost" {
+                return Err(Disallowed {
+                    host: host.into(),
+                    port,
+                }
+                .into());
+            }
+            let (server, backend_socket) = duplex(16384);
+            let acceptor = self.acceptor.clone();
+            tokio::spawn(async move {
+                let mut server = acceptor.accept(server).await.unwrap();
+                let mut buf = [0u8; 1024];
+                loop {
+                    let mut request = vec![];
+                    while !request.ends_with(b"\r\n\r\n") {
+                        match server.read(&mut buf).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => request.extend_from_slice(&buf[..n]),
+                        }
+                    }
+                    let head = format!(
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n",
+                        request.len()
+                    );
+                    server.write_all(head.as_bytes()).await.unwrap();
+                    server.write_all(&request).await.unwrap();
+                    if request.ends_with(b"Connection: close\r\n\r\n") {
+                        server.shutdown().await.unwrap();
+                        return;
+                    }
+                }
+            });
+            Ok(backend_socket)
+        }
+    }
+
+    /// A reverse proxy for a TLS backend at `localhost`
+    fn reverse(config: Config) -> Reverse<TlsEchoBackend> {
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let server_config = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![certified.cert.der().clone()],
+                rustls::pki_types::PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der())
+                    .into(),
+            )
+            .unwrap();
+        let mut roots = RootCertStore::empty();
+        roots.add(certified.cert.der().clone()).unwrap();
+        let client_config = ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        let backend = TlsEchoBackend {
+            acceptor: TlsAcceptor::from(Arc::new(server_config)),
+        };
+        Reverse::new(Arc::new(config), backend)
+            .unwrap()
+            .with_connector(TlsConnector::from(Arc::new(client_config)))
+    }
+
+    /// Send `request` to the reverse proxy, returning the full response and the summary
+    async fn exchange(config: Config, request: &'static [u8]) -> (String, ConnectionSummary) {
+        send(&reverse(config), request).await
+    }
+
+    /// Send `request` to the given reverse proxy, returning the full response and the
+    /// summary
+    async fn send(
+        reverse: &Reverse<TlsEchoBackend>,
+        request: &'static [u8],
+    ) -> (String, ConnectionSummary) {
+        let (mut client, server) = duplex(16384);
+        let client_task = tokio::spawn(async move {
+            client.write_all(request).await.unwrap();
+            let mut buf = vec![];
+            client.read_to_end(&mut buf).await.unwrap();
+            String::from_utf8(buf).unwrap()
+        });
+        let summary = reverse.connection(server, ConnectionInfo::default()).await;
+        (client_task.await.unwrap(), summary)
+    }
+
+    fn config() -> Config {
+        let mut config = Config::default();
+        config.backend.host = "localhost".into();
+        config.backend.port = 443;
+        config.reverse.api_key = Some("secret".into());
+        config
+    }
+
+    #[tokio::test]
+    async fn test_reverse() {
+        let (response, summary) = exchange(
+            config(),
+            b"GET /v1/gifs/search?q=cat HTTP/1.1\r\nHost: 127.0.0.1:8081\r\n\r\n",
+        )
+        .await;
+        let expected = "GET /v1/gifs/search?q=cat&api_key=secret HTTP/1.1\r\n\
+                        Host: localhost\r\nConnection: keep-alive\r\n\r\n";
+        assert_eq!(
+            response,
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                expected.len(),
+                expected
+            )
+        );
+        assert_eq!(summary.reason, Reason::BackendClosed);
+    }
+
+    #[tokio::test]
+    async fn test_reverse_pool() {
+        let reverse = reverse(config());
+        let hits = || crate::metrics::METRICS.pool_hits.get();
+        let request = b"GET /v1/gifs/trending HTTP/1.1\r\n\r\n";
+        let (first, _) = send(&reverse, request).await;
+        let before = hits();
+        let (second, summary) = send(&reverse, request).await;
+        // the second request was sent on the first's connection
+        assert!(hits() > before);
+        assert_eq!(first, second);
+        assert_eq!(summary.reason, Reason::BackendClosed);
+
+        let mut config = config();
+        config.reverse.pool.max_idle = 0;
+        let (response, _) = exchange(config, request).await;
+        assert!(response.ends_with("Host: localhost\r\nConnection: close\r\n\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_reverse_cache() {
+        let mut config = config();
+        config.cache.max_size = 100000;
+        let cache = Arc::new(ResponseCache::new(&config).unwrap());
+        let reverse = reverse(config).with_cache(cache);
+
+        let (response, summary) = send(
+            &reverse,
+            b"GET /v1/gifs/search?q=cat&limit=5 HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n",
+        )
+        .await;
+        let expected = "GET /v1/gifs/search?q=cat&limit=5&api_key=secret HTTP/1.1\r\n\
+                        Host: localhost\r\nConnection: keep-alive\r\n\r\n";
+        let head = format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n",
+            expected.len()
+        );
+        assert_eq!(response, format!("{}\r\n{}", head, expected));
+        assert_eq!(summary.reason, Reason::BackendClosed);
+
+        // the same query, in a different order, is answered from the cache
+        let (response, summary) = send(
+            &reverse,
+            b"GET /v1/gifs/search?limit=5&q=cat HTTP/1.1\r\n\r\n",
+        )
+        .await;
+        assert_eq!(response, format!("{}Age: 0\r\n\r\n{}", head, expected));
+        assert_eq!(summary.reason, Reason::Cached);
+        assert_eq!(summary.bytes_down, response.len() as u64);
+    }
+
+    #[tokio::test]
+    async fn test_reverse_errors() {
+        let (response, summary) = exchange(config(), b"CONNECT foo.com:443 HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        assert_eq!(summary.reason, Reason::BadRequest);
+
+        let mut disallowed = config();
+        disallowed.backend.host = "giphy.com".into();
+        let (response, summary) = exchange(disallowed, b"GET / HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 502 Bad Gateway\r\n"));
+        assert_eq!(summary.reason, Reason::Disallowed);
+    }
+}
@@ -0,0 +1,229 @@
+//! Rotation of connections across a backend host's addresses.  The host is re-resolved
+//! periodically in a background task, rather than for each connection, and successive
+//! connections begin with successive addresses.  An address that fails to connect is
+//! marked unhealthy for a time, during which it is tried only after the healthy addresses.
+
+use crate::backend::resolve;
+use crate::config::RotationConfig;
+use crate::dns::Resolver;
+use crate::error::Result;
+use ipnet::IpNet;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Instant;
+
+/// The current addresses of a host, and the health of each
+pub(crate) struct Rotation {
+    config: RotationConfig,
+    state: Mutex<State>,
+    refreshing: AtomicBool,
+}
+
+#[derive(Default)]
+struct State {
+    addrs: Vec<SocketAddr>,
+    /// The index in `addrs` of the address with which to begin the next connection
+    next: usize,
+    /// When each address most recently failed to connect
+    failed: HashMap<IpAddr, Instant>,
+}
+
+impl Rotation {
+    pub(crate) fn new(config: RotationConfig) -> Self {
+        Self {
+            config,
+            state: Mutex::new(State::default()),
+            refreshing: AtomicBool::new(false),
+        }
+    }
+
+    /// The addresses to try for the next connection to `host`:`port`, healthy addresses
+    /// first, beginning with the next in turn.  The first call resolves the host and starts
+    /// re-resolving it every `refresh` in a background task.
+    pub(crate) async fn addresses(
+        self: &Arc<Self>,
+        resolver: &Arc<Resolver>,
+        host: &str,
+        port: u16,
+        blocked: &[IpNet],
+    ) -> Result<Vec<SocketAddr>> {
+        if !self.refreshing.swap(true, Ordering::SeqCst) {
+            if let Err(e) = self.refresh(resolver, host, port, blocked).await {
+                self.refreshing.store(false, Ordering::SeqCst);
+                return Err(e);
+            }
+            self.spawn_refresh(resolver.clone(), host.to_owned(), port, blocked.to_vec());
+        }
+        let addrs = self.order(Instant::now());
+        if addrs.is_empty() {
+            // another connection is making the first resolution
+            return resolve(resolver, host, port, blocked).await;
+        }
+        Ok(addrs)
+    }
+
+    /// Mark an address as having failed to connect
+    pub(crate) fn failed(&self, addr: SocketAddr) {
+        log::debug!("marking backend address {} unhealthy", addr.ip());
+        let mut state = self.state.lock().unwrap();
+        state.failed.insert(addr.ip(), Instant::now());
+    }
+
+    /// Mark an address as having connected
+    pub(crate) fn succeeded(&self, addr: SocketAddr) {
+        self.state.lock().unwrap().failed.remove(&addr.ip());
+    }
+
+    /// The addresses in the order to try them at `now`, advancing the rotation
+    fn order(&self, now: Instant) -> Vec<SocketAddr> {
+        let mut state = self.state.lock().unwrap();
+        let len = state.addrs.len();
+        if len == 0 {
+            return vec![];
+        }
+        let start = state.next % len;
+        state.next = (start + 1) % len;
+        let mut addrs = state.addrs.clone();
+        addrs.rotate_left(start);
+        let healthy = |addr: &SocketAddr| {
+            state
+                .failed
+                .get(&addr.ip())
+                .is_none_or(|failed| now.duration_since(*failed) >= self.config.unhealthy)
+        };
+        let (mut healthy, unhealthy): (Vec<_>, Vec<_>) = addrs.into_iter().partition(healthy);
+        healthy.extend(unhealthy);
+        healthy
+    }
+
+    /// Resolve the host, replacing the current addresses.  Health marks are kept for
+    /// addresses that remain.
+    async fn refresh(
+        &self,
+        resolver: &Resolver,
+        host: &str,
+        port: u16,
+        blocked: &[IpNet],
+    ) -> Result<()> {
+        let addrs = resolve(resolver, host, port, blocked).await?;
+        let mut state = self.state.lock().unwrap();
+        if state.addrs != addrs {
+            log::info!("backend {} now resolves to {:?}", host, addrs);
+        }
+        state
+            .failed
+            .retain(|ip, _| addrs.iter().any(|addr| addr.ip() == *ip));
+        state.addrs = addrs;
+        Ok(())
+    }
+
+    /// Re-resolve the host every `refresh` until the rotation is dropped.  If resolution
+    /// fails, the previous addresses are kept.
+    fn spawn_refresh(
+        self: &Arc<Self>,
+        resolver: Arc<Resolver>,
+        host: String,
+        port: u16,
+        blocked: Vec<IpNet>,
+    ) {
+        let weak: Weak<Self> = Arc::downgrade(self);
+        let refresh = self.config.refresh;
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(refresh);
+            // the first tick completes immediately
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                let rotation = match weak.upgrade() {
+                    Some(rotation) => rotation,
+                    None => return,
+                };
+                if let Err(e) = rotation.refresh(&resolver, &host, port, &blocked).await {
+                    log::warn!("re-resolving backend {}: {}", host, e);
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::DnsConfig;
+    use std::collections::BTreeMap;
+    use std::time::Duration;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    fn new_rotation(unhealthy: Duration) -> Arc<Rotation> {
+        Arc::new(Rotation::new(RotationConfig {
+            refresh: Duration::from_secs(60),
+            unhealthy,
+        }))
+    }
+
+    #[tokio::test]
+    async fn test_addresses() {
+        let ips = ["192.0.2.1", "192.0.2.2", "192.0.2.3"];
+        let config = DnsConfig {
+            hosts: BTreeMap::from([(
+                "api.giphy.com".to_owned(),
+                ips.iter().map(|ip| ip.parse().unwrap()).collect(),
+            )]),
+            ..DnsConfig::default()
+        };
+        let resolver = Arc::new(Resolver::from_config(&config));
+        let rotation = new_rotation(Duration::from_secs(60));
+        let mut firsts = vec![];
+        for _ in 0..4 {
+            let addrs = rotation
+                .addresses(&resolver, "api.giphy.com", 443, &[])
+                .await
+                .unwrap();
+            assert_eq!(addrs.len(), 3);
+            firsts.push(addrs[0]);
+        }
+        assert_eq!(
+            firsts,
+            vec![
+                addr("192.0.2.1:443"),
+                addr("192.0.2.2:443"),
+                addr("192.0.2.3:443"),
+                addr("192.0.2.1:443"),
+            ]
+        );
+
+        // blocked addresses are never used
+        let blocked = ["192.0.2.0/24".parse().unwrap()];
+        assert!(new_rotation(Duration::ZERO)
+            .addresses(&resolver, "api.giphy.com", 443, &blocked)
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn test_health() {
+        let rotation = new_rotation(Duration::from_secs(30));
+        let (a, b, c) = (
+            addr("192.0.2.1:443"),
+            addr("192.0.2.2:443"),
+            addr("192.0.2.3:443"),
+        );
+        rotation.state.lock().unwrap().addrs = vec![a, b, c];
+        rotation.failed(a);
+        rotation.failed(b);
+
+        let now = Instant::now();
+        // unhealthy addresses are tried last
+        assert_eq!(rotation.order(now), vec![c, a, b]);
+        assert_eq!(rotation.order(now), vec![c, b, a]);
+        // until they recover or connect again
+        rotation.succeeded(a);
+        assert_eq!(rotation.order(now), vec![c, a, b]);
+        assert_eq!(rotation.order(now + Duration::from_secs(30)), vec![a, b, c]);
+    }
+}
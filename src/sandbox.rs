@@ -0,0 +1,388 @@
+//! Confining the process once its listen addresses are bound: switching to an unprivileged
+//! user and group, so that privileged ports can be bound as root, and, on Linux, changing
+//! the root directory and installing a seccomp filter.  The proxy handles untrusted input,
+//! so this limits what a compromised process could do.
+//!
+//! The seccomp filter is a deny-list: it refuses, with `EPERM`, system calls the proxy
+//! never needs once running, such as starting programs, tracing processes, creating
+//! namespaces, mounting filesystems, and loading kernel modules, and allows the rest.
+//! System calls made through x86_64's x32 ABI, which the deny-list would not match, kill
+//! the process.
+
+use crate::config::SandboxConfig;
+use anyhow::{bail, Result};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Set once the root directory has been changed or the seccomp filter installed, either of
+/// which prevents the process from starting programs
+static PREVENTS_EXEC: AtomicBool = AtomicBool::new(false);
+
+/// Apply the configured confinement to the whole process.  This must be called after
+/// every listen address is bound and every file the proxy needs has been opened.
+pub fn apply(config: &SandboxConfig) -> Result<()> {
+    imp::apply(config)
+}
+
+/// Whether the confinement applied to the process prevents it from starting programs, such
+/// as a new process for an upgrade
+pub fn prevents_exec() -> bool {
+    PREVENTS_EXEC.load(Ordering::SeqCst)
+}
+
+#[cfg(unix)]
+mod imp {
+    use super::*;
+    use anyhow::Context;
+    use std::ffi::{CStr, CString};
+    use std::io;
+    use std::os::unix::ffi::OsStrExt;
+
+    pub(super) fn apply(config: &SandboxConfig) -> Result<()> {
+        // names are resolved before changing the root directory, which hides /etc/passwd
+        let user = config.user.as_deref().map(user).transpose()?;
+        let gid = match (&config.group, user) {
+            (Some(group_name), _) => Some(group(group_name)?),
+            (None, Some((_, gid))) => Some(gid),
+            (None, None) => None,
+        };
+
+        if let Some(dir) = &config.chroot {
+            let path = CString::new(dir.as_os_str().as_bytes())?;
+            // SAFETY: path is a valid C string
+            check(unsafe { libc::chroot(path.as_ptr()) })
+                .with_context(|| format!("changing root directory to {}", dir.display()))?;
+            std::env::set_current_dir("/")?;
+            PREVENTS_EXEC.store(true, Ordering::SeqCst);
+            log::info!("changed root directory to {}", dir.display());
+        }
+
+        // the groups must be changed while the process still has the privilege to do so;
+        // root's supplementary groups are dropped even if the primary group is unchanged
+        // SAFETY: these calls have no memory-safety requirements
+        if let Some(gid) = gid {
+            if unsafe { libc::geteuid() } == 0 {
+                check(unsafe { libc::setgroups(1, &gid) }).context("setting groups")?;
+            }
+            if unsafe { libc::getgid() } != gid {
+                check(unsafe { libc::setgid(gid) })
+                    .with_context(|| format!("switching to group {}", gid))?;
+            }
+        }
+        if let Some((uid, _)) = user {
+            if unsafe { libc::getuid() } != uid {
+                check(unsafe { libc::setuid(uid) })
+                    .with_context(|| format!("switching to user {}", uid))?;
+            }
+        }
+        if user.is_some() || gid.is_some() {
+            // SAFETY: as above
+            log::info!(
+                "running as user {} and group {}",
+                unsafe { libc::getuid() },
+                unsafe { libc::getgid() }
+            );
+        }
+
+        if config.seccomp {
+            seccomp::install().context("installing seccomp filter")?;
+            PREVENTS_EXEC.store(true, Ordering::SeqCst);
+            log::info!("installed seccomp filter");
+        }
+        Ok(())
+    }
+
+    /// Turn the result of a libc call into an error from `errno`
+    fn check(result: libc::c_int) -> io::Result<()> {
+        if result < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Look up a user by name or numeric ID, returning its ID and primary group
+    fn user(name: &str) -> Result<(libc::uid_t, libc::gid_t)> {
+        let c_name = CString::new(name)?;
+        let mut buf = vec![0 as libc::c_char; 16384];
+        // SAFETY: passwd is plain data, filled in by getpwnam_r or getpwuid_r, whose string
+        // fields point into buf, which outlives them
+        let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+        let mut result = std::ptr::null_mut();
+        let error = match name.parse::<libc::uid_t>() {
+            Ok(uid) => unsafe {
+                libc::getpwuid_r(uid, &mut passwd, buf.as_mut_ptr(), buf.len(), &mut result)
+            },
+            Err(_) => unsafe {
+                libc::getpwnam_r(
+                    c_name.as_ptr(),
+                    &mut passwd,
+                    buf.as_mut_ptr(),
+                    buf.len(),
+                    &mut result,
+                )
+            },
+        };
+        if error != 0 {
+            return Err(io::Error::from_raw_os_error(error))
+                .with_context(|| format!("looking up user {}", name));
+        }
+        if result.is_null() {
+            bail!("no such user {}", name);
+        }
+        Ok((passwd.pw_uid, passwd.pw_gid))
+    }
+
+    /// Look up a group by name or numeric ID, returning its ID
+    fn group(name: &str) -> Result<libc::gid_t> {
+        if let Ok(gid) = name.parse() {
+            return Ok(gid);
+        }
+        let c_name = CString::new(name)?;
+        let mut buf = vec![0 as libc::c_char; 16384];
+        // SAFETY: as for `user`
+        let mut group: libc::group = unsafe { std::mem::zeroed() };
+        let mut result = std::ptr::null_mut();
+        let error = unsafe {
+            libc::getgrnam_r(
+                c_name.as_ptr(),
+                &mut group,
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut result,
+            )
+        };
+        if error != 0 {
+            return Err(io::Error::from_raw_os_error(error))
+                .with_context(|| format!("looking up group {}", name));
+        }
+        if result.is_null() {
+            bail!("no such group {}", name);
+        }
+        // SAFETY: gr_name points into buf
+        log::debug!(
+            "group {:?} has ID {}",
+            unsafe { CStr::from_ptr(group.gr_name) },
+            group.gr_gid
+        );
+        Ok(group.gr_gid)
+    }
+
+    #[cfg(all(
+        target_os = "linux",
+        any(target_arch = "x86_64", target_arch = "aarch64")
+    ))]
+    mod seccomp {
+        use super::check;
+        use std::io;
+
+        /// The `AUDIT_ARCH_*` value for this architecture, which the filter checks so
+        /// that system call numbers are not misinterpreted
+        #[cfg(target_arch = "x86_64")]
+        const AUDIT_ARCH: u32 = 0xc000_003e;
+        #[cfg(target_arch = "aarch64")]
+        const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+        /// The bit set in system call numbers of the x32 ABI, which x86_64 kernels may also
+        /// accept; no system call of this architecture's ABI has it
+        const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+        /// Offsets of the fields of `struct seccomp_data`
+        const OFFSET_NR: u32 = 0;
+        const OFFSET_ARCH: u32 = 4;
+        /// The low 32 bits of the first argument, on these little-endian architectures
+        const OFFSET_ARG0: u32 = 16;
+
+        /// The `clone` flags creating namespaces, which `clone` is refused if given, as
+        /// `unshare` is
+        const CLONE_NAMESPACES: u32 = (libc::CLONE_NEWNS
+            | libc::CLONE_NEWCGROUP
+            | libc::CLONE_NEWUTS
+            | libc::CLONE_NEWIPC
+            | libc::CLONE_NEWUSER
+            | libc::CLONE_NEWPID
+            | libc::CLONE_NEWNET) as u32;
+
+        /// System calls refused once the filter is installed
+        const DENIED: &[libc::c_long] = &[
+            libc::SYS_execve,
+            libc::SYS_execveat,
+            libc::SYS_ptrace,
+            libc::SYS_process_vm_readv,
+            libc::SYS_process_vm_writev,
+            libc::SYS_mount,
+            libc::SYS_umount2,
+            libc::SYS_fsopen,
+            libc::SYS_fsconfig,
+            libc::SYS_fsmount,
+            libc::SYS_fspick,
+            libc::SYS_move_mount,
+            libc::SYS_open_tree,
+            libc::SYS_mount_setattr,
+            libc::SYS_pivot_root,
+            libc::SYS_chroot,
+            libc::SYS_unshare,
+            libc::SYS_setns,
+            libc::SYS_reboot,
+            libc::SYS_kexec_load,
+            libc::SYS_kexec_file_load,
+            libc::SYS_init_module,
+            libc::SYS_finit_module,
+            libc::SYS_delete_module,
+            libc::SYS_swapon,
+            libc::SYS_swapoff,
+            libc::SYS_bpf,
+            libc::SYS_perf_event_open,
+            libc::SYS_userfaultfd,
+            libc::SYS_keyctl,
+            libc::SYS_add_key,
+            libc::SYS_request_key,
+            libc::SYS_personality,
+        ];
+
+        fn statement(code: u32, k: u32) -> libc::sock_filter {
+            jump(code, k, 0, 0)
+        }
+
+        fn jump(code: u32, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+            libc::sock_filter {
+                code: code as u16,
+                jt,
+                jf,
+                k,
+            }
+        }
+
+        /// The BPF program: kill the process if the architecture is not the expected one,
+        /// refuse x32 system calls and the denied calls, and allow everything else.
+        /// `clone3`, whose flags are in memory the filter cannot read, fails with `ENOSYS`
+        /// so that the C library falls back to `clone`, which is refused only if it
+        /// creates namespaces.
+        pub(super) fn program() -> Vec<libc::sock_filter> {
+            use libc::{
+                BPF_ABS, BPF_JEQ, BPF_JGE, BPF_JMP, BPF_JSET, BPF_K, BPF_LD, BPF_RET, BPF_W,
+            };
+            let mut program = vec![
+                statement(BPF_LD | BPF_W | BPF_ABS, OFFSET_ARCH),
+                jump(BPF_JMP | BPF_JEQ | BPF_K, AUDIT_ARCH, 1, 0),
+                statement(BPF_RET | BPF_K, libc::SECCOMP_RET_KILL_PROCESS),
+                statement(BPF_LD | BPF_W | BPF_ABS, OFFSET_NR),
+                // x32 calls share the architecture, so the deny-list would not match them
+                jump(BPF_JMP | BPF_JGE | BPF_K, X32_SYSCALL_BIT, 0, 1),
+                statement(BPF_RET | BPF_K, libc::SECCOMP_RET_KILL_PROCESS),
+            ];
+            for &nr in DENIED {
+                // skip the refusal unless the call matches
+                program.push(jump(BPF_JMP | BPF_JEQ | BPF_K, nr as u32, 0, 1));
+                program.push(statement(
+                    BPF_RET | BPF_K,
+                    libc::SECCOMP_RET_ERRNO | libc::EPERM as u32,
+                ));
+            }
+            program.extend([
+                jump(BPF_JMP | BPF_JEQ | BPF_K, libc::SYS_clone3 as u32, 0, 1),
+                statement(
+                    BPF_RET | BPF_K,
+                    libc::SECCOMP_RET_ERRNO | libc::ENOSYS as u32,
+                ),
+                // the flags are only loaded for clone, which then returns either way
+                jump(BPF_JMP | BPF_JEQ | BPF_K, libc::SYS_clone as u32, 0, 3),
+                statement(BPF_LD | BPF_W | BPF_ABS, OFFSET_ARG0),
+                jump(BPF_JMP | BPF_JSET | BPF_K, CLONE_NAMESPACES, 0, 1),
+                statement(
+                    BPF_RET | BPF_K,
+                    libc::SECCOMP_RET_ERRNO | libc::EPERM as u32,
+                ),
+                statement(BPF_RET | BPF_K, libc::SECCOMP_RET_ALLOW),
+            ]);
+            program
+        }
+
+        /// Install the filter on every thread of the process.  The calling thread's
+        /// `no_new_privs` flag, which unprivileged processes need to install a filter, is
+        /// set on the others as they are synchronized.
+        pub(super) fn install() -> io::Result<()> {
+            let mut program = program();
+            let fprog = libc::sock_fprog {
+                len: program.len() as libc::c_ushort,
+                filter: program.as_mut_ptr(),
+            };
+            // SAFETY: fprog points to a valid program, which the kernel copies
+            unsafe {
+                check(libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0))?;
+                let result = libc::syscall(
+                    libc::SYS_seccomp,
+                    libc::SECCOMP_SET_MODE_FILTER,
+                    libc::SECCOMP_FILTER_FLAG_TSYNC,
+                    &fprog as *const libc::sock_fprog,
+                );
+                // with TSYNC, a positive result is the ID of a thread that could not be
+                // synchronized
+                match result {
+                    0 => Ok(()),
+                    r if r < 0 => Err(io::Error::last_os_error()),
+                    r => Err(io::Error::other(format!(
+                        "could not synchronize thread {}",
+                        r
+                    ))),
+                }
+            }
+        }
+    }
+
+    #[cfg(not(all(
+        target_os = "linux",
+        any(target_arch = "x86_64", target_arch = "aarch64")
+    )))]
+    mod seccomp {
+        /// seccomp is not supported on this platform
+        pub(super) fn install() -> anyhow::Result<()> {
+            anyhow::bail!("seccomp is only supported on Linux, on x86_64 and aarch64")
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+
+        #[test]
+        fn test_user() {
+            assert_eq!(user("root").unwrap(), (0, 0));
+            assert_eq!(user("0").unwrap().0, 0);
+            assert!(user("no-such-user-giphyproxy").is_err());
+        }
+
+        #[test]
+        fn test_group() {
+            assert_eq!(group("0").unwrap(), 0);
+            assert_eq!(group("root").unwrap(), 0);
+            assert!(group("no-such-group-giphyproxy").is_err());
+        }
+
+        #[cfg(all(
+            target_os = "linux",
+            any(target_arch = "x86_64", target_arch = "aarch64")
+        ))]
+        #[test]
+        fn test_seccomp_program() {
+            let program = seccomp::program();
+            // the architecture and x32 checks, a test and refusal for each call, the clone3
+            // and clone checks, and the final allow
+            assert_eq!(program.len(), 6 + 2 * 33 + 6 + 1);
+            assert_eq!(program[4].k, 0x4000_0000);
+            assert_eq!(program[5].k, libc::SECCOMP_RET_KILL_PROCESS);
+            assert_eq!(program.last().unwrap().k, libc::SECCOMP_RET_ALLOW);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod imp {
+    use super::*;
+
+    pub(super) fn apply(config: &SandboxConfig) -> Result<()> {
+        if *config != SandboxConfig::default() {
+            bail!("sandbox options are not supported on this platform");
+        }
+        Ok(())
+    }
+}
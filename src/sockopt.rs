@@ -0,0 +1,149 @@
+//! Applying the configured TCP options to listening, accepted, and outgoing sockets.
+//! Buffer sizes are set before a socket listens or connects, so that they are reflected
+//! in the TCP window scale negotiated with the peer; sockets accepted from a listener
+//! inherit them.  Outgoing sockets are also bound to the configured local address and
+//! interface before connecting.
+
+use crate::config::SocketConfig;
+use socket2::{SockRef, TcpKeepalive};
+use std::io;
+use std::net::SocketAddr;
+use tokio::net::{TcpSocket, TcpStream};
+
+/// Create an unconnected socket for `addr`'s address family, with the configured buffer
+/// sizes
+pub(crate) fn socket(addr: SocketAddr, config: &SocketConfig) -> io::Result<TcpSocket> {
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    if let Some(size) = config.send_buffer_size {
+        socket.set_send_buffer_size(size)?;
+    }
+    if let Some(size) = config.recv_buffer_size {
+        socket.set_recv_buffer_size(size)?;
+    }
+    Ok(socket)
+}
+
+/// Connect to `addr` with the configured options
+pub(crate) async fn connect(addr: SocketAddr, config: SocketConfig) -> io::Result<TcpStream> {
+    let socket = socket(addr, &config)?;
+    bind(&socket, addr, &config)?;
+    let stream = socket.connect(addr).await?;
+    configure(&stream, &config)?;
+    Ok(stream)
+}
+
+/// Bind an outgoing socket to the configured local address and interface, if any
+fn bind(socket: &TcpSocket, addr: SocketAddr, config: &SocketConfig) -> io::Result<()> {
+    if let Some(device) = &config.bind_device {
+        #[cfg(target_os = "linux")]
+        SockRef::from(socket).bind_device(Some(device.as_bytes()))?;
+        #[cfg(not(target_os = "linux"))]
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!(
+                "cannot bind to device {}: not supported on this platform",
+                device
+            ),
+        ));
+    }
+    if let Some(local) = config.bind_address {
+        if local.is_ipv4() != addr.is_ipv4() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                format!("cannot reach {} from bind address {}", addr, local),
+            ));
+        }
+        socket.bind(SocketAddr::new(local, 0))?;
+    }
+    Ok(())
+}
+
+/// Set the configured `TCP_NODELAY` and keepalive options on a connected socket
+pub(crate) fn configure(stream: &TcpStream, config: &SocketConfig) -> io::Result<()> {
+    if config.nodelay {
+        stream.set_nodelay(true)?;
+    }
+    if let Some(keepalive) = &config.keepalive {
+        let params = TcpKeepalive::new().with_time(keepalive.time);
+        #[cfg(any(target_os = "linux", target_os = "macos", target_os = "freebsd"))]
+        let params = params
+            .with_interval(keepalive.interval)
+            .with_retries(keepalive.retries);
+        SockRef::from(stream).set_tcp_keepalive(&params)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::KeepaliveConfig;
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_connect() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = SocketConfig {
+            nodelay: true,
+            keepalive: Some(KeepaliveConfig {
+                time: Duration::from_secs(30),
+                interval: Duration::from_secs(5),
+                retries: 3,
+            }),
+            send_buffer_size: Some(65536),
+            recv_buffer_size: Some(65536),
+            ..SocketConfig::default()
+        };
+        let stream = connect(listener.local_addr().unwrap(), config)
+            .await
+            .unwrap();
+
+        assert!(stream.nodelay().unwrap());
+        let sock = SockRef::from(&stream);
+        assert!(sock.keepalive().unwrap());
+        #[cfg(target_os = "linux")]
+        {
+            assert_eq!(sock.tcp_keepalive_time().unwrap(), Duration::from_secs(30));
+            assert_eq!(
+                sock.tcp_keepalive_interval().unwrap(),
+                Duration::from_secs(5)
+            );
+            assert_eq!(sock.tcp_keepalive_retries().unwrap(), 3);
+        }
+        // the kernel may adjust the size, e.g. Linux doubles it for bookkeeping overhead
+        assert!(sock.send_buffer_size().unwrap() >= 65536);
+    }
+
+    #[tokio::test]
+    async fn test_defaults() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = connect(listener.local_addr().unwrap(), SocketConfig::default())
+            .await
+            .unwrap();
+        assert!(!stream.nodelay().unwrap());
+        assert!(!SockRef::from(&stream).keepalive().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_bind() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = SocketConfig {
+            bind_address: Some("127.0.0.1".parse().unwrap()),
+            #[cfg(target_os = "linux")]
+            bind_device: Some("lo".into()),
+            ..SocketConfig::default()
+        };
+        let stream = connect(addr, config.clone()).await.unwrap();
+        assert_eq!(stream.local_addr().unwrap().ip(), addr.ip());
+
+        // an IPv4 bind address cannot reach IPv6 destinations
+        let v6 = SocketAddr::new("::1".parse().unwrap(), addr.port());
+        let err = connect(v6, config).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AddrNotAvailable);
+    }
+}
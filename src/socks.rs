@@ -0,0 +1,415 @@
+//! The server side of the SOCKS5 protocol ([RFC 1928](https://tools.ietf.org/html/rfc1928)),
+//! with optional username/password authentication
+//! ([RFC 1929](https://tools.ietf.org/html/rfc1929)).  The CONNECT command is supported,
+//! and UDP ASSOCIATE if it is enabled, in which case datagrams are relayed by the `udp`
+//! module.
+
+use crate::access::{AccessRecord, Reason};
+use crate::auth::{constant_time_eq, Htpasswd};
+use crate::config::SocksConfig;
+use crate::http::authority;
+use anyhow::{bail, Context, Result};
+use std::future::Future;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// The SOCKS protocol version, which is also the first byte a SOCKS5 client sends
+pub const VERSION: u8 = 5;
+
+/// Authentication methods
+const METHOD_NO_AUTH: u8 = 0x00;
+const METHOD_USERNAME_PASSWORD: u8 = 0x02;
+const METHOD_NONE_ACCEPTABLE: u8 = 0xff;
+
+/// Version of the username/password subnegotiation
+const AUTH_VERSION: u8 = 1;
+
+const CMD_CONNECT: u8 = 1;
+const CMD_UDP_ASSOCIATE: u8 = 3;
+
+pub(crate) const ATYP_IPV4: u8 = 1;
+pub(crate) const ATYP_DOMAIN: u8 = 3;
+pub(crate) const ATYP_IPV6: u8 = 4;
+
+/// The commands a client may request
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Command {
+    /// Open a TCP tunnel to the requested host and port
+    Connect,
+    /// Relay UDP datagrams; the requested host and port are those from which the client
+    /// will send them, or zero if it does not know
+    UdpAssociate,
+}
+
+/// Reply codes sent in response to a request
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum Reply {
+    Succeeded = 0,
+    GeneralFailure = 1,
+    NotAllowed = 2,
+    HostUnreachable = 4,
+    CommandNotSupported = 7,
+    AddressTypeNotSupported = 8,
+}
+
+/// Send a reply to the client's request.  The bound address is always reported as
+/// `0.0.0.0:0`, as clients of a CONNECT tunnel have no use for it.
+pub async fn send_reply<S: AsyncWrite + Unpin>(socket: &mut S, reply: Reply) -> Result<()> {
+    if reply != Reply::Succeeded {
+        log::debug!("SOCKS reply {:?}", reply);
+    }
+    socket
+        .write_all(&[VERSION, reply as u8, 0, ATYP_IPV4, 0, 0, 0, 0, 0, 0])
+        .await?;
+    Ok(())
+}
+
+/// Send a successful reply giving the address the proxy has bound for the client, such as
+/// the relay address for a UDP ASSOCIATE.
+pub async fn send_bound_reply<S: AsyncWrite + Unpin>(
+    socket: &mut S,
+    bound: SocketAddr,
+) -> Result<()> {
+    let mut reply = vec![VERSION, Reply::Succeeded as u8, 0];
+    reply.extend_from_slice(&encode_addr(bound));
+    socket.write_all(&reply).await?;
+    Ok(())
+}
+
+/// Encode an address as its type, the address, and the port, as in replies and in the
+/// header of each relayed datagram
+pub(crate) fn encode_addr(addr: SocketAddr) -> Vec<u8> {
+    let mut buf = vec![];
+    match addr {
+        SocketAddr::V4(v4) => {
+            buf.push(ATYP_IPV4);
+            buf.extend_from_slice(&v4.ip().octets());
+        }
+        SocketAddr::V6(v6) => {
+            buf.push(ATYP_IPV6);
+            buf.extend_from_slice(&v6.ip().octets());
+        }
+    }
+    buf.extend_from_slice(&addr.port().to_be_bytes());
+    buf
+}
+
+/// Perform the SOCKS5 handshake, up to and including reading the client's request.
+/// Returns the requested command, host, and port, or replies with an error and fails,
+/// recording the reason in `record`.  The caller must send the final reply with
+/// `send_reply` or `send_bound_reply`.
+///
+/// Clients must authenticate if `config` has credentials, which take precedence, or if
+/// `htpasswd` is given.
+pub async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(
+    socket: &mut S,
+    config: &SocksConfig,
+    htpasswd: Option<&Htpasswd>,
+    record: &mut AccessRecord,
+) -> Result<(Command, String, u16)> {
+    let require_auth = config.username.is_some() || htpasswd.is_some();
+    record.reason = Reason::BadRequest;
+    negotiate_method(socket, require_auth).await?;
+    if require_auth {
+        record.reason = Reason::AuthFailed;
+        let user = authenticate(socket, |user, password| async move {
+            match (&config.username, htpasswd) {
+                (Some(username), _) => {
+                    user == *username
+                        && config
+                            .password
+                            .as_ref()
+                            .is_some_and(|p| constant_time_eq(password.as_bytes(), p.as_bytes()))
+                }
+                (None, Some(htpasswd)) => htpasswd.verify(&user, &password).await,
+                (None, None) => false,
+            }
+        })
+        .await?;
+        record.user = Some(user);
+    }
+    record.reason = Reason::BadRequest;
+    let (command, host, port) = read_request(socket, config.udp.is_some()).await?;
+    log::debug!("got SOCKS {:?} for {}", command, authority(&host, port));
+    Ok((command, host, port))
+}
+
+/// Read the client's greeting and select an authentication method: username/password if
+/// authentication is required, and otherwise no authentication.
+async fn negotiate_method<S: AsyncRead + AsyncWrite + Unpin>(
+    socket: &mut S,
+    require_auth: bool,
+) -> Result<()> {
+    let mut header = [0u8; 2];
+    socket
+        .read_exact(&mut header)
+        .await
+        .context("reading SOCKS greeting")?;
+    if header[0] != VERSION {
+        bail!("unsupported SOCKS version {}", header[0]);
+    }
+    let mut methods = vec![0u8; header[1] as usize];
+    socket
+        .read_exact(&mut methods)
+        .await
+        .context("reading SOCKS greeting")?;
+
+    let wanted = if require_auth {
+        METHOD_USERNAME_PASSWORD
+    } else {
+        METHOD_NO_AUTH
+    };
+    if !methods.contains(&wanted) {
+        let _ = socket.write_all(&[VERSION, METHOD_NONE_ACCEPTABLE]).await;
+        bail!("client offered no acceptable SOCKS authentication method");
+    }
+    socket.write_all(&[VERSION, wanted]).await?;
+    Ok(())
+}
+
+/// Perform username/password authentication, checking the credentials with `verify`.
+/// Returns the authenticated username.
+async fn authenticate<S, V, F>(socket: &mut S, verify: V) -> Result<String>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    V: FnOnce(String, String) -> F,
+    F: Future<Output = bool>,
+{
+    let version = socket
+        .read_u8()
+        .await
+        .context("reading SOCKS credentials")?;
+    if version != AUTH_VERSION {
+        bail!("unsupported SOCKS authentication version {}", version);
+    }
+    let given_username = read_string(socket)
+        .await
+        .context("reading SOCKS credentials")?;
+    let given_password = read_string(socket)
+        .await
+        .context("reading SOCKS credentials")?;
+
+    let given_username = String::from_utf8_lossy(&given_username).into_owned();
+    let given_password = String::from_utf8_lossy(&given_password).into_owned();
+
+    if !verify(given_username.clone(), given_password).await {
+        let _ = socket.write_all(&[AUTH_VERSION, 1]).await;
+        bail!("invalid SOCKS credentials for {:?}", given_username);
+    }
+    socket.write_all(&[AUTH_VERSION, 0]).await?;
+    Ok(given_username)
+}
+
+/// Read the client's request, returning its command, host, and port.  UDP ASSOCIATE is
+/// refused unless `udp` is set.
+async fn read_request<S: AsyncRead + AsyncWrite + Unpin>(
+    socket: &mut S,
+    udp: bool,
+) -> Result<(Command, String, u16)> {
+    let mut header = [0u8; 4];
+    socket
+        .read_exact(&mut header)
+        .await
+        .context("reading SOCKS request")?;
+    let [version, command, _, atyp] = header;
+    if version != VERSION {
+        bail!("unsupported SOCKS version {}", version);
+    }
+    let command = match command {
+        CMD_CONNECT => Command::Connect,
+        CMD_UDP_ASSOCIATE if udp => Command::UdpAssociate,
+        _ => {
+            let _ = send_reply(socket, Reply::CommandNotSupported).await;
+            bail!("unsupported SOCKS command {}", command);
+        }
+    };
+
+    let host = match atyp {
+        ATYP_IPV4 => {
+            let mut addr = [0u8; 4];
+            socket.read_exact(&mut addr).await?;
+            Ipv4Addr::from(addr).to_string()
+        }
+        ATYP_IPV6 => {
+            let mut addr = [0u8; 16];
+            socket.read_exact(&mut addr).await?;
+            Ipv6Addr::from(addr).to_string()
+        }
+        ATYP_DOMAIN => {
+            let name = read_string(socket).await.context("reading SOCKS request")?;
+            match String::from_utf8(name) {
+                Ok(name) => name,
+                Err(_) => {
+                    let _ = send_reply(socket, Reply::GeneralFailure).await;
+                    bail!("SOCKS domain name is not valid UTF-8");
+                }
+            }
+        }
+        _ => {
+            let _ = send_reply(socket, Reply::AddressTypeNotSupported).await;
+            bail!("unsupported SOCKS address type {}", atyp);
+        }
+    };
+    let port = socket.read_u16().await.context("reading SOCKS request")?;
+
+    Ok((command, host, port))
+}
+
+/// Read a string prefixed with a one-byte length
+async fn read_string<S: AsyncRead + Unpin>(socket: &mut S) -> Result<Vec<u8>> {
+    let len = socket.read_u8().await?;
+    let mut buf = vec![0u8; len as usize];
+    socket.read_exact(&mut buf).await?;
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::duplex;
+
+    fn with_credentials() -> SocksConfig {
+        SocksConfig {
+            username: Some("user".into()),
+            password: Some("pass".into()),
+            ..SocksConfig::default()
+        }
+    }
+
+    /// Run the handshake against the given client bytes, returning the result, the
+    /// access record, and everything the server wrote.
+    async fn run(
+        config: SocksConfig,
+        input: &'static [u8],
+    ) -> (Result<(Command, String, u16)>, AccessRecord, Vec<u8>) {
+        let (mut client, mut server) = duplex(1024);
+        client.write_all(input).await.unwrap();
+        let mut record = AccessRecord::new(None);
+        let result = handshake(&mut server, &config, None, &mut record).await;
+        drop(server);
+        let mut output = vec![];
+        client.read_to_end(&mut output).await.unwrap();
+        (result, record, output)
+    }
+
+    #[tokio::test]
+    async fn test_no_auth_domain() {
+        let (result, _, output) = run(
+            SocksConfig::default(),
+            b"\x05\x01\x00\x05\x01\x00\x03\x0dapi.giphy.com\x01\xbb",
+        )
+        .await;
+        assert_eq!(
+            result.unwrap(),
+            (Command::Connect, "api.giphy.com".into(), 443)
+        );
+        assert_eq!(output, b"\x05\x00");
+    }
+
+    #[tokio::test]
+    async fn test_ipv4_and_ipv6() {
+        let (result, _, _) = run(
+            SocksConfig::default(),
+            b"\x05\x01\x00\x05\x01\x00\x01\x0a\x00\x00\x01\x00\x50",
+        )
+        .await;
+        assert_eq!(result.unwrap(), (Command::Connect, "10.0.0.1".into(), 80));
+
+        let (result, _, _) = run(
+            SocksConfig::default(),
+            b"\x05\x01\x00\x05\x01\x00\x04\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\x01\x00\x50",
+        )
+        .await;
+        assert_eq!(result.unwrap(), (Command::Connect, "::1".into(), 80));
+    }
+
+    #[tokio::test]
+    async fn test_no_acceptable_method() {
+        let (result, record, output) = run(with_credentials(), b"\x05\x01\x00").await;
+        assert!(result.is_err());
+        assert_eq!(record.reason, Reason::BadRequest);
+        assert_eq!(output, b"\x05\xff");
+    }
+
+    #[tokio::test]
+    async fn test_auth() {
+        let (result, record, output) = run(
+            with_credentials(),
+            b"\x05\x02\x00\x02\x01\x04user\x04pass\x05\x01\x00\x03\x03foo\x00\x50",
+        )
+        .await;
+        assert_eq!(result.unwrap(), (Command::Connect, "foo".into(), 80));
+        assert_eq!(record.user.as_deref(), Some("user"));
+        assert_eq!(output, b"\x05\x02\x01\x00");
+    }
+
+    #[tokio::test]
+    async fn test_auth_failed() {
+        let (result, record, output) =
+            run(with_credentials(), b"\x05\x01\x02\x01\x04user\x05wrong").await;
+        assert!(result.is_err());
+        assert_eq!(record.reason, Reason::AuthFailed);
+        assert_eq!(output, b"\x05\x02\x01\x01");
+    }
+
+    #[tokio::test]
+    async fn test_auth_htpasswd() {
+        let htpasswd = Htpasswd::parse("pat:secret\n").unwrap();
+        let (mut client, mut server) = duplex(1024);
+        client
+            .write_all(b"\x05\x01\x02\x01\x03pat\x06secret\x05\x01\x00\x03\x03foo\x00\x50")
+            .await
+            .unwrap();
+        let mut record = AccessRecord::new(None);
+        let result = handshake(
+            &mut server,
+            &SocksConfig::default(),
+            Some(&htpasswd),
+            &mut record,
+        )
+        .await;
+        assert_eq!(result.unwrap(), (Command::Connect, "foo".into(), 80));
+        assert_eq!(record.user.as_deref(), Some("pat"));
+    }
+
+    #[tokio::test]
+    async fn test_unsupported_command() {
+        // BIND
+        let (result, _, output) = run(
+            SocksConfig::default(),
+            b"\x05\x01\x00\x05\x02\x00\x03\x03foo\x00\x50",
+        )
+        .await;
+        assert!(result.is_err());
+        assert_eq!(output, b"\x05\x00\x05\x07\x00\x01\0\0\0\0\0\0");
+    }
+
+    #[tokio::test]
+    async fn test_udp_associate() {
+        let request = b"\x05\x01\x00\x05\x03\x00\x01\0\0\0\0\0\0";
+        // refused unless UDP is enabled
+        let (result, _, output) = run(SocksConfig::default(), request).await;
+        assert!(result.is_err());
+        assert_eq!(output, b"\x05\x00\x05\x07\x00\x01\0\0\0\0\0\0");
+
+        let config = SocksConfig {
+            udp: Some(Default::default()),
+            ..SocksConfig::default()
+        };
+        let (result, _, _) = run(config, request).await;
+        assert_eq!(
+            result.unwrap(),
+            (Command::UdpAssociate, "0.0.0.0".into(), 0)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_send_bound_reply() {
+        let mut output = vec![];
+        send_bound_reply(&mut output, "192.0.2.1:5353".parse().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(output, b"\x05\x00\x00\x01\xc0\x00\x02\x01\x14\xe9");
+    }
+}
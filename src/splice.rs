@@ -0,0 +1,203 @@
+//! A zero-copy fast path for tunnels between two TCP sockets on Linux.  Data moves from
+//! each socket to the other through a pipe with `splice(2)`, so it is never copied into
+//! userspace.
+
+use crate::config::LimitsConfig;
+use crate::connection::{run_tunnel, ConnectionSummary, Direction, TunnelState};
+use crate::error::{IoContext, Result};
+use crate::registry::Registration;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use tokio::io::Interest;
+use tokio::net::TcpStream;
+
+/// Proxy data bidirectionally between two TCP sockets with `splice`, behaving otherwise
+/// like `bidirectional_proxy`.
+pub(crate) async fn proxy(
+    client: &TcpStream,
+    backend: &TcpStream,
+    limits: &LimitsConfig,
+    tunnel: &Registration,
+) -> ConnectionSummary {
+    let state = &tunnel.state;
+    let up = splice_all(
+        client,
+        "client socket",
+        backend,
+        "backend socket",
+        limits.tunnel_buffer_size,
+        state,
+        Direction::Up,
+    );
+    let down = splice_all(
+        backend,
+        "backend socket",
+        client,
+        "client socket",
+        limits.tunnel_buffer_size,
+        state,
+        Direction::Down,
+    );
+    run_tunnel(tunnel, up, down, limits.idle_timeout).await
+}
+
+/// A non-blocking pipe
+struct Pipe {
+    read: OwnedFd,
+    write: OwnedFd,
+}
+
+impl Pipe {
+    /// Create a pipe, asking the kernel for a buffer of `size` bytes
+    fn new(size: usize) -> io::Result<Self> {
+        let mut fds = [0; 2];
+        // SAFETY: fds has room for the two descriptors pipe2 writes
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: pipe2 succeeded, so both descriptors are open and owned by nothing else
+        let pipe = unsafe {
+            Self {
+                read: OwnedFd::from_raw_fd(fds[0]),
+                write: OwnedFd::from_raw_fd(fds[1]),
+            }
+        };
+        // if this fails (for example, if size exceeds /proc/sys/fs/pipe-max-size), the pipe
+        // keeps its default size, which only affects performance
+        let size = size.min(libc::c_int::MAX as usize) as libc::c_int;
+        // SAFETY: the descriptor is open, and F_SETPIPE_SZ takes an int argument
+        unsafe { libc::fcntl(pipe.write.as_raw_fd(), libc::F_SETPIPE_SZ, size) };
+        Ok(pipe)
+    }
+}
+
+/// Move up to `len` bytes from one descriptor to another, one of which must be a pipe,
+/// without blocking
+fn splice(from: RawFd, to: RawFd, len: usize) -> io::Result<usize> {
+    // SAFETY: null offsets are permitted, and mean the descriptors' current positions
+    let n = unsafe {
+        libc::splice(
+            from,
+            std::ptr::null_mut(),
+            to,
+            std::ptr::null_mut(),
+            len,
+            libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK,
+        )
+    };
+    if n < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(n as usize)
+    }
+}
+
+/// Move data from `read` to `write` through a pipe of `pipe_size` bytes until `read` is
+/// closed, then shut down `write` for writing.  The pipe is always drained before it is
+/// filled again, so only the sockets can make a splice wait.
+async fn splice_all(
+    read: &TcpStream,
+    read_name: &'static str,
+    write: &TcpStream,
+    write_name: &'static str,
+    pipe_size: usize,
+    state: &TunnelState,
+    direction: Direction,
+) -> Result<()> {
+    let pipe = Pipe::new(pipe_size).io_context("creating pipe")?;
+    loop {
+        let n = loop {
+            read.readable()
+                .await
+                .with_io_context(|| format!("reading from {}", read_name))?;
+            let result = read.try_io(Interest::READABLE, || {
+                splice(read.as_raw_fd(), pipe.write.as_raw_fd(), pipe_size)
+            });
+            match result {
+                Ok(n) => break n,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(e).with_io_context(|| format!("reading from {}", read_name)),
+            }
+        };
+        if n == 0 {
+            // as in `copy`, an error here suggests the socket is already closed
+            // SAFETY: the descriptor is open for as long as `write` is borrowed
+            unsafe { libc::shutdown(write.as_raw_fd(), libc::SHUT_WR) };
+            return Ok(());
+        }
+        state.touch();
+
+        let mut remaining = n;
+        while remaining > 0 {
+            write
+                .writable()
+                .await
+                .with_io_context(|| format!("writing to {}", write_name))?;
+            let result = write.try_io(Interest::WRITABLE, || {
+                splice(pipe.read.as_raw_fd(), write.as_raw_fd(), remaining)
+            });
+            match result {
+                Ok(n) => {
+                    remaining -= n;
+                    state.transferred(direction, n as u64);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(e).with_io_context(|| format!("writing to {}", write_name)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::access::{AccessRecord, Reason};
+    use crate::registry::REGISTRY;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// A connected pair of TCP sockets
+    async fn socket_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (connected, accepted) = tokio::join!(TcpStream::connect(addr), listener.accept());
+        (connected.unwrap(), accepted.unwrap().0)
+    }
+
+    #[tokio::test]
+    async fn test_proxy() {
+        let (mut client, client_end) = socket_pair().await;
+        let (backend_end, mut backend) = socket_pair().await;
+        let limits = LimitsConfig {
+            tunnel_buffer_size: 4096,
+            ..LimitsConfig::default()
+        };
+        let proxy = tokio::spawn(async move {
+            let registration = REGISTRY.register(&AccessRecord::new(None));
+            proxy(&client_end, &backend_end, &limits, &registration).await
+        });
+
+        // the client sends a large request and half-closes its socket, after which the
+        // backend can still respond
+        let request: Vec<u8> = (0..1_000_000u32).map(|i| i as u8).collect();
+        let expected = request.clone();
+        let client_task = tokio::spawn(async move {
+            client.write_all(&request).await.unwrap();
+            client.shutdown().await.unwrap();
+            let mut buf = vec![];
+            client.read_to_end(&mut buf).await.unwrap();
+            buf
+        });
+        let mut buf = vec![];
+        backend.read_to_end(&mut buf).await.unwrap();
+        assert!(buf == expected);
+        backend.write_all(b"response").await.unwrap();
+        drop(backend);
+
+        assert_eq!(client_task.await.unwrap(), b"response");
+        let tunnel = proxy.await.unwrap();
+        assert_eq!(tunnel.bytes_up, 1_000_000);
+        assert_eq!(tunnel.bytes_down, 8);
+        assert_eq!(tunnel.reason, Reason::ClientClosed);
+    }
+}
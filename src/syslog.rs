@@ -0,0 +1,197 @@
+//! Sending log records to a syslog daemon, as RFC 5424 messages over UDP or a unix
+//! datagram socket such as `/dev/log`.
+
+use crate::config::SyslogConfig;
+use anyhow::{Context, Result};
+use std::io::{self, Write};
+use std::net::UdpSocket;
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+use std::sync::Arc;
+use time::OffsetDateTime;
+use tracing::{Level, Metadata};
+use tracing_subscriber::fmt::MakeWriter;
+
+/// The socket over which messages are sent
+enum Transport {
+    Udp(UdpSocket),
+    #[cfg(unix)]
+    Unix(UnixDatagram),
+}
+
+struct Inner {
+    transport: Transport,
+    facility: u8,
+    app_name: String,
+    pid: u32,
+}
+
+/// A `MakeWriter` for `tracing_subscriber::fmt`, sending each formatted event to the
+/// syslog daemon as one message with the event's severity
+#[derive(Clone)]
+pub(crate) struct SyslogWriter(Arc<Inner>);
+
+impl SyslogWriter {
+    /// Connect to the daemon at the configured address
+    pub(crate) fn new(config: &SyslogConfig) -> Result<Self> {
+        let transport = match config.address.strip_prefix("udp://") {
+            Some(addr) => {
+                let socket = UdpSocket::bind("0.0.0.0:0").context("binding syslog socket")?;
+                socket
+                    .connect(addr)
+                    .with_context(|| format!("connecting to syslog at {}", addr))?;
+                Transport::Udp(socket)
+            }
+            #[cfg(unix)]
+            None => {
+                let socket = UnixDatagram::unbound().context("creating syslog socket")?;
+                socket
+                    .connect(&config.address)
+                    .with_context(|| format!("connecting to syslog at {}", config.address))?;
+                Transport::Unix(socket)
+            }
+            #[cfg(not(unix))]
+            None => anyhow::bail!("syslog address must begin with udp:// on this platform"),
+        };
+        Ok(Self(Arc::new(Inner {
+            transport,
+            facility: config.facility.code(),
+            app_name: config.app_name.clone(),
+            pid: std::process::id(),
+        })))
+    }
+}
+
+impl<'a> MakeWriter<'a> for SyslogWriter {
+    type Writer = Message<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        Message {
+            syslog: &self.0,
+            severity: 6,
+            buf: vec![],
+        }
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        let severity = match *meta.level() {
+            Level::ERROR => 3,
+            Level::WARN => 4,
+            Level::INFO => 6,
+            Level::DEBUG | Level::TRACE => 7,
+        };
+        Message {
+            syslog: &self.0,
+            severity,
+            buf: vec![],
+        }
+    }
+}
+
+/// A single message, sent when dropped
+pub(crate) struct Message<'a> {
+    syslog: &'a Inner,
+    severity: u8,
+    buf: Vec<u8>,
+}
+
+impl Message<'_> {
+    /// Format the message, with a header giving its priority, the time, and the app name
+    /// and process ID.  The hostname is left for the daemon to fill in.
+    fn to_bytes(&self, time: OffsetDateTime) -> Vec<u8> {
+        let header = format!(
+            "<{}>1 {:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:06}Z - {} {} - - ",
+            self.syslog.facility * 8 + self.severity,
+            time.year(),
+            time.month() as u8,
+            time.day(),
+            time.hour(),
+            time.minute(),
+            time.second(),
+            time.microsecond(),
+            self.syslog.app_name,
+            self.syslog.pid,
+        );
+        let mut message = header.into_bytes();
+        message.extend_from_slice(self.buf.trim_ascii_end());
+        message
+    }
+}
+
+impl Write for Message<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for Message<'_> {
+    fn drop(&mut self) {
+        if self.buf.is_empty() {
+            return;
+        }
+        let message = self.to_bytes(OffsetDateTime::now_utc());
+        // there is nowhere to report a failure to log
+        let _ = match &self.syslog.transport {
+            Transport::Udp(socket) => socket.send(&message),
+            #[cfg(unix)]
+            Transport::Unix(socket) => socket.send(&message),
+        };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::SyslogFacility;
+
+    #[test]
+    fn test_message_format() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let writer = SyslogWriter::new(&SyslogConfig {
+            address: format!("udp://{}", receiver.local_addr().unwrap()),
+            facility: SyslogFacility::Local3,
+            app_name: "proxy".into(),
+        })
+        .unwrap();
+
+        let mut message = writer.make_writer();
+        message.severity = 4;
+        message.write_all(b"something happened\n").unwrap();
+        let time = OffsetDateTime::from_unix_timestamp(971186136).unwrap();
+        assert_eq!(
+            String::from_utf8(message.to_bytes(time)).unwrap(),
+            format!(
+                "<156>1 2000-10-10T13:55:36.000000Z - proxy {} - - something happened",
+                std::process::id()
+            )
+        );
+    }
+
+    #[test]
+    fn test_send() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let writer = SyslogWriter::new(&SyslogConfig {
+            address: format!("udp://{}", receiver.local_addr().unwrap()),
+            ..SyslogConfig::default()
+        })
+        .unwrap();
+        let subscriber = tracing_subscriber::fmt()
+            .with_ansi(false)
+            .without_time()
+            .with_writer(writer)
+            .finish();
+        tracing::subscriber::with_default(subscriber, || tracing::warn!(host = "a", "refused"));
+
+        let mut buf = [0u8; 1024];
+        let n = receiver.recv(&mut buf).unwrap();
+        let message = String::from_utf8_lossy(&buf[..n]);
+        // daemon facility (3) and warning severity (4)
+        assert!(message.starts_with("<28>1 "), "{}", message);
+        assert!(message.ends_with("refused host=\"a\""), "{}", message);
+    }
+}
@@ -0,0 +1,194 @@
+//! Integration with systemd: socket activation, readiness notification, and the service
+//! watchdog.  All of these are controlled by environment variables that systemd sets, so
+//! outside of systemd they do nothing.
+
+use anyhow::{bail, Context, Result};
+#[cfg(unix)]
+use std::ffi::OsStr;
+use std::ops::Range;
+use std::time::Duration;
+
+/// The first file descriptor passed by systemd for socket activation
+const LISTEN_FDS_START: i32 = 3;
+
+/// Take the listening sockets passed by systemd for socket activation, in the order they
+/// appear in the socket unit.  Returns an empty list if the process was not started by
+/// socket activation.  The environment variables describing the sockets are removed, so
+/// that later calls (and child processes) do not take them again.
+#[cfg(unix)]
+pub fn take_listeners() -> Result<Vec<std::net::TcpListener>> {
+    use std::os::fd::FromRawFd;
+
+    let fds = listen_fds(
+        std::env::var("LISTEN_PID").ok().as_deref(),
+        std::env::var("LISTEN_FDS").ok().as_deref(),
+        std::process::id(),
+    )?;
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+
+    let mut listeners = vec![];
+    for fd in fds {
+        // SAFETY: systemd passes these descriptors to this process, and the environment
+        // variables are removed above, so nothing else takes ownership of them
+        let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+        // this fails if systemd passed something other than a socket
+        listener
+            .local_addr()
+            .with_context(|| format!("file descriptor {} from systemd", fd))?;
+        listeners.push(listener);
+    }
+    Ok(listeners)
+}
+
+/// Socket activation is not supported on this platform
+#[cfg(not(unix))]
+pub fn take_listeners() -> Result<Vec<std::net::TcpListener>> {
+    Ok(vec![])
+}
+
+/// Send a state notification such as `READY=1` to systemd.  Returns false if the process
+/// is not running under systemd with notification enabled.
+#[cfg(unix)]
+pub fn notify(state: &str) -> Result<bool> {
+    match std::env::var_os("NOTIFY_SOCKET") {
+        Some(path) => {
+            notify_socket(&path, state)
+                .with_context(|| format!("sending {:?} to systemd", state))?;
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+/// Notification is not supported on this platform
+#[cfg(not(unix))]
+pub fn notify(_state: &str) -> Result<bool> {
+    Ok(false)
+}
+
+/// If systemd expects watchdog pings, send them in a background task at half the
+/// watchdog interval.
+pub fn spawn_watchdog() {
+    let interval = match watchdog_interval(
+        std::env::var("WATCHDOG_USEC").ok().as_deref(),
+        std::env::var("WATCHDOG_PID").ok().as_deref(),
+        std::process::id(),
+    ) {
+        Some(interval) => interval,
+        None => return,
+    };
+    log::info!("sending systemd watchdog pings every {:?}", interval / 2);
+    tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(interval / 2);
+        loop {
+            ticks.tick().await;
+            if let Err(e) = notify("WATCHDOG=1") {
+                log::error!("{:?}", e);
+            }
+        }
+    });
+}
+
+/// Determine the file descriptors passed for socket activation from the values of
+/// `LISTEN_PID` and `LISTEN_FDS`.  The descriptors are only meant for this process if
+/// `LISTEN_PID` is its PID.
+fn listen_fds(listen_pid: Option<&str>, listen_fds: Option<&str>, pid: u32) -> Result<Range<i32>> {
+    let (listen_pid, listen_fds) = match (listen_pid, listen_fds) {
+        (Some(listen_pid), Some(listen_fds)) => (listen_pid, listen_fds),
+        _ => return Ok(0..0),
+    };
+    if listen_pid.parse::<u32>().ok() != Some(pid) {
+        return Ok(0..0);
+    }
+    let count: i32 = match listen_fds.parse() {
+        Ok(count) if count >= 0 => count,
+        _ => bail!("invalid LISTEN_FDS {:?}", listen_fds),
+    };
+    Ok(LISTEN_FDS_START..LISTEN_FDS_START + count)
+}
+
+/// Determine the watchdog interval from the values of `WATCHDOG_USEC` and
+/// `WATCHDOG_PID`, if the watchdog is enabled for this process
+fn watchdog_interval(usec: Option<&str>, watchdog_pid: Option<&str>, pid: u32) -> Option<Duration> {
+    if let Some(watchdog_pid) = watchdog_pid {
+        if watchdog_pid.parse::<u32>().ok() != Some(pid) {
+            return None;
+        }
+    }
+    match usec?.parse() {
+        Ok(0) | Err(_) => None,
+        Ok(usec) => Some(Duration::from_micros(usec)),
+    }
+}
+
+/// Send a notification to the given socket path.  A leading `@` denotes a socket in the
+/// abstract namespace.
+#[cfg(unix)]
+fn notify_socket(path: &OsStr, state: &str) -> Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::net::UnixDatagram;
+
+    let socket = UnixDatagram::unbound()?;
+    match path.as_bytes() {
+        #[cfg(target_os = "linux")]
+        [b'@', name @ ..] => {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(state.as_bytes(), &addr)?;
+        }
+        _ => {
+            socket.send_to(state.as_bytes(), path)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::temp_path;
+
+    #[test]
+    fn test_listen_fds() {
+        assert_eq!(listen_fds(None, None, 100).unwrap(), 0..0);
+        assert_eq!(listen_fds(Some("100"), Some("2"), 100).unwrap(), 3..5);
+        // meant for another process
+        assert_eq!(listen_fds(Some("99"), Some("2"), 100).unwrap(), 0..0);
+        assert!(listen_fds(Some("100"), Some("two"), 100).is_err());
+        assert!(listen_fds(Some("100"), Some("-1"), 100).is_err());
+    }
+
+    #[test]
+    fn test_watchdog_interval() {
+        assert_eq!(watchdog_interval(None, None, 100), None);
+        assert_eq!(
+            watchdog_interval(Some("30000000"), None, 100),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            watchdog_interval(Some("30000000"), Some("100"), 100),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(watchdog_interval(Some("30000000"), Some("99"), 100), None);
+        assert_eq!(watchdog_interval(Some("0"), None, 100), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_notify_socket() {
+        use std::os::unix::net::UnixDatagram;
+
+        let path = temp_path("notify");
+        let _ = std::fs::remove_file(&path);
+        let receiver = UnixDatagram::bind(&path).unwrap();
+        notify_socket(path.as_os_str(), "READY=1").unwrap();
+        let mut buf = [0u8; 64];
+        let n = receiver.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"READY=1");
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(notify_socket(OsStr::new("/nonexistent/notify"), "READY=1").is_err());
+    }
+}
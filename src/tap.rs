@@ -0,0 +1,289 @@
+//! Traffic taps, for debugging protocol problems without external capture tools.  While
+//! taps are enabled, in the configuration or through the admin server, the first
+//! `max_bytes` in each direction of each new tunnel are copied to a file named by the
+//! tunnel's ID in `dir`, or to a new TCP connection to `mirror`.  No more than
+//! `max_per_minute` tunnels are tapped.
+//!
+//! Tapped bytes are the clients' traffic, in the clear unless the clients encrypt it, so
+//! taps are for debugging only and should never be left enabled.  Each tapped tunnel is
+//! logged as a warning.
+//!
+//! A tap begins with a line of JSON identifying the tunnel, followed by a record for each
+//! chunk of data: the byte `u` for data from the client or `d` for data to it, the length
+//! of the data as a 32-bit big-endian integer, and the data.  Chunks are dropped, rather
+//! than slowing the tunnel, if the tap's writer falls behind.
+
+use crate::access::AccessRecord;
+use crate::config::TapConfig;
+use crate::connection::Direction;
+use crate::metrics::METRICS;
+use crate::telemetry::spawn;
+use anyhow::{Context, Result};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+
+/// The taps, if they have been installed with `Taps::install`
+static TAPS: RwLock<Option<Arc<Taps>>> = RwLock::new(None);
+
+/// Chunks that may wait for a tap's writer before more are dropped
+const QUEUE: usize = 256;
+
+/// A chunk of tunnel data, and the direction in which it was sent
+type Chunk = (Direction, Vec<u8>);
+
+/// The configured taps, and whether they are enabled
+pub(crate) struct Taps {
+    config: TapConfig,
+    enabled: AtomicBool,
+    /// The start of the current minute, and the tunnels tapped in it
+    window: Mutex<(Instant, u32)>,
+}
+
+impl Taps {
+    pub(crate) fn new(config: &TapConfig) -> Self {
+        if config.enabled {
+            log::warn!("traffic taps are enabled; tunnel data will be copied");
+        }
+        Self {
+            config: config.clone(),
+            enabled: AtomicBool::new(config.enabled),
+            window: Mutex::new((Instant::now(), 0)),
+        }
+    }
+
+    /// Make these taps available to new tunnels and to the admin server
+    pub(crate) fn install(self: &Arc<Self>) {
+        *TAPS.write().unwrap() = Some(self.clone());
+    }
+
+    pub(crate) fn enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    /// Enable or disable tapping of new tunnels; tunnels already tapped are unaffected
+    pub(crate) fn set_enabled(&self, enabled: bool) {
+        if self.enabled.swap(enabled, Ordering::Relaxed) != enabled {
+            if enabled {
+                log::warn!("traffic taps enabled; tunnel data will be copied");
+            } else {
+                log::warn!("traffic taps disabled");
+            }
+        }
+    }
+
+    /// Whether another tunnel may be tapped at `now`, counting it if so
+    fn admit(&self, now: Instant) -> bool {
+        let mut window = self.window.lock().unwrap();
+        if now.duration_since(window.0) >= Duration::from_secs(60) {
+            *window = (now, 0);
+        }
+        if window.1 >= self.config.max_per_minute {
+            return false;
+        }
+        window.1 += 1;
+        true
+    }
+
+    /// Begin tapping the tunnel for `record`, if taps are enabled and the rate allows.  The
+    /// tap is written in a background task.
+    pub(crate) fn open(&self, record: &AccessRecord) -> Option<Tap> {
+        if !self.enabled() || !self.admit(Instant::now()) {
+            return None;
+        }
+        log::warn!(
+            "tapping tunnel {} from {} to {}",
+            record.id,
+            record
+                .client
+                .map_or_else(|| "-".to_owned(), |c| c.to_string()),
+            record.target.as_deref().unwrap_or("-"),
+        );
+        METRICS.tunnels_tapped.inc();
+
+        let header = serde_json::json!({
+            "id": record.id,
+            "client": record.client,
+            "target": record.target,
+        });
+        let (sender, receiver) = mpsc::channel(QUEUE);
+        let config = self.config.clone();
+        let id = record.id;
+        spawn("tap", async move {
+            if let Err(e) = write(&config, id, header, receiver).await {
+                log::warn!("tap of tunnel {} failed: {:#}", id, e);
+            }
+        });
+        Some(Tap {
+            sender,
+            remaining: [
+                AtomicU64::new(self.config.max_bytes),
+                AtomicU64::new(self.config.max_bytes),
+            ],
+        })
+    }
+}
+
+/// Whether the installed taps are enabled, or None if taps are not configured
+pub(crate) fn enabled() -> Option<bool> {
+    TAPS.read().unwrap().as_ref().map(|taps| taps.enabled())
+}
+
+/// Enable or disable the installed taps, returning false if taps are not configured
+pub(crate) fn set_enabled(enabled: bool) -> bool {
+    match TAPS.read().unwrap().as_ref() {
+        Some(taps) => {
+            taps.set_enabled(enabled);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Begin tapping the tunnel for `record` with the installed taps, if any
+pub(crate) fn open(record: &AccessRecord) -> Option<Tap> {
+    TAPS.read().unwrap().as_ref()?.open(record)
+}
+
+/// The tap of a single tunnel.  The tap's file or connection is closed once this is
+/// dropped and the queued chunks have been written.
+pub(crate) struct Tap {
+    sender: mpsc::Sender<Chunk>,
+    /// Bytes that may yet be tapped, up and down
+    remaining: [AtomicU64; 2],
+}
+
+impl Tap {
+    /// Copy data sent in the given direction to the tap, up to its limit
+    pub(crate) fn copy(&self, direction: Direction, data: &[u8]) {
+        let remaining = match direction {
+            Direction::Up => &self.remaining[0],
+            Direction::Down => &self.remaining[1],
+        };
+        // each direction is copied by a single task, so there is no race here
+        let n = remaining.load(Ordering::Relaxed).min(data.len() as u64);
+        if n == 0 {
+            return;
+        }
+        remaining.fetch_sub(n, Ordering::Relaxed);
+        let chunk = (direction, data[..n as usize].to_vec());
+        if self.sender.try_send(chunk).is_err() {
+            METRICS.tap_chunks_dropped.inc();
+        }
+    }
+}
+
+/// Open the destination for a tap of tunnel `id`, and write its header and chunks to it
+async fn write(
+    config: &TapConfig,
+    id: u64,
+    header: serde_json::Value,
+    mut chunks: mpsc::Receiver<Chunk>,
+) -> Result<()> {
+    let mut out: Box<dyn AsyncWrite + Send + Unpin> = match (&config.dir, config.mirror) {
+        (Some(dir), _) => {
+            let path = dir.join(format!("{}.tap", id));
+            let file = tokio::fs::File::create(&path)
+                .await
+                .with_context(|| format!("creating {:?}", path))?;
+            Box::new(file)
+        }
+        (None, Some(mirror)) => {
+            let socket = TcpStream::connect(mirror)
+                .await
+                .with_context(|| format!("connecting to mirror {}", mirror))?;
+            Box::new(socket)
+        }
+        (None, None) => unreachable!("validated in config"),
+    };
+
+    let mut line = serde_json::to_vec(&header)?;
+    line.push(b'\n');
+    out.write_all(&line).await?;
+    while let Some((direction, data)) = chunks.recv().await {
+        out.write_all(&encode(direction, &data)).await?;
+        out.flush().await?;
+    }
+    out.shutdown().await?;
+    Ok(())
+}
+
+/// Encode a chunk as a tap record
+pub(crate) fn encode(direction: Direction, data: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(data.len() + 5);
+    record.push(match direction {
+        Direction::Up => b'u',
+        Direction::Down => b'd',
+    });
+    record.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    record.extend_from_slice(data);
+    record
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::temp_path;
+    use std::path::PathBuf;
+
+    fn taps(dir: PathBuf, max_bytes: u64, max_per_minute: u32) -> Taps {
+        Taps::new(&TapConfig {
+            enabled: true,
+            dir: Some(dir),
+            max_bytes,
+            max_per_minute,
+            ..TapConfig::default()
+        })
+    }
+
+    #[test]
+    fn test_admit() {
+        let taps = taps(std::env::temp_dir(), 10, 2);
+        let start = Instant::now();
+        assert!(taps.admit(start));
+        assert!(taps.admit(start + Duration::from_secs(1)));
+        assert!(!taps.admit(start + Duration::from_secs(59)));
+        assert!(taps.admit(start + Duration::from_secs(60)));
+    }
+
+    #[tokio::test]
+    async fn test_tap_file() {
+        let dir = temp_path("tap");
+        std::fs::create_dir_all(&dir).unwrap();
+        let taps = taps(dir.clone(), 4, 10);
+
+        // disabled taps tap nothing
+        taps.set_enabled(false);
+        let mut record = AccessRecord::new(Some("10.0.0.1:5555".parse().unwrap()));
+        assert!(taps.open(&record).is_none());
+        taps.set_enabled(true);
+
+        record.target = Some("api.giphy.com:443".into());
+        let tap = taps.open(&record).unwrap();
+        tap.copy(Direction::Up, b"HELLO");
+        tap.copy(Direction::Up, b"more");
+        tap.copy(Direction::Down, b"hi");
+        drop(tap);
+
+        let path = dir.join(format!("{}.tap", record.id));
+        let expected = b"u\0\0\0\x04HELLd\0\0\0\x02hi";
+        let mut content = vec![];
+        for _ in 0..100 {
+            content = std::fs::read(&path).unwrap_or_default();
+            if content.ends_with(expected) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let (header, records) = content.split_at(content.iter().position(|b| *b == b'\n').unwrap());
+        let header: serde_json::Value = serde_json::from_slice(header).unwrap();
+        assert_eq!(header["client"], "10.0.0.1:5555");
+        assert_eq!(header["target"], "api.giphy.com:443");
+        assert_eq!(&records[1..], expected);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
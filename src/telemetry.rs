@@ -0,0 +1,143 @@
+//! Logging and distributed tracing for the `giphyproxy` binary.
+//!
+//! Logs are written to stderr, syslog, or journald, filtered with `RUST_LOG` syntax as with
+//! `env_logger`.  The proxy itself logs with the `log` crate, whose records are bridged
+//! into `tracing`.  Each connection has a `tracing` span, with child spans for reading the
+//! request head, connecting to the backend, and proxying data; if an OTLP endpoint is
+//! configured, these spans are exported to it.
+//!
+//! With the `console` feature, the proxy's tasks can be inspected with `tokio-console`.
+//! Tasks are named for the console (with `spawn`) if the proxy is also built with
+//! `--cfg tokio_unstable`.
+
+use crate::config::{LogConfig, LogOutput, TracingConfig};
+use crate::syslog::SyslogWriter;
+use anyhow::{Context, Result};
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::trace::{Sampler, SdkTracerProvider};
+use opentelemetry_sdk::Resource;
+use std::future::Future;
+use tokio::task::JoinHandle;
+use tracing_subscriber::filter::{EnvFilter, LevelFilter, Targets};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{Layer, Registry};
+
+/// Installed telemetry, which flushes any unexported spans when dropped
+pub struct Telemetry {
+    provider: Option<SdkTracerProvider>,
+}
+
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        if let Some(provider) = self.provider.take() {
+            if let Err(e) = provider.shutdown() {
+                eprintln!("shutting down trace exporter: {}", e);
+            }
+        }
+    }
+}
+
+/// Install the global logger and tracing subscriber.  This must be called at most once,
+/// and outside of any async runtime, as the OTLP exporter runs on its own thread.
+pub fn init(log: &LogConfig, tracing: &TracingConfig) -> Result<Telemetry> {
+    let filter = match &log.level {
+        Some(filters) => EnvFilter::try_new(filters)
+            .with_context(|| format!("invalid log filter {:?}", filters))?,
+        None => EnvFilter::from_default_env(),
+    };
+    let output = output(log)?.with_filter(filter);
+
+    let provider = match &tracing.otlp_endpoint {
+        Some(endpoint) => Some(provider(endpoint, tracing)?),
+        None => None,
+    };
+    // spans are exported regardless of the log filter
+    let otel = provider.as_ref().map(|provider| {
+        tracing_opentelemetry::layer()
+            .with_tracer(provider.tracer("giphyproxy"))
+            .with_filter(Targets::new().with_target("giphyproxy", LevelFilter::INFO))
+    });
+
+    // this also installs the `tracing-log` bridge, so records from the `log` crate are
+    // handled like any other event
+    let registry = tracing_subscriber::registry().with(output).with(otel);
+    // the console server runs on its own thread, configured by `TOKIO_CONSOLE_*` variables
+    #[cfg(feature = "console")]
+    let registry = registry.with(console_subscriber::spawn());
+    registry
+        .try_init()
+        .context("installing tracing subscriber")?;
+    Ok(Telemetry { provider })
+}
+
+/// Spawn a task, giving it a name for `tokio-console` if that is supported
+pub(crate) fn spawn<F>(name: &str, future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    #[cfg(all(feature = "console", tokio_unstable))]
+    return tokio::task::Builder::new()
+        .name(name)
+        .spawn(future)
+        .expect("spawning task");
+    #[cfg(not(all(feature = "console", tokio_unstable)))]
+    {
+        let _ = name;
+        tokio::spawn(future)
+    }
+}
+
+/// Create the layer writing log records to the configured output
+fn output(log: &LogConfig) -> Result<Box<dyn Layer<Registry> + Send + Sync>> {
+    Ok(match log.output {
+        LogOutput::Stderr => tracing_subscriber::fmt::layer()
+            .with_writer(std::io::stderr)
+            .boxed(),
+        // the daemon records the time and severity itself
+        LogOutput::Syslog => tracing_subscriber::fmt::layer()
+            .with_ansi(false)
+            .without_time()
+            .with_level(false)
+            .with_writer(SyslogWriter::new(&log.syslog)?)
+            .boxed(),
+        LogOutput::Journald => journald()?,
+    })
+}
+
+/// Create a layer sending log records to systemd-journald
+#[cfg(target_os = "linux")]
+fn journald() -> Result<Box<dyn Layer<Registry> + Send + Sync>> {
+    let layer = tracing_journald::layer()
+        .context("connecting to journald")?
+        .with_syslog_identifier("giphyproxy".into());
+    Ok(layer.boxed())
+}
+
+/// journald is not available on this platform
+#[cfg(not(target_os = "linux"))]
+fn journald() -> Result<Box<dyn Layer<Registry> + Send + Sync>> {
+    anyhow::bail!("log.output = \"journald\" is only supported on Linux")
+}
+
+/// Create a tracer provider exporting spans to the given OTLP/HTTP endpoint
+fn provider(endpoint: &str, tracing: &TracingConfig) -> Result<SdkTracerProvider> {
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .with_endpoint(endpoint)
+        .build()
+        .context("creating OTLP exporter")?;
+    Ok(SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+            tracing.sample_ratio,
+        ))))
+        .with_resource(
+            Resource::builder()
+                .with_service_name(tracing.service_name.clone())
+                .build(),
+        )
+        .build())
+}
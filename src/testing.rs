@@ -0,0 +1,436 @@
+//! Backends for testing code that embeds the proxy, without real networking.  This module
+//! is only available with the `test-util` feature.
+//!
+//! * [`EchoBackend`] echoes back whatever each connection is sent.
+//! * [`FaultBackend`] wraps another backend, injecting connection failures, latency,
+//!   resets, and limited throughput.
+//! * [`ScriptedBackend`] answers each connection with scripted responses to expected
+//!   requests.
+//!
+//! [`FakeGiphy`] is a local TLS server standing in for the Giphy API, for end-to-end tests
+//! with a real HTTP client.
+
+use crate::backend::{Backend, Disallowed};
+use crate::chaos::FaultSpec;
+use crate::error::{ProxyError, Result};
+use crate::http::authority;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{duplex, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::sleep;
+use tokio_rustls::TlsAcceptor;
+
+/// A backend whose connections echo back whatever they are sent
+pub use crate::backend::MockBackend as EchoBackend;
+/// A socket returned by a `FaultBackend`
+pub use crate::chaos::FaultSocket;
+
+/// Size of the in-memory buffer for each `ScriptedBackend` connection
+const SCRIPT_BUFFER: usize = 16384;
+
+/// The faults a `FaultBackend` injects
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Faults {
+    /// Number of connections to fail, before connections are passed to the wrapped backend
+    pub connect_failures: usize,
+    /// Delay before each connection completes or fails
+    pub latency: Duration,
+    /// If set, each connection is reset once this many bytes have been read from it
+    pub reset_after: Option<u64>,
+    /// If set, reads from and writes to each connection are each limited to this many bytes
+    /// per second
+    pub bytes_per_sec: Option<u64>,
+}
+
+/// A backend which wraps another, injecting the configured `Faults`
+pub struct FaultBackend<B: Backend> {
+    inner: B,
+    faults: Faults,
+    attempts: AtomicUsize,
+}
+
+impl<B: Backend> FaultBackend<B> {
+    pub fn new(inner: B, faults: Faults) -> Self {
+        Self {
+            inner,
+            faults,
+            attempts: AtomicUsize::new(0),
+        }
+    }
+
+    /// The number of connections attempted so far, including failed connections
+    pub fn attempts(&self) -> usize {
+        self.attempts.load(Ordering::SeqCst)
+    }
+}
+
+#[async_trait::async_trait]
+impl<B: Backend> Backend for FaultBackend<B> {
+    type Socket = FaultSocket<B::Socket>;
+
+    async fn connect(&self, host: &str, port: u16) -> Result<Self::Socket> {
+        let attempt = self.attempts.fetch_add(1, Ordering::SeqCst);
+        sleep(self.faults.latency).await;
+        if attempt < self.faults.connect_failures {
+            return Err(ProxyError::UpstreamConnect {
+                target: authority(host, port),
+                error: io::Error::new(io::ErrorKind::ConnectionRefused, "injected failure"),
+            });
+        }
+        let socket = self.inner.connect(host, port).await?;
+        Ok(FaultSocket::new(
+            socket,
+            FaultSpec {
+                reset_after: self.faults.reset_after,
+                read_bytes_per_sec: self.faults.bytes_per_sec,
+                write_bytes_per_sec: self.faults.bytes_per_sec,
+                ..FaultSpec::default()
+            },
+        ))
+    }
+}
+
+/// A backend which runs a script on each connection: for each step, it reads exactly the
+/// expected request and writes the response.  If a request does not match, the
+/// connection is closed.  The destinations and received requests are recorded for
+/// inspection.
+#[derive(Clone, Default)]
+pub struct ScriptedBackend {
+    steps: Vec<(Vec<u8>, Vec<u8>)>,
+    log: Arc<Mutex<ScriptLog>>,
+}
+
+/// What a `ScriptedBackend` has seen
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ScriptLog {
+    /// The host and port of each connection
+    pub destinations: Vec<(String, u16)>,
+    /// The requests that did not match the script
+    pub mismatches: Vec<Vec<u8>>,
+}
+
+impl ScriptedBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a step to the script, answering `request` with `response`
+    pub fn expect<Q: Into<Vec<u8>>, R: Into<Vec<u8>>>(mut self, request: Q, response: R) -> Self {
+        self.steps.push((request.into(), response.into()));
+        self
+    }
+
+    /// What the backend has seen so far
+    pub fn log(&self) -> ScriptLog {
+        self.log.lock().unwrap().clone()
+    }
+}
+
+#[async_trait::async_trait]
+impl Backend for ScriptedBackend {
+    type Socket = DuplexStream;
+
+    async fn connect(&self, host: &str, port: u16) -> Result<Self::Socket> {
+        self.log
+            .lock()
+            .unwrap()
+            .destinations
+            .push((host.to_owned(), port));
+        let (socket, mut server) = duplex(SCRIPT_BUFFER);
+        let steps = self.steps.clone();
+        let log = self.log.clone();
+        tokio::spawn(async move {
+            for (request, response) in steps {
+                let mut received = vec![0u8; request.len()];
+                if server.read_exact(&mut received).await.is_err() {
+                    return;
+                }
+                if received != request {
+                    log.lock().unwrap().mismatches.push(received);
+                    return;
+                }
+                if server.write_all(&response).await.is_err() {
+                    return;
+                }
+            }
+            let _ = server.shutdown().await;
+        });
+        Ok(socket)
+    }
+}
+
+/// The host served by `FakeGiphy`
+pub const GIPHY_HOST: &str = "api.giphy.com";
+
+/// The response `FakeGiphy` gives to every request, as Giphy does when no API key is given
+const GIPHY_UNAUTHORIZED: &str = "{\"message\":\"No API key found in request\"}";
+
+/// A local TLS server impersonating the Giphy API, answering every request with `401
+/// Unauthorized`.  It presents a self-signed certificate for `api.giphy.com`, which clients
+/// must trust (see `cert_pem`), and `backend` gives a backend that sends connections for
+/// `api.giphy.com:443` to it.
+pub struct FakeGiphy {
+    addr: SocketAddr,
+    cert_pem: String,
+}
+
+impl FakeGiphy {
+    /// Start the server on an ephemeral local port.  It runs in a background task until the
+    /// runtime shuts down.
+    pub async fn start() -> anyhow::Result<Self> {
+        let certified = rcgen::generate_simple_self_signed(vec![GIPHY_HOST.into()])?;
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let server_config = rustls::ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![certified.cert.der().clone()],
+                rustls::pki_types::PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der())
+                    .into(),
+            )?;
+        let acceptor = TlsAcceptor::from(Arc::new(server_config));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    if let Ok(tls) = acceptor.accept(socket).await {
+                        let _ = Self::respond(tls).await;
+                    }
+                });
+            }
+        });
+
+        Ok(Self {
+            addr,
+            cert_pem: certified.cert.pem(),
+        })
+    }
+
+    /// Read a request head and answer it, closing the connection
+    async fn respond<S: AsyncRead + AsyncWrite + Unpin>(mut socket: S) -> io::Result<()> {
+        let mut head = vec![];
+        let mut buf = [0u8; 1024];
+        while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+            let n = socket.read(&mut buf).await?;
+            if n == 0 {
+                return Ok(());
+            }
+            head.extend_from_slice(&buf[..n]);
+        }
+        let response = format!(
+            "HTTP/1.1 401 Unauthorized\r\nContent-Type: application/json\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+            GIPHY_UNAUTHORIZED.len(),
+            GIPHY_UNAUTHORIZED
+        );
+        socket.write_all(response.as_bytes()).await?;
+        socket.shutdown().await
+    }
+
+    /// The address the server is listening on
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The server's certificate, in PEM format
+    pub fn cert_pem(&self) -> &str {
+        &self.cert_pem
+    }
+
+    /// A backend which connects `api.giphy.com:443` to this server, and disallows all
+    /// other destinations
+    pub fn backend(&self) -> FakeGiphyBackend {
+        FakeGiphyBackend { addr: self.addr }
+    }
+}
+
+/// A backend returned by `FakeGiphy::backend`
+#[derive(Debug, Clone)]
+pub struct FakeGiphyBackend {
+    addr: SocketAddr,
+}
+
+#[async_trait::async_trait]
+impl Backend for FakeGiphyBackend {
+    type Socket = TcpStream;
+
+    async fn connect(&self, host: &str, port: u16) -> Result<Self::Socket> {
+        if host != GIPHY_HOST || port != 443 {
+            return Err(Disallowed {
+                host: host.into(),
+                port,
+            }
+            .into());
+        }
+        TcpStream::connect(self.addr)
+            .await
+            .map_err(|error| ProxyError::UpstreamConnect {
+                target: authority(host, port),
+                error,
+            })
+    }
+}
+
+/// A path in the temporary directory for a test, named for `name` and this process
+#[cfg(test)]
+pub(crate) fn temp_path(name: &str) -> std::path::PathBuf {
+    std::env::temp_dir().join(format!("giphyproxy-test-{}-{}", name, std::process::id()))
+}
+
+/// Write a temporary file for a test, named for `name` and this process, returning its
+/// path
+#[cfg(test)]
+pub(crate) fn temp_file(name: &str, content: &str) -> std::path::PathBuf {
+    let path = temp_path(name);
+    std::fs::write(&path, content).unwrap();
+    path
+}
+
+/// Start a TCP server on localhost that echoes back a single five-byte read from the
+/// first connection it accepts, returning its port
+#[cfg(test)]
+pub(crate) async fn echo_once() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut buf = [0u8; 5];
+        socket.read_exact(&mut buf).await.unwrap();
+        socket.write_all(&buf).await.unwrap();
+    });
+    port
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rustls::pki_types::ServerName;
+    use std::convert::TryFrom;
+    use std::time::Instant;
+
+    #[tokio::test]
+    async fn test_echo() {
+        let mut socket = EchoBackend.connect("example.com", 443).await.unwrap();
+        socket.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 5];
+        socket.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+
+    #[tokio::test]
+    async fn test_connect_failures() {
+        let faults = Faults {
+            connect_failures: 2,
+            ..Faults::default()
+        };
+        let backend = FaultBackend::new(EchoBackend, faults);
+        for _ in 0..2 {
+            let err = backend.connect("example.com", 443).await.err().unwrap();
+            assert!(matches!(err, ProxyError::UpstreamConnect { .. }));
+        }
+        assert!(backend.connect("example.com", 443).await.is_ok());
+        assert_eq!(backend.attempts(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_reset() {
+        let faults = Faults {
+            reset_after: Some(3),
+            ..Faults::default()
+        };
+        let backend = FaultBackend::new(EchoBackend, faults);
+        let mut socket = backend.connect("example.com", 443).await.unwrap();
+        socket.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 5];
+        let err = socket.read_exact(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+        assert_eq!(&buf[..3], b"hel");
+        assert!(socket.write_all(b"more").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_latency_and_throughput() {
+        let faults = Faults {
+            latency: Duration::from_millis(50),
+            bytes_per_sec: Some(1000),
+            ..Faults::default()
+        };
+        let backend = FaultBackend::new(EchoBackend, faults);
+        let start = Instant::now();
+        let mut socket = backend.connect("example.com", 443).await.unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        // the second write waits for the first 100 bytes to "transfer"
+        let start = Instant::now();
+        socket.write_all(&[0u8; 100]).await.unwrap();
+        socket.write_all(&[0u8; 1]).await.unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[tokio::test]
+    async fn test_scripted() {
+        let backend = ScriptedBackend::new()
+            .expect("ping", "pong")
+            .expect("ding", "dong");
+        let mut socket = backend.connect("example.com", 443).await.unwrap();
+        socket.write_all(b"pingding").await.unwrap();
+        let mut response = String::new();
+        socket.read_to_string(&mut response).await.unwrap();
+        assert_eq!(response, "pongdong");
+
+        let mut socket = backend.connect("example.com", 80).await.unwrap();
+        socket.write_all(b"PING").await.unwrap();
+        let mut response = vec![];
+        socket.read_to_end(&mut response).await.unwrap();
+        assert!(response.is_empty());
+
+        assert_eq!(
+            backend.log(),
+            ScriptLog {
+                destinations: vec![("example.com".into(), 443), ("example.com".into(), 80)],
+                mismatches: vec![b"PING".to_vec()],
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fake_giphy() {
+        let giphy = FakeGiphy::start().await.unwrap();
+        let backend = giphy.backend();
+        assert!(matches!(
+            backend.connect("example.com", 443).await.err().unwrap(),
+            ProxyError::Disallowed(_)
+        ));
+
+        let socket = backend.connect(GIPHY_HOST, 443).await.unwrap();
+        let mut roots = rustls::RootCertStore::empty();
+        for cert in rustls_pemfile::certs(&mut giphy.cert_pem().as_bytes()) {
+            roots.add(cert.unwrap()).unwrap();
+        }
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let client_config = rustls::ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let connector = tokio_rustls::TlsConnector::from(Arc::new(client_config));
+        let mut tls = connector
+            .connect(ServerName::try_from(GIPHY_HOST).unwrap(), socket)
+            .await
+            .unwrap();
+        tls.write_all(b"GET /v1/gifs/search HTTP/1.1\r\nHost: api.giphy.com\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        tls.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 401 Unauthorized\r\n"));
+        assert!(response.ends_with(GIPHY_UNAUTHORIZED));
+    }
+}
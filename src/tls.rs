@@ -0,0 +1,534 @@
+//! TLS termination for the listen addresses, so that clients can speak to the proxy over
+//! `https://`, optionally requiring clients to present a certificate.  The certificates
+//! and keys are read from PEM files, and can be reloaded without a restart.
+//!
+//! This module also provides [`TlsBackend`], for the TLS connections the proxy makes to
+//! backends itself.
+
+use crate::backend::Backend;
+use crate::config::{BackendConfig, TlsConfig};
+use crate::connection::ConnectionInfo;
+use crate::error::{self, IoContext, ProxyError};
+use crate::http::authority;
+use crate::reload;
+use anyhow::{bail, Context, Result};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{ClientConfig, RootCertStore, ServerConfig};
+use std::convert::TryFrom;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::time::timeout;
+use tokio_rustls::{client, server::TlsStream};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+use x509_parser::extensions::GeneralName;
+
+/// A TLS acceptor whose certificates can be reloaded from disk.  Connections that are
+/// already established keep the certificates they were accepted with.
+pub struct Acceptor {
+    config: TlsConfig,
+    current: RwLock<TlsAcceptor>,
+}
+
+impl Acceptor {
+    /// Create an acceptor, reading the certificate chain, private key, and client CA
+    /// bundle named in the configuration.
+    pub fn new(config: &TlsConfig) -> Result<Self> {
+        let server_config = load_server_config(config)?;
+        Ok(Self {
+            config: config.clone(),
+            current: RwLock::new(TlsAcceptor::from(Arc::new(server_config))),
+        })
+    }
+
+    /// Re-read the certificate, key, and client CA files.  On error, the acceptor continues
+    /// to use the previous certificates.
+    pub fn reload(&self) -> Result<()> {
+        let server_config = load_server_config(&self.config)?;
+        *self.current.write().unwrap() = TlsAcceptor::from(Arc::new(server_config));
+        Ok(())
+    }
+
+    /// Perform the TLS handshake on a newly accepted connection, failing if it does not
+    /// complete within `limit`.  If client certificates are required, this also checks
+    /// the certificate's names against `client_names`, and returns the client's identity:
+    /// the matching name if `client_names` is set, and otherwise the certificate's common
+    /// name or first DNS name.
+    pub async fn accept<S>(
+        &self,
+        socket: S,
+        limit: Duration,
+    ) -> Result<(TlsStream<S>, Option<String>)>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let acceptor = self.current.read().unwrap().clone();
+        let stream = timeout(limit, acceptor.accept(socket))
+            .await
+            .context("timed out in TLS handshake")?
+            .context("TLS handshake")?;
+
+        let cert = match stream.get_ref().1.peer_certificates() {
+            Some(certs) if !certs.is_empty() => &certs[0],
+            _ => return Ok((stream, None)),
+        };
+        let names = certificate_names(cert)?;
+        let identity = if self.config.client_names.is_empty() {
+            names.into_iter().next()
+        } else {
+            match names
+                .iter()
+                .find(|name| self.config.client_names.iter().any(|p| p.matches(name)))
+            {
+                Some(name) => Some(name.clone()),
+                None => bail!("client certificate names {:?} are not allowed", names),
+            }
+        };
+        Ok((stream, identity))
+    }
+
+    /// Reload the certificates whenever the process receives SIGHUP, in a background task.
+    pub fn reload_on_sighup(self: &Arc<Self>) -> Result<()> {
+        let this = self.clone();
+        reload::on_sighup("TLS certificates", move || this.reload())
+    }
+}
+
+/// A backend which performs a TLS handshake over each connection made by another backend,
+/// verifying the server's certificate and that it is valid for the requested host.  The
+/// handshake is limited by `backend.connect_timeout`.
+pub struct TlsBackend<B: Backend> {
+    backend: B,
+    connector: TlsConnector,
+    handshake_timeout: Duration,
+}
+
+impl<B: Backend> TlsBackend<B> {
+    /// Wrap `backend`, trusting the CA certificates in `config.tls_ca`, or the Mozilla root
+    /// certificates if that is not set
+    pub fn new(backend: B, config: &BackendConfig) -> Result<Self> {
+        Ok(Self {
+            backend,
+            connector: backend_connector(config)?,
+            handshake_timeout: config.connect_timeout,
+        })
+    }
+
+    /// Use the given TLS connector, rather than one built from the configuration
+    pub fn with_connector(mut self, connector: TlsConnector) -> Self {
+        self.connector = connector;
+        self
+    }
+}
+
+#[async_trait::async_trait]
+impl<B: Backend> Backend for TlsBackend<B> {
+    type Socket = client::TlsStream<B::Socket>;
+
+    async fn connect(&self, host: &str, port: u16) -> error::Result<Self::Socket> {
+        self.connect_for(&ConnectionInfo::default(), host, port)
+            .await
+    }
+
+    async fn connect_for(
+        &self,
+        info: &ConnectionInfo,
+        host: &str,
+        port: u16,
+    ) -> error::Result<Self::Socket> {
+        let name = ServerName::try_from(host.to_owned())
+            .with_context(|| format!("invalid backend host {:?}", host))?;
+        let socket = self.backend.connect_for(info, host, port).await?;
+        let handshake = self.connector.connect(name, socket);
+        match timeout(self.handshake_timeout, handshake).await {
+            Ok(result) => result.with_io_context(|| format!("TLS handshake with {}", host)),
+            Err(elapsed) => Err(ProxyError::UpstreamConnect {
+                target: authority(host, port),
+                error: elapsed.into(),
+            }),
+        }
+    }
+}
+
+/// A TLS connector for connections the proxy makes itself, verifying servers' certificates
+/// against the CA certificates in `config.tls_ca`, or the Mozilla root certificates if that
+/// is not set.  Only HTTP/1.1 is offered via ALPN.
+pub fn backend_connector(config: &BackendConfig) -> Result<TlsConnector> {
+    let roots = match &config.tls_ca {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for ca in read_certs(path).with_context(|| format!("reading {}", path.display()))? {
+                roots
+                    .add(ca)
+                    .with_context(|| format!("invalid CA certificate in {}", path.display()))?;
+            }
+            roots
+        }
+        None => RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        },
+    };
+    let mut config =
+        ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .expect("the default protocol versions are supported")
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+/// Build a rustls server configuration from the TLS configuration.  Both HTTP/2 and
+/// HTTP/1.1 are offered via ALPN.
+pub(crate) fn load_server_config(config: &TlsConfig) -> Result<ServerConfig> {
+    let (cert, key) = match (&config.cert, &config.key) {
+        (Some(cert), Some(key)) => (cert, key),
+        _ => bail!("tls.cert and tls.key are required"),
+    };
+    let certs = read_certs(cert).with_context(|| format!("reading {}", cert.display()))?;
+    let key = read_key(key).with_context(|| format!("reading {}", key.display()))?;
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?;
+    let builder = match &config.client_ca {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for ca in read_certs(path).with_context(|| format!("reading {}", path.display()))? {
+                roots
+                    .add(ca)
+                    .with_context(|| format!("invalid CA certificate in {}", path.display()))?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                .build()
+                .context("building client certificate verifier")?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let mut server_config = builder
+        .with_single_cert(certs, key)
+        .context("invalid TLS certificate or key")?;
+    server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(server_config)
+}
+
+/// Read all of the certificates in a PEM file, failing if there are none
+pub(crate) fn read_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let mut reader = BufReader::new(std::fs::File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader).collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        bail!("no certificates found");
+    }
+    Ok(certs)
+}
+
+/// Read the first private key in a PEM file
+pub(crate) fn read_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    let mut reader = BufReader::new(std::fs::File::open(path)?);
+    rustls_pemfile::private_key(&mut reader)?.context("no private key found")
+}
+
+/// The names in a certificate: the subject's common name, if any, followed by its DNS
+/// subject alternative names
+fn certificate_names(cert: &CertificateDer<'_>) -> Result<Vec<String>> {
+    let (_, cert) =
+        x509_parser::parse_x509_certificate(cert).context("parsing client certificate")?;
+    let mut names: Vec<String> = cert
+        .subject()
+        .iter_common_name()
+        .filter_map(|cn| cn.as_str().ok())
+        .map(str::to_owned)
+        .collect();
+    if let Ok(Some(san)) = cert.subject_alternative_name() {
+        for name in &san.value.general_names {
+            if let GeneralName::DNSName(name) = name {
+                names.push((*name).to_owned());
+            }
+        }
+    }
+    Ok(names)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::backend::SingleHostBackend;
+    use crate::config::Config;
+    use crate::listen::start_listening;
+    use crate::testing::{echo_once, temp_file};
+    use rcgen::{
+        BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, KeyPair,
+    };
+    use rustls::pki_types::{PrivatePkcs8KeyDer, ServerName};
+    use std::convert::TryFrom;
+    use std::path::PathBuf;
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Write a self-signed certificate for `localhost` and its key to temporary files,
+    /// returning the certificate's DER encoding and a configuration using the files.
+    fn self_signed(name: &str) -> (CertificateDer<'static>, TlsConfig) {
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let config = TlsConfig {
+            cert: Some(temp_file(&format!("{}.crt", name), &certified.cert.pem())),
+            key: Some(temp_file(
+                &format!("{}.key", name),
+                &certified.key_pair.serialize_pem(),
+            )),
+            ..TlsConfig::default()
+        };
+        (certified.cert.der().clone(), config)
+    }
+
+    /// Remove the files named in a configuration
+    fn remove_files(config: &TlsConfig) {
+        for path in [&config.cert, &config.key, &config.client_ca]
+            .iter()
+            .copied()
+            .flatten()
+        {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+
+    /// A client CA, and a client certificate and key signed by it, with the given common
+    /// name and DNS name
+    struct ClientCerts {
+        ca_pem: String,
+        cert: CertificateDer<'static>,
+        key: PrivateKeyDer<'static>,
+    }
+
+    fn client_certs(common_name: &str, dns_name: &str) -> ClientCerts {
+        let ca_key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(vec![]).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params
+            .distinguished_name
+            .push(DnType::CommonName, "test CA");
+        let ca = params.self_signed(&ca_key).unwrap();
+
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(vec![dns_name.into()]).unwrap();
+        params
+            .distinguished_name
+            .push(DnType::CommonName, common_name);
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+        let cert = params.signed_by(&key, &ca, &ca_key).unwrap();
+
+        ClientCerts {
+            ca_pem: ca.pem(),
+            cert: cert.der().clone(),
+            key: PrivatePkcs8KeyDer::from(key.serialize_der()).into(),
+        }
+    }
+
+    /// A TLS connector trusting only the given server certificate, and presenting the given
+    /// client certificate, if any
+    fn connector(
+        server: CertificateDer<'static>,
+        client: Option<&ClientCerts>,
+    ) -> tokio_rustls::TlsConnector {
+        let mut roots = RootCertStore::empty();
+        roots.add(server).unwrap();
+        let builder = rustls::ClientConfig::builder_with_provider(Arc::new(
+            rustls::crypto::ring::default_provider(),
+        ))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots);
+        let config = match client {
+            Some(client) => builder
+                .with_client_auth_cert(vec![client.cert.clone()], client.key.clone_key())
+                .unwrap(),
+            None => builder.with_no_client_auth(),
+        };
+        tokio_rustls::TlsConnector::from(Arc::new(config))
+    }
+
+    /// Perform a handshake between the given connector and acceptor over an in-memory
+    /// pipe, returning the acceptor's result
+    async fn handshake(
+        connector: tokio_rustls::TlsConnector,
+        acceptor: &Acceptor,
+    ) -> Result<Option<String>> {
+        let (client, server) = duplex(16384);
+        let client = async move {
+            // keep the client's end open until the server is done
+            connector
+                .connect(ServerName::try_from("localhost").unwrap(), client)
+                .await
+        };
+        let (client, server) = tokio::join!(client, acceptor.accept(server, TIMEOUT));
+        drop(client);
+        server.map(|(_, identity)| identity)
+    }
+
+    #[test]
+    fn test_load_errors() {
+        let (_, config) = self_signed("load-errors");
+        assert!(Acceptor::new(&config).is_ok());
+        let swapped = TlsConfig {
+            cert: config.key.clone(),
+            key: config.cert.clone(),
+            ..TlsConfig::default()
+        };
+        assert!(Acceptor::new(&swapped).is_err());
+        let missing = TlsConfig {
+            cert: Some(PathBuf::from("/nonexistent/cert.pem")),
+            ..config.clone()
+        };
+        assert!(Acceptor::new(&missing).is_err());
+        remove_files(&config);
+    }
+
+    #[test]
+    fn test_reload_keeps_old_config_on_error() {
+        let (_, config) = self_signed("reload");
+        let acceptor = Acceptor::new(&config).unwrap();
+        let current = || Arc::as_ptr(acceptor.current.read().unwrap().config());
+        let before = current();
+
+        acceptor.reload().unwrap();
+        let reloaded = current();
+        assert_ne!(before, reloaded);
+
+        std::fs::write(config.cert.as_ref().unwrap(), "garbage").unwrap();
+        assert!(acceptor.reload().is_err());
+        assert_eq!(current(), reloaded);
+
+        remove_files(&config);
+    }
+
+    #[tokio::test]
+    async fn test_connect_over_tls() {
+        let port = echo_once().await;
+
+        let (der, tls_config) = self_signed("connect");
+        let tls = Arc::new(Acceptor::new(&tls_config).unwrap());
+        let config = Config {
+            listen: vec!["127.0.0.1:0".parse().unwrap()],
+            ..Config::default()
+        };
+        let backend = Arc::new(SingleHostBackend::new("127.0.0.1", port));
+        let addrs = start_listening(Arc::new(config), backend, None, None, Some(tls), None)
+            .await
+            .unwrap();
+
+        let socket = TcpStream::connect(addrs[0]).await.unwrap();
+        let mut client = connector(der, None)
+            .connect(ServerName::try_from("localhost").unwrap(), socket)
+            .await
+            .unwrap();
+        client
+            .write_all(format!("CONNECT 127.0.0.1:{} HTTP/1.1\r\n\r\n", port).as_bytes())
+            .await
+            .unwrap();
+
+        const EXPECTED_RESPONSE: &[u8] = b"HTTP/1.1 200 OK\r\n\r\n";
+        let mut buf = [0u8; EXPECTED_RESPONSE.len()];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, EXPECTED_RESPONSE);
+
+        client.write_all(b"HELLO").await.unwrap();
+        let mut buf = [0u8; 5];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"HELLO");
+
+        remove_files(&tls_config);
+    }
+
+    #[tokio::test]
+    async fn test_client_certificates() {
+        let alice = client_certs("alice", "alice.clients.example.com");
+        let (der, mut config) = self_signed("client-certs");
+        config.client_ca = Some(temp_file("client-certs-ca.crt", &alice.ca_pem));
+
+        // any certificate from the CA is accepted, identified by its common name
+        let acceptor = Acceptor::new(&config).unwrap();
+        let identity = handshake(connector(der.clone(), Some(&alice)), &acceptor).await;
+        assert_eq!(identity.unwrap(), Some("alice".into()));
+
+        // clients without a certificate are rejected
+        assert!(handshake(connector(der.clone(), None), &acceptor)
+            .await
+            .is_err());
+
+        // certificates from another CA are rejected
+        let mallory = client_certs("alice", "alice.clients.example.com");
+        assert!(handshake(connector(der.clone(), Some(&mallory)), &acceptor)
+            .await
+            .is_err());
+
+        // with client_names, the certificate must have a matching name
+        config.client_names = vec!["*.clients.example.com".parse().unwrap()];
+        let acceptor = Acceptor::new(&config).unwrap();
+        let identity = handshake(connector(der.clone(), Some(&alice)), &acceptor).await;
+        assert_eq!(identity.unwrap(), Some("alice.clients.example.com".into()));
+
+        config.client_names = vec!["bob".parse().unwrap()];
+        let acceptor = Acceptor::new(&config).unwrap();
+        assert!(handshake(connector(der, Some(&alice)), &acceptor)
+            .await
+            .is_err());
+
+        remove_files(&config);
+    }
+
+    #[tokio::test]
+    async fn test_tls_backend() {
+        let (_, tls_config) = self_signed("backend");
+        let acceptor = Arc::new(Acceptor::new(&tls_config).unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            loop {
+                let (socket, _) = listener.accept().await.unwrap();
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    if let Ok((mut stream, _)) = acceptor.accept(socket, TIMEOUT).await {
+                        stream.write_all(b"HELLO").await.unwrap();
+                        stream.shutdown().await.unwrap();
+                    }
+                });
+            }
+        });
+
+        // trusting the server's certificate, the handshake succeeds for its name
+        let config = BackendConfig {
+            tls_ca: tls_config.cert.clone(),
+            ..BackendConfig::default()
+        };
+        let backend = TlsBackend::new(SingleHostBackend::new("localhost", port), &config).unwrap();
+        let mut stream = backend.connect("localhost", port).await.unwrap();
+        let mut buf = vec![];
+        stream.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"HELLO");
+
+        // but not for another name for the same server
+        let backend = TlsBackend::new(SingleHostBackend::new("127.0.0.1", port), &config).unwrap();
+        assert!(backend.connect("127.0.0.1", port).await.is_err());
+
+        // and the Mozilla root certificates do not include the server's certificate
+        let backend = TlsBackend::new(
+            SingleHostBackend::new("localhost", port),
+            &BackendConfig::default(),
+        )
+        .unwrap();
+        assert!(backend.connect("localhost", port).await.is_err());
+
+        let missing = BackendConfig {
+            tls_ca: Some(PathBuf::from("/nonexistent/ca.pem")),
+            ..BackendConfig::default()
+        };
+        assert!(TlsBackend::new(SingleHostBackend::new("localhost", port), &missing).is_err());
+
+        remove_files(&tls_config);
+    }
+}
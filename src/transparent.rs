@@ -0,0 +1,137 @@
+//! Transparent proxying: connections redirected to the proxy by the firewall arrive without
+//! a request, and are tunneled to the destination the client originally addressed.  With
+//! `REDIRECT` (or `DNAT`) rules, the kernel's connection tracking records that destination,
+//! which is read with `SO_ORIGINAL_DST`.  With `TPROXY` rules, the connection is accepted
+//! unchanged on a listener with `IP_TRANSPARENT`, so its local address is the destination.
+//! Linux only.
+
+use crate::config::{Config, TransparentMode};
+use crate::sockopt;
+use anyhow::{Context, Result};
+use std::io;
+use std::net::SocketAddr;
+use tokio::net::{TcpListener, TcpStream};
+
+/// Bind a listener for redirected connections on `addr`, with the configured backlog and
+/// buffer sizes.  For TPROXY, the listener is made transparent, so that it accepts
+/// connections addressed to other hosts.
+pub(crate) fn bind(
+    addr: SocketAddr,
+    mode: TransparentMode,
+    config: &Config,
+) -> Result<TcpListener> {
+    let socket = sockopt::socket(addr, &config.socket)?;
+    #[cfg(unix)]
+    socket.set_reuseaddr(true)?;
+    if mode == TransparentMode::Tproxy {
+        set_transparent(&socket, addr).with_context(|| {
+            format!(
+                "setting IP_TRANSPARENT on {} (requires CAP_NET_ADMIN)",
+                addr
+            )
+        })?;
+    }
+    socket
+        .bind(addr)
+        .with_context(|| format!("binding {}", addr))?;
+    Ok(socket.listen(config.backlog)?)
+}
+
+#[cfg(target_os = "linux")]
+fn set_transparent(socket: &tokio::net::TcpSocket, addr: SocketAddr) -> io::Result<()> {
+    let socket = socket2::SockRef::from(socket);
+    match addr {
+        SocketAddr::V4(_) => socket.set_ip_transparent_v4(true),
+        SocketAddr::V6(_) => socket.set_ip_transparent_v6(true),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_transparent(_socket: &tokio::net::TcpSocket, _addr: SocketAddr) -> io::Result<()> {
+    Err(unsupported())
+}
+
+/// The destination to which a connection accepted on `listener` was originally addressed.
+/// Connections made to the listener itself, rather than redirected to it, are refused, since
+/// tunneling them would connect the proxy to itself.
+pub(crate) fn original_dst(
+    socket: &TcpStream,
+    mode: TransparentMode,
+    listener: SocketAddr,
+) -> io::Result<SocketAddr> {
+    let dst = match mode {
+        TransparentMode::Redirect => redirected_dst(socket)?,
+        TransparentMode::Tproxy => socket.local_addr()?,
+    };
+    // a wildcard listener matches any local address on its port
+    let to_listener = dst.port() == listener.port()
+        && (dst.ip() == listener.ip() || listener.ip().is_unspecified());
+    if to_listener {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("connection to {} was not redirected", dst),
+        ));
+    }
+    Ok(dst)
+}
+
+#[cfg(target_os = "linux")]
+fn redirected_dst(socket: &TcpStream) -> io::Result<SocketAddr> {
+    let sock = socket2::SockRef::from(socket);
+    let dst = match socket.local_addr()? {
+        SocketAddr::V4(_) => sock.original_dst_v4()?,
+        SocketAddr::V6(_) => sock.original_dst_v6()?,
+    };
+    dst.as_socket().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "original destination is not an IP address",
+        )
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn redirected_dst(_socket: &TcpStream) -> io::Result<SocketAddr> {
+    Err(unsupported())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "transparent proxying is only supported on Linux",
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_not_redirected() {
+        let config = Config::default();
+        let listener = bind(
+            "127.0.0.1:0".parse().unwrap(),
+            TransparentMode::Redirect,
+            &config,
+        )
+        .unwrap();
+        let addr = listener.local_addr().unwrap();
+        let _client = TcpStream::connect(addr).await.unwrap();
+        let (socket, _) = listener.accept().await.unwrap();
+
+        // a direct connection has no NAT mapping, or is addressed to the listener itself
+        assert!(original_dst(&socket, TransparentMode::Redirect, addr).is_err());
+        let err = original_dst(&socket, TransparentMode::Tproxy, addr).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let wildcard = SocketAddr::new("0.0.0.0".parse().unwrap(), addr.port());
+        assert!(original_dst(&socket, TransparentMode::Tproxy, wildcard).is_err());
+
+        // a connection accepted on another address looks redirected to it
+        let other = SocketAddr::new(addr.ip(), addr.port().wrapping_add(1));
+        assert_eq!(
+            original_dst(&socket, TransparentMode::Tproxy, other).unwrap(),
+            addr
+        );
+    }
+}
@@ -0,0 +1,563 @@
+//! Relaying of UDP datagrams for SOCKS5 UDP ASSOCIATE
+//! ([RFC 1928, section 7](https://tools.ietf.org/html/rfc1928#section-7)), so that QUIC and
+//! other UDP traffic to allowed destinations can be proxied.  Each association binds a
+//! socket to which the client sends datagrams, each with a header naming its destination.
+//! Like a NAT, the relay keeps a flow for each destination, with a socket of its own
+//! connected to that destination, and relays the destination's replies back to the client
+//! with a header naming their source.  Flows are opened in the background, so that
+//! resolving one destination does not hold up datagrams to others, and destinations that
+//! are refused are remembered until the next expiry.  Flows unused for the idle timeout are
+//! forgotten, and the association ends when its control connection closes, or when no
+//! datagrams have passed in either direction for that long.
+
+use crate::access::{AccessRecord, Reason};
+use crate::backend::{allow_entries, check_allowed, resolve, AllowEntry, PortSet};
+use crate::config::{BackendConfig, UdpRelayConfig};
+use crate::connection::{supervise, ConnectionInfo, Direction, TunnelState};
+use crate::dns::Resolver;
+use crate::error::{IoContext, Result};
+use crate::http::authority;
+use crate::metrics::{ActiveTunnel, METRICS};
+use crate::registry::REGISTRY;
+use crate::socks::{self, Reply, ATYP_DOMAIN, ATYP_IPV4, ATYP_IPV6};
+use crate::telemetry::spawn;
+use anyhow::bail;
+use ipnet::IpNet;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+/// The largest datagram that can be relayed
+const MAX_DATAGRAM: usize = 65535;
+
+/// The most datagrams held for a destination while its flow is being opened
+const MAX_PENDING: usize = 8;
+
+/// A datagram received from a destination, with its source
+type Received = (SocketAddr, Vec<u8>);
+
+/// The destination of a flow, as the client named it
+type Destination = (String, u16);
+
+/// The outcome of opening a flow: the destination's address and a socket connected to it
+type Opened = (Destination, Result<(SocketAddr, UdpSocket)>);
+
+/// Handle a UDP ASSOCIATE request on the control connection `control`: bind a relay socket
+/// on the address on which the connection was accepted, tell the client its address, and
+/// relay datagrams until the association ends.  Datagrams are accepted only from the
+/// client's IP address, and from `port` if it is not zero; otherwise, the port from which
+/// the client sends its first datagram.
+pub(crate) async fn associate<S: AsyncRead + AsyncWrite + Unpin>(
+    mut control: S,
+    port: u16,
+    config: &UdpRelayConfig,
+    backend: &BackendConfig,
+    info: &ConnectionInfo,
+    record: &mut AccessRecord,
+) -> Result<()> {
+    let ip = info
+        .local
+        .map(|local| local.ip())
+        .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+    let client = match UdpSocket::bind(SocketAddr::new(ip, 0)).await {
+        Ok(client) => client,
+        Err(e) => {
+            let _ = socks::send_reply(&mut control, Reply::GeneralFailure).await;
+            return Err(e).io_context("binding UDP relay socket");
+        }
+    };
+    let relay_addr = client.local_addr().io_context("binding UDP relay socket")?;
+    socks::send_bound_reply(&mut control, relay_addr).await?;
+    METRICS.udp_associations.inc();
+    log::debug!("relaying UDP datagrams for {} on {}", info, relay_addr);
+
+    record.reason = Reason::Error;
+    let _active = ActiveTunnel::new();
+    let registration = REGISTRY.register(record);
+    let relay = Relay {
+        socket: client,
+        source: Source {
+            ip: info.peer.map(|peer| peer.ip()),
+            port: Some(port).filter(|port| *port != 0),
+        },
+        client: None,
+        policy: Arc::new(Policy::from_config(backend)),
+        config: config.clone(),
+        flows: HashMap::new(),
+        pending: HashMap::new(),
+        refused: HashMap::new(),
+    };
+    let transfer = relay.run(control, &registration.state);
+    let summary = supervise(&registration, transfer, config.idle_timeout).await;
+    summary.record(record);
+    Ok(())
+}
+
+/// The destinations to which datagrams may be relayed: those allowed by the backend
+/// configuration, as for TCP tunnels, resolving only to addresses that are not blocked
+struct Policy {
+    entries: Vec<AllowEntry>,
+    ports: PortSet,
+    blocked: Vec<IpNet>,
+    resolver: Resolver,
+}
+
+impl Policy {
+    fn from_config(config: &BackendConfig) -> Self {
+        Self {
+            entries: allow_entries(config),
+            ports: config.allowed_ports.clone(),
+            blocked: config.blocked_networks.clone(),
+            resolver: Resolver::from_config(&config.dns),
+        }
+    }
+
+    /// Check that datagrams may be sent to `host` and `port`, and resolve them
+    async fn resolve(&self, host: &str, port: u16) -> Result<SocketAddr> {
+        let allowed =
+            self.ports.contains(port) && self.entries.iter().any(|e| e.allows(host, port));
+        check_allowed(allowed, host, port)?;
+        Ok(resolve(&self.resolver, host, port, &self.blocked).await?[0])
+    }
+}
+
+/// Check that `host` and `port` are allowed, and open a socket connected to them
+async fn open(policy: &Policy, host: &str, port: u16) -> Result<(SocketAddr, UdpSocket)> {
+    let addr = policy.resolve(host, port).await?;
+    let bind: SocketAddr = match addr {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(bind)
+        .await
+        .io_context("binding UDP flow socket")?;
+    socket
+        .connect(addr)
+        .await
+        .with_io_context(|| format!("connecting UDP flow socket to {}", addr))?;
+    Ok((addr, socket))
+}
+
+/// Where the client's datagrams must come from
+struct Source {
+    ip: Option<IpAddr>,
+    port: Option<u16>,
+}
+
+impl Source {
+    /// Does a datagram from `from` come from the client?  If the client's port is not yet
+    /// known, it is taken from the first datagram from the client's IP address.
+    fn accepts(&mut self, from: SocketAddr) -> bool {
+        if self.ip.is_some_and(|ip| ip != from.ip()) {
+            return false;
+        }
+        match self.port {
+            Some(port) => port == from.port(),
+            None => {
+                self.port = Some(from.port());
+                true
+            }
+        }
+    }
+}
+
+/// The mapping from a destination to the socket from which datagrams are sent to it
+struct Flow {
+    addr: SocketAddr,
+    socket: Arc<UdpSocket>,
+    /// When a datagram last passed in either direction
+    last: Instant,
+    /// The task receiving the destination's replies
+    receiver: JoinHandle<()>,
+}
+
+impl Flow {
+    /// Send a datagram from the client to the destination
+    async fn send(&mut self, payload: &[u8], state: &TunnelState) {
+        self.last = Instant::now();
+        match self.socket.send(payload).await {
+            Ok(n) => {
+                state.touch();
+                state.transferred(Direction::Up, n as u64);
+                state.tap(Direction::Up, payload);
+            }
+            Err(e) => {
+                log::debug!("sending UDP datagram to {}: {}", self.addr, e);
+                METRICS.udp_datagrams_dropped.inc();
+            }
+        }
+    }
+}
+
+impl Drop for Flow {
+    fn drop(&mut self) {
+        self.receiver.abort();
+    }
+}
+
+/// The state of an association
+struct Relay {
+    /// The socket on which datagrams are exchanged with the client
+    socket: UdpSocket,
+    source: Source,
+    /// The client's address, once it has sent a datagram
+    client: Option<SocketAddr>,
+    policy: Arc<Policy>,
+    config: UdpRelayConfig,
+    /// The flows, by destination as the client named it
+    flows: HashMap<Destination, Flow>,
+    /// The datagrams for destinations whose flows are being opened
+    pending: HashMap<Destination, Vec<Vec<u8>>>,
+    /// Destinations refused, and when, to which datagrams are dropped without resolving
+    /// them again until they expire.  At most `config.max_flows` are remembered; others
+    /// are logged only at debug level.
+    refused: HashMap<Destination, Instant>,
+}
+
+impl Relay {
+    /// Relay datagrams until the control connection closes or fails, returning why
+    async fn run<S: AsyncRead + Unpin>(mut self, mut control: S, state: &TunnelState) -> Reason {
+        let (sender, mut received) = mpsc::channel::<Received>(64);
+        let (opening, mut opened) = mpsc::channel::<Opened>(16);
+        let mut buf = vec![0u8; MAX_DATAGRAM];
+        let mut control_buf = [0u8; 64];
+        let mut expire = tokio::time::interval(self.config.idle_timeout);
+        loop {
+            tokio::select! {
+                // the control connection carries nothing more, but the association lasts
+                // only as long as it is open
+                read = control.read(&mut control_buf) => match read {
+                    Ok(0) => return Reason::ClientClosed,
+                    Ok(_) => {}
+                    Err(e) => {
+                        log::warn!("reading UDP association control connection: {}", e);
+                        return Reason::Error;
+                    }
+                },
+                datagram = self.socket.recv_from(&mut buf) => match datagram {
+                    Ok((n, from)) => self.relay_up(&buf[..n], from, &opening, state).await,
+                    Err(e) => {
+                        log::warn!("receiving from UDP relay socket: {}", e);
+                        return Reason::Error;
+                    }
+                },
+                Some((from, payload)) = received.recv() => {
+                    self.relay_down(from, &payload, state).await;
+                }
+                Some((destination, result)) = opened.recv() => {
+                    self.opened(destination, result, &sender, state).await;
+                }
+                _ = expire.tick() => self.expire(Instant::now()),
+            }
+        }
+    }
+
+    /// Relay a datagram from `from`, if it is the client, to the destination named in its
+    /// header.  If there is no flow to the destination, one is opened in a task which
+    /// reports to `opening`, and the datagram is held until it is open.
+    async fn relay_up(
+        &mut self,
+        datagram: &[u8],
+        from: SocketAddr,
+        opening: &mpsc::Sender<Opened>,
+        state: &TunnelState,
+    ) {
+        if !self.source.accepts(from) {
+            log::debug!(
+                "dropping UDP datagram from {}, which is not the client",
+                from
+            );
+            METRICS.udp_datagrams_dropped.inc();
+            return;
+        }
+        self.client = Some(from);
+        let (host, port, payload) = match parse(datagram) {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                log::debug!("dropping UDP datagram from {}: {}", from, e);
+                METRICS.udp_datagrams_dropped.inc();
+                return;
+            }
+        };
+
+        let destination = (host, port);
+        if let Some(flow) = self.flows.get_mut(&destination) {
+            flow.send(payload, state).await;
+            return;
+        }
+        let drop_reason = if self.refused.contains_key(&destination) {
+            Some("destination was refused")
+        } else if let Some(queued) = self.pending.get_mut(&destination) {
+            if queued.len() < MAX_PENDING {
+                queued.push(payload.to_vec());
+                return;
+            }
+            Some("flow is still opening")
+        } else if self.flows.len() + self.pending.len() >= self.config.max_flows {
+            Some("too many flows")
+        } else {
+            None
+        };
+        if let Some(reason) = drop_reason {
+            log::debug!(
+                "dropping UDP datagram to {}: {}",
+                authority(&destination.0, port),
+                reason
+            );
+            METRICS.udp_datagrams_dropped.inc();
+            return;
+        }
+
+        self.pending
+            .insert(destination.clone(), vec![payload.to_vec()]);
+        let policy = self.policy.clone();
+        let opening = opening.clone();
+        spawn("udp-open", async move {
+            let result = open(&policy, &destination.0, destination.1).await;
+            // the association may have ended in the meantime
+            let _ = opening.send((destination, result)).await;
+        });
+    }
+
+    /// Finish opening a flow to `destination`, passing the datagrams it receives to
+    /// `sender`, and send the datagrams held for it; or, if it was refused, drop them and
+    /// remember the refusal
+    async fn opened(
+        &mut self,
+        destination: Destination,
+        result: Result<(SocketAddr, UdpSocket)>,
+        sender: &mpsc::Sender<Received>,
+        state: &TunnelState,
+    ) {
+        let queued = self.pending.remove(&destination).unwrap_or_default();
+        match result {
+            Ok((addr, socket)) => {
+                log::debug!("opened UDP flow to {}", addr);
+                let socket = Arc::new(socket);
+                let receiver = spawn("udp-flow", receive(socket.clone(), addr, sender.clone()));
+                let mut flow = Flow {
+                    addr,
+                    socket,
+                    last: Instant::now(),
+                    receiver,
+                };
+                for payload in queued {
+                    flow.send(&payload, state).await;
+                }
+                self.flows.insert(destination, flow);
+            }
+            Err(e) => {
+                let name = authority(&destination.0, destination.1);
+                if self.refused.len() < self.config.max_flows {
+                    log::warn!("not relaying UDP datagrams to {}: {}", name, e);
+                    self.refused.insert(destination, Instant::now());
+                } else {
+                    log::debug!("dropping UDP datagrams to {}: {}", name, e);
+                }
+                METRICS.udp_datagrams_dropped.add(queued.len() as u64);
+            }
+        }
+    }
+
+    /// Relay a datagram received from `from` to the client
+    async fn relay_down(&mut self, from: SocketAddr, payload: &[u8], state: &TunnelState) {
+        let client = match self.client {
+            Some(client) => client,
+            None => return,
+        };
+        if let Some(flow) = self.flows.values_mut().find(|flow| flow.addr == from) {
+            flow.last = Instant::now();
+        }
+        match self.socket.send_to(&encode(from, payload), client).await {
+            Ok(_) => {
+                state.touch();
+                state.transferred(Direction::Down, payload.len() as u64);
+                state.tap(Direction::Down, payload);
+            }
+            Err(e) => {
+                log::debug!("sending UDP datagram to {}: {}", client, e);
+                METRICS.udp_datagrams_dropped.inc();
+            }
+        }
+    }
+
+    /// Forget the flows that have been idle for the idle timeout at `now`, and the
+    /// destinations refused that long ago, so that they are tried again
+    fn expire(&mut self, now: Instant) {
+        let idle_timeout = self.config.idle_timeout;
+        self.refused
+            .retain(|_, refused| now.duration_since(*refused) < idle_timeout);
+        self.flows.retain(|_, flow| {
+            let idle = now.duration_since(flow.last) >= idle_timeout;
+            if idle {
+                log::debug!("closing idle UDP flow to {}", flow.addr);
+            }
+            !idle
+        });
+    }
+}
+
+/// Pass each datagram received on a flow's socket, connected to `addr`, to `sender`, until
+/// the association is gone
+async fn receive(socket: Arc<UdpSocket>, addr: SocketAddr, sender: mpsc::Sender<Received>) {
+    let mut buf = vec![0u8; MAX_DATAGRAM];
+    loop {
+        match socket.recv(&mut buf).await {
+            Ok(n) => {
+                if sender.send((addr, buf[..n].to_vec())).await.is_err() {
+                    return;
+                }
+            }
+            // connected sockets report ICMP errors, such as port unreachable, which the
+            // client would not hear about from a NAT either
+            Err(e) => log::debug!("receiving from {}: {}", addr, e),
+        }
+    }
+}
+
+/// Parse a datagram from the client, returning its destination and payload
+fn parse(datagram: &[u8]) -> anyhow::Result<(String, u16, &[u8])> {
+    if datagram.len() < 4 {
+        bail!("datagram is too short");
+    }
+    if datagram[2] != 0 {
+        bail!("fragmented datagrams are not supported");
+    }
+    let rest = &datagram[4..];
+    let (host, rest) = match datagram[3] {
+        ATYP_IPV4 if rest.len() >= 4 => {
+            let mut ip = [0u8; 4];
+            ip.copy_from_slice(&rest[..4]);
+            (Ipv4Addr::from(ip).to_string(), &rest[4..])
+        }
+        ATYP_IPV6 if rest.len() >= 16 => {
+            let mut ip = [0u8; 16];
+            ip.copy_from_slice(&rest[..16]);
+            (Ipv6Addr::from(ip).to_string(), &rest[16..])
+        }
+        ATYP_DOMAIN if !rest.is_empty() && rest.len() > rest[0] as usize => {
+            let len = rest[0] as usize;
+            match String::from_utf8(rest[1..=len].to_vec()) {
+                Ok(name) => (name, &rest[1 + len..]),
+                Err(_) => bail!("domain name is not valid UTF-8"),
+            }
+        }
+        ATYP_IPV4 | ATYP_IPV6 | ATYP_DOMAIN => bail!("datagram is too short"),
+        atyp => bail!("unsupported address type {}", atyp),
+    };
+    if rest.len() < 2 {
+        bail!("datagram is too short");
+    }
+    let port = u16::from_be_bytes([rest[0], rest[1]]);
+    Ok((host, port, &rest[2..]))
+}
+
+/// Wrap a datagram from `from` in a header for the client
+fn encode(from: SocketAddr, payload: &[u8]) -> Vec<u8> {
+    let mut datagram = vec![0, 0, 0];
+    datagram.extend_from_slice(&socks::encode_addr(from));
+    datagram.extend_from_slice(payload);
+    datagram
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::{duplex, AsyncReadExt};
+
+    #[test]
+    fn test_parse() {
+        let (host, port, payload) = parse(b"\0\0\0\x01\xc0\x00\x02\x01\x01\xbbping").unwrap();
+        assert_eq!(
+            (host.as_str(), port, payload),
+            ("192.0.2.1", 443, &b"ping"[..])
+        );
+        let (host, port, payload) = parse(b"\0\0\0\x03\x03foo\x00\x35").unwrap();
+        assert_eq!((host.as_str(), port, payload), ("foo", 53, &b""[..]));
+
+        assert!(parse(b"\0\0\x01\x03\x03foo\x00\x35").is_err());
+        assert!(parse(b"\0\0\0\x03\x03fo").is_err());
+        assert!(parse(b"\0\0\0\x02\x03foo\x00\x35").is_err());
+    }
+
+    #[test]
+    fn test_encode() {
+        let from = "[2001:db8::1]:443".parse().unwrap();
+        let datagram = encode(from, b"pong");
+        assert_eq!(&datagram[..4], b"\0\0\0\x04");
+        assert_eq!(parse(&datagram).unwrap().2, b"pong");
+    }
+
+    #[test]
+    fn test_source() {
+        let mut source = Source {
+            ip: Some("127.0.0.1".parse().unwrap()),
+            port: None,
+        };
+        assert!(!source.accepts("127.0.0.2:5000".parse().unwrap()));
+        assert!(source.accepts("127.0.0.1:5000".parse().unwrap()));
+        assert!(!source.accepts("127.0.0.1:5001".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_associate() {
+        // a UDP echo server, as the only allowed destination
+        let echo = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let echo_addr = echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+            loop {
+                let (n, from) = echo.recv_from(&mut buf).await.unwrap();
+                echo.send_to(&buf[..n], from).await.unwrap();
+            }
+        });
+        let backend = BackendConfig {
+            host: "127.0.0.1".into(),
+            port: echo_addr.port(),
+            blocked_networks: vec![],
+            ..BackendConfig::default()
+        };
+
+        let (mut control, server) = duplex(1024);
+        let relay = tokio::spawn(async move {
+            let mut record = AccessRecord::new(None);
+            let config = UdpRelayConfig::default();
+            let info = ConnectionInfo::default();
+            associate(server, 0, &config, &backend, &info, &mut record)
+                .await
+                .unwrap();
+            record
+        });
+
+        // the reply gives the relay's port
+        let mut reply = [0u8; 10];
+        control.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply[..4], b"\x05\x00\x00\x01");
+        let relay_port = u16::from_be_bytes([reply[8], reply[9]]);
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect(("127.0.0.1", relay_port)).await.unwrap();
+        // a disallowed destination is dropped, and an allowed one echoes
+        let disallowed = encode("127.0.0.1:1".parse().unwrap(), b"dropped");
+        client.send(&disallowed).await.unwrap();
+        // datagrams sent while the flow opens are held until it is open
+        client.send(&encode(echo_addr, b"ping")).await.unwrap();
+        client.send(&encode(echo_addr, b"pong")).await.unwrap();
+        let mut buf = [0u8; 1024];
+        let n = client.recv(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], &encode(echo_addr, b"ping")[..]);
+        let n = client.recv(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], &encode(echo_addr, b"pong")[..]);
+
+        // closing the control connection ends the association
+        drop(control);
+        let record = relay.await.unwrap();
+        assert_eq!(record.reason, Reason::ClientClosed);
+        assert_eq!((record.bytes_up, record.bytes_down), (8, 8));
+    }
+}
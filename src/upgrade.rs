@@ -0,0 +1,401 @@
+//! Zero-downtime upgrades.  The running process starts a new one from the (possibly
+//! replaced) binary, with the same arguments, handing over its listening sockets as
+//! inherited file descriptors described by an environment variable.  Once the new process
+//! has started, it reports that it is ready through a pipe, and the old process stops
+//! accepting connections, leaving them to the new process, and exits when its open tunnels
+//! have closed.  Both processes accept from the same sockets, so no connection is refused
+//! along the way.  Unix only.
+//!
+//! Sockets are handed over for the `listen`, `transparent.listen`, `reverse.listen`, and
+//! `admin.listen` addresses, keyed by the configured address, so the new process binds
+//! any addresses added to its configuration.  Sockets passed by systemd and the HTTP/3
+//! listeners are not handed over; the new process cannot bind their addresses, so it
+//! fails to start, and the old process carries on.
+
+use anyhow::{bail, Context, Result};
+use std::net::SocketAddr;
+use tokio::net::TcpListener;
+use tokio::sync::SetOnce;
+
+/// The environment variable describing the inherited sockets, as `addr=fd,fd;addr=fd`
+const INHERITED_ENV: &str = "GIPHYPROXY_INHERITED_FDS";
+
+/// The environment variable giving the file descriptor to which the new process writes
+/// once it is ready
+const READY_ENV: &str = "GIPHYPROXY_UPGRADE_READY_FD";
+
+/// Set once the listening sockets have been handed over to a new process
+static HANDED_OVER: SetOnce<()> = SetOnce::const_new();
+
+/// Wait until the listening sockets have been handed over to a new process, after which
+/// this process should accept no more connections
+pub async fn handed_over() {
+    HANDED_OVER.wait().await;
+}
+
+/// What a process started by an upgrade is passed by its predecessor, through the
+/// environment
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Inherited {
+    /// The listening sockets' file descriptors, by configured address
+    sockets: Vec<(SocketAddr, Vec<i32>)>,
+    /// The file descriptor to write to once ready
+    ready_fd: Option<i32>,
+}
+
+impl Inherited {
+    /// Read, and remove from the environment, what the previous process passed to this one.
+    /// This must be called while the process has a single thread, before the async runtime
+    /// is built, as other threads may read the environment at any time.
+    pub fn from_env() -> Result<Self> {
+        let mut inherited = Inherited::default();
+        if let Some(value) = std::env::var_os(INHERITED_ENV) {
+            std::env::remove_var(INHERITED_ENV);
+            let value = value
+                .into_string()
+                .map_err(|v| anyhow::anyhow!("invalid {} {:?}", INHERITED_ENV, v))?;
+            inherited.sockets = parse_inherited(&value)?;
+        }
+        if let Some(value) = std::env::var_os(READY_ENV) {
+            std::env::remove_var(READY_ENV);
+            inherited.ready_fd = match value.to_str().map(str::parse) {
+                Some(Ok(fd)) if fd >= 0 => Some(fd),
+                _ => bail!("invalid {} {:?}", READY_ENV, value),
+            };
+        }
+        Ok(inherited)
+    }
+
+    /// Whether this process was started by an upgrade, to take over from a running process
+    pub fn started_by_upgrade(&self) -> bool {
+        self.ready_fd.is_some()
+    }
+}
+
+/// Parse the value of `GIPHYPROXY_INHERITED_FDS`
+fn parse_inherited(value: &str) -> Result<Vec<(SocketAddr, Vec<i32>)>> {
+    let mut inherited = vec![];
+    for entry in value.split(';').filter(|e| !e.is_empty()) {
+        let (addr, fds) = entry
+            .rsplit_once('=')
+            .with_context(|| format!("invalid inherited socket {:?}", entry))?;
+        let addr = addr
+            .parse()
+            .with_context(|| format!("invalid inherited socket address {:?}", addr))?;
+        let fds = fds
+            .split(',')
+            .map(|fd| match fd.parse() {
+                Ok(fd) if fd >= 0 => Ok(fd),
+                _ => bail!("invalid inherited file descriptor {:?}", fd),
+            })
+            .collect::<Result<_>>()?;
+        inherited.push((addr, fds));
+    }
+    Ok(inherited)
+}
+
+/// Format a value for `GIPHYPROXY_INHERITED_FDS`
+#[cfg_attr(not(unix), allow(dead_code))]
+fn format_inherited(sockets: &[(SocketAddr, Vec<i32>)]) -> String {
+    let entries: Vec<String> = sockets
+        .iter()
+        .map(|(addr, fds)| {
+            let fds: Vec<String> = fds.iter().map(|fd| fd.to_string()).collect();
+            format!("{}={}", addr, fds.join(","))
+        })
+        .collect();
+    entries.join(";")
+}
+
+#[cfg(unix)]
+mod imp {
+    use super::*;
+    use crate::sandbox;
+    use std::collections::BTreeMap;
+    use std::io::{Read, Write};
+    use std::os::fd::{AsFd, AsRawFd, FromRawFd, OwnedFd, RawFd};
+    use std::os::unix::process::CommandExt;
+    use std::path::PathBuf;
+    use std::process::{Child, Command};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    /// How long the new process may take to report that it is ready
+    const READY_TIMEOUT: Duration = Duration::from_secs(30);
+
+    /// The sockets this process would hand over, by configured address
+    static LISTENERS: Mutex<Vec<(SocketAddr, Vec<OwnedFd>)>> = Mutex::new(vec![]);
+
+    /// The sockets inherited from the previous process and not yet taken
+    static INHERITED: Mutex<BTreeMap<SocketAddr, Vec<OwnedFd>>> = Mutex::new(BTreeMap::new());
+
+    /// The pipe to the previous process, until this one is ready
+    static READY: Mutex<Option<OwnedFd>> = Mutex::new(None);
+
+    /// Set while an upgrade is in progress
+    static UPGRADING: AtomicBool = AtomicBool::new(false);
+
+    /// Take ownership of the descriptors the previous process passed to this one, for
+    /// `listeners` and `notify_ready`.  This must be called at most once, with the result
+    /// of `Inherited::from_env`.
+    pub fn inherit(inherited: Inherited) {
+        // SAFETY: the previous process passes these descriptors to this one, and
+        // `Inherited::from_env` removes them from the environment, so nothing else takes
+        // ownership of them
+        let sockets = inherited.sockets.into_iter().map(|(addr, fds)| {
+            let fds = fds
+                .into_iter()
+                .map(|fd| unsafe { OwnedFd::from_raw_fd(fd) })
+                .collect();
+            (addr, fds)
+        });
+        INHERITED.lock().unwrap().extend(sockets);
+        // SAFETY: as above
+        *READY.lock().unwrap() = inherited
+            .ready_fd
+            .map(|fd| unsafe { OwnedFd::from_raw_fd(fd) });
+    }
+
+    /// Take the sockets for `addr` inherited from the previous process, if any
+    fn take_inherited(addr: SocketAddr) -> Result<Vec<std::net::TcpListener>> {
+        let fds = INHERITED.lock().unwrap().remove(&addr).unwrap_or_default();
+        let mut listeners = vec![];
+        for fd in fds {
+            // so that the descriptor is not passed on to processes this one starts
+            // SAFETY: the descriptor is owned, and so open
+            if unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC) } < 0 {
+                return Err(std::io::Error::last_os_error())
+                    .with_context(|| format!("socket inherited for {}", addr));
+            }
+            let listener = std::net::TcpListener::from(fd);
+            // this fails if the descriptor is not a socket
+            listener
+                .local_addr()
+                .with_context(|| format!("socket inherited for {}", addr))?;
+            listeners.push(listener);
+        }
+        Ok(listeners)
+    }
+
+    /// The listening sockets for the configured address `addr`: those inherited from the
+    /// previous process, if it handed any over, or otherwise those returned by `bind`.
+    /// Either way, copies are kept to hand over to the next process.
+    pub(crate) fn listeners<F>(addr: SocketAddr, bind: F) -> Result<Vec<TcpListener>>
+    where
+        F: FnOnce() -> Result<Vec<TcpListener>>,
+    {
+        let inherited = take_inherited(addr)?;
+        let listeners = if inherited.is_empty() {
+            bind()?
+        } else {
+            log::info!("Using {} sockets inherited for {}", inherited.len(), addr);
+            let mut listeners = vec![];
+            for listener in inherited {
+                listener.set_nonblocking(true)?;
+                listeners.push(TcpListener::from_std(listener)?);
+            }
+            listeners
+        };
+        let fds = listeners
+            .iter()
+            .map(|listener| listener.as_fd().try_clone_to_owned())
+            .collect::<std::io::Result<_>>()?;
+        LISTENERS.lock().unwrap().push((addr, fds));
+        Ok(listeners)
+    }
+
+    /// Tell the previous process, if this one was started by an upgrade, that it is ready
+    /// to accept connections.  Any inherited sockets whose addresses are no longer
+    /// configured are closed.
+    pub fn notify_ready() -> Result<()> {
+        for addr in std::mem::take(&mut *INHERITED.lock().unwrap()).into_keys() {
+            log::info!(
+                "Closing socket inherited for {}, no longer configured",
+                addr
+            );
+        }
+        let Some(fd) = READY.lock().unwrap().take() else {
+            return Ok(());
+        };
+        let mut ready = std::fs::File::from(fd);
+        ready
+            .write_all(b"1")
+            .context("notifying the previous process")?;
+        Ok(())
+    }
+
+    /// The binary to run for the new process.  On Linux, the running binary's path has
+    /// " (deleted)" appended once it has been replaced.
+    fn binary() -> Result<PathBuf> {
+        let exe = std::env::current_exe().context("finding the running binary")?;
+        match exe.to_str().and_then(|e| e.strip_suffix(" (deleted)")) {
+            Some(exe) => Ok(exe.into()),
+            None => Ok(exe),
+        }
+    }
+
+    /// Start a new process from the binary, with the same arguments, handing over the
+    /// listening sockets, and wait for it to report that it is ready.  Once it has, this
+    /// process stops accepting connections.  Returns the new process's PID.  If the new
+    /// process fails to start, it is killed, and this process carries on.
+    pub async fn upgrade() -> Result<u32> {
+        if sandbox::prevents_exec() {
+            // the new process could not be started, as the binary is hidden or exec is
+            // refused
+            bail!("upgrades are not possible with sandbox.chroot or sandbox.seccomp");
+        }
+        if HANDED_OVER.initialized() {
+            bail!("the listening sockets have already been handed over");
+        }
+        if UPGRADING.swap(true, Ordering::SeqCst) {
+            bail!("an upgrade is already in progress");
+        }
+        let result = start().await;
+        UPGRADING.store(false, Ordering::SeqCst);
+        let pid = result?;
+        let _ = HANDED_OVER.set(());
+        log::info!(
+            "handed listening sockets over to process {}; no longer accepting connections",
+            pid
+        );
+        Ok(pid)
+    }
+
+    /// Start the new process and wait for it to report that it is ready.  Quota usage is
+    /// saved first, so that the new process begins with it.
+    async fn start() -> Result<u32> {
+        crate::quota::save();
+        let (mut reader, writer) = std::io::pipe().context("creating pipe")?;
+        let (inherited, mut fds) = {
+            let listeners = LISTENERS.lock().unwrap();
+            let sockets: Vec<(SocketAddr, Vec<RawFd>)> = listeners
+                .iter()
+                .map(|(addr, fds)| (*addr, fds.iter().map(|fd| fd.as_raw_fd()).collect()))
+                .collect();
+            let fds: Vec<RawFd> = sockets.iter().flat_map(|(_, fds)| fds.clone()).collect();
+            (format_inherited(&sockets), fds)
+        };
+        fds.push(writer.as_raw_fd());
+
+        let binary = binary()?;
+        let mut command = Command::new(&binary);
+        command
+            .args(std::env::args_os().skip(1))
+            .env(INHERITED_ENV, inherited)
+            .env(READY_ENV, writer.as_raw_fd().to_string());
+        // SAFETY: fcntl is async-signal-safe, and the descriptors remain open in the
+        // child until it execs
+        unsafe {
+            command.pre_exec(move || {
+                for &fd in &fds {
+                    if libc::fcntl(fd, libc::F_SETFD, 0) < 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                }
+                Ok(())
+            });
+        }
+        let child = command
+            .spawn()
+            .with_context(|| format!("starting {}", binary.display()))?;
+        // the read sees end-of-file if the new process exits without reporting ready
+        drop(writer);
+        let pid = child.id();
+        log::info!("started process {} from {}", pid, binary.display());
+
+        let ready = tokio::task::spawn_blocking(move || {
+            let mut buf = [0u8; 1];
+            reader.read(&mut buf)
+        });
+        match tokio::time::timeout(READY_TIMEOUT, ready).await {
+            Ok(Ok(Ok(1))) => Ok(pid),
+            Ok(Ok(Ok(_))) => abandon(child, "exited before it was ready"),
+            Ok(Ok(Err(e))) => abandon(child, &format!("could not be read from: {}", e)),
+            Ok(Err(e)) => abandon(child, &format!("could not be waited for: {}", e)),
+            Err(_) => abandon(child, "did not become ready in time"),
+        }
+    }
+
+    /// Kill a new process which failed to start
+    fn abandon(mut child: Child, why: &str) -> Result<u32> {
+        let pid = child.id();
+        let _ = child.kill();
+        let _ = child.wait();
+        bail!("new process {} {}", pid, why)
+    }
+}
+
+#[cfg(not(unix))]
+mod imp {
+    use super::*;
+
+    /// Sockets are never inherited on this platform
+    pub fn inherit(_inherited: Inherited) {}
+
+    /// Sockets are never inherited on this platform, so they are always bound
+    pub(crate) fn listeners<F>(_addr: SocketAddr, bind: F) -> Result<Vec<TcpListener>>
+    where
+        F: FnOnce() -> Result<Vec<TcpListener>>,
+    {
+        bind()
+    }
+
+    /// Upgrades are not supported on this platform
+    pub fn notify_ready() -> Result<()> {
+        Ok(())
+    }
+
+    /// Upgrades are not supported on this platform
+    pub async fn upgrade() -> Result<u32> {
+        bail!("upgrades are not supported on this platform")
+    }
+}
+
+pub(crate) use imp::listeners;
+pub use imp::{inherit, notify_ready, upgrade};
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_inherited() {
+        let sockets = vec![
+            ("127.0.0.1:8080".parse().unwrap(), vec![5, 6]),
+            ("[::1]:9090".parse().unwrap(), vec![7]),
+        ];
+        let value = format_inherited(&sockets);
+        assert_eq!(value, "127.0.0.1:8080=5,6;[::1]:9090=7");
+        assert_eq!(parse_inherited(&value).unwrap(), sockets);
+        assert_eq!(parse_inherited("").unwrap(), vec![]);
+
+        assert!(parse_inherited("127.0.0.1:8080").is_err());
+        assert!(parse_inherited("localhost=3").is_err());
+        assert!(parse_inherited("127.0.0.1:8080=-1").is_err());
+        assert!(parse_inherited("127.0.0.1:8080=").is_err());
+    }
+
+    #[test]
+    fn test_started_by_upgrade() {
+        assert!(!Inherited::default().started_by_upgrade());
+        let inherited = Inherited {
+            ready_fd: Some(3),
+            ..Inherited::default()
+        };
+        assert!(inherited.started_by_upgrade());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_listeners() {
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let listeners = listeners(addr, || {
+            let listener = std::net::TcpListener::bind(addr)?;
+            listener.set_nonblocking(true)?;
+            Ok(vec![TcpListener::from_std(listener)?])
+        })
+        .unwrap();
+        assert_eq!(listeners.len(), 1);
+    }
+}
@@ -0,0 +1,566 @@
+//! WebSocket tunnels (RFC 6455), for clients that cannot send CONNECT, such as browsers, or
+//! those behind firewalls that block it.  A client requests a tunnel with a WebSocket
+//! upgrade request for the configured path, naming the destination in the query, as in
+//! `GET /tunnel?host=api.giphy.com&port=443 HTTP/1.1`.  Once the proxy has connected to the
+//! backend and answered `101 Switching Protocols`, the tunnel's data is carried in binary
+//! frames in both directions.  The frames are translated to and from a plain byte stream
+//! through an in-memory pipe, so the tunnel is otherwise handled like any other.
+//!
+//! Browsers send the origin of the page opening a WebSocket, which must be one of
+//! `websocket.allowed_origins`, so that other pages cannot use the proxy.  Nor can
+//! browsers send `Proxy-Authorization`, so when authentication is required, credentials
+//! are also accepted in `Authorization`, or as the `token` query parameter: the URL-safe
+//! base64 encoding of `user:password`.
+
+use crate::config::WebSocketConfig;
+use crate::error::{ProxyError, Result};
+use crate::http::{dns_name, parse_origin, Headers, ParseOptions, Response};
+use anyhow::bail;
+use base64::Engine;
+use sha1::{Digest, Sha1};
+use std::io;
+use std::net::Ipv6Addr;
+use std::time::Duration;
+use tokio::io::{
+    split, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadHalf, WriteHalf,
+};
+use tokio::sync::mpsc;
+use tokio::time::timeout;
+
+/// Appended to the client's key to compute `Sec-WebSocket-Accept`
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xa;
+
+const CLOSE_NORMAL: u16 = 1000;
+const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+const CLOSE_UNSUPPORTED_DATA: u16 = 1003;
+
+/// Size of the buffer for copying frame payloads, and of the pipe to the tunnel
+pub(crate) const BUFFER: usize = 16384;
+
+/// How long to wait for the client to answer a close frame the proxy sent
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Whether a request head, recognized by `parse_head` as `OtherMethod`, is a `GET` of the
+/// configured tunnel path, with any query
+pub(crate) fn is_tunnel_request(
+    head: &[u8],
+    options: &ParseOptions,
+    config: &WebSocketConfig,
+) -> bool {
+    match parse_origin(head, options) {
+        Ok(request) => {
+            let path = request.path.split('?').next().unwrap_or_default();
+            request.method == "GET" && path == config.path
+        }
+        Err(_) => false,
+    }
+}
+
+/// Parse a WebSocket upgrade request for a tunnel, returning the destination host and port
+/// given in its query, and its version and headers.  The port defaults to
+/// `options.default_port`.  Requests from origins not allowed by `config` are refused.
+/// Credentials given in `Authorization` or the query's `token` are returned as
+/// `Proxy-Authorization`, unless the request has that header itself.
+pub(crate) fn parse_upgrade(
+    head: &[u8],
+    options: &ParseOptions,
+    config: &WebSocketConfig,
+) -> Result<(String, u16, String, Headers)> {
+    let request = parse_origin(head, options)?;
+    let invalid = |why: &str| ProxyError::Parse(format!("invalid WebSocket request: {}", why));
+    if request.version != "HTTP/1.1" {
+        return Err(invalid("HTTP/1.1 is required"));
+    }
+    let mut headers = request.headers;
+    let has_token = |name: &str, token: &str| {
+        headers.get(name).is_some_and(|value| {
+            value
+                .split(',')
+                .any(|t| t.trim().eq_ignore_ascii_case(token))
+        })
+    };
+    if !has_token("Upgrade", "websocket") || !has_token("Connection", "upgrade") {
+        return Err(invalid("not an upgrade to websocket"));
+    }
+    if headers.get("Sec-WebSocket-Version") != Some("13") {
+        return Err(invalid("unsupported Sec-WebSocket-Version"));
+    }
+    let key = headers
+        .get("Sec-WebSocket-Key")
+        .and_then(|key| base64::engine::general_purpose::STANDARD.decode(key).ok());
+    if key.is_none_or(|key| key.len() != 16) {
+        return Err(invalid("missing or invalid Sec-WebSocket-Key"));
+    }
+    if let Some(origin) = headers.get("Origin") {
+        let origin = origin.trim().trim_end_matches('/');
+        let allowed = config
+            .allowed_origins
+            .iter()
+            .any(|allowed| allowed.trim_end_matches('/').eq_ignore_ascii_case(origin));
+        if !allowed {
+            return Err(invalid("origin not allowed"));
+        }
+    }
+
+    let query = request.path.split_once('?').map_or("", |(_, query)| query);
+    let mut host = None;
+    let mut port = options.default_port;
+    let mut token = None;
+    for (name, value) in query.split('&').filter_map(|param| param.split_once('=')) {
+        match name {
+            "host" => host = Some(value),
+            "port" => port = value.parse().map_err(|_| invalid("invalid port"))?,
+            "token" => token = Some(value),
+            _ => (),
+        }
+    }
+    if headers.get("Proxy-Authorization").is_none() {
+        let credentials = match (headers.get("Authorization"), token) {
+            (Some(value), _) => Some(value.to_owned()),
+            (None, Some(token)) => {
+                let decoded = base64::engine::general_purpose::URL_SAFE_NO_PAD
+                    .decode(token.trim_end_matches('='))
+                    .map_err(|_| invalid("invalid token"))?;
+                let encoded = base64::engine::general_purpose::STANDARD.encode(decoded);
+                Some(format!("Basic {}", encoded))
+            }
+            (None, None) => None,
+        };
+        if let Some(credentials) = credentials {
+            headers.set("Proxy-Authorization", credentials);
+        }
+    }
+    let host = match host {
+        Some(host) if host.contains(':') => host
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse::<Ipv6Addr>()
+            .map_err(|_| invalid("invalid host"))?
+            .to_string(),
+        Some(host) => dns_name(host.to_owned(), options.strict_hostnames)?,
+        None => return Err(invalid("no host in query")),
+    };
+    if port == 0 {
+        return Err(invalid("invalid port"));
+    }
+    Ok((host, port, request.version, headers))
+}
+
+/// The `101 Switching Protocols` response accepting an upgrade request with the given
+/// headers, which `parse_upgrade` has checked
+pub(crate) fn switching_protocols(headers: &Headers) -> Response {
+    let key = headers.get("Sec-WebSocket-Key").unwrap_or_default();
+    Response::new(101, "Switching Protocols")
+        .header("Upgrade", "websocket")
+        .header("Connection", "Upgrade")
+        .header("Sec-WebSocket-Accept", accept_key(key))
+}
+
+/// The `Sec-WebSocket-Accept` value for a client's `Sec-WebSocket-Key`
+fn accept_key(key: &str) -> String {
+    let mut sha1 = Sha1::new();
+    sha1.update(key.as_bytes());
+    sha1.update(GUID.as_bytes());
+    base64::engine::general_purpose::STANDARD.encode(sha1.finalize())
+}
+
+/// Relay between a WebSocket client on `socket` and `pipe`, writing the payloads of the
+/// client's binary frames to the pipe, and sending data read from the pipe as binary
+/// frames, until the connection is closed.
+pub(crate) async fn relay<S: AsyncRead + AsyncWrite + Unpin>(socket: S, pipe: DuplexStream) {
+    let (mut reader, mut writer) = split(socket);
+    let (mut pipe_read, mut pipe_write) = split(pipe);
+    // control frames answering the client's, sent between data frames
+    let (control, mut replies) = mpsc::channel(4);
+
+    let (up, down) = {
+        let up = async {
+            let result = read_frames(&mut reader, &mut pipe_write, &control).await;
+            drop(control);
+            let _ = pipe_write.shutdown().await;
+            result
+        };
+        let down = write_frames(&mut writer, &mut pipe_read, &mut replies);
+        tokio::pin!(up, down);
+        tokio::select! {
+            // the client closed first, and `down` sends the reply
+            up_result = &mut up => (up_result, down.await),
+            // the proxy closed first, and the client should reply promptly
+            down_result = &mut down => match timeout(CLOSE_TIMEOUT, up).await {
+                Ok(up_result) => (up_result, down_result),
+                Err(_) => (Ok(()), down_result),
+            },
+        }
+    };
+    if let Err(e) = up {
+        log::debug!("reading from WebSocket client: {}", e);
+    }
+    if let Err(e) = down {
+        log::debug!("writing to WebSocket client: {}", e);
+    }
+    let _ = writer.shutdown().await;
+}
+
+/// The header of a frame from the client
+struct FrameHeader {
+    fin: bool,
+    opcode: u8,
+    len: u64,
+    mask: [u8; 4],
+}
+
+/// Read the next frame header, or `None` at the end of the input
+async fn read_header<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Option<FrameHeader>> {
+    let mut head = [0u8; 2];
+    match reader.read_exact(&mut head).await {
+        Ok(_) => (),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let len = match head[1] & 0x7f {
+        126 => reader.read_u16().await? as u64,
+        127 => reader.read_u64().await?,
+        len => len as u64,
+    };
+    // clients must mask every frame
+    if head[1] & 0x80 == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "unmasked frame from client",
+        ));
+    }
+    let mut mask = [0u8; 4];
+    reader.read_exact(&mut mask).await?;
+    Ok(Some(FrameHeader {
+        fin: head[0] & 0x80 != 0,
+        opcode: head[0] & 0x0f,
+        len,
+        mask,
+    }))
+}
+
+/// Unmask part of a payload, beginning at `offset` within it
+fn unmask(data: &mut [u8], mask: [u8; 4], offset: u64) {
+    for (i, byte) in data.iter_mut().enumerate() {
+        *byte ^= mask[((offset + i as u64) % 4) as usize];
+    }
+}
+
+/// Encode a frame from the proxy, which is never masked or fragmented
+fn encode(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + 10);
+    frame.push(0x80 | opcode);
+    match payload.len() {
+        len if len < 126 => frame.push(len as u8),
+        len if len <= 0xffff => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// A close frame with the given status code
+fn close_frame(code: u16) -> Vec<u8> {
+    encode(OP_CLOSE, &code.to_be_bytes())
+}
+
+/// Read frames from the client, writing data to the pipe and queueing replies to control
+/// frames, until the client closes the connection.  Protocol errors are answered with a
+/// close frame.
+async fn read_frames<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    pipe: &mut WriteHalf<DuplexStream>,
+    control: &mpsc::Sender<Vec<u8>>,
+) -> anyhow::Result<()> {
+    let refuse = |code: u16, why: &str| {
+        let _ = control.try_send(close_frame(code));
+        bail!("{}", why.to_owned())
+    };
+    let mut buf = vec![0u8; BUFFER];
+    // whether a fragmented binary message is in progress
+    let mut continuing = false;
+    loop {
+        let header = match read_header(reader).await {
+            Ok(Some(header)) => header,
+            Ok(None) => return Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                return refuse(CLOSE_PROTOCOL_ERROR, &e.to_string())
+            }
+            Err(e) => return Err(e.into()),
+        };
+        match header.opcode {
+            OP_BINARY if !continuing => (),
+            OP_CONTINUATION if continuing => (),
+            OP_TEXT => return refuse(CLOSE_UNSUPPORTED_DATA, "text frame from client"),
+            OP_CLOSE | OP_PING | OP_PONG => {
+                if !header.fin || header.len > 125 {
+                    return refuse(CLOSE_PROTOCOL_ERROR, "invalid control frame");
+                }
+                let payload = &mut buf[..header.len as usize];
+                reader.read_exact(payload).await?;
+                unmask(payload, header.mask, 0);
+                match header.opcode {
+                    // echo the status code, if any, as the close handshake requires
+                    OP_CLOSE => {
+                        let code = &payload[..2.min(payload.len())];
+                        let _ = control.send(encode(OP_CLOSE, code)).await;
+                        return Ok(());
+                    }
+                    OP_PING => {
+                        let _ = control.send(encode(OP_PONG, payload)).await;
+                    }
+                    _ => (),
+                }
+                continue;
+            }
+            _ => return refuse(CLOSE_PROTOCOL_ERROR, "unexpected frame from client"),
+        }
+        continuing = !header.fin;
+
+        let mut offset = 0;
+        while offset < header.len {
+            let n = (header.len - offset).min(buf.len() as u64) as usize;
+            reader.read_exact(&mut buf[..n]).await?;
+            unmask(&mut buf[..n], header.mask, offset);
+            pipe.write_all(&buf[..n]).await?;
+            offset += n as u64;
+        }
+    }
+}
+
+/// Send data from the pipe to the client in binary frames, along with replies to the
+/// client's control frames, until either the pipe or the client closes, ending with a close
+/// frame.
+async fn write_frames<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    pipe: &mut ReadHalf<DuplexStream>,
+    replies: &mut mpsc::Receiver<Vec<u8>>,
+) -> anyhow::Result<()> {
+    let mut buf = vec![0u8; BUFFER];
+    loop {
+        tokio::select! {
+            n = pipe.read(&mut buf) => {
+                let n = n?;
+                if n == 0 {
+                    writer.write_all(&close_frame(CLOSE_NORMAL)).await?;
+                    return Ok(());
+                }
+                writer.write_all(&encode(OP_BINARY, &buf[..n])).await?;
+            }
+            Some(frame) = replies.recv() => {
+                writer.write_all(&frame).await?;
+                if frame[0] & 0x0f == OP_CLOSE {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::duplex;
+
+    const UPGRADE: &str = "GET /tunnel?host=api.giphy.com&port=443 HTTP/1.1\r\n\
+        Host: proxy\r\n\
+        Upgrade: websocket\r\n\
+        Connection: keep-alive, Upgrade\r\n\
+        Sec-WebSocket-Version: 13\r\n\
+        Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n";
+
+    /// Encode a masked frame, as a client sends
+    fn client_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [1, 2, 3, 4];
+        let mut frame = encode(opcode, payload);
+        if !fin {
+            frame[0] &= 0x7f;
+        }
+        let start = frame.len() - payload.len();
+        frame[1] |= 0x80;
+        frame.splice(start..start, mask.iter().copied());
+        unmask(&mut frame[start + 4..], mask, 0);
+        frame
+    }
+
+    #[test]
+    fn test_is_tunnel_request() {
+        let options = ParseOptions::default();
+        let config = WebSocketConfig::default();
+        assert!(is_tunnel_request(UPGRADE.as_bytes(), &options, &config));
+        let other = UPGRADE.replace("/tunnel?", "/other?");
+        assert!(!is_tunnel_request(other.as_bytes(), &options, &config));
+        let post = UPGRADE.replace("GET", "POST");
+        assert!(!is_tunnel_request(post.as_bytes(), &options, &config));
+    }
+
+    #[test]
+    fn test_parse_upgrade() {
+        let options = ParseOptions::default();
+        let config = WebSocketConfig::default();
+        let (host, port, version, headers) =
+            parse_upgrade(UPGRADE.as_bytes(), &options, &config).unwrap();
+        assert_eq!(
+            (host.as_str(), port, version.as_str()),
+            ("api.giphy.com", 443, "HTTP/1.1")
+        );
+
+        // the response accepts the key, as in the example in RFC 6455
+        let response = switching_protocols(&headers);
+        assert_eq!(response.status, 101);
+        assert!(response.headers.contains(&(
+            "Sec-WebSocket-Accept".into(),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=".into()
+        )));
+
+        let ipv6 = UPGRADE.replace("host=api.giphy.com&port=443", "host=[::1]");
+        let (host, port, _, _) = parse_upgrade(ipv6.as_bytes(), &options, &config).unwrap();
+        assert_eq!((host.as_str(), port), ("::1", options.default_port));
+
+        for (from, to) in [
+            ("host=api.giphy.com&", ""),
+            ("port=443", "port=0"),
+            ("host=api.giphy.com", "host=bad..name"),
+            ("Upgrade: websocket", "Upgrade: h2c"),
+            ("Version: 13", "Version: 8"),
+            ("Key: dGhlIHNhbXBsZSBub25jZQ==", "Key: c2hvcnQ="),
+            ("HTTP/1.1", "HTTP/1.0"),
+        ] {
+            let request = UPGRADE.replace(from, to);
+            assert!(
+                parse_upgrade(request.as_bytes(), &options, &config).is_err(),
+                "{}",
+                request
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_upgrade_origin() {
+        let options = ParseOptions::default();
+        let with_origin = |origin: &str| {
+            UPGRADE.replace(
+                "Host: proxy\r\n",
+                &format!("Host: proxy\r\nOrigin: {}\r\n", origin),
+            )
+        };
+        let request = with_origin("https://app.example.com");
+        let config = WebSocketConfig::default();
+        assert!(parse_upgrade(request.as_bytes(), &options, &config).is_err());
+
+        let config = WebSocketConfig {
+            allowed_origins: vec!["https://App.example.com/".into()],
+            ..WebSocketConfig::default()
+        };
+        assert!(parse_upgrade(request.as_bytes(), &options, &config).is_ok());
+        let request = with_origin("https://evil.example");
+        assert!(parse_upgrade(request.as_bytes(), &options, &config).is_err());
+    }
+
+    #[test]
+    fn test_parse_upgrade_credentials() {
+        let options = ParseOptions::default();
+        let config = WebSocketConfig::default();
+        let credentials = |request: String| {
+            let (_, _, _, headers) = parse_upgrade(request.as_bytes(), &options, &config).unwrap();
+            headers.get("Proxy-Authorization").map(str::to_owned)
+        };
+        assert_eq!(credentials(UPGRADE.into()), None);
+
+        // pat:secret?
+        let token = UPGRADE.replace("port=443", "port=443&token=cGF0OnNlY3JldD8");
+        assert_eq!(credentials(token), Some("Basic cGF0OnNlY3JldD8=".into()));
+        let authorization = UPGRADE.replace(
+            "Host: proxy\r\n",
+            "Host: proxy\r\nAuthorization: Basic cGF0OnNlY3JldA==\r\n",
+        );
+        assert_eq!(
+            credentials(authorization.clone()),
+            Some("Basic cGF0OnNlY3JldA==".into())
+        );
+        let both = authorization.replace(
+            "Host: proxy\r\n",
+            "Host: proxy\r\nProxy-Authorization: Basic b3RoZXI6eA==\r\n",
+        );
+        assert_eq!(credentials(both), Some("Basic b3RoZXI6eA==".into()));
+
+        let bad = UPGRADE.replace("port=443", "port=443&token=!!!");
+        assert!(parse_upgrade(bad.as_bytes(), &options, &config).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_relay() {
+        let (mut client, socket) = duplex(BUFFER);
+        let (mut tunnel, pipe) = duplex(BUFFER);
+        let relay = tokio::spawn(relay(socket, pipe));
+
+        // a fragmented message from the client arrives as a byte stream
+        client
+            .write_all(&client_frame(false, OP_BINARY, b"HEL"))
+            .await
+            .unwrap();
+        client
+            .write_all(&client_frame(true, OP_CONTINUATION, b"LO"))
+            .await
+            .unwrap();
+        let mut buf = [0u8; 5];
+        tunnel.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"HELLO");
+
+        // data from the tunnel arrives in binary frames
+        tunnel.write_all(b"WORLD").await.unwrap();
+        let mut frame = [0u8; 7];
+        client.read_exact(&mut frame).await.unwrap();
+        assert_eq!(frame[..], encode(OP_BINARY, b"WORLD")[..]);
+
+        // pings are answered
+        client
+            .write_all(&client_frame(true, OP_PING, b"hi"))
+            .await
+            .unwrap();
+        let mut frame = [0u8; 4];
+        client.read_exact(&mut frame).await.unwrap();
+        assert_eq!(frame[..], encode(OP_PONG, b"hi")[..]);
+
+        // a close from the client is echoed, and ends the tunnel's input
+        client
+            .write_all(&client_frame(true, OP_CLOSE, &CLOSE_NORMAL.to_be_bytes()))
+            .await
+            .unwrap();
+        let mut rest = vec![];
+        client.read_to_end(&mut rest).await.unwrap();
+        assert_eq!(rest, close_frame(CLOSE_NORMAL));
+        assert_eq!(tunnel.read(&mut buf).await.unwrap(), 0);
+        relay.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_relay_protocol_error() {
+        let (mut client, socket) = duplex(BUFFER);
+        let (_tunnel, pipe) = duplex(BUFFER);
+        let relay = tokio::spawn(relay(socket, pipe));
+
+        // an unmasked frame is a protocol error
+        client
+            .write_all(&encode(OP_BINARY, b"HELLO"))
+            .await
+            .unwrap();
+        let mut rest = vec![];
+        client.read_to_end(&mut rest).await.unwrap();
+        assert_eq!(rest, close_frame(CLOSE_PROTOCOL_ERROR));
+        relay.await.unwrap();
+    }
+}